@@ -0,0 +1,494 @@
+//! An [age](https://age-encryption.org) plugin backed by a BigKey, for encrypting files.
+//!
+//! The `age-plugin-bigkey` binary speaks the age plugin protocol, so `age` and `rage` encrypt to
+//! and decrypt with a BigKey key file:
+//!
+//! ```text
+//! $ age-plugin-bigkey /keys/big.key > identity.txt
+//! $ age -R identity.txt -o secret.age secret.txt     # recipient from the "# recipient:" line
+//! $ age -d -i identity.txt secret.age
+//! ```
+//!
+//! BigKey encryption is symmetric: the recipient `age1bigkey1...` and the identity
+//! `AGE-PLUGIN-BIGKEY-1...` both name the key file, and whoever can read the file can decrypt.
+//! For each file the plugin derives a fresh 256-bit key bound to the plugin (see
+//! `BigKey::new_key_for_peer()`) and encrypts the age file key under it with ChaCha20-Poly1305
+//! and an all-zero nonce, as each derived key encrypts once. The stanza is
+//!
+//! ```text
+//! -> bigkey <armored locator>
+//! <base64 of the encrypted file key and tag>
+//! ```
+//!
+//! and decryption re-derives the key from the locator.
+
+use std::io::{BufRead, Write};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use digest::Digest;
+
+use crate::helpers::{open_big_key, DiskBigKey};
+use crate::kem::{armor_locator, bech32_decode, bech32_encode, dearmor_locator, BigKey};
+use crate::memory::wipe;
+use crate::storage::StorageReader;
+use crate::traits::{key_from_base64, BigKeyError, KeyMaterial, SecretBytes, SecurityLevel};
+
+/// Tag of the stanzas the plugin writes
+pub const STANZA_TAG: &str = "bigkey";
+
+const RECIPIENT_HRP: &str = "age1bigkey";
+const IDENTITY_HRP: &str = "age-plugin-bigkey-";
+// Peer id binding derived keys to this plugin
+const PEER_ID: &[u8] = b"big_fluffy_dise age plugin";
+const FILE_KEY_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const BODY_COLUMNS: usize = 64;
+
+/// A stanza of the age header and plugin protocol: `-> tag args...` and a base64 body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stanza {
+    pub tag: String,
+    pub args: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+impl Stanza {
+    pub fn new(tag: &str, args: &[&str], body: &[u8]) -> Self {
+        Stanza {
+            tag: tag.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            body: body.to_vec(),
+        }
+    }
+
+    /// Read the next stanza from `input`, `None` at the end of input
+    pub fn read(input: &mut impl BufRead) -> Result<Option<Stanza>, BigKeyError> {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut words = line
+            .strip_suffix('\n')
+            .and_then(|line| line.strip_prefix("-> "))
+            .ok_or_else(|| invalid("expected a stanza"))?
+            .split(' ');
+        let tag = words
+            .next()
+            .filter(|tag| !tag.is_empty())
+            .ok_or_else(|| invalid("stanza without a tag"))?
+            .to_string();
+        let args = words.map(str::to_string).collect();
+
+        // the body ends with its first line shorter than a full line, possibly empty
+        let mut text = String::new();
+        loop {
+            line.clear();
+            input.read_line(&mut line)?;
+            let chunk = line
+                .strip_suffix('\n')
+                .ok_or_else(|| invalid("stanza body cut short"))?;
+            if chunk.len() > BODY_COLUMNS {
+                return Err(invalid("stanza body line too long"));
+            }
+            text.push_str(chunk);
+            if chunk.len() < BODY_COLUMNS {
+                break;
+            }
+        }
+        let body = decode_unpadded(&text)?;
+
+        Ok(Some(Stanza {
+            tag,
+            args,
+            body: body.into_vec(),
+        }))
+    }
+
+    /// Write the stanza to `output` and flush it
+    pub fn write(&self, output: &mut impl Write) -> Result<(), BigKeyError> {
+        write!(output, "-> {}", self.tag)?;
+        for arg in self.args.iter() {
+            write!(output, " {}", arg)?;
+        }
+        writeln!(output)?;
+
+        let text = self.body.to_base64();
+        let text = text.trim_end_matches('=');
+        for line in text.as_bytes().chunks(BODY_COLUMNS) {
+            output.write_all(line)?;
+            writeln!(output)?;
+        }
+        if text.len().is_multiple_of(BODY_COLUMNS) {
+            writeln!(output)?;
+        }
+        Ok(output.flush()?)
+    }
+}
+
+/// The key file behind an age recipient or identity of this plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgeKeyFile {
+    path: String,
+}
+
+impl AgeKeyFile {
+    /// Recipient and identity of the key file at `path`, which should be absolute so they work
+    /// from any directory
+    pub fn new(path: &str) -> Self {
+        AgeKeyFile {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// `age1bigkey1...`
+    pub fn recipient(&self) -> String {
+        bech32_encode(RECIPIENT_HRP, self.path.as_bytes())
+    }
+
+    /// `AGE-PLUGIN-BIGKEY-1...`
+    pub fn identity(&self) -> String {
+        bech32_encode(IDENTITY_HRP, self.path.as_bytes()).to_uppercase()
+    }
+
+    pub fn parse_recipient(recipient: &str) -> Result<Self, BigKeyError> {
+        AgeKeyFile::parse(recipient, RECIPIENT_HRP, "not a bigkey recipient")
+    }
+
+    pub fn parse_identity(identity: &str) -> Result<Self, BigKeyError> {
+        AgeKeyFile::parse(identity, IDENTITY_HRP, "not a bigkey identity")
+    }
+
+    fn parse(text: &str, hrp: &str, reason: &'static str) -> Result<Self, BigKeyError> {
+        match bech32_decode(text) {
+            Ok((prefix, path)) if prefix == hrp => Ok(AgeKeyFile {
+                path: String::from_utf8(path).map_err(|_| invalid("key file path is not UTF-8"))?,
+            }),
+            _ => Err(invalid(reason)),
+        }
+    }
+
+    fn open(&self) -> Result<DiskBigKey, BigKeyError> {
+        open_big_key(&self.path)
+    }
+}
+
+/// Encrypt age `file_key` under a fresh key derived from `big_key`, as a `bigkey` stanza
+pub fn wrap_file_key<S: StorageReader, H: Digest>(
+    big_key: &mut BigKey<S, H>,
+    file_key: &[u8],
+) -> Result<Stanza, BigKeyError> {
+    let (locator, mut key) = big_key.new_key_for_peer(SecurityLevel::Bits256, PEER_ID)?;
+    let body = cipher(&key).encrypt(Nonce::from_slice(&[0u8; 12]), file_key);
+    wipe(&mut key);
+
+    let body = body.map_err(|_| invalid("file key encryption failed"))?;
+    Ok(Stanza::new(
+        STANZA_TAG,
+        &[&armor_locator(locator.as_bytes())],
+        &body,
+    ))
+}
+
+/// The file key in `stanza` if it was wrapped for `big_key`. `None` for stanzas of other
+/// recipient types and of other BigKeys; malformed `bigkey` stanzas are an error.
+pub fn unwrap_stanza<S: StorageReader, H: Digest>(
+    big_key: &mut BigKey<S, H>,
+    stanza: &Stanza,
+) -> Result<Option<KeyMaterial>, BigKeyError> {
+    if stanza.tag != STANZA_TAG {
+        return Ok(None);
+    }
+    if stanza.args.len() != 1 || stanza.body.len() != FILE_KEY_LEN + TAG_LEN {
+        return Err(invalid("malformed bigkey stanza"));
+    }
+    let locator = dearmor_locator(&stanza.args[0])?;
+
+    let mut key = match big_key.get_key_for_peer(&locator, PEER_ID) {
+        Ok(key) => key,
+        Err(BigKeyError::UnknownKeyId { .. }) | Err(BigKeyError::LocatorAuthenticationFailed) => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    let file_key = cipher(&key).decrypt(Nonce::from_slice(&[0u8; 12]), &stanza.body[..]);
+    wipe(&mut key);
+
+    // a different BigKey derives a different key, failing authentication
+    Ok(file_key.ok().map(Vec::into_boxed_slice))
+}
+
+/// Run the `recipient-v1` state machine of the age plugin protocol: read recipients,
+/// identities and file keys from the client, then wrap every file key for every one of them
+pub fn run_recipient_v1(
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<(), BigKeyError> {
+    let mut key_files = Vec::new();
+    let mut file_keys = Vec::new();
+    loop {
+        let stanza = Stanza::read(&mut input)?.ok_or_else(|| invalid("client hung up"))?;
+        match (stanza.tag.as_str(), stanza.args.first()) {
+            ("add-recipient", Some(recipient)) => {
+                key_files.push(("recipient", AgeKeyFile::parse_recipient(recipient)))
+            }
+            ("add-identity", Some(identity)) => {
+                key_files.push(("identity", AgeKeyFile::parse_identity(identity)))
+            }
+            ("wrap-file-key", _) => file_keys.push(stanza.body),
+            ("done", _) => break,
+            // grease and extensions we do not support
+            _ => {}
+        }
+    }
+
+    let (kinds, opened): (Vec<_>, Vec<_>) = key_files
+        .into_iter()
+        .map(|(kind, key_file)| (kind, key_file.and_then(|key_file| key_file.open())))
+        .unzip();
+    let mut failed = false;
+    let mut big_keys = Vec::new();
+    for (i, (kind, big_key)) in kinds.into_iter().zip(opened).enumerate() {
+        match big_key {
+            Ok(big_key) => big_keys.push(big_key),
+            Err(e) => {
+                let index = i.to_string();
+                let error = Stanza::new("error", &[kind, &index], e.to_string().as_bytes());
+                command(&mut input, &mut output, &error)?;
+                failed = true;
+            }
+        }
+    }
+
+    if !failed {
+        for (file_index, file_key) in file_keys.iter().enumerate() {
+            for big_key in big_keys.iter_mut() {
+                let wrapped = wrap_file_key(big_key, file_key)?;
+                let index = file_index.to_string();
+                let mut args = vec![index.as_str(), wrapped.tag.as_str()];
+                args.extend(wrapped.args.iter().map(String::as_str));
+                let stanza = Stanza::new("recipient-stanza", &args, &wrapped.body);
+                command(&mut input, &mut output, &stanza)?;
+            }
+        }
+    }
+    Stanza::new("done", &[], &[]).write(&mut output)
+}
+
+/// Run the `identity-v1` state machine of the age plugin protocol: read identities and the
+/// stanzas of each file from the client, then return the file keys the identities unwrap
+pub fn run_identity_v1(mut input: impl BufRead, mut output: impl Write) -> Result<(), BigKeyError> {
+    let mut identities = Vec::new();
+    // stanzas of each file, in file order
+    let mut files: Vec<(String, Vec<Stanza>)> = Vec::new();
+    loop {
+        let stanza = Stanza::read(&mut input)?.ok_or_else(|| invalid("client hung up"))?;
+        match stanza.tag.as_str() {
+            "add-identity" if !stanza.args.is_empty() => {
+                identities.push(AgeKeyFile::parse_identity(&stanza.args[0]))
+            }
+            "recipient-stanza" if stanza.args.len() >= 2 => {
+                let mut args = stanza.args.into_iter();
+                let file_index = args.next().unwrap();
+                let file = Stanza {
+                    tag: args.next().unwrap(),
+                    args: args.collect(),
+                    body: stanza.body,
+                };
+                match files.iter_mut().find(|(index, _)| *index == file_index) {
+                    Some((_, stanzas)) => stanzas.push(file),
+                    None => files.push((file_index, vec![file])),
+                }
+            }
+            "done" => break,
+            _ => {}
+        }
+    }
+
+    let mut big_keys = Vec::new();
+    for (i, identity) in identities.into_iter().enumerate() {
+        match identity.and_then(|identity| identity.open()) {
+            Ok(big_key) => big_keys.push(big_key),
+            Err(e) => {
+                let index = i.to_string();
+                let error = Stanza::new("error", &["identity", &index], e.to_string().as_bytes());
+                command(&mut input, &mut output, &error)?;
+            }
+        }
+    }
+
+    for (file_index, stanzas) in files.iter() {
+        'stanzas: for (stanza_index, stanza) in stanzas.iter().enumerate() {
+            for big_key in big_keys.iter_mut() {
+                match unwrap_stanza(big_key, stanza) {
+                    Ok(Some(mut file_key)) => {
+                        let reply = Stanza::new("file-key", &[file_index], &file_key);
+                        wipe(&mut file_key);
+                        command(&mut input, &mut output, &reply)?;
+                        break 'stanzas;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        let index = stanza_index.to_string();
+                        let args = ["stanza", file_index.as_str(), &index];
+                        let error = Stanza::new("error", &args, e.to_string().as_bytes());
+                        command(&mut input, &mut output, &error)?;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Stanza::new("done", &[], &[]).write(&mut output)
+}
+
+// Send `stanza` to the client and wait for its response
+fn command(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    stanza: &Stanza,
+) -> Result<Stanza, BigKeyError> {
+    stanza.write(output)?;
+    Stanza::read(input)?.ok_or_else(|| invalid("client hung up"))
+}
+
+fn cipher(key: &[u8]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+// Unpadded standard base64, as in age stanza bodies
+fn decode_unpadded(text: &str) -> Result<KeyMaterial, BigKeyError> {
+    let padding = match text.len() % 4 {
+        _ if text.contains('=') => return Err(invalid("padded stanza body")),
+        0 => 0,
+        1 => return Err(invalid("invalid stanza body length")),
+        rem => 4 - rem,
+    };
+    key_from_base64(&format!("{}{}", text, &"=="[..padding]))
+        .map_err(|_| invalid("stanza body is not base64"))
+}
+
+fn invalid(reason: &'static str) -> BigKeyError {
+    BigKeyError::AgePluginFailed { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::age::{run_identity_v1, run_recipient_v1, AgeKeyFile, Stanza};
+    use crate::helpers::test_key_file;
+
+    fn stanzas(bytes: &[u8]) -> Vec<Stanza> {
+        let mut input = Cursor::new(bytes);
+        let mut out = Vec::new();
+        while let Some(stanza) = Stanza::read(&mut input).unwrap() {
+            out.push(stanza);
+        }
+        out
+    }
+
+    fn transcript(messages: &[Stanza]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for stanza in messages {
+            stanza.write(&mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn stanzas_use_canonical_base64_bodies() {
+        for len in [0, 1, 47, 48, 49, 96].iter() {
+            let stanza = Stanza::new("x", &["a", "b"], &vec![0xa5u8; *len]);
+            let bytes = transcript(std::slice::from_ref(&stanza));
+            assert_eq!(bytes.ends_with(b"\n\n"), *len % 48 == 0);
+            assert_eq!(stanzas(&bytes), vec![stanza]);
+        }
+        assert_eq!(transcript(&[Stanza::new("ok", &[], &[])]), b"-> ok\n\n");
+
+        for bad in ["-> x\nAAA=\n", "x\n\n", "-> \n\n", "-> x\n"].iter() {
+            assert!(
+                Stanza::read(&mut Cursor::new(bad.as_bytes())).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn files_encrypted_to_a_big_key_decrypt_with_it() {
+        let (tmp, other) = (test_key_file(), test_key_file());
+        let key_file = AgeKeyFile::new(tmp.to_str());
+        assert!(key_file.recipient().starts_with("age1bigkey1"));
+        assert!(key_file.identity().starts_with("AGE-PLUGIN-BIGKEY-1"));
+        assert_eq!(
+            AgeKeyFile::parse_recipient(&key_file.recipient()).unwrap(),
+            key_file
+        );
+        assert_eq!(
+            AgeKeyFile::parse_identity(&key_file.identity()).unwrap(),
+            key_file
+        );
+        assert!(AgeKeyFile::parse_identity(&key_file.recipient()).is_err());
+
+        let ok = Stanza::new("ok", &[], &[]);
+        let file_key = [7u8; 16];
+        let client = transcript(&[
+            Stanza::new("add-recipient", &[&key_file.recipient()], &[]),
+            Stanza::new("grease-x", &["y"], b"ignored"),
+            Stanza::new("wrap-file-key", &[], &file_key),
+            Stanza::new("done", &[], &[]),
+            ok.clone(),
+        ]);
+        let mut plugin = Vec::new();
+        run_recipient_v1(Cursor::new(client), &mut plugin).unwrap();
+        let replies = stanzas(&plugin);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].tag, "recipient-stanza");
+        assert_eq!(
+            replies[0].args[..2],
+            ["0".to_string(), "bigkey".to_string()]
+        );
+        assert_eq!(replies[1].tag, "done");
+
+        let decrypt = |identity: &AgeKeyFile| {
+            let client = transcript(&[
+                Stanza::new("add-identity", &[&identity.identity()], &[]),
+                Stanza::new("recipient-stanza", &["0", "X25519", "abc"], &[1u8; 32]),
+                replies[0].clone(),
+                Stanza::new("done", &[], &[]),
+                ok.clone(),
+            ]);
+            let mut plugin = Vec::new();
+            run_identity_v1(Cursor::new(client), &mut plugin).unwrap();
+            stanzas(&plugin)
+        };
+
+        let replies = decrypt(&key_file);
+        assert_eq!(replies[0], Stanza::new("file-key", &["0"], &file_key));
+        assert_eq!(replies[1].tag, "done");
+        // another BigKey cannot unwrap the file key
+        assert_eq!(
+            decrypt(&AgeKeyFile::new(other.to_str())),
+            vec![Stanza::new("done", &[], &[])]
+        );
+
+        // unreadable key files are reported against the recipient
+        let missing = AgeKeyFile::new("/nonexistent/big.key");
+        let client = transcript(&[
+            Stanza::new("add-recipient", &[&missing.recipient()], &[]),
+            Stanza::new("wrap-file-key", &[], &file_key),
+            Stanza::new("done", &[], &[]),
+            ok,
+        ]);
+        let mut plugin = Vec::new();
+        run_recipient_v1(Cursor::new(client), &mut plugin).unwrap();
+        let replies = stanzas(&plugin);
+        assert_eq!(replies[0].args, ["recipient".to_string(), "0".to_string()]);
+        assert_eq!(replies[1].tag, "done");
+    }
+} // mod test
@@ -0,0 +1,850 @@
+//! A local derivation daemon holding an opened BigKey, in the manner of `ssh-agent`.
+//!
+//! Opening a multi-hundred-GiB key file in every process that needs keys is wasteful, and
+//! spreads read access to the file across many users. The `bigkey-agent` binary instead opens
+//! it once and serves derivations over a Unix socket:
+//!
+//! ```text
+//! $ bigkey-agent /keys/big.key /run/bigkey.sock --allow-gid 1001
+//! ```
+//!
+//! Every connection is checked against an `AgentPolicy` using the peer credentials the kernel
+//! reports for the socket (`SO_PEERCRED` on Linux, `getpeereid()` on the BSDs and macOS), not
+//! anything the client claims. By default only the daemon's own user is served.
+//!
+//! The protocol is one JSON object per line. Requests are `{"op":"new_key"}` and
+//! `{"op":"get_key","locator":"bklc1..."}`; responses carry the armored `locator` and the hex
+//! `key`, or an `error`. `AgentClient` speaks it for Rust callers.
+//!
+//! An agent shared by several tenants can also require a lease token (see `crate::lease`) in a
+//! `lease` field of every request, e.g. `{"op":"new_key","lease":"bkls1..."}`. The blocks a
+//! derivation probes are charged to the lease before deriving, and requests without a valid
+//! lease or beyond its quota are refused. Charges are only kept in memory and start over when
+//! the agent restarts.
+//!
+//! Locators are checked against the key's id and security level before a derivation waits for
+//! the key, so locators claiming far more probes than their security level needs are refused
+//! without probing or charging a lease, whether or not leases are required.
+//!
+//! An agent on a measured or TEE-hosted key host can also attest itself (see
+//! `crate::attestation`): `{"op":"attest","nonce":"<hex>"}` is answered with the hex `quote` of
+//! its `Attester`. `AgentClient::connect_attested()` sends a fresh nonce and verifies the quote
+//! before the connection is used for any derivation, so locators are only revealed to hosts the
+//! client's `QuoteVerifier` accepts. Quotes also bind the agent's credentials as the client's
+//! socket reports them (`channel_binding()`), so an agent must attest from the process that
+//! bound its socket, and a relay forwarding nonces to another agent cannot pass its quotes on.
+
+use std::convert::TryInto;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::attestation::{new_nonce, report_data, Attester, QuoteVerifier, NONCE_LEN};
+use crate::kem::{
+    armor_locator, check_probe_count, dearmor_locator, probe_count, KemSession, LocatorBody,
+};
+use crate::lease::{LeaseIssuer, LeaseLedger};
+use crate::memory::wipe;
+use crate::storage::StorageReader;
+use crate::traits::{key_from_hex, BigKeyError, BlockSize, KeyMaterial, Locator, SecretBytes};
+
+// Requests are a locator at most; anything longer is not a request
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+/// Credentials of the process at the other end of a Unix socket, as reported by the kernel
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Process id, where the platform reports it
+    pub pid: Option<i32>,
+}
+
+/// Credentials of the peer of `stream`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, io::Error> {
+    // Safety: ucred is plain data, zeroed is a valid value
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // Safety: the descriptor is owned by `stream`, `cred` and `len` outlive the call
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: Some(cred.pid),
+    })
+}
+
+/// Credentials of the peer of `stream`
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+pub fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, io::Error> {
+    let (mut uid, mut gid) = (0, 0);
+    // Safety: the descriptor is owned by `stream`, `uid` and `gid` outlive the call
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        uid,
+        gid,
+        pid: None,
+    })
+}
+
+/// Credentials of the peer of `stream`
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+pub fn peer_credentials(_stream: &UnixStream) -> Result<PeerCredentials, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "peer credentials are not available on this platform",
+    ))
+}
+
+/// Credentials of this process as its peers see them: effective ids, and the process id where
+/// `peer_credentials()` reports one
+fn own_credentials() -> PeerCredentials {
+    // Safety: geteuid and getegid cannot fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let pid = if cfg!(any(target_os = "linux", target_os = "android")) {
+        Some(std::process::id() as i32)
+    } else {
+        None
+    };
+    PeerCredentials { uid, gid, pid }
+}
+
+/// The channel binding attestation quotes over an agent connection bind: the agent's
+/// credentials, which the client reads from its socket and the agent knows of itself. A relay
+/// between a client and a genuine agent runs as another process, so the agent's quote does
+/// not verify for the client. Where the platform reports no process id, only a relay under
+/// another user or group is detected.
+pub fn channel_binding(agent: &PeerCredentials) -> Vec<u8> {
+    let mut binding = Vec::with_capacity(16);
+    binding.extend_from_slice(&agent.uid.to_be_bytes());
+    binding.extend_from_slice(&agent.gid.to_be_bytes());
+    if let Some(pid) = agent.pid {
+        binding.extend_from_slice(&pid.to_be_bytes());
+    }
+    binding
+}
+
+/// Which local users and groups the agent serves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentPolicy {
+    allowed_uids: Vec<u32>,
+    allowed_gids: Vec<u32>,
+}
+
+impl AgentPolicy {
+    /// Serve only the user the agent runs as
+    pub fn owner_only() -> Self {
+        // Safety: geteuid cannot fail
+        let uid = unsafe { libc::geteuid() };
+        AgentPolicy {
+            allowed_uids: vec![uid],
+            allowed_gids: Vec::new(),
+        }
+    }
+
+    /// Also serve processes running as `uid`
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.allowed_uids.push(uid);
+        self
+    }
+
+    /// Also serve processes whose primary group is `gid`
+    pub fn allow_gid(mut self, gid: u32) -> Self {
+        self.allowed_gids.push(gid);
+        self
+    }
+
+    /// Remove the agent's own user, serving only the explicitly allowed users and groups
+    pub fn without_owner(mut self) -> Self {
+        // Safety: geteuid cannot fail
+        let uid = unsafe { libc::geteuid() };
+        self.allowed_uids.retain(|allowed| *allowed != uid);
+        self
+    }
+
+    pub fn permits(&self, peer: &PeerCredentials) -> bool {
+        self.allowed_uids.contains(&peer.uid) || self.allowed_gids.contains(&peer.gid)
+    }
+
+    // The socket is only reachable by other users if the policy serves them
+    fn socket_mode(&self) -> u32 {
+        // Safety: geteuid cannot fail
+        let uid = unsafe { libc::geteuid() };
+        if self.allowed_gids.is_empty() && self.allowed_uids.iter().all(|allowed| *allowed == uid) {
+            0o600
+        } else {
+            0o666
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    NewKey {
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        lease: Option<String>,
+    },
+    GetKey {
+        locator: String,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        lease: Option<String>,
+    },
+    Attest {
+        nonce: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    locator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    quote: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    error: Option<String>,
+}
+
+impl Response {
+    fn failed(error: impl ToString) -> Self {
+        Response {
+            locator: None,
+            key: None,
+            quote: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            wipe(&mut key.into_bytes());
+        }
+    }
+}
+
+// What the session's BigKey requires of a locator that can be checked without it, so requests
+// for locators it would refuse are failed without waiting for the session
+struct LocatorLimits {
+    key_id: u32,
+    leakage_tolerance: f32,
+    block_size: BlockSize,
+}
+
+impl LocatorLimits {
+    // Blocks deriving the key of `locator` probes, failing for key ids and probe counts the
+    // session would refuse
+    fn check(&self, locator: &Locator) -> Result<u64, BigKeyError> {
+        let body = LocatorBody::decode(locator.as_bytes())?;
+        if body.key_id != self.key_id {
+            return Err(BigKeyError::UnknownKeyId {
+                key_id: body.key_id,
+            });
+        }
+        let required = probe_count(body.security_level, self.leakage_tolerance, self.block_size)?;
+        check_probe_count(body.probe_count, required)?;
+        Ok(body.probe_count as u64)
+    }
+}
+
+/// The daemon: a `KemSession` shared by the connections of permitted local clients
+pub struct Agent {
+    session: Mutex<KemSession>,
+    limits: LocatorLimits,
+    policy: AgentPolicy,
+    leases: Option<(LeaseIssuer, LeaseLedger)>,
+    attester: Option<Box<dyn Attester>>,
+}
+
+impl Agent {
+    pub fn new(session: KemSession, policy: AgentPolicy) -> Self {
+        let limits = LocatorLimits {
+            key_id: session.params().key_id,
+            leakage_tolerance: session.params().leakage_tolerance,
+            block_size: session.storage().block_size(),
+        };
+        Agent {
+            session: Mutex::new(session),
+            limits,
+            policy,
+            leases: None,
+            attester: None,
+        }
+    }
+
+    /// Require every request to carry a lease issued by `issuer`, and charge the blocks each
+    /// derivation probes to it. Charges are kept in memory only: restarting the agent resets the
+    /// blocks charged to every lease, so a tenant able to restart it is not bounded by its quota.
+    pub fn require_leases(mut self, issuer: LeaseIssuer) -> Self {
+        self.leases = Some((issuer, LeaseLedger::new()));
+        self
+    }
+
+    /// Answer attestation requests with quotes of `attester`
+    pub fn with_attester(mut self, attester: impl Attester + 'static) -> Self {
+        self.attester = Some(Box::new(attester));
+        self
+    }
+
+    /// Bind the agent's socket at `path`, replacing a stale socket left there but no other
+    /// kind of file, and make it reachable by the users the policy serves
+    pub fn bind(&self, path: impl AsRef<Path>) -> Result<UnixListener, BigKeyError> {
+        let path = path.as_ref();
+        if let Ok(metadata) = path.symlink_metadata() {
+            if !metadata.file_type().is_socket() {
+                return Err(failed(format!(
+                    "{} exists and is not a socket",
+                    path.display()
+                )));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(
+            path,
+            std::fs::Permissions::from_mode(self.policy.socket_mode()),
+        )?;
+        Ok(listener)
+    }
+
+    /// Accept connections on `listener` until it fails, serving each on its own thread
+    pub fn serve(self: Arc<Self>, listener: UnixListener) -> Result<(), BigKeyError> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let agent = Arc::clone(&self);
+            thread::spawn(move || {
+                if let Err(e) = agent.handle(stream) {
+                    log::warn!("agent connection failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Serve the requests of one connection until the client hangs up. Clients the policy
+    /// does not permit get a single error response.
+    pub fn handle(&self, stream: UnixStream) -> Result<(), BigKeyError> {
+        let peer = peer_credentials(&stream)?;
+        let mut writer = stream.try_clone()?;
+        if !self.policy.permits(&peer) {
+            log::warn!(
+                "agent refused uid {} gid {} pid {:?}",
+                peer.uid,
+                peer.gid,
+                peer.pid
+            );
+            return send(&mut writer, &Response::failed("permission denied"));
+        }
+
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            if reader.by_ref().take(MAX_REQUEST_LEN).read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if !line.ends_with('\n') {
+                return send(&mut writer, &Response::failed("request too long"));
+            }
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => self.respond(request),
+                Err(e) => Response::failed(format!("malformed request: {}", e)),
+            };
+            send(&mut writer, &response)?;
+        }
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        if let Request::Attest { nonce } = &request {
+            return match self.attest(nonce) {
+                Ok(quote) => Response {
+                    locator: None,
+                    key: None,
+                    quote: Some(quote.to_hex()),
+                    error: None,
+                },
+                Err(e) => Response::failed(e),
+            };
+        }
+        let result = match request {
+            Request::NewKey { lease } => self.session().and_then(|mut session| {
+                self.charge(lease.as_deref(), session.estimated_probe_count()?)?;
+                session.new_key()
+            }),
+            // locators are checked before waiting for the session, which derivations of hostile
+            // locators would otherwise hold for as long as they probe
+            Request::GetKey { locator, lease } => dearmor_locator(&locator).and_then(|locator| {
+                self.charge(lease.as_deref(), self.limits.check(&locator)?)?;
+                let key = self.session()?.get_key(&locator)?;
+                Ok((locator, key))
+            }),
+            Request::Attest { .. } => unreachable!("attestation requests are answered above"),
+        };
+        match result {
+            Ok((locator, mut key)) => {
+                let response = Response {
+                    locator: Some(armor_locator(locator.as_bytes())),
+                    key: Some(key.to_hex()),
+                    quote: None,
+                    error: None,
+                };
+                wipe(&mut key);
+                response
+            }
+            Err(e) => Response::failed(e),
+        }
+    }
+
+    fn attest(&self, nonce: &str) -> Result<Vec<u8>, BigKeyError> {
+        let attester = self
+            .attester
+            .as_ref()
+            .ok_or_else(|| failed("attestation not configured"))?;
+        let nonce: [u8; NONCE_LEN] = key_from_hex(nonce)
+            .ok()
+            .and_then(|nonce| nonce[..].try_into().ok())
+            .ok_or_else(|| failed("malformed attestation nonce"))?;
+        // the kernel reports the credentials of the process that bound the socket, which is this
+        // one unless the socket was handed over
+        attester.quote(&report_data(&nonce, &channel_binding(&own_credentials())))
+    }
+
+    fn session(&self) -> Result<MutexGuard<'_, KemSession>, BigKeyError> {
+        self.session
+            .lock()
+            .map_err(|_| failed("agent session poisoned"))
+    }
+
+    // Charge `probes` blocks to `lease`, if the agent requires leases
+    fn charge(&self, lease: Option<&str>, probes: u64) -> Result<(), BigKeyError> {
+        let (issuer, ledger) = match &self.leases {
+            Some(leases) => leases,
+            None => return Ok(()),
+        };
+        let lease = issuer.verify(lease.ok_or(BigKeyError::InvalidLease {
+            reason: "lease required",
+        })?)?;
+        ledger
+            .charge(&lease, probes)
+            .inspect_err(|_| log::warn!("agent refused tenant {} beyond its quota", lease.tenant))
+    }
+}
+
+fn send(writer: &mut impl Write, response: &Response) -> Result<(), BigKeyError> {
+    let mut line = serde_json::to_vec(response).expect("responses serialize");
+    line.push(b'\n');
+    let result = writer.write_all(&line);
+    wipe(&mut line);
+    Ok(result?)
+}
+
+/// Client of a running agent
+pub struct AgentClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    lease: Option<String>,
+}
+
+impl AgentClient {
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, BigKeyError> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(AgentClient {
+            reader,
+            writer,
+            lease: None,
+        })
+    }
+
+    /// Connect to the agent at `path` and have it attest itself, failing with
+    /// `AttestationFailed` unless `verifier` accepts its quote
+    pub fn connect_attested(
+        path: impl AsRef<Path>,
+        verifier: &dyn QuoteVerifier,
+    ) -> Result<Self, BigKeyError> {
+        let mut client = AgentClient::connect(path)?;
+        client.attest(verifier)?;
+        Ok(client)
+    }
+
+    /// Have the agent attest itself with a quote over a fresh nonce, and verify it with
+    /// `verifier`
+    pub fn attest(&mut self, verifier: &dyn QuoteVerifier) -> Result<(), BigKeyError> {
+        let nonce = new_nonce()?;
+        let agent = peer_credentials(&self.writer).map_err(attestation_failed)?;
+        let response = self
+            .call(&Request::Attest {
+                nonce: nonce.to_hex(),
+            })
+            .map_err(|e| attestation_failed(e.to_string()))?;
+        let quote = match response.quote.as_deref().map(key_from_hex) {
+            Some(Ok(quote)) => quote,
+            _ => return Err(attestation_failed("response without a quote")),
+        };
+        verifier.verify(&quote, &report_data(&nonce, &channel_binding(&agent)))
+    }
+
+    /// Present `lease` with every following request, for agents requiring leases
+    pub fn set_lease(&mut self, lease: Option<String>) {
+        self.lease = lease;
+    }
+
+    /// Derive a fresh key at the agent's security level
+    pub fn new_key(&mut self) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        let mut response = self.call(&Request::NewKey {
+            lease: self.lease.clone(),
+        })?;
+        let locator = match response.locator.as_deref() {
+            Some(locator) => dearmor_locator(locator)?,
+            None => return Err(failed("response without a locator")),
+        };
+        Ok((locator, take_key(&mut response)?))
+    }
+
+    /// Re-derive the key identified by `locator`
+    pub fn get_key(&mut self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+        let mut response = self.call(&Request::GetKey {
+            locator: armor_locator(locator.as_bytes()),
+            lease: self.lease.clone(),
+        })?;
+        take_key(&mut response)
+    }
+
+    fn call(&mut self, request: &Request) -> Result<Response, BigKeyError> {
+        let mut line = serde_json::to_vec(request).expect("requests serialize");
+        line.push(b'\n');
+        // an agent refusing us answers and hangs up without reading, possibly before we write,
+        // so a broken pipe still leaves its answer to read
+        match self.writer.write_all(&line) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(failed("agent closed the connection"));
+        }
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|e| failed(format!("malformed response: {}", e)))?;
+        wipe(&mut line.into_bytes());
+        match &response.error {
+            Some(error) => Err(failed(error.clone())),
+            None => Ok(response),
+        }
+    }
+}
+
+fn take_key(response: &mut Response) -> Result<KeyMaterial, BigKeyError> {
+    match response.key.take() {
+        Some(hex) => {
+            let key = key_from_hex(&hex);
+            wipe(&mut hex.into_bytes());
+            key
+        }
+        None => Err(failed("response without a key")),
+    }
+}
+
+fn failed(reason: impl ToString) -> BigKeyError {
+    BigKeyError::AgentFailed {
+        reason: reason.to_string(),
+    }
+}
+
+fn attestation_failed(reason: impl ToString) -> BigKeyError {
+    BigKeyError::AttestationFailed {
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::agent::{
+        channel_binding, peer_credentials, send, Agent, AgentClient, AgentPolicy, PeerCredentials,
+        Request, Response,
+    };
+    use crate::attestation::{report_data, Attester, QuoteVerifier};
+    use crate::helpers::test_key_file;
+    use crate::kem::{armor_locator, KemSession, LocatorBody, SessionParams};
+    use crate::lease::LeaseIssuer;
+    use crate::storage::tempfile::tempfile;
+    use crate::traits::{key_from_hex, BigKeyError, SecretBytes};
+
+    // Stand-in for a TPM or TEE: quotes are a MAC over the report data under a "platform" key
+    struct MacQuotes([u8; 32]);
+
+    impl Attester for MacQuotes {
+        fn quote(&self, report_data: &[u8; 32]) -> Result<Vec<u8>, BigKeyError> {
+            Ok(blake3::keyed_hash(&self.0, report_data).as_bytes().to_vec())
+        }
+    }
+
+    impl QuoteVerifier for MacQuotes {
+        fn verify(&self, quote: &[u8], report_data: &[u8; 32]) -> Result<(), BigKeyError> {
+            match self.quote(report_data)?.ct_eq(quote) {
+                true => Ok(()),
+                false => Err(BigKeyError::AttestationFailed {
+                    reason: "quote does not verify".to_string(),
+                }),
+            }
+        }
+    }
+
+    #[test]
+    fn agents_serve_permitted_peers_only() {
+        let (tmp, socket) = (test_key_file(), tempfile());
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let peer = peer_credentials(&ours).unwrap();
+        assert_eq!(peer, peer_credentials(&theirs).unwrap());
+        assert!(AgentPolicy::owner_only().permits(&peer));
+        assert!(!AgentPolicy::owner_only().without_owner().permits(&peer));
+        assert!(AgentPolicy::owner_only()
+            .without_owner()
+            .allow_gid(peer.gid)
+            .permits(&peer));
+
+        let session = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        let agent = Arc::new(Agent::new(session, AgentPolicy::owner_only()));
+        let listener = agent.bind(socket.as_path()).unwrap();
+        thread::spawn(move || agent.serve(listener));
+
+        let mut client = AgentClient::connect(socket.as_path()).unwrap();
+        let (locator, key) = client.new_key().unwrap();
+        assert_eq!(key.len(), 16);
+        let mut other = AgentClient::connect(socket.as_path()).unwrap();
+        assert_eq!(other.get_key(&locator).unwrap(), key);
+        let direct = KemSession::open(tmp.to_str(), SessionParams::default())
+            .unwrap()
+            .get_key(&locator)
+            .unwrap();
+        assert_eq!(direct, key);
+        match client.get_key(&vec![1u8, 2, 3].into()) {
+            Err(BigKeyError::AgentFailed { .. }) => {}
+            _ => panic!("expected a malformed locator to fail"),
+        }
+
+        // a policy not serving us answers with an error only
+        let session = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        let refusing = Agent::new(session, AgentPolicy::owner_only().without_owner());
+        thread::spawn(move || refusing.handle(theirs));
+        let mut client = AgentClient {
+            reader: BufReader::new(ours.try_clone().unwrap()),
+            writer: ours,
+            lease: None,
+        };
+        match client.new_key() {
+            Err(BigKeyError::AgentFailed { reason }) => assert_eq!(reason, "permission denied"),
+            _ => panic!("expected the agent to refuse"),
+        }
+    }
+
+    #[test]
+    fn agents_charge_probes_to_leases() {
+        let (tmp, socket) = (test_key_file(), tempfile());
+        let session = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        let probes = session.estimated_probe_count().unwrap();
+
+        let issuer = LeaseIssuer::new(b"0123456789abcdef0123456789abcdef");
+        let lease = issuer
+            .issue("tenant-a", Duration::from_secs(60), 2 * probes)
+            .unwrap();
+        let agent = Arc::new(
+            Agent::new(session, AgentPolicy::owner_only())
+                .require_leases(LeaseIssuer::new(b"0123456789abcdef0123456789abcdef")),
+        );
+        let listener = agent.bind(socket.as_path()).unwrap();
+        thread::spawn(move || agent.serve(listener));
+
+        let mut client = AgentClient::connect(socket.as_path()).unwrap();
+        match client.new_key() {
+            Err(BigKeyError::AgentFailed { reason }) => assert!(reason.contains("lease required")),
+            _ => panic!("expected a request without a lease to be refused"),
+        }
+        client.set_lease(Some(lease));
+        let (locator, key) = client.new_key().unwrap();
+        assert_eq!(client.get_key(&locator).unwrap(), key);
+        match client.get_key(&locator) {
+            Err(BigKeyError::AgentFailed { reason }) => assert!(reason.contains("quota")),
+            _ => panic!("expected the lease's quota to run out"),
+        }
+
+        let foreign = LeaseIssuer::new(b"another secret of at least 32 bytes")
+            .issue("tenant-b", Duration::from_secs(60), 1_000_000)
+            .unwrap();
+        client.set_lease(Some(foreign));
+        assert!(client.get_key(&locator).is_err());
+    }
+
+    #[test]
+    fn hostile_locators_are_refused_before_deriving() {
+        let tmp = test_key_file();
+        let mut session = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        let (locator, _) = session.new_key().unwrap();
+        let body = LocatorBody::decode(locator.as_bytes()).unwrap();
+        let locator_with = |body: LocatorBody| armor_locator(body.encode().as_bytes());
+        let hostile = [
+            locator_with(LocatorBody {
+                probe_count: u32::MAX,
+                ..body.clone()
+            }),
+            locator_with(LocatorBody {
+                probe_count: 1,
+                ..body.clone()
+            }),
+            locator_with(LocatorBody {
+                key_id: 7,
+                ..body.clone()
+            }),
+        ];
+
+        let issuer = LeaseIssuer::new(b"0123456789abcdef0123456789abcdef");
+        let lease = issuer
+            .issue("tenant-a", Duration::from_secs(60), u64::MAX)
+            .unwrap();
+        let agents = [
+            Agent::new(session, AgentPolicy::owner_only()),
+            Agent::new(
+                KemSession::open(tmp.to_str(), SessionParams::default()).unwrap(),
+                AgentPolicy::owner_only(),
+            )
+            .require_leases(LeaseIssuer::new(b"0123456789abcdef0123456789abcdef")),
+        ];
+        for agent in agents.iter() {
+            for locator in hostile.iter() {
+                let response = agent.respond(Request::GetKey {
+                    locator: locator.clone(),
+                    lease: Some(lease.clone()),
+                });
+                assert!(response.error.is_some());
+                assert!(response.key.is_none());
+            }
+        }
+        let (_, ledger) = agents[1].leases.as_ref().unwrap();
+        assert!(ledger.is_empty());
+
+        let response = agents[1].respond(Request::GetKey {
+            locator: locator_with(body),
+            lease: Some(lease.clone()),
+        });
+        assert!(response.key.is_some());
+        assert_eq!(
+            ledger.used(&issuer.verify(&lease).unwrap().id),
+            agents[1].limits.check(&locator).unwrap()
+        );
+    }
+
+    #[test]
+    fn clients_verify_attestation_before_deriving() {
+        let (tmp, socket, plain_socket) = (test_key_file(), tempfile(), tempfile());
+
+        let session = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        let agent = Arc::new(
+            Agent::new(session, AgentPolicy::owner_only()).with_attester(MacQuotes([1u8; 32])),
+        );
+        let listener = agent.bind(socket.as_path()).unwrap();
+        thread::spawn(move || agent.serve(listener));
+
+        let mut client =
+            AgentClient::connect_attested(socket.as_path(), &MacQuotes([1u8; 32])).unwrap();
+        let (locator, key) = client.new_key().unwrap();
+        assert_eq!(client.get_key(&locator).unwrap(), key);
+        match AgentClient::connect_attested(socket.as_path(), &MacQuotes([2u8; 32])) {
+            Err(BigKeyError::AttestationFailed { .. }) => {}
+            _ => panic!("expected a quote from another platform to be refused"),
+        }
+
+        // a genuine agent's quote relayed by another process binds the agent's credentials, not
+        // those of the process the client is connected to
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        thread::spawn(move || {
+            let mut line = String::new();
+            BufReader::new(theirs.try_clone().unwrap())
+                .read_line(&mut line)
+                .unwrap();
+            let nonce = match serde_json::from_str(&line).unwrap() {
+                Request::Attest { nonce } => key_from_hex(&nonce).unwrap(),
+                _ => panic!("expected an attestation request"),
+            };
+            let relay = peer_credentials(&theirs).unwrap();
+            let agent = PeerCredentials {
+                pid: Some(relay.pid.unwrap_or(0) + 1),
+                ..relay
+            };
+            let data = report_data(nonce[..].try_into().unwrap(), &channel_binding(&agent));
+            let response = Response {
+                locator: None,
+                key: None,
+                quote: Some(MacQuotes([1u8; 32]).quote(&data).unwrap().to_hex()),
+                error: None,
+            };
+            send(&mut theirs, &response)
+        });
+        let mut client = AgentClient {
+            reader: BufReader::new(ours.try_clone().unwrap()),
+            writer: ours,
+            lease: None,
+        };
+        match client.attest(&MacQuotes([1u8; 32])) {
+            Err(BigKeyError::AttestationFailed { .. }) => {}
+            _ => panic!("expected a relayed quote to be refused"),
+        }
+
+        // agents without an attester cannot pass
+        let session = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        let plain = Arc::new(Agent::new(session, AgentPolicy::owner_only()));
+        let listener = plain.bind(plain_socket.as_path()).unwrap();
+        thread::spawn(move || plain.serve(listener));
+        match AgentClient::connect_attested(plain_socket.as_path(), &MacQuotes([1u8; 32])) {
+            Err(BigKeyError::AttestationFailed { reason }) => {
+                assert!(reason.contains("not configured"))
+            }
+            _ => panic!("expected an agent without attester to fail attestation"),
+        }
+    }
+} // mod test
@@ -0,0 +1,222 @@
+//! Remote attestation of key hosts.
+//!
+//! A client sending locators to a key host (see `crate::agent`) reveals which keys it uses to
+//! whoever answers. Where the host runs in a TPM-measured boot or a TEE, it can prove that
+//! before any locator is sent: the client picks a fresh nonce, the host answers with a quote
+//! over `report_data()` of that nonce, and the client verifies the quote against the
+//! measurements it expects.
+//!
+//! A nonce alone proves the quote is fresh, not that it came from the host at the other end of
+//! the connection: a relay could forward the nonce to a genuine host and pass its quote back.
+//! `report_data()` therefore also binds a channel binding both ends compute for their
+//! connection, e.g. the TLS exporter value of RFC 9266 or, for the agent's Unix socket, the
+//! agent's credentials (see `crate::agent::channel_binding`). A relayed quote binds the
+//! relay's connection to the host, not the client's connection to the relay, and fails
+//! verification.
+//!
+//! Producing and checking quotes is platform specific, so both sides are traits. `Attester` is
+//! implemented by the host, `QuoteVerifier` by the client. `CommandAttester` and
+//! `CommandVerifier` delegate to external programs, e.g. wrappers around `tpm2_quote` and
+//! `tpm2_checkquote`, or the quoting tools of a TEE.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::traits::{BigKeyError, SecretBytes};
+
+const REPORT_DATA_CONTEXT: &str = "big_fluffy_dise 2024 key host attestation v2";
+
+/// Length of the client's attestation nonce
+pub const NONCE_LEN: usize = 32;
+
+/// Produces attestation quotes on the key host
+pub trait Attester: Send + Sync {
+    /// A quote binding `report_data` to the host's measured state, e.g. as the qualifying data
+    /// of a TPM quote or the report data of a TEE report
+    fn quote(&self, report_data: &[u8; 32]) -> Result<Vec<u8>, BigKeyError>;
+}
+
+/// Verifies attestation quotes on the client
+pub trait QuoteVerifier {
+    /// Succeed only if `quote` is genuine, binds `report_data`, and attests a state the client
+    /// trusts; fail with `AttestationFailed` otherwise
+    fn verify(&self, quote: &[u8], report_data: &[u8; 32]) -> Result<(), BigKeyError>;
+}
+
+/// The data a quote answering `nonce` over the connection identified by `channel_binding` must
+/// bind. Derived from both rather than taken verbatim, so quotes made for this protocol cannot
+/// be replayed in others.
+pub fn report_data(nonce: &[u8; NONCE_LEN], channel_binding: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key(REPORT_DATA_CONTEXT);
+    hasher.update(nonce);
+    hasher.update(channel_binding);
+    *hasher.finalize().as_bytes()
+}
+
+/// A fresh random nonce for an attestation request
+pub fn new_nonce() -> Result<[u8; NONCE_LEN], BigKeyError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// Attester running `program` with the hex report data as its last argument and taking its
+/// standard output as the quote
+#[derive(Debug, Clone)]
+pub struct CommandAttester {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl CommandAttester {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        CommandAttester {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Pass `arg` to the program ahead of the report data
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+impl Attester for CommandAttester {
+    fn quote(&self, report_data: &[u8; 32]) -> Result<Vec<u8>, BigKeyError> {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .arg(report_data.to_hex())
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| failed(format!("cannot run {}: {}", self.program.display(), e)))?;
+        if !output.status.success() {
+            return Err(failed(format!(
+                "{} exited with {}",
+                self.program.display(),
+                output.status
+            )));
+        }
+        if output.stdout.is_empty() {
+            return Err(failed(format!(
+                "{} produced no quote",
+                self.program.display()
+            )));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Verifier running `program` with the hex report data as its last argument and the quote on
+/// its standard input, accepting the quote if the program exits successfully
+#[derive(Debug, Clone)]
+pub struct CommandVerifier {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl CommandVerifier {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        CommandVerifier {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Pass `arg` to the program ahead of the report data
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+impl QuoteVerifier for CommandVerifier {
+    fn verify(&self, quote: &[u8], report_data: &[u8; 32]) -> Result<(), BigKeyError> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(report_data.to_hex())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| failed(format!("cannot run {}: {}", self.program.display(), e)))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // a verifier rejecting early may close its input; its exit status decides
+            let _ = stdin.write_all(quote);
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(failed(format!(
+                "{} rejected the quote: {}",
+                self.program.display(),
+                status
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn failed(reason: String) -> BigKeyError {
+    BigKeyError::AttestationFailed { reason }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::attestation::{
+        new_nonce, report_data, Attester, CommandAttester, CommandVerifier, QuoteVerifier,
+    };
+    use crate::traits::{BigKeyError, SecretBytes};
+
+    #[test]
+    fn commands_quote_and_verify_report_data() {
+        let nonce = new_nonce().unwrap();
+        let data = report_data(&nonce, b"channel");
+        assert_ne!(data, report_data(&new_nonce().unwrap(), b"channel"));
+        assert_ne!(data, report_data(&nonce, b"another channel"));
+        assert_ne!(data, report_data(&nonce, b""));
+
+        // the "quote" is the report data itself, and verification compares it to stdin
+        let attester = CommandAttester::new("/bin/sh")
+            .arg("-c")
+            .arg("printf %s \"$0\"");
+        let quote = attester.quote(&data).unwrap();
+        assert_eq!(quote, data.to_hex().into_bytes());
+
+        let verifier = CommandVerifier::new("/bin/sh")
+            .arg("-c")
+            .arg("test \"$(cat)\" = \"$0\"");
+        verifier.verify(&quote, &data).unwrap();
+        assert!(matches!(
+            verifier.verify(&quote, &report_data(&nonce, b"another channel")),
+            Err(BigKeyError::AttestationFailed { .. })
+        ));
+        assert!(CommandAttester::new("/bin/false").quote(&data).is_err());
+    }
+
+    #[test]
+    fn broken_commands_fail_attestation() {
+        fn reason<T>(result: Result<T, BigKeyError>) -> String {
+            match result {
+                Err(BigKeyError::AttestationFailed { reason }) => reason,
+                _ => panic!("expected attestation to fail"),
+            }
+        }
+        let data = report_data(&new_nonce().unwrap(), b"channel");
+
+        let missing = "/nonexistent/quote-tool";
+        assert!(reason(CommandAttester::new(missing).quote(&data)).starts_with("cannot run"));
+        assert!(
+            reason(CommandVerifier::new(missing).verify(b"quote", &data)).starts_with("cannot run")
+        );
+        assert!(reason(CommandAttester::new("/bin/true").quote(&data)).contains("no quote"));
+
+        // a verifier exiting without reading its input still decides by its exit status
+        assert!(CommandVerifier::new("/bin/true")
+            .verify(&[0u8; 1 << 20], &data)
+            .is_ok());
+        reason(CommandVerifier::new("/bin/false").verify(&[0u8; 1 << 20], &data));
+    }
+} // mod test
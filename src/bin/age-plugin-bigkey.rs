@@ -0,0 +1,57 @@
+//! age plugin encrypting files to a BigKey.
+//!
+//! ```text
+//! age-plugin-bigkey KEYFILE
+//! ```
+//!
+//! prints the age identity of `KEYFILE`, preceded by its recipient in a comment, for use with
+//! `age -R` / `age -i`. `age` and `rage` run the plugin with `--age-plugin=recipient-v1` or
+//! `--age-plugin=identity-v1` themselves; see `big_fluffy_dise::age`.
+
+use std::io;
+use std::path::Path;
+use std::process;
+
+use big_fluffy_dise::age::{run_identity_v1, run_recipient_v1, AgeKeyFile};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("--age-plugin=recipient-v1") => run_recipient_v1(io::stdin().lock(), io::stdout()),
+        Some("--age-plugin=identity-v1") => run_identity_v1(io::stdin().lock(), io::stdout()),
+        Some(path) if args.len() == 1 && !path.starts_with('-') => {
+            print_identity(path);
+            Ok(())
+        }
+        _ => {
+            eprintln!("usage: age-plugin-bigkey KEYFILE");
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("age-plugin-bigkey: {}", e);
+        process::exit(1);
+    }
+}
+
+fn print_identity(path: &str) {
+    let path = match Path::new(path).canonicalize() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("cannot find key file {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let key_file = match path.to_str() {
+        Some(path) => AgeKeyFile::new(path),
+        None => {
+            eprintln!("key file path {} is not UTF-8", path.display());
+            process::exit(1);
+        }
+    };
+
+    println!("# key file: {}", key_file.path());
+    println!("# recipient: {}", key_file.recipient());
+    println!("{}", key_file.identity());
+}
@@ -0,0 +1,87 @@
+//! Local derivation daemon holding a BigKey open.
+//!
+//! ```text
+//! bigkey-agent KEYFILE SOCKET [--allow-uid UID]... [--allow-gid GID]... [--lease-key-file PATH]
+//!              [--attest-command PATH]
+//! ```
+//!
+//! opens `KEYFILE` and serves derivations on the Unix socket `SOCKET` to processes of the
+//! daemon's own user and of the allowed users and groups; see `big_fluffy_dise::agent`. With
+//! `--lease-key-file`, requests must also carry a lease token issued under the secret in `PATH`
+//! (see `big_fluffy_dise::lease`). With `--attest-command`, attestation requests are answered
+//! with the output of `PATH REPORT_DATA_HEX`, e.g. a wrapper around `tpm2_quote` (see
+//! `big_fluffy_dise::attestation`).
+
+use std::process;
+use std::sync::Arc;
+
+use big_fluffy_dise::agent::{Agent, AgentPolicy};
+use big_fluffy_dise::attestation::CommandAttester;
+use big_fluffy_dise::kem::{KemSession, SessionParams};
+use big_fluffy_dise::lease::LeaseIssuer;
+
+fn usage() -> ! {
+    eprintln!("usage: bigkey-agent KEYFILE SOCKET [--allow-uid UID]... [--allow-gid GID]... [--lease-key-file PATH] [--attest-command PATH]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 || !args.len().is_multiple_of(2) {
+        usage();
+    }
+    let (key_file, socket) = (&args[0], &args[1]);
+
+    let mut policy = AgentPolicy::owner_only();
+    let mut issuer = None;
+    let mut attester = None;
+    for option in args[2..].chunks(2) {
+        if option[0] == "--lease-key-file" {
+            match std::fs::read(&option[1]) {
+                Ok(secret) if secret.len() >= 32 => issuer = Some(LeaseIssuer::new(&secret)),
+                Ok(_) => {
+                    eprintln!("lease key file {} holds less than 32 bytes", option[1]);
+                    process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("cannot read lease key file {}: {}", option[1], e);
+                    process::exit(1);
+                }
+            }
+            continue;
+        }
+        if option[0] == "--attest-command" {
+            attester = Some(CommandAttester::new(&option[1]));
+            continue;
+        }
+        let id: u32 = option[1].parse().unwrap_or_else(|_| usage());
+        policy = match option[0].as_str() {
+            "--allow-uid" => policy.allow_uid(id),
+            "--allow-gid" => policy.allow_gid(id),
+            _ => usage(),
+        };
+    }
+
+    let session = match KemSession::open(key_file, SessionParams::default()) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("cannot open key file {}: {}", key_file, e);
+            process::exit(1);
+        }
+    };
+    let mut agent = Agent::new(session, policy);
+    if let Some(issuer) = issuer {
+        agent = agent.require_leases(issuer);
+    }
+    if let Some(attester) = attester {
+        agent = agent.with_attester(attester);
+    }
+    let agent = Arc::new(agent);
+    let result = agent
+        .bind(socket)
+        .and_then(|listener| Arc::clone(&agent).serve(listener));
+    if let Err(e) = result {
+        eprintln!("bigkey-agent: {}", e);
+        process::exit(1);
+    }
+}
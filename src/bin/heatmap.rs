@@ -0,0 +1,96 @@
+//! Aggregate a probe trace into a block coverage heatmap.
+//!
+//! ```text
+//! heatmap TRACE [BINS [text|csv|json]]
+//! ```
+//!
+//! `TRACE` is a CSV or JSON lines file written by `BigKey::with_probe_trace()`, `-` for stdin.
+//! The key's blocks are split into `BINS` ranges (default 256). `text` (the default) prints a
+//! summary and the shaded heatmap, `csv` one row per bin and `json` the counts.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::process;
+
+use big_fluffy_dise::kem::CoverageHeatmap;
+
+const DEFAULT_BINS: usize = 256;
+const COLUMNS: usize = 64;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = match args.first() {
+        Some(path) => path.as_str(),
+        None => {
+            eprintln!("usage: heatmap TRACE [BINS [text|csv|json]]");
+            process::exit(2);
+        }
+    };
+    let bins = match args.get(1).map(|b| b.parse::<usize>()) {
+        None => DEFAULT_BINS,
+        Some(Ok(bins)) if bins > 0 => bins,
+        Some(_) => {
+            eprintln!("BINS must be a positive number");
+            process::exit(2);
+        }
+    };
+
+    let trace: Box<dyn BufRead> = match path {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        path => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("cannot open {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+    };
+    let heatmap = match CoverageHeatmap::from_trace(trace, bins) {
+        Ok(heatmap) => heatmap,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    match args.get(2).map_or("text", String::as_str) {
+        "text" => print!("{}", text(&heatmap)),
+        "csv" => print!("{}", csv(&heatmap)),
+        "json" => println!("{}", serde_json::to_string_pretty(&heatmap).unwrap()),
+        other => {
+            eprintln!("unknown format '{}', expected text, csv or json", other);
+            process::exit(2);
+        }
+    }
+}
+
+fn text(heatmap: &CoverageHeatmap) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("blocks:       {}\n", heatmap.block_count));
+    out.push_str(&format!("derivations:  {}\n", heatmap.derivations));
+    out.push_str(&format!("probes:       {}\n", heatmap.probes));
+    out.push_str(&format!(
+        "chi-squared:  {:.1} ({} degrees of freedom)\n",
+        heatmap.chi_squared(),
+        heatmap.bins.len() - 1
+    ));
+    out.push_str("shading:      ' ' none, '=' uniform, '@' twice uniform or more\n\n");
+    out.push_str(&heatmap.render(COLUMNS));
+    out
+}
+
+fn csv(heatmap: &CoverageHeatmap) -> String {
+    let mut out = String::from("bin,first_block,end_block,probes,expected\n");
+    for (bin, count) in heatmap.bins.iter().enumerate() {
+        let blocks = heatmap.bin_blocks(bin);
+        out.push_str(&format!(
+            "{},{},{},{},{:.2}\n",
+            bin,
+            blocks.start,
+            blocks.end,
+            count,
+            heatmap.expected(bin)
+        ));
+    }
+    out
+}
@@ -0,0 +1,79 @@
+//! Print the KEM known-answer test vectors.
+//!
+//! ```text
+//! vectors [json|markdown]
+//! ```
+//!
+//! `json` (the default) emits the vectors for machine consumption, as published in
+//! `vectors/kem.json`; `markdown` renders them with a summary of the derivation as a
+//! specification appendix.
+
+use std::process;
+
+use big_fluffy_dise::kem::{generate_test_vectors, TestVector, LOCATOR_VERSION};
+
+fn main() {
+    let format = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "json".to_string());
+
+    let vectors = match generate_test_vectors() {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            eprintln!("failed to generate test vectors: {}", e);
+            process::exit(1);
+        }
+    };
+
+    match format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&vectors).unwrap()),
+        "markdown" => print!("{}", markdown(&vectors)),
+        other => {
+            eprintln!("unknown format '{}', expected json or markdown", other);
+            process::exit(2);
+        }
+    }
+}
+
+fn markdown(vectors: &[TestVector]) -> String {
+    let mut out = String::new();
+
+    out.push_str("# big_fluffy_dise KEM test vectors\n\n");
+    out.push_str(&format!(
+        "Locators are format version {}. For each vector:\n\n",
+        LOCATOR_VERSION
+    ));
+    out.push_str(
+        "1. the BigKey is the first `key_length` bytes of SHAKE256(`key_seed`), split into \
+         `block_size` byte blocks;\n\
+         2. probe `i` reads block `H(\"big_fluffy_dise probe index\" || selector || i)[0..8]` \
+         (big-endian) modulo the block count;\n\
+         3. the key is the first `security_level / 8` bytes of \
+         `H(\"big_fluffy_dise derived key\" || key_id || security_level || selector || \
+         (index || block)*)`, integers big-endian.\n\n",
+    );
+
+    for vector in vectors {
+        out.push_str(&format!(
+            "## {} / {} bits\n\n",
+            vector.hash, vector.security_level
+        ));
+        out.push_str("| field | value |\n|-------|-------|\n");
+        let rows = [
+            ("leakage_tolerance", vector.leakage_tolerance.to_string()),
+            ("block_size", vector.block_size.to_string()),
+            ("key_seed", vector.key_seed.clone()),
+            ("key_length", vector.key_length.to_string()),
+            ("key_fingerprint", vector.key_fingerprint.clone()),
+            ("locator", vector.locator.clone()),
+            ("probes", vector.probe_indices.len().to_string()),
+            ("derived_key", vector.derived_key.clone()),
+        ];
+        for (name, value) in rows.iter() {
+            out.push_str(&format!("| {} | `{}` |\n", name, value));
+        }
+        out.push('\n');
+    }
+
+    out
+}
@@ -0,0 +1,38 @@
+//! SIGINT / SIGTERM turned into a cancellation a long running command can stop cleanly on
+
+use std::sync::OnceLock;
+
+use big_fluffy_dise::storage::CancellationToken;
+
+static INTERRUPT: OnceLock<CancellationToken> = OnceLock::new();
+
+/// A token cancelled by the first SIGINT or SIGTERM. A second signal terminates the process as
+/// usual, so a command that stops slowly can still be killed.
+pub fn interrupt_token() -> CancellationToken {
+    INTERRUPT
+        .get_or_init(|| {
+            #[cfg(unix)]
+            unsafe {
+                libc::signal(libc::SIGINT, on_signal as *const () as libc::sighandler_t);
+                libc::signal(libc::SIGTERM, on_signal as *const () as libc::sighandler_t);
+            }
+            CancellationToken::new()
+        })
+        .clone()
+}
+
+/// Whether the process received SIGINT or SIGTERM since `interrupt_token()` was first called
+pub fn interrupted() -> bool {
+    INTERRUPT.get().is_some_and(CancellationToken::is_cancelled)
+}
+
+// Only async-signal-safe work here: an atomic store and re-arming the default action
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    if let Some(token) = INTERRUPT.get() {
+        token.cancel();
+    }
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
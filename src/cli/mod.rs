@@ -0,0 +1,7 @@
+//! Command line front-end helpers
+
+pub use interrupt::{interrupt_token, interrupted};
+pub use report::{Field, OutputFormat, Report};
+
+mod interrupt;
+mod report;
@@ -0,0 +1,166 @@
+//! Command results rendered as human readable text or JSON
+
+use std::fmt::Write;
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+/// How command results are printed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<OutputFormat> {
+        match name {
+            "text" => Some(OutputFormat::Text),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A single value in a `Report`
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(untagged)]
+pub enum Field {
+    Str(String),
+    Num(u64),
+    Bool(bool),
+    List(Vec<u64>),
+    Strs(Vec<String>),
+    Null,
+}
+
+/// Ordered set of named results produced by a command
+#[derive(Debug, Default)]
+pub struct Report {
+    fields: Vec<(&'static str, Field)>,
+    failed: bool,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report::default()
+    }
+
+    pub fn add(&mut self, name: &'static str, value: Field) -> &mut Self {
+        self.fields.push((name, value));
+        self
+    }
+
+    /// Mark the report of a command that ran but whose checks did not pass, so the process
+    /// exits unsuccessfully after printing it
+    pub fn fail(&mut self) -> &mut Self {
+        self.failed = true;
+        self
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Text => self.to_text(),
+            OutputFormat::Json => self.to_json(),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let width = self.fields.iter().map(|(n, _)| n.len()).max().unwrap_or(0) + 2;
+        let mut out = String::new();
+
+        for (name, value) in &self.fields {
+            let value = match value {
+                Field::Str(s) => s.clone(),
+                Field::Num(n) => n.to_string(),
+                Field::Bool(b) => b.to_string(),
+                Field::List(l) => format!("{:?}", l),
+                Field::Strs(l) => l.join(", "),
+                Field::Null => "none".to_string(),
+            };
+            let label = format!("{}:", name.replace('_', " "));
+            writeln!(out, "{:width$}{}", label, value, width = width).unwrap();
+        }
+
+        out
+    }
+
+    fn to_json(&self) -> String {
+        let mut out = serde_json::to_string(self).expect("reports serialize");
+        out.push('\n');
+        out
+    }
+}
+
+// A JSON object of the fields, in the order they were added
+impl Serialize for Report {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for (name, value) in &self.fields {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cli::{Field, OutputFormat, Report};
+
+    #[test]
+    fn json_rendering() {
+        let mut report = Report::new();
+        report
+            .add("file", Field::Str("a \"quoted\"\tpath".to_string()))
+            .add("size", Field::Num(4096))
+            .add("passed", Field::Bool(true))
+            .add("zero_blocks", Field::List(vec![1, 2]))
+            .add(
+                "labels",
+                Field::Strs(vec!["a".to_string(), "b\"".to_string()]),
+            )
+            .add("merkle_root", Field::Null);
+
+        assert_eq!(
+            report.render(OutputFormat::Json),
+            "{\"file\":\"a \\\"quoted\\\"\\tpath\",\"size\":4096,\"passed\":true,\
+             \"zero_blocks\":[1,2],\"labels\":[\"a\",\"b\\\"\"],\"merkle_root\":null}\n"
+        );
+
+        // control characters are escaped, other text is kept as is
+        let mut report = Report::new();
+        report
+            .add("error", Field::Str("bell\u{7} \\ caf\u{e9}".to_string()))
+            .add("list", Field::List(Vec::new()));
+        let json = report.render(OutputFormat::Json);
+        assert_eq!(
+            json,
+            "{\"error\":\"bell\\u0007 \\\\ caf\u{e9}\",\"list\":[]}\n"
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["error"], "bell\u{7} \\ caf\u{e9}");
+    }
+
+    #[test]
+    fn text_rendering() {
+        let mut report = Report::new();
+        report
+            .add("size", Field::Num(1))
+            .add("block_size", Field::Null);
+
+        assert_eq!(
+            report.render(OutputFormat::Text),
+            "size:       1\nblock size: none\n"
+        );
+        assert!(!report.failed());
+        report.fail();
+        assert!(report.failed());
+        assert_eq!(
+            report.render(OutputFormat::Text),
+            "size:       1\nblock size: none\n"
+        );
+    }
+} // mod test
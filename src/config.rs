@@ -0,0 +1,236 @@
+//! Declarative settings shared by the CLI and applications embedding the library.
+//!
+//! Settings are loaded from a TOML file and can be overridden by environment variables:
+//!
+//! ```toml
+//! key_path = "/srv/keys/big.key"
+//! block_size = 4096
+//! security_level = 256
+//! leakage_tolerance = 0.2
+//! max_derivation_io_bytes = 2097152
+//! ```
+//!
+//! | setting                   | environment variable            |
+//! |---------------------------|---------------------------------|
+//! | `key_path`                | `BFD_KEY_PATH`                  |
+//! | `block_size`              | `BFD_BLOCK_SIZE`                |
+//! | `security_level`          | `BFD_SECURITY_LEVEL`            |
+//! | `leakage_tolerance`       | `BFD_LEAKAGE_TOLERANCE`         |
+//! | `max_derivation_io_bytes` | `BFD_MAX_DERIVATION_IO_BYTES`   |
+
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::kem::probe_count;
+use crate::traits::{BigKeyError, BlockSize, SecurityLevel, BLOCK_4K};
+
+const ENV_KEY_PATH: &str = "BFD_KEY_PATH";
+const ENV_BLOCK_SIZE: &str = "BFD_BLOCK_SIZE";
+const ENV_SECURITY_LEVEL: &str = "BFD_SECURITY_LEVEL";
+const ENV_LEAKAGE_TOLERANCE: &str = "BFD_LEAKAGE_TOLERANCE";
+const ENV_MAX_DERIVATION_IO_BYTES: &str = "BFD_MAX_DERIVATION_IO_BYTES";
+
+/// Validated BigKey settings
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Location of the BigKey file
+    pub key_path: Option<String>,
+    /// `BlockSize` used to generate and probe the BigKey
+    pub block_size: BlockSize,
+    /// Security level of derived keys
+    pub security_level: SecurityLevel,
+    /// Fraction of the BigKey that may leak while retaining `security_level`
+    pub leakage_tolerance: f32,
+    /// Upper bound on bytes read from storage by a single key derivation
+    pub max_derivation_io_bytes: Option<u64>,
+}
+
+// On-disk representation, every setting is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    key_path: Option<String>,
+    block_size: Option<usize>,
+    security_level: Option<u32>,
+    leakage_tolerance: Option<f32>,
+    max_derivation_io_bytes: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            key_path: None,
+            block_size: BLOCK_4K,
+            security_level: SecurityLevel::Bits128,
+            leakage_tolerance: 0.2,
+            max_derivation_io_bytes: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load settings from the TOML file at `path` then apply environment overrides.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, BigKeyError> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut config = Config::from_toml(&contents)?;
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Default settings with environment overrides applied
+    pub fn from_env() -> Result<Config, BigKeyError> {
+        let mut config = Config::default();
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse settings from TOML, unset values keep their defaults
+    pub fn from_toml(contents: &str) -> Result<Config, BigKeyError> {
+        let file: ConfigFile = toml::from_str(contents).map_err(|e| invalid(e.to_string()))?;
+        let mut config = Config::default();
+
+        if let Some(path) = file.key_path {
+            config.key_path = Some(path);
+        }
+        if let Some(len) = file.block_size {
+            config.block_size = parse_block_size(len)?;
+        }
+        if let Some(bits) = file.security_level {
+            config.security_level = parse_security_level(bits)?;
+        }
+        if let Some(tolerance) = file.leakage_tolerance {
+            config.leakage_tolerance = tolerance;
+        }
+        if let Some(budget) = file.max_derivation_io_bytes {
+            config.max_derivation_io_bytes = Some(budget);
+        }
+
+        Ok(config)
+    }
+
+    /// Override settings with values returned by `lookup` for each `BFD_*` variable name
+    pub fn apply_overrides(
+        &mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), BigKeyError> {
+        if let Some(path) = lookup(ENV_KEY_PATH) {
+            self.key_path = Some(path);
+        }
+        if let Some(len) = lookup(ENV_BLOCK_SIZE) {
+            self.block_size = parse_block_size(parse_env(ENV_BLOCK_SIZE, &len)?)?;
+        }
+        if let Some(bits) = lookup(ENV_SECURITY_LEVEL) {
+            self.security_level = parse_security_level(parse_env(ENV_SECURITY_LEVEL, &bits)?)?;
+        }
+        if let Some(tolerance) = lookup(ENV_LEAKAGE_TOLERANCE) {
+            self.leakage_tolerance = parse_env(ENV_LEAKAGE_TOLERANCE, &tolerance)?;
+        }
+        if let Some(budget) = lookup(ENV_MAX_DERIVATION_IO_BYTES) {
+            self.max_derivation_io_bytes = Some(parse_env(ENV_MAX_DERIVATION_IO_BYTES, &budget)?);
+        }
+
+        Ok(())
+    }
+
+    /// Check the settings are consistent: leakage tolerance in range and derivation cost within
+    /// budget.
+    pub fn validate(&self) -> Result<(), BigKeyError> {
+        let probes = probe_count(self.security_level, self.leakage_tolerance, self.block_size)?;
+        let estimated = probes * self.block_size.byte_len as u64;
+
+        match self.max_derivation_io_bytes {
+            Some(budget) if estimated > budget => {
+                Err(BigKeyError::DerivationBudgetExceeded { estimated, budget })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse_block_size(len: usize) -> Result<BlockSize, BigKeyError> {
+    BlockSize::from_byte_len(len).ok_or_else(|| invalid(format!("unsupported block size {}", len)))
+}
+
+fn parse_security_level(bits: u32) -> Result<SecurityLevel, BigKeyError> {
+    SecurityLevel::from_bits(bits)
+        .ok_or_else(|| invalid(format!("unsupported security level {}", bits)))
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> Result<T, BigKeyError> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("cannot parse {}={:?}", name, value)))
+}
+
+fn invalid(reason: String) -> BigKeyError {
+    BigKeyError::InvalidConfig { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::config::Config;
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K, BLOCK_4K};
+
+    #[test]
+    fn toml_settings_are_loaded() {
+        let config = Config::from_toml(
+            r#"
+            key_path = "/srv/big.key"
+            block_size = 1024
+            security_level = 256
+            leakage_tolerance = 0.5
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.key_path.as_deref(), Some("/srv/big.key"));
+        assert_eq!(config.block_size.byte_len, BLOCK_1K.byte_len);
+        assert!(matches!(config.security_level, SecurityLevel::Bits256));
+        assert_eq!(config.leakage_tolerance, 0.5);
+        assert_eq!(config.max_derivation_io_bytes, None);
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let mut config = Config::from_toml("block_size = 1024").unwrap();
+        let env: HashMap<&str, &str> = [("BFD_BLOCK_SIZE", "4096"), ("BFD_KEY_PATH", "/k")]
+            .iter()
+            .cloned()
+            .collect();
+
+        config
+            .apply_overrides(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+
+        assert_eq!(config.block_size.byte_len, BLOCK_4K.byte_len);
+        assert_eq!(config.key_path.as_deref(), Some("/k"));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        for contents in ["block_size = 1000", "security_level = 64", "unknown = 1"].iter() {
+            match Config::from_toml(contents) {
+                Err(BigKeyError::InvalidConfig { .. }) => {}
+                _ => panic!("expected {:?} to be rejected", contents),
+            }
+        }
+    }
+
+    #[test]
+    fn derivation_budget_is_enforced() {
+        let mut config = Config::from_toml("max_derivation_io_bytes = 4096").unwrap();
+        match config.validate() {
+            Err(BigKeyError::DerivationBudgetExceeded { .. }) => {}
+            _ => panic!("expected derivation budget to be exceeded"),
+        }
+
+        config.max_derivation_io_bytes = Some(1 << 30);
+        config.validate().unwrap();
+    }
+} // mod test
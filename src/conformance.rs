@@ -0,0 +1,705 @@
+//! Conformance checks for third-party implementations of the crate's traits.
+//!
+//! A storage backend, generator or KEM written outside this crate can run these checks against
+//! itself to confirm it follows the semantics the rest of the crate relies on:
+//!
+//! * `check_storage_reader()`: lengths, bounds checking, block contents and repeatable probes
+//! * `check_generator()`: the stream is a function of the seed alone, however it is split up
+//!   and across `state()` / `restore()`
+//! * `check_kem()`: keys of the requested length that re-derive from their locators, fresh keys
+//!   on every call, rejection of malformed locators
+//! * `check_kem_agreement()`: two instances over the same BigKey derive the same keys
+//! * `check_differential()`: `BigKey` derivations over a storage backend, under randomized
+//!   configurations, match `reference_key()`, a deliberately naive derivation from the whole
+//!   key held in memory
+//!
+//! Each check returns the first violation as `BigKeyError::ConformanceFailed`, naming the check
+//! and what went wrong. Call them from the implementation's own tests:
+//!
+//! ```
+//! use std::io::Cursor;
+//!
+//! use big_fluffy_dise::conformance::check_storage_reader;
+//! use big_fluffy_dise::storage::ReadSeekStorage;
+//! use big_fluffy_dise::traits::BLOCK_1K;
+//!
+//! let key: Vec<u8> = (0..16 * 1024u32).map(|i| (i % 251) as u8).collect();
+//! let mut reader = ReadSeekStorage::new(Cursor::new(key.clone()), BLOCK_1K)?;
+//! check_storage_reader(&mut reader, &key)?;
+//! # Ok::<(), big_fluffy_dise::traits::BigKeyError>(())
+//! ```
+
+use std::convert::TryInto;
+
+use digest::Digest;
+
+use crate::generation::BigKeyGenerator;
+use crate::kem::{
+    builtin, AppId, BigKey, BigKeyKem, ExcludeEnds, ExcludeRanges, LocatorBody, APP_ID_LEN,
+};
+use crate::storage::StorageReader;
+use crate::traits::{
+    BigKeyError, BlockCount, BlockIndex, BlockSize, KeyMaterial, Locator, SecurityLevel,
+};
+
+// Bytes of generator output compared by `check_generator()`, deliberately not a multiple of
+// any block or hash output size
+const GENERATOR_SAMPLE_LEN: usize = 64 * 1024 + 13;
+
+// Keys derived by `check_kem()` and `check_kem_agreement()`
+const KEM_SAMPLE_KEYS: usize = 8;
+
+// Domains of the derivation, spelled out again rather than shared with `BigKey`
+const REFERENCE_PROBE_DOMAIN: &[u8] = b"big_fluffy_dise probe index";
+const REFERENCE_KEY_DOMAIN: &[u8] = b"big_fluffy_dise derived key";
+
+const DIFFERENTIAL_CONTEXT: &str = "big_fluffy_dise 2024 differential test cases v1";
+
+/// Check that `reader` holds exactly `expected` and behaves like the crate's own backends:
+/// consistent length and block size, every block reads back as in `expected` in any order,
+/// probes beyond the end of the key or with a wrongly sized buffer fail instead of panicking or
+/// returning data.
+pub fn check_storage_reader<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    expected: &[u8],
+) -> Result<(), BigKeyError> {
+    let block_len = reader.block_size().byte_len;
+    let length = reader.big_key_length();
+    if block_len == 0 || block_len * 8 != reader.block_size().bit_len {
+        return fail(
+            "block size",
+            format!("inconsistent block size {:?}", reader.block_size()),
+        );
+    }
+    if length != expected.len() as u64 {
+        return fail(
+            "length",
+            format!(
+                "big_key_length() is {}, expected {}",
+                length,
+                expected.len()
+            ),
+        );
+    }
+    if length == 0 || !length.is_multiple_of(block_len as u64) {
+        return fail(
+            "length",
+            format!(
+                "length {} is not a whole number of {} byte blocks",
+                length, block_len
+            ),
+        );
+    }
+    let block_count = BlockCount::new(length / block_len as u64);
+
+    let mut block = vec![0u8; block_len];
+    // forwards, then backwards to catch readers that only work sequentially
+    for index in block_count.indices().chain(block_count.indices().rev()) {
+        reader
+            .probe(index, &mut block)
+            .map_err(|e| failure("blocks", format!("probe of block {} failed: {}", index, e)))?;
+        let offset = index.get() as usize * block_len;
+        if block[..] != expected[offset..offset + block_len] {
+            return fail(
+                "blocks",
+                format!("block {} differs from the expected key", index),
+            );
+        }
+    }
+
+    for index in [
+        block_count.get(),
+        block_count.get() + 1,
+        u64::MAX / block_len as u64,
+        u64::MAX,
+    ]
+    .iter()
+    {
+        if reader.probe(BlockIndex::new(*index), &mut block).is_ok() {
+            return fail(
+                "bounds",
+                format!("probe of block {} beyond the key succeeded", index),
+            );
+        }
+    }
+    for len in [block_len - 1, block_len + 1].iter() {
+        if reader
+            .probe(BlockIndex::new(0), &mut vec![0u8; *len])
+            .is_ok()
+        {
+            return fail(
+                "bounds",
+                format!(
+                    "probe into a {} byte buffer of {} byte blocks succeeded",
+                    len, block_len
+                ),
+            );
+        }
+    }
+
+    // a failed probe must not disturb later ones
+    let last = BlockIndex::new(block_count.get() - 1);
+    reader
+        .probe(last, &mut block)
+        .map_err(|e| failure("determinism", format!("probe after a failed probe: {}", e)))?;
+    if block[..] != expected[last.get() as usize * block_len..] {
+        return fail(
+            "determinism",
+            "block read after a failed probe differs".to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Check that generator `G` produces a stream determined by `seed` alone: output is the same
+/// however `fill()` calls split it and after a `state()` / `restore()` round trip, and differs
+/// for a different seed. Not applicable to generators that ignore their seed, such as
+/// `HwRngGenerator`.
+pub fn check_generator<G: BigKeyGenerator>(seed: &[u8]) -> Result<(), BigKeyError> {
+    let new = || G::new(Some(seed.into()));
+
+    let mut whole = Vec::with_capacity(GENERATOR_SAMPLE_LEN);
+    new()?.fill(&mut whole, GENERATOR_SAMPLE_LEN)?;
+    if whole.len() != GENERATOR_SAMPLE_LEN {
+        return fail(
+            "length",
+            format!(
+                "fill() wrote {} of {} bytes",
+                whole.len(),
+                GENERATOR_SAMPLE_LEN
+            ),
+        );
+    }
+
+    let mut again = Vec::with_capacity(GENERATOR_SAMPLE_LEN);
+    new()?.fill(&mut again, GENERATOR_SAMPLE_LEN)?;
+    if again != whole {
+        return fail(
+            "determinism",
+            "the same seed produced different output".to_string(),
+        );
+    }
+
+    let mut pieces = Vec::with_capacity(GENERATOR_SAMPLE_LEN);
+    let mut generator = new()?;
+    let mut filled = 0;
+    for len in [1, 0, 31, 4096, 7].iter().copied().cycle() {
+        let len = len.min(GENERATOR_SAMPLE_LEN - filled);
+        generator.fill(&mut pieces, len)?;
+        filled += len;
+        if filled == GENERATOR_SAMPLE_LEN {
+            break;
+        }
+    }
+    if pieces != whole {
+        return fail(
+            "stream",
+            "output depends on how fill() calls split it".to_string(),
+        );
+    }
+
+    let half = GENERATOR_SAMPLE_LEN / 2;
+    let mut resumed = Vec::with_capacity(GENERATOR_SAMPLE_LEN);
+    let mut generator = new()?;
+    generator.fill(&mut resumed, half)?;
+    let mut restored = G::restore(&generator.state())?;
+    restored.fill(&mut resumed, GENERATOR_SAMPLE_LEN - half)?;
+    if resumed != whole {
+        return fail(
+            "state",
+            "restore(state()) does not continue the stream".to_string(),
+        );
+    }
+
+    let mut other_seed = seed.to_vec();
+    match other_seed.last_mut() {
+        Some(last) => *last ^= 1,
+        None => other_seed.push(1),
+    }
+    let mut other = Vec::with_capacity(GENERATOR_SAMPLE_LEN);
+    G::new(Some(other_seed.into()))?.fill(&mut other, GENERATOR_SAMPLE_LEN)?;
+    if other == whole {
+        return fail(
+            "seed",
+            "different seeds produced the same output".to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Check that `kem` derives `security_level / 8` byte keys that `get_key()` re-derives from
+/// their locators, repeatably, that every `new_key()` gives a fresh locator and key, and that
+/// empty or truncated locators are rejected.
+pub fn check_kem<K, S, H>(kem: &mut K, security_level: SecurityLevel) -> Result<(), BigKeyError>
+where
+    K: BigKeyKem<S, H>,
+    S: StorageReader,
+    H: Digest,
+{
+    let derived = new_keys(kem, security_level)?;
+
+    for (locator, key) in derived.iter() {
+        if key.len() != security_level as usize / 8 {
+            return fail(
+                "key length",
+                format!("{} byte key at {} bits", key.len(), security_level as usize),
+            );
+        }
+        for _ in 0..2 {
+            if kem.get_key(locator)? != *key {
+                return fail("agreement", "get_key() differs from new_key()".to_string());
+            }
+        }
+    }
+
+    for (i, (locator, key)) in derived.iter().enumerate() {
+        if derived[..i].iter().any(|(l, k)| l == locator || k == key) {
+            return fail(
+                "freshness",
+                "new_key() repeated a locator or key".to_string(),
+            );
+        }
+    }
+
+    let (locator, _) = &derived[0];
+    if kem.get_key(&Locator::default()).is_ok() {
+        return fail("bounds", "an empty locator was accepted".to_string());
+    }
+    if kem
+        .get_key(&locator.as_bytes()[..locator.len() - 1].into())
+        .is_ok()
+    {
+        return fail("bounds", "a truncated locator was accepted".to_string());
+    }
+    Ok(())
+}
+
+/// Check that locators of `a` re-derive the same keys in `b` and vice versa, for two instances
+/// configured alike over copies of the same BigKey (e.g. another backend holding the key)
+pub fn check_kem_agreement<K1, K2, S1, S2, H1, H2>(
+    a: &mut K1,
+    b: &mut K2,
+    security_level: SecurityLevel,
+) -> Result<(), BigKeyError>
+where
+    K1: BigKeyKem<S1, H1>,
+    K2: BigKeyKem<S2, H2>,
+    S1: StorageReader,
+    S2: StorageReader,
+    H1: Digest,
+    H2: Digest,
+{
+    for (locator, key) in new_keys(a, security_level)? {
+        if b.get_key(&locator)? != key {
+            return fail(
+                "agreement",
+                "second instance derived a different key".to_string(),
+            );
+        }
+    }
+    for (locator, key) in new_keys(b, security_level)? {
+        if a.get_key(&locator)? != key {
+            return fail(
+                "agreement",
+                "first instance derived a different key".to_string(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Key of `locator` derived the slow, obviously correct way: with the whole BigKey `key` of
+/// `block_size` blocks in memory, hashing each probed block sliced straight out of it. Shares
+/// nothing with `BigKey` but locator parsing and the built-in probe distributions, so it serves
+/// as the reference optimized derivation paths are checked against.
+///
+/// Only derives: locator MACs, probe checks and key ids are not checked, and peer bound
+/// locators are refused.
+pub fn reference_key<H: Digest>(
+    key: &[u8],
+    block_size: BlockSize,
+    locator: &Locator,
+) -> Result<KeyMaterial, BigKeyError> {
+    let body = LocatorBody::decode(locator.as_bytes())?;
+    if body.peer_bound {
+        return Err(BigKeyError::InvalidLocator {
+            reason: "the reference derivation does not support peer bound locators",
+        });
+    }
+    let key_len = body.security_level as usize / 8;
+    if H::output_size() < key_len.max(8) {
+        return Err(BigKeyError::DigestTooShort {
+            digest_len: H::output_size(),
+            key_len,
+        });
+    }
+    let blocks: Vec<&[u8]> = key.chunks_exact(block_size.byte_len).collect();
+    if blocks.is_empty() {
+        return Err(BigKeyError::OutputLengthTooShort {
+            out_len: key.len(),
+            min_len: block_size.byte_len,
+        });
+    }
+    let distribution = builtin(&body.distribution)?;
+
+    let mut key_hash = H::new();
+    key_hash.update(REFERENCE_KEY_DOMAIN);
+    key_hash.update(body.key_id.to_be_bytes());
+    key_hash.update((body.security_level as u16).to_be_bytes());
+    key_hash.update(body.selector);
+    if let Some(app_id) = &body.app_id {
+        key_hash.update(app_id.as_bytes());
+    }
+    for i in 0..body.probe_count as u64 {
+        let mut probe_hash = H::new();
+        probe_hash.update(REFERENCE_PROBE_DOMAIN);
+        if let Some(app_id) = &body.app_id {
+            probe_hash.update(app_id.as_bytes());
+        }
+        probe_hash.update(body.selector);
+        probe_hash.update(i.to_be_bytes());
+        let sample = u64::from_be_bytes(probe_hash.finalize()[..8].try_into().unwrap());
+
+        let index = distribution.index(sample, BlockCount::new(blocks.len() as u64))?;
+        key_hash.update(index.get().to_be_bytes());
+        key_hash.update(blocks[index.get() as usize]);
+    }
+
+    let digest = key_hash.finalize();
+    match &body.hardening {
+        Some(hardening) => hardening.apply(&digest, &body.selector, key_len),
+        None => Ok(digest[..key_len].into()),
+    }
+}
+
+/// Check that `BigKey` derivations over `reader` match `reference_key()` over `expected`, the
+/// key `reader` should hold, for `cases` randomly configured BigKeys. Security level, leakage
+/// tolerance, key id, probe distribution, application namespace, probe checks and locator MACs
+/// are drawn from `seed`, so a failing case reproduces with the same seed. Each case checks
+/// both `new_key()` and `get_key()`.
+pub fn check_differential<R, H>(
+    reader: &mut R,
+    expected: &[u8],
+    cases: usize,
+    seed: &[u8],
+) -> Result<(), BigKeyError>
+where
+    R: StorageReader + ?Sized,
+    H: Digest,
+{
+    let block_size = reader.block_size();
+    let block_count = reader.big_key_length() / block_size.byte_len as u64;
+    let mut rng = blake3::Hasher::new_derive_key(DIFFERENTIAL_CONTEXT);
+    rng.update(seed);
+    let mut rng = rng.finalize_xof();
+    let mut draw = |bound: u64| {
+        let mut bytes = [0u8; 8];
+        rng.fill(&mut bytes);
+        u64::from_be_bytes(bytes) % bound.max(1)
+    };
+
+    for case in 0..cases {
+        let security_level = match draw(2) {
+            0 => SecurityLevel::Bits128,
+            _ => SecurityLevel::Bits256,
+        };
+        let leakage_tolerance = [0.05f32, 0.2, 0.4][draw(3) as usize];
+        let key_id = draw(1 << 32) as u32;
+        let mut config = format!(
+            "case {}: {} bits, leakage {}, key id {}",
+            case, security_level as u32, leakage_tolerance, key_id
+        );
+
+        let mut big_key =
+            BigKey::new_big_key(security_level, leakage_tolerance, &mut *reader, H::new())
+                .with_key_id(key_id);
+        big_key = match draw(3) {
+            1 => {
+                let head = draw(block_count / 2);
+                let tail = draw(block_count - head);
+                config += &format!(", excluding {} head and {} tail blocks", head, tail);
+                big_key.with_probe_distribution(ExcludeEnds { head, tail })
+            }
+            2 if block_count >= 2 => {
+                let start = draw(block_count);
+                let end = start + 1 + draw(block_count / 2);
+                config += &format!(", excluding blocks {}..{}", start, end);
+                big_key.with_probe_distribution(ExcludeRanges::new(&[(start, end)])?)
+            }
+            _ => big_key,
+        };
+        if draw(2) == 1 {
+            let mut app_id = [0u8; APP_ID_LEN];
+            app_id.iter_mut().for_each(|b| *b = draw(256) as u8);
+            config += ", namespaced";
+            big_key = big_key.with_app_id(AppId::from_bytes(app_id));
+        }
+        if draw(2) == 1 {
+            config += ", probe check";
+            big_key = big_key.with_probe_check();
+        }
+        if draw(2) == 1 {
+            config += ", locator MAC";
+            big_key = big_key.with_locator_mac();
+        }
+
+        let (locator, key) = big_key.new_key(security_level)?;
+        let reference = reference_key::<H>(expected, block_size, &locator)?;
+        if key != reference {
+            return fail(
+                "differential",
+                format!("{}: new_key() differs from the reference", config),
+            );
+        }
+        if big_key.get_key(&locator)? != reference {
+            return fail(
+                "differential",
+                format!("{}: get_key() differs from the reference", config),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn new_keys<K, S, H>(
+    kem: &mut K,
+    security_level: SecurityLevel,
+) -> Result<Vec<(Locator, KeyMaterial)>, BigKeyError>
+where
+    K: BigKeyKem<S, H>,
+    S: StorageReader,
+    H: Digest,
+{
+    (0..KEM_SAMPLE_KEYS)
+        .map(|_| kem.new_key(security_level))
+        .collect()
+}
+
+fn failure(check: &'static str, reason: String) -> BigKeyError {
+    BigKeyError::ConformanceFailed { check, reason }
+}
+
+fn fail(check: &'static str, reason: String) -> Result<(), BigKeyError> {
+    Err(failure(check, reason))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use sha3::{Digest, Sha3_256};
+
+    use sha3::Sha3_512;
+
+    use crate::conformance::{
+        check_differential, check_generator, check_kem, check_kem_agreement, check_storage_reader,
+        reference_key,
+    };
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::kem::{BigKey, BigKeyKem};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{
+        pack, ContainerStorage, DiskStorage, Fault, FaultyStorage, OramOptions, OramStorage,
+        PinnedStorage, ReadSeekStorage, RechunkedReader, StorageReader, StorageWriter,
+    };
+    use crate::traits::{
+        BigKeyError, BlockIndex, BlockSize, GeneratorId, Locator, SecurityLevel, BLOCK_1K, BLOCK_4K,
+    };
+
+    const SEED: &[u8; 32] = b"big_fluffy_dise conformance seed";
+
+    // Reader returning zeros past the end of the key instead of failing
+    struct Lenient(ReadSeekStorage<Cursor<Vec<u8>>>);
+
+    impl StorageReader for Lenient {
+        fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+            match self.0.block_count().contains(index) {
+                true => self.0.probe(index, output),
+                false => {
+                    output.iter_mut().for_each(|b| *b = 0);
+                    Ok(())
+                }
+            }
+        }
+
+        fn big_key_length(&self) -> u64 {
+            self.0.big_key_length()
+        }
+
+        fn block_size(&self) -> BlockSize {
+            self.0.block_size()
+        }
+    }
+
+    #[test]
+    fn crate_implementations_conform() {
+        let mut key = Vec::new();
+        Shake256Generator::new(Some(SEED.to_vec().into()))
+            .unwrap()
+            .fill(&mut key, 64 * 1024)
+            .unwrap();
+
+        let tmp = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), key.len()).unwrap();
+        Shake256Generator::generate(&mut writer, Some(SEED.to_vec().into()), key.len()).unwrap();
+        drop(writer);
+        let mut disk = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        check_storage_reader(&mut disk, &key).unwrap();
+        let mut memory = ReadSeekStorage::new(Cursor::new(key.clone()), BLOCK_1K).unwrap();
+        check_storage_reader(&mut memory, &key).unwrap();
+
+        check_generator::<Shake256Generator>(SEED).unwrap();
+
+        let mut a = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, disk, Sha3_256::new());
+        let mut b = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, memory, Sha3_256::new())
+            .with_locator_mac();
+        check_kem(&mut a, SecurityLevel::Bits128).unwrap();
+        check_kem(&mut b, SecurityLevel::Bits256).unwrap();
+        // a does not authenticate locators, so only a's locators are understood by both
+        assert!(check_kem_agreement(&mut a, &mut b, SecurityLevel::Bits128).is_err());
+        let mut b = BigKey::new_big_key(
+            SecurityLevel::Bits128,
+            0.2,
+            b.into_storage(),
+            Sha3_256::new(),
+        );
+        check_kem_agreement(&mut a, &mut b, SecurityLevel::Bits128).unwrap();
+    }
+
+    #[test]
+    fn violations_are_reported() {
+        let key: Vec<u8> = (0..16 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let mut lenient =
+            Lenient(ReadSeekStorage::new(Cursor::new(key.clone()), BLOCK_1K).unwrap());
+        match check_storage_reader(&mut lenient, &key) {
+            Err(BigKeyError::ConformanceFailed {
+                check: "bounds", ..
+            }) => {}
+            other => panic!("expected a bounds failure, got {:?}", other),
+        }
+        match check_storage_reader(&mut lenient, &key[1024..]) {
+            Err(BigKeyError::ConformanceFailed {
+                check: "length", ..
+            }) => {}
+            other => panic!("expected a length failure, got {:?}", other),
+        }
+        let mut other = key.clone();
+        other[5000] ^= 1;
+        match check_storage_reader(&mut lenient, &other) {
+            Err(BigKeyError::ConformanceFailed {
+                check: "blocks",
+                reason,
+            }) => {
+                assert!(reason.contains("block 4 "), "{}", reason)
+            }
+            other => panic!("expected a blocks failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn overflowing_probes_are_out_of_bounds() {
+        let key: Vec<u8> = (0..16 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let tmp = tempfile();
+        let mut writer =
+            DiskStorage::new_writer_with_checksums(BLOCK_1K, tmp.to_str(), key.len()).unwrap();
+        writer.permute_blocks().unwrap();
+        Shake256Generator::generate(&mut writer, Some(SEED.to_vec().into()), key.len()).unwrap();
+        drop(writer);
+        let mut disk = DiskStorage::open_with_checksums(BLOCK_1K, tmp.to_str()).unwrap();
+
+        let container = tempfile();
+        pack(&mut disk, GeneratorId::Shake256, container.to_str()).unwrap();
+        let oram = OramStorage::setup(
+            &mut disk,
+            Cursor::new(Vec::new()),
+            &[7u8; 32],
+            &OramOptions::default(),
+        )
+        .unwrap();
+        let memory = ReadSeekStorage::new(Cursor::new(key.clone()), BLOCK_1K).unwrap();
+        let coarse = ReadSeekStorage::new(Cursor::new(key.clone()), BLOCK_4K).unwrap();
+
+        let mut readers: Vec<Box<dyn StorageReader>> = vec![
+            Box::new(disk),
+            Box::new(ContainerStorage::open_verified(container.to_str()).unwrap()),
+            Box::new(oram),
+            Box::new(RechunkedReader::new(coarse, BLOCK_1K).unwrap()),
+            Box::new(PinnedStorage::new(memory, &[0, 15]).unwrap()),
+        ];
+        let mut block = vec![0u8; 1024];
+        for reader in readers.iter_mut() {
+            // offsets ending exactly at, just past and far past the end of the u64 range
+            for index in [16, u64::MAX / 1024, u64::MAX / 1024 + 1, u64::MAX].iter() {
+                match reader.probe(BlockIndex::new(*index), &mut block) {
+                    Err(BigKeyError::ProbeOffsetOutOfBounds { .. }) => {}
+                    other => panic!("probe of block {} returned {:?}", index, other),
+                }
+            }
+            reader.probe(BlockIndex::new(15), &mut block).unwrap();
+        }
+    }
+
+    #[test]
+    fn derivation_paths_match_the_reference() {
+        let mut key = Vec::new();
+        Shake256Generator::new(Some(SEED.to_vec().into()))
+            .unwrap()
+            .fill(&mut key, 96 * 1024)
+            .unwrap();
+
+        let plain = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, plain.to_str(), key.len()).unwrap();
+        Shake256Generator::generate(&mut writer, Some(SEED.to_vec().into()), key.len()).unwrap();
+        drop(writer);
+        let mut disk = DiskStorage::open(BLOCK_1K, plain.to_str()).unwrap();
+        check_differential::<_, Sha3_256>(&mut disk, &key, 16, b"disk").unwrap();
+        check_differential::<_, Sha3_512>(&mut disk, &key, 4, b"disk").unwrap();
+
+        let permuted = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, permuted.to_str(), key.len()).unwrap();
+        writer.permute_blocks().unwrap();
+        Shake256Generator::generate(&mut writer, Some(SEED.to_vec().into()), key.len()).unwrap();
+        drop(writer);
+        let mut disk = DiskStorage::open(BLOCK_1K, permuted.to_str()).unwrap();
+        check_differential::<_, Sha3_256>(&mut disk, &key, 16, b"permuted").unwrap();
+
+        let mut memory = ReadSeekStorage::new(Cursor::new(key.clone()), BLOCK_1K).unwrap();
+        check_differential::<_, Sha3_256>(&mut memory, &key, 16, b"memory").unwrap();
+
+        let container = tempfile();
+        pack(&mut memory, GeneratorId::Shake256, container.to_str()).unwrap();
+        let mut container = ContainerStorage::open(container.to_str()).unwrap();
+        check_differential::<_, Sha3_256>(&mut container, &key, 16, b"container").unwrap();
+
+        let even: Vec<u64> = (0..96).step_by(2).collect();
+        let mut pinned = PinnedStorage::new(memory, &even).unwrap();
+        check_differential::<_, Sha3_256>(&mut pinned, &key, 16, b"pinned").unwrap();
+    }
+
+    #[test]
+    fn differential_failures_name_the_case() {
+        let key: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let memory = ReadSeekStorage::new(Cursor::new(key.clone()), BLOCK_1K).unwrap();
+        let mut faulty = FaultyStorage::new(memory);
+        faulty.inject(17, Fault::Corrupt);
+        match check_differential::<_, Sha3_256>(&mut faulty, &key, 16, b"faulty") {
+            Err(BigKeyError::ConformanceFailed {
+                check: "differential",
+                reason,
+            }) => assert!(reason.starts_with("case "), "{}", reason),
+            other => panic!("expected a differential failure, got {:?}", other),
+        }
+
+        // the reference derives without a storage backend, and refuses what it cannot derive
+        let mut memory = faulty.into_inner();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut memory, Sha3_256::new());
+        let (locator, derived) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(
+            reference_key::<Sha3_256>(&key, BLOCK_1K, &locator).unwrap(),
+            derived
+        );
+        assert!(reference_key::<Sha3_256>(&[], BLOCK_1K, &locator).is_err());
+        let truncated = Locator::from(&locator.as_bytes()[..10]);
+        assert!(reference_key::<Sha3_256>(&key, BLOCK_1K, &truncated).is_err());
+    }
+} // mod test
@@ -0,0 +1,321 @@
+//! Small deterministic BigKeys for the integration tests of applications using this crate.
+//!
+//! Generating a real BigKey takes minutes; a test only needs a key file that behaves like one.
+//! `fixture()` writes a small key file expanded with SHAKE256 from a fixed seed, and derives a
+//! few keys from it with `SeededRandomness`, so the file, the locators and the keys are the
+//! same on every machine and every run:
+//!
+//! ```ignore
+//! let fixture = fixture(FixtureKey::Tiny)?;
+//! let mut big_key = fixture.open()?;
+//! let vector = &fixture.vectors[0];
+//! assert_eq!(big_key.get_key(&vector.locator)?, vector.key);
+//! ```
+//!
+//! Files are kept in `fixture_dir()`: `<name>.key` and `<name>.json`, the latter holding the
+//! vectors as hex for tests not written in Rust. Existing files are reused if their contents
+//! match, so only the first test of a run pays for generating them.
+//!
+//! Fixture keys are public and far too small for real use. Only built with the `test-fixtures`
+//! feature.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::helpers::{generate_key_file, open_big_key_with, DiskBigKey, GenerateOptions};
+use crate::kem::{BigKeyKem, SeededRandomness};
+use crate::storage::{fingerprint, DiskStorage};
+use crate::traits::{
+    BigKeyError, BlockSize, KeyMaterial, Locator, SecretBytes, SecurityLevel, BLOCK_1K, BLOCK_4K,
+};
+
+/// Environment variable overriding `fixture_dir()`
+pub const FIXTURE_DIR_ENV: &str = "BIGKEY_FIXTURE_DIR";
+
+/// Leakage tolerance fixture keys are opened and derived with
+pub const FIXTURE_LEAKAGE_TOLERANCE: f32 = 0.2;
+
+// Keys derived per security level
+const VECTORS_PER_LEVEL: usize = 2;
+const SECURITY_LEVELS: [SecurityLevel; 2] = [SecurityLevel::Bits128, SecurityLevel::Bits256];
+
+// Distinguishes the temporary files of concurrent writers in one process
+static WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The fixture keys
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FixtureKey {
+    /// 128 KiB of 1 KiB blocks
+    Tiny,
+    /// 1 MiB of 4 KiB blocks
+    Small,
+}
+
+impl FixtureKey {
+    pub fn name(self) -> &'static str {
+        match self {
+            FixtureKey::Tiny => "tiny",
+            FixtureKey::Small => "small",
+        }
+    }
+
+    pub fn block_size(self) -> BlockSize {
+        match self {
+            FixtureKey::Tiny => BLOCK_1K,
+            FixtureKey::Small => BLOCK_4K,
+        }
+    }
+
+    pub fn key_length(self) -> u64 {
+        match self {
+            FixtureKey::Tiny => 128 * 1024,
+            FixtureKey::Small => 1024 * 1024,
+        }
+    }
+
+    /// Lowercase hex BLAKE3 digest of the key data, as recorded in the key file's header
+    pub fn fingerprint(self) -> &'static str {
+        match self {
+            FixtureKey::Tiny => "f09597acb8c7b6bbbbc949b07c3423031d4c7dc2882210083238e90382fe5a8b",
+            FixtureKey::Small => "a8c624e5253471721541b0716515a17adfecf41df80ec8becce19befd4345cfa",
+        }
+    }
+
+    fn seed(self) -> KeyMaterial {
+        format!("big_fluffy_dise fixture key {}", self.name())
+            .into_bytes()
+            .into_boxed_slice()
+    }
+}
+
+/// A key derived from a fixture key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixtureVector {
+    pub security_level: SecurityLevel,
+    pub locator: Locator,
+    pub key: KeyMaterial,
+}
+
+/// A fixture key file and the keys derived from it
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    pub key: FixtureKey,
+    /// The key file
+    pub path: PathBuf,
+    /// The vectors as JSON
+    pub vectors_path: PathBuf,
+    /// `VECTORS_PER_LEVEL` keys at 128 and at 256 bits
+    pub vectors: Vec<FixtureVector>,
+}
+
+impl Fixture {
+    /// The settings the vectors were derived with
+    pub fn config(&self) -> Config {
+        Config {
+            key_path: self.path.to_str().map(str::to_string),
+            block_size: self.key.block_size(),
+            security_level: SecurityLevel::Bits256,
+            leakage_tolerance: FIXTURE_LEAKAGE_TOLERANCE,
+            ..Config::default()
+        }
+    }
+
+    /// Open the key file with `config()`
+    pub fn open(&self) -> Result<DiskBigKey, BigKeyError> {
+        open_big_key_with(&self.path, &self.config())
+    }
+}
+
+// On-disk representation of the vectors, byte strings as lowercase hex
+#[derive(Serialize)]
+struct VectorFile<'a> {
+    name: &'a str,
+    block_size: usize,
+    key_length: u64,
+    key_fingerprint: String,
+    leakage_tolerance: f32,
+    vectors: Vec<VectorEntry>,
+}
+
+#[derive(Serialize)]
+struct VectorEntry {
+    security_level: u32,
+    locator: String,
+    derived_key: String,
+}
+
+/// Where fixtures are written: `$BIGKEY_FIXTURE_DIR` if set, a directory in the system's
+/// temporary directory otherwise
+pub fn fixture_dir() -> PathBuf {
+    match std::env::var_os(FIXTURE_DIR_ENV) {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::temp_dir().join("big_fluffy_dise-fixtures-v1"),
+    }
+}
+
+/// The fixture `key` in `fixture_dir()`, writing it first if needed
+pub fn fixture(key: FixtureKey) -> Result<Fixture, BigKeyError> {
+    fixture_in(key, fixture_dir())
+}
+
+/// The fixture `key` in `dir`, writing it first if needed
+pub fn fixture_in(key: FixtureKey, dir: impl AsRef<Path>) -> Result<Fixture, BigKeyError> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.key", key.name()));
+    if !is_intact(key, &path) {
+        let temp = temp_path(dir, key.name());
+        let options = GenerateOptions {
+            block_size: key.block_size(),
+            seed: Some(key.seed()),
+            ..GenerateOptions::default()
+        };
+        generate_key_file(&temp, key.key_length(), &options)?;
+        // concurrent writers write the same contents, so whichever rename lands last is fine
+        fs::rename(&temp, &path)?;
+    }
+
+    let mut fixture = Fixture {
+        key,
+        vectors_path: dir.join(format!("{}.json", key.name())),
+        path,
+        vectors: Vec::new(),
+    };
+    fixture.vectors = derive_vectors(&fixture)?;
+
+    let json = serde_json::to_vec_pretty(&vector_file(&fixture)).map_err(|e| {
+        BigKeyError::InvalidConfig {
+            reason: format!("cannot encode fixture vectors: {}", e),
+        }
+    })?;
+    if fs::read(&fixture.vectors_path).ok().as_ref() != Some(&json) {
+        let temp = temp_path(dir, key.name());
+        fs::write(&temp, &json)?;
+        fs::rename(&temp, &fixture.vectors_path)?;
+    }
+    Ok(fixture)
+}
+
+// Whether `path` holds the complete fixture `key`
+fn is_intact(key: FixtureKey, path: &Path) -> bool {
+    let path = match path.to_str() {
+        Some(path) if Path::new(path).is_file() => path,
+        _ => return false,
+    };
+    DiskStorage::open(key.block_size(), path)
+        .and_then(|mut storage| fingerprint(&mut storage))
+        .is_ok_and(|digest| digest.to_hex() == key.fingerprint())
+}
+
+fn derive_vectors(fixture: &Fixture) -> Result<Vec<FixtureVector>, BigKeyError> {
+    let randomness = SeededRandomness::new(fixture.key.name().as_bytes());
+    let mut big_key = fixture.open()?.with_probe_randomness(randomness);
+    let mut vectors = Vec::new();
+    for security_level in SECURITY_LEVELS.iter() {
+        for _ in 0..VECTORS_PER_LEVEL {
+            let (locator, key) = big_key.new_key(*security_level)?;
+            vectors.push(FixtureVector {
+                security_level: *security_level,
+                locator,
+                key,
+            });
+        }
+    }
+    Ok(vectors)
+}
+
+fn vector_file(fixture: &Fixture) -> VectorFile<'static> {
+    VectorFile {
+        name: fixture.key.name(),
+        block_size: fixture.key.block_size().byte_len,
+        key_length: fixture.key.key_length(),
+        key_fingerprint: fixture.key.fingerprint().to_string(),
+        leakage_tolerance: FIXTURE_LEAKAGE_TOLERANCE,
+        vectors: fixture
+            .vectors
+            .iter()
+            .map(|vector| VectorEntry {
+                security_level: vector.security_level as u32,
+                locator: vector.locator.as_bytes().to_hex(),
+                derived_key: vector.key.to_hex(),
+            })
+            .collect(),
+    }
+}
+
+fn temp_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        process::id(),
+        WRITERS.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fixtures::{fixture_in, FixtureKey};
+    use crate::kem::BigKeyKem;
+    use crate::storage::fingerprint;
+    use crate::storage::tempfile::tempfile;
+    use crate::traits::SecretBytes;
+
+    #[test]
+    fn fixtures_are_deterministic_and_reused() {
+        let dirs = [tempfile(), tempfile()];
+        for key in [FixtureKey::Tiny, FixtureKey::Small].iter() {
+            let first = fixture_in(*key, dirs[0].as_path()).unwrap();
+            let second = fixture_in(*key, dirs[1].as_path()).unwrap();
+            assert_eq!(first.vectors, second.vectors);
+            assert_eq!(first.vectors.len(), 4);
+            assert_eq!(
+                std::fs::read(&first.vectors_path).unwrap(),
+                std::fs::read(&second.vectors_path).unwrap()
+            );
+
+            let mut big_key = first.open().unwrap();
+            for vector in first.vectors.iter() {
+                assert_eq!(big_key.get_key(&vector.locator).unwrap(), vector.key);
+                assert_eq!(vector.key.len(), vector.security_level as usize / 8);
+            }
+
+            // a damaged key file is rewritten
+            let mut contents = std::fs::read(&first.path).unwrap();
+            let last = contents.len() - 1;
+            contents[last] ^= 1;
+            std::fs::write(&first.path, contents).unwrap();
+            let rewritten = fixture_in(*key, dirs[0].as_path()).unwrap();
+            assert_eq!(rewritten.vectors, first.vectors);
+            assert_eq!(
+                fingerprint(&mut first.open().unwrap().into_storage())
+                    .unwrap()
+                    .to_hex(),
+                key.fingerprint()
+            );
+        }
+        for dir in dirs.iter() {
+            std::fs::remove_dir_all(dir.as_path()).unwrap();
+        }
+    }
+
+    #[test]
+    fn stale_vectors_are_rewritten_and_bad_dirs_fail() {
+        let dir = tempfile();
+        let fixture = fixture_in(FixtureKey::Tiny, dir.as_path()).unwrap();
+        let json = std::fs::read(&fixture.vectors_path).unwrap();
+        std::fs::write(&fixture.vectors_path, b"{\"vectors\":[]}").unwrap();
+        fixture_in(FixtureKey::Tiny, dir.as_path()).unwrap();
+        assert_eq!(std::fs::read(&fixture.vectors_path).unwrap(), json);
+
+        // a file where the fixture directory should be
+        let file = tempfile();
+        std::fs::write(file.as_path(), b"not a directory").unwrap();
+        assert!(fixture_in(FixtureKey::Tiny, file.as_path()).is_err());
+        std::fs::remove_dir_all(dir.as_path()).unwrap();
+    }
+} // mod test
@@ -2,5 +2,6 @@ use blake3::OutputReader;
 
 /// Generate the contents of a BigKey using Blake3
 pub struct Blake3Generator {
+    #[allow(dead_code)]
     xof: OutputReader,
 }
@@ -0,0 +1,364 @@
+//! Checkpoints of interrupted key generations.
+//!
+//! Generating a large key can take hours. `generate_interruptible()` writes the key in chunks
+//! and, once its `CancellationToken` is cancelled (e.g. from a SIGINT handler), stops at the
+//! next chunk boundary with everything generated so far written out, returning a `Checkpoint`
+//! instead of leaving a partial key nothing can continue. Saved next to the key (see
+//! `checkpoint_path()`), the checkpoint lets the generation continue later, in this or another
+//! process, through `DiskStorage::resume_writer()` and `Checkpoint::generator()`.
+//!
+//! A checkpoint holds the generator state, which reveals the rest of the key: it is saved
+//! readable by its owner only, and should be removed once the key is complete.
+//!
+//! File format, integers big-endian: magic `BFDCKPT2`, generator id (u16), block length (u32),
+//! key length (u64), bytes written (u64), state length (u32), state, then a flag byte and the
+//! 32 byte seed commitment, zero when the flag is 0. `BFDCKPT1` checkpoints end after the state
+//! and load without a seed commitment.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+
+use crate::generation::BigKeyGenerator;
+use crate::memory::wipe;
+use crate::storage::{CancellationToken, StorageWriter};
+use crate::traits::{BigKeyError, BlockSize, GeneratorId, KeyMaterial};
+
+/// Bytes `generate_interruptible()` writes between checks of its token
+pub const CHECKPOINT_INTERVAL: usize = 4 * 1024 * 1024;
+
+const MAGIC: &[u8; 8] = b"BFDCKPT2";
+const MAGIC_V1: &[u8; 8] = b"BFDCKPT1";
+const FIXED_LEN: usize = 8 + 2 + 4 + 8 + 8 + 4;
+const COMMITMENT_LEN: usize = 1 + 32;
+
+/// Path of the checkpoint of an interrupted generation into `storage_location`
+pub fn checkpoint_path(storage_location: &str) -> String {
+    format!("{}.checkpoint", storage_location)
+}
+
+/// Where an interrupted generation stopped
+#[derive(Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub generator: GeneratorId,
+    pub block_len: usize,
+    pub key_length: u64,
+    /// Bytes of the key written, a whole number of blocks
+    pub written: u64,
+    /// `Provenance::seed_commitment` of the interrupted key, recorded again once it is resumed.
+    /// `generate_interruptible()` leaves it to the caller.
+    pub seed_commitment: Option<[u8; 32]>,
+    state: KeyMaterial,
+}
+
+impl Checkpoint {
+    /// The generator, positioned to continue after the `written` bytes
+    pub fn generator<G: BigKeyGenerator>(&self) -> Result<G, BigKeyError> {
+        if G::ID != self.generator {
+            return Err(invalid("checkpoint belongs to a different generator"));
+        }
+        G::restore(&self.state)
+    }
+
+    pub fn block_size(&self) -> Result<BlockSize, BigKeyError> {
+        BlockSize::from_byte_len(self.block_len).ok_or(invalid("unsupported block size"))
+    }
+
+    /// Write the checkpoint to `path`, readable by its owner only, replacing any earlier one
+    pub fn save(&self, path: &str) -> Result<(), BigKeyError> {
+        let tmp = format!("{}.tmp", path);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut bytes = self.to_bytes();
+        let written = options
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp, path));
+        wipe(&mut bytes);
+        written.map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            e.into()
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Checkpoint, BigKeyError> {
+        let mut bytes = fs::read(path)?;
+        let checkpoint = Checkpoint::from_bytes(&bytes);
+        wipe(&mut bytes);
+        checkpoint
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(FIXED_LEN + self.state.len() + COMMITMENT_LEN);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&(self.generator as u16).to_be_bytes());
+        out.extend_from_slice(&(self.block_len as u32).to_be_bytes());
+        out.extend_from_slice(&self.key_length.to_be_bytes());
+        out.extend_from_slice(&self.written.to_be_bytes());
+        out.extend_from_slice(&(self.state.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.state);
+        out.push(self.seed_commitment.is_some() as u8);
+        out.extend_from_slice(&self.seed_commitment.unwrap_or([0u8; 32]));
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Checkpoint, BigKeyError> {
+        let v1 = bytes.starts_with(MAGIC_V1);
+        if bytes.len() < FIXED_LEN || !(v1 || bytes.starts_with(MAGIC)) {
+            return Err(invalid("not a generation checkpoint"));
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        let (state, seed_commitment) = match v1 {
+            true => (&bytes[FIXED_LEN..], None),
+            false => {
+                let state_end = bytes
+                    .len()
+                    .checked_sub(COMMITMENT_LEN)
+                    .filter(|end| *end >= FIXED_LEN)
+                    .ok_or(invalid("truncated seed commitment"))?;
+                let commitment: [u8; 32] = bytes[state_end + 1..].try_into().unwrap();
+                let commitment = match bytes[state_end] {
+                    0 if commitment == [0u8; 32] => None,
+                    1 => Some(commitment),
+                    _ => return Err(invalid("malformed seed commitment")),
+                };
+                (&bytes[FIXED_LEN..state_end], commitment)
+            }
+        };
+
+        let generator = GeneratorId::from_u16(u16::from_be_bytes([bytes[8], bytes[9]]))
+            .ok_or(invalid("unknown generator"))?;
+        let checkpoint = Checkpoint {
+            generator,
+            block_len: u32_at(10) as usize,
+            key_length: u64_at(14),
+            written: u64_at(22),
+            seed_commitment,
+            state: state.into(),
+        };
+        if u32_at(30) as usize != checkpoint.state.len() {
+            return Err(invalid("truncated generator state"));
+        }
+        if checkpoint.block_len == 0
+            || checkpoint.written > checkpoint.key_length
+            || !checkpoint
+                .written
+                .is_multiple_of(checkpoint.block_len as u64)
+        {
+            return Err(invalid("inconsistent lengths"));
+        }
+        Ok(checkpoint)
+    }
+}
+
+impl fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Checkpoint")
+            .field("generator", &self.generator)
+            .field("block_len", &self.block_len)
+            .field("key_length", &self.key_length)
+            .field("written", &self.written)
+            .field("seed_commitment", &self.seed_commitment.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        wipe(&mut self.state);
+    }
+}
+
+/// Outcome of `generate_interruptible()`
+#[derive(Debug)]
+pub enum Generation {
+    /// The key is complete and the writer finalized
+    Complete,
+    /// Cancelled; the key is written and flushed up to the checkpoint
+    Interrupted(Checkpoint),
+}
+
+/// Write the rest of a key with `generator` to `writer`, which already holds `written` bytes,
+/// finalizing it when complete. Stops at the next chunk boundary after `cancel` is cancelled.
+pub fn generate_interruptible<G, W>(
+    generator: &mut G,
+    writer: &mut W,
+    written: u64,
+    cancel: &CancellationToken,
+) -> Result<Generation, BigKeyError>
+where
+    G: BigKeyGenerator,
+    W: StorageWriter,
+{
+    let block_len = writer.block_size().byte_len;
+    let key_length = writer.expected_big_key_length();
+    let chunk_len = (CHECKPOINT_INTERVAL / block_len).max(1) as u64 * block_len as u64;
+    writer.set_generator(G::ID);
+
+    let mut written = written;
+    while written < key_length {
+        if cancel.is_cancelled() {
+            writer.flush()?;
+            return Ok(Generation::Interrupted(Checkpoint {
+                generator: G::ID,
+                block_len,
+                key_length,
+                written,
+                seed_commitment: None,
+                state: generator.state(),
+            }));
+        }
+        let len = chunk_len.min(key_length - written);
+        generator.fill(writer, len as usize)?;
+        written += len;
+    }
+    writer.finalize()?;
+    Ok(Generation::Complete)
+}
+
+fn invalid(reason: &'static str) -> BigKeyError {
+    BigKeyError::InvalidCheckpoint { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generation::checkpoint::{COMMITMENT_LEN, FIXED_LEN, MAGIC_V1};
+    use crate::generation::{
+        checkpoint_path, generate_interruptible, BigKeyGenerator, Checkpoint, Generation,
+        HwRngGenerator, Shake256Generator,
+    };
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{commit_seed, CancellationToken, DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, GeneratorId, BLOCK_1K};
+
+    #[test]
+    fn interrupted_generations_resume_to_the_same_key() {
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let length = 9 * 1024 * 1024;
+        let tmp = tempfile();
+
+        let cancel = CancellationToken::new();
+        let mut generator = Shake256Generator::new(Some(seed.clone().into())).unwrap();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), length).unwrap();
+        generator.fill(&mut writer, 5 * 1024 * 1024).unwrap();
+        cancel.cancel();
+        let mut checkpoint =
+            match generate_interruptible(&mut generator, &mut writer, 5 * 1024 * 1024, &cancel)
+                .unwrap()
+            {
+                Generation::Interrupted(checkpoint) => checkpoint,
+                Generation::Complete => panic!("expected the generation to stop"),
+            };
+        drop(writer);
+        assert_eq!(checkpoint.written, 5 * 1024 * 1024);
+        assert!(!format!("{:?}", checkpoint).contains("state"));
+        assert_eq!(checkpoint.seed_commitment, None);
+        checkpoint.seed_commitment = Some(commit_seed(&seed));
+
+        let path = checkpoint_path(tmp.to_str());
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        assert_eq!(loaded, checkpoint);
+        std::fs::remove_file(&path).unwrap();
+
+        // the seed commitment is optional, and absent from checkpoints of the first format
+        let mut bytes = loaded.to_bytes();
+        let end = bytes.len() - COMMITMENT_LEN;
+        bytes[end] = 2;
+        assert!(Checkpoint::from_bytes(&bytes).is_err());
+        bytes[end] = 0;
+        assert!(Checkpoint::from_bytes(&bytes).is_err());
+        bytes[end..].iter_mut().for_each(|b| *b = 0);
+        assert_eq!(
+            Checkpoint::from_bytes(&bytes).unwrap().seed_commitment,
+            None
+        );
+        bytes.truncate(end);
+        assert!(Checkpoint::from_bytes(&bytes).is_err());
+        bytes[..8].copy_from_slice(MAGIC_V1);
+        let v1 = Checkpoint::from_bytes(&bytes).unwrap();
+        assert_eq!(v1.seed_commitment, None);
+        assert_eq!(v1.written, loaded.written);
+        assert!(Checkpoint::from_bytes(&bytes[..FIXED_LEN - 1]).is_err());
+
+        let mut generator = loaded.generator::<Shake256Generator>().unwrap();
+        let mut writer = DiskStorage::resume_writer(
+            loaded.block_size().unwrap(),
+            tmp.to_str(),
+            length,
+            loaded.written,
+        )
+        .unwrap();
+        let outcome = generate_interruptible(
+            &mut generator,
+            &mut writer,
+            loaded.written,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert!(matches!(outcome, Generation::Complete));
+
+        let mut expected = Vec::new();
+        Shake256Generator::new(Some(seed.into()))
+            .unwrap()
+            .fill(&mut expected, length)
+            .unwrap();
+        let header = writer.header().unwrap();
+        assert_eq!(header.generator, GeneratorId::Shake256);
+        assert_eq!(
+            header.fingerprint,
+            Some(*blake3::hash(&expected).as_bytes())
+        );
+
+        // complete keys cannot be resumed, nor checkpoints restored into other generators
+        assert!(DiskStorage::resume_writer(BLOCK_1K, tmp.to_str(), length, 1024).is_err());
+        assert!(loaded.generator::<HwRngGenerator>().is_err());
+    }
+
+    #[test]
+    fn hostile_checkpoints_are_rejected() {
+        let checkpoint = Checkpoint {
+            generator: GeneratorId::Shake256,
+            block_len: 1024,
+            key_length: 8 * 1024,
+            written: 4 * 1024,
+            seed_commitment: None,
+            state: vec![7u8; 16].into(),
+        };
+        let bytes = checkpoint.to_bytes();
+        assert_eq!(Checkpoint::from_bytes(&bytes).unwrap(), checkpoint);
+
+        let reason = |patch: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = bytes.clone();
+            patch(&mut bytes);
+            match Checkpoint::from_bytes(&bytes) {
+                Err(BigKeyError::InvalidCheckpoint { reason }) => reason,
+                _ => panic!("expected the checkpoint to be rejected"),
+            }
+        };
+        assert_eq!(reason(&|b| b[0] = b'X'), "not a generation checkpoint");
+        assert_eq!(
+            reason(&|b| b[8..10].copy_from_slice(&[0xff, 0xff])),
+            "unknown generator"
+        );
+        assert_eq!(reason(&|b| b[33] = 17), "truncated generator state");
+        assert_eq!(
+            reason(&|b| b[30..34].copy_from_slice(&[0xff; 4])),
+            "truncated generator state"
+        );
+        assert_eq!(reason(&|b| b[10..14].fill(0)), "inconsistent lengths");
+        assert_eq!(reason(&|b| b[29] = 1), "inconsistent lengths");
+        assert_eq!(reason(&|b| b[14..22].fill(0)), "inconsistent lengths");
+
+        // a checkpoint that was never saved, or only partly
+        let tmp = tempfile();
+        assert!(Checkpoint::load(tmp.to_str()).is_err());
+        std::fs::write(tmp.as_path(), &bytes[..FIXED_LEN + 3]).unwrap();
+        assert!(Checkpoint::load(tmp.to_str()).is_err());
+    }
+} // mod test
@@ -0,0 +1,134 @@
+//! Child BigKeys for delegation.
+//!
+//! A site that only needs limited key material (say 10 GiB) can be provisioned with a child
+//! BigKey derived from the parent instead of a copy of it. The child is the SHAKE256 expansion
+//! of a seed the parent derives for the child's label (`BigKey::derive_child_seed()`), so:
+//!
+//! * the parent re-creates any child from its label, no child needs backing up;
+//! * a compromised child reveals its seed at most, which is one-way in the parent's blocks and
+//!   useless for deriving the parent's other keys or children;
+//! * the child is an ordinary key file, used like any other BigKey.
+//!
+//! A child is only as strong as its 256-bit seed, not leakage resilient beyond it the way an
+//! independently generated BigKey of the same size would be with a fresh seed.
+
+use digest::Digest;
+
+use crate::generation::{generate_verified, BigKeyGenerator, Shake256Generator};
+use crate::kem::BigKey;
+use crate::storage::{StorageReader, StorageWriter};
+use crate::traits::{BigKeyError, BlockSize};
+
+/// Write the child of `parent` called `label`, `length_bytes` long, to `storage_method`
+pub fn generate_child_key<S: StorageReader, H: Digest>(
+    parent: &mut BigKey<S, H>,
+    label: &str,
+    storage_method: &mut impl StorageWriter,
+    length_bytes: usize,
+) -> Result<(), BigKeyError> {
+    let seed = parent.derive_child_seed(label.as_bytes())?;
+    Shake256Generator::generate(storage_method, Some(seed), length_bytes)
+}
+
+/// Write the child of `parent` called `label` to the key file `storage_location` and verify it
+/// (see `generate_verified()`), returning its fingerprint
+pub fn generate_child_key_file<S: StorageReader, H: Digest>(
+    parent: &mut BigKey<S, H>,
+    label: &str,
+    block_size: BlockSize,
+    storage_location: &str,
+    length_bytes: usize,
+) -> Result<[u8; 32], BigKeyError> {
+    let seed = parent.derive_child_seed(label.as_bytes())?;
+    generate_verified::<Shake256Generator>(block_size, storage_location, Some(seed), length_bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generation::{generate_child_key, generate_child_key_file};
+    use crate::helpers::{open_big_key, test_key_file};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageReader, StorageWriter};
+    use crate::traits::{BlockIndex, BLOCK_1K};
+
+    #[test]
+    fn children_are_reproducible_and_distinct() {
+        let (parent, child) = (test_key_file(), tempfile());
+        let mut parent_key = open_big_key(parent.as_path()).unwrap();
+
+        let fingerprint = generate_child_key_file(
+            &mut parent_key,
+            "site-a",
+            BLOCK_1K,
+            child.to_str(),
+            64 * 1024,
+        )
+        .unwrap();
+        let again = generate_child_key_file(
+            &mut open_big_key(parent.as_path()).unwrap(),
+            "site-a",
+            BLOCK_1K,
+            child.to_str(),
+            64 * 1024,
+        )
+        .unwrap();
+        assert_eq!(again, fingerprint);
+        let other = tempfile();
+        let sibling = generate_child_key_file(
+            &mut parent_key,
+            "site-b",
+            BLOCK_1K,
+            other.to_str(),
+            64 * 1024,
+        )
+        .unwrap();
+        assert_ne!(sibling, fingerprint);
+
+        // an ordinary BigKey, sharing no blocks with its parent
+        let mut child_key = open_big_key(child.as_path()).unwrap();
+        let (locator, key) = crate::derive(&mut child_key).unwrap();
+        assert_eq!(crate::rederive(&mut child_key, &locator).unwrap(), key);
+        let mut parent_storage = DiskStorage::open(BLOCK_1K, parent.to_str()).unwrap();
+        let mut child_storage = DiskStorage::open(BLOCK_1K, child.to_str()).unwrap();
+        let (mut a, mut b) = (vec![0u8; 1024], vec![0u8; 1024]);
+        for index in 0..64 {
+            parent_storage
+                .probe(BlockIndex::new(index), &mut a)
+                .unwrap();
+            child_storage.probe(BlockIndex::new(index), &mut b).unwrap();
+            assert_ne!(a, b);
+        }
+
+        // streamed into any storage writer, the same child
+        let streamed = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, streamed.to_str(), 64 * 1024).unwrap();
+        generate_child_key(&mut parent_key, "site-a", &mut writer, 64 * 1024).unwrap();
+        assert_eq!(writer.fingerprint(), Some(fingerprint));
+    }
+
+    #[test]
+    fn children_depend_on_the_parent_and_fail_on_bad_lengths() {
+        let (first, second, child) = (test_key_file(), test_key_file(), tempfile());
+        let mut first_key = open_big_key(first.as_path()).unwrap();
+        let mut second_key = open_big_key(second.as_path()).unwrap();
+
+        let mut fingerprints = Vec::new();
+        for parent in [&mut first_key, &mut second_key].iter_mut() {
+            let streamed = tempfile();
+            let mut writer = DiskStorage::new_writer(BLOCK_1K, streamed.to_str(), 4096).unwrap();
+            generate_child_key(parent, "site-a", &mut writer, 4096).unwrap();
+            fingerprints.push(writer.fingerprint());
+        }
+        assert_ne!(fingerprints[0], fingerprints[1]);
+
+        // a key file must hold whole blocks
+        assert!(generate_child_key_file(
+            &mut first_key,
+            "site-a",
+            BLOCK_1K,
+            child.to_str(),
+            64 * 1024 + 1
+        )
+        .is_err());
+    }
+} // mod test
@@ -0,0 +1,300 @@
+//! BigKey generation from an external entropy source, e.g. a file captured from a QRNG
+//! appliance or the character device of a hardware token.
+//!
+//! `FileSeedGenerator` consumes the source directly as key material. Its output is only as good
+//! as the source, so by default the bytes are copied verbatim and the source must be full
+//! entropy. Sources delivering less can be conditioned with `Whitening::Shake256`, which
+//! compresses every `ratio` source bytes into one output byte: output chunk `j` is
+//! SHAKE256(domain || j || source chunk). Whitening removes bias but adds no entropy; pick a
+//! `ratio` covering the source's assessed min-entropy.
+//!
+//! The source is never stretched. A source shorter than the key needs fails with
+//! `EntropySourceTooShort`: up front for regular files, whose length is known, or when a stream
+//! runs dry.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::Shake256;
+
+use crate::generation::traits::BigKeyGenerator;
+use crate::memory::wipe;
+use crate::traits::{BigKeyError, GeneratorId, KeyMaterial};
+
+const WHITENING_DOMAIN: &[u8] = b"big_fluffy_dise entropy file whitening";
+
+// Key bytes produced, and source bytes conditioned together, per step
+const FILL_CHUNK: usize = 4096;
+
+/// How `FileSeedGenerator` turns source bytes into key bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Whitening {
+    /// Copy the source verbatim
+    None,
+    /// Condition `ratio` source bytes into each key byte with SHAKE256
+    Shake256 { ratio: u32 },
+}
+
+impl Whitening {
+    // Source bytes consumed per key byte
+    fn ratio(self) -> u64 {
+        match self {
+            Whitening::None => 1,
+            Whitening::Shake256 { ratio } => ratio as u64,
+        }
+    }
+}
+
+/// Generate BigKey contents from an entropy file or stream. The key is reproducible from the
+/// source, so keep (or destroy) the source accordingly.
+pub struct FileSeedGenerator {
+    source: Box<dyn Read>,
+    // Bytes left in the source, where known
+    available: Option<u64>,
+    consumed: u64,
+    whitening: Whitening,
+    chunk: u64,
+}
+
+impl BigKeyGenerator for FileSeedGenerator {
+    const ID: GeneratorId = GeneratorId::EntropyFile;
+
+    fn new(_seed: Option<KeyMaterial>) -> Result<Self, BigKeyError> {
+        Err(BigKeyError::InvalidConfig {
+            reason: "FileSeedGenerator reads an entropy source, create it with open()".to_string(),
+        })
+    }
+
+    fn fill(&mut self, writer: &mut impl Write, length_bytes: usize) -> Result<(), BigKeyError> {
+        let ratio = self.whitening.ratio();
+        let required =
+            (length_bytes as u64)
+                .checked_mul(ratio)
+                .ok_or(BigKeyError::OutputLengthTooLong {
+                    out_len: length_bytes,
+                    max_len: (u64::MAX / ratio) as usize,
+                })?;
+        if let Some(available) = self.available {
+            if available < required {
+                return Err(BigKeyError::EntropySourceTooShort {
+                    available: self.consumed + available,
+                    required: self.consumed + required,
+                });
+            }
+        }
+        let required = self.consumed + required;
+
+        let mut input = vec![0u8; (length_bytes.min(FILL_CHUNK) as u64 * ratio) as usize];
+        let mut output = vec![0u8; length_bytes.min(FILL_CHUNK)];
+        let mut remaining = length_bytes;
+        let result = loop {
+            if remaining == 0 {
+                break Ok(());
+            }
+            let len = remaining.min(FILL_CHUNK);
+            let source = &mut input[..(len as u64 * ratio) as usize];
+            if let Err(e) = self.read_source(source, required) {
+                break Err(e);
+            }
+            let chunk = &mut output[..len];
+            match self.whitening {
+                Whitening::None => chunk.copy_from_slice(source),
+                Whitening::Shake256 { .. } => {
+                    let mut xof = Shake256::default();
+                    xof.update(WHITENING_DOMAIN);
+                    xof.update(self.chunk.to_be_bytes());
+                    xof.update(&*source);
+                    XofReader::read(&mut xof.finalize_xof(), chunk);
+                }
+            }
+            self.chunk += 1;
+            if let Err(e) = writer.write_all(chunk) {
+                break Err(e.into());
+            }
+            remaining -= len;
+        };
+        wipe(&mut input);
+        wipe(&mut output);
+        result
+    }
+
+    fn state(&self) -> KeyMaterial {
+        (Self::ID as u16).to_be_bytes().to_vec().into_boxed_slice()
+    }
+
+    fn restore(_state: &[u8]) -> Result<Self, BigKeyError> {
+        Err(BigKeyError::InvalidGeneratorState {
+            reason: "entropy file generators resume by reopening their source",
+        })
+    }
+}
+
+impl FileSeedGenerator {
+    /// Generate from the entropy file or device at `path`
+    pub fn open(path: impl AsRef<Path>, whitening: Whitening) -> Result<Self, BigKeyError> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let available = if metadata.is_file() {
+            Some(metadata.len())
+        } else {
+            None
+        };
+        let mut generator = FileSeedGenerator::from_reader(file, whitening)?;
+        generator.available = available;
+        Ok(generator)
+    }
+
+    /// Generate from a stream of unknown length, e.g. a hardware token
+    pub fn from_reader(
+        source: impl Read + 'static,
+        whitening: Whitening,
+    ) -> Result<Self, BigKeyError> {
+        if whitening.ratio() == 0 {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "whitening ratio must be at least 1".to_string(),
+            });
+        }
+        Ok(FileSeedGenerator {
+            source: Box::new(source),
+            available: None,
+            consumed: 0,
+            whitening,
+            chunk: 0,
+        })
+    }
+
+    /// Source bytes a key of `length_bytes` consumes
+    pub fn required_source_len(&self, length_bytes: u64) -> u64 {
+        length_bytes.saturating_mul(self.whitening.ratio())
+    }
+
+    // Fill `buf` from the source, failing with `EntropySourceTooShort` at its end; `required`
+    // is the source length the current fill needs, for the error
+    fn read_source(&mut self, buf: &mut [u8], required: u64) -> Result<(), BigKeyError> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.source.read(&mut buf[read..]) {
+                Ok(0) => {
+                    return Err(BigKeyError::EntropySourceTooShort {
+                        available: self.consumed,
+                        required,
+                    })
+                }
+                Ok(n) => {
+                    read += n;
+                    self.consumed += n as u64;
+                    if let Some(available) = self.available.as_mut() {
+                        *available = available.saturating_sub(n as u64);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+
+    use crate::generation::{BigKeyGenerator, FileSeedGenerator, Whitening};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageReader, StorageWriter};
+    use crate::traits::{BigKeyError, BlockIndex, GeneratorId, BLOCK_1K};
+
+    fn entropy(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        getrandom::getrandom(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn entropy_files_become_keys() {
+        let (source, key) = (tempfile(), tempfile());
+        let bytes = entropy(64 * 1024);
+        File::create(source.as_path())
+            .unwrap()
+            .write_all(&bytes)
+            .unwrap();
+
+        let mut storage = DiskStorage::new_writer(BLOCK_1K, key.to_str(), 64 * 1024).unwrap();
+        FileSeedGenerator::open(source.as_path(), Whitening::None)
+            .unwrap()
+            .write_key(&mut storage, 64 * 1024)
+            .unwrap();
+        drop(storage);
+        let header = DiskStorage::read_header(key.to_str()).unwrap().unwrap();
+        assert_eq!(header.generator, GeneratorId::EntropyFile);
+        let mut reader = DiskStorage::open(BLOCK_1K, key.to_str()).unwrap();
+        let mut block = vec![0u8; 1024];
+        reader.probe(BlockIndex::new(5), &mut block).unwrap();
+        assert_eq!(&block[..], &bytes[5 * 1024..6 * 1024]);
+
+        // whitening compresses, deterministically
+        let whiten = |bytes: &[u8]| {
+            let mut out = Vec::new();
+            FileSeedGenerator::from_reader(
+                Cursor::new(bytes.to_vec()),
+                Whitening::Shake256 { ratio: 2 },
+            )
+            .unwrap()
+            .fill(&mut out, 32 * 1024)
+            .unwrap();
+            out
+        };
+        let whitened = whiten(&bytes);
+        assert_eq!(whitened.len(), 32 * 1024);
+        assert_eq!(whiten(&bytes), whitened);
+        assert_ne!(&whitened[..], &bytes[..32 * 1024]);
+    }
+
+    #[test]
+    fn short_sources_fail() {
+        let source = tempfile();
+        File::create(source.as_path())
+            .unwrap()
+            .write_all(&entropy(10_000))
+            .unwrap();
+
+        // regular files are checked before anything is written
+        let mut generator = FileSeedGenerator::open(source.as_path(), Whitening::None).unwrap();
+        let mut out = Vec::new();
+        match generator.fill(&mut out, 16 * 1024) {
+            Err(BigKeyError::EntropySourceTooShort {
+                available,
+                required,
+            }) => assert_eq!((available, required), (10_000, 16 * 1024)),
+            _ => panic!("expected the entropy file to be too short"),
+        }
+        assert!(out.is_empty());
+        let mut generator =
+            FileSeedGenerator::open(source.as_path(), Whitening::Shake256 { ratio: 4 }).unwrap();
+        match generator.fill(&mut out, 4096) {
+            Err(BigKeyError::EntropySourceTooShort { required, .. }) => assert_eq!(required, 16384),
+            _ => panic!("expected the entropy file to be too short for whitening"),
+        }
+
+        // streams fail when they run dry
+        let mut stream =
+            FileSeedGenerator::from_reader(Cursor::new(entropy(10_000)), Whitening::None).unwrap();
+        match stream.fill(&mut out, 16 * 1024) {
+            Err(BigKeyError::EntropySourceTooShort {
+                available,
+                required,
+            }) => assert_eq!((available, required), (10_000, 16 * 1024)),
+            _ => panic!("expected the entropy stream to run dry"),
+        }
+
+        assert!(FileSeedGenerator::new(None).is_err());
+        assert!(FileSeedGenerator::from_reader(
+            Cursor::new(vec![]),
+            Whitening::Shake256 { ratio: 0 }
+        )
+        .is_err());
+    }
+} // mod test
@@ -0,0 +1,255 @@
+//! Escrow of the seed of a deterministic BigKey.
+//!
+//! A BigKey generated from a seed can be rebuilt from that seed alone, which makes the seed a
+//! convenient disaster recovery backup but also a single secret worth the entire key. The
+//! escrow ceremony encrypts the seed to an offline X25519 escrow public key and stores the
+//! result next to the key's `Manifest`, so the seed is never written anywhere in plaintext and
+//! recovery requires the escrow secret key.
+//!
+//! Sealing: an ephemeral X25519 key pair is generated, the shared secret with the escrow public
+//! key is hashed into a ChaCha20-Poly1305 key, and the seed is encrypted with the manifest's
+//! fingerprint and key length as associated data, binding the sealed seed to its key.
+//!
+//! Escrow file layout: 16 byte magic, 32 byte ephemeral public key, ciphertext and tag.
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::storage::Manifest;
+use crate::traits::{BigKeyError, KeyMaterial};
+
+const MAGIC: &[u8; 16] = b"BFDISE-ESCROW-1\x00";
+const KDF_CONTEXT: &str = "big_fluffy_dise 2024 seed escrow v1";
+const TAG_LEN: usize = 16;
+
+/// Public half of an escrow key pair, the only part needed on the generating host
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EscrowPublicKey([u8; 32]);
+
+impl EscrowPublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        EscrowPublicKey(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+/// Secret half of an escrow key pair. Keep offline; it is needed only for recovery.
+pub struct EscrowSecretKey(StaticSecret);
+
+impl EscrowSecretKey {
+    /// Generate a new escrow key pair from operating system randomness
+    pub fn generate() -> Result<Self, BigKeyError> {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes)?;
+        Ok(EscrowSecretKey(StaticSecret::from(bytes)))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        EscrowSecretKey(StaticSecret::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn public_key(&self) -> EscrowPublicKey {
+        EscrowPublicKey(PublicKey::from(&self.0).to_bytes())
+    }
+}
+
+/// Location of the escrowed seed for the manifest at `manifest_path`
+pub fn escrow_path(manifest_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = manifest_path.as_ref().as_os_str().to_owned();
+    path.push(".escrow");
+    PathBuf::from(path)
+}
+
+/// Escrow ceremony: write the `Manifest` of the key at `storage_location` to `manifest_path`,
+/// and `seed` sealed to `recipient` next to it (see `escrow_path()`).
+///
+/// The caller is responsible for `seed` being the seed the key was generated from, e.g. by
+/// generating the key with `generate_verified()`.
+pub fn escrow_seed(
+    seed: &[u8],
+    recipient: &EscrowPublicKey,
+    storage_location: &str,
+    manifest_path: impl AsRef<Path>,
+) -> Result<Manifest, BigKeyError> {
+    let manifest = Manifest::for_key(storage_location)?;
+    let sealed = seal_seed(seed, recipient, &manifest)?;
+
+    manifest.export(manifest_path.as_ref())?;
+    std::fs::write(escrow_path(manifest_path), sealed)?;
+    Ok(manifest)
+}
+
+/// Recover the seed escrowed next to the manifest at `manifest_path`
+pub fn recover_seed(
+    manifest_path: impl AsRef<Path>,
+    secret: &EscrowSecretKey,
+) -> Result<KeyMaterial, BigKeyError> {
+    let manifest = Manifest::import(manifest_path.as_ref())?;
+    let sealed = std::fs::read(escrow_path(manifest_path))?;
+    open_seed(&sealed, secret, &manifest)
+}
+
+/// Encrypt `seed` to `recipient`, bound to `manifest`
+pub fn seal_seed(
+    seed: &[u8],
+    recipient: &EscrowPublicKey,
+    manifest: &Manifest,
+) -> Result<Vec<u8>, BigKeyError> {
+    let ephemeral = EscrowSecretKey::generate()?;
+    let ephemeral_public = ephemeral.public_key();
+    let shared = diffie_hellman(&ephemeral, recipient);
+    let cipher = cipher(&shared, &ephemeral_public, recipient);
+
+    let ciphertext = cipher
+        .encrypt(
+            &Nonce::default(),
+            Payload {
+                msg: seed,
+                aad: &associated_data(manifest),
+            },
+        )
+        .map_err(|_| failed("encryption failed"))?;
+
+    let mut out = Vec::with_capacity(MAGIC.len() + 32 + ciphertext.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&ephemeral_public.to_bytes());
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a seed sealed by `seal_seed()`
+pub fn open_seed(
+    sealed: &[u8],
+    secret: &EscrowSecretKey,
+    manifest: &Manifest,
+) -> Result<KeyMaterial, BigKeyError> {
+    let header_len = MAGIC.len() + 32;
+    if sealed.len() < header_len + TAG_LEN || &sealed[..MAGIC.len()] != MAGIC {
+        return Err(failed("not a sealed seed"));
+    }
+
+    let ephemeral_public =
+        EscrowPublicKey::from_bytes(sealed[MAGIC.len()..header_len].try_into().unwrap());
+    let shared = diffie_hellman(secret, &ephemeral_public);
+    let cipher = cipher(&shared, &ephemeral_public, &secret.public_key());
+
+    let seed = cipher
+        .decrypt(
+            &Nonce::default(),
+            Payload {
+                msg: &sealed[header_len..],
+                aad: &associated_data(manifest),
+            },
+        )
+        .map_err(|_| failed("wrong escrow key or sealed seed does not match manifest"))?;
+    Ok(seed.into_boxed_slice())
+}
+
+// Each sealing uses a fresh ephemeral key, so the derived key encrypts exactly one message and
+// a fixed nonce is safe.
+fn cipher(
+    shared: &[u8; 32],
+    ephemeral_public: &EscrowPublicKey,
+    recipient: &EscrowPublicKey,
+) -> ChaCha20Poly1305 {
+    let mut hasher = blake3::Hasher::new_derive_key(KDF_CONTEXT);
+    hasher.update(shared);
+    hasher.update(&ephemeral_public.0);
+    hasher.update(&recipient.0);
+    ChaCha20Poly1305::new(Key::from_slice(hasher.finalize().as_bytes()))
+}
+
+fn diffie_hellman(secret: &EscrowSecretKey, public: &EscrowPublicKey) -> [u8; 32] {
+    secret
+        .0
+        .diffie_hellman(&PublicKey::from(public.0))
+        .to_bytes()
+}
+
+fn associated_data(manifest: &Manifest) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(40);
+    aad.extend_from_slice(&manifest.fingerprint);
+    aad.extend_from_slice(&manifest.key_length.to_be_bytes());
+    aad
+}
+
+fn failed(reason: &'static str) -> BigKeyError {
+    BigKeyError::EscrowFailed { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generation::escrow::{escrow_seed, open_seed, recover_seed, seal_seed};
+    use crate::generation::{generate_verified, EscrowSecretKey, Shake256Generator};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::Manifest;
+    use crate::traits::{BigKeyError, BLOCK_1K};
+
+    #[test]
+    fn escrowed_seed_is_recovered() {
+        let key = tempfile();
+        let manifest_path = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        generate_verified::<Shake256Generator>(
+            BLOCK_1K,
+            key.to_str(),
+            Some(seed.clone().into_boxed_slice()),
+            16 * 1024,
+        )
+        .unwrap();
+
+        let escrow = EscrowSecretKey::generate().unwrap();
+        escrow_seed(
+            &seed,
+            &escrow.public_key(),
+            key.to_str(),
+            manifest_path.as_path(),
+        )
+        .unwrap();
+
+        let recovered = recover_seed(manifest_path.as_path(), &escrow).unwrap();
+        assert_eq!(&recovered[..], &seed[..]);
+
+        let _ = std::fs::remove_file(crate::generation::escrow_path(manifest_path.as_path()));
+    }
+
+    #[test]
+    fn wrong_key_or_manifest_fails() {
+        let manifest = Manifest {
+            generator: crate::traits::GeneratorId::Shake256,
+            block_len: 1024,
+            key_length: 1024,
+            fingerprint: [0x11; 32],
+            merkle_root: None,
+        };
+        let escrow = EscrowSecretKey::generate().unwrap();
+        let sealed = seal_seed(b"seed", &escrow.public_key(), &manifest).unwrap();
+        assert_eq!(
+            &open_seed(&sealed, &escrow, &manifest).unwrap()[..],
+            b"seed"
+        );
+
+        let other = EscrowSecretKey::generate().unwrap();
+        let other_manifest = Manifest {
+            fingerprint: [0x22; 32],
+            ..manifest.clone()
+        };
+        for (secret, manifest) in [(&other, &manifest), (&escrow, &other_manifest)].iter() {
+            match open_seed(&sealed, secret, manifest) {
+                Err(BigKeyError::EscrowFailed { .. }) => {}
+                _ => panic!("expected sealed seed to stay sealed"),
+            }
+        }
+    }
+} // mod test
@@ -0,0 +1,281 @@
+//! BigKey generation straight from a hardware random number generator.
+//!
+//! Bytes are read from `/dev/hwrng` when present, otherwise from the x86-64 `RDSEED`
+//! instruction. Every byte passes through the continuous health tests of NIST SP 800-90B
+//! section 4.4 (repetition count and adaptive proportion) before it is written to the key, and
+//! generation is aborted on the first failure rather than committing suspect output.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+
+use crate::generation::traits::{state_body, BigKeyGenerator};
+use crate::memory::wipe;
+use crate::traits::{BigKeyError, GeneratorId, KeyMaterial};
+
+const HWRNG_DEVICE: &str = "/dev/hwrng";
+
+/// Claimed min-entropy per byte of hardware RNG output, deliberately conservative
+const MIN_ENTROPY_BITS: f64 = 1.0;
+
+/// Health test false positive probability, 2^-20 as suggested by SP 800-90B
+const ALPHA_LOG2: f64 = -20.0;
+
+/// Adaptive proportion test window for non-binary samples
+const APT_WINDOW: usize = 512;
+
+// Bytes read from the source, and health tested, per step
+const READ_CHUNK: usize = 4096;
+
+/// Generate BigKey contents from a hardware RNG. The resulting key cannot be regenerated, so
+/// seeds are ignored, and restoring a saved state continues with fresh hardware output.
+pub struct HwRngGenerator {
+    source: Box<dyn Read>,
+    health: HealthTests,
+}
+
+impl BigKeyGenerator for HwRngGenerator {
+    const ID: GeneratorId = GeneratorId::HwRng;
+
+    fn new(_seed: Option<KeyMaterial>) -> Result<Self, BigKeyError> {
+        let source: Box<dyn Read> = match File::open(HWRNG_DEVICE) {
+            Ok(device) => Box::new(device),
+            Err(_) => Box::new(RdSeed::new()?),
+        };
+        Ok(HwRngGenerator::with_source(source))
+    }
+
+    // Health test source output and write `length_bytes` of it to `writer`
+    fn fill(&mut self, writer: &mut impl Write, length_bytes: usize) -> Result<(), BigKeyError> {
+        let mut buf = vec![0u8; length_bytes.min(READ_CHUNK)];
+        let mut remaining = length_bytes;
+
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(READ_CHUNK)];
+            self.source.read_exact(chunk)?;
+            self.health.check(chunk)?;
+            writer.write_all(chunk)?;
+            remaining -= chunk.len();
+        }
+        wipe(&mut buf);
+
+        Ok(())
+    }
+
+    fn state(&self) -> KeyMaterial {
+        (Self::ID as u16).to_be_bytes().to_vec().into_boxed_slice()
+    }
+
+    fn restore(state: &[u8]) -> Result<Self, BigKeyError> {
+        if !state_body(state, Self::ID)?.is_empty() {
+            return Err(BigKeyError::InvalidGeneratorState {
+                reason: "malformed hardware RNG state",
+            });
+        }
+        HwRngGenerator::new(None)
+    }
+}
+
+impl HwRngGenerator {
+    // Generate from `source` with fresh health tests
+    fn with_source(source: Box<dyn Read>) -> Self {
+        HwRngGenerator {
+            source,
+            health: HealthTests::new(MIN_ENTROPY_BITS),
+        }
+    }
+}
+
+/// SP 800-90B continuous health tests over byte samples
+pub struct HealthTests {
+    rct_cutoff: u32,
+    rct_last: Option<u8>,
+    rct_count: u32,
+    apt_cutoff: u32,
+    apt_first: u8,
+    apt_seen: usize,
+    apt_count: u32,
+}
+
+impl HealthTests {
+    /// Tests for a source claiming `min_entropy_bits` (0 < H <= 8) of min-entropy per byte
+    pub fn new(min_entropy_bits: f64) -> Self {
+        let alpha = 2f64.powf(ALPHA_LOG2);
+
+        HealthTests {
+            rct_cutoff: 1 + (-ALPHA_LOG2 / min_entropy_bits).ceil() as u32,
+            rct_last: None,
+            rct_count: 0,
+            apt_cutoff: 1 + critical_binomial(APT_WINDOW, 2f64.powf(-min_entropy_bits), alpha),
+            apt_first: 0,
+            apt_seen: 0,
+            apt_count: 0,
+        }
+    }
+
+    /// Feed `samples` through both tests, failing with `HealthTestFailed` on the first alarm
+    pub fn check(&mut self, samples: &[u8]) -> Result<(), BigKeyError> {
+        for &sample in samples {
+            // repetition count test
+            if self.rct_last == Some(sample) {
+                self.rct_count += 1;
+                if self.rct_count >= self.rct_cutoff {
+                    return Err(BigKeyError::HealthTestFailed {
+                        test: "repetition count",
+                    });
+                }
+            } else {
+                self.rct_last = Some(sample);
+                self.rct_count = 1;
+            }
+
+            // adaptive proportion test
+            if self.apt_seen == 0 {
+                self.apt_first = sample;
+                self.apt_count = 1;
+            } else if sample == self.apt_first {
+                self.apt_count += 1;
+                if self.apt_count >= self.apt_cutoff {
+                    return Err(BigKeyError::HealthTestFailed {
+                        test: "adaptive proportion",
+                    });
+                }
+            }
+            self.apt_seen = (self.apt_seen + 1) % APT_WINDOW;
+        }
+
+        Ok(())
+    }
+}
+
+// Smallest k where P(X <= k) >= 1 - alpha for X ~ Binomial(n, p) (CRITBINOM in SP 800-90B)
+fn critical_binomial(n: usize, p: f64, alpha: f64) -> u32 {
+    let mut pmf = (1.0 - p).powi(n as i32);
+    let mut cdf = pmf;
+    let mut k = 0;
+
+    while cdf < 1.0 - alpha && k < n {
+        pmf *= (n - k) as f64 / (k + 1) as f64 * p / (1.0 - p);
+        cdf += pmf;
+        k += 1;
+    }
+    k as u32
+}
+
+// `RDSEED` as a byte stream
+struct RdSeed;
+
+impl RdSeed {
+    fn new() -> Result<Self, BigKeyError> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("rdseed") {
+                return Ok(RdSeed);
+            }
+        }
+        Err(BigKeyError::HardwareRngUnavailable)
+    }
+}
+
+impl Read for RdSeed {
+    #[cfg(target_arch = "x86_64")]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for chunk in buf.chunks_mut(8) {
+            let mut value = 0u64;
+            // RDSEED may transiently run out of entropy, retry a bounded number of times
+            let mut ready = false;
+            for _ in 0..1024 {
+                // Safety: `new()` verified the CPU supports RDSEED
+                if unsafe { std::arch::x86_64::_rdseed64_step(&mut value) } == 1 {
+                    ready = true;
+                    break;
+                }
+            }
+            if !ready {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "RDSEED exhausted",
+                ));
+            }
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "RDSEED unavailable",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+
+    use crate::generation::hwrng::{critical_binomial, HealthTests, HwRngGenerator};
+    use crate::generation::BigKeyGenerator;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, GeneratorId, BLOCK_1K};
+
+    // Stands in for a healthy hardware RNG
+    struct OsRandom;
+
+    impl Read for OsRandom {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            getrandom::getrandom(buf).unwrap();
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn cutoffs_match_sp800_90b() {
+        // SP 800-90B section 4.4.2, table 2 (W = 512, alpha = 2^-20)
+        assert_eq!(1 + critical_binomial(512, 0.5, 2f64.powi(-20)), 311);
+        assert_eq!(
+            1 + critical_binomial(512, 2f64.powf(-0.5), 2f64.powi(-20)),
+            410
+        );
+        assert_eq!(HealthTests::new(1.0).rct_cutoff, 21);
+    }
+
+    #[test]
+    fn stuck_and_biased_sources_fail() {
+        let mut health = HealthTests::new(1.0);
+        match health.check(&[0x55; 64]) {
+            Err(BigKeyError::HealthTestFailed { test }) => assert_eq!(test, "repetition count"),
+            _ => panic!("expected stuck source to fail"),
+        }
+
+        let biased: Vec<u8> = (0..512).map(|i| if i % 8 == 7 { 1 } else { 0 }).collect();
+        let mut health = HealthTests::new(1.0);
+        match health.check(&biased) {
+            Err(BigKeyError::HealthTestFailed { test }) => assert_eq!(test, "adaptive proportion"),
+            _ => panic!("expected biased source to fail"),
+        }
+    }
+
+    #[test]
+    fn healthy_source_generates_key() {
+        let tmp = tempfile();
+        let mut storage = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 64 * 1024).unwrap();
+        HwRngGenerator::with_source(Box::new(OsRandom))
+            .write_key(&mut storage, 64 * 1024)
+            .unwrap();
+
+        let header = DiskStorage::read_header(tmp.to_str()).unwrap().unwrap();
+        assert_eq!(header.generator, GeneratorId::HwRng);
+        assert_eq!(header.key_length, 64 * 1024);
+        drop(storage);
+
+        let mut storage = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 64 * 1024).unwrap();
+        let mut stuck = HwRngGenerator::with_source(Box::new(Cursor::new(vec![0u8; 64 * 1024])));
+        match stuck.write_key(&mut storage, 64 * 1024) {
+            Err(BigKeyError::HealthTestFailed { .. }) => {}
+            _ => panic!("expected constant source to be rejected"),
+        }
+    }
+} // mod test
@@ -1,7 +1,39 @@
 pub use self::blake3::Blake3Generator;
+pub use self::checkpoint::{
+    checkpoint_path, generate_interruptible, Checkpoint, Generation, CHECKPOINT_INTERVAL,
+};
+pub use self::child::{generate_child_key, generate_child_key_file};
+pub use self::entropy_file::{FileSeedGenerator, Whitening};
+#[cfg(feature = "escrow")]
+pub use self::escrow::{
+    escrow_path, escrow_seed, open_seed, recover_seed, seal_seed, EscrowPublicKey, EscrowSecretKey,
+};
+pub use self::hwrng::{HealthTests, HwRngGenerator};
+pub use self::pipeline::{
+    generate_pipelined, generate_pipelined_into, PipelineOptions, PipelineReport,
+    DEFAULT_CHUNK_LEN, DEFAULT_PIPELINE_DEPTH,
+};
+#[cfg(feature = "pkcs11")]
+pub use self::pkcs11::Pkcs11SeedProvider;
+pub use self::seed::{FixedSeedProvider, OsSeedProvider, SeedProvider};
 pub use self::shake256::Shake256Generator;
+pub use self::shake256x4::Shake256x4Generator;
 pub use self::traits::BigKeyGenerator;
+pub use self::verified::generate_verified;
+pub(crate) use self::verified::generate_verified_with;
 
 mod blake3;
+mod checkpoint;
+mod child;
+mod entropy_file;
+#[cfg(feature = "escrow")]
+mod escrow;
+mod hwrng;
+mod pipeline;
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+mod seed;
 mod shake256;
+mod shake256x4;
 mod traits;
+mod verified;
@@ -0,0 +1,305 @@
+//! Generating a key while it is written and verified.
+//!
+//! `generate_verified()` runs the generator twice and reads the key back only once it is
+//! complete, so provisioning a large key takes three passes, one after another.
+//! `generate_pipelined()` overlaps them: a generator thread fills chunks of the key stream, a
+//! writer thread persists each chunk as it arrives, and a verifier thread reads every written
+//! chunk back from the file, fingerprinting it and hashing its blocks into a Merkle tree. The
+//! bounded channels between the stages hold at most `depth` chunks each, so memory use does not
+//! grow with the key.
+//!
+//! The key is only finalized once all of it has been read back: the fingerprints of the
+//! generator output, of the bytes written and of the bytes read back must agree, and the Merkle
+//! root (the one a container of the key records, see `storage::ContainerWriter`) is stored in
+//! the key header. Generator streams are sequential, so one thread generates; the pipeline pays
+//! off when generating, writing and reading back each take a comparable share of the time.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SendError, SyncSender};
+use std::thread::{self, ScopedJoinHandle};
+
+use crate::generation::BigKeyGenerator;
+use crate::memory::wipe;
+use crate::storage::header::HEADER_LEN;
+use crate::storage::{DiskStorage, MerkleAccumulator, StorageWriter};
+use crate::traits::{BigKeyError, BlockSize, KeyMaterial};
+
+/// Chunk length of `PipelineOptions::default()`
+pub const DEFAULT_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Chunks in flight between two stages in `PipelineOptions::default()`
+pub const DEFAULT_PIPELINE_DEPTH: usize = 4;
+
+/// Tuning of `generate_pipelined()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineOptions {
+    /// Bytes handed from stage to stage at a time, rounded up to whole blocks
+    pub chunk_len: usize,
+    /// Chunks queued between two stages before the earlier one waits
+    pub depth: usize,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            chunk_len: DEFAULT_CHUNK_LEN,
+            depth: DEFAULT_PIPELINE_DEPTH,
+        }
+    }
+}
+
+/// A key generated by `generate_pipelined()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineReport {
+    /// BLAKE3 fingerprint of the key, as recorded in its header
+    pub fingerprint: [u8; 32],
+    /// Root of the Merkle tree over the key blocks, as recorded in its header
+    pub merkle_root: [u8; 32],
+}
+
+/// Generate a BigKey into `storage_location` with generation, writing and reading back
+/// overlapped, failing with `VerificationFailed` if the key read back differs from the key
+/// generated. Produces the same key file as `generate_verified()`, with the Merkle root of the
+/// blocks added to the header.
+pub fn generate_pipelined<G: BigKeyGenerator>(
+    block_size: BlockSize,
+    storage_location: &str,
+    seed: Option<KeyMaterial>,
+    length_bytes: usize,
+    options: &PipelineOptions,
+) -> Result<PipelineReport, BigKeyError> {
+    let writer = DiskStorage::new_writer(block_size, storage_location, length_bytes)?;
+    generate_pipelined_into::<G>(writer, storage_location, seed, length_bytes, options)
+}
+
+/// `generate_pipelined()` with a prepared `writer` of the key at `storage_location`, e.g. one
+/// recording a hash algorithm or provenance
+pub fn generate_pipelined_into<G: BigKeyGenerator>(
+    mut writer: DiskStorage,
+    storage_location: &str,
+    seed: Option<KeyMaterial>,
+    length_bytes: usize,
+    options: &PipelineOptions,
+) -> Result<PipelineReport, BigKeyError> {
+    let block_len = StorageWriter::block_size(&writer).byte_len;
+    let chunk_len = options.chunk_len.max(1).div_ceil(block_len) * block_len;
+    writer.set_generator(G::ID);
+    let readback = File::open(storage_location)?;
+
+    let (chunks_tx, chunks_rx) = sync_channel(options.depth.max(1));
+    let (written_tx, written_rx) = sync_channel(options.depth.max(1));
+    let (generated, written, verified) = thread::scope(|scope| {
+        let generator =
+            scope.spawn(move || generate_chunks::<G>(seed, length_bytes, chunk_len, chunks_tx));
+        let writer = scope.spawn(move || write_chunks(writer, chunks_rx, written_tx));
+        let verifier = scope.spawn(move || verify_chunks(readback, block_len, written_rx));
+        (join(generator), join(writer), join(verifier))
+    });
+
+    // a failed writer also stops the generator, and a failed generator the writer
+    let mut writer = written?;
+    let generated = generated?;
+    let (read_back, merkle_root) = verified?;
+    if read_back != generated {
+        return Err(BigKeyError::VerificationFailed {
+            stage: "key read back from storage differs from key generated",
+        });
+    }
+    let merkle_root = merkle_root.ok_or(BigKeyError::VerificationFailed {
+        stage: "no blocks were read back",
+    })?;
+
+    writer.set_merkle_root(merkle_root);
+    writer.finalize()?;
+    if writer.fingerprint() != Some(generated) {
+        return Err(BigKeyError::VerificationFailed {
+            stage: "key written differs from key generated",
+        });
+    }
+
+    log::debug!(
+        "generated key {} in {} byte chunks",
+        storage_location,
+        chunk_len
+    );
+    Ok(PipelineReport {
+        fingerprint: generated,
+        merkle_root,
+    })
+}
+
+// Generator stage: the key stream in chunks of `chunk_len`, returning its fingerprint
+fn generate_chunks<G: BigKeyGenerator>(
+    seed: Option<KeyMaterial>,
+    length_bytes: usize,
+    chunk_len: usize,
+    chunks: SyncSender<Vec<u8>>,
+) -> Result<[u8; 32], BigKeyError> {
+    let mut generator = G::new(seed)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut remaining = length_bytes;
+    while remaining > 0 {
+        let len = remaining.min(chunk_len);
+        let mut chunk = Vec::with_capacity(len);
+        generator.fill(&mut chunk, len)?;
+        hasher.update(&chunk);
+        if let Err(SendError(mut chunk)) = chunks.send(chunk) {
+            // the writer failed, and reports why
+            wipe(&mut chunk);
+            break;
+        }
+        remaining -= len;
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+// Writer stage: persist each chunk, then tell the verifier its length
+fn write_chunks(
+    mut writer: DiskStorage,
+    chunks: Receiver<Vec<u8>>,
+    written: SyncSender<usize>,
+) -> Result<DiskStorage, BigKeyError> {
+    for mut chunk in chunks {
+        let result = writer.write_all(&chunk);
+        wipe(&mut chunk);
+        result?;
+        // a failed verifier reports why once joined
+        let _ = written.send(chunk.len());
+    }
+    Ok(writer)
+}
+
+// Verifier stage: read each written chunk back from `file`, returning the fingerprint and
+// Merkle root of everything read
+fn verify_chunks(
+    mut file: File,
+    block_len: usize,
+    written: Receiver<usize>,
+) -> Result<([u8; 32], Option<[u8; 32]>), BigKeyError> {
+    let mut hasher = blake3::Hasher::new();
+    let mut tree = MerkleAccumulator::default();
+    let mut chunk = Vec::new();
+
+    let read_back = || -> Result<(), BigKeyError> {
+        file.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        for len in written {
+            chunk.resize(len, 0);
+            file.read_exact(&mut chunk)?;
+            hasher.update(&chunk);
+            chunk
+                .chunks(block_len)
+                .for_each(|block| tree.push_block(block));
+        }
+        Ok(())
+    };
+    let result = read_back();
+    wipe(&mut chunk);
+    result?;
+    Ok((*hasher.finalize().as_bytes(), tree.root()))
+}
+
+fn join<T>(handle: ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generation::{
+        generate_pipelined, BigKeyGenerator, PipelineOptions, Shake256Generator,
+    };
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{pack, DiskStorage, StorageReader};
+    use crate::traits::{BigKeyError, BlockIndex, GeneratorId, BLOCK_1K};
+
+    #[test]
+    fn pipelined_keys_are_verified_with_merkle_roots() {
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let length = 200 * 1024;
+        let mut expected = Vec::new();
+        Shake256Generator::new(Some(seed.clone().into()))
+            .unwrap()
+            .fill(&mut expected, length)
+            .unwrap();
+
+        let tmp = tempfile();
+        let options = PipelineOptions {
+            chunk_len: 3000,
+            depth: 1,
+        };
+        let report = generate_pipelined::<Shake256Generator>(
+            BLOCK_1K,
+            tmp.to_str(),
+            Some(seed.into()),
+            length,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(report.fingerprint, *blake3::hash(&expected).as_bytes());
+
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let header = storage.header().unwrap().clone();
+        assert_eq!(header.generator, GeneratorId::Shake256);
+        assert_eq!(header.fingerprint, Some(report.fingerprint));
+        assert_eq!(header.merkle_root, Some(report.merkle_root));
+        let mut block = vec![0u8; 1024];
+        storage.probe(BlockIndex::new(199), &mut block).unwrap();
+        assert_eq!(&block[..], &expected[199 * 1024..]);
+
+        // the root is the one a container of the key records
+        let container = tempfile();
+        let manifest = pack(&mut storage, GeneratorId::Shake256, container.to_str()).unwrap();
+        assert_eq!(manifest.merkle_root, Some(report.merkle_root));
+
+        let uneven = tempfile();
+        assert!(matches!(
+            generate_pipelined::<Shake256Generator>(
+                BLOCK_1K,
+                uneven.to_str(),
+                None,
+                length + 1,
+                &PipelineOptions::default(),
+            ),
+            Err(BigKeyError::KeyLengthIndivisible { .. })
+        ));
+    }
+
+    #[test]
+    fn degenerate_options_and_failing_generators() {
+        // zero chunk lengths and depths are clamped rather than stalling the pipeline
+        let tmp = tempfile();
+        let options = PipelineOptions {
+            chunk_len: 0,
+            depth: 0,
+        };
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let report = generate_pipelined::<Shake256Generator>(
+            BLOCK_1K,
+            tmp.to_str(),
+            Some(seed.into()),
+            8 * 1024,
+            &options,
+        )
+        .unwrap();
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        assert_eq!(
+            storage.header().unwrap().merkle_root,
+            Some(report.merkle_root)
+        );
+
+        // a generator failing up front stops the writer and verifier instead of hanging them
+        let short = tempfile();
+        assert!(matches!(
+            generate_pipelined::<Shake256Generator>(
+                BLOCK_1K,
+                short.to_str(),
+                Some(b"too short".to_vec().into()),
+                8 * 1024,
+                &PipelineOptions::default(),
+            ),
+            Err(BigKeyError::SeedTooShort { .. })
+        ));
+    }
+} // mod test
@@ -0,0 +1,559 @@
+//! Generation seeds derived inside a PKCS#11 token.
+//!
+//! The root secret of a deterministic BigKey can live in an HSM or smart card as a generic
+//! secret key object that is never extracted. `Pkcs11SeedProvider` has the token compute the
+//! seed as HMAC-SHA256 under that key: block `i` of the seed is
+//! `HMAC(root, SEED_CONTEXT || i as u32 big endian)`, and the blocks are concatenated and
+//! truncated to the requested length. The same token key therefore regenerates the same BigKey,
+//! while the root itself never exists in host memory; the seed does, for the duration of
+//! generation.
+//!
+//! The token's PKCS#11 module is loaded at runtime, so building needs no PKCS#11 headers or
+//! libraries:
+//!
+//! ```ignore
+//! let mut provider = Pkcs11SeedProvider::open("/usr/lib/softhsm/libsofthsm2.so", 0, "bigkey-root")?
+//!     .with_pin(b"1234");
+//! let seed = provider.seed(64)?;
+//! ```
+//!
+//! Only built with the `pkcs11` feature.
+
+use std::ffi::{c_void, OsStr};
+use std::os::raw::c_ulong;
+use std::ptr;
+
+use libloading::Library;
+
+use crate::generation::SeedProvider;
+use crate::memory::wipe;
+use crate::traits::{BigKeyError, KeyMaterial};
+
+/// Context the token's HMAC key is applied to, followed by the block counter
+pub const SEED_CONTEXT: &[u8] = b"big_fluffy_dise 2024 pkcs11 generation seed v1";
+
+// Output length of CKM_SHA256_HMAC
+const HMAC_LEN: usize = 32;
+
+type CkUlong = c_ulong;
+type CkRv = CkUlong;
+
+const CKR_OK: CkRv = 0;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+const CKF_SERIAL_SESSION: CkUlong = 0x4;
+const CKU_USER: CkUlong = 1;
+const CKA_CLASS: CkUlong = 0x0;
+const CKA_LABEL: CkUlong = 0x3;
+const CKO_SECRET_KEY: CkUlong = 0x4;
+const CKM_SHA256_HMAC: CkUlong = 0x251;
+
+#[repr(C)]
+struct CkVersion {
+    major: u8,
+    minor: u8,
+}
+
+#[repr(C)]
+struct CkAttribute {
+    kind: CkUlong,
+    value: *mut c_void,
+    value_len: CkUlong,
+}
+
+#[repr(C)]
+struct CkMechanism {
+    mechanism: CkUlong,
+    parameter: *mut c_void,
+    parameter_len: CkUlong,
+}
+
+type Unused = Option<unsafe extern "C" fn()>;
+
+// CK_FUNCTION_LIST up to C_Sign, in the order of the PKCS#11 specification. Modules own the
+// list and it is only read through a pointer, so the entries after C_Sign can be left out.
+#[repr(C)]
+struct FunctionList {
+    version: CkVersion,
+    initialize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    finalize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    // C_GetInfo to C_SetPIN
+    _info: [Unused; 10],
+    open_session: Option<
+        unsafe extern "C" fn(CkUlong, CkUlong, *mut c_void, *mut c_void, *mut CkUlong) -> CkRv,
+    >,
+    close_session: Option<unsafe extern "C" fn(CkUlong) -> CkRv>,
+    // C_CloseAllSessions to C_SetOperationState
+    _sessions: [Unused; 4],
+    login: Option<unsafe extern "C" fn(CkUlong, CkUlong, *const u8, CkUlong) -> CkRv>,
+    logout: Option<unsafe extern "C" fn(CkUlong) -> CkRv>,
+    // C_CreateObject to C_SetAttributeValue
+    _objects: [Unused; 6],
+    find_objects_init: Option<unsafe extern "C" fn(CkUlong, *const CkAttribute, CkUlong) -> CkRv>,
+    find_objects:
+        Option<unsafe extern "C" fn(CkUlong, *mut CkUlong, CkUlong, *mut CkUlong) -> CkRv>,
+    find_objects_final: Option<unsafe extern "C" fn(CkUlong) -> CkRv>,
+    // C_EncryptInit to C_DigestFinal
+    _crypt: [Unused; 13],
+    sign_init: Option<unsafe extern "C" fn(CkUlong, *const CkMechanism, CkUlong) -> CkRv>,
+    sign: Option<unsafe extern "C" fn(CkUlong, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv>,
+}
+
+/// A `SeedProvider` computing seeds with an HMAC key kept in a PKCS#11 token
+pub struct Pkcs11SeedProvider {
+    functions: *const FunctionList,
+    // finalize the module on drop, unless another user in the process initialized it
+    finalize: bool,
+    slot: CkUlong,
+    key_label: String,
+    pin: Option<KeyMaterial>,
+    // keeps the module's code mapped, dropped last
+    _module: Option<Library>,
+}
+
+impl Pkcs11SeedProvider {
+    /// Load the PKCS#11 module at `module` and use the secret key labelled `key_label` in the
+    /// token in `slot`
+    pub fn open(
+        module: impl AsRef<OsStr>,
+        slot: u64,
+        key_label: &str,
+    ) -> Result<Self, BigKeyError> {
+        // Safety: loading a PKCS#11 module runs its initializers, which is what the caller asks
+        // for by naming it
+        let library = unsafe { Library::new(module.as_ref()) }
+            .map_err(|e| failed("loading module", e.to_string()))?;
+        let mut functions: *const FunctionList = ptr::null();
+        // Safety: C_GetFunctionList has this signature in every PKCS#11 version
+        let rv = unsafe {
+            let get_function_list = library
+                .get::<unsafe extern "C" fn(*mut *const FunctionList) -> CkRv>(
+                    b"C_GetFunctionList\0",
+                )
+                .map_err(|e| failed("loading module", e.to_string()))?;
+            get_function_list(&mut functions)
+        };
+        check("C_GetFunctionList", rv)?;
+        let mut provider = Pkcs11SeedProvider::from_function_list(functions, slot, key_label)?;
+        provider._module = Some(library);
+        Ok(provider)
+    }
+
+    fn from_function_list(
+        functions: *const FunctionList,
+        slot: u64,
+        key_label: &str,
+    ) -> Result<Self, BigKeyError> {
+        if functions.is_null() {
+            return Err(failed("C_GetFunctionList", "no function list".to_string()));
+        }
+        // Safety: the module keeps its function list valid while it is loaded
+        let rv = unsafe { call((*functions).initialize, "C_Initialize")?(ptr::null_mut()) };
+        let finalize = rv != CKR_CRYPTOKI_ALREADY_INITIALIZED;
+        if finalize {
+            check("C_Initialize", rv)?;
+        }
+        Ok(Pkcs11SeedProvider {
+            functions,
+            finalize,
+            slot: slot as CkUlong,
+            key_label: key_label.to_string(),
+            pin: None,
+            _module: None,
+        })
+    }
+
+    /// Log in as the token's user with `pin` before using the key
+    pub fn with_pin(mut self, pin: &[u8]) -> Self {
+        self.pin = Some(pin.to_vec().into_boxed_slice());
+        self
+    }
+
+    // HMAC of `data` under `key` in `session`
+    fn hmac(
+        &self,
+        session: CkUlong,
+        key: CkUlong,
+        data: &[u8],
+    ) -> Result<[u8; HMAC_LEN], BigKeyError> {
+        let mechanism = CkMechanism {
+            mechanism: CKM_SHA256_HMAC,
+            parameter: ptr::null_mut(),
+            parameter_len: 0,
+        };
+        let mut mac = [0u8; HMAC_LEN];
+        let mut mac_len = HMAC_LEN as CkUlong;
+        // Safety: every pointer refers to a live local of the length passed with it
+        unsafe {
+            let functions = &*self.functions;
+            check(
+                "C_SignInit",
+                call(functions.sign_init, "C_SignInit")?(session, &mechanism, key),
+            )?;
+            check(
+                "C_Sign",
+                call(functions.sign, "C_Sign")?(
+                    session,
+                    data.as_ptr(),
+                    data.len() as CkUlong,
+                    mac.as_mut_ptr(),
+                    &mut mac_len,
+                ),
+            )?;
+        }
+        if mac_len as usize != HMAC_LEN {
+            wipe(&mut mac);
+            return Err(failed("C_Sign", format!("{} byte HMAC", mac_len)));
+        }
+        Ok(mac)
+    }
+
+    // Handle of the one secret key labelled `key_label`
+    fn find_key(&self, session: CkUlong) -> Result<CkUlong, BigKeyError> {
+        let mut class = CKO_SECRET_KEY;
+        let template = [
+            CkAttribute {
+                kind: CKA_CLASS,
+                value: &mut class as *mut CkUlong as *mut c_void,
+                value_len: std::mem::size_of::<CkUlong>() as CkUlong,
+            },
+            CkAttribute {
+                kind: CKA_LABEL,
+                value: self.key_label.as_ptr() as *mut c_void,
+                value_len: self.key_label.len() as CkUlong,
+            },
+        ];
+        let mut keys = [0 as CkUlong; 2];
+        let mut found: CkUlong = 0;
+        // Safety: the template and output buffers are live locals of the lengths passed
+        unsafe {
+            let functions = &*self.functions;
+            check(
+                "C_FindObjectsInit",
+                call(functions.find_objects_init, "C_FindObjectsInit")?(
+                    session,
+                    template.as_ptr(),
+                    template.len() as CkUlong,
+                ),
+            )?;
+            let rv = call(functions.find_objects, "C_FindObjects")?(
+                session,
+                keys.as_mut_ptr(),
+                keys.len() as CkUlong,
+                &mut found,
+            );
+            call(functions.find_objects_final, "C_FindObjectsFinal")?(session);
+            check("C_FindObjects", rv)?;
+        }
+        match found {
+            1 => Ok(keys[0]),
+            0 => Err(failed(
+                "C_FindObjects",
+                format!("no secret key labelled {:?}", self.key_label),
+            )),
+            _ => Err(failed(
+                "C_FindObjects",
+                format!("several secret keys labelled {:?}", self.key_label),
+            )),
+        }
+    }
+
+    // Fill `seed` with HMAC blocks computed in `session`
+    fn fill_seed(&self, session: CkUlong, seed: &mut [u8]) -> Result<(), BigKeyError> {
+        if let Some(pin) = &self.pin {
+            // Safety: the PIN outlives the call
+            let rv = unsafe {
+                call((*self.functions).login, "C_Login")?(
+                    session,
+                    CKU_USER,
+                    pin.as_ptr(),
+                    pin.len() as CkUlong,
+                )
+            };
+            if rv != CKR_USER_ALREADY_LOGGED_IN {
+                check("C_Login", rv)?;
+            }
+        }
+        let key = self.find_key(session)?;
+
+        let mut data = SEED_CONTEXT.to_vec();
+        data.extend_from_slice(&[0u8; 4]);
+        let counter_at = SEED_CONTEXT.len();
+        for (i, chunk) in seed.chunks_mut(HMAC_LEN).enumerate() {
+            data[counter_at..].copy_from_slice(&(i as u32).to_be_bytes());
+            let mut mac = self.hmac(session, key, &data)?;
+            chunk.copy_from_slice(&mac[..chunk.len()]);
+            wipe(&mut mac);
+        }
+        Ok(())
+    }
+}
+
+impl SeedProvider for Pkcs11SeedProvider {
+    fn seed(&mut self, len: usize) -> Result<KeyMaterial, BigKeyError> {
+        let mut session: CkUlong = 0;
+        // Safety: no application data or notification callback is passed
+        let rv = unsafe {
+            call((*self.functions).open_session, "C_OpenSession")?(
+                self.slot,
+                CKF_SERIAL_SESSION,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut session,
+            )
+        };
+        check("C_OpenSession", rv)?;
+
+        let mut seed = vec![0u8; len].into_boxed_slice();
+        let result = self.fill_seed(session, &mut seed);
+        // Safety: the session was opened above; closing it also logs out
+        unsafe {
+            if let Some(close_session) = (*self.functions).close_session {
+                close_session(session);
+            }
+        }
+        match result {
+            Ok(()) => Ok(seed),
+            Err(e) => {
+                wipe(&mut seed);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Pkcs11SeedProvider {
+    fn drop(&mut self) {
+        if let Some(pin) = &mut self.pin {
+            wipe(pin);
+        }
+        if self.finalize {
+            // Safety: the module is still loaded, `_module` is dropped after this
+            unsafe {
+                if let Some(finalize) = (*self.functions).finalize {
+                    finalize(ptr::null_mut());
+                }
+            }
+        }
+    }
+}
+
+fn call<F>(function: Option<F>, name: &'static str) -> Result<F, BigKeyError> {
+    function.ok_or_else(|| failed(name, "not provided by the module".to_string()))
+}
+
+fn check(op: &'static str, rv: CkRv) -> Result<(), BigKeyError> {
+    match rv {
+        CKR_OK => Ok(()),
+        rv => Err(failed(op, format!("CKR {:#x}", rv))),
+    }
+}
+
+fn failed(op: &'static str, reason: String) -> BigKeyError {
+    BigKeyError::Pkcs11Failed { op, reason }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use crate::generation::pkcs11::{
+        CkAttribute, CkMechanism, CkRv, CkUlong, CkVersion, FunctionList, Pkcs11SeedProvider,
+        CKA_LABEL, CKM_SHA256_HMAC, CKR_OK, SEED_CONTEXT,
+    };
+    use crate::generation::SeedProvider;
+    use crate::traits::BigKeyError;
+
+    // A token holding one HMAC key, labelled "root", usable after logging in with PIN "1234".
+    // Its "HMAC" is keyed BLAKE3 under a fixed key, which is all a deterministic seed needs.
+    const ROOT: [u8; 32] = [0x5a; 32];
+    const CKR_PIN_INCORRECT: CkRv = 0xa0;
+    const CKR_USER_NOT_LOGGED_IN: CkRv = 0x101;
+
+    // Open sessions: (handle, logged in, keys found by the last search)
+    static SESSIONS: Mutex<Vec<(CkUlong, bool, CkUlong)>> = Mutex::new(Vec::new());
+    static NEXT_SESSION: AtomicUsize = AtomicUsize::new(1);
+
+    fn with_session<T>(handle: CkUlong, f: impl FnOnce(&mut (CkUlong, bool, CkUlong)) -> T) -> T {
+        let mut sessions = SESSIONS.lock().unwrap();
+        f(sessions
+            .iter_mut()
+            .find(|s| s.0 == handle)
+            .expect("open session"))
+    }
+
+    fn is_open(handle: CkUlong) -> bool {
+        SESSIONS.lock().unwrap().iter().any(|s| s.0 == handle)
+    }
+
+    unsafe extern "C" fn initialize(_args: *mut c_void) -> CkRv {
+        CKR_OK
+    }
+
+    unsafe extern "C" fn open_session(
+        _slot: CkUlong,
+        _flags: CkUlong,
+        _app: *mut c_void,
+        _notify: *mut c_void,
+        session: *mut CkUlong,
+    ) -> CkRv {
+        *session = NEXT_SESSION.fetch_add(1, Ordering::SeqCst) as CkUlong;
+        SESSIONS.lock().unwrap().push((*session, false, 0));
+        CKR_OK
+    }
+
+    unsafe extern "C" fn close_session(session: CkUlong) -> CkRv {
+        SESSIONS.lock().unwrap().retain(|s| s.0 != session);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn login(
+        session: CkUlong,
+        _user: CkUlong,
+        pin: *const u8,
+        len: CkUlong,
+    ) -> CkRv {
+        if std::slice::from_raw_parts(pin, len as usize) != b"1234" {
+            return CKR_PIN_INCORRECT;
+        }
+        with_session(session, |s| s.1 = true);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn find_objects_init(
+        session: CkUlong,
+        template: *const CkAttribute,
+        count: CkUlong,
+    ) -> CkRv {
+        let template = std::slice::from_raw_parts(template, count as usize);
+        let root = template.iter().any(|attribute| {
+            attribute.kind == CKA_LABEL
+                && std::slice::from_raw_parts(
+                    attribute.value as *const u8,
+                    attribute.value_len as usize,
+                ) == b"root"
+        });
+        with_session(session, |s| s.2 = root as CkUlong);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn find_objects(
+        session: CkUlong,
+        objects: *mut CkUlong,
+        _max: CkUlong,
+        found: *mut CkUlong,
+    ) -> CkRv {
+        *objects = 7;
+        *found = with_session(session, |s| s.2);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn find_objects_final(_session: CkUlong) -> CkRv {
+        CKR_OK
+    }
+
+    unsafe extern "C" fn sign_init(
+        session: CkUlong,
+        mechanism: *const CkMechanism,
+        key: CkUlong,
+    ) -> CkRv {
+        assert_eq!((*mechanism).mechanism, CKM_SHA256_HMAC);
+        assert_eq!(key, 7);
+        match with_session(session, |s| s.1) {
+            true => CKR_OK,
+            false => CKR_USER_NOT_LOGGED_IN,
+        }
+    }
+
+    unsafe extern "C" fn sign(
+        session: CkUlong,
+        data: *const u8,
+        len: CkUlong,
+        mac: *mut u8,
+        mac_len: *mut CkUlong,
+    ) -> CkRv {
+        assert!(is_open(session));
+        let data = std::slice::from_raw_parts(data, len as usize);
+        let hash = blake3::keyed_hash(&ROOT, data);
+        std::ptr::copy_nonoverlapping(hash.as_bytes().as_ptr(), mac, 32);
+        *mac_len = 32;
+        CKR_OK
+    }
+
+    static TOKEN: FunctionList = FunctionList {
+        version: CkVersion {
+            major: 2,
+            minor: 40,
+        },
+        initialize: Some(initialize),
+        finalize: None,
+        _info: [None; 10],
+        open_session: Some(open_session),
+        close_session: Some(close_session),
+        _sessions: [None; 4],
+        login: Some(login),
+        logout: None,
+        _objects: [None; 6],
+        find_objects_init: Some(find_objects_init),
+        find_objects: Some(find_objects),
+        find_objects_final: Some(find_objects_final),
+        _crypt: [None; 13],
+        sign_init: Some(sign_init),
+        sign: Some(sign),
+    };
+
+    #[test]
+    fn seeds_are_computed_by_the_token() {
+        let mut provider = Pkcs11SeedProvider::from_function_list(&TOKEN, 0, "root")
+            .unwrap()
+            .with_pin(b"1234");
+        let seed = provider.seed(80).unwrap();
+        assert_eq!(seed.len(), 80);
+        assert_eq!(provider.seed(80).unwrap(), seed);
+        assert_eq!(provider.seed(20).unwrap()[..], seed[..20]);
+
+        let block = |i: u32| {
+            let mut data = SEED_CONTEXT.to_vec();
+            data.extend_from_slice(&i.to_be_bytes());
+            blake3::keyed_hash(&ROOT, &data)
+        };
+        assert_eq!(seed[..32], block(0).as_bytes()[..]);
+        assert_eq!(seed[64..], block(2).as_bytes()[..16]);
+    }
+
+    #[test]
+    fn token_failures_are_reported() {
+        let mut wrong_pin = Pkcs11SeedProvider::from_function_list(&TOKEN, 0, "root")
+            .unwrap()
+            .with_pin(b"0000");
+        match wrong_pin.seed(32) {
+            Err(BigKeyError::Pkcs11Failed { op: "C_Login", .. }) => {}
+            _ => panic!("expected a wrong PIN to be refused"),
+        }
+
+        let mut no_pin = Pkcs11SeedProvider::from_function_list(&TOKEN, 0, "root").unwrap();
+        match no_pin.seed(32) {
+            Err(BigKeyError::Pkcs11Failed {
+                op: "C_SignInit", ..
+            }) => {}
+            _ => panic!("expected signing without logging in to fail"),
+        }
+
+        let mut unknown = Pkcs11SeedProvider::from_function_list(&TOKEN, 0, "other")
+            .unwrap()
+            .with_pin(b"1234");
+        match unknown.seed(32) {
+            Err(BigKeyError::Pkcs11Failed {
+                op: "C_FindObjects",
+                reason,
+            }) => {
+                assert!(reason.contains("other"))
+            }
+            _ => panic!("expected an unknown key label to fail"),
+        }
+
+        assert!(Pkcs11SeedProvider::open("/nonexistent/libpkcs11.so", 0, "root").is_err());
+    }
+} // mod test
@@ -0,0 +1,104 @@
+//! Sources of BigKey generation seeds.
+//!
+//! Generators take their seed as a `KeyMaterial`; a `SeedProvider` decides where it comes from.
+//! Deployments keeping their root secret in a hardware token implement `SeedProvider` over the
+//! token's API, unwrapping the seed only for the duration of generation.
+
+use crate::memory::wipe;
+use crate::traits::{BigKeyError, KeyMaterial};
+
+/// Supplies the seed of a new BigKey
+pub trait SeedProvider {
+    /// Produce a seed of `len` bytes
+    fn seed(&mut self, len: usize) -> Result<KeyMaterial, BigKeyError>;
+}
+
+/// Fresh seeds from operating system randomness, the resulting BigKey cannot be regenerated
+#[derive(Debug, Default, Copy, Clone)]
+pub struct OsSeedProvider;
+
+impl SeedProvider for OsSeedProvider {
+    fn seed(&mut self, len: usize) -> Result<KeyMaterial, BigKeyError> {
+        let mut seed = vec![0u8; len];
+        getrandom::getrandom(&mut seed)?;
+        Ok(seed.into_boxed_slice())
+    }
+}
+
+/// A fixed seed held in memory, for deterministic BigKeys whose seed is managed by the caller.
+/// Seeds shorter than the seed are its first `len` bytes.
+pub struct FixedSeedProvider {
+    seed: KeyMaterial,
+}
+
+impl FixedSeedProvider {
+    pub fn new(seed: KeyMaterial) -> Self {
+        FixedSeedProvider { seed }
+    }
+}
+
+impl Drop for FixedSeedProvider {
+    fn drop(&mut self) {
+        wipe(&mut self.seed);
+    }
+}
+
+impl SeedProvider for FixedSeedProvider {
+    fn seed(&mut self, len: usize) -> Result<KeyMaterial, BigKeyError> {
+        if self.seed.len() < len {
+            return Err(BigKeyError::SeedTooShort {
+                seed_len: self.seed.len(),
+                req_len: len,
+            });
+        }
+        Ok(self.seed[..len].to_vec().into_boxed_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generation::{
+        BigKeyGenerator, FixedSeedProvider, OsSeedProvider, SeedProvider, Shake256Generator,
+    };
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn providers_supply_seeds() {
+        let a = OsSeedProvider.seed(32).unwrap();
+        let b = OsSeedProvider.seed(32).unwrap();
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, b);
+
+        let seed: Vec<u8> = (0..32).collect();
+        let mut fixed = FixedSeedProvider::new(seed.clone().into_boxed_slice());
+        assert_eq!(fixed.seed(32).unwrap()[..], seed[..]);
+        assert_eq!(fixed.seed(16).unwrap()[..], seed[..16]);
+        assert!(fixed.seed(0).unwrap().is_empty());
+        match fixed.seed(64) {
+            Err(BigKeyError::SeedTooShort { .. }) => {}
+            _ => panic!("expected short fixed seed to be rejected"),
+        }
+    }
+
+    #[test]
+    fn fixed_seeds_regenerate_the_same_key() {
+        let mut fixed = FixedSeedProvider::new(vec![0x5a; 64].into_boxed_slice());
+        let mut keys = Vec::new();
+        for _ in 0..2 {
+            let mut key = Vec::new();
+            Shake256Generator::new(Some(fixed.seed(32).unwrap()))
+                .unwrap()
+                .fill(&mut key, 4096)
+                .unwrap();
+            keys.push(key);
+        }
+        assert_eq!(keys[0], keys[1]);
+
+        // a prefix too short for the generator is refused by the generator, not truncated
+        match Shake256Generator::new(Some(fixed.seed(16).unwrap())) {
+            Err(BigKeyError::SeedTooShort { seed_len: 16, .. }) => {}
+            _ => panic!("expected a 16 byte seed to be too short"),
+        }
+        assert!(OsSeedProvider.seed(0).unwrap().is_empty());
+    }
+} // mod test
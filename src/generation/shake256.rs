@@ -1,31 +1,61 @@
-use std::io::{Error, Read};
+use std::convert::TryInto;
+use std::io::Write;
 
-use digest::{ExtendableOutput, Update};
-use sha3::{Sha3XofReader, Shake256};
-
-use crate::generation::traits::BigKeyGenerator;
-use crate::storage::StorageWriter;
-use crate::traits::{BigKeyError, KeyMaterial};
+use crate::generation::traits::{state_body, BigKeyGenerator};
+use crate::memory::wipe;
+use crate::traits::{BigKeyError, GeneratorId, KeyMaterial};
 
 // Minimum acceptable seed length in bytes
-const MIN_SEED_LENGTH: usize = 32;
+pub(super) const MIN_SEED_LENGTH: usize = 32;
 
 // Shake256 has no restriction on output length. We'll arbitrarily limit it at 2^64 which would
 // be a very large BigKey indeed. See the SHA3 standard for details:
 //   https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.202.pdf#page=31
-const MAX_OUTPUT_LENGTH: usize = u64::max_value() as usize;
+pub(super) const MAX_OUTPUT_LENGTH: usize = u64::MAX as usize;
+
+// SHAKE256 rate in bytes (1600 - 2 * 256 bits of capacity)
+pub(super) const RATE: usize = 136;
+
+// Keccak-f[1600] state lanes
+pub(super) const LANES: usize = 25;
+
+// Serialized state: generator id, lanes, offset of the next output byte in the current block
+const STATE_LEN: usize = 2 + LANES * 8 + 1;
+
+// Bytes written to the writer per `fill()` step
+pub(super) const FILL_CHUNK: usize = 64 * 1024;
 
 /// Generate the contents of a BigKey using Shake256 from SHA3
+///
+/// SHAKE256 throughput bounds generation speed. Enabling the `keccak-asm` feature switches the
+/// Keccak permutation to hardware SHA3 instructions where available (ARMv8) without changing the
+/// output stream. The key is a single sequential XOF stream, so multi-buffer (multi-lane) Keccak
+/// implementations cannot accelerate it; `Shake256x4Generator` defines a four-lane stream that
+/// they can.
+///
+/// The sponge is driven directly over the Keccak permutation, rather than through `sha3`, so its
+/// state can be exported by `state()`.
 pub struct Shake256Generator {
-    xof: Sha3XofReader,
+    pub(super) lanes: [u64; LANES],
+    // offset of the next output byte within the current block; `RATE` once it is used up
+    pos: usize,
 }
 
 impl BigKeyGenerator for Shake256Generator {
-    fn generate(
-        storage_method: &mut impl StorageWriter,
-        optional_seed: Option<KeyMaterial>,
-        length_bytes: usize,
-    ) -> Result<(), BigKeyError> {
+    const ID: GeneratorId = GeneratorId::Shake256;
+
+    fn new(seed: Option<KeyMaterial>) -> Result<Self, BigKeyError> {
+        let mut seed = seed.ok_or(BigKeyError::SeedTooShort {
+            seed_len: 0,
+            req_len: MIN_SEED_LENGTH,
+        })?;
+        let generator = Shake256Generator::from_seed(&seed);
+        wipe(&mut seed);
+        generator
+    }
+
+    fn fill(&mut self, writer: &mut impl Write, length_bytes: usize) -> Result<(), BigKeyError> {
+        #[allow(clippy::absurd_extreme_comparisons)]
         if length_bytes > MAX_OUTPUT_LENGTH {
             return Err(BigKeyError::OutputLengthTooLong {
                 out_len: length_bytes,
@@ -33,26 +63,50 @@ impl BigKeyGenerator for Shake256Generator {
             });
         }
 
-        let seed = optional_seed.unwrap();
-        let mut generator = Shake256Generator::from_seed(&seed)?;
+        let mut buf = vec![0u8; length_bytes.min(FILL_CHUNK)];
+        let mut remaining = length_bytes;
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(FILL_CHUNK)];
+            self.fill_bytes(chunk);
+            writer.write_all(chunk)?;
+            remaining -= chunk.len();
+        }
+        wipe(&mut buf);
 
-        let mut buf = vec![0u8; storage_method.block_size().byte_len];
-        let mut total_written = 0usize;
+        Ok(())
+    }
 
-        while total_written < length_bytes {
-            generator.fill_bytes(buf.as_mut_slice())?;
-            storage_method.write_all(&buf)?;
-            total_written += buf.capacity();
+    fn state(&self) -> KeyMaterial {
+        let mut state = Vec::with_capacity(STATE_LEN);
+        state.extend_from_slice(&(Self::ID as u16).to_be_bytes());
+        for lane in self.lanes.iter() {
+            state.extend_from_slice(&lane.to_le_bytes());
         }
+        state.push(self.pos as u8);
+        state.into_boxed_slice()
+    }
 
-        storage_method.finalize()?;
+    fn restore(state: &[u8]) -> Result<Self, BigKeyError> {
+        let body = state_body(state, Self::ID)?;
+        if state.len() != STATE_LEN || body[LANES * 8] as usize > RATE {
+            return Err(BigKeyError::InvalidGeneratorState {
+                reason: "malformed SHAKE256 state",
+            });
+        }
 
-        Ok(())
+        let mut lanes = [0u64; LANES];
+        for (lane, bytes) in lanes.iter_mut().zip(body.chunks_exact(8)) {
+            *lane = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Ok(Shake256Generator {
+            lanes,
+            pos: body[LANES * 8] as usize,
+        })
     }
 }
 
 impl Shake256Generator {
-    fn from_seed(seed: &[u8]) -> Result<Self, BigKeyError> {
+    pub(super) fn from_seed(seed: &[u8]) -> Result<Self, BigKeyError> {
         if seed.len() < MIN_SEED_LENGTH {
             return Err(BigKeyError::SeedTooShort {
                 seed_len: seed.len(),
@@ -60,29 +114,63 @@ impl Shake256Generator {
             });
         }
 
-        let mut hash = Shake256::default();
-        hash.update(seed);
+        let mut generator = Shake256Generator {
+            lanes: [0u64; LANES],
+            pos: 0,
+        };
 
-        Ok(Shake256Generator {
-            xof: hash.finalize_xof(),
-        })
+        // absorb, then pad the final block with the SHAKE domain bits and pad10*1
+        let mut blocks = seed.chunks_exact(RATE);
+        for block in &mut blocks {
+            generator.absorb(block);
+        }
+        let mut last = [0u8; RATE];
+        let rest = blocks.remainder();
+        last[..rest.len()].copy_from_slice(rest);
+        last[rest.len()] ^= 0x1f;
+        last[RATE - 1] ^= 0x80;
+        generator.absorb(&last);
+        wipe(&mut last);
+
+        Ok(generator)
     }
 
-    fn fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
-        self.xof.read_exact(dest)
+    fn absorb(&mut self, block: &[u8]) {
+        for (i, byte) in block.iter().enumerate() {
+            self.lanes[i / 8] ^= (*byte as u64) << (8 * (i % 8));
+        }
+        keccak::f1600(&mut self.lanes);
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut dest = dest;
+        while !dest.is_empty() {
+            if self.pos == RATE {
+                keccak::f1600(&mut self.lanes);
+                self.pos = 0;
+            }
+            let take = (RATE - self.pos).min(dest.len());
+            for (i, out) in dest[..take].iter_mut().enumerate() {
+                let offset = self.pos + i;
+                *out = (self.lanes[offset / 8] >> (8 * (offset % 8))) as u8;
+            }
+            self.pos += take;
+            dest = &mut dest[take..];
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::fs::File;
-    use std::io::{Error, ErrorKind, Read};
+    use std::io::{Read, Seek, SeekFrom};
 
     use crate::generation::shake256::Shake256Generator;
     use crate::generation::traits::BigKeyGenerator;
-    use crate::storage::{DiskStorage, StorageWriter};
-    use crate::traits::{BigKeyError, BLOCK_8};
+    use crate::storage::header::HEADER_LEN;
     use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, GeneratorId, BLOCK_8};
 
     #[test]
     fn shake_256_known_answer_test() {
@@ -91,11 +179,11 @@ mod test {
         let expected = [0x5a, 0x81, 0x82, 0xc1, 0xe3, 0x72, 0x89, 0xf4];
 
         let mut buf = [0u8; 8];
-        gen.fill_bytes(buf.as_mut()).unwrap();
+        gen.fill_bytes(buf.as_mut());
         assert_eq!(buf, expected);
 
         // Second fill must be different
-        gen.fill_bytes(buf.as_mut()).unwrap();
+        gen.fill_bytes(buf.as_mut());
         assert_ne!(buf, expected);
     }
 
@@ -118,10 +206,39 @@ mod test {
 
         Shake256Generator::generate(&mut storage, Some(seed.into_boxed_slice()), 8).unwrap();
 
+        assert_eq!(storage.header().unwrap().generator, GeneratorId::Shake256);
+
         let mut infile = File::open(tmp.as_path()).unwrap();
+        infile.seek(SeekFrom::Start(HEADER_LEN as u64)).unwrap();
         let mut buf = [0u8; 8];
         infile.read_exact(buf.as_mut()).unwrap();
 
         assert_eq!(buf, expected);
     }
+
+    #[test]
+    fn paused_generation_resumes() {
+        let seed = b"0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef".to_vec();
+        let mut whole = Vec::new();
+        let mut gen = Shake256Generator::new(Some(seed.clone().into_boxed_slice())).unwrap();
+        gen.fill(&mut whole, 1000).unwrap();
+
+        // stream into two writers, pausing at a point that is not a block boundary
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        let mut gen = Shake256Generator::new(Some(seed.into_boxed_slice())).unwrap();
+        gen.fill(&mut first, 1).unwrap();
+        gen.fill(&mut first, 300).unwrap();
+        let state = gen.state();
+
+        let mut gen = Shake256Generator::restore(&state).unwrap();
+        gen.fill(&mut second, 699).unwrap();
+        assert_eq!(&whole[..301], &first[..]);
+        assert_eq!(&whole[301..], &second[..]);
+
+        match Shake256Generator::restore(&state[..state.len() - 1]) {
+            Err(BigKeyError::InvalidGeneratorState { .. }) => {}
+            _ => panic!("expected truncated state to be rejected"),
+        }
+    }
 } // mod test
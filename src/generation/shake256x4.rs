@@ -0,0 +1,408 @@
+use std::convert::TryInto;
+use std::io::Write;
+
+use crate::generation::shake256::{
+    Shake256Generator, FILL_CHUNK, LANES, MAX_OUTPUT_LENGTH, MIN_SEED_LENGTH, RATE,
+};
+use crate::generation::traits::{state_body, BigKeyGenerator};
+use crate::memory::wipe;
+use crate::traits::{BigKeyError, GeneratorId, KeyMaterial};
+
+// Parallel SHAKE256 instances
+const WAYS: usize = 4;
+
+// Output of one permutation of every instance
+const SUPERBLOCK: usize = WAYS * RATE;
+
+// Serialized state: generator id, lanes of every instance, offset of the next output byte in
+// the current superblock
+const STATE_LEN: usize = 2 + WAYS * LANES * 8 + 2;
+
+/// Generate the contents of a BigKey from four interleaved SHAKE256 streams
+///
+/// Instance `j` (0 to 3) is SHAKE256 of the seed followed by the byte `j`. The key is their
+/// output in 136 byte (rate sized) blocks, taken round robin: block `k` of the key is block
+/// `k / 4` of instance `k % 4`. The four instances are permuted together, so a multi-buffer
+/// Keccak computes the stream two to three times as fast as `Shake256Generator` computes its own.
+///
+/// With the `keccak-asm` feature, x86-64 CPUs supporting AVX2 (detected at run time) permute the
+/// four states at once in 256 bit registers. Other targets permute them one after another and
+/// produce the same stream. The stream is not the `Shake256Generator` stream, keys are
+/// reproduced by the generator recorded in their header.
+pub struct Shake256x4Generator {
+    // lane `i` of instance `j` is `lanes[i][j]`, the layout the AVX2 permutation works on
+    lanes: [[u64; WAYS]; LANES],
+    // offset of the next output byte within the current superblock; `SUPERBLOCK` once used up
+    pos: usize,
+}
+
+impl BigKeyGenerator for Shake256x4Generator {
+    const ID: GeneratorId = GeneratorId::Shake256x4;
+
+    fn new(seed: Option<KeyMaterial>) -> Result<Self, BigKeyError> {
+        let mut seed = seed.ok_or(BigKeyError::SeedTooShort {
+            seed_len: 0,
+            req_len: MIN_SEED_LENGTH,
+        })?;
+        let generator = Shake256x4Generator::from_seed(&seed);
+        wipe(&mut seed);
+        generator
+    }
+
+    fn fill(&mut self, writer: &mut impl Write, length_bytes: usize) -> Result<(), BigKeyError> {
+        #[allow(clippy::absurd_extreme_comparisons)]
+        if length_bytes > MAX_OUTPUT_LENGTH {
+            return Err(BigKeyError::OutputLengthTooLong {
+                out_len: length_bytes,
+                max_len: MAX_OUTPUT_LENGTH,
+            });
+        }
+
+        let mut buf = vec![0u8; length_bytes.min(FILL_CHUNK)];
+        let mut remaining = length_bytes;
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(FILL_CHUNK)];
+            self.fill_bytes(chunk);
+            writer.write_all(chunk)?;
+            remaining -= chunk.len();
+        }
+        wipe(&mut buf);
+
+        Ok(())
+    }
+
+    fn state(&self) -> KeyMaterial {
+        let mut state = Vec::with_capacity(STATE_LEN);
+        state.extend_from_slice(&(Self::ID as u16).to_be_bytes());
+        for lane in self.lanes.iter().flatten() {
+            state.extend_from_slice(&lane.to_le_bytes());
+        }
+        state.extend_from_slice(&(self.pos as u16).to_be_bytes());
+        state.into_boxed_slice()
+    }
+
+    fn restore(state: &[u8]) -> Result<Self, BigKeyError> {
+        let body = state_body(state, Self::ID)?;
+        let pos = match state.len() {
+            STATE_LEN => u16::from_be_bytes([body[WAYS * LANES * 8], body[WAYS * LANES * 8 + 1]]),
+            _ => u16::MAX,
+        };
+        if pos as usize > SUPERBLOCK {
+            return Err(BigKeyError::InvalidGeneratorState {
+                reason: "malformed four-lane SHAKE256 state",
+            });
+        }
+
+        let mut lanes = [[0u64; WAYS]; LANES];
+        for (lane, bytes) in lanes.iter_mut().flatten().zip(body.chunks_exact(8)) {
+            *lane = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Ok(Shake256x4Generator {
+            lanes,
+            pos: pos as usize,
+        })
+    }
+}
+
+impl Shake256x4Generator {
+    fn from_seed(seed: &[u8]) -> Result<Self, BigKeyError> {
+        if seed.len() < MIN_SEED_LENGTH {
+            return Err(BigKeyError::SeedTooShort {
+                seed_len: seed.len(),
+                req_len: MIN_SEED_LENGTH,
+            });
+        }
+
+        let mut generator = Shake256x4Generator {
+            lanes: [[0u64; WAYS]; LANES],
+            pos: 0,
+        };
+        let mut lane_seed = Vec::with_capacity(seed.len() + 1);
+        for way in 0..WAYS {
+            lane_seed.clear();
+            lane_seed.extend_from_slice(seed);
+            lane_seed.push(way as u8);
+            let instance = Shake256Generator::from_seed(&lane_seed)?;
+            for (lane, value) in generator.lanes.iter_mut().zip(instance.lanes.iter()) {
+                lane[way] = *value;
+            }
+        }
+        wipe(&mut lane_seed);
+
+        Ok(generator)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut dest = dest;
+        while !dest.is_empty() {
+            if self.pos == SUPERBLOCK {
+                f1600_x4(&mut self.lanes);
+                self.pos = 0;
+            }
+            let (way, offset) = (self.pos / RATE, self.pos % RATE);
+            let take = (RATE - offset).min(dest.len());
+            // a lane at a time, the permutation is fast enough for byte copies to dominate
+            let mut copied = 0;
+            while copied < take {
+                let (lane, start) = ((offset + copied) / 8, (offset + copied) % 8);
+                let n = (8 - start).min(take - copied);
+                let bytes = self.lanes[lane][way].to_le_bytes();
+                dest[copied..copied + n].copy_from_slice(&bytes[start..start + n]);
+                copied += n;
+            }
+            self.pos += take;
+            dest = &mut dest[take..];
+        }
+    }
+}
+
+// Keccak-f[1600] of all four instances
+fn f1600_x4(lanes: &mut [[u64; WAYS]; LANES]) {
+    #[cfg(all(feature = "keccak-asm", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // Safety: the CPU supports AVX2
+            unsafe { avx2::f1600_x4(lanes) };
+            return;
+        }
+    }
+    f1600_x4_portable(lanes);
+}
+
+// The four permutations one after another
+fn f1600_x4_portable(lanes: &mut [[u64; WAYS]; LANES]) {
+    let mut state = [0u64; LANES];
+    for way in 0..WAYS {
+        for (value, lane) in state.iter_mut().zip(lanes.iter()) {
+            *value = lane[way];
+        }
+        keccak::f1600(&mut state);
+        for (value, lane) in state.iter().zip(lanes.iter_mut()) {
+            lane[way] = *value;
+        }
+    }
+}
+
+// Keccak-f[1600] on four states at once, lane `i` of every state in one 256 bit register
+#[cfg(all(feature = "keccak-asm", target_arch = "x86_64"))]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    use super::{LANES, WAYS};
+
+    // Round constants
+    const RC: [u64; 24] = [
+        0x0000_0000_0000_0001,
+        0x0000_0000_0000_8082,
+        0x8000_0000_0000_808a,
+        0x8000_0000_8000_8000,
+        0x0000_0000_0000_808b,
+        0x0000_0000_8000_0001,
+        0x8000_0000_8000_8081,
+        0x8000_0000_0000_8009,
+        0x0000_0000_0000_008a,
+        0x0000_0000_0000_0088,
+        0x0000_0000_8000_8009,
+        0x0000_0000_8000_000a,
+        0x0000_0000_8000_808b,
+        0x8000_0000_0000_008b,
+        0x8000_0000_0000_8089,
+        0x8000_0000_0000_8003,
+        0x8000_0000_0000_8002,
+        0x8000_0000_0000_0080,
+        0x0000_0000_0000_800a,
+        0x8000_0000_8000_000a,
+        0x8000_0000_8000_8081,
+        0x8000_0000_0000_8080,
+        0x0000_0000_8000_0001,
+        0x8000_0000_8000_8008,
+    ];
+
+    // Rotate every lane of `reg` left by `n` bits
+    macro_rules! rotl {
+        ($reg:expr, $n:literal) => {
+            _mm256_or_si256(
+                _mm256_slli_epi64::<$n>($reg),
+                _mm256_srli_epi64::<{ 64 - $n }>($reg),
+            )
+        };
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn f1600_x4(lanes: &mut [[u64; WAYS]; LANES]) {
+        let mut a = [_mm256_setzero_si256(); LANES];
+        for (reg, lane) in a.iter_mut().zip(lanes.iter()) {
+            *reg = _mm256_loadu_si256(lane.as_ptr() as *const __m256i);
+        }
+
+        let mut b = [_mm256_setzero_si256(); LANES];
+        for rc in RC.iter() {
+            // theta
+            let mut c = [_mm256_setzero_si256(); 5];
+            for (x, column) in c.iter_mut().enumerate() {
+                *column = _mm256_xor_si256(
+                    _mm256_xor_si256(_mm256_xor_si256(a[x], a[x + 5]), a[x + 10]),
+                    _mm256_xor_si256(a[x + 15], a[x + 20]),
+                );
+            }
+            for x in 0..5 {
+                let d = _mm256_xor_si256(c[(x + 4) % 5], rotl!(c[(x + 1) % 5], 1));
+                for y in 0..5 {
+                    a[x + 5 * y] = _mm256_xor_si256(a[x + 5 * y], d);
+                }
+            }
+
+            // rho and pi, written out so the rotations are immediates: lane `x + 5 * y` rotated
+            // by its offset moves to lane `y + 5 * ((2 * x + 3 * y) % 5)`
+            b[0] = a[0];
+            b[10] = rotl!(a[1], 1);
+            b[20] = rotl!(a[2], 62);
+            b[5] = rotl!(a[3], 28);
+            b[15] = rotl!(a[4], 27);
+            b[16] = rotl!(a[5], 36);
+            b[1] = rotl!(a[6], 44);
+            b[11] = rotl!(a[7], 6);
+            b[21] = rotl!(a[8], 55);
+            b[6] = rotl!(a[9], 20);
+            b[7] = rotl!(a[10], 3);
+            b[17] = rotl!(a[11], 10);
+            b[2] = rotl!(a[12], 43);
+            b[12] = rotl!(a[13], 25);
+            b[22] = rotl!(a[14], 39);
+            b[23] = rotl!(a[15], 41);
+            b[8] = rotl!(a[16], 45);
+            b[18] = rotl!(a[17], 15);
+            b[3] = rotl!(a[18], 21);
+            b[13] = rotl!(a[19], 8);
+            b[14] = rotl!(a[20], 18);
+            b[24] = rotl!(a[21], 2);
+            b[9] = rotl!(a[22], 61);
+            b[19] = rotl!(a[23], 56);
+            b[4] = rotl!(a[24], 14);
+
+            // chi
+            for y in 0..5 {
+                for x in 0..5 {
+                    a[x + 5 * y] = _mm256_xor_si256(
+                        b[x + 5 * y],
+                        _mm256_andnot_si256(b[(x + 1) % 5 + 5 * y], b[(x + 2) % 5 + 5 * y]),
+                    );
+                }
+            }
+
+            // iota
+            a[0] = _mm256_xor_si256(a[0], _mm256_set1_epi64x(*rc as i64));
+        }
+
+        for (reg, lane) in a.iter().zip(lanes.iter_mut()) {
+            _mm256_storeu_si256(lane.as_mut_ptr() as *mut __m256i, *reg);
+        }
+    }
+} // mod avx2
+
+#[cfg(test)]
+mod test {
+    use crate::generation::shake256::{Shake256Generator, LANES, RATE};
+    use crate::generation::shake256x4::{f1600_x4, f1600_x4_portable, Shake256x4Generator, WAYS};
+    use crate::generation::traits::BigKeyGenerator;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, GeneratorId, BLOCK_8};
+
+    const SEED: &[u8; 64] = b"0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn blocks_interleave_the_lane_streams() {
+        let mut key = Vec::new();
+        let mut gen = Shake256x4Generator::new(Some(SEED.to_vec().into_boxed_slice())).unwrap();
+        gen.fill(&mut key, 3 * WAYS * RATE).unwrap();
+
+        for way in 0..WAYS {
+            let mut lane_seed = SEED.to_vec();
+            lane_seed.push(way as u8);
+            let mut lane = Vec::new();
+            Shake256Generator::new(Some(lane_seed.into_boxed_slice()))
+                .unwrap()
+                .fill(&mut lane, 3 * RATE)
+                .unwrap();
+            for (k, block) in lane.chunks(RATE).enumerate() {
+                let start = (k * WAYS + way) * RATE;
+                assert_eq!(&key[start..start + RATE], block);
+            }
+        }
+    }
+
+    #[test]
+    fn permutation_backends_agree() {
+        let mut lanes = [[0u64; WAYS]; LANES];
+        for (i, lane) in lanes.iter_mut().flatten().enumerate() {
+            *lane = (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+        let mut expected = lanes;
+        f1600_x4_portable(&mut expected);
+        f1600_x4(&mut lanes);
+        assert_eq!(lanes, expected);
+
+        let mut way = [0u64; LANES];
+        for (value, lane) in way.iter_mut().zip(lanes.iter()) {
+            *value = lane[2];
+        }
+        let mut state = [0u64; LANES];
+        for (i, value) in state.iter_mut().enumerate() {
+            *value = ((i * WAYS + 2) as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+        keccak::f1600(&mut state);
+        assert_eq!(way, state);
+    }
+
+    #[test]
+    fn paused_generation_resumes() {
+        let mut whole = Vec::new();
+        let mut gen = Shake256x4Generator::new(Some(SEED.to_vec().into_boxed_slice())).unwrap();
+        gen.fill(&mut whole, 2000).unwrap();
+
+        // pause inside the second instance's block of the first superblock
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        let mut gen = Shake256x4Generator::new(Some(SEED.to_vec().into_boxed_slice())).unwrap();
+        gen.fill(&mut first, 301).unwrap();
+        let state = gen.state();
+        let mut gen = Shake256x4Generator::restore(&state).unwrap();
+        gen.fill(&mut second, 1699).unwrap();
+        assert_eq!(&whole[..301], &first[..]);
+        assert_eq!(&whole[301..], &second[..]);
+
+        match Shake256x4Generator::restore(&state[..state.len() - 1]) {
+            Err(BigKeyError::InvalidGeneratorState { .. }) => {}
+            _ => panic!("expected truncated state to be rejected"),
+        }
+        let mut past_end = state.to_vec();
+        let len = past_end.len();
+        past_end[len - 2..].copy_from_slice(&(WAYS as u16 * RATE as u16 + 1).to_be_bytes());
+        match Shake256x4Generator::restore(&past_end) {
+            Err(BigKeyError::InvalidGeneratorState { .. }) => {}
+            _ => panic!("expected an offset past the superblock to be rejected"),
+        }
+        let single = Shake256Generator::new(Some(SEED.to_vec().into_boxed_slice()))
+            .unwrap()
+            .state();
+        match Shake256x4Generator::restore(&single) {
+            Err(BigKeyError::InvalidGeneratorState { .. }) => {}
+            _ => panic!("expected a SHAKE256 state to be rejected"),
+        }
+    }
+
+    #[test]
+    fn short_seed_fails_and_header_records_generator() {
+        match Shake256x4Generator::new(Some(b"01234".to_vec().into_boxed_slice())) {
+            Err(BigKeyError::SeedTooShort { .. }) => {}
+            _ => panic!("expected seed too short, but didn't get it"),
+        }
+
+        let tmp = tempfile();
+        let mut storage = DiskStorage::new_writer(BLOCK_8, tmp.to_str(), 8).unwrap();
+        Shake256x4Generator::generate(&mut storage, Some(SEED.to_vec().into_boxed_slice()), 8)
+            .unwrap();
+        assert_eq!(storage.header().unwrap().generator, GeneratorId::Shake256x4);
+    }
+} // mod test
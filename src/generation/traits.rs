@@ -1,14 +1,63 @@
+use std::io::Write;
+
 use crate::storage::StorageWriter;
-use crate::traits::{BigKeyError, KeyMaterial};
+use crate::traits::{BigKeyError, GeneratorId, KeyMaterial};
 
 /// A Cryptographically secure random number generator that can be used to generate BigKey material.
 ///
 /// Deterministic implementations of `BigKeyGenerator` will use the value from `Some(seed)` to
 /// establish their initial conditions.
-pub trait BigKeyGenerator {
+///
+/// A generator is a stream: successive `fill()` calls continue where the previous one stopped,
+/// so a key can be written in pieces or spread over several writers. `state()` captures the
+/// position in the stream and `restore()` resumes from it, letting long generations be paused
+/// and picked up later, possibly in another process.
+pub trait BigKeyGenerator: Sized {
+    /// Identifies this generator in the BigKey header
+    const ID: GeneratorId;
+
+    /// A generator at the start of its stream
+    fn new(seed: Option<KeyMaterial>) -> Result<Self, BigKeyError>;
+
+    /// Write the next `length_bytes` of the stream to `writer`
+    fn fill(&mut self, writer: &mut impl Write, length_bytes: usize) -> Result<(), BigKeyError>;
+
+    /// Opaque snapshot of the generator. It reveals all output still to come, so treat it like
+    /// the seed.
+    fn state(&self) -> KeyMaterial;
+
+    /// Resume the generator captured by `state()`
+    fn restore(state: &[u8]) -> Result<Self, BigKeyError>;
+
+    /// Write a complete BigKey of `length_bytes` to `storage_method`
+    fn write_key(
+        &mut self,
+        storage_method: &mut impl StorageWriter,
+        length_bytes: usize,
+    ) -> Result<(), BigKeyError> {
+        storage_method.set_generator(Self::ID);
+        self.fill(storage_method, length_bytes)?;
+        storage_method.finalize()
+    }
+
+    /// Generate a BigKey of `length_bytes` from `seed` into `storage_method`
     fn generate(
         storage_method: &mut impl StorageWriter,
         seed: Option<KeyMaterial>,
         length_bytes: usize,
-    ) -> Result<(), BigKeyError>;
+    ) -> Result<(), BigKeyError> {
+        Self::new(seed)?.write_key(storage_method, length_bytes)
+    }
+}
+
+// Check `state` was captured from generator `id`, returning the state that follows the id
+pub(crate) fn state_body(state: &[u8], id: GeneratorId) -> Result<&[u8], BigKeyError> {
+    if state.len() < 2
+        || GeneratorId::from_u16(u16::from_be_bytes([state[0], state[1]])) != Some(id)
+    {
+        return Err(BigKeyError::InvalidGeneratorState {
+            reason: "state belongs to a different generator",
+        });
+    }
+    Ok(&state[2..])
 }
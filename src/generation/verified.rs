@@ -0,0 +1,218 @@
+use std::io;
+use std::io::Write;
+
+use crate::generation::BigKeyGenerator;
+use crate::storage::{
+    evict_from_cache, fingerprint, BufferedStorageWriter, DiskStorage, StorageWriter,
+};
+use crate::traits::{BigKeyError, BlockSize, GeneratorId, HashAlgorithm, KeyMaterial};
+
+/// Generate a BigKey into `storage_location` and verify it was written correctly, returning the
+/// BLAKE3 fingerprint of the key.
+///
+/// Provisioning a BigKey happens once and silent corruption is costly, so the key is checked
+/// end to end:
+///
+/// 1. when a seed is provided, the (deterministic) generator is first run into a hashing sink,
+///    establishing the expected fingerprint independently of the write path;
+/// 2. the key is generated to disk, fingerprinting the bytes as they are written;
+/// 3. the key is synced, evicted from the page cache and read back from disk, then
+///    fingerprinted again. Eviction is Linux only (`POSIX_FADV_DONTNEED`); elsewhere the read back
+///    may be served from memory and only checks the write path up to the page cache.
+///
+/// Any mismatch fails with `VerificationFailed`.
+pub fn generate_verified<G: BigKeyGenerator>(
+    block_size: BlockSize,
+    storage_location: &str,
+    seed: Option<KeyMaterial>,
+    length_bytes: usize,
+) -> Result<[u8; 32], BigKeyError> {
+    generate_verified_with::<G>(
+        block_size,
+        storage_location,
+        seed,
+        length_bytes,
+        false,
+        None,
+    )
+}
+
+// `generate_verified()`, storing the key in a permuted block order if `permute_blocks` and
+// recording `hash_algorithm` in its header
+pub(crate) fn generate_verified_with<G: BigKeyGenerator>(
+    block_size: BlockSize,
+    storage_location: &str,
+    seed: Option<KeyMaterial>,
+    length_bytes: usize,
+    permute_blocks: bool,
+    hash_algorithm: Option<HashAlgorithm>,
+) -> Result<[u8; 32], BigKeyError> {
+    let expected = match &seed {
+        Some(seed) => {
+            let mut sink = HashingSink::new_writer(block_size, storage_location, length_bytes)?;
+            G::generate(&mut sink, Some(seed.clone()), length_bytes)?;
+            Some(sink.fingerprint())
+        }
+        None => None,
+    };
+
+    let mut writer = BufferedStorageWriter::<DiskStorage>::new_writer(
+        block_size,
+        storage_location,
+        length_bytes,
+    )?;
+    if permute_blocks {
+        writer.get_mut().permute_blocks()?;
+    }
+    if let Some(algorithm) = hash_algorithm {
+        writer.get_mut().set_hash_algorithm(algorithm);
+    }
+    G::generate(&mut writer, seed, length_bytes)?;
+    let writer = writer.into_inner()?;
+    let written = writer
+        .header()
+        .and_then(|header| header.fingerprint)
+        .ok_or(BigKeyError::VerificationFailed {
+            stage: "writer recorded no fingerprint",
+        })?;
+    drop(writer);
+
+    if expected.is_some() && expected != Some(written) {
+        return Err(BigKeyError::VerificationFailed {
+            stage: "generated stream differs between runs",
+        });
+    }
+
+    // read back what the device holds, not the pages just written
+    if !evict_from_cache(storage_location)? {
+        log::warn!(
+            "{} could not be evicted from the page cache, its read back may not reach the disk",
+            storage_location
+        );
+    }
+    let mut reader = DiskStorage::open(block_size, storage_location)?;
+
+    if fingerprint(&mut reader)? != written {
+        return Err(BigKeyError::VerificationFailed {
+            stage: "key read back from storage differs from key written",
+        });
+    }
+
+    Ok(written)
+}
+
+// StorageWriter that discards the key, keeping only its fingerprint
+struct HashingSink {
+    block_size: BlockSize,
+    expected_length: u64,
+    written: u64,
+    hasher: blake3::Hasher,
+}
+
+impl HashingSink {
+    fn fingerprint(&self) -> [u8; 32] {
+        *self.hasher.finalize().as_bytes()
+    }
+}
+
+impl StorageWriter for HashingSink {
+    type Options = str;
+
+    fn new_writer(
+        block_size: BlockSize,
+        _storage_location: &str,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        Ok(HashingSink {
+            block_size,
+            expected_length: expected_size as u64,
+            written: 0,
+            hasher: blake3::Hasher::new(),
+        })
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.expected_length
+    }
+
+    fn set_generator(&mut self, _generator: GeneratorId) {}
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        if self.written != self.expected_length {
+            return Err(BigKeyError::FailedToWriteBigKey {
+                expected_len: self.expected_length as usize,
+                wrote_len: self.written as usize,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Write for HashingSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generation::{generate_verified, Shake256Generator};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::DiskStorage;
+    use crate::traits::{BigKeyError, BLOCK_1K};
+
+    #[test]
+    fn verified_generation_returns_fingerprint() {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef"
+            .to_vec()
+            .into_boxed_slice();
+
+        let fingerprint =
+            generate_verified::<Shake256Generator>(BLOCK_1K, tmp.to_str(), Some(seed), 16 * 1024)
+                .unwrap();
+
+        let header = DiskStorage::read_header(tmp.to_str()).unwrap().unwrap();
+        assert_eq!(header.fingerprint, Some(fingerprint));
+    }
+
+    #[test]
+    fn unusable_seeds_lengths_and_locations_fail() {
+        let tmp = tempfile();
+        let seed = || {
+            Some(
+                b"0123456789abcdef0123456789abcdef"
+                    .to_vec()
+                    .into_boxed_slice(),
+            )
+        };
+        assert!(matches!(
+            generate_verified::<Shake256Generator>(
+                BLOCK_1K,
+                tmp.to_str(),
+                Some(b"short".to_vec().into_boxed_slice()),
+                16 * 1024
+            ),
+            Err(BigKeyError::SeedTooShort { .. })
+        ));
+        assert!(matches!(
+            generate_verified::<Shake256Generator>(BLOCK_1K, tmp.to_str(), seed(), 16 * 1024 + 1),
+            Err(BigKeyError::KeyLengthIndivisible { .. })
+        ));
+        let missing_dir = format!("{}/no/such/dir/key", tmp.to_str());
+        assert!(
+            generate_verified::<Shake256Generator>(BLOCK_1K, &missing_dir, seed(), 16 * 1024)
+                .is_err()
+        );
+    }
+} // mod test
@@ -0,0 +1,282 @@
+//! Health of a BigKey for service health endpoints.
+//!
+//! A key that has become unreadable, is silently losing blocks or is about to be retired only
+//! shows up as failing requests unless something looks for it. `check_health()` gathers what
+//! the key's sidecars and a few timed probes say into a `HealthReport`, which serializes to JSON
+//! for a service's `/healthz`:
+//!
+//! ```no_run
+//! use big_fluffy_dise::health::{check_health, HealthCheck};
+//! use big_fluffy_dise::traits::BLOCK_4K;
+//!
+//! let report = check_health(BLOCK_4K, "/srv/keys/big.key", &HealthCheck::default());
+//! let status = if report.is_available() { 200 } else { 503 };
+//! let body = serde_json::to_string(&report).unwrap();
+//! ```
+//!
+//! Checking health never fails: problems are reported in the `HealthReport` instead.
+
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+use crate::kem::RetirementPolicy;
+use crate::storage::{bench_probes, scrub_state_path, DiskStorage, ScrubState, UsageTracker};
+use crate::traits::BlockSize;
+
+/// Overall verdict of a `HealthReport`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// Keys can be derived and nothing needs attention
+    Healthy,
+    /// Keys can be derived, but the key is corrupted in places or slow
+    Degraded,
+    /// New keys cannot be derived: the key is unreadable or retired
+    Unhealthy,
+}
+
+/// What `check_health()` examines and when it reports the key as degraded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthCheck {
+    /// Random blocks probed to measure latency, none to skip the measurement
+    pub probes: usize,
+    /// Degraded when the 99th percentile probe latency exceeds this
+    pub max_latency_p99: Option<Duration>,
+    /// Retirement policy the key is used under, to report the remaining budget
+    pub policy: RetirementPolicy,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            probes: 32,
+            max_latency_p99: Some(Duration::from_millis(50)),
+            policy: RetirementPolicy::default(),
+        }
+    }
+}
+
+/// State of a BigKey as seen by `check_health()`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// Whether the key could be opened and probed
+    pub storage_reachable: bool,
+    /// Why the key is unhealthy, if it is
+    pub error: Option<String>,
+    /// When scrubbing last made progress, `None` if the key has never been scrubbed
+    pub last_scrub: Option<SystemTime>,
+    /// Blocks the scrubber found corrupted and has not since seen intact
+    pub corrupted_blocks: u64,
+    /// Derivations left before the policy's derivation limit, `None` without a limit
+    pub derivations_remaining: Option<u64>,
+    /// Whether the retirement policy still allows new keys
+    pub retired: bool,
+    /// 99th percentile latency of the health check's probes, `None` if none were made
+    pub latency_p99: Option<Duration>,
+}
+
+impl HealthReport {
+    /// Whether keys can be derived, i.e. the status is not `Unhealthy`
+    pub fn is_available(&self) -> bool {
+        self.status != HealthStatus::Unhealthy
+    }
+}
+
+/// Examine the BigKey at `storage_location` and its sidecars
+pub fn check_health(
+    block_size: BlockSize,
+    storage_location: &str,
+    check: &HealthCheck,
+) -> HealthReport {
+    let mut error = None;
+
+    let latency_p99 = match DiskStorage::open(block_size, storage_location)
+        .and_then(|mut storage| bench_probes(&mut storage, check.probes))
+    {
+        Ok(bench) if bench.latency.count() > 0 => Some(bench.latency.percentile(0.99)),
+        Ok(_) => None,
+        Err(e) => {
+            error = Some(format!("storage unreachable: {}", e));
+            None
+        }
+    };
+    let storage_reachable = error.is_none();
+
+    let scrub_path = scrub_state_path(storage_location);
+    let last_scrub = std::fs::metadata(&scrub_path)
+        .and_then(|m| m.modified())
+        .ok();
+    let corrupted_blocks = std::fs::read_to_string(&scrub_path)
+        .ok()
+        .and_then(|contents| toml::from_str::<ScrubState>(&contents).ok())
+        .map_or(0, |state| state.corrupted_blocks.len() as u64);
+
+    let (derivations_remaining, retired) = match UsageTracker::read(storage_location) {
+        Ok(usage) => {
+            let derivations = usage.map_or(0, |u| u.derivations);
+            let remaining = check
+                .policy
+                .max_derivations
+                .map(|max| max.saturating_sub(derivations));
+            let retired = match usage.map(|u| check.policy.check(&u)) {
+                Some(Err(e)) => {
+                    error = error.or_else(|| Some(e.to_string()));
+                    true
+                }
+                _ => false,
+            };
+            (remaining, retired)
+        }
+        Err(e) => {
+            error = error.or_else(|| Some(format!("usage unreadable: {}", e)));
+            (None, false)
+        }
+    };
+
+    let slow = match (latency_p99, check.max_latency_p99) {
+        (Some(p99), Some(max)) => p99 > max,
+        _ => false,
+    };
+    let status = if !storage_reachable || retired {
+        HealthStatus::Unhealthy
+    } else if corrupted_blocks > 0 || slow {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Healthy
+    };
+
+    HealthReport {
+        status,
+        storage_reachable,
+        error,
+        last_scrub,
+        corrupted_blocks,
+        derivations_remaining,
+        retired,
+        latency_p99,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use crate::health::{check_health, HealthCheck, HealthStatus};
+    use crate::kem::RetirementPolicy;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{
+        scrub_state_path, usage_path, DiskStorage, ScrubState, StorageWriter, UsageTracker,
+    };
+    use crate::traits::BLOCK_1K;
+
+    #[test]
+    fn report_reflects_sidecars() {
+        let tmp = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 16 * 1024).unwrap();
+        writer.write_all(&[7u8; 16 * 1024]).unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+
+        let report = check_health(BLOCK_1K, tmp.to_str(), &HealthCheck::default());
+        assert_eq!(report.status, HealthStatus::Healthy);
+        assert!(report.storage_reachable);
+        assert!(report.latency_p99.is_some());
+        assert_eq!(report.last_scrub, None);
+        assert_eq!(report.derivations_remaining, None);
+
+        let state = ScrubState {
+            corrupted_blocks: vec![3],
+            ..ScrubState::default()
+        };
+        std::fs::write(
+            scrub_state_path(tmp.to_str()),
+            toml::to_string(&state).unwrap(),
+        )
+        .unwrap();
+        let mut tracker = UsageTracker::open(tmp.to_str(), 16).unwrap();
+        for _ in 0..3 {
+            tracker.record_derivation();
+        }
+        tracker.save().unwrap();
+
+        let check = HealthCheck {
+            policy: RetirementPolicy {
+                max_derivations: Some(5),
+                ..RetirementPolicy::default()
+            },
+            ..HealthCheck::default()
+        };
+        let report = check_health(BLOCK_1K, tmp.to_str(), &check);
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert!(report.last_scrub.is_some());
+        assert_eq!(report.corrupted_blocks, 1);
+        assert_eq!(report.derivations_remaining, Some(2));
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains("\"status\":\"degraded\""));
+
+        let _ = std::fs::remove_file(scrub_state_path(tmp.to_str()));
+        let _ = std::fs::remove_file(usage_path(tmp.to_str()));
+        drop(tmp);
+        let report = check_health(BLOCK_1K, "/nonexistent/big.key", &check);
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+        assert!(!report.storage_reachable && !report.is_available());
+    }
+
+    #[test]
+    fn limits_and_damaged_sidecars() {
+        let tmp = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 16 * 1024).unwrap();
+        writer.write_all(&[7u8; 16 * 1024]).unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+
+        // no probes, no latency; any latency exceeds a zero limit
+        let unmeasured = HealthCheck {
+            probes: 0,
+            ..HealthCheck::default()
+        };
+        let report = check_health(BLOCK_1K, tmp.to_str(), &unmeasured);
+        assert_eq!(
+            (report.status, report.latency_p99),
+            (HealthStatus::Healthy, None)
+        );
+        let impatient = HealthCheck {
+            max_latency_p99: Some(Duration::from_secs(0)),
+            ..HealthCheck::default()
+        };
+        let report = check_health(BLOCK_1K, tmp.to_str(), &impatient);
+        assert_eq!(report.status, HealthStatus::Degraded);
+
+        // an unparsable scrub state counts no corruption, an unreadable usage file is reported
+        std::fs::write(scrub_state_path(tmp.to_str()), "corrupted_blocks = \"3\"").unwrap();
+        std::fs::write(usage_path(tmp.to_str()), [0xffu8; 7]).unwrap();
+        let report = check_health(BLOCK_1K, tmp.to_str(), &unmeasured);
+        assert_eq!(report.corrupted_blocks, 0);
+        assert!(report.error.unwrap().starts_with("usage unreadable"));
+
+        // a key past its derivation limit is retired
+        std::fs::remove_file(usage_path(tmp.to_str())).unwrap();
+        let mut tracker = UsageTracker::open(tmp.to_str(), 16).unwrap();
+        for _ in 0..3 {
+            tracker.record_derivation();
+        }
+        tracker.save().unwrap();
+        let exhausted = HealthCheck {
+            policy: RetirementPolicy {
+                max_derivations: Some(2),
+                ..RetirementPolicy::default()
+            },
+            ..unmeasured
+        };
+        let report = check_health(BLOCK_1K, tmp.to_str(), &exhausted);
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+        assert!(report.retired && report.storage_reachable);
+        assert_eq!(report.derivations_remaining, Some(0));
+
+        let _ = std::fs::remove_file(scrub_state_path(tmp.to_str()));
+        let _ = std::fs::remove_file(usage_path(tmp.to_str()));
+    }
+} // mod test
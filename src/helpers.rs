@@ -0,0 +1,277 @@
+//! One-call versions of the common path: generate a key file, open it, derive keys.
+//!
+//! These wire up the pieces `main.rs` assembles by hand (preflight, a buffered `DiskStorage`
+//! writer, `Shake256Generator`, a `BigKey` over SHA3-256) with the defaults of `Config`:
+//!
+//! ```no_run
+//! use big_fluffy_dise::prelude::*;
+//!
+//! generate_key_file("big.key", ByteSize::from_gib(1), &GenerateOptions::default())?;
+//! let (locator, key) = derive("big.key")?;
+//! assert_eq!(rederive("big.key", &locator)?, key);
+//! # Ok::<(), BigKeyError>(())
+//! ```
+//!
+//! Anything beyond that (other generators, storage backends, hardening, usage tracking) still
+//! goes through the underlying types.
+
+use std::path::{Path, PathBuf};
+
+use sha3::{Digest, Sha3_256};
+
+use crate::config::Config;
+use crate::generation::{
+    generate_pipelined_into, generate_verified_with, BigKeyGenerator, OsSeedProvider,
+    PipelineOptions, SeedProvider, Shake256Generator,
+};
+use crate::kem::{BigKey, BigKeyKem};
+use crate::storage::{preflight, BufferedStorageWriter, DiskStorage, StorageReader, StorageWriter};
+use crate::traits::{BigKeyError, BlockSize, ByteSize, HashAlgorithm, KeyMaterial, Locator};
+
+/// Length of the seeds `generate_key_file()` draws when none is given
+const SEED_LEN: usize = 64;
+
+/// A `BigKey` held in a key file, as returned by `open_big_key()`
+pub type DiskBigKey = BigKey<DiskStorage, Sha3_256>;
+
+/// How `generate_key_file()` generates a key
+pub struct GenerateOptions {
+    pub block_size: BlockSize,
+    /// SHAKE256 seed making the key reproducible, a fresh random seed if `None`
+    pub seed: Option<KeyMaterial>,
+    /// Read the key back while writing and compare it to the generator output (see
+    /// `generation::generate_pipelined()`)
+    pub verify: bool,
+    /// Store the blocks in a random order (see `storage::permutation`)
+    pub permute_blocks: bool,
+    /// Hash recorded in the header for sessions to derive new keys with, see
+    /// `kem::SessionParams::algorithm`
+    pub hash_algorithm: Option<HashAlgorithm>,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions {
+            block_size: Config::default().block_size,
+            seed: None,
+            verify: false,
+            permute_blocks: false,
+            hash_algorithm: None,
+        }
+    }
+}
+
+/// Generate a key file of `size` (a `ByteSize` or a number of bytes) at `path`, returning its
+/// fingerprint
+pub fn generate_key_file(
+    path: impl AsRef<Path>,
+    size: impl Into<ByteSize>,
+    options: &GenerateOptions,
+) -> Result<[u8; 32], BigKeyError> {
+    let path = path_str(path.as_ref())?;
+    let size = size.into();
+    preflight(path, size.bytes())?;
+    let len = size.to_usize()?;
+    let seed = match &options.seed {
+        Some(seed) => seed.clone(),
+        None => OsSeedProvider.seed(SEED_LEN)?,
+    };
+
+    if options.verify && !options.permute_blocks {
+        let mut writer = DiskStorage::new_writer(options.block_size, path, len)?;
+        if let Some(algorithm) = options.hash_algorithm {
+            writer.set_hash_algorithm(algorithm);
+        }
+        let report = generate_pipelined_into::<Shake256Generator>(
+            writer,
+            path,
+            Some(seed),
+            len,
+            &PipelineOptions::default(),
+        )?;
+        return Ok(report.fingerprint);
+    }
+    if options.verify {
+        return generate_verified_with::<Shake256Generator>(
+            options.block_size,
+            path,
+            Some(seed),
+            len,
+            options.permute_blocks,
+            options.hash_algorithm,
+        );
+    }
+    let mut writer = BufferedStorageWriter::<DiskStorage>::create(options.block_size, path, size)?;
+    if options.permute_blocks {
+        writer.get_mut().permute_blocks()?;
+    }
+    if let Some(algorithm) = options.hash_algorithm {
+        writer.get_mut().set_hash_algorithm(algorithm);
+    }
+    Shake256Generator::generate(&mut writer, Some(seed), len)?;
+    writer
+        .into_inner()?
+        .fingerprint()
+        .ok_or(BigKeyError::InvalidHeader {
+            reason: "finalized key has no fingerprint",
+        })
+}
+
+/// Open the key file at `path` with the block size recorded in its header (the `Config`
+/// default for raw key files) and the `Config` default security level and leakage tolerance
+pub fn open_big_key(path: impl AsRef<Path>) -> Result<DiskBigKey, BigKeyError> {
+    open_big_key_with(path, &Config::default())
+}
+
+/// Like `open_big_key()`, with security level, leakage tolerance and the block size of raw key
+/// files taken from `config`. Fails with `KeyTooSmallForSecurityLevel` if the key is too small
+/// for them.
+pub fn open_big_key_with(
+    path: impl AsRef<Path>,
+    config: &Config,
+) -> Result<DiskBigKey, BigKeyError> {
+    let path = path_str(path.as_ref())?;
+    let block_size = match DiskStorage::read_header(path)? {
+        Some(header) => header.block_size()?,
+        None => config.block_size,
+    };
+    let storage = DiskStorage::open(block_size, path)?;
+    Ok(BigKey::open(
+        config.security_level,
+        config.leakage_tolerance,
+        storage,
+        Sha3_256::new(),
+    )?
+    .with_hash_algorithm(HashAlgorithm::Sha3_256))
+}
+
+/// A BigKey to derive from: a key file path, opened on each use, or an open `BigKey`
+pub trait KeySource {
+    /// Derive a fresh key at the BigKey's security level
+    fn new_key(self) -> Result<(Locator, KeyMaterial), BigKeyError>;
+
+    /// Re-derive the key identified by `locator`
+    fn get_key(self, locator: &Locator) -> Result<KeyMaterial, BigKeyError>;
+}
+
+impl<S: StorageReader, H: Digest> KeySource for &mut BigKey<S, H> {
+    fn new_key(self) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        let security_level = self.security_level();
+        BigKeyKem::new_key(self, security_level)
+    }
+
+    fn get_key(self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+        BigKeyKem::get_key(self, locator)
+    }
+}
+
+macro_rules! path_key_source {
+    ($($path:ty),*) => {$(
+        impl KeySource for $path {
+            fn new_key(self) -> Result<(Locator, KeyMaterial), BigKeyError> {
+                KeySource::new_key(&mut open_big_key(self)?)
+            }
+
+            fn get_key(self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+                BigKeyKem::get_key(&mut open_big_key(self)?, locator)
+            }
+        }
+    )*};
+}
+
+path_key_source!(&str, &String, &Path, &PathBuf);
+
+/// Derive a fresh key from `source`, returning it with the `Locator` to derive it again
+pub fn derive(source: impl KeySource) -> Result<(Locator, KeyMaterial), BigKeyError> {
+    source.new_key()
+}
+
+/// Re-derive the key identified by `locator` from `source`
+pub fn rederive(source: impl KeySource, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+    source.get_key(locator)
+}
+
+fn path_str(path: &Path) -> Result<&str, BigKeyError> {
+    path.to_str().ok_or_else(|| BigKeyError::InvalidConfig {
+        reason: format!("key path {} is not UTF-8", path.display()),
+    })
+}
+
+// A fresh 256 KiB key file of 1 KiB blocks, large enough for 256-bit derivations, removed when
+// dropped
+#[cfg(test)]
+pub(crate) fn test_key_file() -> crate::storage::tempfile::TempFile {
+    let tmp = crate::storage::tempfile::tempfile();
+    let options = GenerateOptions {
+        block_size: crate::traits::BLOCK_1K,
+        ..GenerateOptions::default()
+    };
+    generate_key_file(tmp.as_path(), 256 * 1024u64, &options).unwrap();
+    tmp
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(unix)]
+    use std::{ffi::OsStr, path::Path};
+
+    use crate::helpers::{
+        derive, generate_key_file, open_big_key, rederive, test_key_file, GenerateOptions,
+    };
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{fingerprint, StorageReader};
+    use crate::traits::{BigKeyError, ByteSize, BLOCK_1K};
+
+    #[test]
+    fn three_line_round_trip() {
+        let tmp = tempfile();
+        let options = GenerateOptions {
+            block_size: BLOCK_1K,
+            verify: true,
+            ..GenerateOptions::default()
+        };
+        let size = "256KiB".parse::<ByteSize>().unwrap();
+        let generated = generate_key_file(tmp.as_path(), size, &options).unwrap();
+
+        let (locator, key) = derive(tmp.to_str()).unwrap();
+        assert_eq!(rederive(tmp.as_path(), &locator).unwrap(), key);
+
+        let mut big_key = open_big_key(tmp.as_path()).unwrap();
+        assert_eq!(big_key.storage().block_size().byte_len, 1024);
+        assert_eq!(rederive(&mut big_key, &locator).unwrap(), key);
+        let (other_locator, other_key) = derive(&mut big_key).unwrap();
+        assert_ne!(other_locator, locator);
+        assert_ne!(other_key, key);
+
+        let mut storage = big_key.into_storage();
+        assert_eq!(fingerprint(&mut storage).unwrap(), generated);
+    }
+
+    #[test]
+    fn unusable_paths_and_keys_fail() {
+        assert!(derive("/nonexistent/big.key").is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let not_utf8 = Path::new(OsStr::from_bytes(b"big\xff.key"));
+            match open_big_key(not_utf8) {
+                Err(BigKeyError::InvalidConfig { reason }) => assert!(reason.contains("UTF-8")),
+                _ => panic!("expected a non UTF-8 path to be refused"),
+            }
+        }
+
+        let tmp = tempfile();
+        let options = GenerateOptions {
+            block_size: BLOCK_1K,
+            ..GenerateOptions::default()
+        };
+        generate_key_file(tmp.as_path(), 4096u64, &options).unwrap();
+        match open_big_key(tmp.as_path()) {
+            Err(BigKeyError::KeyTooSmallForSecurityLevel { .. }) => {}
+            _ => panic!("expected a 4 KiB key to be too small"),
+        }
+
+        let big = test_key_file();
+        assert!(rederive(big.as_path(), &vec![1u8, 2, 3].into()).is_err());
+    }
+} // mod test
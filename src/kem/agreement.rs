@@ -0,0 +1,182 @@
+//! Two-party agreement on a locator's selector.
+//!
+//! Mutually distrusting parties holding the same BigKey can derive a common key from it, as long
+//! as neither party alone chooses which blocks are probed: whoever picks the selector could
+//! search for one that probes only blocks they know have leaked. Instead each party contributes a random
+//! share in a commit-and-reveal exchange:
+//!
+//! 1. each party sends `commitment()` of its share,
+//! 2. having received the peer's commitment, each party sends `reveal()`ed share,
+//! 3. `finish()` checks the peer's share against its commitment and hashes both shares into the
+//!    selector.
+//!
+//! A party must commit before seeing the other share, so neither can bias the selector. Both
+//! parties then call `BigKey::new_key_agreed()` with the selector and obtain the same locator and
+//! key.
+
+use crate::kem::locator::SELECTOR_LEN;
+use crate::kem::randomness::{OsRandomness, ProbeRandomness};
+use crate::traits::{ct_eq, BigKeyError};
+
+const COMMIT_CONTEXT: &str = "big_fluffy_dise 2024 selector share commitment v1";
+const SELECTOR_CONTEXT: &str = "big_fluffy_dise 2024 agreed selector v1";
+
+/// One party's random contribution to the selector
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SelectorShare(pub [u8; SELECTOR_LEN]);
+
+/// Binding commitment to a `SelectorShare`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShareCommitment(pub [u8; 32]);
+
+/// Selector agreed by both parties, see `BigKey::new_key_agreed()`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AgreedSelector(pub(crate) [u8; SELECTOR_LEN]);
+
+/// One party's side of the commit-and-reveal exchange
+pub struct LocatorAgreement {
+    share: SelectorShare,
+    peer_commitment: Option<ShareCommitment>,
+}
+
+impl LocatorAgreement {
+    /// Start an agreement with a fresh random share from the operating system
+    pub fn new() -> Result<Self, BigKeyError> {
+        LocatorAgreement::new_with(&mut OsRandomness)
+    }
+
+    /// Start an agreement with a share drawn from `randomness`, e.g. the approved DRBG a
+    /// `BigKey` draws its selectors from (see `kem::randomness`)
+    pub fn new_with(randomness: &mut dyn ProbeRandomness) -> Result<Self, BigKeyError> {
+        let mut share = [0u8; SELECTOR_LEN];
+        randomness.fill(&mut share)?;
+        Ok(LocatorAgreement {
+            share: SelectorShare(share),
+            peer_commitment: None,
+        })
+    }
+
+    /// Commitment to send to the peer first
+    pub fn commitment(&self) -> ShareCommitment {
+        commit(&self.share)
+    }
+
+    /// Record the peer's commitment and return our share to send in exchange
+    pub fn reveal(
+        &mut self,
+        peer_commitment: ShareCommitment,
+    ) -> Result<SelectorShare, BigKeyError> {
+        if peer_commitment == self.commitment() {
+            return Err(failed("peer replayed our commitment"));
+        }
+        self.peer_commitment = Some(peer_commitment);
+        Ok(self.share)
+    }
+
+    /// Check the peer's share against its commitment and derive the agreed selector
+    pub fn finish(self, peer_share: SelectorShare) -> Result<AgreedSelector, BigKeyError> {
+        let peer_commitment = self
+            .peer_commitment
+            .ok_or_else(|| failed("share revealed before receiving peer commitment"))?;
+        if !ct_eq(&commit(&peer_share).0, &peer_commitment.0) {
+            return Err(failed("peer share does not match its commitment"));
+        }
+
+        // order the shares so both parties hash them identically
+        let (first, second) = if self.share.0 <= peer_share.0 {
+            (self.share, peer_share)
+        } else {
+            (peer_share, self.share)
+        };
+        let mut hasher = blake3::Hasher::new_derive_key(SELECTOR_CONTEXT);
+        hasher.update(&first.0);
+        hasher.update(&second.0);
+        Ok(AgreedSelector(*hasher.finalize().as_bytes()))
+    }
+}
+
+fn commit(share: &SelectorShare) -> ShareCommitment {
+    let mut hasher = blake3::Hasher::new_derive_key(COMMIT_CONTEXT);
+    hasher.update(&share.0);
+    ShareCommitment(*hasher.finalize().as_bytes())
+}
+
+fn failed(reason: &'static str) -> BigKeyError {
+    BigKeyError::AgreementFailed { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use sha3::{Digest, Sha3_256};
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::kem::agreement::{LocatorAgreement, SelectorShare};
+    use crate::kem::{BigKey, BigKeyKem, SeededRandomness};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K};
+
+    #[test]
+    fn both_parties_derive_the_same_key() {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 64 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 64 * 1024).unwrap();
+
+        let mut alice = LocatorAgreement::new().unwrap();
+        let mut bob = LocatorAgreement::new().unwrap();
+        let (alice_commitment, bob_commitment) = (alice.commitment(), bob.commitment());
+        let alice_share = alice.reveal(bob_commitment).unwrap();
+        let bob_share = bob.reveal(alice_commitment).unwrap();
+        let alice_selector = alice.finish(bob_share).unwrap();
+        let bob_selector = bob.finish(alice_share).unwrap();
+        assert_eq!(alice_selector, bob_selector);
+
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut storage, Sha3_256::new());
+        let (locator_a, key_a) = bk
+            .new_key_agreed(SecurityLevel::Bits128, &alice_selector)
+            .unwrap();
+        let (locator_b, key_b) = bk
+            .new_key_agreed(SecurityLevel::Bits128, &bob_selector)
+            .unwrap();
+        assert_eq!(locator_a, locator_b);
+        assert_eq!(key_a, key_b);
+        assert_eq!(bk.get_key(&locator_a).unwrap(), key_a);
+    }
+
+    #[test]
+    fn changed_or_early_shares_are_rejected() {
+        let mut alice = LocatorAgreement::new().unwrap();
+        let bob = LocatorAgreement::new().unwrap();
+        alice.reveal(bob.commitment()).unwrap();
+        match alice.finish(SelectorShare([0x42; 32])) {
+            Err(BigKeyError::AgreementFailed { .. }) => {}
+            _ => panic!("expected share not matching commitment to be rejected"),
+        }
+
+        let alice = LocatorAgreement::new().unwrap();
+        assert!(alice.finish(SelectorShare([0x42; 32])).is_err());
+
+        let mut alice = LocatorAgreement::new().unwrap();
+        let replayed = alice.commitment();
+        assert!(alice.reveal(replayed).is_err());
+    }
+
+    #[test]
+    fn shares_come_from_the_given_randomness() {
+        let alice = LocatorAgreement::new_with(&mut SeededRandomness::new(b"alice")).unwrap();
+        let again = LocatorAgreement::new_with(&mut SeededRandomness::new(b"alice")).unwrap();
+        let bob = LocatorAgreement::new_with(&mut SeededRandomness::new(b"bob")).unwrap();
+        assert_eq!(alice.commitment(), again.commitment());
+        assert_ne!(alice.commitment(), bob.commitment());
+
+        // a peer sharing our randomness sends our own commitment back
+        let mut alice = alice;
+        match alice.reveal(again.commitment()) {
+            Err(BigKeyError::AgreementFailed { .. }) => {}
+            _ => panic!("expected a share equal to ours to be refused"),
+        }
+    }
+} // mod test
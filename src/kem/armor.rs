@@ -0,0 +1,287 @@
+//! Text encoding of locators for configs, tickets and chat.
+//!
+//! Locators copied by hand are easily mangled, and a mangled locator is not an error to
+//! `get_key()`: it derives a different, wrong key. `armor_locator()` encodes a locator as
+//! Bech32m (BIP 350) with the human readable prefix `bklc`, e.g. `bklc1qvp...`. Its checksum
+//! detects any error in up to four characters of typical locators, and `dearmor_locator()`
+//! reports where the text went wrong. A single mistyped character is located exactly; it is
+//! never corrected, as a guessed correction could just as well yield the wrong key.
+//!
+//! Armored locators are longer than BIP 173's 90 character limit for addresses, which only
+//! weakens the guaranteed error detection for long strings; random errors are still missed
+//! with probability about 2^-30.
+
+use crate::traits::{BigKeyError, Locator};
+
+/// Human readable prefix of armored locators
+pub const LOCATOR_HRP: &str = "bklc";
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const CHECKSUM_CHARS: usize = 6;
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+#[cfg(feature = "age-plugin")]
+const BECH32_CONST: u32 = 1;
+
+/// Encode `locator` as Bech32m text with the `LOCATOR_HRP` prefix
+pub fn armor_locator(locator: &[u8]) -> String {
+    encode(LOCATOR_HRP, &to_base32(locator), BECH32M_CONST)
+}
+
+/// Decode the output of `armor_locator()`, failing with `InvalidArmoredLocator` (and the
+/// character position of the problem where known) if the text is malformed or mistyped
+pub fn dearmor_locator(text: &str) -> Result<Locator, BigKeyError> {
+    let (hrp, data) = decode(text.trim())?;
+    if hrp != LOCATOR_HRP {
+        return Err(invalid("not a locator prefix", Some(0)));
+    }
+    from_base32(&data).map(Locator::from)
+}
+
+// Bech32m encoding of `bytes` under `hrp`, as for locators
+#[cfg(feature = "lease")]
+pub(crate) fn bech32m_encode(hrp: &str, bytes: &[u8]) -> String {
+    encode(hrp, &to_base32(bytes), BECH32M_CONST)
+}
+
+// Prefix and bytes of Bech32m `text`, the inverse of `bech32m_encode()`
+#[cfg(feature = "lease")]
+pub(crate) fn bech32m_decode(text: &str) -> Result<(String, Vec<u8>), BigKeyError> {
+    let (hrp, data) = decode(text.trim())?;
+    Ok((hrp, from_base32(&data)?))
+}
+
+// Original Bech32 (BIP 173) encoding of `bytes`, without BIP 173's length limit, as used for
+// age recipients and identities
+#[cfg(feature = "age-plugin")]
+pub(crate) fn bech32_encode(hrp: &str, bytes: &[u8]) -> String {
+    encode(hrp, &to_base32(bytes), BECH32_CONST)
+}
+
+// Prefix and bytes of Bech32 `text`, the inverse of `bech32_encode()`
+#[cfg(feature = "age-plugin")]
+pub(crate) fn bech32_decode(text: &str) -> Result<(String, Vec<u8>), BigKeyError> {
+    let (hrp, data) = decode_with(text, BECH32_CONST)?;
+    Ok((hrp, from_base32(&data)?))
+}
+
+fn encode(hrp: &str, data: &[u8], constant: u32) -> String {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; CHECKSUM_CHARS]);
+    let checksum = polymod(&values) ^ constant;
+
+    let mut out = String::with_capacity(hrp.len() + 1 + data.len() + CHECKSUM_CHARS);
+    out.push_str(hrp);
+    out.push('1');
+    for value in data {
+        out.push(CHARSET[*value as usize] as char);
+    }
+    for i in 0..CHECKSUM_CHARS {
+        out.push(CHARSET[((checksum >> (5 * (5 - i))) & 31) as usize] as char);
+    }
+    out
+}
+
+// Split Bech32m `text` into its prefix and 5-bit data values, checksum removed
+fn decode(text: &str) -> Result<(String, Vec<u8>), BigKeyError> {
+    decode_with(text, BECH32M_CONST)
+}
+
+// `decode()` for the Bech32 variant with checksum constant `constant`
+fn decode_with(text: &str, constant: u32) -> Result<(String, Vec<u8>), BigKeyError> {
+    let bytes = text.as_bytes();
+    if let Some(pos) = bytes.iter().position(|c| !(33..=126).contains(c)) {
+        return Err(invalid("character outside printable ASCII", Some(pos)));
+    }
+    let has_lower = bytes.iter().any(u8::is_ascii_lowercase);
+    if let Some(pos) = bytes
+        .iter()
+        .position(u8::is_ascii_uppercase)
+        .filter(|_| has_lower)
+    {
+        return Err(invalid("mixed upper and lower case", Some(pos)));
+    }
+
+    let text = text.to_ascii_lowercase();
+    let separator = match text.rfind('1') {
+        Some(0) => return Err(invalid("missing prefix", Some(0))),
+        Some(pos) if text.len() - pos - 1 < CHECKSUM_CHARS => {
+            return Err(invalid("too short for a checksum", Some(pos)))
+        }
+        Some(pos) => pos,
+        None => return Err(invalid("missing separator", None)),
+    };
+
+    let (hrp, rest) = (&text[..separator], &text.as_bytes()[separator + 1..]);
+    let mut data = Vec::with_capacity(rest.len());
+    for (i, c) in rest.iter().enumerate() {
+        match CHARSET.iter().position(|x| x == c) {
+            Some(value) => data.push(value as u8),
+            None => return Err(invalid("invalid character", Some(separator + 1 + i))),
+        }
+    }
+
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    if polymod(&values) != constant {
+        let data_start = values.len() - data.len();
+        let typo =
+            locate_typo(&values, data_start, constant).map(|i| i - data_start + separator + 1);
+        return Err(invalid("checksum mismatch", typo));
+    }
+
+    data.truncate(data.len() - CHECKSUM_CHARS);
+    Ok((hrp.to_string(), data))
+}
+
+// Index into `values` of the single substituted character that would make the checksum
+// valid, if there is exactly one such character. Only the data part, from `data_start`, is
+// considered.
+fn locate_typo(values: &[u8], data_start: usize, constant: u32) -> Option<usize> {
+    let mut candidates = Vec::new();
+    let mut trial = values.to_vec();
+
+    for i in data_start..values.len() {
+        for value in 0..32u8 {
+            if value == values[i] {
+                continue;
+            }
+            trial[i] = value;
+            if polymod(&trial) == constant {
+                candidates.push(i);
+            }
+        }
+        trial[i] = values[i];
+    }
+
+    match candidates[..] {
+        [position] => Some(position),
+        _ => None,
+    }
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 31));
+    values
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ff_ffff) << 5 ^ *value as u32;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= g;
+            }
+        }
+    }
+    checksum
+}
+
+fn to_base32(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut acc, mut bits) = (0u32, 0);
+    for byte in bytes {
+        acc = acc << 8 | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        out.push(((acc << (5 - bits)) & 31) as u8);
+    }
+    out
+}
+
+fn from_base32(values: &[u8]) -> Result<Vec<u8>, BigKeyError> {
+    let mut out = Vec::with_capacity(values.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for value in values {
+        acc = (acc << 5 | *value as u32) & 0xfff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+        return Err(invalid("invalid padding", None));
+    }
+    Ok(out)
+}
+
+fn invalid(reason: &'static str, position: Option<usize>) -> BigKeyError {
+    BigKeyError::InvalidArmoredLocator { reason, position }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kem::armor::{armor_locator, dearmor_locator, decode};
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn bip350_vectors_decode() {
+        for valid in [
+            "A1LQFN3A",
+            "a1lqfn3a",
+            "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+            "split1checkupstagehandshakeupstreamerranterredcaperredlc445v",
+        ]
+        .iter()
+        {
+            assert!(decode(valid).is_ok(), "{} should decode", valid);
+        }
+        // bech32 (not bech32m) checksum
+        assert!(decode("a12uel5l").is_err());
+    }
+
+    #[test]
+    fn locators_round_trip_and_typos_are_located() {
+        let locator: Vec<u8> = (0..60u8).map(|i| i.wrapping_mul(37)).collect();
+        let armored = armor_locator(&locator);
+        assert!(armored.starts_with("bklc1"));
+        assert_eq!(dearmor_locator(&armored).unwrap().as_bytes(), &locator[..]);
+        assert_eq!(
+            dearmor_locator(&armored.to_uppercase()).unwrap().as_bytes(),
+            &locator[..]
+        );
+
+        let mut typo = armored.clone().into_bytes();
+        typo[20] = if typo[20] == b'q' { b'p' } else { b'q' };
+        match dearmor_locator(std::str::from_utf8(&typo).unwrap()) {
+            Err(BigKeyError::InvalidArmoredLocator { position, .. }) => {
+                assert_eq!(position, Some(20))
+            }
+            _ => panic!("expected the typo to be caught"),
+        }
+
+        let mut swapped = armored.clone().into_bytes();
+        let (a, b) = (swapped[30], swapped[31]);
+        swapped[30] = b;
+        swapped[31] = if a == b { b'q' } else { a };
+        assert!(dearmor_locator(std::str::from_utf8(&swapped).unwrap()).is_err());
+
+        match dearmor_locator(&armored.replacen("bklc", "bkxc", 1)) {
+            Err(BigKeyError::InvalidArmoredLocator { .. }) => {}
+            _ => panic!("expected a wrong prefix to be rejected"),
+        }
+        match dearmor_locator(&armored.replacen('q', "b", 1)) {
+            Err(BigKeyError::InvalidArmoredLocator { position, .. }) => {
+                assert_eq!(position, armored.find('q'))
+            }
+            _ => panic!("expected the invalid character to be located"),
+        }
+    }
+} // mod test
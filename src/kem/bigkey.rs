@@ -1,56 +1,1791 @@
-use crate::storage::StorageReader;
-use crate::traits::types::{KeyMaterial, Locator, SecurityLevel};
-use crate::traits::BigKeyError;
+use std::convert::TryInto;
+
+use crate::kem::agreement::AgreedSelector;
+use crate::kem::distribution::{builtin, ProbeDistribution, Uniform, MAX_PARAMS_LEN};
+use crate::kem::hardening::Hardening;
+use crate::kem::locator::{
+    LocatorBody, LEGACY_HASH, LOCATOR_VERSION, PROBE_CHECK_LEN, SELECTOR_LEN, TAG_LEN,
+};
+use crate::kem::namespace::AppId;
+use crate::kem::params::DerivationParams;
+use crate::kem::randomness::{OsRandomness, ProbeRandomness};
+use crate::kem::retirement::RetirementPolicy;
+use crate::kem::trace::ProbeTrace;
+use crate::kem::transcript::{Transcript, TranscriptRecorder};
+use crate::memory::{wipe, LockedBuffer};
+use crate::storage::{DerivationCounter, KeyUsage, StorageReader, UsageTracker};
+use crate::traits::types::{BlockSize, HashAlgorithm, KeyMaterial, SecurityLevel};
+use crate::traits::{ct_eq, BigKeyError, BlockCount, BlockIndex, Locator};
 use digest::Digest;
 
+// Domain separation of the uses of the hash function
+const PROBE_DOMAIN: &[u8] = b"big_fluffy_dise probe index";
+const KEY_DOMAIN: &[u8] = b"big_fluffy_dise derived key";
+const MAC_DOMAIN: &[u8] = b"big_fluffy_dise locator mac key";
+const OBJECT_ID_DOMAIN: &[u8] = b"big_fluffy_dise object id selector key";
+const CHILD_DOMAIN: &[u8] = b"big_fluffy_dise child big key seed";
+const PEER_KEY_DOMAIN: &[u8] = b"big_fluffy_dise peer derived key";
+// A child BigKey seed probes this many times the blocks of a 256-bit key
+const CHILD_PROBE_FACTOR: u32 = 16;
+// Locators may probe at most this many times the blocks their security level requires, which
+// covers keys derived at higher leakage tolerances and bounds the work a hostile locator causes
+const MAX_PROBE_FACTOR: u64 = 16;
+const PROBE_CHECK_CONTEXT: &str = "big_fluffy_dise 2024 probe order check v1";
+const SELECTOR_CONTEXT: &str = "big_fluffy_dise 2024 counter mixed selector v1";
+// Blocks are probed in batches of at most this many bytes (see `StorageReader::probe_many()`)
+const PROBE_BATCH_LEN: usize = 2 * 1024 * 1024;
+
 /// A BigKey cryptographic key encapsulation scheme
-pub trait BigKeyKem<'a, S, H>
-    where S: 'a + StorageReader,
-          H: 'a + Digest
+pub trait BigKeyKem<S, H>
+where
+    S: StorageReader,
+    H: Digest,
 {
     fn new_big_key(
         security_level: SecurityLevel,
         leakage_tolerance: f32,
-        storage_scheme: &'a S,
-        xof: &'a mut H,
+        storage_scheme: S,
+        xof: H,
     ) -> Self;
 
-    fn get_key(self, locator: &Locator) -> Result<KeyMaterial, BigKeyError>;
+    /// Re-derive the key identified by `locator`. Malformed, mismatched or unauthenticated
+    /// locators fail only after as much probing and hashing as a valid locator at the same
+    /// security level takes, so timing does not reveal why (or whether) a locator was refused.
+    fn get_key(&mut self, locator: &Locator) -> Result<KeyMaterial, BigKeyError>;
 
-    fn new_key(self, security_level: SecurityLevel) -> Result<(Locator, KeyMaterial), BigKeyError>;
+    /// Derive a fresh key at `security_level`, returning it with the `Locator` needed to
+    /// derive it again.
+    fn new_key(
+        &mut self,
+        security_level: SecurityLevel,
+    ) -> Result<(Locator, KeyMaterial), BigKeyError>;
 }
 
-pub struct BigKey<'a, S: StorageReader, H: Digest> {
+/// Derives keys by hashing a random selection of blocks from a BigKey.
+///
+/// A random selector expands (via `H`) into a sequence of probe indices; the probed blocks are
+/// hashed together with the selector into the derived key. Only the selector and derivation
+/// parameters are stored in the `Locator`.
+///
+/// `BigKey` owns its storage and hash state. To keep using a storage backend elsewhere, pass
+/// `&mut storage` (every `&mut StorageReader` is a `StorageReader`) or a `Box<dyn StorageReader>`.
+pub struct BigKey<S: StorageReader, H: Digest> {
     security_level: SecurityLevel,
     leakage_tolerance: f32,
-    storage_scheme: &'a S,
-    xof: &'a mut H,
+    storage_scheme: S,
+    xof: H,
+    key_id: u32,
+    locator_mac: bool,
+    probe_check: bool,
+    mac_key: Option<[u8; 32]>,
+    object_id_key: Option<[u8; 32]>,
+    distribution: Box<dyn ProbeDistribution>,
+    randomness: Box<dyn ProbeRandomness>,
+    hardening: Option<Hardening>,
+    usage: Option<UsageTracker>,
+    retirement: Option<RetirementPolicy>,
+    counter: DerivationCounter,
+    trace: Option<ProbeTrace>,
+    app_id: Option<AppId>,
+    hash_algorithm: Option<HashAlgorithm>,
 }
 
-impl<'a, S1, H1> BigKeyKem<'a, S1, H1> for BigKey<'a, S1, H1>
-    where
-        S1: 'a + StorageReader,
-        H1: 'a + Digest
+impl<S1, H1> BigKeyKem<S1, H1> for BigKey<S1, H1>
+where
+    S1: StorageReader,
+    H1: Digest,
 {
     fn new_big_key(
         security_level: SecurityLevel,
         leakage_tolerance: f32,
-        storage_scheme: &'a S1,
-        xof: &'a mut H1,
+        storage_scheme: S1,
+        xof: H1,
     ) -> Self {
         BigKey {
             security_level,
             leakage_tolerance,
             storage_scheme,
             xof,
+            key_id: 0,
+            locator_mac: false,
+            probe_check: false,
+            mac_key: None,
+            object_id_key: None,
+            distribution: Box::new(Uniform),
+            randomness: Box::new(OsRandomness),
+            hardening: None,
+            usage: None,
+            retirement: None,
+            counter: DerivationCounter::in_memory(),
+            trace: None,
+            app_id: None,
+            hash_algorithm: None,
+        }
+    }
+
+    fn get_key(&mut self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+        self.get_key_recorded(locator, None, None)
+    }
+
+    fn new_key(
+        &mut self,
+        security_level: SecurityLevel,
+    ) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        self.new_key_recorded(security_level, None)
+    }
+}
+
+impl<S: StorageReader, H: Digest> BigKey<S, H> {
+    /// Identify this BigKey by `key_id` in the locators it creates
+    pub fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = key_id;
+        self
+    }
+
+    /// Authenticate locators with a MAC keyed by a sub-key derived from the BigKey itself.
+    ///
+    /// Locators are often stored next to ciphertext in untrusted places; with locator MACs an
+    /// attacker who does not hold the BigKey cannot splice locators or alter their probe
+    /// parameters. New locators carry a tag and `get_key()` rejects locators without a valid
+    /// one (use `authenticate_locator()` to tag locators created before MACs were enabled).
+    pub fn with_locator_mac(mut self) -> Self {
+        self.locator_mac = true;
+        self
+    }
+
+    /// Record a short check value of the probed indices and blocks, in probe order, in new
+    /// locators. `get_key()` fails with `ProbeCheckMismatch` when storage (or a buggy
+    /// implementation) returns different blocks, rather than silently deriving a key that does
+    /// not match the other party's.
+    ///
+    /// The check value depends on the probed blocks, so it lets an attacker who has leaked most
+    /// of the BigKey test guesses of the missing blocks against the locator alone.
+    pub fn with_probe_check(mut self) -> Self {
+        self.probe_check = true;
+        self
+    }
+
+    /// Choose which blocks new keys probe (default `Uniform`). The distribution is recorded in
+    /// each locator; `get_key()` re-creates the built-in distributions from the locator, custom
+    /// ones can only be re-derived by a `BigKey` configured with the same distribution.
+    pub fn with_probe_distribution(
+        mut self,
+        distribution: impl ProbeDistribution + 'static,
+    ) -> Self {
+        self.distribution = Box::new(distribution);
+        self
+    }
+
+    /// Draw the random selectors of new keys from `randomness` instead of the operating system
+    /// (see `kem::randomness`)
+    pub fn with_probe_randomness(mut self, randomness: impl ProbeRandomness + 'static) -> Self {
+        self.randomness = Box::new(randomness);
+        self
+    }
+
+    /// Pass the probe digest of new keys through Argon2id with `hardening` costs before output.
+    ///
+    /// The costs are recorded in each locator. `get_key()` then also rejects locators with
+    /// weaker hardening, so an attacker cannot strip it from stored locators.
+    pub fn with_hardening(mut self, hardening: Hardening) -> Self {
+        self.hardening = Some(hardening);
+        self
+    }
+
+    /// Record `algorithm`, which must be the hash `H`, in new locators, and reject locators
+    /// recording another, or recording none unless `algorithm` is SHA3-256 (see `kem::locator`).
+    /// Without it locators record no hash and any locator is derived with `H`.
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = Some(algorithm);
+        self
+    }
+
+    /// Record derivations and probed blocks in `tracker` (see `storage::UsageTracker`), which
+    /// saves its sidecar when this `BigKey` is dropped or on `save_usage()`
+    pub fn with_usage_tracker(mut self, tracker: UsageTracker) -> Self {
+        self.usage = Some(tracker);
+        self
+    }
+
+    /// Stop deriving new keys once tracked usage reaches a limit of `policy`: `new_key()` and
+    /// friends fail with `KeyRetired`, while `get_key()` keeps working. Requires
+    /// `with_usage_tracker()`.
+    pub fn with_retirement_policy(mut self, policy: RetirementPolicy) -> Self {
+        self.retirement = Some(policy);
+        self
+    }
+
+    /// Mix values of `counter` (see `storage::DerivationCounter`) into the random selectors of
+    /// new keys instead of an in-memory counter, keeping selectors distinct across restarts
+    /// even if the random number generator repeats
+    pub fn with_derivation_counter(mut self, counter: DerivationCounter) -> Self {
+        self.counter = counter;
+        self
+    }
+
+    /// Write the block indices probed by every `new_key()` and `get_key()` (and their variants)
+    /// to `trace`, e.g. to check with `CoverageHeatmap` that probes are spread uniformly
+    pub fn with_probe_trace(mut self, trace: ProbeTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Lifetime usage of the BigKey, if tracked
+    pub fn usage(&self) -> Option<KeyUsage> {
+        self.usage.as_ref().map(UsageTracker::usage)
+    }
+
+    /// Write tracked usage to its sidecar now
+    pub fn save_usage(&self) -> Result<(), BigKeyError> {
+        match &self.usage {
+            Some(tracker) => tracker.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Record that `derivations` keys derived from this BigKey were retired, returning their
+    /// budget to the retirement policy (see `storage::UsageTracker`)
+    pub fn record_retirement(&mut self, derivations: u64) {
+        if let Some(usage) = self.usage.as_mut() {
+            usage.record_retirement(derivations);
+        }
+    }
+
+    /// Indices of the blocks re-deriving the key of `locator` probes, in probe order, computed
+    /// without probing them. Fails for locators `get_key()` would refuse, but without its decoy
+    /// derivation; checking a MAC tag takes the probes deriving the MAC key the first time.
+    pub fn probe_indices(&mut self, locator: &Locator) -> Result<Vec<BlockIndex>, BigKeyError> {
+        let body = LocatorBody::decode(locator.as_bytes())?;
+        self.check_locator(&body, body.peer_bound)?;
+        self.indices(&body, self.storage_scheme.block_count())
+    }
+
+    /// Start loading the blocks `locator` probes (see `StorageReader::prefetch()`), so a
+    /// `get_key()` of it soon after finds them in memory. Returns without waiting for the reads,
+    /// and does nothing on storage that cannot prefetch.
+    pub fn prefetch(&mut self, locator: &Locator) -> Result<(), BigKeyError> {
+        let indices = self.probe_indices(locator)?;
+        self.storage_scheme.prefetch(&indices)
+    }
+
+    /// Add (or replace) the MAC tag of `locator`, upgrading it to the current locator version.
+    /// Only use on locators known to be genuine.
+    pub fn authenticate_locator(&mut self, locator: &Locator) -> Result<Locator, BigKeyError> {
+        let body = LocatorBody {
+            version: LOCATOR_VERSION,
+            ..LocatorBody::decode(locator.as_bytes())?
+        };
+        let tag = self.tag(&body)?;
+        Ok(LocatorBody {
+            tag: Some(tag),
+            ..body
+        }
+        .encode())
+    }
+
+    /// Re-encode `locator` as the current locator version, deriving the same key (see
+    /// `kem::upgrade_locator()`). A tagged locator has its tag verified, then is re-tagged under
+    /// this BigKey's MAC key.
+    pub fn upgrade_locator(&mut self, locator: &Locator) -> Result<Locator, BigKeyError> {
+        let body = LocatorBody::decode(locator.as_bytes())?;
+        let tagged = match &body.tag {
+            Some(tag) => {
+                self.verify_tag(&body, tag)?;
+                true
+            }
+            None => false,
+        };
+        let mut upgraded = LocatorBody {
+            version: LOCATOR_VERSION,
+            tag: None,
+            ..body
+        };
+        if tagged {
+            upgraded.tag = Some(self.tag(&upgraded)?);
+        }
+        Ok(upgraded.encode())
+    }
+
+    /// Derive keys in the application namespace `app_id` (see `kem::namespace`): its locators
+    /// are only accepted by a BigKey with the same `app_id`
+    pub fn with_app_id(mut self, app_id: AppId) -> Self {
+        self.app_id = Some(app_id);
+        self
+    }
+
+    pub fn app_id(&self) -> Option<AppId> {
+        self.app_id
+    }
+
+    /// The storage backend holding the BigKey
+    pub fn storage(&self) -> &S {
+        &self.storage_scheme
+    }
+
+    /// Give up the BigKey, returning its storage backend
+    pub fn into_storage(self) -> S {
+        self.storage_scheme
+    }
+
+    /// Security level of keys derived by `new_key()` unless another is requested
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level
+    }
+
+    /// Identifier of this BigKey recorded in its locators
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Parameters of derivations at `security_level` with this BigKey's leakage tolerance and
+    /// the `BlockSize` of its storage, recorded in the locators of new keys
+    pub fn derivation_params(
+        &self,
+        security_level: SecurityLevel,
+    ) -> Result<DerivationParams, BigKeyError> {
+        DerivationParams::new(
+            security_level,
+            self.leakage_tolerance,
+            self.storage_scheme.block_size(),
+        )
+    }
+
+    /// Number of random probes (block reads) a single key derivation will perform given this
+    /// BigKey's security level, leakage tolerance, and the `BlockSize` of its storage.
+    pub fn estimated_probe_count(&self) -> Result<u64, BigKeyError> {
+        probe_count(
+            self.security_level,
+            self.leakage_tolerance,
+            self.storage_scheme.block_size(),
+        )
+    }
+
+    /// Check the BigKey is big enough for its security level and leakage tolerance, failing
+    /// with `KeyTooSmallForSecurityLevel` if derived keys would be weaker than claimed
+    pub fn check_security(&self) -> Result<(), BigKeyError> {
+        check_key_size(
+            self.security_level,
+            self.leakage_tolerance,
+            self.storage_scheme.block_size(),
+            self.storage_scheme.big_key_length(),
+        )
+    }
+
+    /// Like `new_big_key()`, but failing with `KeyTooSmallForSecurityLevel` unless the key in
+    /// `storage_scheme` supports `security_level` at `leakage_tolerance`
+    pub fn open(
+        security_level: SecurityLevel,
+        leakage_tolerance: f32,
+        storage_scheme: S,
+        xof: H,
+    ) -> Result<Self, BigKeyError> {
+        let big_key = BigKey::new_big_key(security_level, leakage_tolerance, storage_scheme, xof);
+        big_key.check_security()?;
+        Ok(big_key)
+    }
+
+    /// Like `open()` at the security level and leakage tolerance of `params`, also failing with
+    /// `DerivationParamsMismatch` unless `storage_scheme` has their block size
+    pub fn from_params(
+        params: DerivationParams,
+        storage_scheme: S,
+        xof: H,
+    ) -> Result<Self, BigKeyError> {
+        if storage_scheme.block_size().byte_len != params.block_size().byte_len {
+            return Err(BigKeyError::DerivationParamsMismatch {
+                reason: "block size differs",
+            });
+        }
+        BigKey::open(
+            params.security_level(),
+            params.leakage_tolerance(),
+            storage_scheme,
+            xof,
+        )
+    }
+
+    /// Total bytes read from storage by a single key derivation
+    pub fn estimated_derivation_io_bytes(&self) -> Result<u64, BigKeyError> {
+        let probes = self.estimated_probe_count()?;
+        Ok(probes * self.storage_scheme.block_size().byte_len as u64)
+    }
+
+    /// Like `new_key()`, but probing with a selector agreed with another party holding the same
+    /// BigKey (see `kem::LocatorAgreement`). Both parties obtain the same locator and key as
+    /// long as their `BigKey`s are configured alike.
+    pub fn new_key_agreed(
+        &mut self,
+        security_level: SecurityLevel,
+        selector: &AgreedSelector,
+    ) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        self.new_key_with_selector(security_level, selector.0, None, None)
+    }
+
+    /// Derive the key of `object_id` without a locator, e.g. for content-addressed storage where
+    /// the id is stored anyway. The selector is a PRF of the id keyed by the BigKey itself, so
+    /// probe positions cannot be predicted without the key file, and the same id always yields
+    /// the same key from a BigKey configured alike: security level, leakage tolerance, key id,
+    /// probe distribution, hardening and application namespace all enter the derivation.
+    pub fn derive_for_id(&mut self, object_id: &[u8]) -> Result<KeyMaterial, BigKeyError> {
+        let id_key = self.object_id_key()?;
+        let selector = *blake3::keyed_hash(&id_key, object_id).as_bytes();
+        let body = self.new_body(self.security_level, selector, false)?;
+        Ok(self.derive(&body, None, None)?.0)
+    }
+
+    /// Seed of the child BigKey `label`, from which `generation::generate_child_key()` expands
+    /// it. The seed is derived like a 256-bit key from 16 times the probes, so predicting it
+    /// takes leaking far more of this BigKey than its leakage tolerance, and the same label
+    /// always yields the same seed from a BigKey configured alike. The child's holder learns
+    /// nothing about this BigKey beyond what the seed reveals.
+    pub fn derive_child_seed(&mut self, label: &[u8]) -> Result<KeyMaterial, BigKeyError> {
+        let id_key = self.object_id_key()?;
+        let mut selector_hash = blake3::Hasher::new_keyed(&id_key);
+        selector_hash.update(CHILD_DOMAIN);
+        selector_hash.update(label);
+        let selector = *blake3::Hasher::finalize(&selector_hash).as_bytes();
+
+        let body = self.new_body(SecurityLevel::Bits256, selector, false)?;
+        let body = LocatorBody {
+            probe_count: body.probe_count.checked_mul(CHILD_PROBE_FACTOR).ok_or(
+                BigKeyError::InvalidConfig {
+                    reason: "too many probes for a child seed".to_string(),
+                },
+            )?,
+            ..body
+        };
+        let (seed, _, _) = self.derive_in(CHILD_DOMAIN, &body, None, None)?;
+        if let Some(usage) = self.usage.as_mut() {
+            usage.record_derivation();
+        }
+        Ok(seed)
+    }
+
+    /// Like `new_key()`, but the key is also bound to `peer_id`: keys for different peers are
+    /// independent even if their locators come from the same namespace, and the key can only
+    /// be re-derived with `get_key_for_peer()` and the same `peer_id`.
+    pub fn new_key_for_peer(
+        &mut self,
+        security_level: SecurityLevel,
+        peer_id: &[u8],
+    ) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        let selector = self.fresh_selector()?;
+        self.new_key_with_selector(security_level, selector, Some(peer_id), None)
+    }
+
+    /// Re-derive the key for `peer_id` identified by a locator from `new_key_for_peer()`
+    pub fn get_key_for_peer(
+        &mut self,
+        locator: &Locator,
+        peer_id: &[u8],
+    ) -> Result<KeyMaterial, BigKeyError> {
+        self.get_key_recorded(locator, Some(peer_id), None)
+    }
+
+    /// Like `new_key()`, also returning a `Transcript` of the derivation for audit
+    pub fn new_key_with_transcript(
+        &mut self,
+        security_level: SecurityLevel,
+    ) -> Result<(Locator, KeyMaterial, Transcript), BigKeyError> {
+        let mut recorder = None;
+        let (locator, key) = self.new_key_recorded(security_level, Some(&mut recorder))?;
+        let transcript = self.finish_transcript(recorder, &locator, &key)?;
+        Ok((locator, key, transcript))
+    }
+
+    /// Like `get_key()`, also returning a `Transcript` of the derivation for audit
+    pub fn get_key_with_transcript(
+        &mut self,
+        locator: &Locator,
+    ) -> Result<(KeyMaterial, Transcript), BigKeyError> {
+        let mut recorder = None;
+        let key = self.get_key_recorded(locator, None, Some(&mut recorder))?;
+        let transcript = self.finish_transcript(recorder, locator, &key)?;
+        Ok((key, transcript))
+    }
+
+    /// Replay the derivation recorded in `transcript`, failing with `VerificationFailed` unless
+    /// every probe and the resulting key match. Returns the derived key.
+    pub fn verify_transcript(
+        &mut self,
+        transcript: &Transcript,
+    ) -> Result<KeyMaterial, BigKeyError> {
+        let mut recorder = None;
+        let key = self.get_key_recorded(&transcript.locator, None, Some(&mut recorder))?;
+
+        if self.finish_transcript(recorder, &transcript.locator, &key)? != *transcript {
+            return Err(BigKeyError::VerificationFailed {
+                stage: "transcript differs from re-derivation",
+            });
+        }
+        Ok(key)
+    }
+
+    fn new_key_recorded(
+        &mut self,
+        security_level: SecurityLevel,
+        recorder: Option<&mut Option<TranscriptRecorder>>,
+    ) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        let selector = self.fresh_selector()?;
+        self.new_key_with_selector(security_level, selector, None, recorder)
+    }
+
+    // Random selector mixed with the next derivation counter value
+    fn fresh_selector(&mut self) -> Result<[u8; SELECTOR_LEN], BigKeyError> {
+        let mut random = [0u8; SELECTOR_LEN];
+        self.randomness.fill(&mut random)?;
+        Ok(mix_selector(&random, self.counter.take()?))
+    }
+
+    fn new_key_with_selector(
+        &mut self,
+        security_level: SecurityLevel,
+        selector: [u8; SELECTOR_LEN],
+        peer_id: Option<&[u8]>,
+        recorder: Option<&mut Option<TranscriptRecorder>>,
+    ) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        if let Some(policy) = &self.retirement {
+            let usage = self.usage().ok_or_else(|| BigKeyError::InvalidConfig {
+                reason: "retirement policy requires usage tracking".to_string(),
+            })?;
+            policy.check(&usage)?;
+        }
+
+        let body = self.new_body(security_level, selector, peer_id.is_some())?;
+        let (key, check) = self.derive(&body, peer_id, recorder)?;
+        let body = LocatorBody {
+            probe_check: if self.probe_check { Some(check) } else { None },
+            ..body
+        };
+
+        if self.locator_mac {
+            let tag = self.tag(&body)?;
+            return Ok((
+                LocatorBody {
+                    tag: Some(tag),
+                    ..body
+                }
+                .encode(),
+                key,
+            ));
+        }
+
+        Ok((body.encode(), key))
+    }
+
+    // Locator body of a new derivation with this BigKey's configuration
+    fn new_body(
+        &self,
+        security_level: SecurityLevel,
+        selector: [u8; SELECTOR_LEN],
+        peer_bound: bool,
+    ) -> Result<LocatorBody, BigKeyError> {
+        let params = self.derivation_params(security_level)?;
+
+        let distribution = self.distribution.descriptor();
+        if distribution.params.len() > MAX_PARAMS_LEN {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "probe distribution parameters too long for a locator".to_string(),
+            });
+        }
+
+        Ok(LocatorBody {
+            version: LOCATOR_VERSION,
+            key_id: self.key_id,
+            security_level,
+            probe_count: params.probe_count(),
+            selector,
+            distribution,
+            hardening: self.hardening,
+            peer_bound,
+            probe_check: None,
+            app_id: self.app_id,
+            hash: self.hash_algorithm,
+            params: Some(params),
+            tag: None,
+        })
+    }
+
+    // Invalid locators fail only after a decoy derivation costing as much as a genuine one, so
+    // the time to fail reveals neither which check failed nor that one did
+    fn get_key_recorded(
+        &mut self,
+        locator: &Locator,
+        peer_id: Option<&[u8]>,
+        recorder: Option<&mut Option<TranscriptRecorder>>,
+    ) -> Result<KeyMaterial, BigKeyError> {
+        let body = match LocatorBody::decode(locator.as_bytes()) {
+            Ok(body) => body,
+            Err(e) => return Err(self.reject(self.security_level, None, peer_id, e)),
+        };
+        if let Err(e) = self.check_locator(&body, peer_id.is_some()) {
+            return Err(self.reject(body.security_level, Some(body.probe_count), peer_id, e));
+        }
+        Ok(self.derive(&body, peer_id, recorder)?.0)
+    }
+
+    // Whether this BigKey may derive the key of `body`, without probing
+    fn check_locator(&mut self, body: &LocatorBody, peer_bound: bool) -> Result<(), BigKeyError> {
+        match &body.tag {
+            Some(tag) => self.verify_tag(body, tag)?,
+            None if self.locator_mac => {
+                return Err(BigKeyError::LocatorAuthenticationFailed);
+            }
+            None => {}
+        }
+
+        if body.key_id != self.key_id {
+            return Err(BigKeyError::UnknownKeyId {
+                key_id: body.key_id,
+            });
+        }
+
+        if let Some(expected) = self.hash_algorithm {
+            let found = body.hash.unwrap_or(LEGACY_HASH);
+            if expected != found {
+                return Err(BigKeyError::HashAlgorithmMismatch { expected, found });
+            }
+        }
+
+        if body.app_id != self.app_id {
+            return Err(BigKeyError::InvalidLocator {
+                reason: "locator belongs to another application namespace",
+            });
+        }
+
+        if let Some(params) = &body.params {
+            self.derivation_params(body.security_level)?
+                .check_compatible(params)?;
+        }
+
+        let required = probe_count(
+            body.security_level,
+            self.leakage_tolerance,
+            self.storage_scheme.block_size(),
+        )?;
+        check_probe_count(body.probe_count, required)?;
+
+        // the locator picks its distribution: one other than the configured one must leave at
+        // least as many blocks to probe as a key needs (see `check_key_size()`)
+        if body.distribution != self.distribution.descriptor() {
+            let blocks = self.storage_scheme.block_count();
+            if builtin(&body.distribution)?.usable_blocks(blocks) < required {
+                return Err(BigKeyError::InvalidLocator {
+                    reason: "probe distribution leaves too few blocks for security level",
+                });
+            }
+        }
+
+        if let Some(required) = &self.hardening {
+            if !body.hardening.is_some_and(|h| h.at_least(required)) {
+                return Err(BigKeyError::InvalidLocator {
+                    reason: "weaker hardening than configured",
+                });
+            }
+        }
+
+        match (body.peer_bound, peer_bound) {
+            (true, false) => Err(BigKeyError::InvalidLocator {
+                reason: "locator is bound to a peer",
+            }),
+            (false, true) => Err(BigKeyError::InvalidLocator {
+                reason: "locator is not bound to a peer",
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Run a decoy derivation as costly as a genuine one at `security_level` and `probe_count`
+    // (the locator's claimed level and probe count, the latter within the bounds of
+    // `check_probe_count()`; the BigKey's own for undecodable locators), then return `error`. The
+    // decoy probes a fresh random selector, as a fixed one would find its blocks cached, and
+    // is not recorded in usage, traces or transcripts.
+    fn reject(
+        &mut self,
+        security_level: SecurityLevel,
+        probe_count: Option<u32>,
+        peer_id: Option<&[u8]>,
+        error: BigKeyError,
+    ) -> BigKeyError {
+        let mut selector = [0u8; SELECTOR_LEN];
+        let _ = self.randomness.fill(&mut selector);
+        if let Ok(mut body) = self.new_body(security_level, selector, peer_id.is_some()) {
+            if let Some(claimed) = probe_count {
+                let required = body.probe_count as u64;
+                let max = required.saturating_mul(MAX_PROBE_FACTOR);
+                body.probe_count = (claimed as u64).clamp(required, max) as u32;
+            }
+            let domain = match peer_id {
+                Some(_) => PEER_KEY_DOMAIN,
+                None => KEY_DOMAIN,
+            };
+            let usage = self.usage.take();
+            if let Ok((mut key, _, _)) = self.derive_in(domain, &body, peer_id, None) {
+                wipe(&mut key);
+            }
+            self.usage = usage;
+        }
+        error
+    }
+
+    fn finish_transcript(
+        &self,
+        recorder: Option<TranscriptRecorder>,
+        locator: &Locator,
+        key: &KeyMaterial,
+    ) -> Result<Transcript, BigKeyError> {
+        let body = LocatorBody::decode(locator.as_bytes())?;
+        let recorder = recorder.ok_or(BigKeyError::VerificationFailed {
+            stage: "derivation was not recorded",
+        })?;
+        Ok(recorder.finish(
+            locator,
+            &body,
+            key,
+            self.storage_scheme.block_size().byte_len,
+            self.storage_scheme.big_key_length(),
+        ))
+    }
+
+    fn tag(&mut self, body: &LocatorBody) -> Result<[u8; TAG_LEN], BigKeyError> {
+        let mac_key = match self.mac_key {
+            Some(mac_key) => mac_key,
+            None => {
+                let mac_key = self.internal_key(MAC_DOMAIN)?;
+                self.mac_key = Some(mac_key);
+                mac_key
+            }
+        };
+
+        let mac = blake3::keyed_hash(&mac_key, &body.mac_input());
+        Ok(mac.as_bytes()[..TAG_LEN].try_into().unwrap())
+    }
+
+    // Key of the PRFs mapping object ids and child labels to selectors
+    fn object_id_key(&mut self) -> Result<[u8; 32], BigKeyError> {
+        match self.object_id_key {
+            Some(id_key) => Ok(id_key),
+            None => {
+                let id_key = self.internal_key(OBJECT_ID_DOMAIN)?;
+                self.object_id_key = Some(id_key);
+                Ok(id_key)
+            }
+        }
+    }
+
+    // 256-bit key for the BigKey's own use in hash domain `domain`, derived from a fixed
+    // all-zero selector with the uniform distribution
+    fn internal_key(&mut self, domain: &[u8]) -> Result<[u8; 32], BigKeyError> {
+        let security_level = SecurityLevel::Bits256;
+        let probes = probe_count(
+            security_level,
+            self.leakage_tolerance,
+            self.storage_scheme.block_size(),
+        )?;
+        let params = LocatorBody {
+            version: LOCATOR_VERSION,
+            key_id: self.key_id,
+            security_level,
+            probe_count: probes as u32,
+            selector: [0u8; SELECTOR_LEN],
+            distribution: Uniform.descriptor(),
+            hardening: None,
+            peer_bound: false,
+            probe_check: None,
+            app_id: None,
+            hash: None,
+            params: None,
+            tag: None,
+        };
+        let (mut derived, _, _) = self.derive_in(domain, &params, None, None)?;
+        let key = derived[..].try_into().unwrap();
+        wipe(&mut derived);
+        Ok(key)
+    }
+
+    fn verify_tag(&mut self, body: &LocatorBody, tag: &[u8; TAG_LEN]) -> Result<(), BigKeyError> {
+        let expected = self.tag(body)?;
+        if !ct_eq(&expected, tag) {
+            return Err(BigKeyError::LocatorAuthenticationFailed);
+        }
+        Ok(())
+    }
+
+    fn derive(
+        &mut self,
+        body: &LocatorBody,
+        peer_id: Option<&[u8]>,
+        recorder: Option<&mut Option<TranscriptRecorder>>,
+    ) -> Result<(KeyMaterial, [u8; PROBE_CHECK_LEN]), BigKeyError> {
+        let (key, check, indices) = match peer_id {
+            Some(_) => self.derive_in(PEER_KEY_DOMAIN, body, peer_id, recorder)?,
+            None => self.derive_in(KEY_DOMAIN, body, None, recorder)?,
+        };
+        if let Some(usage) = self.usage.as_mut() {
+            usage.record_derivation();
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(self.storage_scheme.block_count(), &indices)?;
+        }
+        Ok((key, check))
+    }
+
+    // Key of `body` in hash domain `domain`: H(domain || key id || security level || selector
+    // || [app id] || [peer id length || peer id] || (index || block)*), and the probe check value over the
+    // same (index || block)* sequence, verified against the locator's if it has one. Also
+    // returns the probed indices.
+    fn derive_in(
+        &mut self,
+        domain: &[u8],
+        body: &LocatorBody,
+        peer_id: Option<&[u8]>,
+        recorder: Option<&mut Option<TranscriptRecorder>>,
+    ) -> Result<(KeyMaterial, [u8; PROBE_CHECK_LEN], Vec<BlockIndex>), BigKeyError> {
+        let key_len = body.security_level as usize / 8;
+        if H::output_size() < key_len || H::output_size() < 8 {
+            return Err(BigKeyError::DigestTooShort {
+                digest_len: H::output_size(),
+                key_len,
+            });
+        }
+
+        let block_len = self.storage_scheme.block_size().byte_len;
+        let block_count = self.storage_scheme.block_count();
+        if block_count.get() == 0 {
+            return Err(BigKeyError::OutputLengthTooShort {
+                out_len: 0,
+                min_len: block_len,
+            });
         }
+
+        let indices = self.indices(body, block_count)?;
+
+        let batch_len = (PROBE_BATCH_LEN / block_len).max(1);
+        let mut blocks = LockedBuffer::sensitive(batch_len.min(indices.len()) * block_len);
+        let mut key_hash = H::new();
+        key_hash.update(domain);
+        key_hash.update(body.key_id.to_be_bytes());
+        key_hash.update((body.security_level as u16).to_be_bytes());
+        key_hash.update(body.selector);
+        if let Some(app_id) = &body.app_id {
+            key_hash.update(app_id.as_bytes());
+        }
+        if let Some(peer_id) = peer_id {
+            key_hash.update((peer_id.len() as u64).to_be_bytes());
+            key_hash.update(peer_id);
+        }
+
+        let mut check_hash = blake3::Hasher::new_derive_key(PROBE_CHECK_CONTEXT);
+        let mut transcript = recorder.map(|r| r.insert(TranscriptRecorder::new(body)));
+
+        for batch in indices.chunks(batch_len) {
+            let batch_blocks = &mut blocks[..batch.len() * block_len];
+            self.storage_scheme.probe_many(batch, batch_blocks)?;
+            for (&index, block) in batch.iter().zip(batch_blocks.chunks_exact(block_len)) {
+                if let Some(usage) = self.usage.as_mut() {
+                    usage.record_probe(index.get());
+                }
+                key_hash.update(index.get().to_be_bytes());
+                key_hash.update(block);
+                check_hash.update(&index.get().to_be_bytes());
+                check_hash.update(block);
+                if let Some(transcript) = transcript.as_mut() {
+                    transcript.record(index.get(), block);
+                }
+            }
+        }
+
+        let check_digest = blake3::Hasher::finalize(&check_hash);
+        let check: [u8; PROBE_CHECK_LEN] = check_digest.as_bytes()[..PROBE_CHECK_LEN]
+            .try_into()
+            .unwrap();
+        if body.probe_check.is_some_and(|expected| expected != check) {
+            return Err(BigKeyError::ProbeCheckMismatch);
+        }
+
+        let mut digest = key_hash.finalize();
+        let key = match &body.hardening {
+            Some(hardening) => hardening.apply(&digest, &body.selector, key_len),
+            None => Ok(digest[..key_len].to_vec().into_boxed_slice()),
+        };
+        wipe(&mut digest);
+        Ok((key?, check, indices))
+    }
+
+    // Indices of the blocks probed for `body` in a key of `block_count` blocks
+    fn indices(
+        &mut self,
+        body: &LocatorBody,
+        block_count: BlockCount,
+    ) -> Result<Vec<BlockIndex>, BigKeyError> {
+        let samples: Vec<u64> = (0..body.probe_count as u64)
+            .map(|i| self.probe_sample(body.app_id.as_ref(), &body.selector, i))
+            .collect();
+
+        // locators of custom distributions are only understood by a BigKey configured with them
+        let builtin_distribution;
+        let distribution = if body.distribution == self.distribution.descriptor() {
+            &self.distribution
+        } else {
+            builtin_distribution = builtin(&body.distribution)?;
+            &builtin_distribution
+        };
+        samples
+            .into_iter()
+            .map(|sample| distribution.index(sample, block_count))
+            .collect()
+    }
+
+    // Sample of probe number `i`: H(domain || [app id] || selector || i), mapped to a block
+    // index by the probe distribution
+    fn probe_sample(&mut self, app_id: Option<&AppId>, selector: &[u8], i: u64) -> u64 {
+        self.xof.update(PROBE_DOMAIN);
+        if let Some(app_id) = app_id {
+            self.xof.update(app_id.as_bytes());
+        }
+        self.xof.update(selector);
+        self.xof.update(i.to_be_bytes());
+        let digest = self.xof.finalize_reset();
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
     }
+}
+
+// Number of probes needed so an adversary who has leaked a `leakage_tolerance` fraction of the
+// BigKey can predict the derived key with probability at most 2^-security_level.
+//
+// Following Bellare, Kane, and Rogaway "Big-Key Symmetric Encryption" each probe is predicted
+// with probability at most (γ + (1 - γ) * 2^-w) for leakage fraction γ and w-bit blocks, so we
+// need the smallest p where p * -log2(γ + (1 - γ) * 2^-w) >= security_level.
+pub(crate) fn probe_count(
+    security_level: SecurityLevel,
+    leakage_tolerance: f32,
+    block_size: BlockSize,
+) -> Result<u64, BigKeyError> {
+    if !(0.0..1.0).contains(&leakage_tolerance) {
+        return Err(BigKeyError::LeakageToleranceOutOfRange {
+            tolerance: leakage_tolerance,
+        });
+    }
+
+    let leakage = leakage_tolerance as f64;
+    let guess = 2f64.powi(-(block_size.bit_len.min(1023) as i32));
+    let bits_per_probe = -(leakage + (1.0 - leakage) * guess).log2();
+    let probes = (security_level as u32 as f64 / bits_per_probe).ceil() as u64;
 
-    fn get_key(self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
-        unimplemented!()
+    Ok(probes.max(1))
+}
+
+// Fail unless a locator's `probes` are at least the `required` probes of its security level and
+// at most `MAX_PROBE_FACTOR` times as many
+pub(crate) fn check_probe_count(probes: u32, required: u64) -> Result<(), BigKeyError> {
+    if (probes as u64) < required {
+        return Err(BigKeyError::InvalidLocator {
+            reason: "too few probes for security level",
+        });
+    }
+    if probes as u64 > required.saturating_mul(MAX_PROBE_FACTOR) {
+        return Err(BigKeyError::InvalidLocator {
+            reason: "too many probes for security level",
+        });
     }
+    Ok(())
+}
 
-    fn new_key(self, security_level: SecurityLevel) -> Result<(Locator, KeyMaterial), BigKeyError> {
-        unimplemented!()
+// Fail unless a `key_len` byte key of `block_size` blocks has at least as many blocks as a
+// derivation at `security_level` and `leakage_tolerance` probes. Each probe yields at most
+// (1 - γ) * w bits of min-entropy (see `probe_count()`), so such a key also keeps at least
+// `security_level` bits after leakage; smaller keys repeat probes and deliver weaker keys.
+pub(crate) fn check_key_size(
+    security_level: SecurityLevel,
+    leakage_tolerance: f32,
+    block_size: BlockSize,
+    key_len: u64,
+) -> Result<(), BigKeyError> {
+    let probes = probe_count(security_level, leakage_tolerance, block_size)?;
+    let min_len = probes.saturating_mul(block_size.byte_len as u64);
+    if key_len < min_len {
+        return Err(BigKeyError::KeyTooSmallForSecurityLevel {
+            bits: security_level as u32,
+            tolerance: leakage_tolerance,
+            key_len,
+            min_len,
+        });
     }
+    Ok(())
 }
+
+// Selector from `random` and derivation `counter`, distinct for distinct counter values even if
+// `random` repeats
+fn mix_selector(random: &[u8; SELECTOR_LEN], counter: u64) -> [u8; SELECTOR_LEN] {
+    let mut hasher = blake3::Hasher::new_derive_key(SELECTOR_CONTEXT);
+    hasher.update(random);
+    hasher.update(&counter.to_be_bytes());
+    *blake3::Hasher::finalize(&hasher).as_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::kem::bigkey::{mix_selector, probe_count, MAC_DOMAIN};
+    use crate::kem::locator::LocatorBody;
+    use crate::kem::params::PARAMS_LEN;
+    use crate::kem::{
+        locator_app_id, locator_params, locator_version, AppId, BigKey, BigKeyKem,
+        DerivationParams, ExcludeEnds, ExcludeRanges, ProbeDistribution, RetirementPolicy,
+        APP_ID_LEN, LOCATOR_VERSION,
+    };
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{
+        counter_path, usage_path, DerivationCounter, DiskStorage, DiskStorageFactory,
+        StorageReader, StorageReaderFactory, UsageTracker,
+    };
+    use crate::traits::{
+        BigKeyError, BlockIndex, BlockSize, Locator, SecurityLevel, BLOCK_1K, BLOCK_4K, BLOCK_8,
+    };
+
+    // Fill a raw key file with `blocks` distinct 1K blocks
+    fn key_file(blocks: u8) -> crate::storage::tempfile::TempFile {
+        let tmp = tempfile();
+        let mut ofile = File::create(tmp.as_path()).unwrap();
+        for i in 0..blocks {
+            ofile.write_all(&[i; 1024]).unwrap();
+        }
+        tmp
+    }
+
+    #[test]
+    fn new_key_can_be_recovered() {
+        let tmp = key_file(64);
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut storage, Sha3_256::new());
+
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(key.len(), 16);
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        let (locator2, key2) = bk.new_key(SecurityLevel::Bits256).unwrap();
+        assert_eq!(key2.len(), 32);
+        assert_ne!(locator, locator2);
+        assert_eq!(bk.get_key(&locator2).unwrap(), key2);
+    }
+
+    #[test]
+    fn usage_is_tracked_across_sessions() {
+        let tmp = key_file(64);
+        let probes = probe_count(SecurityLevel::Bits128, 0.2, BLOCK_1K).unwrap();
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_usage_tracker(UsageTracker::open(tmp.to_str(), 64).unwrap());
+
+        let (locator, _) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        let usage = bk.usage().unwrap();
+        assert_eq!(usage.derivations, 1);
+        assert_eq!(usage.blocks_probed, probes);
+        assert!(usage.distinct_blocks > 0 && usage.distinct_blocks <= 64);
+        drop(bk);
+
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_usage_tracker(UsageTracker::open(tmp.to_str(), 64).unwrap());
+        bk.get_key(&locator).unwrap();
+        let again = bk.usage().unwrap();
+        assert_eq!(again.derivations, 2);
+        assert_eq!(again.blocks_probed, 2 * probes);
+        assert_eq!(again.distinct_blocks, usage.distinct_blocks);
+
+        drop(bk);
+        let _ = std::fs::remove_file(usage_path(tmp.to_str()));
+    }
+
+    #[test]
+    fn selectors_mix_in_persistent_counter() {
+        // a repeating RNG still yields distinct selectors
+        let random = [0x42; 32];
+        assert_ne!(mix_selector(&random, 0), mix_selector(&random, 1));
+
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_derivation_counter(DerivationCounter::open(tmp.to_str()).unwrap());
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        bk.new_key_for_peer(SecurityLevel::Bits128, b"peer")
+            .unwrap();
+        assert_eq!(bk.counter.peek(), 2);
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+        drop(bk);
+
+        // a restarted process continues past the reserved values
+        let counter = DerivationCounter::open(tmp.to_str()).unwrap();
+        assert!(counter.peek() >= 2);
+        let _ = std::fs::remove_file(counter_path(tmp.to_str()));
+    }
+
+    #[test]
+    fn retired_keys_stop_deriving() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let policy = RetirementPolicy {
+            max_derivations: Some(2),
+            ..RetirementPolicy::default()
+        };
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_retirement_policy(policy);
+        match bk.new_key(SecurityLevel::Bits128) {
+            Err(BigKeyError::InvalidConfig { .. }) => {}
+            _ => panic!("expected policy without usage tracking to be rejected"),
+        }
+
+        let mut bk = bk.with_usage_tracker(UsageTracker::open(tmp.to_str(), 64).unwrap());
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        bk.new_key(SecurityLevel::Bits128).unwrap();
+        match bk.new_key(SecurityLevel::Bits128) {
+            Err(BigKeyError::KeyRetired { .. }) => {}
+            _ => panic!("expected key to be retired"),
+        }
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        drop(bk);
+        let _ = std::fs::remove_file(usage_path(tmp.to_str()));
+    }
+
+    #[test]
+    fn key_depends_on_big_key_contents() {
+        let tmp1 = key_file(64);
+        let tmp2 = key_file(65);
+        let storage1 = DiskStorage::open(BLOCK_1K, tmp1.to_str()).unwrap();
+        let storage2 = DiskStorage::open(BLOCK_1K, tmp2.to_str()).unwrap();
+
+        let mut bk1 = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage1, Sha3_256::new());
+        let mut bk2 = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage2, Sha3_256::new());
+
+        let (locator, key) = bk1.new_key(SecurityLevel::Bits128).unwrap();
+        assert_ne!(bk2.get_key(&locator).unwrap(), key);
+    }
+
+    #[test]
+    fn mismatched_derivation_params_are_refused() {
+        let tmp = key_file(64);
+        let params = DerivationParams::new(SecurityLevel::Bits128, 0.2, BLOCK_1K).unwrap();
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::from_params(params, storage, Sha3_256::new()).unwrap();
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(locator_params(locator.as_bytes()).unwrap(), Some(params));
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        // fewer probes suffice at a lower tolerance, but the locator was made for another
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut lenient =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.1, storage, Sha3_256::new());
+        match lenient.get_key(&locator) {
+            Err(BigKeyError::DerivationParamsMismatch { .. }) => {}
+            _ => panic!("expected a locator of another leakage tolerance to be refused"),
+        }
+
+        let storage = DiskStorage::open(BLOCK_4K, tmp.to_str()).unwrap();
+        match BigKey::from_params(params, storage, Sha3_256::new()) {
+            Err(BigKeyError::DerivationParamsMismatch { .. }) => {}
+            _ => panic!("expected storage of another block size to be refused"),
+        }
+    }
+
+    #[test]
+    fn locator_mac_detects_tampering() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_locator_mac();
+
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(locator.len(), 70);
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        // flip a selector bit, and strip the tag entirely
+        let mut tampered = locator.to_bytes();
+        tampered[20] ^= 0x01;
+        let mut stripped = locator.as_bytes()[..46].to_vec();
+        stripped[1] = 0;
+
+        for bad in [tampered, stripped].iter() {
+            match bk.get_key(&bad.clone().into()) {
+                Err(BigKeyError::LocatorAuthenticationFailed) => {}
+                _ => panic!("expected tampered locator to be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn transcript_replays_derivation() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+
+        let (locator, key, transcript) =
+            bk.new_key_with_transcript(SecurityLevel::Bits128).unwrap();
+        assert_eq!(transcript.locator, locator);
+        assert_eq!(
+            transcript.probes.len() as u64,
+            bk.estimated_probe_count().unwrap()
+        );
+        assert!(!transcript
+            .to_bytes()
+            .windows(key.len())
+            .any(|w| w == &key[..]));
+        assert_eq!(bk.verify_transcript(&transcript).unwrap(), key);
+
+        let mut altered = transcript.clone();
+        altered.probes[0].commitment[0] ^= 0x01;
+        assert_ne!(altered.digest(), transcript.digest());
+        match bk.verify_transcript(&altered) {
+            Err(BigKeyError::VerificationFailed { .. }) => {}
+            _ => panic!("expected altered transcript to be rejected"),
+        }
+    }
+
+    #[test]
+    fn probe_distribution_is_recorded_in_locator() {
+        let tmp = key_file(64);
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut storage, Sha3_256::new())
+                .with_probe_distribution(ExcludeEnds { head: 4, tail: 4 });
+
+        let (locator, key, transcript) =
+            bk.new_key_with_transcript(SecurityLevel::Bits128).unwrap();
+        assert!(transcript.probes.iter().all(|p| (4..60).contains(&p.index)));
+
+        // a BigKey with the default distribution re-derives the key from the locator alone
+        let mut bk =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut storage, Sha3_256::new());
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+    }
+
+    #[test]
+    #[cfg(feature = "hardening")]
+    fn hardening_cannot_be_stripped() {
+        let tmp = key_file(64);
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let hardening = crate::kem::Hardening {
+            memory_kib: 64,
+            passes: 1,
+        };
+
+        let mut plain =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut storage, Sha3_256::new());
+        let (plain_locator, _) = plain.new_key(SecurityLevel::Bits128).unwrap();
+
+        let mut bk =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut storage, Sha3_256::new())
+                .with_hardening(hardening);
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(key.len(), 16);
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        match bk.get_key(&plain_locator) {
+            Err(BigKeyError::InvalidLocator { .. }) => {}
+            _ => panic!("expected locator without hardening to be rejected"),
+        }
+    }
+
+    #[test]
+    fn legacy_locators_can_be_authenticated() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        let mut bk = bk.with_locator_mac();
+
+        assert!(bk.get_key(&locator).is_err());
+        let authenticated = bk.authenticate_locator(&locator).unwrap();
+        assert_eq!(bk.get_key(&authenticated).unwrap(), key);
+    }
+
+    #[test]
+    fn version_2_tagged_locators_still_verify() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_locator_mac();
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        let body = LocatorBody::decode(locator.as_bytes()).unwrap();
+
+        // tagged as version 2 locators were: a MAC over bytes 0..44 of the version 2 encoding
+        let mut v2 = vec![2, 0x01];
+        v2.extend_from_slice(&body.key_id.to_be_bytes());
+        v2.extend_from_slice(&(body.security_level as u16).to_be_bytes());
+        v2.extend_from_slice(&body.probe_count.to_be_bytes());
+        v2.extend_from_slice(&body.selector);
+        let mac_key = bk.internal_key(MAC_DOMAIN).unwrap();
+        let tag = blake3::keyed_hash(&mac_key, &v2);
+        v2.extend_from_slice(&tag.as_bytes()[..16]);
+        assert_eq!(v2.len(), 60);
+        assert_eq!(bk.get_key(&v2.clone().into()).unwrap(), key);
+
+        let upgraded = bk.upgrade_locator(&v2.clone().into()).unwrap();
+        assert_eq!(
+            locator_version(upgraded.as_bytes()).unwrap(),
+            LOCATOR_VERSION
+        );
+        assert_eq!(bk.get_key(&upgraded).unwrap(), key);
+
+        let mut forged = v2;
+        forged[20] ^= 0x01;
+        let forged = Locator::from(forged);
+        for result in [bk.get_key(&forged).err(), bk.upgrade_locator(&forged).err()].iter() {
+            match result {
+                Some(BigKeyError::LocatorAuthenticationFailed) => {}
+                _ => panic!("expected a forged version 2 tag to be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn peer_keys_are_independent() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+
+        let (locator, key) = bk
+            .new_key_for_peer(SecurityLevel::Bits128, b"alice")
+            .unwrap();
+        assert_eq!(bk.get_key_for_peer(&locator, b"alice").unwrap(), key);
+        assert_ne!(bk.get_key_for_peer(&locator, b"bob").unwrap(), key);
+        assert!(bk.get_key(&locator).is_err());
+
+        let (plain_locator, _) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        match bk.get_key_for_peer(&plain_locator, b"alice") {
+            Err(BigKeyError::InvalidLocator { .. }) => {}
+            _ => panic!("expected unbound locator to be rejected for a peer"),
+        }
+    }
+
+    // Counts the blocks probed through it, and of those the blocks requested in batches, and
+    // records the blocks prefetched
+    struct CountingStorage<S> {
+        inner: S,
+        probes: u64,
+        batched: u64,
+        prefetched: Vec<BlockIndex>,
+    }
+
+    impl<S: StorageReader> StorageReader for CountingStorage<S> {
+        fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+            self.probes += 1;
+            self.inner.probe(index, output)
+        }
+
+        fn probe_many(
+            &mut self,
+            indices: &[BlockIndex],
+            output: &mut [u8],
+        ) -> Result<(), BigKeyError> {
+            self.probes += indices.len() as u64;
+            self.batched += indices.len() as u64;
+            self.inner.probe_many(indices, output)
+        }
+
+        fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+            self.prefetched.extend_from_slice(indices);
+            self.inner.prefetch(indices)
+        }
+
+        fn big_key_length(&self) -> u64 {
+            self.inner.big_key_length()
+        }
+
+        fn block_size(&self) -> BlockSize {
+            self.inner.block_size()
+        }
+    }
+
+    #[test]
+    fn rejected_locators_cost_a_derivation() {
+        let tmp = key_file(64);
+        let storage = CountingStorage {
+            inner: DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap(),
+            probes: 0,
+            batched: 0,
+            prefetched: Vec::new(),
+        };
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_locator_mac();
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        // the first MAC check derives the MAC key, counting its probes up front
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        let before = bk.storage().probes;
+        assert!(bk.get_key(&locator).is_ok());
+        let valid = bk.storage().probes - before;
+        assert!(valid > 0);
+
+        let mut forged = locator.to_bytes();
+        let len = forged.len();
+        forged[len - 1] ^= 1;
+        let mut other_key_id = locator.to_bytes();
+        other_key_id[5] ^= 1;
+        let mut undecodable = locator.to_bytes();
+        undecodable[0] = 0;
+        for bad in [forged, other_key_id, undecodable, vec![]].iter() {
+            let locator = Locator::from(bad.clone());
+            let before = bk.storage().probes;
+            assert!(bk.get_key(&locator).is_err());
+            assert_eq!(bk.storage().probes - before, valid, "{:?}", bad);
+        }
+        match bk.get_key_for_peer(&locator, b"peer") {
+            Err(BigKeyError::InvalidLocator { .. }) => {}
+            _ => panic!("expected unbound locator to be rejected for a peer"),
+        }
+    }
+
+    #[test]
+    fn decoys_probe_the_claimed_count() {
+        let tmp = key_file(64);
+        let storage = CountingStorage {
+            inner: DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap(),
+            probes: 0,
+            batched: 0,
+            prefetched: Vec::new(),
+        };
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_locator_mac();
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+        let body = LocatorBody::decode(locator.as_bytes()).unwrap();
+        let required = probe_count(SecurityLevel::Bits128, 0.2, BLOCK_1K).unwrap() as u32;
+
+        // forged probe counts under the original tag: the decoy probes as many blocks as a
+        // genuine locator claiming them would, within the bounds of `check_probe_count()`
+        for &(claimed, probed) in [
+            (required * 4, required * 4),
+            (required * 16, required * 16),
+            (u32::MAX, required * 16),
+            (1, required),
+        ]
+        .iter()
+        {
+            let forged = LocatorBody {
+                probe_count: claimed,
+                params: None,
+                ..body.clone()
+            };
+            let before = bk.storage().batched;
+            match bk.get_key(&forged.encode()) {
+                Err(BigKeyError::LocatorAuthenticationFailed) => {}
+                _ => panic!("expected a forged probe count to be rejected"),
+            }
+            assert_eq!(bk.storage().batched - before, probed as u64, "{}", claimed);
+        }
+    }
+
+    #[test]
+    fn oversized_probe_counts_are_refused() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        let required = probe_count(SecurityLevel::Bits128, 0.2, BLOCK_1K).unwrap() as u32;
+
+        // a locator without parameters, as a hostile client could send
+        let body = LocatorBody {
+            params: None,
+            ..LocatorBody::decode(locator.as_bytes()).unwrap()
+        };
+        let generous = LocatorBody {
+            probe_count: required * 16,
+            ..body.clone()
+        };
+        assert_ne!(bk.get_key(&generous.encode()).unwrap(), key);
+        for probe_count in [required * 16 + 1, u32::MAX].iter() {
+            let hostile = LocatorBody {
+                probe_count: *probe_count,
+                ..body.clone()
+            };
+            assert!(matches!(
+                bk.get_key(&hostile.encode()),
+                Err(BigKeyError::InvalidLocator { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn locator_distributions_must_leave_enough_blocks() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+        let (locator, _) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        let body = LocatorBody::decode(locator.as_bytes()).unwrap();
+
+        // 60 of 64 blocks are enough for the 56 probes of a 128-bit key, 1 is not
+        let narrowed = LocatorBody {
+            distribution: ExcludeEnds { head: 2, tail: 2 }.descriptor(),
+            ..body.clone()
+        };
+        let key = bk.get_key(&narrowed.encode()).unwrap();
+        assert_eq!(bk.get_key(&narrowed.encode()).unwrap(), key);
+        for distribution in [
+            ExcludeEnds { head: 63, tail: 0 }.descriptor(),
+            ExcludeRanges::new(&[(0, 10), (20, 64)])
+                .unwrap()
+                .descriptor(),
+        ]
+        .iter()
+        {
+            let hostile = LocatorBody {
+                distribution: distribution.clone(),
+                ..body.clone()
+            };
+            assert!(matches!(
+                bk.get_key(&hostile.encode()),
+                Err(BigKeyError::InvalidLocator { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn prefetch_hints_the_blocks_a_locator_probes() {
+        let tmp = key_file(64);
+        let storage = CountingStorage {
+            inner: DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap(),
+            probes: 0,
+            batched: 0,
+            prefetched: Vec::new(),
+        };
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+
+        let probes = bk.storage().probes;
+        bk.prefetch(&locator).unwrap();
+        assert_eq!(bk.storage().probes, probes);
+        let indices = bk.probe_indices(&locator).unwrap();
+        assert_eq!(bk.storage().prefetched, indices);
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        let mut other_key_id = locator.to_bytes();
+        other_key_id[5] ^= 1;
+        assert!(bk.prefetch(&Locator::from(other_key_id)).is_err());
+
+        // hostile locators are refused before anything is read or allocated
+        let hostile = LocatorBody {
+            probe_count: u32::MAX,
+            params: None,
+            ..LocatorBody::decode(locator.as_bytes()).unwrap()
+        };
+        let prefetched = bk.storage().prefetched.len();
+        assert!(matches!(
+            bk.prefetch(&hostile.encode()),
+            Err(BigKeyError::InvalidLocator { .. })
+        ));
+        assert!(bk.probe_indices(&hostile.encode()).is_err());
+        assert_eq!(bk.storage().prefetched.len(), prefetched);
+
+        // as are locators without the MAC tag a BigKey requires
+        let mut bk = bk.with_locator_mac();
+        assert!(matches!(
+            bk.prefetch(&locator),
+            Err(BigKeyError::LocatorAuthenticationFailed)
+        ));
+        let tagged = bk.authenticate_locator(&locator).unwrap();
+        bk.prefetch(&tagged).unwrap();
+
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        storage
+            .prefetch(&[BlockIndex::new(0), BlockIndex::new(63)])
+            .unwrap();
+        assert!(storage.prefetch(&[BlockIndex::new(64)]).is_err());
+    }
+
+    #[test]
+    fn object_ids_derive_stable_keys() {
+        let tmp = key_file(64);
+        let open = || {
+            let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+            BigKey::new_big_key(SecurityLevel::Bits256, 0.2, storage, Sha3_256::new())
+        };
+        let mut bk = open();
+        let key = bk.derive_for_id(b"sha256:5891b5b522d5df08").unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(
+            open().derive_for_id(b"sha256:5891b5b522d5df08").unwrap(),
+            key
+        );
+        assert_ne!(bk.derive_for_id(b"sha256:5891b5b522d5df09").unwrap(), key);
+        assert_ne!(bk.derive_for_id(b"").unwrap(), key);
+
+        // the derivation depends on the BigKey's configuration
+        let mut other_id = open().with_key_id(1);
+        assert_ne!(
+            other_id.derive_for_id(b"sha256:5891b5b522d5df08").unwrap(),
+            key
+        );
+        let mut namespaced = open().with_app_id(AppId::new("billing"));
+        assert_ne!(
+            namespaced
+                .derive_for_id(b"sha256:5891b5b522d5df08")
+                .unwrap(),
+            key
+        );
+    }
+
+    #[test]
+    fn app_namespaces_are_separate() {
+        let tmp = key_file(64);
+        let open = |app: Option<&str>| {
+            let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+            let bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+            match app {
+                Some(name) => bk.with_app_id(AppId::new(name)),
+                None => bk,
+            }
+        };
+        let (mut billing, mut payroll, mut plain) =
+            (open(Some("billing")), open(Some("payroll")), open(None));
+
+        let (locator, key) = billing.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(billing.get_key(&locator).unwrap(), key);
+        assert_eq!(
+            locator_app_id(locator.as_bytes()).unwrap(),
+            Some(AppId::new("billing"))
+        );
+        for other in [&mut payroll, &mut plain].iter_mut() {
+            match other.get_key(&locator) {
+                Err(BigKeyError::InvalidLocator { .. }) => {}
+                _ => panic!("expected a locator of another namespace to be rejected"),
+            }
+        }
+        let (plain_locator, _) = plain.new_key(SecurityLevel::Bits128).unwrap();
+        assert!(billing.get_key(&plain_locator).is_err());
+
+        // the same selector in another namespace probes different blocks for a different key
+        let mut moved = locator.to_bytes();
+        let len = moved.len();
+        moved[len - PARAMS_LEN - APP_ID_LEN..len - PARAMS_LEN]
+            .copy_from_slice(AppId::new("payroll").as_bytes());
+        let moved = Locator::from(moved);
+        assert_ne!(payroll.get_key(&moved).unwrap(), key);
+    }
+
+    #[test]
+    fn reordered_blocks_fail_probe_check() {
+        let tmp = key_file(64);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_probe_check();
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        // same blocks, different order
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            for i in (0..64u8).rev() {
+                ofile.write_all(&[i; 1024]).unwrap();
+            }
+        }
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+        match bk.get_key(&locator) {
+            Err(BigKeyError::ProbeCheckMismatch) => {}
+            _ => panic!("expected reordered blocks to fail the probe check"),
+        }
+    }
+
+    #[test]
+    fn runtime_selected_storage_backend() {
+        let tmp = key_file(16);
+        let factory: &dyn StorageReaderFactory = &DiskStorageFactory;
+        let storage: Box<dyn StorageReader> = factory.open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+    }
+
+    #[test]
+    fn locator_with_too_few_probes_fails() {
+        let tmp = key_file(4);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        assert_eq!(storage.big_key_length(), 4096);
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.5, storage, Sha3_256::new());
+
+        let (locator, _) = bk.new_key(SecurityLevel::Bits128).unwrap();
+        let mut weakened = locator.to_bytes();
+        weakened[11] = 1;
+
+        match bk.get_key(&weakened.into()) {
+            Err(BigKeyError::InvalidLocator { .. }) => {}
+            _ => panic!("expected locator with too few probes to be rejected"),
+        }
+    }
+
+    #[test]
+    fn no_leakage_needs_enough_block_bits() {
+        assert_eq!(
+            probe_count(SecurityLevel::Bits128, 0.0, BLOCK_8).unwrap(),
+            16
+        );
+        assert_eq!(
+            probe_count(SecurityLevel::Bits256, 0.0, BLOCK_8).unwrap(),
+            32
+        );
+        assert_eq!(
+            probe_count(SecurityLevel::Bits128, 0.0, BLOCK_4K).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn probe_count_grows_with_leakage() {
+        // -log2(0.5) == 1 bit per probe
+        assert_eq!(
+            probe_count(SecurityLevel::Bits128, 0.5, BLOCK_4K).unwrap(),
+            128
+        );
+        assert_eq!(
+            probe_count(SecurityLevel::Bits256, 0.5, BLOCK_4K).unwrap(),
+            256
+        );
+
+        let low = probe_count(SecurityLevel::Bits128, 0.1, BLOCK_4K).unwrap();
+        let high = probe_count(SecurityLevel::Bits128, 0.9, BLOCK_4K).unwrap();
+        assert!(low < high);
+    }
+
+    #[test]
+    fn leakage_tolerance_out_of_range_fails() {
+        for tolerance in [-0.1f32, 1.0, 1.5, f32::NAN].iter() {
+            match probe_count(SecurityLevel::Bits128, *tolerance, BLOCK_4K) {
+                Err(BigKeyError::LeakageToleranceOutOfRange { .. }) => {}
+                _ => panic!("expected tolerance {} to be rejected", tolerance),
+            }
+        }
+    }
+
+    #[test]
+    fn opening_checks_the_key_supports_the_security_level() {
+        // 128 bits at leakage 0.2 takes 56 probes of 1K blocks
+        let tmp = key_file(56);
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let bk = BigKey::open(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new()).unwrap();
+        assert_eq!(bk.estimated_probe_count().unwrap(), 56);
+
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        match BigKey::open(SecurityLevel::Bits256, 0.2, storage, Sha3_256::new()) {
+            Err(BigKeyError::KeyTooSmallForSecurityLevel {
+                bits,
+                key_len,
+                min_len,
+                ..
+            }) => assert_eq!((bits, key_len, min_len), (256, 56 * 1024, 111 * 1024)),
+            _ => panic!("expected a 56 block key to be too small for 256 bits"),
+        }
+
+        // more leakage needs more blocks
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.5, storage, Sha3_256::new());
+        assert!(bk.check_security().is_err());
+    }
+} // mod test
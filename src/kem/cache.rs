@@ -0,0 +1,305 @@
+//! Pre-derived keys for devices that are temporarily cut off from their BigKey.
+//!
+//! A laptop leaving the office loses access to the BigKey on the file server, yet still needs
+//! fresh keys to encrypt. Before it leaves, `KeyCache::fill()` derives a fixed number of
+//! `(Locator, KeyMaterial)` pairs; `take()` hands them out one at a time while offline, and the
+//! locators let the BigKey re-derive every key once back in reach. Each cached key is handed out
+//! once only, exactly as if it came from `new_key()`.
+//!
+//! At rest the cache is sealed under a passphrase: Argon2id (see `Hardening`) stretches the
+//! passphrase with a random salt into a ChaCha20-Poly1305 key, and the cost parameters and salt
+//! are authenticated as associated data.
+//!
+//! Sealed layout: 16 byte magic, 8 byte `Hardening`, 16 byte salt, 12 byte nonce, then the
+//! encrypted entries and tag. Entries are a u32 count followed by, for each pair, u16 lengths
+//! and bytes of the locator and of the key, all big-endian.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use digest::Digest;
+
+use crate::kem::hardening::HARDENING_LEN;
+use crate::kem::{BigKey, BigKeyKem, Hardening};
+use crate::memory::wipe;
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, KeyMaterial, Locator, SecurityLevel};
+
+/// Passphrase stretching cost used by `KeyCache::seal()`: 64 MiB, 3 passes
+pub const DEFAULT_CACHE_HARDENING: Hardening = Hardening {
+    memory_kib: 64 * 1024,
+    passes: 3,
+};
+
+/// Largest stretching cost `KeyCache::open()` accepts: 4 GiB, 64 passes
+const MAX_CACHE_HARDENING: Hardening = Hardening {
+    memory_kib: 4 * 1024 * 1024,
+    passes: 64,
+};
+
+const MAGIC: &[u8; 16] = b"BFDISE-KCACHE-1\x00";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + HARDENING_LEN + SALT_LEN + NONCE_LEN;
+
+/// Fixed capacity ring of pre-derived keys
+pub struct KeyCache {
+    capacity: usize,
+    entries: VecDeque<(Locator, KeyMaterial)>,
+}
+
+impl KeyCache {
+    /// An empty cache holding up to `capacity` keys
+    pub fn new(capacity: usize) -> Self {
+        KeyCache {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Derive `capacity` fresh keys at `security_level` from `kem`
+    pub fn fill<S: StorageReader, H: Digest>(
+        kem: &mut BigKey<S, H>,
+        security_level: SecurityLevel,
+        capacity: usize,
+    ) -> Result<KeyCache, BigKeyError> {
+        let mut cache = KeyCache::new(capacity);
+        cache.refill(kem, security_level)?;
+        Ok(cache)
+    }
+
+    /// Top the cache up to its capacity with fresh keys from `kem`, returning how many were
+    /// added
+    pub fn refill<S: StorageReader, H: Digest>(
+        &mut self,
+        kem: &mut BigKey<S, H>,
+        security_level: SecurityLevel,
+    ) -> Result<usize, BigKeyError> {
+        let missing = self.capacity - self.entries.len();
+        for _ in 0..missing {
+            self.entries.push_back(kem.new_key(security_level)?);
+        }
+        Ok(missing)
+    }
+
+    /// Hand out the oldest cached key and its locator, removing it from the cache
+    pub fn take(&mut self) -> Option<(Locator, KeyMaterial)> {
+        self.entries.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Encrypt the remaining keys under `passphrase` at the `DEFAULT_CACHE_HARDENING` cost
+    pub fn seal(&self, passphrase: &[u8]) -> Result<Vec<u8>, BigKeyError> {
+        self.seal_with(passphrase, DEFAULT_CACHE_HARDENING)
+    }
+
+    /// Encrypt the remaining keys under `passphrase`, stretched at the given Argon2id `cost`
+    pub fn seal_with(&self, passphrase: &[u8], cost: Hardening) -> Result<Vec<u8>, BigKeyError> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&cost.to_bytes());
+        let mut random = [0u8; SALT_LEN + NONCE_LEN];
+        getrandom::getrandom(&mut random)?;
+        header.extend_from_slice(&random);
+
+        let mut plaintext = self.encode_entries()?;
+        let sealed = cipher(passphrase, &header)?.encrypt(
+            Nonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]),
+            Payload {
+                msg: &plaintext,
+                aad: &header,
+            },
+        );
+        wipe(&mut plaintext);
+
+        header.extend_from_slice(&sealed.map_err(|_| failed("encryption failed"))?);
+        Ok(header)
+    }
+
+    /// Decrypt a cache sealed by `seal()`. The cache's capacity is the number of keys it holds.
+    pub fn open(sealed: &[u8], passphrase: &[u8]) -> Result<KeyCache, BigKeyError> {
+        if sealed.len() < HEADER_LEN || &sealed[..MAGIC.len()] != MAGIC {
+            return Err(failed("not a sealed key cache"));
+        }
+        let header = &sealed[..HEADER_LEN];
+
+        let mut plaintext = cipher(passphrase, header)?
+            .decrypt(
+                Nonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]),
+                Payload {
+                    msg: &sealed[HEADER_LEN..],
+                    aad: header,
+                },
+            )
+            .map_err(|_| failed("wrong passphrase or damaged key cache"))?;
+        let entries = decode_entries(&plaintext);
+        wipe(&mut plaintext);
+
+        let entries = entries.ok_or_else(|| failed("malformed key cache entries"))?;
+        Ok(KeyCache {
+            capacity: entries.len(),
+            entries,
+        })
+    }
+
+    fn encode_entries(&self) -> Result<Vec<u8>, BigKeyError> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for (locator, key) in self.entries.iter() {
+            for field in [locator.as_bytes(), &key[..]].iter() {
+                if field.len() > u16::MAX as usize {
+                    return Err(failed("cached locator or key too long"));
+                }
+                out.extend_from_slice(&(field.len() as u16).to_be_bytes());
+                out.extend_from_slice(field);
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Drop for KeyCache {
+    fn drop(&mut self) {
+        for (_, key) in self.entries.iter_mut() {
+            wipe(key);
+        }
+    }
+}
+
+fn decode_entries(mut bytes: &[u8]) -> Option<VecDeque<(Locator, KeyMaterial)>> {
+    let mut take = |len: usize| -> Option<&[u8]> {
+        let (head, rest) = (bytes.get(..len)?, bytes.get(len..)?);
+        bytes = rest;
+        Some(head)
+    };
+
+    let count = u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize;
+    let mut entries = VecDeque::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        let locator_len = u16::from_be_bytes(take(2)?.try_into().unwrap()) as usize;
+        let locator: Locator = take(locator_len)?.into();
+        let key_len = u16::from_be_bytes(take(2)?.try_into().unwrap()) as usize;
+        let key: KeyMaterial = take(key_len)?.into();
+        entries.push_back((locator, key));
+    }
+    match take(1) {
+        None => Some(entries),
+        Some(_) => None,
+    }
+}
+
+// A fresh salt per sealing makes every stretched key, and so every nonce, single use
+fn cipher(passphrase: &[u8], header: &[u8]) -> Result<ChaCha20Poly1305, BigKeyError> {
+    let cost = Hardening::from_bytes(&header[MAGIC.len()..MAGIC.len() + HARDENING_LEN]);
+    // the cost is only authenticated after stretching, so bound it before
+    if !MAX_CACHE_HARDENING.at_least(&cost) {
+        return Err(failed("passphrase stretching cost out of range"));
+    }
+    let salt = &header[MAGIC.len() + HARDENING_LEN..MAGIC.len() + HARDENING_LEN + SALT_LEN];
+    let mut key = cost.apply(passphrase, salt, 32)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    wipe(&mut key);
+    Ok(cipher)
+}
+
+fn failed(reason: &'static str) -> BigKeyError {
+    BigKeyError::KeyCacheFailed { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::kem::cache::{decode_entries, HEADER_LEN};
+    use crate::kem::{BigKey, BigKeyKem, Hardening, KeyCache};
+    use crate::storage::ReadSeekStorage;
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K};
+
+    const CHEAP: Hardening = Hardening {
+        memory_kib: 8,
+        passes: 1,
+    };
+
+    #[test]
+    fn sealed_cache_serves_keys_offline() {
+        let key: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 31 % 251) as u8).collect();
+        let storage = ReadSeekStorage::new(Cursor::new(key), BLOCK_1K).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+
+        let mut cache = KeyCache::fill(&mut bk, SecurityLevel::Bits128, 4).unwrap();
+        assert_eq!(cache.len(), 4);
+        let (first_locator, first_key) = cache.take().unwrap();
+        assert_eq!(bk.get_key(&first_locator).unwrap(), first_key);
+
+        let sealed = cache.seal_with(b"correct horse", CHEAP).unwrap();
+        match KeyCache::open(&sealed, b"wrong horse") {
+            Err(BigKeyError::KeyCacheFailed { .. }) => {}
+            _ => panic!("expected the wrong passphrase to fail"),
+        }
+        let mut tampered = sealed.clone();
+        tampered[19] ^= 1;
+        assert!(KeyCache::open(&tampered, b"correct horse").is_err());
+        tampered[16] = 0xff;
+        match KeyCache::open(&tampered, b"correct horse") {
+            Err(BigKeyError::KeyCacheFailed { reason }) => assert!(reason.contains("cost")),
+            _ => panic!("expected an absurd stretching cost to be refused"),
+        }
+
+        // offline: keys come from the opened cache alone
+        let mut offline = KeyCache::open(&sealed, b"correct horse").unwrap();
+        assert_eq!(offline.len(), 3);
+        let mut taken = Vec::new();
+        while let Some(pair) = offline.take() {
+            taken.push(pair);
+        }
+        assert!(offline.is_empty());
+
+        // back online, every offline key can be re-derived, and the cache refills
+        for (locator, key) in taken.iter() {
+            assert_eq!(&bk.get_key(locator).unwrap(), key);
+        }
+        assert_eq!(offline.refill(&mut bk, SecurityLevel::Bits128).unwrap(), 3);
+    }
+
+    #[test]
+    fn hostile_caches_are_refused() {
+        let empty = KeyCache::new(2).seal_with(b"pass", CHEAP).unwrap();
+        let opened = KeyCache::open(&empty, b"pass").unwrap();
+        assert!(opened.is_empty());
+        assert_eq!(opened.capacity(), 0);
+
+        for sealed in [&b""[..], &empty[..HEADER_LEN - 1], &[0u8; 64][..]].iter() {
+            match KeyCache::open(sealed, b"pass") {
+                Err(BigKeyError::KeyCacheFailed { reason }) => {
+                    assert_eq!(reason, "not a sealed key cache")
+                }
+                _ => panic!("expected a short or foreign cache to be refused"),
+            }
+        }
+        assert!(KeyCache::open(&empty[..empty.len() - 1], b"pass").is_err());
+
+        // entries claiming more than they hold, or followed by more, do not decode
+        let one = [&[0, 0, 0, 1][..], &[0, 2, 1, 2], &[0, 1, 9]].concat();
+        assert_eq!(decode_entries(&one).unwrap().len(), 1);
+        assert!(decode_entries(&one[..one.len() - 1]).is_none());
+        assert!(decode_entries(&[&one[..], &[0]].concat()).is_none());
+        assert!(decode_entries(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]).is_none());
+        assert!(decode_entries(&[0, 0, 0, 1, 0xff, 0xff]).is_none());
+        assert!(decode_entries(&[0, 0]).is_none());
+    }
+} // mod test
@@ -0,0 +1,256 @@
+//! How probe samples are mapped onto the blocks of a BigKey.
+//!
+//! Each probe starts as a uniformly random 64-bit sample expanded from the locator's selector.
+//! A `ProbeDistribution` maps that sample to a block index, by default uniformly over the whole
+//! key. Alternatives can keep probes away from parts of the key, e.g. blocks reserved for
+//! metadata or known-bad sectors. The distribution's `DistributionDescriptor` is recorded in
+//! every locator so the same blocks are probed when the key is re-derived.
+
+use std::convert::TryInto;
+
+use crate::traits::{BigKeyError, BlockCount, BlockIndex};
+
+/// Identifier of `Uniform` in locators
+pub const UNIFORM_ID: u8 = 0;
+/// Identifier of `ExcludeEnds` in locators
+pub const EXCLUDE_ENDS_ID: u8 = 1;
+/// Identifier of `ExcludeRanges` in locators
+pub const EXCLUDE_RANGES_ID: u8 = 2;
+
+/// Maximum length of `DistributionDescriptor::params`
+pub const MAX_PARAMS_LEN: usize = 255;
+
+/// Serialized form of a `ProbeDistribution` as stored in a locator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DistributionDescriptor {
+    pub id: u8,
+    /// At most `MAX_PARAMS_LEN` bytes
+    pub params: Vec<u8>,
+}
+
+impl DistributionDescriptor {
+    pub fn uniform() -> Self {
+        DistributionDescriptor {
+            id: UNIFORM_ID,
+            params: Vec::new(),
+        }
+    }
+}
+
+/// Maps uniformly random samples to the block indices probed during key derivation
+pub trait ProbeDistribution: Send + Sync {
+    /// One of the `block_count` blocks, for the uniformly random `sample`
+    fn index(&self, sample: u64, block_count: BlockCount) -> Result<BlockIndex, BigKeyError>;
+
+    /// Identifier and parameters recorded in locators
+    fn descriptor(&self) -> DistributionDescriptor;
+
+    /// How many of the `block_count` blocks may be probed at all
+    fn usable_blocks(&self, block_count: BlockCount) -> u64 {
+        block_count.get()
+    }
+}
+
+/// Every block is equally likely to be probed
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Uniform;
+
+impl ProbeDistribution for Uniform {
+    fn index(&self, sample: u64, block_count: BlockCount) -> Result<BlockIndex, BigKeyError> {
+        if block_count.get() == 0 {
+            return Err(no_blocks());
+        }
+        Ok(BlockIndex::new(sample % block_count.get()))
+    }
+
+    fn descriptor(&self) -> DistributionDescriptor {
+        DistributionDescriptor::uniform()
+    }
+}
+
+/// Never probe the first `head` or last `tail` blocks, uniform over the rest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExcludeEnds {
+    pub head: u64,
+    pub tail: u64,
+}
+
+impl ProbeDistribution for ExcludeEnds {
+    fn index(&self, sample: u64, block_count: BlockCount) -> Result<BlockIndex, BigKeyError> {
+        let usable = self.usable_blocks(block_count);
+        if usable == 0 {
+            return Err(no_blocks());
+        }
+        Ok(BlockIndex::new(self.head + sample % usable))
+    }
+
+    fn usable_blocks(&self, block_count: BlockCount) -> u64 {
+        block_count
+            .get()
+            .saturating_sub(self.head)
+            .saturating_sub(self.tail)
+    }
+
+    fn descriptor(&self) -> DistributionDescriptor {
+        let mut params = Vec::with_capacity(16);
+        params.extend_from_slice(&self.head.to_be_bytes());
+        params.extend_from_slice(&self.tail.to_be_bytes());
+        DistributionDescriptor {
+            id: EXCLUDE_ENDS_ID,
+            params,
+        }
+    }
+}
+
+/// Never probe blocks in any of the half-open `(start, end)` ranges, uniform over the rest.
+/// At most 15 ranges fit in a locator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExcludeRanges {
+    ranges: Vec<(u64, u64)>,
+}
+
+impl ExcludeRanges {
+    /// Exclude `ranges`, which may overlap and be given in any order
+    pub fn new(ranges: &[(u64, u64)]) -> Result<Self, BigKeyError> {
+        let mut sorted: Vec<(u64, u64)> = ranges.iter().filter(|(s, e)| s < e).cloned().collect();
+        sorted.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(sorted.len());
+        for (start, end) in sorted {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        if merged.len() * 16 > MAX_PARAMS_LEN {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "too many excluded block ranges".to_string(),
+            });
+        }
+        Ok(ExcludeRanges { ranges: merged })
+    }
+
+    /// The excluded ranges, sorted and merged
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+}
+
+impl ProbeDistribution for ExcludeRanges {
+    fn index(&self, sample: u64, block_count: BlockCount) -> Result<BlockIndex, BigKeyError> {
+        let usable = self.usable_blocks(block_count);
+        if usable == 0 {
+            return Err(no_blocks());
+        }
+
+        // skip over every excluded range at or before the candidate
+        let mut index = sample % usable;
+        for (start, end) in self.ranges.iter() {
+            if *start > index {
+                break;
+            }
+            index += end - start;
+        }
+        Ok(BlockIndex::new(index))
+    }
+
+    fn usable_blocks(&self, block_count: BlockCount) -> u64 {
+        let block_count = block_count.get();
+        let excluded: u64 = self
+            .ranges
+            .iter()
+            .map(|(s, e)| e.min(&block_count).saturating_sub(*s))
+            .sum();
+        block_count.saturating_sub(excluded)
+    }
+
+    fn descriptor(&self) -> DistributionDescriptor {
+        let mut params = Vec::with_capacity(self.ranges.len() * 16);
+        for (start, end) in self.ranges.iter() {
+            params.extend_from_slice(&start.to_be_bytes());
+            params.extend_from_slice(&end.to_be_bytes());
+        }
+        DistributionDescriptor {
+            id: EXCLUDE_RANGES_ID,
+            params,
+        }
+    }
+}
+
+/// Reconstruct one of the built-in distributions from its descriptor
+pub(crate) fn builtin(
+    descriptor: &DistributionDescriptor,
+) -> Result<Box<dyn ProbeDistribution>, BigKeyError> {
+    let params = &descriptor.params;
+    let u64_at = |i: usize| u64::from_be_bytes(params[i..i + 8].try_into().unwrap());
+
+    match (descriptor.id, params.len()) {
+        (UNIFORM_ID, 0) => Ok(Box::new(Uniform)),
+        (EXCLUDE_ENDS_ID, 16) => Ok(Box::new(ExcludeEnds {
+            head: u64_at(0),
+            tail: u64_at(8),
+        })),
+        (EXCLUDE_RANGES_ID, len) if len % 16 == 0 => {
+            let ranges: Vec<(u64, u64)> = (0..len)
+                .step_by(16)
+                .map(|i| (u64_at(i), u64_at(i + 8)))
+                .collect();
+            Ok(Box::new(ExcludeRanges::new(&ranges)?))
+        }
+        _ => Err(BigKeyError::InvalidLocator {
+            reason: "unknown probe distribution",
+        }),
+    }
+}
+
+fn no_blocks() -> BigKeyError {
+    BigKeyError::InvalidConfig {
+        reason: "probe distribution leaves no blocks to probe".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kem::distribution::{builtin, ExcludeEnds, ExcludeRanges, ProbeDistribution};
+    use crate::traits::BlockCount;
+
+    #[test]
+    fn excluded_blocks_are_never_probed() {
+        let ends = ExcludeEnds { head: 2, tail: 3 };
+        let ranges = ExcludeRanges::new(&[(10, 12), (4, 6), (5, 8)]).unwrap();
+        assert_eq!(ranges.ranges(), &[(4, 8), (10, 12)]);
+
+        let blocks = BlockCount::new(16);
+        for sample in 0..1000 {
+            let index = ends.index(sample, blocks).unwrap().get();
+            assert!((2..13).contains(&index));
+
+            let index = ranges.index(sample, blocks).unwrap().get();
+            assert!(index < 16);
+            assert!(!(4..8).contains(&index) && !(10..12).contains(&index));
+        }
+
+        assert!(ends.index(0, BlockCount::new(5)).is_err());
+        assert_eq!(ends.usable_blocks(blocks), 11);
+        assert_eq!(ends.usable_blocks(BlockCount::new(4)), 0);
+        assert_eq!(ranges.usable_blocks(blocks), 10);
+        assert_eq!(ranges.usable_blocks(BlockCount::new(9)), 5);
+    }
+
+    #[test]
+    fn builtin_distributions_round_trip() {
+        let ends = ExcludeEnds { head: 1, tail: 7 };
+        let ranges = ExcludeRanges::new(&[(3, 9)]).unwrap();
+
+        for d in [&ends as &dyn ProbeDistribution, &ranges].iter() {
+            let rebuilt = builtin(&d.descriptor()).unwrap();
+            assert_eq!(rebuilt.descriptor(), d.descriptor());
+            let blocks = BlockCount::new(64);
+            assert_eq!(
+                rebuilt.index(12345, blocks).unwrap(),
+                d.index(12345, blocks).unwrap()
+            );
+        }
+    }
+} // mod test
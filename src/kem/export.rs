@@ -0,0 +1,192 @@
+//! Text encodings of derived keys for other systems.
+//!
+//! `export_key()` writes `KeyMaterial` in one of the `KeyFormat`s and `import_key()` reads it
+//! back, with constant-time codecs (see `traits::secret`):
+//!
+//! * `Hex`: lowercase hex, e.g. `000102...`
+//! * `Base64`: standard, padded base64
+//! * `Jwk`: a JSON Web Key (RFC 7517) of type `oct`, the key in unpadded base64url:
+//!   `{"kty":"oct","k":"AAEC..."}`, with a `kid` if given one. The armored locator of the key
+//!   (`armor_locator()`) makes a good `kid`, letting the holder of the BigKey re-derive it.
+//!
+//! To hand keys to systems that store them, wrap them under a key encryption key instead, see
+//! `wrap_key()`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::memory::wipe;
+use crate::traits::{key_from_base64, key_from_base64url, key_from_hex, SecretBytes};
+use crate::traits::{BigKeyError, KeyMaterial};
+
+const JWK_KEY_TYPE: &str = "oct";
+
+/// Text encoding of exported keys
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyFormat {
+    Hex,
+    Base64,
+    Jwk,
+}
+
+impl KeyFormat {
+    pub fn parse(name: &str) -> Option<KeyFormat> {
+        match name {
+            "hex" => Some(KeyFormat::Hex),
+            "base64" => Some(KeyFormat::Base64),
+            "jwk" => Some(KeyFormat::Jwk),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    kid: Option<String>,
+    k: String,
+}
+
+impl Drop for Jwk {
+    fn drop(&mut self) {
+        wipe(&mut std::mem::take(&mut self.k).into_bytes());
+    }
+}
+
+/// Encode `key` as `format`. `kid` is included in JWKs and ignored by the other formats.
+pub fn export_key(key: &[u8], format: KeyFormat, kid: Option<&str>) -> String {
+    match format {
+        KeyFormat::Hex => key.to_hex(),
+        KeyFormat::Base64 => key.to_base64(),
+        KeyFormat::Jwk => {
+            let jwk = Jwk {
+                kty: JWK_KEY_TYPE.to_string(),
+                kid: kid.map(str::to_string),
+                k: key.to_base64url(),
+            };
+            serde_json::to_string(&jwk).expect("JWKs serialize")
+        }
+    }
+}
+
+/// Decode a key exported as `format`, failing with `InvalidEncoding` if it is malformed or, for
+/// JWKs, not of type `oct`
+pub fn import_key(text: &str, format: KeyFormat) -> Result<KeyMaterial, BigKeyError> {
+    match format {
+        KeyFormat::Hex => key_from_hex(text.trim()),
+        KeyFormat::Base64 => key_from_base64(text.trim()),
+        KeyFormat::Jwk => {
+            let jwk: Jwk =
+                serde_json::from_str(text).map_err(|_| BigKeyError::InvalidEncoding {
+                    reason: "not a JSON Web Key",
+                })?;
+            if jwk.kty != JWK_KEY_TYPE {
+                return Err(BigKeyError::InvalidEncoding {
+                    reason: "JSON Web Key is not of type oct",
+                });
+            }
+            key_from_base64url(&jwk.k)
+        }
+    }
+}
+
+/// The `kid` of a JWK exported by `export_key()`, if it has one
+pub fn jwk_key_id(text: &str) -> Result<Option<String>, BigKeyError> {
+    let jwk: Jwk = serde_json::from_str(text).map_err(|_| BigKeyError::InvalidEncoding {
+        reason: "not a JSON Web Key",
+    })?;
+    Ok(jwk.kid.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kem::{export_key, import_key, jwk_key_id, KeyFormat};
+    use crate::traits::{BigKeyError, KeyMaterial};
+
+    #[test]
+    fn exports_have_fixed_encodings() {
+        let key: KeyMaterial = (0..16u8).map(|i| i * 17).collect::<Vec<u8>>().into();
+
+        let hex = export_key(&key, KeyFormat::Hex, Some("ignored"));
+        assert_eq!(hex, "00112233445566778899aabbccddeeff");
+        let base64 = export_key(&key, KeyFormat::Base64, None);
+        assert_eq!(base64, "ABEiM0RVZneImaq7zN3u/w==");
+        let jwk = export_key(&key, KeyFormat::Jwk, Some("bklc1example"));
+        assert_eq!(
+            jwk,
+            r#"{"kty":"oct","kid":"bklc1example","k":"ABEiM0RVZneImaq7zN3u_w"}"#
+        );
+        assert_eq!(
+            export_key(&key, KeyFormat::Jwk, None),
+            r#"{"kty":"oct","k":"ABEiM0RVZneImaq7zN3u_w"}"#
+        );
+
+        for (text, format) in [
+            (&hex, KeyFormat::Hex),
+            (&base64, KeyFormat::Base64),
+            (&jwk, KeyFormat::Jwk),
+        ]
+        .iter()
+        {
+            assert_eq!(import_key(text, *format).unwrap(), key);
+        }
+        assert_eq!(jwk_key_id(&jwk).unwrap().as_deref(), Some("bklc1example"));
+
+        // RFC 7517 appendix A.3, members in any order and unknown members ignored
+        let rfc = r#"{"kty":"oct", "alg":"A128KW", "k":"GawgguFyGrWKav7AX4VKUg"}"#;
+        let rfc_key = import_key(rfc, KeyFormat::Jwk).unwrap();
+        assert_eq!(rfc_key.len(), 16);
+        assert!(export_key(&rfc_key, KeyFormat::Jwk, None).contains("GawgguFyGrWKav7AX4VKUg"));
+
+        for (text, format) in [
+            (r#"{"kty":"RSA","k":"AAAA"}"#, KeyFormat::Jwk),
+            (r#"{"kty":"oct"}"#, KeyFormat::Jwk),
+            (r#"{"kty":"oct","k":"AA=A"}"#, KeyFormat::Jwk),
+            ("0011zz", KeyFormat::Hex),
+        ]
+        .iter()
+        {
+            match import_key(text, *format) {
+                Err(BigKeyError::InvalidEncoding { .. }) => {}
+                _ => panic!("expected {} to be rejected", text),
+            }
+        }
+    }
+
+    #[test]
+    fn hostile_and_padded_imports() {
+        // surrounding whitespace is tolerated for the bare encodings, not inside them
+        assert_eq!(
+            import_key(" 0011\n", KeyFormat::Hex).unwrap()[..],
+            [0x00, 0x11]
+        );
+        assert_eq!(
+            import_key("ABE=\n", KeyFormat::Base64).unwrap()[..],
+            [0x00, 0x11]
+        );
+        assert!(import_key("00 11", KeyFormat::Hex).is_err());
+        assert!(import_key("001", KeyFormat::Hex).is_err());
+        assert!(import_key("AB=E", KeyFormat::Base64).is_err());
+
+        for text in [
+            "",
+            "null",
+            "[]",
+            r#"{"kty":"oct","k":42}"#,
+            r#"{"kty":"oct","k":"AAAA""#,
+            r#"{"kty":"oct","k":"AAAA/+"}"#,
+        ]
+        .iter()
+        {
+            assert!(import_key(text, KeyFormat::Jwk).is_err(), "{}", text);
+        }
+        assert!(jwk_key_id("not json").is_err());
+        assert_eq!(jwk_key_id(r#"{"kty":"oct","k":""}"#).unwrap(), None);
+
+        for name in ["hex", "base64", "jwk"].iter() {
+            assert!(KeyFormat::parse(name).is_some());
+        }
+        assert_eq!(KeyFormat::parse("JWK"), None);
+        assert_eq!(KeyFormat::parse("pem"), None);
+    }
+} // mod test
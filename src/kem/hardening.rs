@@ -0,0 +1,81 @@
+//! Optional memory-hard step between probing and key output.
+//!
+//! Against an adversary who has exfiltrated most of a BigKey, each derived key is protected by
+//! the few blocks they are missing; they may try to brute force those blocks offline. Passing
+//! the probe digest through Argon2id makes every guess cost `memory_kib` KiB of memory and
+//! `passes` passes over it.
+
+use std::convert::TryInto;
+
+#[cfg(feature = "hardening")]
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::traits::{BigKeyError, KeyMaterial};
+
+/// Encoded length of `Hardening` in a locator
+pub(crate) const HARDENING_LEN: usize = 8;
+
+/// Argon2id cost parameters
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hardening {
+    /// Memory used per derivation in KiB, at least 8
+    pub memory_kib: u32,
+    /// Passes over memory, at least 1
+    pub passes: u32,
+}
+
+impl Hardening {
+    /// Whether `self` costs at least as much as `other` in both memory and passes
+    pub fn at_least(&self, other: &Hardening) -> bool {
+        self.memory_kib >= other.memory_kib && self.passes >= other.passes
+    }
+
+    /// Argon2id of `digest` salted with `salt`, `key_len` bytes long
+    #[cfg(feature = "hardening")]
+    pub(crate) fn apply(
+        &self,
+        digest: &[u8],
+        salt: &[u8],
+        key_len: usize,
+    ) -> Result<KeyMaterial, BigKeyError> {
+        let params = Params::new(self.memory_kib, self.passes, 1, Some(key_len))
+            .map_err(|e| invalid(e.to_string()))?;
+        let mut key = vec![0u8; key_len];
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(digest, salt, &mut key)
+            .map_err(|e| invalid(e.to_string()))?;
+
+        Ok(key.into_boxed_slice())
+    }
+
+    #[cfg(not(feature = "hardening"))]
+    pub(crate) fn apply(
+        &self,
+        _digest: &[u8],
+        _salt: &[u8],
+        _key_len: usize,
+    ) -> Result<KeyMaterial, BigKeyError> {
+        Err(invalid("built without the `hardening` feature".to_string()))
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; HARDENING_LEN] {
+        let mut out = [0u8; HARDENING_LEN];
+        out[0..4].copy_from_slice(&self.memory_kib.to_be_bytes());
+        out[4..8].copy_from_slice(&self.passes.to_be_bytes());
+        out
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Hardening {
+        Hardening {
+            memory_kib: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            passes: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+        }
+    }
+}
+
+fn invalid(reason: String) -> BigKeyError {
+    BigKeyError::InvalidConfig {
+        reason: format!("hardening: {}", reason),
+    }
+}
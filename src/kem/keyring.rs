@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+
+use digest::Digest;
+
+use crate::kem::locator::LocatorBody;
+use crate::kem::{BigKey, BigKeyKem};
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, KeyMaterial, Locator, SecurityLevel};
+
+/// A set of BigKeys identified by key id, e.g. the current generation plus previous generations
+/// still needed to decrypt older data.
+///
+/// New keys are always derived from the *current* BigKey. Each locator records the id of the
+/// BigKey it was derived from, so `get_key()` routes to the right BigKey automatically.
+pub struct Keyring<S: StorageReader, H: Digest> {
+    keys: BTreeMap<u32, BigKey<S, H>>,
+    current: Option<u32>,
+}
+
+impl<S: StorageReader, H: Digest> Keyring<S, H> {
+    pub fn new() -> Self {
+        Keyring {
+            keys: BTreeMap::new(),
+            current: None,
+        }
+    }
+
+    /// Add `big_key` under its `key_id()`. The first BigKey added becomes current.
+    pub fn add(&mut self, big_key: BigKey<S, H>) -> Result<(), BigKeyError> {
+        let key_id = big_key.key_id();
+
+        if self.keys.contains_key(&key_id) {
+            return Err(BigKeyError::DuplicateKeyId { key_id });
+        }
+
+        self.keys.insert(key_id, big_key);
+        self.current.get_or_insert(key_id);
+
+        Ok(())
+    }
+
+    /// Remove the BigKey `key_id`; locators referring to it can no longer be resolved.
+    pub fn remove(&mut self, key_id: u32) -> Option<BigKey<S, H>> {
+        if self.current == Some(key_id) {
+            self.current = None;
+        }
+        self.keys.remove(&key_id)
+    }
+
+    /// Derive new keys from BigKey `key_id` from now on
+    pub fn set_current(&mut self, key_id: u32) -> Result<(), BigKeyError> {
+        if !self.keys.contains_key(&key_id) {
+            return Err(BigKeyError::UnknownKeyId { key_id });
+        }
+        self.current = Some(key_id);
+        Ok(())
+    }
+
+    /// Id of the BigKey new keys are derived from
+    pub fn current(&self) -> Option<u32> {
+        self.current
+    }
+
+    /// Ids of all BigKeys in the keyring, in ascending order
+    pub fn key_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.keys.keys().copied()
+    }
+
+    /// Derive a fresh key from the current BigKey
+    pub fn new_key(
+        &mut self,
+        security_level: SecurityLevel,
+    ) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        let key_id = self.current.ok_or(BigKeyError::NoCurrentKey)?;
+        self.keys
+            .get_mut(&key_id)
+            .ok_or(BigKeyError::UnknownKeyId { key_id })?
+            .new_key(security_level)
+    }
+
+    /// Re-derive the key identified by `locator` using whichever BigKey it refers to
+    pub fn get_key(&mut self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+        let key_id = LocatorBody::decode(locator.as_bytes())?.key_id;
+        self.keys
+            .get_mut(&key_id)
+            .ok_or(BigKeyError::UnknownKeyId { key_id })?
+            .get_key(locator)
+    }
+}
+
+impl<S: StorageReader, H: Digest> Default for Keyring<S, H> {
+    fn default() -> Self {
+        Keyring::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::kem::{BigKey, BigKeyKem, Keyring};
+    use crate::storage::tempfile::{tempfile, TempFile};
+    use crate::storage::{DiskStorage, StorageReader};
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K};
+
+    fn key_file(fill: u8) -> TempFile {
+        let tmp = tempfile();
+        let mut ofile = File::create(tmp.as_path()).unwrap();
+        for i in 0..32u8 {
+            ofile.write_all(&[i ^ fill; 1024]).unwrap();
+        }
+        tmp
+    }
+
+    #[test]
+    fn locators_route_to_their_big_key() {
+        let (tmp1, tmp2) = (key_file(0x00), key_file(0xff));
+        let storage1 = DiskStorage::open(BLOCK_1K, tmp1.to_str()).unwrap();
+        let storage2 = DiskStorage::open(BLOCK_1K, tmp2.to_str()).unwrap();
+
+        let mut keyring = Keyring::new();
+        keyring
+            .add(
+                BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage1, Sha3_256::new())
+                    .with_key_id(1),
+            )
+            .unwrap();
+        keyring
+            .add(
+                BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage2, Sha3_256::new())
+                    .with_key_id(2),
+            )
+            .unwrap();
+        assert_eq!(keyring.current(), Some(1));
+
+        let (old_locator, old_key) = keyring.new_key(SecurityLevel::Bits128).unwrap();
+
+        keyring.set_current(2).unwrap();
+        let (new_locator, new_key) = keyring.new_key(SecurityLevel::Bits128).unwrap();
+
+        assert_eq!(keyring.get_key(&old_locator).unwrap(), old_key);
+        assert_eq!(keyring.get_key(&new_locator).unwrap(), new_key);
+
+        keyring.remove(1);
+        match keyring.get_key(&old_locator) {
+            Err(BigKeyError::UnknownKeyId { key_id: 1 }) => {}
+            _ => panic!("expected removed key id to be unknown"),
+        }
+    }
+
+    #[test]
+    fn duplicate_key_ids_are_rejected() {
+        let tmp = key_file(0x00);
+        let storage1 = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let storage2 = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        assert_eq!(storage2.big_key_length(), 32 * 1024);
+
+        let mut keyring = Keyring::new();
+        keyring
+            .add(BigKey::new_big_key(
+                SecurityLevel::Bits128,
+                0.2,
+                storage1,
+                Sha3_256::new(),
+            ))
+            .unwrap();
+
+        match keyring.add(BigKey::new_big_key(
+            SecurityLevel::Bits128,
+            0.2,
+            storage2,
+            Sha3_256::new(),
+        )) {
+            Err(BigKeyError::DuplicateKeyId { key_id: 0 }) => {}
+            _ => panic!("expected duplicate key id to be rejected"),
+        }
+    }
+} // mod test
@@ -0,0 +1,630 @@
+//! Serialized form of a `Locator`.
+//!
+//! The first byte of every locator is its format version. Compatibility policy:
+//!
+//! * `get_key()` accepts locators of every version up to `LOCATOR_VERSION`, ciphertexts
+//!   stored with old locators must remain decryptable;
+//! * locators newer than `LOCATOR_VERSION` fail with `UnsupportedLocatorVersion` rather than
+//!   being guessed at, and current version locators setting flags this version does not know
+//!   fail with `UnsupportedLocatorFlags`;
+//! * `new_key()` only ever emits `LOCATOR_VERSION` locators, `upgrade_locator()` re-encodes
+//!   older locators as the current version (deriving the same key). A MAC tag covers the
+//!   encoding it was made for, so `BigKey::upgrade_locator()` upgrades tagged locators,
+//!   verifying the old tag and re-tagging the new encoding.
+//!
+//! Version 1 layout, all integers big-endian:
+//!
+//! | offset | length | field                                   |
+//! |--------|--------|-----------------------------------------|
+//! | 0      | 1      | format version (1)                      |
+//! | 1      | 4      | key id of the BigKey within a `Keyring` |
+//! | 5      | 2      | security level in bits                  |
+//! | 7      | 4      | number of probes                        |
+//! | 11     | 32     | random selector                         |
+//!
+//! Version 2 inserts a flags byte after the version and may append a MAC tag:
+//!
+//! | offset | length | field                                   |
+//! |--------|--------|-----------------------------------------|
+//! | 0      | 1      | format version (2)                      |
+//! | 1      | 1      | flags, `0x01` = MAC tag present         |
+//! | 2      | 42     | fields of version 1 at offsets 1..43    |
+//! | 44     | 16     | MAC tag over bytes 0..44 (if flagged)   |
+//!
+//! Version 3 records the probe distribution (see `kem::distribution`) and optional Argon2id
+//! hardening costs (memory KiB and passes, see `kem::hardening`) between the version 1 fields and
+//! the MAC tag; version 1 and 2 locators always used the uniform distribution, no hardening:
+//!
+//! | offset | length | field                                   |
+//! |--------|--------|-----------------------------------------|
+//! | 0      | 1      | format version (3)                      |
+//! | 1      | 1      | flags, `0x01` = MAC tag present,        |
+//! |        |        | `0x02` = hardening costs present,       |
+//! |        |        | `0x04` = key bound to a peer identity,  |
+//! |        |        | `0x08` = probe check value present,     |
+//! |        |        | `0x10` = application id present,        |
+//! |        |        | `0x20` = hash algorithm id present,     |
+//! |        |        | `0x40` = derivation parameters present  |
+//! | 2      | 42     | fields of version 1 at offsets 1..43    |
+//! | 44     | 1      | probe distribution id                   |
+//! | 45     | 1      | length `n` of distribution parameters   |
+//! | 46     | n      | distribution parameters                 |
+//! | 46 + n | 8      | hardening costs (if flagged)            |
+//! | next   | 4      | probe check value (if flagged)          |
+//! | next   | 16     | application id (if flagged)             |
+//! | next   | 1      | hash algorithm id (if flagged)          |
+//! | next   | 8      | leakage tolerance (f32 bits) and block  |
+//! |        |        | length in bytes (if flagged)            |
+//! | end    | 16     | MAC tag over prior bytes (if flagged)   |
+//!
+//! New locators record the id of the hash they were derived with (see `HashAlgorithm::id()`),
+//! so a `KemSession` re-derives them with that hash whichever it uses for new keys. Locators
+//! without one predate hash ids and were derived with SHA3-256 (`LEGACY_HASH`), so a BigKey or
+//! session using another hash refuses or re-routes them rather than deriving a wrong key.
+//!
+//! New locators also record the leakage tolerance and block size they were derived with (see
+//! `DerivationParams`), so a BigKey configured differently refuses them rather than
+//! re-deriving with parameters its holder did not intend. Locators without them are checked
+//! against the probe count alone, as they always were.
+//!
+//! Locators never list probe indices, they are expanded from the selector, so a locator does not
+//! grow with its probe count.
+
+use std::convert::TryInto;
+
+use crate::kem::distribution::DistributionDescriptor;
+use crate::kem::hardening::{Hardening, HARDENING_LEN};
+use crate::kem::namespace::{AppId, APP_ID_LEN};
+use crate::kem::params::{DerivationParams, PARAMS_LEN};
+use crate::traits::{BigKeyError, HashAlgorithm, Locator, SecurityLevel};
+
+pub(crate) const LOCATOR_V1: u8 = 1;
+pub(crate) const LOCATOR_V2: u8 = 2;
+pub(crate) const LOCATOR_V3: u8 = 3;
+pub(crate) const SELECTOR_LEN: usize = 32;
+pub(crate) const TAG_LEN: usize = 16;
+pub(crate) const PROBE_CHECK_LEN: usize = 4;
+
+/// Locator format version produced by `new_key()`
+pub const LOCATOR_VERSION: u8 = LOCATOR_V3;
+
+/// Hash of locators recording none, derived before locators recorded their hash
+pub(crate) const LEGACY_HASH: HashAlgorithm = HashAlgorithm::Sha3_256;
+
+const LOCATOR_V1_LEN: usize = 11 + SELECTOR_LEN;
+const LOCATOR_V2_LEN: usize = 12 + SELECTOR_LEN;
+const LOCATOR_V3_MIN_LEN: usize = LOCATOR_V2_LEN + 2;
+
+const FLAG_MAC: u8 = 0x01;
+const FLAG_HARDENING: u8 = 0x02;
+const FLAG_PEER: u8 = 0x04;
+const FLAG_PROBE_CHECK: u8 = 0x08;
+const FLAG_APP_ID: u8 = 0x10;
+const FLAG_HASH: u8 = 0x20;
+const FLAG_PARAMS: u8 = 0x40;
+const FLAGS_V3: u8 = FLAG_MAC
+    | FLAG_HARDENING
+    | FLAG_PEER
+    | FLAG_PROBE_CHECK
+    | FLAG_APP_ID
+    | FLAG_HASH
+    | FLAG_PARAMS;
+
+/// Decoded contents of a `Locator`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LocatorBody {
+    /// Format version the locator was decoded from, `LOCATOR_VERSION` for new ones. `encode()`
+    /// always produces `LOCATOR_VERSION`; the version only decides what a MAC tag covers.
+    pub version: u8,
+    pub key_id: u32,
+    pub security_level: SecurityLevel,
+    pub probe_count: u32,
+    pub selector: [u8; SELECTOR_LEN],
+    pub distribution: DistributionDescriptor,
+    pub hardening: Option<Hardening>,
+    /// Key derivation mixes in a peer identity supplied by the caller
+    pub peer_bound: bool,
+    /// Digest of the probed indices and blocks, in order, to detect storage returning the wrong
+    /// blocks
+    pub probe_check: Option<[u8; PROBE_CHECK_LEN]>,
+    /// Application namespace mixed into probe selection and key derivation
+    pub app_id: Option<AppId>,
+    /// Hash the key is derived with, `None` for `LEGACY_HASH`
+    pub hash: Option<HashAlgorithm>,
+    /// Parameters the key was derived with, consistent with `security_level` and `probe_count`
+    pub params: Option<DerivationParams>,
+    pub tag: Option<[u8; TAG_LEN]>,
+}
+
+impl LocatorBody {
+    /// Encode as a `LOCATOR_VERSION` locator. Distribution parameters must be at most
+    /// `MAX_PARAMS_LEN` bytes.
+    pub fn encode(&self) -> Locator {
+        let mut out = self.authenticated_bytes(self.tag.is_some());
+        if let Some(tag) = &self.tag {
+            out.extend_from_slice(tag);
+        }
+        out.into()
+    }
+
+    /// Bytes covered by the MAC tag: the encoding up to (excluding) the tag itself, in the
+    /// version of the locator the tag came with. Version 2 tags cover bytes 0..44 of the version
+    /// 2 encoding.
+    pub fn mac_input(&self) -> Vec<u8> {
+        match self.version {
+            LOCATOR_V2 => {
+                let mut out = Vec::with_capacity(LOCATOR_V2_LEN);
+                out.push(LOCATOR_V2);
+                out.push(FLAG_MAC);
+                self.push_fields(&mut out);
+                out
+            }
+            _ => self.authenticated_bytes(true),
+        }
+    }
+
+    fn authenticated_bytes(&self, with_mac: bool) -> Vec<u8> {
+        let params = &self.distribution.params;
+        let mut out = Vec::with_capacity(
+            LOCATOR_V3_MIN_LEN
+                + params.len()
+                + HARDENING_LEN
+                + PROBE_CHECK_LEN
+                + APP_ID_LEN
+                + 1
+                + PARAMS_LEN
+                + TAG_LEN,
+        );
+        let mut flags = 0;
+        if with_mac {
+            flags |= FLAG_MAC;
+        }
+        if self.hardening.is_some() {
+            flags |= FLAG_HARDENING;
+        }
+        if self.peer_bound {
+            flags |= FLAG_PEER;
+        }
+        if self.probe_check.is_some() {
+            flags |= FLAG_PROBE_CHECK;
+        }
+        if self.app_id.is_some() {
+            flags |= FLAG_APP_ID;
+        }
+        if self.hash.is_some() {
+            flags |= FLAG_HASH;
+        }
+        if self.params.is_some() {
+            flags |= FLAG_PARAMS;
+        }
+
+        out.push(LOCATOR_V3);
+        out.push(flags);
+        self.push_fields(&mut out);
+        out.push(self.distribution.id);
+        out.push(params.len() as u8);
+        out.extend_from_slice(params);
+        if let Some(hardening) = self.hardening {
+            out.extend_from_slice(&hardening.to_bytes());
+        }
+        if let Some(check) = &self.probe_check {
+            out.extend_from_slice(check);
+        }
+        if let Some(app_id) = &self.app_id {
+            out.extend_from_slice(app_id.as_bytes());
+        }
+        if let Some(hash) = self.hash {
+            out.push(hash.id());
+        }
+        if let Some(params) = self.params {
+            out.extend_from_slice(&params.to_bytes());
+        }
+        out
+    }
+
+    // append the fields `decode_fields()` reads
+    fn push_fields(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.key_id.to_be_bytes());
+        out.extend_from_slice(&(self.security_level as u16).to_be_bytes());
+        out.extend_from_slice(&self.probe_count.to_be_bytes());
+        out.extend_from_slice(&self.selector);
+    }
+
+    pub fn decode(locator: &[u8]) -> Result<LocatorBody, BigKeyError> {
+        match locator_version(locator)? {
+            LOCATOR_V1 => LocatorBody::decode_v1(locator),
+            LOCATOR_V2 => LocatorBody::decode_v2(locator),
+            LOCATOR_V3 => LocatorBody::decode_v3(locator),
+            _ => Err(invalid("unknown locator version")),
+        }
+    }
+
+    fn decode_v1(locator: &[u8]) -> Result<LocatorBody, BigKeyError> {
+        if locator.len() != LOCATOR_V1_LEN {
+            return Err(invalid("wrong locator length"));
+        }
+        LocatorBody::decode_fields(LOCATOR_V1, &locator[1..LOCATOR_V1_LEN], None)
+    }
+
+    fn decode_v2(locator: &[u8]) -> Result<LocatorBody, BigKeyError> {
+        let tag = match (locator.get(1), locator.len()) {
+            (Some(&0), LOCATOR_V2_LEN) => None,
+            (Some(&FLAG_MAC), len) if len == LOCATOR_V2_LEN + TAG_LEN => {
+                Some(locator[LOCATOR_V2_LEN..].try_into().unwrap())
+            }
+            _ => return Err(invalid("wrong locator length or flags")),
+        };
+        LocatorBody::decode_fields(LOCATOR_V2, &locator[2..LOCATOR_V2_LEN], tag)
+    }
+
+    fn decode_v3(locator: &[u8]) -> Result<LocatorBody, BigKeyError> {
+        if locator.len() < LOCATOR_V3_MIN_LEN {
+            return Err(invalid("wrong locator length or flags"));
+        }
+        let flags = locator[1];
+        // flags added later extend the format, they are not corruption
+        if flags & !FLAGS_V3 != 0 {
+            return Err(BigKeyError::UnsupportedLocatorFlags {
+                flags,
+                supported: FLAGS_V3,
+            });
+        }
+        let params_end = LOCATOR_V3_MIN_LEN + locator[LOCATOR_V3_MIN_LEN - 1] as usize;
+        let hardening_end = match flags & FLAG_HARDENING {
+            0 => params_end,
+            _ => params_end + HARDENING_LEN,
+        };
+        let check_end = match flags & FLAG_PROBE_CHECK {
+            0 => hardening_end,
+            _ => hardening_end + PROBE_CHECK_LEN,
+        };
+        let app_id_end = match flags & FLAG_APP_ID {
+            0 => check_end,
+            _ => check_end + APP_ID_LEN,
+        };
+        let hash_end = match flags & FLAG_HASH {
+            0 => app_id_end,
+            _ => app_id_end + 1,
+        };
+        let derivation_end = match flags & FLAG_PARAMS {
+            0 => hash_end,
+            _ => hash_end + PARAMS_LEN,
+        };
+        let tag_len = match flags & FLAG_MAC {
+            0 => 0,
+            _ => TAG_LEN,
+        };
+        if locator.len() != derivation_end + tag_len {
+            return Err(invalid("wrong locator length or flags"));
+        }
+
+        let hardening = match flags & FLAG_HARDENING {
+            0 => None,
+            _ => Some(Hardening::from_bytes(&locator[params_end..hardening_end])),
+        };
+        let probe_check = match flags & FLAG_PROBE_CHECK {
+            0 => None,
+            _ => Some(locator[hardening_end..check_end].try_into().unwrap()),
+        };
+        let app_id = match flags & FLAG_APP_ID {
+            0 => None,
+            _ => Some(AppId::from_bytes(
+                locator[check_end..app_id_end].try_into().unwrap(),
+            )),
+        };
+        let hash = match flags & FLAG_HASH {
+            0 => None,
+            _ => {
+                let id = locator[app_id_end];
+                Some(
+                    HashAlgorithm::from_id(id)
+                        .ok_or(BigKeyError::UnsupportedHashAlgorithm { id })?,
+                )
+            }
+        };
+        let tag = match tag_len {
+            0 => None,
+            _ => Some(locator[derivation_end..].try_into().unwrap()),
+        };
+
+        let fields = LocatorBody::decode_fields(LOCATOR_V3, &locator[2..LOCATOR_V2_LEN], tag)?;
+        let params = match flags & FLAG_PARAMS {
+            0 => None,
+            _ => Some(DerivationParams::from_bytes(
+                fields.security_level,
+                fields.probe_count,
+                &locator[hash_end..derivation_end],
+            )?),
+        };
+
+        Ok(LocatorBody {
+            distribution: DistributionDescriptor {
+                id: locator[LOCATOR_V2_LEN],
+                params: locator[LOCATOR_V3_MIN_LEN..params_end].to_vec(),
+            },
+            hardening,
+            peer_bound: flags & FLAG_PEER != 0,
+            probe_check,
+            app_id,
+            hash,
+            params,
+            ..fields
+        })
+    }
+
+    // key id, security level, probe count and selector as laid out in version 1
+    fn decode_fields(
+        version: u8,
+        fields: &[u8],
+        tag: Option<[u8; TAG_LEN]>,
+    ) -> Result<LocatorBody, BigKeyError> {
+        let bits = u16::from_be_bytes(fields[4..6].try_into().unwrap());
+
+        Ok(LocatorBody {
+            version,
+            key_id: u32::from_be_bytes(fields[0..4].try_into().unwrap()),
+            security_level: SecurityLevel::from_bits(bits as u32)
+                .ok_or_else(|| invalid("unknown security level"))?,
+            probe_count: u32::from_be_bytes(fields[6..10].try_into().unwrap()),
+            selector: fields[10..10 + SELECTOR_LEN].try_into().unwrap(),
+            distribution: DistributionDescriptor::uniform(),
+            hardening: None,
+            peer_bound: false,
+            probe_check: None,
+            app_id: None,
+            hash: None,
+            params: None,
+            tag,
+        })
+    }
+}
+
+/// Number of blocks re-deriving the key of `locator` probes, without touching the BigKey.
+pub fn locator_probe_count(locator: &[u8]) -> Result<u32, BigKeyError> {
+    Ok(LocatorBody::decode(locator)?.probe_count)
+}
+
+/// Hash algorithm `locator` was derived with, if it records one.
+pub fn locator_hash_algorithm(locator: &[u8]) -> Result<Option<HashAlgorithm>, BigKeyError> {
+    Ok(LocatorBody::decode(locator)?.hash)
+}
+
+/// Derivation parameters `locator` records, if any (see `DerivationParams`).
+pub fn locator_params(locator: &[u8]) -> Result<Option<DerivationParams>, BigKeyError> {
+    Ok(LocatorBody::decode(locator)?.params)
+}
+
+/// Format version of `locator`, failing for versions newer than this library understands.
+pub fn locator_version(locator: &[u8]) -> Result<u8, BigKeyError> {
+    match locator.first() {
+        None | Some(0) => Err(invalid("missing locator version")),
+        Some(&version) if version > LOCATOR_VERSION => {
+            Err(BigKeyError::UnsupportedLocatorVersion {
+                version,
+                max_supported: LOCATOR_VERSION,
+            })
+        }
+        Some(&version) => Ok(version),
+    }
+}
+
+/// Re-encode a locator of any supported version as a `LOCATOR_VERSION` locator for the same key.
+/// An older tagged locator must be re-tagged, use `BigKey::upgrade_locator()` for those.
+pub fn upgrade_locator(locator: &[u8]) -> Result<Locator, BigKeyError> {
+    let body = LocatorBody::decode(locator)?;
+    if body.tag.is_some() && body.version != LOCATOR_VERSION {
+        return Err(invalid("tagged locator must be upgraded by its BigKey"));
+    }
+    Ok(LocatorBody {
+        version: LOCATOR_VERSION,
+        ..body
+    }
+    .encode())
+}
+
+fn invalid(reason: &'static str) -> BigKeyError {
+    BigKeyError::InvalidLocator { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kem::distribution::{DistributionDescriptor, EXCLUDE_ENDS_ID};
+    use crate::kem::hardening::Hardening;
+    use crate::kem::locator::{
+        locator_hash_algorithm, locator_version, upgrade_locator, LocatorBody, LOCATOR_V1,
+        LOCATOR_V2, LOCATOR_VERSION,
+    };
+    use crate::kem::namespace::AppId;
+    use crate::traits::{BigKeyError, HashAlgorithm, SecurityLevel};
+
+    #[test]
+    fn locator_round_trips() {
+        let body = LocatorBody {
+            version: LOCATOR_VERSION,
+            key_id: 7,
+            security_level: SecurityLevel::Bits256,
+            probe_count: 113,
+            selector: [0x3c; 32],
+            distribution: DistributionDescriptor::uniform(),
+            hardening: None,
+            peer_bound: false,
+            probe_check: None,
+            app_id: None,
+            hash: None,
+            params: None,
+            tag: None,
+        };
+
+        let locator = body.encode();
+        assert_eq!(locator.len(), 46);
+        assert_eq!(LocatorBody::decode(locator.as_bytes()).unwrap(), body);
+
+        let tagged = LocatorBody {
+            distribution: DistributionDescriptor {
+                id: EXCLUDE_ENDS_ID,
+                params: vec![0x07; 16],
+            },
+            hardening: Some(Hardening {
+                memory_kib: 64,
+                passes: 2,
+            }),
+            peer_bound: true,
+            probe_check: Some([0x5a; 4]),
+            app_id: Some(AppId::from_bytes([0x42; 16])),
+            hash: Some(HashAlgorithm::Sha3_512),
+            tag: Some([0x99; 16]),
+            ..body
+        };
+        let locator = tagged.encode();
+        assert_eq!(locator.len(), 107);
+        assert_eq!(&locator.as_bytes()[..91], &tagged.mac_input()[..]);
+        assert_eq!(&locator.as_bytes()[74..90], &[0x42; 16]);
+        assert_eq!(locator.as_bytes()[90], HashAlgorithm::Sha3_512.id());
+        assert_eq!(
+            locator_hash_algorithm(locator.as_bytes()).unwrap(),
+            Some(HashAlgorithm::Sha3_512)
+        );
+        assert_eq!(LocatorBody::decode(locator.as_bytes()).unwrap(), tagged);
+    }
+
+    #[test]
+    fn malformed_locators_fail() {
+        let mut locator = LocatorBody {
+            version: LOCATOR_VERSION,
+            key_id: 0,
+            security_level: SecurityLevel::Bits128,
+            probe_count: 1,
+            selector: [0; 32],
+            distribution: DistributionDescriptor::uniform(),
+            hardening: None,
+            peer_bound: false,
+            probe_check: None,
+            app_id: None,
+            hash: None,
+            params: None,
+            tag: None,
+        }
+        .encode()
+        .to_bytes();
+
+        let mut bad_flags = locator.clone();
+        bad_flags[1] = 0x01;
+
+        let mut bad_params_len = locator.clone();
+        bad_params_len[45] = 3;
+        let mut missing_hardening = locator.clone();
+        missing_hardening[1] = 0x02;
+        let mut missing_app_id = locator.clone();
+        missing_app_id[1] = 0x10;
+        let mut missing_hash = locator.clone();
+        missing_hash[1] = 0x20;
+        let mut missing_params = locator.clone();
+        missing_params[1] = 0x40;
+
+        for bad in [
+            vec![],
+            vec![0u8; 43],
+            locator[..20].to_vec(),
+            bad_flags,
+            bad_params_len,
+            missing_hardening,
+            missing_app_id,
+            missing_hash,
+            missing_params,
+        ]
+        .iter()
+        {
+            match LocatorBody::decode(bad) {
+                Err(BigKeyError::InvalidLocator { .. }) => {}
+                _ => panic!("expected {:?} to be rejected", bad),
+            }
+        }
+
+        locator[7] = 64;
+        match LocatorBody::decode(&locator) {
+            Err(BigKeyError::InvalidLocator { .. }) => {}
+            _ => panic!("expected unknown security level to be rejected"),
+        }
+    }
+
+    #[test]
+    fn future_locator_versions_are_unsupported() {
+        let mut locator = vec![LOCATOR_VERSION + 1];
+        locator.extend_from_slice(&[0u8; 42]);
+
+        match LocatorBody::decode(&locator) {
+            Err(BigKeyError::UnsupportedLocatorVersion {
+                version,
+                max_supported,
+            }) => {
+                assert_eq!(version, LOCATOR_VERSION + 1);
+                assert_eq!(max_supported, LOCATOR_VERSION);
+            }
+            _ => panic!("expected future locator version to be rejected"),
+        }
+
+        // a flag defined after this version, on an otherwise well formed locator
+        let mut locator = LocatorBody {
+            version: LOCATOR_VERSION,
+            key_id: 0,
+            security_level: SecurityLevel::Bits128,
+            probe_count: 1,
+            selector: [0; 32],
+            distribution: DistributionDescriptor::uniform(),
+            hardening: None,
+            peer_bound: false,
+            probe_check: None,
+            app_id: None,
+            hash: None,
+            params: None,
+            tag: None,
+        }
+        .encode()
+        .to_bytes();
+        locator[1] |= 0x80;
+        match LocatorBody::decode(&locator) {
+            Err(BigKeyError::UnsupportedLocatorFlags { flags, supported }) => {
+                assert_eq!(flags, 0x80);
+                assert_eq!(supported, 0x7f);
+            }
+            _ => panic!("expected an unknown locator flag to be unsupported"),
+        }
+        locator.truncate(20);
+        match LocatorBody::decode(&locator) {
+            Err(BigKeyError::InvalidLocator { .. }) => {}
+            _ => panic!("expected a truncated locator to be invalid"),
+        }
+    }
+
+    #[test]
+    fn upgrade_produces_current_version() {
+        // version 1: key id 3, 128 bits, 9 probes
+        let mut locator = vec![LOCATOR_V1, 0, 0, 0, 3, 0, 128, 0, 0, 0, 9];
+        locator.extend_from_slice(&[0x11; 32]);
+
+        let upgraded = upgrade_locator(&locator).unwrap();
+        assert_eq!(
+            locator_version(upgraded.as_bytes()).unwrap(),
+            LOCATOR_VERSION
+        );
+        assert_eq!(
+            LocatorBody::decode(upgraded.as_bytes()).unwrap(),
+            LocatorBody {
+                version: LOCATOR_VERSION,
+                ..LocatorBody::decode(&locator).unwrap()
+            }
+        );
+
+        // version 2 with a MAC tag, which would not cover the upgraded encoding
+        let mut tagged = vec![LOCATOR_V2, 0x01];
+        tagged.extend_from_slice(&locator[1..]);
+        tagged.extend_from_slice(&[0x22; 16]);
+        let body = LocatorBody::decode(&tagged).unwrap();
+        assert_eq!(body.version, LOCATOR_V2);
+        assert_eq!(body.mac_input(), &tagged[..44]);
+        match upgrade_locator(&tagged) {
+            Err(BigKeyError::InvalidLocator { .. }) => {}
+            _ => panic!("expected a tagged version 2 locator to need its BigKey"),
+        }
+    }
+} // mod test
@@ -1,3 +1,61 @@
+pub use crate::traits::HashAlgorithm;
+pub use agreement::{AgreedSelector, LocatorAgreement, SelectorShare, ShareCommitment};
+pub use armor::{armor_locator, dearmor_locator, LOCATOR_HRP};
 pub use bigkey::{BigKey, BigKeyKem};
+#[cfg(feature = "key-cache")]
+pub use cache::{KeyCache, DEFAULT_CACHE_HARDENING};
+pub use distribution::{
+    DistributionDescriptor, ExcludeEnds, ExcludeRanges, ProbeDistribution, Uniform,
+};
+pub use export::{export_key, import_key, jwk_key_id, KeyFormat};
+pub use hardening::Hardening;
+pub use keyring::Keyring;
+pub use locator::{
+    locator_hash_algorithm, locator_params, locator_probe_count, locator_version, upgrade_locator,
+    LOCATOR_VERSION,
+};
+pub use namespace::{locator_app_id, AppId, APP_ID_LEN};
+pub use params::DerivationParams;
+pub use randomness::{OsRandomness, ProbeRandomness, SeededRandomness};
+pub use reload::ReloadableBigKey;
+pub use retirement::RetirementPolicy;
+pub use session::{KemSession, SessionParams};
+pub use store::{GcReport, LabeledLocator, LocatorStore, MAX_LABEL_LEN};
+pub use trace::{CoverageHeatmap, ProbeTrace, TraceFormat};
+pub use transcript::{ProbeRecord, Transcript};
+pub use vectors::{generate_test_vectors, TestVector};
+#[cfg(feature = "key-wrap")]
+pub use wrap::{unwrap_key, wrap_key, KEK_LEN};
 
+#[cfg(feature = "age-plugin")]
+pub(crate) use armor::{bech32_decode, bech32_encode};
+#[cfg(feature = "lease")]
+pub(crate) use armor::{bech32m_decode, bech32m_encode};
+#[cfg(all(unix, feature = "agent"))]
+pub(crate) use bigkey::check_probe_count;
+pub(crate) use bigkey::probe_count;
+pub(crate) use distribution::builtin;
+pub(crate) use locator::LocatorBody;
+
+mod agreement;
+mod armor;
 mod bigkey;
+#[cfg(feature = "key-cache")]
+mod cache;
+mod distribution;
+mod export;
+mod hardening;
+mod keyring;
+mod locator;
+mod namespace;
+mod params;
+mod randomness;
+mod reload;
+mod retirement;
+mod session;
+mod store;
+mod trace;
+mod transcript;
+mod vectors;
+#[cfg(feature = "key-wrap")]
+mod wrap;
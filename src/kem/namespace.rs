@@ -0,0 +1,48 @@
+//! Application namespaces partitioning the keys of a shared BigKey.
+//!
+//! Applications sharing one key file can each configure their `BigKey` with an `AppId`
+//! (`BigKey::with_app_id()`). The id is mixed into the probe index and key derivation and
+//! recorded in the locator, so:
+//!
+//! * locators of different applications never collide, even for the same selector;
+//! * a locator only re-derives its key in the application that created it; `get_key()` in any
+//!   other namespace (or in none) fails with `InvalidLocator` instead of handing out the key,
+//!   and locators without an id are refused inside a namespace;
+//! * the id is covered by the locator MAC, so with `with_locator_mac()` it cannot be altered.
+//!
+//! Namespaces separate cooperating applications and catch mix-ups. They are not access
+//! control: whoever can read the key file can configure any `AppId`.
+
+use crate::kem::locator::LocatorBody;
+use crate::traits::BigKeyError;
+
+/// Length of an encoded `AppId`
+pub const APP_ID_LEN: usize = 16;
+
+const APP_ID_CONTEXT: &str = "big_fluffy_dise 2024 application namespace v1";
+
+/// Identifier of an application namespace
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct AppId([u8; APP_ID_LEN]);
+
+impl AppId {
+    /// The id of the application called `name`, e.g. `"com.example.billing"`
+    pub fn new(name: &str) -> Self {
+        let mut id = [0u8; APP_ID_LEN];
+        blake3::derive_key(APP_ID_CONTEXT, name.as_bytes(), &mut id);
+        AppId(id)
+    }
+
+    pub fn from_bytes(bytes: [u8; APP_ID_LEN]) -> Self {
+        AppId(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; APP_ID_LEN] {
+        &self.0
+    }
+}
+
+/// The application namespace `locator` belongs to, if any
+pub fn locator_app_id(locator: &[u8]) -> Result<Option<AppId>, BigKeyError> {
+    Ok(LocatorBody::decode(locator)?.app_id)
+}
@@ -0,0 +1,188 @@
+//! Derivation parameters that encryptor and decryptor must agree on.
+//!
+//! The number of probes a derivation needs follows from its security level, the leakage
+//! tolerance of the BigKey and its block size (see `probe_count()`). Two parties configured with
+//! different leakage tolerances or block sizes probe differently, and without a locator to
+//! carry the probe count (`derive_for_id()`, agreed selectors) silently derive different keys.
+//! `DerivationParams` can only be built from a combination that was checked, and new locators
+//! record the leakage tolerance and block size so `get_key()` refuses a locator created with
+//! parameters other than its own with `DerivationParamsMismatch`.
+
+use std::convert::{TryFrom, TryInto};
+
+use crate::kem::bigkey::probe_count;
+use crate::traits::{BigKeyError, BlockSize, SecurityLevel};
+
+/// Encoded length of `DerivationParams` in a locator: leakage tolerance and block length
+pub(crate) const PARAMS_LEN: usize = 8;
+
+/// A validated combination of security level, leakage tolerance, block size and probe count
+#[derive(Debug, Copy, Clone)]
+pub struct DerivationParams {
+    security_level: SecurityLevel,
+    leakage_tolerance: f32,
+    block_size: BlockSize,
+    probe_count: u32,
+}
+
+impl DerivationParams {
+    /// Parameters of derivations at `security_level` from a BigKey of `block_size` blocks
+    /// tolerating leakage of a `leakage_tolerance` fraction, probing as often as that requires.
+    /// Fails with `LeakageToleranceOutOfRange` for tolerances outside `[0.0, 1.0)`.
+    pub fn new(
+        security_level: SecurityLevel,
+        leakage_tolerance: f32,
+        block_size: BlockSize,
+    ) -> Result<DerivationParams, BigKeyError> {
+        let probes = probe_count(security_level, leakage_tolerance, block_size)?;
+        let probe_count = u32::try_from(probes).map_err(|_| BigKeyError::InvalidConfig {
+            reason: "too many probes for a locator".to_string(),
+        })?;
+
+        Ok(DerivationParams {
+            security_level,
+            leakage_tolerance,
+            block_size,
+            probe_count,
+        })
+    }
+
+    /// The same parameters probing `probe_count` times, which must be at least as many probes
+    /// as they require
+    pub fn with_probe_count(self, probe_count: u32) -> Result<DerivationParams, BigKeyError> {
+        let required =
+            DerivationParams::new(self.security_level, self.leakage_tolerance, self.block_size)?;
+        if probe_count < required.probe_count {
+            return Err(BigKeyError::InvalidConfig {
+                reason: format!(
+                    "{} probes are fewer than the {} required",
+                    probe_count, required.probe_count
+                ),
+            });
+        }
+        Ok(DerivationParams {
+            probe_count,
+            ..self
+        })
+    }
+
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level
+    }
+
+    pub fn leakage_tolerance(&self) -> f32 {
+        self.leakage_tolerance
+    }
+
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    pub fn probe_count(&self) -> u32 {
+        self.probe_count
+    }
+
+    /// Fail with `DerivationParamsMismatch` unless keys derived with `other` can be re-derived
+    /// with `self`: same leakage tolerance and block size, and at least as many probes as `self`
+    /// requires at the security level of `other`
+    pub fn check_compatible(&self, other: &DerivationParams) -> Result<(), BigKeyError> {
+        if self.leakage_tolerance.to_bits() != other.leakage_tolerance.to_bits() {
+            return Err(mismatch("leakage tolerance differs"));
+        }
+        if self.block_size.byte_len != other.block_size.byte_len {
+            return Err(mismatch("block size differs"));
+        }
+        let required = DerivationParams::new(
+            other.security_level,
+            self.leakage_tolerance,
+            self.block_size,
+        )?;
+        if other.probe_count < required.probe_count {
+            return Err(mismatch("too few probes for security level"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; PARAMS_LEN] {
+        let mut out = [0u8; PARAMS_LEN];
+        out[0..4].copy_from_slice(&self.leakage_tolerance.to_bits().to_be_bytes());
+        out[4..8].copy_from_slice(&(self.block_size.byte_len as u32).to_be_bytes());
+        out
+    }
+
+    /// Parameters recorded in a locator as `bytes`, alongside its security level and probe
+    /// count. Fails with `InvalidLocator` for combinations `new()` would not produce.
+    pub(crate) fn from_bytes(
+        security_level: SecurityLevel,
+        probe_count: u32,
+        bytes: &[u8],
+    ) -> Result<DerivationParams, BigKeyError> {
+        let invalid = |reason| BigKeyError::InvalidLocator { reason };
+        let leakage_bits = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+        let leakage_tolerance = f32::from_bits(leakage_bits);
+        let block_len = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        let block_size = BlockSize::from_byte_len(block_len as usize)
+            .ok_or_else(|| invalid("unsupported block size"))?;
+
+        DerivationParams::new(security_level, leakage_tolerance, block_size)
+            .map_err(|_| invalid("leakage tolerance out of range"))?
+            .with_probe_count(probe_count)
+            .map_err(|_| invalid("too few probes for recorded parameters"))
+    }
+}
+
+impl PartialEq for DerivationParams {
+    fn eq(&self, other: &DerivationParams) -> bool {
+        self.security_level == other.security_level
+            && self.leakage_tolerance.to_bits() == other.leakage_tolerance.to_bits()
+            && self.block_size.byte_len == other.block_size.byte_len
+            && self.probe_count == other.probe_count
+    }
+}
+
+impl Eq for DerivationParams {}
+
+fn mismatch(reason: &'static str) -> BigKeyError {
+    BigKeyError::DerivationParamsMismatch { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kem::params::DerivationParams;
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K, BLOCK_4K};
+
+    #[test]
+    fn only_validated_combinations_are_constructible() {
+        let params = DerivationParams::new(SecurityLevel::Bits128, 0.2, BLOCK_1K).unwrap();
+        assert!(params.probe_count() > 0);
+        assert!(params.with_probe_count(params.probe_count() - 1).is_err());
+        assert!(DerivationParams::new(SecurityLevel::Bits128, 1.0, BLOCK_1K).is_err());
+
+        let bytes = params.to_bytes();
+        let decoded =
+            DerivationParams::from_bytes(SecurityLevel::Bits128, params.probe_count(), &bytes)
+                .unwrap();
+        assert_eq!(decoded, params);
+        assert!(matches!(
+            DerivationParams::from_bytes(SecurityLevel::Bits256, params.probe_count(), &bytes),
+            Err(BigKeyError::InvalidLocator { .. })
+        ));
+    }
+
+    #[test]
+    fn mismatched_parameters_are_detected() {
+        let params = DerivationParams::new(SecurityLevel::Bits128, 0.2, BLOCK_1K).unwrap();
+        let more_probes = params.with_probe_count(params.probe_count() + 5).unwrap();
+        params.check_compatible(&more_probes).unwrap();
+
+        for other in [
+            DerivationParams::new(SecurityLevel::Bits128, 0.1, BLOCK_1K).unwrap(),
+            DerivationParams::new(SecurityLevel::Bits128, 0.2, BLOCK_4K).unwrap(),
+        ] {
+            assert!(matches!(
+                params.check_compatible(&other),
+                Err(BigKeyError::DerivationParamsMismatch { .. })
+            ));
+        }
+    }
+} // mod test
@@ -0,0 +1,147 @@
+//! Where the random selectors of new keys come from.
+//!
+//! Every `new_key()` draws a fresh selector, which decides the blocks the derivation probes.
+//! By default it is read from the operating system (`OsRandomness`). Deployments that must use
+//! an approved DRBG implement `ProbeRandomness` over it and pass it to
+//! `BigKey::with_probe_randomness()`. Tests and simulations can use `SeededRandomness` to replay
+//! the same derivations exactly.
+//!
+//! The selectors of decoy derivations run for refused locators are drawn from the same source.
+//! The shares of a two-party selector agreement come from the `ProbeRandomness` passed to
+//! `LocatorAgreement::new_with()`.
+
+use crate::traits::BigKeyError;
+
+const SEEDED_CONTEXT: &str = "big_fluffy_dise 2024 seeded probe randomness v1";
+
+/// Source of the random selectors of new keys
+pub trait ProbeRandomness: Send + Sync {
+    /// Fill `dest` with random bytes
+    fn fill(&mut self, dest: &mut [u8]) -> Result<(), BigKeyError>;
+}
+
+/// The operating system's random number generator, the default
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct OsRandomness;
+
+impl ProbeRandomness for OsRandomness {
+    fn fill(&mut self, dest: &mut [u8]) -> Result<(), BigKeyError> {
+        getrandom::getrandom(dest)?;
+        Ok(())
+    }
+}
+
+/// A deterministic stream expanded from a seed with BLAKE3, for tests and simulations.
+///
+/// Keys derived with it are exactly as predictable as its seed: never use it for real keys.
+pub struct SeededRandomness {
+    stream: blake3::OutputReader,
+}
+
+impl SeededRandomness {
+    pub fn new(seed: &[u8]) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key(SEEDED_CONTEXT);
+        hasher.update(seed);
+        SeededRandomness {
+            stream: hasher.finalize_xof(),
+        }
+    }
+}
+
+impl ProbeRandomness for SeededRandomness {
+    fn fill(&mut self, dest: &mut [u8]) -> Result<(), BigKeyError> {
+        self.stream.fill(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use digest::Digest;
+    use sha3::Sha3_256;
+
+    use crate::kem::{BigKey, BigKeyKem, ProbeRandomness, SeededRandomness};
+    use crate::storage::ReadSeekStorage;
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K};
+
+    // Counts the selectors drawn, standing in for an instrumented DRBG
+    struct Counting(SeededRandomness, Arc<AtomicUsize>);
+
+    impl ProbeRandomness for Counting {
+        fn fill(&mut self, dest: &mut [u8]) -> Result<(), BigKeyError> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.fill(dest)
+        }
+    }
+
+    #[test]
+    fn seeded_randomness_replays_derivations() {
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let big_key = |seed: &[u8]| {
+            let storage = ReadSeekStorage::new(Cursor::new(contents.clone()), BLOCK_1K).unwrap();
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+                .with_probe_randomness(SeededRandomness::new(seed))
+        };
+
+        let (mut a, mut b, mut c) = (big_key(b"run 1"), big_key(b"run 1"), big_key(b"run 2"));
+        for _ in 0..3 {
+            let derived = a.new_key(SecurityLevel::Bits128).unwrap();
+            assert_eq!(b.new_key(SecurityLevel::Bits128).unwrap(), derived);
+            assert_ne!(c.new_key(SecurityLevel::Bits128).unwrap(), derived);
+        }
+
+        let drawn = Arc::new(AtomicUsize::new(0));
+        let storage = ReadSeekStorage::new(Cursor::new(contents.clone()), BLOCK_1K).unwrap();
+        let mut counted =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+                .with_probe_randomness(Counting(SeededRandomness::new(b"run 1"), drawn.clone()));
+        let (locator, key) = counted.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(counted.get_key(&locator).unwrap(), key);
+        assert_eq!(drawn.load(Ordering::SeqCst), 1);
+    }
+
+    // A DRBG that has failed its health tests
+    struct Failing;
+
+    impl ProbeRandomness for Failing {
+        fn fill(&mut self, _dest: &mut [u8]) -> Result<(), BigKeyError> {
+            Err(BigKeyError::InvalidConfig {
+                reason: "DRBG failed".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn failing_randomness_fails_new_keys_only() {
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let storage = ReadSeekStorage::new(Cursor::new(contents.clone()), BLOCK_1K).unwrap();
+        let mut working =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+        let (locator, key) = working.new_key(SecurityLevel::Bits128).unwrap();
+
+        let storage = ReadSeekStorage::new(Cursor::new(contents), BLOCK_1K).unwrap();
+        let mut failing =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+                .with_probe_randomness(Failing);
+        match failing.new_key(SecurityLevel::Bits128) {
+            Err(BigKeyError::InvalidConfig { reason }) => assert_eq!(reason, "DRBG failed"),
+            _ => panic!("expected the DRBG failure to surface"),
+        }
+        assert_eq!(failing.get_key(&locator).unwrap(), key);
+
+        // the seeded stream continues across calls rather than restarting
+        let mut seeded = SeededRandomness::new(b"stream");
+        let (mut first, mut second) = ([0u8; 32], [0u8; 32]);
+        seeded.fill(&mut first).unwrap();
+        seeded.fill(&mut second).unwrap();
+        assert_ne!(first, second);
+        let mut whole = [0u8; 64];
+        SeededRandomness::new(b"stream").fill(&mut whole).unwrap();
+        assert_eq!(whole[..32], first);
+        assert_eq!(whole[32..], second);
+    }
+} // mod test
@@ -0,0 +1,204 @@
+//! Swapping the BigKey under a long-running service without restarting it.
+//!
+//! `ReloadableBigKey` is a handle to a `BigKey` shared between threads. `reload()` atomically
+//! replaces the BigKey, e.g. with one over a rotated or repaired key file. Derivations started
+//! before the swap hold on to the BigKey they started with and complete against it; those
+//! started after it use the new one. The old BigKey, and with it its storage, is dropped when
+//! its last derivation finishes.
+//!
+//! ```no_run
+//! use big_fluffy_dise::kem::ReloadableBigKey;
+//! use big_fluffy_dise::open_big_key;
+//!
+//! let big_key = ReloadableBigKey::new(open_big_key("/srv/big.key")?);
+//! // ... on SIGHUP, after the key file was repaired:
+//! big_key.try_reload(|| open_big_key("/srv/big.key"))?;
+//! # Ok::<(), big_fluffy_dise::traits::BigKeyError>(())
+//! ```
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use digest::Digest;
+
+use crate::kem::{BigKey, BigKeyKem};
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, KeyMaterial, Locator, SecurityLevel};
+
+// A BigKey and the number of reloads before it was loaded
+struct Loaded<S: StorageReader, H: Digest> {
+    big_key: Mutex<BigKey<S, H>>,
+    generation: u64,
+}
+
+/// A `BigKey` that can be replaced while in use
+pub struct ReloadableBigKey<S: StorageReader, H: Digest> {
+    current: RwLock<Arc<Loaded<S, H>>>,
+}
+
+impl<S: StorageReader, H: Digest> ReloadableBigKey<S, H> {
+    pub fn new(big_key: BigKey<S, H>) -> Self {
+        ReloadableBigKey {
+            current: RwLock::new(Arc::new(Loaded {
+                big_key: Mutex::new(big_key),
+                generation: 0,
+            })),
+        }
+    }
+
+    /// Use `big_key` for derivations started from now on, returning the new generation.
+    /// Derivations in progress complete against the BigKey they started with.
+    pub fn reload(&self, big_key: BigKey<S, H>) -> u64 {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        let generation = current.generation + 1;
+        *current = Arc::new(Loaded {
+            big_key: Mutex::new(big_key),
+            generation,
+        });
+        log::info!("reloaded BigKey, generation {}", generation);
+        generation
+    }
+
+    /// `reload()` with the BigKey `open` returns, keeping the current one if it fails. `open`
+    /// runs before the swap, so derivations are not held up while the new BigKey opens.
+    pub fn try_reload<F>(&self, open: F) -> Result<u64, BigKeyError>
+    where
+        F: FnOnce() -> Result<BigKey<S, H>, BigKeyError>,
+    {
+        Ok(self.reload(open()?))
+    }
+
+    /// Number of reloads so far
+    pub fn generation(&self) -> u64 {
+        self.loaded().generation
+    }
+
+    /// Derive a fresh key from the current BigKey, see `BigKeyKem::new_key()`
+    pub fn new_key(
+        &self,
+        security_level: SecurityLevel,
+    ) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        self.with_big_key(|big_key| big_key.new_key(security_level))?
+    }
+
+    /// Re-derive the key of `locator` with the current BigKey, see `BigKeyKem::get_key()`
+    pub fn get_key(&self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+        self.with_big_key(|big_key| big_key.get_key(locator))?
+    }
+
+    /// Run `f` on the current BigKey, which a reload meanwhile does not replace for `f`. Calls
+    /// on the same BigKey are serialized. Fails with `BigKeyPoisoned` if an earlier call on it
+    /// panicked, since the panic may have left its hash state half updated.
+    pub fn with_big_key<T, F>(&self, f: F) -> Result<T, BigKeyError>
+    where
+        F: FnOnce(&mut BigKey<S, H>) -> T,
+    {
+        let loaded = self.loaded();
+        let mut big_key = loaded
+            .big_key
+            .lock()
+            .map_err(|_| BigKeyError::BigKeyPoisoned)?;
+        Ok(f(&mut big_key))
+    }
+
+    fn loaded(&self) -> Arc<Loaded<S, H>> {
+        let current = self.current.read().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(&current)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::kem::{BigKey, BigKeyKem, ReloadableBigKey};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K};
+
+    fn big_key(path: &str) -> BigKey<DiskStorage, Sha3_256> {
+        let storage = DiskStorage::open(BLOCK_1K, path).unwrap();
+        BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+    }
+
+    #[test]
+    fn reloads_let_derivations_in_progress_finish() {
+        let (old, new) = (tempfile(), tempfile());
+        for (tmp, fill) in [(&old, 0x11u8), (&new, 0x22)].iter() {
+            let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 64 * 1024).unwrap();
+            std::io::Write::write_all(&mut writer, &[*fill; 64 * 1024]).unwrap();
+            writer.finalize().unwrap();
+        }
+        let reloadable = Arc::new(ReloadableBigKey::new(big_key(old.to_str())));
+        let (locator, key) = reloadable.new_key(SecurityLevel::Bits128).unwrap();
+
+        // a derivation holds on to the old BigKey across the reload
+        let (started_tx, started) = channel();
+        let (resume, resume_rx) = channel::<()>();
+        let in_flight = {
+            let reloadable = Arc::clone(&reloadable);
+            let locator = locator.clone();
+            thread::spawn(move || {
+                reloadable
+                    .with_big_key(|big_key| {
+                        started_tx.send(()).unwrap();
+                        resume_rx.recv().unwrap();
+                        big_key.get_key(&locator)
+                    })
+                    .unwrap()
+            })
+        };
+        started.recv().unwrap();
+        assert_eq!(reloadable.reload(big_key(new.to_str())), 1);
+        resume.send(()).unwrap();
+        assert_eq!(in_flight.join().unwrap().unwrap(), key);
+
+        assert_eq!(reloadable.generation(), 1);
+        assert_ne!(reloadable.get_key(&locator).unwrap(), key);
+
+        // a failed reload keeps the current BigKey
+        let failed = reloadable.try_reload(|| {
+            Err(BigKeyError::InvalidConfig {
+                reason: "repair in progress".to_string(),
+            })
+        });
+        assert!(failed.is_err());
+        assert_eq!(reloadable.generation(), 1);
+        assert_eq!(
+            reloadable.try_reload(|| Ok(big_key(old.to_str()))).unwrap(),
+            2
+        );
+        assert_eq!(reloadable.get_key(&locator).unwrap(), key);
+    }
+
+    #[test]
+    fn reloads_recover_from_poisoned_big_keys() {
+        let tmp = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 64 * 1024).unwrap();
+        std::io::Write::write_all(&mut writer, &[0x33; 64 * 1024]).unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+        let reloadable = Arc::new(ReloadableBigKey::new(big_key(tmp.to_str())));
+
+        // hostile locators fail without harming the BigKey
+        assert!(reloadable.get_key(&vec![0xffu8; 3].into()).is_err());
+        let (locator, key) = reloadable.new_key(SecurityLevel::Bits128).unwrap();
+
+        let panicking = Arc::clone(&reloadable);
+        assert!(thread::spawn(move || {
+            panicking.with_big_key(|_| panic!("derivation interrupted"))
+        })
+        .join()
+        .is_err());
+        match reloadable.get_key(&locator) {
+            Err(BigKeyError::BigKeyPoisoned) => {}
+            _ => panic!("expected the BigKey to be poisoned"),
+        }
+
+        reloadable.reload(big_key(tmp.to_str()));
+        assert_eq!(reloadable.get_key(&locator).unwrap(), key);
+    }
+} // mod test
@@ -0,0 +1,161 @@
+//! Retiring BigKeys before their security margin erodes.
+//!
+//! Each derivation exposes its probed blocks to anyone who later learns the derived key, and
+//! a key in service for long enough is increasingly likely to have leaked. A
+//! `RetirementPolicy` bounds a key's lifetime usage (see `storage::UsageTracker`); once any
+//! limit is reached `new_key()` fails with `KeyRetired`, forcing rotation to a fresh BigKey.
+//! Existing locators stay usable with `get_key()` so stored ciphertexts can still be read.
+
+use std::time::Duration;
+
+use crate::storage::KeyUsage;
+use crate::traits::BigKeyError;
+
+/// Usage limits after which a BigKey stops deriving new keys. Unset limits are not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetirementPolicy {
+    /// Keys derived over the BigKey's lifetime and not since retired
+    pub max_derivations: Option<u64>,
+    /// Time since the BigKey was created
+    pub max_age: Option<Duration>,
+    /// Fraction of the BigKey's blocks probed at least once
+    pub max_distinct_blocks_fraction: Option<f64>,
+}
+
+impl RetirementPolicy {
+    /// Fail with `KeyRetired` if `usage` has reached any limit of the policy
+    pub fn check(&self, usage: &KeyUsage) -> Result<(), BigKeyError> {
+        if self
+            .max_derivations
+            .is_some_and(|max| usage.live_derivations() >= max)
+        {
+            return Err(BigKeyError::KeyRetired {
+                reason: "derivation limit reached",
+            });
+        }
+        if self.max_age.is_some_and(|max| usage.age() >= max) {
+            return Err(BigKeyError::KeyRetired {
+                reason: "maximum age reached",
+            });
+        }
+        if self
+            .max_distinct_blocks_fraction
+            .is_some_and(|max| usage.touched_fraction() >= max)
+        {
+            return Err(BigKeyError::KeyRetired {
+                reason: "distinct block limit reached",
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crate::kem::RetirementPolicy;
+    use crate::storage::KeyUsage;
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn limits_retire_keys() {
+        let usage = KeyUsage {
+            derivations: 10,
+            retired_derivations: 0,
+            blocks_probed: 1000,
+            distinct_blocks: 500,
+            exact: true,
+            block_count: 1000,
+            created: SystemTime::now() - Duration::from_secs(7200),
+        };
+        assert!(RetirementPolicy::default().check(&usage).is_ok());
+
+        let lenient = RetirementPolicy {
+            max_derivations: Some(11),
+            max_age: Some(Duration::from_secs(86400)),
+            max_distinct_blocks_fraction: Some(0.6),
+        };
+        assert!(lenient.check(&usage).is_ok());
+
+        for (policy, expected) in [
+            (
+                RetirementPolicy {
+                    max_derivations: Some(10),
+                    ..lenient
+                },
+                "derivation limit reached",
+            ),
+            (
+                RetirementPolicy {
+                    max_age: Some(Duration::from_secs(3600)),
+                    ..lenient
+                },
+                "maximum age reached",
+            ),
+            (
+                RetirementPolicy {
+                    max_distinct_blocks_fraction: Some(0.5),
+                    ..lenient
+                },
+                "distinct block limit reached",
+            ),
+        ]
+        .iter()
+        {
+            match policy.check(&usage) {
+                Err(BigKeyError::KeyRetired { reason }) => assert_eq!(reason, *expected),
+                _ => panic!("expected {:?} to retire the key", policy),
+            }
+        }
+
+        // retired derivations give their budget back
+        let retired = KeyUsage {
+            retired_derivations: 5,
+            ..usage
+        };
+        let limited = RetirementPolicy {
+            max_derivations: Some(10),
+            ..lenient
+        };
+        assert!(limited.check(&retired).is_ok());
+    }
+
+    #[test]
+    fn degenerate_usage_and_limits() {
+        let fresh = KeyUsage {
+            derivations: 0,
+            retired_derivations: 3,
+            blocks_probed: 0,
+            distinct_blocks: 0,
+            exact: true,
+            block_count: 0,
+            created: SystemTime::now() + Duration::from_secs(3600),
+        };
+        // a clock in the future, an empty key and more retirements than derivations count as
+        // unused rather than overflowing
+        let tight = RetirementPolicy {
+            max_derivations: Some(1),
+            max_age: Some(Duration::from_secs(1)),
+            max_distinct_blocks_fraction: Some(0.01),
+        };
+        assert!(tight.check(&fresh).is_ok());
+
+        // zero limits retire even an unused key
+        let closed = RetirementPolicy {
+            max_derivations: Some(0),
+            ..RetirementPolicy::default()
+        };
+        match closed.check(&fresh) {
+            Err(BigKeyError::KeyRetired { reason }) => {
+                assert_eq!(reason, "derivation limit reached")
+            }
+            _ => panic!("expected a zero derivation limit to retire the key"),
+        }
+        let no_blocks = RetirementPolicy {
+            max_distinct_blocks_fraction: Some(0.0),
+            ..RetirementPolicy::default()
+        };
+        assert!(no_blocks.check(&fresh).is_err());
+    }
+} // mod test
@@ -0,0 +1,343 @@
+use sha3::{Digest, Sha3_256, Sha3_512};
+
+use crate::kem::locator::LEGACY_HASH;
+use crate::kem::{locator_hash_algorithm, AppId, BigKey, BigKeyKem};
+use crate::storage::DiskStorage;
+use crate::traits::{BigKeyError, BlockSize, HashAlgorithm, KeyMaterial, Locator, SecurityLevel};
+
+/// Parameters of a `KemSession`
+#[derive(Debug, Clone)]
+pub struct SessionParams {
+    pub security_level: SecurityLevel,
+    pub leakage_tolerance: f32,
+    /// Hash new keys are derived with, `None` for the one recorded in the key header or else
+    /// SHA3-256. Locators recording another hash are re-derived with theirs.
+    pub algorithm: Option<HashAlgorithm>,
+    /// `BlockSize` of the key file, `None` to take it from the key header
+    pub block_size: Option<BlockSize>,
+    pub key_id: u32,
+    pub locator_mac: bool,
+    /// Application namespace of the session's keys, see `kem::namespace`
+    pub app_id: Option<AppId>,
+}
+
+impl Default for SessionParams {
+    fn default() -> Self {
+        SessionParams {
+            security_level: SecurityLevel::Bits128,
+            leakage_tolerance: 0.2,
+            algorithm: None,
+            block_size: None,
+            key_id: 0,
+            locator_mac: false,
+            app_id: None,
+        }
+    }
+}
+
+// BigKey over the session's storage, one variant per supported hash
+enum SessionKey {
+    Sha3_256(BigKey<DiskStorage, Sha3_256>),
+    Sha3_512(BigKey<DiskStorage, Sha3_512>),
+}
+
+impl SessionKey {
+    fn open(
+        params: &SessionParams,
+        algorithm: HashAlgorithm,
+        storage: DiskStorage,
+    ) -> Result<SessionKey, BigKeyError> {
+        Ok(match algorithm {
+            HashAlgorithm::Sha3_256 => {
+                SessionKey::Sha3_256(big_key(params, algorithm, storage, Sha3_256::new())?)
+            }
+            HashAlgorithm::Sha3_512 => {
+                SessionKey::Sha3_512(big_key(params, algorithm, storage, Sha3_512::new())?)
+            }
+        })
+    }
+
+    fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            SessionKey::Sha3_256(_) => HashAlgorithm::Sha3_256,
+            SessionKey::Sha3_512(_) => HashAlgorithm::Sha3_512,
+        }
+    }
+
+    fn get_key(&mut self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+        match self {
+            SessionKey::Sha3_256(bk) => bk.get_key(locator),
+            SessionKey::Sha3_512(bk) => bk.get_key(locator),
+        }
+    }
+}
+
+/// An opened BigKey that owns its storage and hash state, so keys can be derived repeatedly
+/// without the caller managing borrows of either.
+///
+/// ```no_run
+/// # use big_fluffy_dise::kem::{KemSession, SessionParams};
+/// let mut session = KemSession::open("/srv/big.key", SessionParams::default())?;
+/// let (locator, key) = session.new_key()?;
+/// assert_eq!(session.get_key(&locator)?, key);
+/// # Ok::<(), big_fluffy_dise::traits::BigKeyError>(())
+/// ```
+pub struct KemSession {
+    key: SessionKey,
+    // BigKeys with the other hashes, opened for the first locator recording one
+    others: Vec<SessionKey>,
+    path: String,
+    block_size: BlockSize,
+    params: SessionParams,
+}
+
+impl KemSession {
+    /// Open the key file at `path` for derivations with `params`, failing with
+    /// `KeyTooSmallForSecurityLevel` if the key is too small for their security level
+    pub fn open(path: &str, params: SessionParams) -> Result<Self, BigKeyError> {
+        let header = DiskStorage::read_header(path)?;
+        let block_size = match (params.block_size, &header) {
+            (Some(block_size), _) => block_size,
+            (None, Some(header)) => header.block_size()?,
+            (None, None) => {
+                return Err(BigKeyError::InvalidConfig {
+                    reason: "raw key file has no header; block size must be given".to_string(),
+                })
+            }
+        };
+        let algorithm = params
+            .algorithm
+            .or_else(|| header.and_then(|header| header.hash_algorithm))
+            .unwrap_or(HashAlgorithm::Sha3_256);
+
+        let storage = DiskStorage::open(block_size, path)?;
+        Ok(KemSession {
+            key: SessionKey::open(&params, algorithm, storage)?,
+            others: Vec::new(),
+            path: path.to_string(),
+            block_size,
+            params,
+        })
+    }
+
+    /// Hash new keys are derived with
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.key.algorithm()
+    }
+
+    /// Derive a fresh key at the session's security level
+    pub fn new_key(&mut self) -> Result<(Locator, KeyMaterial), BigKeyError> {
+        let security_level = self.params.security_level;
+        match &mut self.key {
+            SessionKey::Sha3_256(bk) => bk.new_key(security_level),
+            SessionKey::Sha3_512(bk) => bk.new_key(security_level),
+        }
+    }
+
+    /// Re-derive the key identified by `locator`, with the hash it records (SHA3-256 if it
+    /// records none, see `kem::locator`)
+    pub fn get_key(&mut self, locator: &Locator) -> Result<KeyMaterial, BigKeyError> {
+        // malformed locators are left to fail in the session's own BigKey
+        let algorithm = match locator_hash_algorithm(locator.as_bytes()) {
+            Ok(hash) => hash.unwrap_or(LEGACY_HASH),
+            Err(_) => self.key.algorithm(),
+        };
+        if algorithm == self.key.algorithm() {
+            self.key.get_key(locator)
+        } else {
+            self.key_for(algorithm)?.get_key(locator)
+        }
+    }
+
+    // BigKey over the session's key file with `algorithm`, opening it on first use
+    fn key_for(&mut self, algorithm: HashAlgorithm) -> Result<&mut SessionKey, BigKeyError> {
+        let position = match self.others.iter().position(|k| k.algorithm() == algorithm) {
+            Some(position) => position,
+            None => {
+                let storage = DiskStorage::open(self.block_size, &self.path)?;
+                self.others
+                    .push(SessionKey::open(&self.params, algorithm, storage)?);
+                self.others.len() - 1
+            }
+        };
+        Ok(&mut self.others[position])
+    }
+
+    /// Derive the key of `object_id` at the session's security level, see
+    /// `BigKey::derive_for_id()`
+    pub fn derive_for_id(&mut self, object_id: &[u8]) -> Result<KeyMaterial, BigKeyError> {
+        match &mut self.key {
+            SessionKey::Sha3_256(bk) => bk.derive_for_id(object_id),
+            SessionKey::Sha3_512(bk) => bk.derive_for_id(object_id),
+        }
+    }
+
+    /// Blocks probed by a single derivation
+    pub fn estimated_probe_count(&self) -> Result<u64, BigKeyError> {
+        match &self.key {
+            SessionKey::Sha3_256(bk) => bk.estimated_probe_count(),
+            SessionKey::Sha3_512(bk) => bk.estimated_probe_count(),
+        }
+    }
+
+    /// Bytes read from storage by a single derivation
+    pub fn estimated_derivation_io_bytes(&self) -> Result<u64, BigKeyError> {
+        match &self.key {
+            SessionKey::Sha3_256(bk) => bk.estimated_derivation_io_bytes(),
+            SessionKey::Sha3_512(bk) => bk.estimated_derivation_io_bytes(),
+        }
+    }
+
+    pub fn params(&self) -> &SessionParams {
+        &self.params
+    }
+
+    pub fn storage(&self) -> &DiskStorage {
+        match &self.key {
+            SessionKey::Sha3_256(bk) => bk.storage(),
+            SessionKey::Sha3_512(bk) => bk.storage(),
+        }
+    }
+}
+
+fn big_key<H: Digest>(
+    params: &SessionParams,
+    algorithm: HashAlgorithm,
+    storage: DiskStorage,
+    hasher: H,
+) -> Result<BigKey<DiskStorage, H>, BigKeyError> {
+    let bk = BigKey::open(
+        params.security_level,
+        params.leakage_tolerance,
+        storage,
+        hasher,
+    )?
+    .with_key_id(params.key_id)
+    .with_hash_algorithm(algorithm);
+    let bk = match params.app_id {
+        Some(app_id) => bk.with_app_id(app_id),
+        None => bk,
+    };
+
+    Ok(if params.locator_mac {
+        bk.with_locator_mac()
+    } else {
+        bk
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use sha3::{Digest, Sha3_256, Sha3_512};
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::helpers::{generate_key_file, GenerateOptions};
+    use crate::kem::{
+        locator_hash_algorithm, BigKey, BigKeyKem, HashAlgorithm, KemSession, SessionParams,
+    };
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K};
+
+    #[test]
+    fn session_derives_repeatedly() {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 128 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 128 * 1024).unwrap();
+
+        for algorithm in [HashAlgorithm::Sha3_256, HashAlgorithm::Sha3_512].iter() {
+            let params = SessionParams {
+                security_level: SecurityLevel::Bits256,
+                algorithm: Some(*algorithm),
+                ..SessionParams::default()
+            };
+            let mut session = KemSession::open(tmp.to_str(), params).unwrap();
+
+            let (locator1, key1) = session.new_key().unwrap();
+            let (locator2, key2) = session.new_key().unwrap();
+            assert_eq!(key1.len(), 32);
+            assert_ne!(key1, key2);
+            assert_eq!(session.get_key(&locator1).unwrap(), key1);
+            assert_eq!(session.get_key(&locator2).unwrap(), key2);
+        }
+    }
+
+    #[test]
+    fn sessions_follow_the_hash_of_locators() {
+        let tmp = tempfile();
+        let options = GenerateOptions {
+            block_size: BLOCK_1K,
+            hash_algorithm: Some(HashAlgorithm::Sha3_512),
+            ..GenerateOptions::default()
+        };
+        generate_key_file(tmp.as_path(), 64 * 1024u64, &options).unwrap();
+
+        // the header picks the hash of new keys
+        let mut old = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        assert_eq!(old.algorithm(), HashAlgorithm::Sha3_512);
+        let (locator, key) = old.new_key().unwrap();
+        assert_eq!(
+            locator_hash_algorithm(locator.as_bytes()).unwrap(),
+            Some(HashAlgorithm::Sha3_512)
+        );
+
+        // a session migrated to another hash still re-derives old locators
+        let params = SessionParams {
+            algorithm: Some(HashAlgorithm::Sha3_256),
+            ..SessionParams::default()
+        };
+        let mut migrated = KemSession::open(tmp.to_str(), params).unwrap();
+        assert_eq!(migrated.get_key(&locator).unwrap(), key);
+        let (new_locator, new_key) = migrated.new_key().unwrap();
+        assert_eq!(old.get_key(&new_locator).unwrap(), new_key);
+
+        // a bare BigKey refuses locators of another hash instead of deriving a wrong key
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_hash_algorithm(HashAlgorithm::Sha3_256);
+        match bk.get_key(&locator) {
+            Err(BigKeyError::HashAlgorithmMismatch { expected, found }) => {
+                assert_eq!(
+                    (expected, found),
+                    (HashAlgorithm::Sha3_256, HashAlgorithm::Sha3_512)
+                )
+            }
+            _ => panic!("expected a SHA3-512 locator to be refused"),
+        }
+    }
+
+    #[test]
+    fn locators_without_a_hash_are_sha3_256() {
+        let tmp = tempfile();
+        let options = GenerateOptions {
+            block_size: BLOCK_1K,
+            hash_algorithm: Some(HashAlgorithm::Sha3_512),
+            ..GenerateOptions::default()
+        };
+        generate_key_file(tmp.as_path(), 64 * 1024u64, &options).unwrap();
+
+        // a BigKey recording no hash, as before locators recorded one
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut legacy = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+        let (locator, key) = legacy.new_key(SecurityLevel::Bits128).unwrap();
+        assert_eq!(locator_hash_algorithm(locator.as_bytes()).unwrap(), None);
+
+        let mut session = KemSession::open(tmp.to_str(), SessionParams::default()).unwrap();
+        assert_eq!(session.algorithm(), HashAlgorithm::Sha3_512);
+        assert_eq!(session.get_key(&locator).unwrap(), key);
+
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_512::new())
+            .with_hash_algorithm(HashAlgorithm::Sha3_512);
+        match bk.get_key(&locator) {
+            Err(BigKeyError::HashAlgorithmMismatch { expected, found }) => {
+                assert_eq!(
+                    (expected, found),
+                    (HashAlgorithm::Sha3_512, HashAlgorithm::Sha3_256)
+                )
+            }
+            _ => panic!("expected a locator without a hash to be refused by SHA3-512"),
+        }
+    }
+} // mod test
@@ -0,0 +1,460 @@
+//! Persistent labels for locators.
+//!
+//! A derived key is only as recoverable as its locator, so every application using BigKeys ends
+//! up keeping a table of "which locator encrypts what". `LocatorStore` is that table: a map from
+//! user chosen labels to locators, persisted in an append-only log file.
+//!
+//! Each line of the log is one record, `<unix seconds>\t<label>\t<armored locator>`, with an
+//! empty locator for a removed label. Replaying the log in order gives the current labels; the
+//! latest record of a label wins. Records are appended with a single write and synced, so a crash
+//! can at worst leave a torn last line, which is dropped when the store is next opened.
+//!
+//! Once the data a key protected is gone, `gc()` retires its labels: their records are deleted
+//! from the log by rewriting it, the retired derivations are returned to the BigKey's
+//! derivation budget, and a `GcReport` lists the blocks no live locator probes any more.
+//!
+//! A store opened with `open_with()` and a `MetadataCompression` writes the log it rewrites on
+//! `gc()` or `compact()` as a compressed snapshot (see `crate::storage::compress_metadata`);
+//! records labeled later are appended after it uncompressed as usual. Any store reads logs
+//! starting with a snapshot, whatever it was opened with.
+//!
+//! Locators are not secret, but the store says which ones matter; keep it readable only by
+//! those who may derive the keys.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use digest::Digest;
+
+use crate::kem::locator::LocatorBody;
+use crate::kem::{armor_locator, dearmor_locator, BigKey};
+use crate::storage::compression::decompress_prefix;
+use crate::storage::{compress_metadata, MetadataCompression, StorageReader};
+use crate::traits::{BigKeyError, BlockIndex, Locator};
+
+/// Longest label a store accepts
+pub const MAX_LABEL_LEN: usize = 256;
+
+/// A labeled locator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabeledLocator {
+    pub label: String,
+    pub locator: Locator,
+    /// Seconds since the Unix epoch when the label was last set
+    pub labeled_at: u64,
+}
+
+/// Outcome of `LocatorStore::gc()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Labels removed, in the order given
+    pub retired: Vec<String>,
+    /// Labels left in the store
+    pub live: usize,
+    /// Retired locators derived from the BigKey passed to `gc()`, returned to its budget;
+    /// locators of other BigKeys are removed but not counted
+    pub retired_derivations: u64,
+    /// Blocks probed by a retired locator but by no live locator, ascending
+    pub unreferenced_blocks: Vec<BlockIndex>,
+}
+
+/// Labels mapped to locators, persisted in an append-only log
+pub struct LocatorStore {
+    path: PathBuf,
+    file: File,
+    labels: BTreeMap<String, LabeledLocator>,
+    compression: MetadataCompression,
+}
+
+impl LocatorStore {
+    /// Open the store at `path`, creating an empty one if there is none
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BigKeyError> {
+        LocatorStore::open_with(path, MetadataCompression::None)
+    }
+
+    /// Open the store at `path` like `open()`, writing the log with `compression` whenever it
+    /// is rewritten
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        compression: MetadataCompression,
+    ) -> Result<Self, BigKeyError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (snapshot, snapshot_len) = decompress_prefix(&bytes)?;
+        let (snapshot, contents) = match (
+            String::from_utf8(snapshot),
+            std::str::from_utf8(&bytes[snapshot_len..]),
+        ) {
+            (Ok(snapshot), Ok(contents)) => (snapshot, contents),
+            _ => return Err(failed(format!("{} is not UTF-8", path.display()))),
+        };
+
+        let complete = contents.rfind('\n').map_or(0, |end| end + 1);
+        if complete < contents.len() {
+            log::warn!(
+                "dropping torn record at the end of locator store {}",
+                path.display()
+            );
+            file.set_len((snapshot_len + complete) as u64)?;
+        }
+
+        let mut labels = BTreeMap::new();
+        let records = snapshot.lines().chain(contents[..complete].lines());
+        for (number, line) in records.enumerate() {
+            let record = parse_record(line).ok_or_else(|| {
+                failed(format!(
+                    "malformed record on line {} of {}",
+                    number + 1,
+                    path.display()
+                ))
+            })?;
+            match record {
+                (label, None) => labels.remove(&label),
+                (label, Some(labeled)) => labels.insert(label, labeled),
+            };
+        }
+
+        Ok(LocatorStore {
+            path,
+            file,
+            labels,
+            compression,
+        })
+    }
+
+    /// Label `locator` as `label`, replacing the locator previously labeled so
+    pub fn label(&mut self, label: &str, locator: &Locator) -> Result<(), BigKeyError> {
+        check_label(label)?;
+        let labeled_at = unix_now();
+        let armored = armor_locator(locator.as_bytes());
+        self.append(&format!("{}\t{}\t{}\n", labeled_at, label, armored))?;
+        self.labels.insert(
+            label.to_string(),
+            LabeledLocator {
+                label: label.to_string(),
+                locator: locator.clone(),
+                labeled_at,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove `label`, returning whether it was present
+    pub fn remove(&mut self, label: &str) -> Result<bool, BigKeyError> {
+        if !self.labels.contains_key(label) {
+            return Ok(false);
+        }
+        self.append(&format!("{}\t{}\t\n", unix_now(), label))?;
+        self.labels.remove(label);
+        Ok(true)
+    }
+
+    /// Remove `retired_labels` for good, rewriting the log without them, and record their
+    /// retirement with `big_key`. Labels not in the store are ignored.
+    pub fn gc<S: StorageReader, H: Digest>(
+        &mut self,
+        retired_labels: &[&str],
+        big_key: &mut BigKey<S, H>,
+    ) -> Result<GcReport, BigKeyError> {
+        let mut report = GcReport::default();
+        let mut retired = BTreeMap::new();
+        for label in retired_labels {
+            if let Some(labeled) = self.labels.remove(*label) {
+                report.retired.push(labeled.label.clone());
+                retired.insert(labeled.label.clone(), labeled);
+            }
+        }
+        let result = self
+            .account(&retired, big_key, &mut report)
+            .and_then(|_| self.compact());
+        if result.is_err() {
+            self.labels.append(&mut retired);
+        }
+        result?;
+
+        big_key.record_retirement(report.retired_derivations);
+        report.live = self.labels.len();
+        Ok(report)
+    }
+
+    /// The locator labeled `label`
+    pub fn lookup(&self, label: &str) -> Option<&LabeledLocator> {
+        self.labels.get(label)
+    }
+
+    /// Every labeled locator, ordered by label
+    pub fn list(&self) -> impl Iterator<Item = &LabeledLocator> {
+        self.labels.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Count the derivations `retired` retires from `big_key` and find the blocks they probe
+    // that no live locator does
+    fn account<S: StorageReader, H: Digest>(
+        &self,
+        retired: &BTreeMap<String, LabeledLocator>,
+        big_key: &mut BigKey<S, H>,
+        report: &mut GcReport,
+    ) -> Result<(), BigKeyError> {
+        let mut retired_blocks = BTreeSet::new();
+        for labeled in retired.values() {
+            if LocatorBody::decode(labeled.locator.as_bytes())?.key_id == big_key.key_id() {
+                retired_blocks.extend(big_key.probe_indices(&labeled.locator)?);
+                report.retired_derivations += 1;
+            }
+        }
+        let mut live_blocks = HashSet::new();
+        for labeled in self.labels.values() {
+            if LocatorBody::decode(labeled.locator.as_bytes())?.key_id == big_key.key_id() {
+                live_blocks.extend(big_key.probe_indices(&labeled.locator)?);
+            }
+        }
+        report.unreferenced_blocks = retired_blocks
+            .iter()
+            .copied()
+            .filter(|index| !live_blocks.contains(index))
+            .collect();
+        Ok(())
+    }
+
+    /// Replace the log with one holding only the live labels, compressed if the store was
+    /// opened with compression
+    pub fn compact(&mut self) -> Result<(), BigKeyError> {
+        let mut compacted = self.path.clone().into_os_string();
+        compacted.push(".tmp");
+        let compacted = PathBuf::from(compacted);
+
+        let mut log = String::new();
+        for labeled in self.labels.values() {
+            log.push_str(&format!(
+                "{}\t{}\t{}\n",
+                labeled.labeled_at,
+                labeled.label,
+                armor_locator(labeled.locator.as_bytes())
+            ));
+        }
+        let mut file = File::create(&compacted)?;
+        file.write_all(&compress_metadata(log.as_bytes(), self.compression)?)?;
+        file.sync_all()?;
+        std::fs::rename(&compacted, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        Ok(())
+    }
+
+    fn append(&mut self, record: &str) -> Result<(), BigKeyError> {
+        self.file.write_all(record.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+// `(label, Some(locator))` for a labeling record, `(label, None)` for a removal
+fn parse_record(line: &str) -> Option<(String, Option<LabeledLocator>)> {
+    let mut fields = line.splitn(3, '\t');
+    let labeled_at = fields.next()?.parse().ok()?;
+    let label = fields.next()?.to_string();
+    let locator = match fields.next()? {
+        "" => return Some((label, None)),
+        armored => dearmor_locator(armored).ok()?,
+    };
+    Some((
+        label.clone(),
+        Some(LabeledLocator {
+            label,
+            locator,
+            labeled_at,
+        }),
+    ))
+}
+
+fn check_label(label: &str) -> Result<(), BigKeyError> {
+    if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        return Err(failed(format!(
+            "labels must be 1 to {} bytes long",
+            MAX_LABEL_LEN
+        )));
+    }
+    if label.chars().any(char::is_control) {
+        return Err(failed("labels cannot contain control characters"));
+    }
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn failed(reason: impl ToString) -> BigKeyError {
+    BigKeyError::LocatorStoreFailed {
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::fs::OpenOptions;
+    use std::io::{Cursor, Write};
+
+    use digest::Digest;
+    use sha3::Sha3_256;
+
+    use crate::kem::{BigKey, BigKeyKem, LocatorStore};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{usage_path, ReadSeekStorage, UsageTracker};
+    use crate::traits::{BlockIndex, Locator, SecurityLevel, BLOCK_1K};
+
+    #[test]
+    fn labels_persist_across_opens() {
+        let tmp = tempfile();
+        let (a, b): (Locator, Locator) = (vec![3u8, 1, 2, 3].into(), vec![3u8, 4, 5, 6].into());
+
+        let mut store = LocatorStore::open(tmp.as_path()).unwrap();
+        assert!(store.is_empty());
+        store.label("backups/2024", &a).unwrap();
+        store.label("mail", &a).unwrap();
+        store.label("mail", &b).unwrap();
+        store.label("tmp", &b).unwrap();
+        assert!(store.remove("tmp").unwrap());
+        assert!(!store.remove("tmp").unwrap());
+        assert!(store.label("bad\tlabel", &a).is_err());
+        assert!(store.label("", &a).is_err());
+        drop(store);
+
+        // a torn record from a crash is dropped, the rest survives
+        OpenOptions::new()
+            .append(true)
+            .open(tmp.as_path())
+            .unwrap()
+            .write_all(b"1700000000\thalf")
+            .unwrap();
+        let mut store = LocatorStore::open(tmp.as_path()).unwrap();
+        let labels: Vec<&str> = store.list().map(|l| l.label.as_str()).collect();
+        assert_eq!(labels, ["backups/2024", "mail"]);
+        assert_eq!(store.lookup("mail").unwrap().locator, b);
+        assert_eq!(store.lookup("backups/2024").unwrap().locator, a);
+        assert!(store.lookup("mail").unwrap().labeled_at > 0);
+        assert!(store.lookup("tmp").is_none());
+
+        store.label("tmp", &a).unwrap();
+        drop(store);
+        let store = LocatorStore::open(tmp.as_path()).unwrap();
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn gc_retires_labels_and_reports_unreferenced_blocks() {
+        let (tmp, key) = (tempfile(), tempfile());
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let storage = ReadSeekStorage::new(Cursor::new(contents), BLOCK_1K).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_usage_tracker(UsageTracker::open(key.to_str(), 64).unwrap());
+
+        let mut store = LocatorStore::open(tmp.as_path()).unwrap();
+        for label in ["a", "b", "c"] {
+            let (locator, _) = bk.new_key(SecurityLevel::Bits128).unwrap();
+            store.label(label, &locator).unwrap();
+        }
+        let indices = |bk: &mut BigKey<_, _>, label| -> HashSet<BlockIndex> {
+            let locator = store.lookup(label).unwrap().locator.clone();
+            bk.probe_indices(&locator).unwrap().into_iter().collect()
+        };
+        let (a, b, c) = (
+            indices(&mut bk, "a"),
+            indices(&mut bk, "b"),
+            indices(&mut bk, "c"),
+        );
+
+        let report = store.gc(&["b", "missing", "c"], &mut bk).unwrap();
+        assert_eq!(report.retired, ["b", "c"]);
+        assert_eq!((report.live, report.retired_derivations), (1, 2));
+        let unreferenced: HashSet<BlockIndex> =
+            report.unreferenced_blocks.iter().copied().collect();
+        let expected: HashSet<BlockIndex> =
+            b.union(&c).filter(|i| !a.contains(i)).copied().collect();
+        assert_eq!(unreferenced, expected);
+        assert!(report.unreferenced_blocks.windows(2).all(|w| w[0] < w[1]));
+        let usage = bk.usage().unwrap();
+        assert_eq!((usage.derivations, usage.live_derivations()), (3, 1));
+
+        // the retired records are gone from the log itself
+        let log = std::fs::read_to_string(tmp.as_path()).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        store.label("d", &vec![3u8, 1].into()).unwrap();
+        drop(store);
+        let store = LocatorStore::open(tmp.as_path()).unwrap();
+        assert_eq!(
+            store.list().map(|l| l.label.as_str()).collect::<Vec<_>>(),
+            ["a", "d"]
+        );
+
+        drop(bk);
+        let _ = std::fs::remove_file(usage_path(key.to_str()));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compacted_logs_are_compressed_snapshots() {
+        use crate::storage::{is_compressed, MetadataCompression};
+
+        let tmp = tempfile();
+        let compression = MetadataCompression::Zstd { level: 3 };
+        let mut store = LocatorStore::open_with(tmp.as_path(), compression).unwrap();
+        for i in 0..100u8 {
+            store
+                .label(&format!("volumes/{}", i), &vec![3u8, i].into())
+                .unwrap();
+        }
+        let plain_len = std::fs::metadata(tmp.as_path()).unwrap().len();
+        store.compact().unwrap();
+        let compacted = std::fs::read(tmp.as_path()).unwrap();
+        assert!(is_compressed(&compacted));
+        assert!((compacted.len() as u64) < plain_len);
+
+        // records after the snapshot are appended as usual, torn ones still dropped
+        store.label("late", &vec![3u8, 200].into()).unwrap();
+        drop(store);
+        OpenOptions::new()
+            .append(true)
+            .open(tmp.as_path())
+            .unwrap()
+            .write_all(b"1700000000\thalf")
+            .unwrap();
+        let store = LocatorStore::open(tmp.as_path()).unwrap();
+        assert_eq!(store.len(), 101);
+        assert_eq!(store.lookup("late").unwrap().locator, vec![3u8, 200].into());
+
+        // a damaged snapshot is refused rather than losing labels
+        let mut damaged = compacted;
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0x01;
+        std::fs::write(tmp.as_path(), damaged).unwrap();
+        assert!(LocatorStore::open(tmp.as_path()).is_err());
+    }
+} // mod test
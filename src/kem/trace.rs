@@ -0,0 +1,364 @@
+//! Probe index traces, for checking that derivations probe a key uniformly.
+//!
+//! `BigKey::with_probe_trace()` writes the block indices probed by every derivation to a
+//! `ProbeTrace`, either as CSV rows `derivation,block_count,probe,index` or as JSON lines
+//! `{"derivation":0,"block_count":1024,"indices":[...]}`. `CoverageHeatmap::from_trace()` reads
+//! either format back and counts probes per range of blocks over the key's lifetime; the
+//! `heatmap` binary prints the result.
+//!
+//! Probe indices follow from the selector in each locator, so a trace reveals nothing an
+//! observer of the locators could not compute, but it does tie every derivation to the blocks it
+//! read. Keep traces of production keys with the locators.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::traits::{BigKeyError, BlockCount, BlockIndex};
+
+const CSV_HEADER: &str = "derivation,block_count,probe,index";
+
+// Heatmap cells from fewest to most probes relative to a uniform distribution
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+/// Encoding of a `ProbeTrace`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One row per probe
+    Csv,
+    /// One JSON object per derivation and line
+    Json,
+}
+
+impl TraceFormat {
+    pub fn parse(name: &str) -> Option<TraceFormat> {
+        match name {
+            "csv" => Some(TraceFormat::Csv),
+            "json" => Some(TraceFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TraceRecord {
+    derivation: u64,
+    block_count: u64,
+    indices: Vec<u64>,
+}
+
+/// Sink for the probe indices of successive derivations
+pub struct ProbeTrace {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    derivations: u64,
+}
+
+impl ProbeTrace {
+    /// Trace to a new file at `path`, replacing any existing file
+    pub fn create(path: &str, format: TraceFormat) -> Result<ProbeTrace, BigKeyError> {
+        let file = File::create(path)
+            .map_err(|e| BigKeyError::from(e).in_storage("create probe trace", path, None))?;
+        ProbeTrace::new(BufWriter::new(file), format)
+    }
+
+    /// Trace to `out`
+    pub fn new(
+        out: impl Write + Send + 'static,
+        format: TraceFormat,
+    ) -> Result<ProbeTrace, BigKeyError> {
+        let mut trace = ProbeTrace {
+            out: Box::new(out),
+            format,
+            derivations: 0,
+        };
+        if format == TraceFormat::Csv {
+            writeln!(trace.out, "{}", CSV_HEADER)?;
+        }
+        Ok(trace)
+    }
+
+    /// Derivations traced so far
+    pub fn derivations(&self) -> u64 {
+        self.derivations
+    }
+
+    pub fn flush(&mut self) -> Result<(), BigKeyError> {
+        Ok(self.out.flush()?)
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        block_count: BlockCount,
+        indices: &[BlockIndex],
+    ) -> Result<(), BigKeyError> {
+        let derivation = self.derivations;
+        let block_count = block_count.get();
+        match self.format {
+            TraceFormat::Csv => {
+                for (probe, index) in indices.iter().enumerate() {
+                    writeln!(
+                        self.out,
+                        "{},{},{},{}",
+                        derivation, block_count, probe, index
+                    )?;
+                }
+            }
+            TraceFormat::Json => {
+                let record = TraceRecord {
+                    derivation,
+                    block_count,
+                    indices: indices.iter().map(|index| index.get()).collect(),
+                };
+                serde_json::to_writer(&mut self.out, &record).map_err(std::io::Error::from)?;
+                writeln!(self.out)?;
+            }
+        }
+        self.derivations += 1;
+        Ok(())
+    }
+}
+
+/// Probes per range of blocks, aggregated over any number of derivations.
+///
+/// The key's blocks are split into `bins` near-equal ranges. Under a uniform probe distribution
+/// each range receives probes in proportion to its width; `chi_squared()` measures the departure
+/// from that and `render()` draws it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CoverageHeatmap {
+    pub block_count: u64,
+    pub derivations: u64,
+    pub probes: u64,
+    /// Probes falling in each range of blocks, see `bin_blocks()`
+    pub bins: Vec<u64>,
+}
+
+impl CoverageHeatmap {
+    /// An empty heatmap of a key with `block_count` blocks, in at most `bins` ranges (at least
+    /// one block each)
+    pub fn new(block_count: u64, bins: usize) -> Result<CoverageHeatmap, BigKeyError> {
+        if block_count == 0 || bins == 0 {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "heatmaps need at least one block and one bin".to_string(),
+            });
+        }
+        Ok(CoverageHeatmap {
+            block_count,
+            derivations: 0,
+            probes: 0,
+            bins: vec![0; bins.min(block_count.try_into().unwrap_or(usize::MAX))],
+        })
+    }
+
+    /// Aggregate a trace written by `ProbeTrace` in either format into `bins` ranges
+    pub fn from_trace<R: BufRead>(trace: R, bins: usize) -> Result<CoverageHeatmap, BigKeyError> {
+        let mut heatmap: Option<CoverageHeatmap> = None;
+        let mut current = None;
+
+        for (number, line) in trace.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line == CSV_HEADER {
+                continue;
+            }
+            let invalid = |reason: &str| BigKeyError::InvalidProbeTrace {
+                reason: format!("line {}: {}", number + 1, reason),
+            };
+
+            let record = match line.starts_with('{') {
+                true => serde_json::from_str(line).map_err(|e| invalid(&e.to_string()))?,
+                false => parse_csv_row(line).ok_or_else(|| invalid("expected 4 integer fields"))?,
+            };
+            let heatmap = match heatmap.as_mut() {
+                Some(heatmap) if heatmap.block_count != record.block_count => {
+                    return Err(invalid("block count differs from earlier derivations"));
+                }
+                Some(heatmap) => heatmap,
+                None => heatmap.insert(CoverageHeatmap::new(record.block_count, bins)?),
+            };
+            if current != Some(record.derivation) {
+                current = Some(record.derivation);
+                heatmap.derivations += 1;
+            }
+            for &index in record.indices.iter() {
+                heatmap
+                    .add_probe(index)
+                    .map_err(|_| invalid("block index beyond the end of the key"))?;
+            }
+        }
+
+        heatmap.ok_or_else(|| BigKeyError::InvalidProbeTrace {
+            reason: "trace holds no derivations".to_string(),
+        })
+    }
+
+    /// Count one derivation probing `indices`
+    pub fn add(&mut self, indices: &[u64]) -> Result<(), BigKeyError> {
+        for &index in indices {
+            self.add_probe(index)?;
+        }
+        self.derivations += 1;
+        Ok(())
+    }
+
+    /// Blocks counted by bin `bin`
+    pub fn bin_blocks(&self, bin: usize) -> Range<u64> {
+        let bins = self.bins.len() as u128;
+        let edge = |bin: usize| (bin as u128 * self.block_count as u128 / bins) as u64;
+        edge(bin)..edge(bin + 1)
+    }
+
+    /// Probes bin `bin` receives on average if every block is equally likely
+    pub fn expected(&self, bin: usize) -> f64 {
+        let blocks = self.bin_blocks(bin);
+        self.probes as f64 * (blocks.end - blocks.start) as f64 / self.block_count as f64
+    }
+
+    /// Pearson's chi-squared statistic of the bin counts against uniform probing, with
+    /// `bins.len() - 1` degrees of freedom (so around that for uniform probes)
+    pub fn chi_squared(&self) -> f64 {
+        (0..self.bins.len())
+            .map(|bin| {
+                let expected = self.expected(bin);
+                match expected > 0.0 {
+                    true => (self.bins[bin] as f64 - expected).powi(2) / expected,
+                    false => 0.0,
+                }
+            })
+            .sum()
+    }
+
+    /// Draw the bins in rows of `columns` cells, shaded by probes relative to the uniform
+    /// expectation from ' ' (none) through '=' (as expected) to '@' (twice as many or more)
+    pub fn render(&self, columns: usize) -> String {
+        let mut out = String::new();
+        for (bin, &count) in self.bins.iter().enumerate() {
+            let ratio = match self.expected(bin) {
+                expected if expected > 0.0 => count as f64 / expected,
+                _ => 0.0,
+            };
+            let shade = match count {
+                0 => 0,
+                _ => {
+                    ((ratio * (SHADES.len() - 1) as f64 / 2.0) as usize).clamp(1, SHADES.len() - 1)
+                }
+            };
+            out.push(SHADES[shade] as char);
+            if (bin + 1) % columns.max(1) == 0 || bin + 1 == self.bins.len() {
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    fn add_probe(&mut self, index: u64) -> Result<(), BigKeyError> {
+        if index >= self.block_count {
+            return Err(BigKeyError::InvalidProbeTrace {
+                reason: format!("block index {} beyond {} blocks", index, self.block_count),
+            });
+        }
+        let bin = (index as u128 * self.bins.len() as u128 / self.block_count as u128) as usize;
+        // bin edges round down, so an index can sit on the end of its computed bin
+        let bin = match index >= self.bin_blocks(bin).end {
+            true => bin + 1,
+            false => bin,
+        };
+        self.bins[bin] += 1;
+        self.probes += 1;
+        Ok(())
+    }
+}
+
+fn parse_csv_row(line: &str) -> Option<TraceRecord> {
+    let mut fields = line.split(',').map(|f| f.trim().parse::<u64>());
+    let (derivation, block_count, _probe, index) = (
+        fields.next()?.ok()?,
+        fields.next()?.ok()?,
+        fields.next()?.ok()?,
+        fields.next()?.ok()?,
+    );
+    match fields.next() {
+        None => Some(TraceRecord {
+            derivation,
+            block_count,
+            indices: vec![index],
+        }),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::kem::{BigKey, BigKeyKem, CoverageHeatmap, ProbeTrace, TraceFormat};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::ReadSeekStorage;
+    use crate::traits::{SecurityLevel, BLOCK_64};
+
+    #[test]
+    fn traced_probes_cover_the_key_uniformly() {
+        let key: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+        let block_count = key.len() as u64 / BLOCK_64.byte_len as u64;
+
+        for format in [TraceFormat::Csv, TraceFormat::Json].iter() {
+            let tmp = tempfile();
+            let storage =
+                ReadSeekStorage::new(std::io::Cursor::new(key.clone()), BLOCK_64).unwrap();
+            let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+                .with_locator_mac()
+                .with_probe_trace(ProbeTrace::create(tmp.to_str(), *format).unwrap());
+
+            let mut heatmap = CoverageHeatmap::new(block_count, 16).unwrap();
+            for _ in 0..50 {
+                let (_, _, transcript) =
+                    bk.new_key_with_transcript(SecurityLevel::Bits128).unwrap();
+                let indices: Vec<u64> = transcript.probes.iter().map(|p| p.index).collect();
+                heatmap.add(&indices).unwrap();
+            }
+            drop(bk);
+
+            let file = BufReader::new(File::open(tmp.as_path()).unwrap());
+            let traced = CoverageHeatmap::from_trace(file, 16).unwrap();
+            // the locator MAC key derivation is not traced
+            assert_eq!(traced, heatmap);
+            assert_eq!(traced.derivations, 50);
+            assert_eq!(traced.bins.iter().sum::<u64>(), traced.probes);
+            // 15 degrees of freedom: p < 1e-6 beyond 50
+            assert!(traced.chi_squared() < 50.0, "{}", traced.chi_squared());
+            assert_eq!(traced.render(8).lines().count(), 2);
+        }
+    }
+
+    #[test]
+    fn uneven_bins_and_bad_traces() {
+        let mut heatmap = CoverageHeatmap::new(10, 3).unwrap();
+        heatmap.add(&(0..10).collect::<Vec<u64>>()).unwrap();
+        assert_eq!(heatmap.bins, vec![3, 3, 4]);
+        assert_eq!(heatmap.chi_squared(), 0.0);
+        assert_eq!(heatmap.render(3), "===\n");
+        assert!(heatmap.add(&[10]).is_err());
+        assert_eq!(CoverageHeatmap::new(2, 8).unwrap().bins.len(), 2);
+
+        for trace in [
+            "",
+            "derivation,block_count,probe,index\n0,8,0,x\n",
+            "0,8,0,1\n1,16,0,1\n",
+            "{\"derivation\":0,\"block_count\":8,\"indices\":[8]}\n",
+        ]
+        .iter()
+        {
+            assert!(
+                CoverageHeatmap::from_trace(trace.as_bytes(), 4).is_err(),
+                "{}",
+                trace
+            );
+        }
+    }
+} // mod test
@@ -0,0 +1,117 @@
+//! Audit transcripts of key derivations.
+//!
+//! A `Transcript` records everything about how a key was derived except the key and block
+//! contents: the derivation parameters, each probed block index, and a BLAKE3 hash chain
+//! committing to the data absorbed after every probe. An auditor holding the BigKey can replay
+//! the derivation with `BigKey::verify_transcript()`; anyone else can check the caller's
+//! signature over `Transcript::to_bytes()`.
+
+use crate::kem::locator::LocatorBody;
+use crate::traits::{KeyMaterial, Locator, SecurityLevel};
+
+const TRANSCRIPT_DOMAIN: &[u8] = b"big_fluffy_dise transcript";
+const KEY_COMMITMENT_DOMAIN: &[u8] = b"big_fluffy_dise transcript key";
+
+/// A single probe of a derivation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeRecord {
+    /// Index of the probed block
+    pub index: u64,
+    /// Hash chain value after absorbing this probe: `BLAKE3(previous || index || block)`
+    pub commitment: [u8; 32],
+}
+
+/// Record of a key derivation, free of key material
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transcript {
+    pub locator: Locator,
+    pub key_id: u32,
+    pub security_level: SecurityLevel,
+    pub block_len: usize,
+    pub big_key_length: u64,
+    pub probes: Vec<ProbeRecord>,
+    /// `BLAKE3(domain || final commitment || key)`, binds the transcript to the derived key
+    pub key_commitment: [u8; 32],
+}
+
+impl Transcript {
+    /// Canonical encoding of the transcript, suitable for signing
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + self.locator.len() + self.probes.len() * 40);
+        out.extend_from_slice(TRANSCRIPT_DOMAIN);
+        out.extend_from_slice(&(self.locator.len() as u32).to_be_bytes());
+        out.extend_from_slice(self.locator.as_bytes());
+        out.extend_from_slice(&self.key_id.to_be_bytes());
+        out.extend_from_slice(&(self.security_level as u16).to_be_bytes());
+        out.extend_from_slice(&(self.block_len as u32).to_be_bytes());
+        out.extend_from_slice(&self.big_key_length.to_be_bytes());
+        out.extend_from_slice(&(self.probes.len() as u32).to_be_bytes());
+        for probe in self.probes.iter() {
+            out.extend_from_slice(&probe.index.to_be_bytes());
+            out.extend_from_slice(&probe.commitment);
+        }
+        out.extend_from_slice(&self.key_commitment);
+        out
+    }
+
+    /// BLAKE3 digest of `to_bytes()`
+    pub fn digest(&self) -> [u8; 32] {
+        *blake3::hash(&self.to_bytes()).as_bytes()
+    }
+}
+
+// Accumulates probe records while a key is derived
+pub(crate) struct TranscriptRecorder {
+    chain: [u8; 32],
+    probes: Vec<ProbeRecord>,
+}
+
+impl TranscriptRecorder {
+    pub fn new(body: &LocatorBody) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(TRANSCRIPT_DOMAIN);
+        hasher.update(&body.selector);
+
+        TranscriptRecorder {
+            chain: *hasher.finalize().as_bytes(),
+            probes: Vec::with_capacity(body.probe_count as usize),
+        }
+    }
+
+    pub fn record(&mut self, index: u64, block: &[u8]) {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.chain);
+        hasher.update(&index.to_be_bytes());
+        hasher.update(block);
+        self.chain = *hasher.finalize().as_bytes();
+
+        self.probes.push(ProbeRecord {
+            index,
+            commitment: self.chain,
+        });
+    }
+
+    pub fn finish(
+        self,
+        locator: &Locator,
+        body: &LocatorBody,
+        key: &KeyMaterial,
+        block_len: usize,
+        big_key_length: u64,
+    ) -> Transcript {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(KEY_COMMITMENT_DOMAIN);
+        hasher.update(&self.chain);
+        hasher.update(key);
+
+        Transcript {
+            locator: locator.clone(),
+            key_id: body.key_id,
+            security_level: body.security_level,
+            block_len,
+            big_key_length,
+            probes: self.probes,
+            key_commitment: *hasher.finalize().as_bytes(),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Known-answer test vectors for independent implementations of the KEM.
+//!
+//! Each vector fixes a BigKey (SHAKE256 output of `key_seed`), a locator, and the key it
+//! derives, for every supported hash / security level combination. The `vectors` binary prints
+//! them as JSON; `vectors/kem.json` in the repository holds the published set and is checked
+//! against this implementation by the tests below.
+
+use std::io;
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256, Sha3_512};
+
+use crate::generation::{BigKeyGenerator, Shake256Generator};
+use crate::kem::bigkey::probe_count;
+use crate::kem::distribution::DistributionDescriptor;
+use crate::kem::locator::{LocatorBody, LOCATOR_VERSION};
+use crate::kem::{BigKey, BigKeyKem, HashAlgorithm, Transcript};
+use crate::storage::{fingerprint, StorageReader, StorageWriter};
+use crate::traits::{
+    BigKeyError, BlockIndex, BlockSize, GeneratorId, KeyMaterial, SecurityLevel, BLOCK_1K,
+};
+
+const VECTOR_SEED: &[u8; 32] = b"big_fluffy_dise test vector seed";
+const VECTOR_KEY_LENGTH: usize = 64 * 1024;
+const VECTOR_LEAKAGE_TOLERANCE: f32 = 0.2;
+
+/// A single known-answer test. Byte strings are lowercase hex.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TestVector {
+    /// `sha3-256` or `sha3-512`
+    pub hash: String,
+    pub security_level: u32,
+    pub leakage_tolerance: f32,
+    pub block_size: usize,
+    /// SHAKE256 seed of the BigKey
+    pub key_seed: String,
+    pub key_length: u64,
+    /// BLAKE3 digest of the BigKey
+    pub key_fingerprint: String,
+    pub locator: String,
+    pub probe_indices: Vec<u64>,
+    pub derived_key: String,
+}
+
+/// Generate the test vectors for every supported hash and security level
+pub fn generate_test_vectors() -> Result<Vec<TestVector>, BigKeyError> {
+    let mut big_key = MemoryKey::new_writer(BLOCK_1K, "", VECTOR_KEY_LENGTH)?;
+    Shake256Generator::generate(
+        &mut big_key,
+        Some(VECTOR_SEED.to_vec().into_boxed_slice()),
+        VECTOR_KEY_LENGTH,
+    )?;
+    let key_fingerprint = to_hex(&fingerprint(&mut big_key)?);
+
+    let mut vectors = Vec::new();
+    for algorithm in [HashAlgorithm::Sha3_256, HashAlgorithm::Sha3_512].iter() {
+        for security_level in [SecurityLevel::Bits128, SecurityLevel::Bits256].iter() {
+            let (key, transcript) = match algorithm {
+                HashAlgorithm::Sha3_256 => derive(&mut big_key, *security_level, Sha3_256::new())?,
+                HashAlgorithm::Sha3_512 => derive(&mut big_key, *security_level, Sha3_512::new())?,
+            };
+
+            vectors.push(TestVector {
+                hash: hash_name(*algorithm).to_string(),
+                security_level: *security_level as u32,
+                leakage_tolerance: VECTOR_LEAKAGE_TOLERANCE,
+                block_size: BLOCK_1K.byte_len,
+                key_seed: to_hex(VECTOR_SEED),
+                key_length: VECTOR_KEY_LENGTH as u64,
+                key_fingerprint: key_fingerprint.clone(),
+                locator: to_hex(transcript.locator.as_bytes()),
+                probe_indices: transcript.probes.iter().map(|p| p.index).collect(),
+                derived_key: to_hex(&key),
+            });
+        }
+    }
+
+    Ok(vectors)
+}
+
+// Derive the key of a locator whose selector is fixed by the hash and security level
+fn derive<H: Digest>(
+    big_key: &mut MemoryKey,
+    security_level: SecurityLevel,
+    hasher: H,
+) -> Result<(KeyMaterial, Transcript), BigKeyError> {
+    let label = format!(
+        "selector {} {}",
+        H::output_size() * 8,
+        security_level as u32
+    );
+    let body = LocatorBody {
+        version: LOCATOR_VERSION,
+        key_id: 0,
+        security_level,
+        probe_count: probe_count(security_level, VECTOR_LEAKAGE_TOLERANCE, BLOCK_1K)? as u32,
+        selector: *blake3::hash(label.as_bytes()).as_bytes(),
+        distribution: DistributionDescriptor::uniform(),
+        hardening: None,
+        peer_bound: false,
+        probe_check: None,
+        app_id: None,
+        hash: None,
+        params: None,
+        tag: None,
+    };
+
+    let mut bk = BigKey::new_big_key(security_level, VECTOR_LEAKAGE_TOLERANCE, big_key, hasher);
+    bk.get_key_with_transcript(&body.encode())
+}
+
+fn hash_name(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::Sha3_256 => "sha3-256",
+        HashAlgorithm::Sha3_512 => "sha3-512",
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// BigKey held in memory, just large enough for test vectors
+struct MemoryKey {
+    block_size: BlockSize,
+    expected_length: u64,
+    data: Vec<u8>,
+}
+
+impl StorageWriter for MemoryKey {
+    type Options = str;
+
+    fn new_writer(
+        block_size: BlockSize,
+        _storage_location: &str,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        Ok(MemoryKey {
+            block_size,
+            expected_length: expected_size as u64,
+            data: Vec::with_capacity(expected_size),
+        })
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.expected_length
+    }
+
+    fn set_generator(&mut self, _generator: GeneratorId) {}
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        if self.data.len() as u64 != self.expected_length {
+            return Err(BigKeyError::FailedToWriteBigKey {
+                expected_len: self.expected_length as usize,
+                wrote_len: self.data.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Write for MemoryKey {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl StorageReader for MemoryKey {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        let block_len = self.block_size.byte_len;
+
+        if output.len() != block_len {
+            return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+                out_buf_len: output.len(),
+                block_len,
+            });
+        }
+        let offset = match index.byte_offset(self.block_size) {
+            Some(offset)
+                if offset
+                    .checked_add(block_len as u64)
+                    .is_some_and(|end| end <= self.data.len() as u64) =>
+            {
+                offset as usize
+            }
+            offset => {
+                return Err(BigKeyError::ProbeOffsetOutOfBounds {
+                    end_of_key: self.data.len(),
+                    offset: offset.unwrap_or(u64::MAX) as usize,
+                    probe_len: block_len,
+                })
+            }
+        };
+
+        output.copy_from_slice(&self.data[offset..offset + block_len]);
+        Ok(())
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kem::vectors::{generate_test_vectors, TestVector};
+
+    const PUBLISHED: &str = include_str!("../../vectors/kem.json");
+
+    #[test]
+    fn published_vectors_match_implementation() {
+        let published: Vec<TestVector> = serde_json::from_str(PUBLISHED).unwrap();
+        let generated = generate_test_vectors().unwrap();
+
+        assert_eq!(published.len(), 4);
+        assert_eq!(published, generated);
+    }
+
+    #[test]
+    fn vectors_cover_every_combination() {
+        let vectors = generate_test_vectors().unwrap();
+
+        for vector in vectors.iter() {
+            assert_eq!(vector.derived_key.len(), vector.security_level as usize / 4);
+            assert!(!vector.probe_indices.is_empty());
+        }
+        assert_ne!(vectors[0].derived_key, vectors[2].derived_key);
+    }
+} // mod test
@@ -0,0 +1,217 @@
+//! Derived keys wrapped under a key encryption key.
+//!
+//! `wrap_key()` encrypts a key with ChaCha20-Poly1305 under a 32 byte key encryption key (KEK),
+//! so it can be stored by systems that should not see it in the clear; `unwrap_key()` reverses
+//! it. A `context` (e.g. the name of the consuming service or the key's armored locator) is
+//! authenticated with the key: a blob only unwraps with the same context, so blobs cannot be
+//! swapped between uses.
+//!
+//! Layout: 16 byte magic `BFDISE-KWRAP-1\0\0`, 12 byte random nonce, then the encrypted key
+//! and 16 byte tag. The associated data is the magic, the nonce, the context length as a u32
+//! big-endian, and the context. Nonces are random, so wrap at most 2^32 keys under one KEK.
+//!
+//! Systems that already hold small conventional keys (e.g. 32 byte AES keys) can put them under
+//! BigKey protection without adopting the KEM flow: `BigKey::wrap_key()` wraps a key under a KEK
+//! expanded from a freshly derived BigKey subkey and returns the subkey's locator with the blob,
+//! and `BigKey::unwrap_key()` re-derives the subkey from the locator to unwrap it. The locator
+//! is the blob's context, so a blob only unwraps with the locator it was returned with.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use digest::Digest;
+
+use crate::kem::{BigKey, BigKeyKem};
+use crate::memory::wipe;
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, KeyMaterial, Locator};
+
+/// Length of key encryption keys
+pub const KEK_LEN: usize = 32;
+
+const MAGIC: &[u8; 16] = b"BFDISE-KWRAP-1\x00\x00";
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN;
+const TAG_LEN: usize = 16;
+const SUBKEY_KEK_CONTEXT: &str = "big_fluffy_dise 2024 subkey key encryption key v1";
+
+/// Encrypt `key` under `kek`, bound to `context`
+pub fn wrap_key(key: &[u8], kek: &[u8; KEK_LEN], context: &[u8]) -> Result<Vec<u8>, BigKeyError> {
+    let mut wrapped = Vec::with_capacity(HEADER_LEN + key.len() + TAG_LEN);
+    wrapped.extend_from_slice(MAGIC);
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)?;
+    wrapped.extend_from_slice(&nonce);
+
+    let aad = associated_data(&wrapped, context);
+    let sealed = ChaCha20Poly1305::new(Key::from_slice(kek))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: key,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| failed("encryption failed"))?;
+    wrapped.extend_from_slice(&sealed);
+    Ok(wrapped)
+}
+
+/// Decrypt a key wrapped by `wrap_key()`, failing with `KeyWrapFailed` unless `kek` and
+/// `context` are those it was wrapped with and the blob is intact
+pub fn unwrap_key(
+    wrapped: &[u8],
+    kek: &[u8; KEK_LEN],
+    context: &[u8],
+) -> Result<KeyMaterial, BigKeyError> {
+    if wrapped.len() < HEADER_LEN + TAG_LEN || &wrapped[..MAGIC.len()] != MAGIC {
+        return Err(failed("not a wrapped key"));
+    }
+    let header = &wrapped[..HEADER_LEN];
+
+    let aad = associated_data(header, context);
+    let key = ChaCha20Poly1305::new(Key::from_slice(kek))
+        .decrypt(
+            Nonce::from_slice(&header[MAGIC.len()..]),
+            Payload {
+                msg: &wrapped[HEADER_LEN..],
+                aad: &aad,
+            },
+        )
+        .map_err(|_| failed("wrong key encryption key or context, or damaged blob"))?;
+    Ok(key.into_boxed_slice())
+}
+
+impl<S: StorageReader, H: Digest> BigKey<S, H> {
+    /// Wrap the conventional key `key` under a fresh subkey of this BigKey, derived at its
+    /// security level. Returns the subkey's locator and the wrapped key; both are needed, with
+    /// the BigKey, to unwrap it.
+    pub fn wrap_key(&mut self, key: &[u8]) -> Result<(Locator, Vec<u8>), BigKeyError> {
+        let (locator, subkey) = self.new_key(self.security_level())?;
+        let mut kek = subkey_kek(subkey);
+        let wrapped = wrap_key(key, &kek, locator.as_bytes());
+        wipe(&mut kek);
+        Ok((locator, wrapped?))
+    }
+
+    /// Unwrap a key wrapped by `wrap_key()`, failing with `KeyWrapFailed` unless `locator` is
+    /// the one it was returned with and the blob is intact
+    pub fn unwrap_key(
+        &mut self,
+        locator: &Locator,
+        wrapped: &[u8],
+    ) -> Result<KeyMaterial, BigKeyError> {
+        let subkey = self.get_key(locator)?;
+        let mut kek = subkey_kek(subkey);
+        let key = unwrap_key(wrapped, &kek, locator.as_bytes());
+        wipe(&mut kek);
+        key
+    }
+}
+
+// Subkeys are as long as their security level; the KEK is always KEK_LEN bytes
+fn subkey_kek(mut subkey: KeyMaterial) -> [u8; KEK_LEN] {
+    let mut kek = [0u8; KEK_LEN];
+    blake3::derive_key(SUBKEY_KEK_CONTEXT, &subkey, &mut kek);
+    wipe(&mut subkey);
+    kek
+}
+
+fn associated_data(header: &[u8], context: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(HEADER_LEN + 4 + context.len());
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&(context.len() as u32).to_be_bytes());
+    aad.extend_from_slice(context);
+    aad
+}
+
+fn failed(reason: &'static str) -> BigKeyError {
+    BigKeyError::KeyWrapFailed { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+    use std::io::Cursor;
+
+    use digest::Digest;
+    use sha3::Sha3_256;
+
+    use crate::kem::{unwrap_key, wrap_key, BigKey, BigKeyKem};
+    use crate::storage::ReadSeekStorage;
+    use crate::traits::{BigKeyError, SecurityLevel, BLOCK_1K};
+
+    const KEK: &[u8; 32] = b"key encryption key for the tests";
+
+    #[test]
+    fn wrapped_keys_unwrap_only_in_context() {
+        let key = [0x5au8; 32];
+        let wrapped = wrap_key(&key, KEK, b"billing").unwrap();
+        assert_eq!(wrapped.len(), 16 + 12 + 32 + 16);
+        assert_eq!(&wrapped[..16], b"BFDISE-KWRAP-1\x00\x00");
+        assert_eq!(
+            &unwrap_key(&wrapped, KEK, b"billing").unwrap()[..],
+            &key[..]
+        );
+        assert_ne!(wrap_key(&key, KEK, b"billing").unwrap(), wrapped);
+
+        // the encoding is exactly as documented, so other implementations can unwrap it
+        let mut aad = wrapped[..28].to_vec();
+        aad.extend_from_slice(&7u32.to_be_bytes());
+        aad.extend_from_slice(b"billing");
+        let plain = ChaCha20Poly1305::new(Key::from_slice(KEK))
+            .decrypt(
+                Nonce::from_slice(&wrapped[16..28]),
+                Payload {
+                    msg: &wrapped[28..],
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        assert_eq!(plain, key);
+
+        let mut tampered = wrapped.clone();
+        tampered[40] ^= 1;
+        let other_kek = [0u8; 32];
+        for (blob, kek, context) in [
+            (&wrapped[..], KEK, &b"payroll"[..]),
+            (&wrapped[..], &other_kek, &b"billing"[..]),
+            (&tampered[..], KEK, &b"billing"[..]),
+            (&wrapped[..20], KEK, &b"billing"[..]),
+        ]
+        .iter()
+        {
+            match unwrap_key(blob, kek, context) {
+                Err(BigKeyError::KeyWrapFailed { .. }) => {}
+                _ => panic!("expected unwrapping to fail"),
+            }
+        }
+    }
+
+    #[test]
+    fn conventional_keys_wrap_under_subkeys() {
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 239) as u8).collect();
+        let storage = ReadSeekStorage::new(Cursor::new(contents), BLOCK_1K).unwrap();
+        let mut big_key =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new());
+
+        let aes_key = [0xa5u8; 32];
+        let (locator, wrapped) = big_key.wrap_key(&aes_key).unwrap();
+        assert_eq!(
+            &big_key.unwrap_key(&locator, &wrapped).unwrap()[..],
+            &aes_key[..]
+        );
+
+        // each wrap uses a fresh subkey, and blobs only unwrap with their own locator
+        let (other_locator, other_wrapped) = big_key.wrap_key(&aes_key).unwrap();
+        assert_ne!(other_locator, locator);
+        for (locator, blob) in [(&other_locator, &wrapped), (&locator, &other_wrapped)].iter() {
+            assert!(matches!(
+                big_key.unwrap_key(locator, blob),
+                Err(BigKeyError::KeyWrapFailed { .. })
+            ));
+        }
+    }
+} // mod test
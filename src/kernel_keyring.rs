@@ -0,0 +1,278 @@
+//! Derived keys loaded into the Linux kernel keyring.
+//!
+//! dm-crypt and fscrypt take their keys from the kernel keyring, by description. Loading a
+//! derived key there directly (`load_key()`) keeps the key bytes out of shell pipelines,
+//! command lines and temporary files: the key only exists in this process until `add_key(2)`
+//! returns, and is wiped right after.
+//!
+//! Keys consumed by the kernel should be of `KeyType::Logon`, which user space cannot read back.
+//! Their descriptions need a subsystem prefix, e.g. `cryptsetup:volume` for a dm-crypt table
+//! naming `:32:logon:cryptsetup:volume`, or `fscrypt:` followed by the hex key descriptor.
+//! `KeyType::User` keys can be read back by processes with access to the keyring, so only use
+//! them for consumers in user space.
+
+use std::ffi::CString;
+use std::io;
+
+use digest::Digest;
+
+use crate::kem::{BigKey, BigKeyKem};
+use crate::memory::wipe;
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, Locator, SecurityLevel};
+
+// Special keyring ids of keyctl(2), see linux/keyctl.h
+const KEY_SPEC_THREAD_KEYRING: i32 = -1;
+const KEY_SPEC_PROCESS_KEYRING: i32 = -2;
+const KEY_SPEC_SESSION_KEYRING: i32 = -3;
+const KEY_SPEC_USER_KEYRING: i32 = -4;
+const KEY_SPEC_USER_SESSION_KEYRING: i32 = -5;
+
+const KEYCTL_REVOKE: libc::c_long = 3;
+const KEYCTL_UNLINK: libc::c_long = 9;
+const KEYCTL_READ: libc::c_long = 11;
+
+/// Type of a key in the kernel keyring
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyType {
+    /// Readable by user space with access to the keyring
+    User,
+    /// Only usable by the kernel (dm-crypt, fscrypt), never readable by user space
+    Logon,
+}
+
+impl KeyType {
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyType::User => "user",
+            KeyType::Logon => "logon",
+        }
+    }
+}
+
+/// Keyring a key is linked into
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KernelKeyring {
+    Thread,
+    Process,
+    Session,
+    User,
+    UserSession,
+    /// A keyring by serial number
+    Serial(i32),
+}
+
+impl KernelKeyring {
+    fn id(self) -> i32 {
+        match self {
+            KernelKeyring::Thread => KEY_SPEC_THREAD_KEYRING,
+            KernelKeyring::Process => KEY_SPEC_PROCESS_KEYRING,
+            KernelKeyring::Session => KEY_SPEC_SESSION_KEYRING,
+            KernelKeyring::User => KEY_SPEC_USER_KEYRING,
+            KernelKeyring::UserSession => KEY_SPEC_USER_SESSION_KEYRING,
+            KernelKeyring::Serial(serial) => serial,
+        }
+    }
+}
+
+/// Serial number of a key in the kernel keyring
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeySerial(pub i32);
+
+/// Add `payload` to `keyring` as a key of `key_type` described by `description`, replacing a
+/// key of the same type and description already linked there
+pub fn add_key(
+    key_type: KeyType,
+    description: &str,
+    payload: &[u8],
+    keyring: KernelKeyring,
+) -> Result<KeySerial, BigKeyError> {
+    check_description(key_type, description)?;
+    let type_name = CString::new(key_type.name()).expect("key type names have no NUL");
+    let description = CString::new(description).map_err(|_| failed("add_key", invalid_input()))?;
+
+    // Safety: both strings are NUL terminated and `payload` is valid for its length, all of
+    // which outlive the call
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            type_name.as_ptr(),
+            description.as_ptr(),
+            payload.as_ptr() as *const libc::c_void,
+            payload.len(),
+            keyring.id() as libc::c_long,
+        )
+    };
+    if serial == -1 {
+        return Err(failed("add_key", io::Error::last_os_error()));
+    }
+    Ok(KeySerial(serial as i32))
+}
+
+/// Derive the key of `locator` and add it to `keyring`, see `add_key()`
+pub fn load_key<S, H>(
+    big_key: &mut BigKey<S, H>,
+    locator: &Locator,
+    key_type: KeyType,
+    description: &str,
+    keyring: KernelKeyring,
+) -> Result<KeySerial, BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    check_description(key_type, description)?;
+    let mut key = big_key.get_key(locator)?;
+    let serial = add_key(key_type, description, &key, keyring);
+    wipe(&mut key);
+    serial
+}
+
+/// Derive a fresh key at `security_level` and add it to `keyring`, returning the locator to
+/// load it again with `load_key()`
+pub fn load_new_key<S, H>(
+    big_key: &mut BigKey<S, H>,
+    security_level: SecurityLevel,
+    key_type: KeyType,
+    description: &str,
+    keyring: KernelKeyring,
+) -> Result<(Locator, KeySerial), BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    check_description(key_type, description)?;
+    let (locator, mut key) = big_key.new_key(security_level)?;
+    let serial = add_key(key_type, description, &key, keyring);
+    wipe(&mut key);
+    Ok((locator, serial?))
+}
+
+/// Revoke the key `serial`: it stays linked but any further use fails
+pub fn revoke_key(serial: KeySerial) -> Result<(), BigKeyError> {
+    keyctl("keyctl revoke", KEYCTL_REVOKE, serial.0 as libc::c_long, 0)
+}
+
+/// Unlink the key `serial` from `keyring`, destroying it once no keyring links it
+pub fn unlink_key(serial: KeySerial, keyring: KernelKeyring) -> Result<(), BigKeyError> {
+    keyctl(
+        "keyctl unlink",
+        KEYCTL_UNLINK,
+        serial.0 as libc::c_long,
+        keyring.id() as libc::c_long,
+    )
+}
+
+/// Payload of the `KeyType::User` key `serial`
+pub fn read_key(serial: KeySerial) -> Result<Vec<u8>, BigKeyError> {
+    let mut payload = vec![0u8; 4096];
+    // Safety: `payload` is valid for writes of its length for the duration of the call
+    let len = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            KEYCTL_READ,
+            serial.0 as libc::c_long,
+            payload.as_mut_ptr(),
+            payload.len(),
+        )
+    };
+    if len == -1 {
+        return Err(failed("keyctl read", io::Error::last_os_error()));
+    }
+    if len as usize > payload.len() {
+        wipe(&mut payload);
+        return Err(failed("keyctl read", invalid_input()));
+    }
+    payload.truncate(len as usize);
+    Ok(payload)
+}
+
+fn keyctl(
+    op: &'static str,
+    operation: libc::c_long,
+    arg2: libc::c_long,
+    arg3: libc::c_long,
+) -> Result<(), BigKeyError> {
+    // Safety: the operations used take integer arguments only
+    if unsafe { libc::syscall(libc::SYS_keyctl, operation, arg2, arg3) } == -1 {
+        return Err(failed(op, io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// The kernel refuses logon keys whose description lacks a "subsystem:" prefix; fail before
+// deriving rather than after
+fn check_description(key_type: KeyType, description: &str) -> Result<(), BigKeyError> {
+    let prefixed = matches!(description.find(':'), Some(pos) if pos > 0);
+    if description.is_empty() || (key_type == KeyType::Logon && !prefixed) {
+        return Err(BigKeyError::InvalidConfig {
+            reason: format!(
+                "invalid {} key description {:?}",
+                key_type.name(),
+                description
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn invalid_input() -> io::Error {
+    io::Error::from(io::ErrorKind::InvalidInput)
+}
+
+fn failed(op: &'static str, source: io::Error) -> BigKeyError {
+    BigKeyError::KernelKeyring { op, source }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::kernel_keyring::{
+        add_key, read_key, revoke_key, unlink_key, KernelKeyring, KeyType,
+    };
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn logon_descriptions_need_a_prefix() {
+        for description in ["", "volume", ":volume"] {
+            assert!(matches!(
+                add_key(
+                    KeyType::Logon,
+                    description,
+                    &[0u8; 32],
+                    KernelKeyring::Process
+                ),
+                Err(BigKeyError::InvalidConfig { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn user_keys_round_trip() {
+        let payload = [0x5au8; 32];
+        let serial = match add_key(
+            KeyType::User,
+            "big_fluffy_dise:test",
+            &payload,
+            KernelKeyring::Process,
+        ) {
+            Ok(serial) => serial,
+            // containers and seccomp profiles commonly deny the keyring
+            Err(BigKeyError::KernelKeyring { source, .. })
+                if matches!(
+                    source.raw_os_error(),
+                    Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EACCES)
+                ) || source.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                return
+            }
+            Err(e) => panic!("add_key failed: {}", e),
+        };
+
+        assert_eq!(read_key(serial).unwrap(), payload);
+        revoke_key(serial).unwrap();
+        assert!(read_key(serial).is_err());
+        unlink_key(serial, KernelKeyring::Process).unwrap();
+    }
+} // mod test
@@ -0,0 +1,296 @@
+//! Lease tokens bounding how much of a shared BigKey each tenant of a derivation server reads.
+//!
+//! Bounded retrieval only holds if nobody can probe the key without limit. A server shared by
+//! several tenants (such as `bigkey-agent`) can require every derivation to present a lease: a
+//! token the server's `LeaseIssuer` signed, naming the tenant, an expiry and a quota of blocks
+//! the tenant may have probed over the lease's lifetime. Tokens carry everything needed to
+//! check them, so the server keeps no per-client configuration; a `LeaseLedger` only counts the
+//! probes charged to each lease until it expires, then forgets it.
+//!
+//! The ledger lives in memory only. A server restarting forgets what each lease has spent, so a
+//! lease's quota bounds the blocks probed per server lifetime; keep lease lifetimes short where
+//! tenants can cause restarts.
+//!
+//! Tokens are Bech32m text with the prefix `bkls`, authenticated with keyed BLAKE3 under a key
+//! derived from the issuer's secret. Only holders of that secret can issue or extend leases.
+//! Tokens are bearer credentials: whoever holds one spends its quota.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::kem::{bech32m_decode, bech32m_encode};
+use crate::traits::BigKeyError;
+
+/// Human readable prefix of lease tokens
+pub const LEASE_HRP: &str = "bkls";
+
+/// Longest tenant name a lease can carry
+pub const MAX_TENANT_LEN: usize = 255;
+
+const LEASE_VERSION: u8 = 1;
+const LEASE_ID_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+const LEASE_CONTEXT: &str = "big_fluffy_dise 2024 lease token v1";
+
+/// A verified lease
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// Random id telling leases apart, also of the same tenant
+    pub id: [u8; LEASE_ID_LEN],
+    pub tenant: String,
+    /// Seconds since the Unix epoch after which the lease is void
+    pub expires_at: u64,
+    /// Blocks the lease permits probing, over all of its derivations
+    pub probe_quota: u64,
+}
+
+impl Lease {
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(34 + self.tenant.len());
+        body.push(LEASE_VERSION);
+        body.extend_from_slice(&self.id);
+        body.extend_from_slice(&self.expires_at.to_be_bytes());
+        body.extend_from_slice(&self.probe_quota.to_be_bytes());
+        body.push(self.tenant.len() as u8);
+        body.extend_from_slice(self.tenant.as_bytes());
+        body
+    }
+
+    fn decode(body: &[u8]) -> Result<Lease, BigKeyError> {
+        const FIXED_LEN: usize = 1 + LEASE_ID_LEN + 8 + 8 + 1;
+        if body.len() < FIXED_LEN || body[0] != LEASE_VERSION {
+            return Err(invalid("unknown lease format"));
+        }
+        let tenant_len = body[FIXED_LEN - 1] as usize;
+        if body.len() != FIXED_LEN + tenant_len {
+            return Err(invalid("wrong lease length"));
+        }
+        Ok(Lease {
+            id: body[1..17].try_into().unwrap(),
+            expires_at: u64::from_be_bytes(body[17..25].try_into().unwrap()),
+            probe_quota: u64::from_be_bytes(body[25..33].try_into().unwrap()),
+            tenant: String::from_utf8(body[FIXED_LEN..].to_vec())
+                .map_err(|_| invalid("tenant is not UTF-8"))?,
+        })
+    }
+}
+
+/// Issues and verifies the lease tokens of one server
+pub struct LeaseIssuer {
+    key: [u8; 32],
+}
+
+impl LeaseIssuer {
+    /// The issuer keyed by `secret`, which should hold at least 32 random bytes
+    pub fn new(secret: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        blake3::derive_key(LEASE_CONTEXT, secret, &mut key);
+        LeaseIssuer { key }
+    }
+
+    /// Issue a token for `tenant`, valid for `ttl` and `probe_quota` probed blocks
+    pub fn issue(
+        &self,
+        tenant: &str,
+        ttl: Duration,
+        probe_quota: u64,
+    ) -> Result<String, BigKeyError> {
+        if tenant.len() > MAX_TENANT_LEN {
+            return Err(invalid("tenant name too long"));
+        }
+        let mut id = [0u8; LEASE_ID_LEN];
+        getrandom::getrandom(&mut id)?;
+        let lease = Lease {
+            id,
+            tenant: tenant.to_string(),
+            expires_at: unix_now().saturating_add(ttl.as_secs()),
+            probe_quota,
+        };
+        let mut token = lease.body();
+        token.extend_from_slice(&self.tag(&token));
+        Ok(bech32m_encode(LEASE_HRP, &token))
+    }
+
+    /// The lease `token` grants, failing with `InvalidLease` if it was not issued by this
+    /// issuer, was altered or has expired
+    pub fn verify(&self, token: &str) -> Result<Lease, BigKeyError> {
+        self.verify_at(token, unix_now())
+    }
+
+    /// `verify()` as of `now`, in seconds since the Unix epoch
+    pub fn verify_at(&self, token: &str, now: u64) -> Result<Lease, BigKeyError> {
+        let (hrp, bytes) = bech32m_decode(token).map_err(|_| invalid("malformed token"))?;
+        if hrp != LEASE_HRP || bytes.len() < TAG_LEN {
+            return Err(invalid("not a lease token"));
+        }
+        let (body, tag) = bytes.split_at(bytes.len() - TAG_LEN);
+        // blake3::Hash compares in constant time
+        if blake3::Hash::from(self.tag(body))
+            != blake3::Hash::from(<[u8; TAG_LEN]>::try_from(tag).unwrap())
+        {
+            return Err(invalid("bad signature"));
+        }
+        let lease = Lease::decode(body)?;
+        if now >= lease.expires_at {
+            return Err(invalid("lease expired"));
+        }
+        Ok(lease)
+    }
+
+    fn tag(&self, body: &[u8]) -> [u8; TAG_LEN] {
+        *blake3::keyed_hash(&self.key, body).as_bytes()
+    }
+}
+
+/// Probes charged to unexpired leases
+#[derive(Default)]
+pub struct LeaseLedger {
+    // lease id -> (expiry, blocks probed)
+    used: Mutex<HashMap<[u8; LEASE_ID_LEN], (u64, u64)>>,
+}
+
+impl LeaseLedger {
+    pub fn new() -> Self {
+        LeaseLedger::default()
+    }
+
+    /// Charge `probes` blocks to `lease` before they are probed, failing with
+    /// `LeaseQuotaExceeded` (and charging nothing) if that would exceed its quota
+    pub fn charge(&self, lease: &Lease, probes: u64) -> Result<(), BigKeyError> {
+        self.charge_at(lease, probes, unix_now())
+    }
+
+    /// `charge()` as of `now`, in seconds since the Unix epoch
+    pub fn charge_at(&self, lease: &Lease, probes: u64, now: u64) -> Result<(), BigKeyError> {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        used.retain(|_, (expires_at, _)| now < *expires_at);
+
+        let entry = used.entry(lease.id).or_insert((lease.expires_at, 0));
+        match entry.1.checked_add(probes) {
+            Some(total) if total <= lease.probe_quota => {
+                entry.1 = total;
+                Ok(())
+            }
+            _ => Err(BigKeyError::LeaseQuotaExceeded {
+                quota: lease.probe_quota,
+                used: entry.1,
+                requested: probes,
+            }),
+        }
+    }
+
+    /// Blocks charged to the lease `id` so far, `0` once it has expired
+    pub fn used(&self, id: &[u8; LEASE_ID_LEN]) -> u64 {
+        let used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        used.get(id).map_or(0, |(_, probes)| *probes)
+    }
+
+    /// Number of leases tracked, i.e. charged and not yet expired at the last charge
+    pub fn len(&self) -> usize {
+        self.used.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn invalid(reason: &'static str) -> BigKeyError {
+    BigKeyError::InvalidLease { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::lease::{LeaseIssuer, LeaseLedger};
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn leases_verify_and_expire() {
+        let issuer = LeaseIssuer::new(b"0123456789abcdef0123456789abcdef");
+        let token = issuer
+            .issue("billing", Duration::from_secs(60), 1000)
+            .unwrap();
+        assert!(token.starts_with("bkls1"));
+
+        let lease = issuer.verify(&token).unwrap();
+        assert_eq!(
+            (lease.tenant.as_str(), lease.probe_quota),
+            ("billing", 1000)
+        );
+        assert!(issuer.verify_at(&token, lease.expires_at).is_err());
+        assert_ne!(
+            issuer
+                .verify(
+                    &issuer
+                        .issue("billing", Duration::from_secs(60), 1000)
+                        .unwrap()
+                )
+                .unwrap()
+                .id,
+            lease.id
+        );
+
+        // other issuers' and altered tokens are refused
+        let other = LeaseIssuer::new(b"another secret of at least 32 bytes");
+        match other.verify(&token) {
+            Err(BigKeyError::InvalidLease { reason }) => assert_eq!(reason, "bad signature"),
+            _ => panic!("expected a foreign lease to be refused"),
+        }
+        let mut forged = lease.clone();
+        forged.probe_quota = u64::MAX;
+        let mut bytes = forged.body();
+        bytes.extend_from_slice(&[0u8; 32]);
+        assert!(issuer
+            .verify(&crate::kem::bech32m_encode("bkls", &bytes))
+            .is_err());
+        assert!(issuer.verify("bklc1qqqqqq").is_err());
+    }
+
+    #[test]
+    fn ledgers_enforce_quotas_until_expiry() {
+        let issuer = LeaseIssuer::new(b"0123456789abcdef0123456789abcdef");
+        let lease = issuer
+            .verify(
+                &issuer
+                    .issue("search", Duration::from_secs(60), 100)
+                    .unwrap(),
+            )
+            .unwrap();
+        let ledger = LeaseLedger::new();
+
+        ledger.charge(&lease, 60).unwrap();
+        match ledger.charge(&lease, 41) {
+            Err(BigKeyError::LeaseQuotaExceeded {
+                quota,
+                used,
+                requested,
+            }) => {
+                assert_eq!((quota, used, requested), (100, 60, 41))
+            }
+            _ => panic!("expected the quota to be exceeded"),
+        }
+        ledger.charge(&lease, 40).unwrap();
+        assert_eq!(ledger.used(&lease.id), 100);
+
+        // expired leases are forgotten
+        let later = issuer
+            .verify(
+                &issuer
+                    .issue("search", Duration::from_secs(600), 10)
+                    .unwrap(),
+            )
+            .unwrap();
+        ledger.charge_at(&later, 1, lease.expires_at).unwrap();
+        assert_eq!((ledger.len(), ledger.used(&lease.id)), (1, 0));
+    }
+} // mod test
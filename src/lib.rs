@@ -1,5 +1,32 @@
+#[cfg(feature = "age-plugin")]
+pub mod age;
+#[cfg(all(unix, feature = "agent"))]
+pub mod agent;
+#[cfg(feature = "attestation")]
+pub mod attestation;
+pub mod config;
+pub mod conformance;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixtures;
 pub mod generation;
+pub mod health;
+pub mod kem;
+#[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+pub mod kernel_keyring;
+#[cfg(feature = "lease")]
+pub mod lease;
+pub mod memory;
+pub mod prelude;
 pub mod storage;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod traits;
-pub mod kem;
+#[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+pub mod volume;
+
+pub use helpers::{
+    derive, generate_key_file, open_big_key, open_big_key_with, rederive, DiskBigKey,
+    GenerateOptions, KeySource,
+};
 
+mod helpers;
@@ -1,38 +1,926 @@
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use big_fluffy_dise::generation::{BigKeyGenerator, Shake256Generator};
-use big_fluffy_dise::storage::{DiskStorage, StorageWriter, StorageReader};
-use big_fluffy_dise::traits::{BLOCK_4K, SecurityLevel};
-use big_fluffy_dise::kem::{BigKey, BigKeyKem};
-use sha3::{Sha3_256, Digest};
-use std::hash::Hasher;
+use big_fluffy_dise::config::Config;
+#[cfg(feature = "pkcs11")]
+use big_fluffy_dise::generation::Pkcs11SeedProvider;
+use big_fluffy_dise::generation::{
+    checkpoint_path, generate_interruptible, generate_pipelined_into, BigKeyGenerator, Checkpoint,
+    FixedSeedProvider, Generation, HwRngGenerator, OsSeedProvider, PipelineOptions, SeedProvider,
+    Shake256Generator, Shake256x4Generator,
+};
+use big_fluffy_dise::kem::{armor_locator, dearmor_locator, LocatorStore};
+use big_fluffy_dise::open_big_key_with;
+use big_fluffy_dise::storage::{
+    bench_probes, compare, evict_from_cache, migrate_block_size, pack, preflight,
+    recommend_block_size, spot_check, storage_class, BufferedStorageWriter, ContainerStorage,
+    DiskStorage, OperatorKey, ProbeBench, Provenance, StorageReader, StorageWriter, StreamWriter,
+    UsageTracker, DEFAULT_WRITE_BUFFER, STDOUT_LOCATION,
+};
+use big_fluffy_dise::traits::{
+    key_from_hex, BigKeyError, BlockSize, ByteSize, GeneratorId, KeyMaterial, BLOCKS,
+};
+#[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+use big_fluffy_dise::volume::{
+    provision_fscrypt, provision_luks, unlock_fscrypt, unlock_luks, VolumeKind,
+};
+
+use crate::cli::{interrupt_token, interrupted, Field, OutputFormat, Report};
+
+mod cli;
+
+// Exit status of commands stopped by SIGINT or SIGTERM, as for shells killed by SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+
+// Length of the seeds `generate` takes from its seed provider
+const SEED_LEN: usize = 64;
+
+// PIN `--seed-provider pkcs11:...` logs in to the token with, if set
+#[cfg(feature = "pkcs11")]
+const ENV_PKCS11_PIN: &str = "BFD_PKCS11_PIN";
+
+// Number of random blocks `info` probes when not specified
+const DEFAULT_SPOT_CHECKS: usize = 64;
+
+// Size of the scratch key `bench` probes when not specified
+const DEFAULT_BENCH_KEY_BYTES: u64 = 256 * 1024 * 1024;
+
+// Output `bench` generates per generator
+const BENCH_SHAKE256_BYTES: usize = 64 * 1024 * 1024;
+const BENCH_HWRNG_BYTES: usize = 1024 * 1024;
+
+// Hardware RNGs can be very slow, stop reading after this long
+const BENCH_HWRNG_TIME: Duration = Duration::from_secs(2);
+
+// Random probes `bench` times per block size
+const BENCH_PROBES: usize = 2000;
+
+fn usage(program: &str) {
+    println!(
+        "usage: {} [--output text|json] [--config FILE] COMMAND",
+        program
+    );
+    println!();
+    println!("commands:");
+    println!("    bench [DIR [SIZE]]");
+    println!("    compare KEYFILE|CONTAINER KEYFILE|CONTAINER");
+    println!("    gc STORE KEYFILE LABEL...");
+    println!(
+        "    generate [--verify|--resume] [--operator-key FILE] [--seed-provider PROVIDER] \
+         SIZE OUTFILE|-"
+    );
+    println!("    info [--raw] [KEYFILE [SPOT_CHECKS]]");
+    println!("    label STORE LABEL LOCATOR");
+    println!("    list STORE");
+    println!("    lookup STORE LABEL");
+    println!("    migrate BLOCK_BYTES KEYFILE OUTFILE");
+    println!("    pack KEYFILE CONTAINER");
+    #[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+    {
+        println!("    provision-volume luks|fscrypt DEVICE|DIR KEYFILE");
+        println!("    unlock-volume luks DEVICE KEYFILE NAME");
+        println!("    unlock-volume fscrypt DIR KEYFILE");
+    }
+    println!();
+    println!("--seed-provider is os (default, a fresh random seed), file:FILE (a hex seed) or");
+    println!("    pkcs11:MODULE:SLOT:LABEL (an HMAC key in a token, PIN from BFD_PKCS11_PIN)");
+    println!("settings not given on the command line are taken from --config and BFD_* variables");
+    println!("generating to - or a named pipe streams the raw key, reporting on stderr");
+    println!(
+        "an interrupted generate without --verify saves a checkpoint to continue with --resume"
+    );
+    println!("    and exits with status 130");
+    println!("--operator-key signs the key's provenance with the hex Ed25519 secret key in FILE");
+    println!("info --raw reads a KEYFILE without a header as raw key data");
+    println!("info exits with status 1 if a spot-checked block is all zeroes");
+    println!("SIZE is bytes or takes a unit, e.g. 512MiB (2^20) or 2TB (10^12)");
+    #[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+    println!("volume keys reach cryptsetup through a pipe and fscrypt through the kernel keyring");
+}
+
+// Remove `--name` from `args`, returning whether it was present
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|a| a == name) {
+        Some(pos) => {
+            args.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
+
+// Remove `--name VALUE` from `args`, returning VALUE
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<Option<String>> {
+    let pos = args.iter().position(|a| a == name)?;
+    let value = args.get(pos + 1).cloned();
+    args.drain(pos..(pos + 2).min(args.len()));
+    Some(value)
+}
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    let program = args.remove(0);
+    let mut format = OutputFormat::Text;
+
+    if let Some(name) = take_option(&mut args, "--output") {
+        match name.as_deref().and_then(OutputFormat::parse) {
+            Some(f) => format = f,
+            None => {
+                usage(&program);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let config = match take_option(&mut args, "--config") {
+        Some(Some(path)) => Config::load(path),
+        Some(None) => {
+            usage(&program);
+            std::process::exit(2);
+        }
+        None => Config::from_env(),
+    };
+
+    let verify = take_flag(&mut args, "--verify");
+    let resume = take_flag(&mut args, "--resume");
+    let raw = take_flag(&mut args, "--raw");
+    let operator_key = match take_option(&mut args, "--operator-key") {
+        Some(Some(path)) => Some(path),
+        Some(None) => {
+            usage(&program);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    let seed_provider = match take_option(&mut args, "--seed-provider") {
+        Some(Some(provider)) => Some(provider),
+        Some(None) => {
+            usage(&program);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    // keep the report out of a key streamed to stdout
+    let key_on_stdout = args.first().map(String::as_str) == Some("generate")
+        && args.get(2).map(String::as_str) == Some(STDOUT_LOCATION);
+
+    let result = config.and_then(|config| match args.first().map(String::as_str) {
+        Some("bench") if args.len() <= 3 => bench(&config, args.get(1), args.get(2)),
+        Some("compare") if args.len() == 3 => compare_keys(&config, &args[1], &args[2]),
+        Some("gc") if args.len() >= 4 => gc(&config, &args[1], &args[2], &args[3..]),
+        Some("generate") if args.len() == 3 => generate(
+            &config,
+            &args[1],
+            &args[2],
+            verify,
+            resume,
+            operator_key.as_deref(),
+            seed_provider.as_deref(),
+        ),
+        Some("info") if args.len() <= 3 => info(&config, args.get(1), args.get(2), raw),
+        Some("label") if args.len() == 4 => label(&args[1], &args[2], &args[3]),
+        Some("list") if args.len() == 2 => list(&args[1]),
+        Some("lookup") if args.len() == 3 => lookup(&args[1], &args[2]),
+        Some("migrate") if args.len() == 4 => migrate(&config, &args[1], &args[2], &args[3]),
+        Some("pack") if args.len() == 3 => pack_key(&config, &args[1], &args[2]),
+        #[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+        Some("provision-volume") if args.len() == 4 => {
+            provision_volume(&config, &args[1], &args[2], &args[3])
+        }
+        #[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+        Some("unlock-volume") if args.len() == 4 || args.len() == 5 => {
+            unlock_volume(&config, &args[1], &args[2], &args[3], args.get(4))
+        }
+        _ => {
+            usage(&program);
+            std::process::exit(2);
+        }
+    });
+
+    let failed = match result {
+        Ok(report) => {
+            match key_on_stdout {
+                true => eprint!("{}", report.render(format)),
+                false => print!("{}", report.render(format)),
+            }
+            report.failed()
+        }
+        Err(e) => {
+            let mut report = Report::new();
+            report.add("error", Field::Str(e.to_string()));
+            match format {
+                OutputFormat::Text => eprint!("{}", report.render(format)),
+                OutputFormat::Json => print!("{}", report.render(format)),
+            }
+            true
+        }
+    };
+    // a command stopped by SIGINT or SIGTERM did not finish, whatever it reported
+    if interrupted() {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn bench(
+    config: &Config,
+    dir: Option<&String>,
+    size: Option<&String>,
+) -> Result<Report, BigKeyError> {
+    // probe the storage the configured key lives on unless told otherwise
+    let dir = match (dir, &config.key_path) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(key_path)) => Path::new(key_path)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+        (None, None) => PathBuf::from("."),
+    };
+    let size_bytes = match size {
+        Some(size) => ByteSize::from_str(size)?.bytes(),
+        None => DEFAULT_BENCH_KEY_BYTES,
+    };
+    let mut report = Report::new();
+
+    let seed = OsSeedProvider.seed(32)?;
+    let started = Instant::now();
+    Shake256Generator::new(Some(seed.clone()))?.fill(&mut io::sink(), BENCH_SHAKE256_BYTES)?;
+    report.add(
+        "shake256_mb_per_s",
+        Field::Num(mb_per_s(BENCH_SHAKE256_BYTES as u64, started)),
+    );
+    let started = Instant::now();
+    Shake256x4Generator::new(Some(seed.clone()))?.fill(&mut io::sink(), BENCH_SHAKE256_BYTES)?;
+    report.add(
+        "shake256x4_mb_per_s",
+        Field::Num(mb_per_s(BENCH_SHAKE256_BYTES as u64, started)),
+    );
+
+    report.add(
+        "hwrng_kb_per_s",
+        match bench_hwrng() {
+            Ok(rate) => Field::Num(rate),
+            Err(_) => Field::Null,
+        },
+    );
+
+    // a scratch key next to where keys live, removed even if benchmarking fails
+    let scratch = dir.join(format!(".bfd-bench-{}", std::process::id()));
+    preflight(&scratch, size_bytes)?;
+    let benchmarked = bench_storage(&scratch, size_bytes, seed);
+    let _ = std::fs::remove_file(&scratch);
+    let (write_rate, evicted, results) = benchmarked?;
 
-    if args.len() != 3 {
-        println!("usage: {} LEN_MiB OUTFILE", args[0]);
-        return;
+    let per_block = |f: fn(&ProbeBench) -> u64| Field::List(results.iter().map(f).collect());
+    report
+        .add("dir", Field::Str(dir.display().to_string()))
+        .add("generate_to_disk_mb_per_s", Field::Num(write_rate))
+        .add("page_cache_evicted", Field::Bool(evicted))
+        .add("block_sizes", per_block(|r| r.block_size.byte_len as u64))
+        .add("probe_iops", per_block(|r| r.iops() as u64))
+        .add(
+            "probe_p50_us",
+            per_block(|r| r.latency.percentile(0.5).as_micros() as u64),
+        )
+        .add(
+            "probe_p99_us",
+            per_block(|r| r.latency.percentile(0.99).as_micros() as u64),
+        );
+
+    let recommended = recommend_block_size(&results);
+    let class = results
+        .iter()
+        .find(|r| Some(r.block_size.byte_len) == recommended.map(|b| b.byte_len))
+        .map(|r| storage_class(r.latency.percentile(0.99)));
+    report
+        .add(
+            "recommended_block_size",
+            recommended.map_or(Field::Null, |b| Field::Num(b.byte_len as u64)),
+        )
+        .add(
+            "storage_class",
+            class.map_or(Field::Null, |c| Field::Str(c.to_string())),
+        );
+    if class == Some("hdd") {
+        report.add(
+            "advice",
+            Field::Str("random reads are slow, keep BigKeys on NVMe storage".to_string()),
+        );
     }
 
-    let seed = b"0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef".to_vec();
-    let size_bytes = u64::from_str(&args[1]).expect("invalid length");
-    let key_file = &args[2];
+    Ok(report)
+}
 
-    let mut writer = DiskStorage::new_writer(BLOCK_4K, key_file, size_bytes as usize).unwrap();
-    Shake256Generator::generate(
-        &mut writer,
-        Some(seed.into_boxed_slice()),
+// Write a raw key of `size_bytes` to `scratch` and time random probes of it at every block
+// size, returning the write rate in MB/s, whether the key was evicted from the page cache
+// before probing, and the probe results
+fn bench_storage(
+    scratch: &Path,
+    size_bytes: u64,
+    seed: KeyMaterial,
+) -> Result<(u64, bool, Vec<ProbeBench>), BigKeyError> {
+    let scratch_path = scratch.to_string_lossy();
+    let started = Instant::now();
+    // raw, so that every block size can open it
+    let mut writer = BufferedStorageWriter::<StreamWriter>::new_writer(
+        BLOCKS[0],
+        &scratch_path,
         size_bytes as usize,
-    ).unwrap();
+    )?;
+    Shake256Generator::generate(&mut writer, Some(seed), size_bytes as usize)?;
+    drop(writer);
+    let mut evicted = evict_from_cache(scratch)?;
+    let write_rate = mb_per_s(size_bytes, started);
+
+    let mut results = Vec::new();
+    for block_size in BLOCKS.iter() {
+        if !size_bytes.is_multiple_of(block_size.byte_len as u64) {
+            continue;
+        }
+        // start each block size from a cold cache
+        evicted &= evict_from_cache(scratch)?;
+        let mut reader = DiskStorage::open(*block_size, &scratch_path)?;
+        results.push(bench_probes(&mut reader, BENCH_PROBES)?);
+    }
+
+    Ok((write_rate, evicted, results))
+}
+
+// Throughput of the hardware RNG in KB/s, reading for at most `BENCH_HWRNG_TIME`
+fn bench_hwrng() -> Result<u64, BigKeyError> {
+    let mut hwrng = HwRngGenerator::new(None)?;
+    let started = Instant::now();
+    let mut read = 0;
+    while read < BENCH_HWRNG_BYTES && started.elapsed() < BENCH_HWRNG_TIME {
+        hwrng.fill(&mut io::sink(), 4096)?;
+        read += 4096;
+    }
+    let secs = started.elapsed().as_secs_f64().max(1e-9);
+    Ok((read as f64 / secs / 1e3) as u64)
+}
+
+// Throughput in MB/s of `bytes` processed since `started`
+fn mb_per_s(bytes: u64, started: Instant) -> u64 {
+    let secs = started.elapsed().as_secs_f64().max(1e-9);
+    (bytes as f64 / secs / 1e6) as u64
+}
 
-    let reader = DiskStorage::open(BLOCK_4K, key_file).unwrap();
-    let mut h = Sha3_256::new();
+fn generate(
+    config: &Config,
+    size: &str,
+    key_file: &str,
+    verify: bool,
+    resume: bool,
+    operator_key: Option<&str>,
+    seed_provider: Option<&str>,
+) -> Result<Report, BigKeyError> {
+    // a resumed generation continues from the checkpointed generator state, and records the
+    // seed commitment saved with it
+    let seed = match resume {
+        true => None,
+        false => Some(open_seed_provider(seed_provider.unwrap_or("os"))?.seed(SEED_LEN)?),
+    };
+    let mut provenance = Provenance::new(seed.as_deref());
+    let operator = operator_key.map(read_operator_key).transpose()?;
+    let operator_public_key = operator.as_ref().map(OperatorKey::public_key).transpose()?;
+    let size = ByteSize::from_str(size)?;
+    let (size_bytes, len) = (size.bytes(), size.to_usize()?);
+    let streaming = is_stream(key_file);
+    if resume && (streaming || verify) {
+        return Err(BigKeyError::InvalidConfig {
+            reason: "--resume continues an interrupted OUTFILE, without --verify".to_string(),
+        });
+    }
+    if !streaming && !resume {
+        preflight(key_file, size_bytes)?;
+    }
 
-        let bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.20, &reader, &mut h);
-        match bk.new_key(SecurityLevel::Bits128) {
-            Ok(_) => {}
-            Err(_) => {}
+    let fingerprint = if streaming {
+        if verify {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "--verify needs an OUTFILE that can be read back".to_string(),
+            });
+        }
+        if operator.is_some() {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "--operator-key signs the key header, which streamed keys lack".to_string(),
+            });
+        }
+        let mut writer =
+            BufferedStorageWriter::<StreamWriter>::new_writer(config.block_size, key_file, len)?;
+        Shake256Generator::generate(&mut writer, seed, len)?;
+        writer.into_inner()?.fingerprint()
+    } else if verify {
+        let mut writer = DiskStorage::new_writer(config.block_size, key_file, len)?;
+        writer.set_provenance(provenance, operator);
+        let report = generate_pipelined_into::<Shake256Generator>(
+            writer,
+            key_file,
+            seed,
+            len,
+            &PipelineOptions::default(),
+        )?;
+        Some(report.fingerprint)
+    } else {
+        let checkpoint_file = checkpoint_path(key_file);
+        let (mut generator, mut writer, written) = if resume {
+            let checkpoint = Checkpoint::load(&checkpoint_file)?;
+            if checkpoint.key_length != size_bytes
+                || checkpoint.block_len != config.block_size.byte_len
+            {
+                return Err(BigKeyError::InvalidCheckpoint {
+                    reason: "checkpoint is of a key of another size or block size",
+                });
+            }
+            let writer =
+                DiskStorage::resume_writer(config.block_size, key_file, len, checkpoint.written)?;
+            provenance.seed_commitment = checkpoint.seed_commitment;
+            (checkpoint.generator()?, writer, checkpoint.written)
+        } else {
+            let generator = Shake256Generator::new(seed)?;
+            let writer = DiskStorage::new_writer(config.block_size, key_file, len)?;
+            (generator, writer, 0)
         };
-        println!("Done.");
+        let seed_commitment = provenance.seed_commitment;
+        writer.set_provenance(provenance, operator);
+        let mut writer = BufferedStorageWriter::with_capacity(DEFAULT_WRITE_BUFFER, writer);
+
+        match generate_interruptible(&mut generator, &mut writer, written, &interrupt_token())? {
+            Generation::Complete => {
+                if resume {
+                    std::fs::remove_file(&checkpoint_file)?;
+                }
+                writer.get_ref().header().and_then(|h| h.fingerprint)
+            }
+            Generation::Interrupted(mut checkpoint) => {
+                checkpoint.seed_commitment = seed_commitment;
+                checkpoint.save(&checkpoint_file)?;
+                eprintln!(
+                    "interrupted after {} of {} bytes, continue with: generate --resume {} {}",
+                    checkpoint.written, size_bytes, size_bytes, key_file
+                );
+                let mut report = Report::new();
+                report
+                    .add("file", Field::Str(key_file.to_string()))
+                    .add("size", Field::Num(size_bytes))
+                    .add("written", Field::Num(checkpoint.written))
+                    .add("checkpoint", Field::Str(checkpoint_file))
+                    .fail();
+                return Ok(report);
+            }
+        }
+    };
+
+    let mut report = Report::new();
+    report
+        .add("file", Field::Str(key_file.to_string()))
+        .add("size", Field::Num(size_bytes))
+        .add("verified", Field::Bool(verify))
+        .add("fingerprint", digest_field(fingerprint))
+        .add("operator", digest_field(operator_public_key));
+
+    Ok(report)
+}
+
+// The seed provider named by `--seed-provider`
+fn open_seed_provider(spec: &str) -> Result<Box<dyn SeedProvider>, BigKeyError> {
+    if spec == "os" {
+        return Ok(Box::new(OsSeedProvider));
+    }
+    if let Some(path) = spec.strip_prefix("file:") {
+        let seed = key_from_hex(std::fs::read_to_string(path)?.trim())?;
+        return Ok(Box::new(FixedSeedProvider::new(seed)));
+    }
+    if let Some(token) = spec.strip_prefix("pkcs11:") {
+        return open_pkcs11_provider(token);
+    }
+    Err(BigKeyError::InvalidConfig {
+        reason: format!("unknown seed provider {:?}", spec),
+    })
+}
+
+// `MODULE:SLOT:LABEL`, the module path may itself contain colons
+#[cfg(feature = "pkcs11")]
+fn open_pkcs11_provider(token: &str) -> Result<Box<dyn SeedProvider>, BigKeyError> {
+    let invalid = || BigKeyError::InvalidConfig {
+        reason: "a PKCS#11 seed provider is pkcs11:MODULE:SLOT:LABEL".to_string(),
+    };
+    let mut parts = token.rsplitn(3, ':');
+    let (label, slot, module) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(label), Some(slot), Some(module)) => (label, slot, module),
+        _ => return Err(invalid()),
+    };
+    let slot = u64::from_str(slot).map_err(|_| invalid())?;
+    let provider = Pkcs11SeedProvider::open(module, slot, label)?;
+    Ok(match std::env::var(ENV_PKCS11_PIN) {
+        Ok(pin) => Box::new(provider.with_pin(pin.as_bytes())),
+        Err(_) => Box::new(provider),
+    })
+}
+
+#[cfg(not(feature = "pkcs11"))]
+fn open_pkcs11_provider(_token: &str) -> Result<Box<dyn SeedProvider>, BigKeyError> {
+    Err(BigKeyError::InvalidConfig {
+        reason: "built without the `pkcs11` feature".to_string(),
+    })
+}
+
+// The operator signing key stored in hex in `path`
+fn read_operator_key(path: &str) -> Result<OperatorKey, BigKeyError> {
+    let secret = key_from_hex(std::fs::read_to_string(path)?.trim())?;
+    let secret = secret[..]
+        .try_into()
+        .map_err(|_| BigKeyError::InvalidConfig {
+            reason: "an operator key is 32 bytes in hex".to_string(),
+        })?;
+    Ok(OperatorKey::from_bytes(secret))
+}
+
+// Whether `key_file` is stdout or an existing pipe or device rather than a regular file
+fn is_stream(key_file: &str) -> bool {
+    key_file == STDOUT_LOCATION
+        || std::fs::metadata(key_file).is_ok_and(|m| !m.is_file() && !m.is_dir())
+}
+
+fn info(
+    config: &Config,
+    key_file: Option<&String>,
+    spot_checks: Option<&String>,
+    raw: bool,
+) -> Result<Report, BigKeyError> {
+    let key_file =
+        key_file
+            .or(config.key_path.as_ref())
+            .ok_or_else(|| BigKeyError::InvalidConfig {
+                reason: "no KEYFILE given and no key_path configured".to_string(),
+            })?;
+    let samples = match spot_checks {
+        Some(n) => usize::from_str(n).map_err(|_| BigKeyError::InvalidConfig {
+            reason: format!("invalid number of spot checks {:?}", n),
+        })?,
+        None => DEFAULT_SPOT_CHECKS,
+    };
+
+    let header = DiskStorage::read_header(key_file)?;
+    let block_size = match (&header, raw) {
+        (Some(header), _) => header.block_size()?,
+        (None, true) => config.block_size,
+        (None, false) => {
+            return Err(BigKeyError::InvalidConfig {
+                reason: format!(
+                    "{} has no key header; pass --raw to inspect it as a raw key",
+                    key_file
+                ),
+            })
+        }
+    };
+    let mut reader = DiskStorage::open(block_size, key_file)?;
+    let mut report = Report::new();
+
+    report
+        .add("file", Field::Str(key_file.to_string()))
+        .add("size", Field::Num(reader.big_key_length()))
+        .add("block_size", Field::Num(block_size.byte_len as u64))
+        .add(
+            "block_count",
+            Field::Num(reader.big_key_length() / block_size.byte_len as u64),
+        );
+
+    match &header {
+        Some(header) => {
+            report
+                .add("header_version", Field::Num(header.version as u64))
+                .add("generator", Field::Str(format!("{:?}", header.generator)))
+                .add("fingerprint", digest_field(header.fingerprint))
+                .add("merkle_root", digest_field(header.merkle_root))
+                .add("permuted", Field::Bool(header.permutation.is_some()))
+                .add(
+                    "hash_algorithm",
+                    header
+                        .hash_algorithm
+                        .map_or(Field::Null, |alg| Field::Str(format!("{:?}", alg))),
+                );
+            if let Some(provenance) = &header.provenance {
+                let operator = provenance.operator.as_ref().map(|op| op.public_key);
+                report
+                    .add("created_at", Field::Num(provenance.created_at))
+                    .add("host", Field::Str(provenance.host.clone()))
+                    .add("seed_commitment", digest_field(provenance.seed_commitment))
+                    .add("operator", digest_field(operator))
+                    .add(
+                        "operator_signature_valid",
+                        operator.map_or(Field::Null, |_| {
+                            Field::Bool(provenance.verify(header).is_ok())
+                        }),
+                    );
+            }
+        }
+        None => {
+            report.add("header_version", Field::Null);
+        }
+    }
+
+    if let Some(usage) = UsageTracker::read(key_file)? {
+        report
+            .add("derivations", Field::Num(usage.derivations))
+            .add("blocks_probed", Field::Num(usage.blocks_probed))
+            .add("distinct_blocks", Field::Num(usage.distinct_blocks))
+            .add("distinct_blocks_exact", Field::Bool(usage.exact));
+    }
+
+    let check = spot_check(&mut reader, samples)?;
+    let passed = check.passed();
+    report
+        .add("spot_checks", Field::Num(check.probed as u64))
+        .add("spot_check_passed", Field::Bool(passed))
+        .add("zero_blocks", Field::List(check.zero_blocks));
+    if !passed {
+        report.fail();
+    }
+
+    Ok(report)
+}
+
+fn label(store: &str, label: &str, locator: &str) -> Result<Report, BigKeyError> {
+    let locator = dearmor_locator(locator)?;
+    let mut store = LocatorStore::open(store)?;
+    store.label(label, &locator)?;
+    lookup_report(&store, label)
+}
+
+fn lookup(store: &str, label: &str) -> Result<Report, BigKeyError> {
+    lookup_report(&LocatorStore::open(store)?, label)
+}
+
+fn lookup_report(store: &LocatorStore, label: &str) -> Result<Report, BigKeyError> {
+    let labeled = store
+        .lookup(label)
+        .ok_or_else(|| BigKeyError::LocatorStoreFailed {
+            reason: format!("no locator labeled {:?}", label),
+        })?;
+    let mut report = Report::new();
+    report
+        .add("label", Field::Str(labeled.label.clone()))
+        .add(
+            "locator",
+            Field::Str(armor_locator(labeled.locator.as_bytes())),
+        )
+        .add("labeled_at", Field::Num(labeled.labeled_at));
+
+    Ok(report)
+}
+
+fn list(store: &str) -> Result<Report, BigKeyError> {
+    let store = LocatorStore::open(store)?;
+    let mut report = Report::new();
+    report
+        .add("count", Field::Num(store.len() as u64))
+        .add(
+            "labels",
+            Field::Strs(store.list().map(|l| l.label.clone()).collect()),
+        )
+        .add(
+            "locators",
+            Field::Strs(
+                store
+                    .list()
+                    .map(|l| armor_locator(l.locator.as_bytes()))
+                    .collect(),
+            ),
+        );
+
+    Ok(report)
+}
+
+fn compare_keys(config: &Config, key_a: &str, key_b: &str) -> Result<Report, BigKeyError> {
+    // two containers compare through their Merkle trees, anything else block by block
+    let comparison = match (open_container(key_a)?, open_container(key_b)?) {
+        (Some(mut a), Some(mut b)) => a.compare_trees(&mut b)?,
+        (a, b) => {
+            let mut a = open_any(config, key_a, a)?;
+            let mut b = open_any(config, key_b, b)?;
+            compare(&mut *a, &mut *b)?
+        }
+    };
+
+    let mut report = Report::new();
+    report
+        .add("a", Field::Str(key_a.to_string()))
+        .add("b", Field::Str(key_b.to_string()))
+        .add("blocks", Field::Num(comparison.blocks.get()))
+        .add("identical", Field::Bool(comparison.identical()))
+        .add(
+            "first_difference",
+            comparison
+                .first_difference
+                .map_or(Field::Null, |index| Field::Num(index.get())),
+        );
+
+    Ok(report)
+}
+
+// The container at `location`, `None` if it is not a container
+fn open_container(location: &str) -> Result<Option<ContainerStorage>, BigKeyError> {
+    match ContainerStorage::open(location) {
+        Ok(container) => Ok(Some(container)),
+        Err(BigKeyError::InvalidContainer { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// `container` if there is one, otherwise the key file at `key_file`
+fn open_any(
+    config: &Config,
+    key_file: &str,
+    container: Option<ContainerStorage>,
+) -> Result<Box<dyn StorageReader>, BigKeyError> {
+    if let Some(container) = container {
+        return Ok(Box::new(container));
+    }
+    let block_size = match DiskStorage::read_header(key_file)? {
+        Some(header) => header.block_size()?,
+        None => config.block_size,
+    };
+    Ok(Box::new(DiskStorage::open(block_size, key_file)?))
+}
+
+#[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+fn provision_volume(
+    config: &Config,
+    kind: &str,
+    target: &str,
+    key_file: &str,
+) -> Result<Report, BigKeyError> {
+    let mut big_key = open_big_key_with(key_file, config)?;
+    let mut report = Report::new();
+    report
+        .add("volume", Field::Str(target.to_string()))
+        .add("kind", Field::Str(kind.to_string()));
+
+    let locator = match volume_kind(kind)? {
+        VolumeKind::Luks => provision_luks(&mut big_key, target)?,
+        VolumeKind::Fscrypt => {
+            let (locator, serial) = provision_fscrypt(&mut big_key, Path::new(target))?;
+            report.add("key_serial", Field::Num(serial.0 as u64));
+            locator
+        }
+    };
+    report.add("locator", Field::Str(armor_locator(locator.as_bytes())));
+
+    Ok(report)
+}
+
+#[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+fn unlock_volume(
+    config: &Config,
+    kind: &str,
+    target: &str,
+    key_file: &str,
+    name: Option<&String>,
+) -> Result<Report, BigKeyError> {
+    let mut big_key = open_big_key_with(key_file, config)?;
+    let mut report = Report::new();
+    report.add("volume", Field::Str(target.to_string()));
+
+    match (volume_kind(kind)?, name) {
+        (VolumeKind::Luks, Some(name)) => {
+            unlock_luks(&mut big_key, target, name)?;
+            report.add("mapped", Field::Str(format!("/dev/mapper/{}", name)));
+        }
+        (VolumeKind::Fscrypt, None) => {
+            let serial = unlock_fscrypt(&mut big_key, Path::new(target))?;
+            report.add("key_serial", Field::Num(serial.0 as u64));
+        }
+        _ => {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "unlocking takes a NAME for luks volumes, and only for them".to_string(),
+            })
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(all(target_os = "linux", feature = "kernel-keyring"))]
+fn volume_kind(kind: &str) -> Result<VolumeKind, BigKeyError> {
+    VolumeKind::parse(kind).ok_or_else(|| BigKeyError::InvalidConfig {
+        reason: format!("unknown volume kind {:?}, expected luks or fscrypt", kind),
+    })
+}
+
+fn gc(
+    config: &Config,
+    store: &str,
+    key_file: &str,
+    labels: &[String],
+) -> Result<Report, BigKeyError> {
+    let mut big_key = open_big_key_with(key_file, config)?;
+    // return the retired derivations to the key's budget if its usage is tracked
+    if UsageTracker::read(key_file)?.is_some() {
+        let storage = big_key.storage();
+        let block_len = StorageReader::block_size(storage).byte_len as u64;
+        let block_count = storage.big_key_length() / block_len;
+        big_key = big_key.with_usage_tracker(UsageTracker::open(key_file, block_count)?);
+    }
+    let mut store = LocatorStore::open(store)?;
+    let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+    let gc = store.gc(&labels, &mut big_key)?;
+    big_key.save_usage()?;
+
+    let mut report = Report::new();
+    report
+        .add("retired", Field::Strs(gc.retired))
+        .add("live", Field::Num(gc.live as u64))
+        .add("retired_derivations", Field::Num(gc.retired_derivations))
+        .add(
+            "unreferenced_blocks",
+            Field::List(
+                gc.unreferenced_blocks
+                    .iter()
+                    .map(|index| index.get())
+                    .collect(),
+            ),
+        );
+
+    Ok(report)
+}
+
+fn migrate(
+    config: &Config,
+    block_bytes: &str,
+    key_file: &str,
+    out_file: &str,
+) -> Result<Report, BigKeyError> {
+    let new_block_size = usize::from_str(block_bytes)
+        .ok()
+        .and_then(BlockSize::from_byte_len)
+        .ok_or_else(|| BigKeyError::InvalidConfig {
+            reason: format!("unsupported block size {}", block_bytes),
+        })?;
+
+    let header = DiskStorage::read_header(key_file)?;
+    let block_size = match &header {
+        Some(header) => header.block_size()?,
+        None => config.block_size,
+    };
+    let mut reader = DiskStorage::open(block_size, key_file)?;
+    let mut writer = BufferedStorageWriter::<DiskStorage>::new_writer(
+        new_block_size,
+        out_file,
+        reader.big_key_length() as usize,
+    )?;
+    if let Some(header) = &header {
+        writer.set_generator(header.generator);
+    }
+
+    let migrated = migrate_block_size(&mut reader, &mut writer, new_block_size)?;
+    let fingerprint = writer.into_inner()?.header().and_then(|h| h.fingerprint);
+
+    let mut report = Report::new();
+    report
+        .add("file", Field::Str(out_file.to_string()))
+        .add("size", Field::Num(migrated))
+        .add("old_block_size", Field::Num(block_size.byte_len as u64))
+        .add("block_size", Field::Num(new_block_size.byte_len as u64))
+        .add("fingerprint", digest_field(fingerprint));
+
+    Ok(report)
+}
+
+fn pack_key(config: &Config, key_file: &str, container: &str) -> Result<Report, BigKeyError> {
+    let header = DiskStorage::read_header(key_file)?;
+    let block_size = match &header {
+        Some(header) => header.block_size()?,
+        None => config.block_size,
+    };
+    let generator = header.map_or(GeneratorId::Unknown, |h| h.generator);
+
+    let mut reader = DiskStorage::open(block_size, key_file)?;
+    let manifest = pack(&mut reader, generator, container)?;
+
+    let mut report = Report::new();
+    report
+        .add("file", Field::Str(container.to_string()))
+        .add("size", Field::Num(manifest.key_length))
+        .add("block_size", Field::Num(manifest.block_len as u64))
+        .add("fingerprint", digest_field(Some(manifest.fingerprint)))
+        .add("merkle_root", digest_field(manifest.merkle_root));
+
+    Ok(report)
+}
+
+fn digest_field(digest: Option<[u8; 32]>) -> Field {
+    match digest {
+        Some(digest) => Field::Str(hex(&digest)),
+        None => Field::Null,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
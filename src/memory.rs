@@ -0,0 +1,151 @@
+//! Memory locked into RAM.
+//!
+//! Pages holding BigKey blocks or key material can be written to swap, and from there outlive
+//! the process on disk. `LockedBuffer` asks the operating system to keep its pages resident
+//! (`mlock`). Locking is best effort: it fails when the process exceeds `RLIMIT_MEMLOCK` or on
+//! platforms without `mlock`, in which case the buffer still works but `is_locked()` is false.
+//! Buffers are zeroed before being freed either way.
+//!
+//! The library's own short-lived sensitive buffers (probed blocks during key derivation) are
+//! only locked after opting in with `set_lock_sensitive_buffers(true)`, as locking costs a
+//! system call per buffer. Seeds and hash digests are wiped after use regardless. Derived keys
+//! are returned as `KeyMaterial`; move them into locked memory with `LockedBuffer::from_key()`.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::traits::KeyMaterial;
+
+static LOCK_SENSITIVE_BUFFERS: AtomicBool = AtomicBool::new(false);
+
+/// Opt in to (or out of) locking the library's internal sensitive buffers, process wide
+pub fn set_lock_sensitive_buffers(enabled: bool) {
+    LOCK_SENSITIVE_BUFFERS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn lock_sensitive_buffers() -> bool {
+    LOCK_SENSITIVE_BUFFERS.load(Ordering::Relaxed)
+}
+
+/// Overwrite `buf` with zeros in a way the compiler will not optimise away
+pub fn wipe(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // Safety: `b` is a valid, aligned &mut u8; volatile keeps the wipe from being elided
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    std::sync::atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// Fixed size, zero-initialised byte buffer whose pages are locked into RAM if possible
+pub struct LockedBuffer {
+    data: Box<[u8]>,
+    locked: bool,
+}
+
+impl LockedBuffer {
+    pub fn new(len: usize) -> Self {
+        let data = vec![0u8; len].into_boxed_slice();
+        let locked = lock(&data);
+        LockedBuffer { data, locked }
+    }
+
+    /// Move `key` into locked memory, wiping the original
+    pub fn from_key(mut key: KeyMaterial) -> Self {
+        let mut buffer = LockedBuffer::new(key.len());
+        buffer.copy_from_slice(&key);
+        wipe(&mut key);
+        buffer
+    }
+
+    /// Buffer for internal sensitive data, locked if `lock_sensitive_buffers()`
+    pub(crate) fn sensitive(len: usize) -> Self {
+        if lock_sensitive_buffers() {
+            return LockedBuffer::new(len);
+        }
+        LockedBuffer {
+            data: vec![0u8; len].into_boxed_slice(),
+            locked: false,
+        }
+    }
+
+    /// Whether the operating system agreed to keep the buffer out of swap
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl Deref for LockedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for LockedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for LockedBuffer {
+    fn drop(&mut self) {
+        wipe(&mut self.data);
+        if self.locked {
+            unlock(&self.data);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lock(data: &[u8]) -> bool {
+    if data.is_empty() {
+        return false;
+    }
+    // Safety: the range is a live allocation owned by the caller
+    unsafe { libc::mlock(data.as_ptr() as *const libc::c_void, data.len()) == 0 }
+}
+
+#[cfg(unix)]
+fn unlock(data: &[u8]) {
+    // Safety: as for `lock()`, on the same range
+    unsafe {
+        libc::munlock(data.as_ptr() as *const libc::c_void, data.len());
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_data: &[u8]) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn unlock(_data: &[u8]) {}
+
+#[cfg(test)]
+mod test {
+    use crate::memory::{wipe, LockedBuffer};
+
+    #[test]
+    fn buffer_is_usable_whether_or_not_locked() {
+        let mut buf = LockedBuffer::new(4096);
+        assert_eq!(buf.len(), 4096);
+        assert!(buf.iter().all(|b| *b == 0));
+
+        buf[17] = 0x42;
+        assert_eq!(buf[17], 0x42);
+        assert!(!LockedBuffer::new(0).is_locked());
+    }
+
+    #[test]
+    fn keys_move_into_locked_memory() {
+        let key = vec![0x5au8; 32].into_boxed_slice();
+        let locked = LockedBuffer::from_key(key);
+        assert_eq!(&locked[..], &[0x5a; 32][..]);
+        assert!(!LockedBuffer::sensitive(32).is_locked() || super::lock_sensitive_buffers());
+
+        let mut secret = [0xffu8; 16];
+        wipe(&mut secret);
+        assert_eq!(secret, [0; 16]);
+    }
+} // mod test
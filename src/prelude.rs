@@ -0,0 +1,17 @@
+//! The types and functions most programs need, for glob import:
+//!
+//! ```
+//! use big_fluffy_dise::prelude::*;
+//! ```
+
+pub use crate::config::Config;
+pub use crate::generation::{BigKeyGenerator, Shake256Generator};
+pub use crate::helpers::{
+    derive, generate_key_file, open_big_key, open_big_key_with, rederive, DiskBigKey,
+    GenerateOptions, KeySource,
+};
+pub use crate::kem::{BigKey, BigKeyKem};
+pub use crate::storage::{DiskStorage, StorageReader, StorageWriter};
+pub use crate::traits::{
+    BigKeyError, BlockSize, ByteSize, KeyMaterial, Locator, SecurityLevel, BLOCK_1K, BLOCK_4K,
+};
@@ -0,0 +1,241 @@
+//! Statistical analysis of BigKey contents.
+//!
+//! A correctly generated key is indistinguishable from random bytes. `entropy_report()` reads
+//! the whole key in fixed size regions and measures how far each region is from that: its
+//! byte frequency distribution, Shannon entropy and how well it compresses. Blocks holding a
+//! single repeated byte (zeros from a failed write, `0xff` from erased flash) are reported as
+//! runs of block indices so just those blocks can be regenerated.
+
+use miniz_oxide::deflate::compress_to_vec;
+
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex};
+
+/// Regions compressing to less than this fraction of their size are flagged
+pub const MIN_COMPRESSION_RATIO: f64 = 0.9;
+
+// Blocks are compressed in chunks of this many bytes
+const COMPRESSION_CHUNK: usize = 64 * 1024;
+
+// Shorter constant blocks occur by chance in random keys
+const MIN_CONSTANT_BLOCK_LEN: usize = 8;
+
+/// Statistics of one region of a key
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionReport {
+    pub first_block: u64,
+    pub block_count: u64,
+    /// Shannon entropy of the byte distribution, 8.0 for uniform bytes (slightly less for small
+    /// regions)
+    pub entropy_bits_per_byte: f64,
+    /// Pearson's chi-squared statistic of byte frequencies against uniform (255 degrees of
+    /// freedom, so around 255 for random data)
+    pub chi_squared: f64,
+    /// Compressed size divided by size, about 1.0 for random data
+    pub compression_ratio: f64,
+    pub most_common_byte: u8,
+    /// Fraction of bytes equal to `most_common_byte`
+    pub most_common_frequency: f64,
+    /// Region compresses below `MIN_COMPRESSION_RATIO` or contains constant blocks
+    pub suspicious: bool,
+}
+
+/// Consecutive blocks each consisting of a single repeated byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstantRun {
+    pub first_block: u64,
+    pub block_count: u64,
+    pub byte: u8,
+}
+
+/// Per-region analysis of an entire key
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntropyReport {
+    pub regions: Vec<RegionReport>,
+    pub constant_runs: Vec<ConstantRun>,
+}
+
+impl EntropyReport {
+    pub fn passed(&self) -> bool {
+        self.regions.iter().all(|r| !r.suspicious)
+    }
+
+    /// Indices of every block in a constant run, i.e. the blocks to regenerate
+    pub fn constant_blocks(&self) -> impl Iterator<Item = u64> + '_ {
+        self.constant_runs
+            .iter()
+            .flat_map(|run| run.first_block..run.first_block + run.block_count)
+    }
+}
+
+/// Analyse every block of `reader` in regions of `region_blocks` blocks (the last region may be
+/// shorter)
+pub fn entropy_report<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    region_blocks: u64,
+) -> Result<EntropyReport, BigKeyError> {
+    if region_blocks == 0 {
+        return Err(BigKeyError::InvalidConfig {
+            reason: "entropy report regions must hold at least one block".to_string(),
+        });
+    }
+
+    let block_len = reader.block_size().byte_len;
+    let block_count = reader.big_key_length() / block_len as u64;
+    let mut block = vec![0u8; block_len];
+    let mut regions = Vec::new();
+    let mut constant_runs: Vec<ConstantRun> = Vec::new();
+
+    for first_block in (0..block_count).step_by(region_blocks as usize) {
+        let count = region_blocks.min(block_count - first_block);
+        let mut counts = [0u64; 256];
+        let mut chunk = Vec::with_capacity(COMPRESSION_CHUNK);
+        let mut compressed = 0usize;
+        let mut has_constant_block = false;
+
+        for index in first_block..first_block + count {
+            reader.probe(BlockIndex::new(index), &mut block)?;
+            for b in block.iter() {
+                counts[*b as usize] += 1;
+            }
+
+            if block_len >= MIN_CONSTANT_BLOCK_LEN && block.iter().all(|b| *b == block[0]) {
+                has_constant_block = true;
+                match constant_runs.last_mut() {
+                    Some(run)
+                        if run.first_block + run.block_count == index && run.byte == block[0] =>
+                    {
+                        run.block_count += 1
+                    }
+                    _ => constant_runs.push(ConstantRun {
+                        first_block: index,
+                        block_count: 1,
+                        byte: block[0],
+                    }),
+                }
+            }
+
+            chunk.extend_from_slice(&block);
+            if chunk.len() >= COMPRESSION_CHUNK {
+                compressed += compress_to_vec(&chunk, 1).len().min(chunk.len());
+                chunk.clear();
+            }
+        }
+        if !chunk.is_empty() {
+            compressed += compress_to_vec(&chunk, 1).len().min(chunk.len());
+        }
+
+        let total = count as f64 * block_len as f64;
+        let expected = total / 256.0;
+        let mut entropy = 0.0;
+        let mut chi_squared = 0.0;
+        for c in counts.iter() {
+            if *c > 0 {
+                let p = *c as f64 / total;
+                entropy -= p * p.log2();
+            }
+            chi_squared += (*c as f64 - expected).powi(2) / expected;
+        }
+        let (most_common_byte, most_common_count) = counts
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, c)| **c)
+            .map(|(b, c)| (b as u8, *c))
+            .unwrap();
+        let compression_ratio = compressed as f64 / total;
+
+        regions.push(RegionReport {
+            first_block,
+            block_count: count,
+            entropy_bits_per_byte: entropy,
+            chi_squared,
+            compression_ratio,
+            most_common_byte,
+            most_common_frequency: most_common_count as f64 / total,
+            suspicious: has_constant_block || compression_ratio < MIN_COMPRESSION_RATIO,
+        });
+    }
+
+    Ok(EntropyReport {
+        regions,
+        constant_runs,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::analysis::{entropy_report, ConstantRun};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageReader, StorageWriter};
+    use crate::traits::{BlockIndex, BLOCK_1K};
+
+    #[test]
+    fn random_key_passes() {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 256 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 256 * 1024).unwrap();
+
+        let mut reader = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let report = entropy_report(&mut reader, 64).unwrap();
+        assert_eq!(report.regions.len(), 4);
+        assert!(report.passed());
+        for region in report.regions.iter() {
+            assert!(region.entropy_bits_per_byte > 7.99);
+            assert!(region.compression_ratio > 0.99);
+            assert!(region.chi_squared < 400.0);
+        }
+    }
+
+    #[test]
+    fn constant_runs_are_located() {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut random = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 32 * 1024).unwrap();
+        Shake256Generator::generate(&mut random, Some(seed.into()), 32 * 1024).unwrap();
+
+        // rewrite as random blocks with zero blocks 10..13 and an erased block 20
+        let mut reader = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let damaged = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, damaged.to_str(), 32 * 1024).unwrap();
+        let mut block = [0u8; 1024];
+        for index in 0..32u64 {
+            reader.probe(BlockIndex::new(index), &mut block).unwrap();
+            match index {
+                10..=12 => writer.write_all(&[0u8; 1024]).unwrap(),
+                20 => writer.write_all(&[0xffu8; 1024]).unwrap(),
+                _ => writer.write_all(&block).unwrap(),
+            }
+        }
+        writer.finalize().unwrap();
+
+        let mut reader = DiskStorage::open(BLOCK_1K, damaged.to_str()).unwrap();
+        let report = entropy_report(&mut reader, 8).unwrap();
+        assert_eq!(
+            report.constant_runs,
+            vec![
+                ConstantRun {
+                    first_block: 10,
+                    block_count: 3,
+                    byte: 0
+                },
+                ConstantRun {
+                    first_block: 20,
+                    block_count: 1,
+                    byte: 0xff
+                },
+            ]
+        );
+        assert_eq!(
+            report.constant_blocks().collect::<Vec<_>>(),
+            vec![10, 11, 12, 20]
+        );
+
+        let flagged: Vec<bool> = report.regions.iter().map(|r| r.suspicious).collect();
+        assert_eq!(flagged, vec![false, true, true, false]);
+        assert!(report.regions[1].compression_ratio < 0.9);
+    }
+} // mod test
@@ -0,0 +1,189 @@
+//! Measuring how fast the storage holding a BigKey serves random probes.
+//!
+//! Key derivation makes many small random reads, so random read latency rather than sequential
+//! bandwidth decides how practical a BigKey is on given hardware. `bench_probes()` times
+//! uniformly random probes, and `recommend_block_size()` turns results for several block sizes
+//! into a configuration: the largest block that costs little more per probe than the smallest,
+//! since each probe then pulls more key material for the same seek.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::storage::latency::LatencyStats;
+use crate::storage::verify::random_u64;
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// A block size may cost up to this factor of the cheapest block size's mean latency and
+/// still be recommended
+const LATENCY_TOLERANCE: f64 = 1.5;
+
+/// Random probe performance at one block size
+#[derive(Debug, Clone)]
+pub struct ProbeBench {
+    pub block_size: BlockSize,
+    pub latency: LatencyStats,
+    /// Wall time of all probes
+    pub elapsed: Duration,
+}
+
+impl ProbeBench {
+    /// Probes per second
+    pub fn iops(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.latency.count() as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Time `probes` uniformly random probes of `reader`
+pub fn bench_probes<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    probes: usize,
+) -> Result<ProbeBench, BigKeyError> {
+    let block_size = reader.block_size();
+    let block_count = reader.big_key_length() / block_size.byte_len as u64;
+    let mut latency = LatencyStats::default();
+    let mut buf = vec![0u8; block_size.byte_len];
+
+    let started = Instant::now();
+    if block_count > 0 {
+        for _ in 0..probes {
+            let index = BlockIndex::new(random_u64()? % block_count);
+            let probe_started = Instant::now();
+            reader.probe(index, &mut buf)?;
+            latency.record(probe_started.elapsed());
+        }
+    }
+
+    Ok(ProbeBench {
+        block_size,
+        latency,
+        elapsed: started.elapsed(),
+    })
+}
+
+/// The largest benchmarked block size whose mean probe latency is within
+/// `LATENCY_TOLERANCE` of the fastest, `None` if nothing was benchmarked
+pub fn recommend_block_size(results: &[ProbeBench]) -> Option<BlockSize> {
+    let fastest = results.iter().map(|r| r.latency.mean()).min()?;
+    results
+        .iter()
+        .filter(|r| r.latency.mean().as_secs_f64() <= fastest.as_secs_f64() * LATENCY_TOLERANCE)
+        .map(|r| r.block_size)
+        .max_by_key(|b| b.byte_len)
+}
+
+/// Rough class of storage from its 99th percentile random probe latency
+pub fn storage_class(p99: Duration) -> &'static str {
+    if p99 < Duration::from_micros(500) {
+        "nvme"
+    } else if p99 < Duration::from_millis(3) {
+        "ssd"
+    } else {
+        "hdd"
+    }
+}
+
+/// Flush `path` to storage and evict it from the page cache, so that later probes measure the
+/// device rather than memory. Returns whether eviction is supported on this platform.
+pub fn evict_from_cache(path: impl AsRef<Path>) -> Result<bool, BigKeyError> {
+    let file = std::fs::File::open(path)?;
+    file.sync_all()?;
+    evict(&file)
+}
+
+#[cfg(target_os = "linux")]
+fn evict(file: &std::fs::File) -> Result<bool, BigKeyError> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: the descriptor is owned by `file` and stays open for the call
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(true),
+        errno => Err(std::io::Error::from_raw_os_error(errno).into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn evict(_file: &std::fs::File) -> Result<bool, BigKeyError> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use crate::storage::bench::{
+        bench_probes, evict_from_cache, recommend_block_size, storage_class, ProbeBench,
+    };
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{Fault, FaultyStorage, LatencyStats, ReadSeekStorage};
+    use crate::traits::{BigKeyError, BlockSize, BLOCK_1K, BLOCK_32, BLOCK_4K};
+
+    fn bench_with_mean(block_size: BlockSize, micros: u64) -> ProbeBench {
+        let mut latency = LatencyStats::default();
+        latency.record(Duration::from_micros(micros));
+        ProbeBench {
+            block_size,
+            latency,
+            elapsed: Duration::from_micros(micros),
+        }
+    }
+
+    #[test]
+    fn benchmarks_recommend_cheap_large_blocks() {
+        let mut reader = ReadSeekStorage::new(Cursor::new(vec![1u8; 64 * 1024]), BLOCK_1K).unwrap();
+        let bench = bench_probes(&mut reader, 100).unwrap();
+        assert_eq!(bench.latency.count(), 100);
+        assert!(bench.iops() > 0.0);
+
+        // NVMe: a 4 KiB read costs about the same as a 4 byte one
+        let nvme = [
+            bench_with_mean(BLOCK_32, 80),
+            bench_with_mean(BLOCK_1K, 85),
+            bench_with_mean(BLOCK_4K, 95),
+        ];
+        assert_eq!(recommend_block_size(&nvme).unwrap().byte_len, 4096);
+
+        // slow bus: larger blocks cost noticeably more per probe
+        let usb = [
+            bench_with_mean(BLOCK_32, 200),
+            bench_with_mean(BLOCK_1K, 280),
+            bench_with_mean(BLOCK_4K, 900),
+        ];
+        assert_eq!(recommend_block_size(&usb).unwrap().byte_len, 1024);
+        assert!(recommend_block_size(&[]).is_none());
+
+        assert_eq!(storage_class(Duration::from_micros(120)), "nvme");
+        assert_eq!(storage_class(Duration::from_millis(12)), "hdd");
+    }
+
+    #[test]
+    fn empty_failing_and_missing_storage() {
+        let mut reader = ReadSeekStorage::new(Cursor::new(vec![1u8; 64 * 1024]), BLOCK_1K).unwrap();
+        let bench = bench_probes(&mut reader, 0).unwrap();
+        assert_eq!(bench.latency.count(), 0);
+        assert_eq!(bench_with_mean(BLOCK_1K, 0).iops(), 0.0);
+
+        let mut faulty = FaultyStorage::new(reader);
+        faulty.inject_everywhere(Fault::Corrupted);
+        assert!(matches!(
+            bench_probes(&mut faulty, 10),
+            Err(BigKeyError::BlockCorrupted { .. })
+        ));
+
+        // class boundaries belong to the slower class
+        assert_eq!(storage_class(Duration::from_micros(500)), "ssd");
+        assert_eq!(storage_class(Duration::from_millis(3)), "hdd");
+
+        assert!(evict_from_cache("/nonexistent/big.key").is_err());
+        let tmp = tempfile();
+        std::fs::write(tmp.as_path(), [0u8; 4096]).unwrap();
+        assert_eq!(
+            evict_from_cache(tmp.as_path()).unwrap(),
+            cfg!(target_os = "linux")
+        );
+    }
+} // mod test
@@ -0,0 +1,197 @@
+use std::io;
+use std::io::Write;
+
+use crate::memory::wipe;
+use crate::storage::StorageWriter;
+use crate::traits::{BigKeyError, BlockSize, GeneratorId};
+
+/// Default write buffer size, large enough to reach NVMe sequential write throughput
+pub const DEFAULT_WRITE_BUFFER: usize = 8 * 1024 * 1024;
+
+/// Adapts any `StorageWriter` to coalesce the generator's block-sized writes into large writes
+/// to the underlying storage.
+///
+/// The buffer holds key material, so it is wiped whenever it is written out and when the
+/// writer is dropped. Like `BufWriter`, dropping the writer writes out what is still buffered,
+/// ignoring errors; call `flush()`, `finalize()` or `into_inner()` to see them.
+pub struct BufferedStorageWriter<W: StorageWriter> {
+    // `None` only once `into_inner()` has taken it
+    inner: Option<W>,
+    // allocated once at full capacity and never grown, so no copy of the key is left behind
+    // by a reallocation
+    buf: Vec<u8>,
+}
+
+impl<W: StorageWriter> BufferedStorageWriter<W> {
+    /// Buffer up to `capacity` bytes before writing to `inner`
+    pub fn with_capacity(capacity: usize, inner: W) -> Self {
+        BufferedStorageWriter {
+            inner: Some(inner),
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// The wrapped writer. Buffered data may not have reached it yet.
+    pub fn get_ref(&self) -> &W {
+        self.inner
+            .as_ref()
+            .expect("writer present until into_inner()")
+    }
+
+    /// The wrapped writer, e.g. to configure it before the key is written
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner
+            .as_mut()
+            .expect("writer present until into_inner()")
+    }
+
+    /// Flush buffered data and return the wrapped writer
+    pub fn into_inner(mut self) -> Result<W, BigKeyError> {
+        self.flush_buf()?;
+        Ok(self
+            .inner
+            .take()
+            .expect("writer present until into_inner()"))
+    }
+
+    // Write out and wipe the buffer. A failed write leaves the key incomplete whatever is
+    // retried, so the buffer is wiped either way.
+    fn flush_buf(&mut self) -> Result<(), io::Error> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let inner = self
+            .inner
+            .as_mut()
+            .expect("writer present until into_inner()");
+        let result = inner.write_all(&self.buf);
+        wipe(&mut self.buf);
+        self.buf.clear();
+        result
+    }
+}
+
+impl<W: StorageWriter> Drop for BufferedStorageWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush_buf();
+        }
+        wipe(&mut self.buf);
+    }
+}
+
+impl<W: StorageWriter> StorageWriter for BufferedStorageWriter<W> {
+    type Options = W::Options;
+
+    fn new_writer(
+        block_size: BlockSize,
+        storage_location: &W::Options,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        let inner = W::new_writer(block_size, storage_location, expected_size)?;
+        Ok(BufferedStorageWriter::with_capacity(
+            DEFAULT_WRITE_BUFFER,
+            inner,
+        ))
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.get_ref().block_size()
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.get_ref().expected_big_key_length()
+    }
+
+    fn set_generator(&mut self, generator: GeneratorId) {
+        self.get_mut().set_generator(generator)
+    }
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        self.flush()?;
+        self.get_mut().finalize()
+    }
+
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.get_ref().fingerprint()
+    }
+}
+
+impl<W: StorageWriter> Write for BufferedStorageWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.buf.len() + buf.len() > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.buf.capacity() {
+            // too large to be worth copying, write it straight through
+            return self.get_mut().write(buf);
+        }
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.flush_buf()?;
+        self.get_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::header::HEADER_LEN;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{BufferedStorageWriter, DiskStorage, StorageWriter};
+    use crate::traits::BLOCK_64;
+
+    #[test]
+    fn buffered_and_unbuffered_keys_match() {
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let (tmp1, tmp2) = (tempfile(), tempfile());
+
+        let mut plain = DiskStorage::new_writer(BLOCK_64, tmp1.to_str(), 64 * 100).unwrap();
+        Shake256Generator::generate(&mut plain, Some(seed.clone().into()), 64 * 100).unwrap();
+
+        let inner = DiskStorage::new_writer(BLOCK_64, tmp2.to_str(), 64 * 100).unwrap();
+        let mut buffered = BufferedStorageWriter::with_capacity(1000, inner);
+        Shake256Generator::generate(&mut buffered, Some(seed.into()), 64 * 100).unwrap();
+
+        let buffered = buffered.into_inner().unwrap();
+        assert_eq!(
+            plain.header().unwrap().fingerprint,
+            buffered.header().unwrap().fingerprint
+        );
+        assert_eq!(
+            std::fs::read(tmp1.as_path()).unwrap(),
+            std::fs::read(tmp2.as_path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn buffer_is_wiped_once_written_out() {
+        let tmp = tempfile();
+        let inner = DiskStorage::new_writer(BLOCK_64, tmp.to_str(), 64 * 10).unwrap();
+        let mut buffered = BufferedStorageWriter::with_capacity(256, inner);
+        buffered.write_all(&[0x5a; 100]).unwrap();
+        buffered.write_all(&[0xa5; 156]).unwrap();
+        assert_eq!(buffered.buf.len(), 256);
+
+        // a write that does not fit flushes first, one as large as the buffer bypasses it
+        buffered.write_all(&[0x3c; 1]).unwrap();
+        assert_eq!(buffered.buf, vec![0x3c]);
+        buffered.write_all(&[0xc3; 383]).unwrap();
+        assert!(buffered.buf.is_empty());
+        assert_eq!(buffered.buf.capacity(), 256);
+        // every byte the buffer held was overwritten with zeroes before it was cleared
+        // Safety: within the allocation, which `u8` leaves initialized after the wipe
+        let spare = unsafe { std::slice::from_raw_parts(buffered.buf.as_ptr(), 256) };
+        assert!(spare.iter().all(|b| *b == 0));
+
+        buffered.finalize().unwrap();
+        let key = std::fs::read(tmp.as_path()).unwrap();
+        let expected = [&[0x5a; 100][..], &[0xa5; 156], &[0x3c], &[0xc3; 383]].concat();
+        assert_eq!(&key[HEADER_LEN..], &expected[..]);
+    }
+} // mod test
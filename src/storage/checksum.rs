@@ -0,0 +1,158 @@
+//! Per-block checksums kept in a sidecar file next to the BigKey.
+//!
+//! Sidecar layout: 24 byte header (magic, block length u32, block count u64, all big-endian)
+//! followed by an 8 byte checksum per block, in block order. A block's checksum is the BLAKE3
+//! hash of its index and contents truncated to 8 bytes, so misplaced blocks are also detected.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::storage::util::StorageContext;
+use crate::traits::{BigKeyError, BlockIndex};
+
+pub const CHECKSUM_LEN: usize = 8;
+
+const MAGIC: &[u8; 12] = b"BFDISE-SUMS\x00";
+const SIDECAR_HEADER_LEN: u64 = 24;
+
+/// Location of the checksum sidecar for the BigKey at `storage_location`
+pub fn sidecar_path(storage_location: &str) -> String {
+    format!("{}.sums", storage_location)
+}
+
+/// Checksum of block `index` holding `block`
+pub fn block_checksum(index: u64, block: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&index.to_be_bytes());
+    hasher.update(block);
+    hasher.finalize().as_bytes()[..CHECKSUM_LEN]
+        .try_into()
+        .unwrap()
+}
+
+// Computes block checksums of a key as it is written, accepting writes of any size
+pub(crate) struct ChecksumWriter {
+    sidecar: BufWriter<File>,
+    block_len: usize,
+    block_count: u64,
+    pending: Vec<u8>,
+    next_index: u64,
+}
+
+impl ChecksumWriter {
+    pub fn create(
+        storage_location: &str,
+        block_len: usize,
+        block_count: u64,
+    ) -> Result<Self, BigKeyError> {
+        let path = sidecar_path(storage_location);
+        let mut sidecar = BufWriter::new(File::create(&path).context("create", &path)?);
+
+        sidecar
+            .write_all(MAGIC)
+            .and_then(|_| sidecar.write_all(&(block_len as u32).to_be_bytes()))
+            .and_then(|_| sidecar.write_all(&block_count.to_be_bytes()))
+            .context("write", &path)?;
+
+        Ok(ChecksumWriter {
+            sidecar,
+            block_len,
+            block_count,
+            pending: Vec::with_capacity(block_len),
+            next_index: 0,
+        })
+    }
+
+    pub fn update(&mut self, mut buf: &[u8]) -> Result<(), io::Error> {
+        while !buf.is_empty() {
+            let take = (self.block_len - self.pending.len()).min(buf.len());
+            self.pending.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
+
+            if self.pending.len() == self.block_len {
+                let checksum = block_checksum(self.next_index, &self.pending);
+                self.sidecar.write_all(&checksum)?;
+                self.pending.clear();
+                self.next_index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finalize(&mut self) -> Result<(), BigKeyError> {
+        if !self.pending.is_empty() || self.next_index != self.block_count {
+            return Err(BigKeyError::InvalidChecksumSidecar {
+                reason: "key length does not match checksummed blocks",
+            });
+        }
+        self.sidecar.flush()?;
+        Ok(())
+    }
+}
+
+// Verifies probed blocks against the sidecar
+pub(crate) struct ChecksumReader {
+    sidecar: File,
+    block_count: u64,
+}
+
+impl ChecksumReader {
+    pub fn open(storage_location: &str, block_len: usize) -> Result<Self, BigKeyError> {
+        let path = sidecar_path(storage_location);
+        let mut sidecar = File::open(&path).context("open", &path)?;
+        let mut header = [0u8; SIDECAR_HEADER_LEN as usize];
+        sidecar.read_exact(&mut header).context("read", &path)?;
+
+        if &header[0..12] != MAGIC {
+            return Err(BigKeyError::InvalidChecksumSidecar {
+                reason: "not a checksum sidecar",
+            });
+        }
+        if u32::from_be_bytes(header[12..16].try_into().unwrap()) as usize != block_len {
+            return Err(BigKeyError::InvalidChecksumSidecar {
+                reason: "block size differs from key",
+            });
+        }
+
+        let block_count = u64::from_be_bytes(header[16..24].try_into().unwrap());
+        let expected_len = SIDECAR_HEADER_LEN + block_count * CHECKSUM_LEN as u64;
+        if sidecar.metadata()?.len() != expected_len {
+            return Err(BigKeyError::InvalidChecksumSidecar {
+                reason: "truncated checksum sidecar",
+            });
+        }
+
+        Ok(ChecksumReader {
+            sidecar,
+            block_count,
+        })
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    pub fn verify(&mut self, index: BlockIndex, block: &[u8]) -> Result<(), BigKeyError> {
+        let index = index.get();
+        // bounded by the sidecar length, so the seek below cannot overflow
+        if index >= self.block_count {
+            return Err(BigKeyError::ProbeOffsetOutOfBounds {
+                end_of_key: (self.block_count * CHECKSUM_LEN as u64) as usize,
+                offset: index.saturating_mul(CHECKSUM_LEN as u64) as usize,
+                probe_len: CHECKSUM_LEN,
+            });
+        }
+        let mut expected = [0u8; CHECKSUM_LEN];
+        self.sidecar.seek(SeekFrom::Start(
+            SIDECAR_HEADER_LEN + index * CHECKSUM_LEN as u64,
+        ))?;
+        self.sidecar.read_exact(&mut expected)?;
+
+        if block_checksum(index, block) != expected {
+            return Err(BigKeyError::BlockCorrupted { index });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Confirm that two copies of a BigKey are identical.
+//!
+//! Keys replicated out of band (shipped on a drive, copied with `rsync`, restored from backup)
+//! never pass through `replicate()`, so nothing vouches for the copy. `compare()` streams both
+//! keys block by block and stops at the first block that differs; two containers can instead be
+//! compared through their stored Merkle trees with `ContainerStorage::compare_trees()`, reading
+//! a couple of hashes per tree level rather than the whole key.
+
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockCount, BlockIndex};
+
+/// Outcome of comparing two keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparison {
+    /// Blocks in each key
+    pub blocks: BlockCount,
+    /// First block whose contents differ, `None` if the keys are identical
+    pub first_difference: Option<BlockIndex>,
+}
+
+impl Comparison {
+    pub fn identical(&self) -> bool {
+        self.first_difference.is_none()
+    }
+}
+
+/// Compare `a` and `b` block by block. Keys of different block size or length fail with
+/// `ReplicaBlockSizeMismatch` / `ReplicaLengthMismatch` rather than comparing.
+pub fn compare<A, B>(a: &mut A, b: &mut B) -> Result<Comparison, BigKeyError>
+where
+    A: StorageReader + ?Sized,
+    B: StorageReader + ?Sized,
+{
+    let blocks = same_geometry(&*a, &*b)?;
+    let block_len = a.block_size().byte_len;
+    let (mut block_a, mut block_b) = (vec![0u8; block_len], vec![0u8; block_len]);
+
+    for index in blocks.indices() {
+        a.probe(index, &mut block_a)?;
+        b.probe(index, &mut block_b)?;
+        if block_a != block_b {
+            return Ok(Comparison {
+                blocks,
+                first_difference: Some(index),
+            });
+        }
+    }
+
+    Ok(Comparison {
+        blocks,
+        first_difference: None,
+    })
+}
+
+// Block count of `a` and `b`, which must have the same block size and length
+pub(crate) fn same_geometry<A, B>(a: &A, b: &B) -> Result<BlockCount, BigKeyError>
+where
+    A: StorageReader + ?Sized,
+    B: StorageReader + ?Sized,
+{
+    let (len_a, len_b) = (a.block_size().byte_len, b.block_size().byte_len);
+    if len_a != len_b {
+        return Err(BigKeyError::ReplicaBlockSizeMismatch {
+            source_len: len_a,
+            replica_len: len_b,
+        });
+    }
+    let (blocks_a, blocks_b) = (a.block_count(), b.block_count());
+    if blocks_a != blocks_b {
+        return Err(BigKeyError::ReplicaLengthMismatch {
+            source_blocks: blocks_a.get(),
+            replica_blocks: blocks_b.get(),
+        });
+    }
+    Ok(blocks_a)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+
+    use crate::storage::compare::compare;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, Fault, FaultyStorage, ReadSeekStorage};
+    use crate::traits::{BigKeyError, BlockIndex, BLOCK_1K, BLOCK_4K};
+
+    #[test]
+    fn first_differing_block_is_reported() {
+        let (a, b) = (tempfile(), tempfile());
+        let mut data: Vec<u8> = (0..8 * 1024).map(|i| (i % 251) as u8).collect();
+        File::create(a.as_path()).unwrap().write_all(&data).unwrap();
+        File::create(b.as_path()).unwrap().write_all(&data).unwrap();
+
+        let mut reader_a = DiskStorage::open(BLOCK_1K, a.to_str()).unwrap();
+        let mut reader_b = DiskStorage::open(BLOCK_1K, b.to_str()).unwrap();
+        let same = compare(&mut reader_a, &mut reader_b).unwrap();
+        assert!(same.identical());
+        assert_eq!(same.blocks.get(), 8);
+        drop(reader_b);
+
+        data[5 * 1024 + 17] ^= 1;
+        data[7 * 1024] ^= 1;
+        File::create(b.as_path()).unwrap().write_all(&data).unwrap();
+        let mut reader_b = DiskStorage::open(BLOCK_1K, b.to_str()).unwrap();
+        let differ = compare(&mut reader_a, &mut reader_b).unwrap();
+        assert_eq!(differ.first_difference, Some(BlockIndex::new(5)));
+        drop(reader_b);
+
+        File::create(b.as_path())
+            .unwrap()
+            .write_all(&data[..4096])
+            .unwrap();
+        let mut reader_b = DiskStorage::open(BLOCK_1K, b.to_str()).unwrap();
+        assert!(matches!(
+            compare(&mut reader_a, &mut reader_b),
+            Err(BigKeyError::ReplicaLengthMismatch {
+                source_blocks: 8,
+                replica_blocks: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn edge_blocks_geometry_and_failing_copies() {
+        let data: Vec<u8> = (0..8 * 1024).map(|i| (i % 251) as u8).collect();
+        let reader = |data: &[u8], block_size| {
+            ReadSeekStorage::new(Cursor::new(data.to_vec()), block_size).unwrap()
+        };
+
+        for &offset in [0, 8 * 1024 - 1].iter() {
+            let mut changed = data.clone();
+            changed[offset] ^= 0x80;
+            let comparison = compare(
+                &mut reader(&data, BLOCK_1K),
+                &mut reader(&changed, BLOCK_1K),
+            );
+            assert_eq!(
+                comparison.unwrap().first_difference,
+                Some(BlockIndex::new(offset as u64 / 1024))
+            );
+        }
+
+        assert!(matches!(
+            compare(&mut reader(&data, BLOCK_1K), &mut reader(&data, BLOCK_4K)),
+            Err(BigKeyError::ReplicaBlockSizeMismatch {
+                source_len: 1024,
+                replica_len: 4096
+            })
+        ));
+
+        let mut faulty = FaultyStorage::new(reader(&data, BLOCK_1K));
+        faulty.inject(3, Fault::Io(std::io::ErrorKind::UnexpectedEof));
+        assert!(compare(&mut reader(&data, BLOCK_1K), &mut faulty).is_err());
+    }
+} // mod test
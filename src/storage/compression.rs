@@ -0,0 +1,226 @@
+//! Compressed framing for metadata files kept next to a BigKey: manifests and snapshots of
+//! locator stores. Key data is never compressed; it is random and must stay where it is.
+//!
+//! A compressed file starts with a 64 byte frame header followed by the compressed payload:
+//!
+//! ```text
+//! magic "BFDISE-PACK\0" | codec u8 | 3 zero bytes | raw length u64 | packed length u64 | tag
+//! ```
+//!
+//! integers big-endian. The 32 byte tag is a BLAKE3 hash of the codec, the raw length and the
+//! uncompressed contents, checked after decompressing, so a damaged or truncated frame fails
+//! with `InvalidCompressedMetadata` instead of yielding altered metadata. Files without the
+//! magic are read as they are, so uncompressed metadata keeps working.
+
+use std::convert::TryInto;
+
+use crate::traits::BigKeyError;
+
+const MAGIC: &[u8; 12] = b"BFDISE-PACK\x00";
+const FRAME_HEADER_LEN: usize = 64;
+const TAG_CONTEXT: &str = "big_fluffy_dise 2024 compressed metadata v1";
+
+/// Largest uncompressed size a frame may claim, bounding the memory decompression takes
+pub const MAX_METADATA_LEN: u64 = 1 << 32;
+
+const CODEC_ZSTD: u8 = 1;
+
+/// How metadata files are written
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum MetadataCompression {
+    /// Plain, as before compression was supported
+    #[default]
+    None,
+    /// zstd at `level` (1 to 22, 3 is zstd's default)
+    Zstd { level: i32 },
+}
+
+/// `contents` as written with `compression`: unchanged for `MetadataCompression::None`, a
+/// compressed frame otherwise
+pub fn compress_metadata(
+    contents: &[u8],
+    compression: MetadataCompression,
+) -> Result<Vec<u8>, BigKeyError> {
+    let (codec, packed) = match compression {
+        MetadataCompression::None => return Ok(contents.to_vec()),
+        MetadataCompression::Zstd { level } => (CODEC_ZSTD, zstd_compress(contents, level)?),
+    };
+    if contents.len() as u64 > MAX_METADATA_LEN {
+        return Err(invalid("metadata too large to compress".to_string()));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + packed.len());
+    frame.extend_from_slice(MAGIC);
+    frame.extend_from_slice(&[codec, 0, 0, 0]);
+    frame.extend_from_slice(&(contents.len() as u64).to_be_bytes());
+    frame.extend_from_slice(&(packed.len() as u64).to_be_bytes());
+    frame.extend_from_slice(&tag(codec, contents));
+    frame.extend_from_slice(&packed);
+    Ok(frame)
+}
+
+/// The contents of a metadata file written by `compress_metadata()`, compressed or not
+pub fn decompress_metadata(bytes: &[u8]) -> Result<Vec<u8>, BigKeyError> {
+    let (contents, frame_len) = decompress_prefix(bytes)?;
+    match frame_len {
+        0 => Ok(bytes.to_vec()),
+        len if len == bytes.len() => Ok(contents),
+        _ => Err(invalid("data after compressed frame".to_string())),
+    }
+}
+
+/// Whether `bytes` start with a compressed frame
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// The contents of the compressed frame `bytes` start with and the frame's length, or nothing
+/// and 0 if they do not start with a frame
+pub(crate) fn decompress_prefix(bytes: &[u8]) -> Result<(Vec<u8>, usize), BigKeyError> {
+    if !is_compressed(bytes) {
+        return Ok((Vec::new(), 0));
+    }
+    if bytes.len() < FRAME_HEADER_LEN {
+        return Err(invalid("truncated frame header".to_string()));
+    }
+    let codec = bytes[12];
+    let raw_len = u64::from_be_bytes(bytes[16..24].try_into().unwrap());
+    let packed_len = u64::from_be_bytes(bytes[24..32].try_into().unwrap());
+    if bytes[13..16] != [0, 0, 0] {
+        return Err(invalid("unsupported frame flags".to_string()));
+    }
+    if raw_len > MAX_METADATA_LEN {
+        return Err(invalid(format!(
+            "frame claims {} bytes, more than the maximum of {}",
+            raw_len, MAX_METADATA_LEN
+        )));
+    }
+    let frame_len = (packed_len as usize)
+        .checked_add(FRAME_HEADER_LEN)
+        .filter(|&len| packed_len <= MAX_METADATA_LEN && len <= bytes.len())
+        .ok_or_else(|| invalid("truncated frame".to_string()))?;
+
+    let packed = &bytes[FRAME_HEADER_LEN..frame_len];
+    let contents = match codec {
+        CODEC_ZSTD => zstd_decompress(packed, raw_len as usize)?,
+        _ => return Err(invalid(format!("unknown codec {}", codec))),
+    };
+    if contents.len() as u64 != raw_len || tag(codec, &contents) != bytes[32..64] {
+        return Err(invalid("frame failed integrity check".to_string()));
+    }
+    Ok((contents, frame_len))
+}
+
+fn tag(codec: u8, contents: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key(TAG_CONTEXT);
+    hasher.update(&[codec]);
+    hasher.update(&(contents.len() as u64).to_be_bytes());
+    hasher.update(contents);
+    *hasher.finalize().as_bytes()
+}
+
+#[cfg(feature = "compression")]
+fn zstd_compress(contents: &[u8], level: i32) -> Result<Vec<u8>, BigKeyError> {
+    if !zstd::compression_level_range().contains(&level) {
+        return Err(BigKeyError::InvalidConfig {
+            reason: format!("zstd level {} out of range", level),
+        });
+    }
+    zstd::bulk::compress(contents, level).map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(feature = "compression")]
+fn zstd_decompress(packed: &[u8], raw_len: usize) -> Result<Vec<u8>, BigKeyError> {
+    zstd::bulk::decompress(packed, raw_len).map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(not(feature = "compression"))]
+fn zstd_compress(_contents: &[u8], _level: i32) -> Result<Vec<u8>, BigKeyError> {
+    Err(BigKeyError::InvalidConfig {
+        reason: "built without the `compression` feature".to_string(),
+    })
+}
+
+#[cfg(not(feature = "compression"))]
+fn zstd_decompress(_packed: &[u8], _raw_len: usize) -> Result<Vec<u8>, BigKeyError> {
+    Err(invalid(
+        "built without the `compression` feature".to_string(),
+    ))
+}
+
+fn invalid(reason: String) -> BigKeyError {
+    BigKeyError::InvalidCompressedMetadata { reason }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod test {
+    use crate::storage::compression::{decompress_prefix, FRAME_HEADER_LEN};
+    use crate::storage::{
+        compress_metadata, decompress_metadata, is_compressed, MetadataCompression,
+        MAX_METADATA_LEN,
+    };
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn frames_round_trip_and_detect_damage() {
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 17) as u8).collect();
+        let plain = compress_metadata(&contents, MetadataCompression::None).unwrap();
+        assert_eq!(plain, contents);
+        assert_eq!(decompress_metadata(&plain).unwrap(), contents);
+
+        let frame = compress_metadata(&contents, MetadataCompression::Zstd { level: 3 }).unwrap();
+        assert!(frame.len() < contents.len() / 10);
+        assert_eq!(decompress_metadata(&frame).unwrap(), contents);
+
+        // a flipped payload bit, a changed length and a truncation all fail
+        let mut damaged = vec![
+            frame.clone(),
+            frame.clone(),
+            frame[..frame.len() - 1].to_vec(),
+        ];
+        let last = frame.len() - 1;
+        damaged[0][last] ^= 0x01;
+        damaged[1][23] ^= 0x01;
+        for bytes in damaged {
+            assert!(matches!(
+                decompress_metadata(&bytes),
+                Err(BigKeyError::InvalidCompressedMetadata { .. })
+            ));
+        }
+        assert!(compress_metadata(&contents, MetadataCompression::Zstd { level: 99 }).is_err());
+    }
+
+    #[test]
+    fn hostile_frame_headers_are_refused() {
+        let empty = compress_metadata(&[], MetadataCompression::Zstd { level: 1 }).unwrap();
+        assert!(is_compressed(&empty));
+        assert!(decompress_metadata(&empty).unwrap().is_empty());
+        assert_eq!(decompress_prefix(b"plain toml").unwrap(), (Vec::new(), 0));
+
+        let frame = compress_metadata(b"[store]", MetadataCompression::Zstd { level: 3 }).unwrap();
+        let reason = |patch: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = frame.clone();
+            patch(&mut bytes);
+            match decompress_metadata(&bytes) {
+                Err(BigKeyError::InvalidCompressedMetadata { reason }) => reason,
+                _ => panic!("expected the frame to be refused"),
+            }
+        };
+        assert_eq!(
+            reason(&|b| b.truncate(FRAME_HEADER_LEN - 1)),
+            "truncated frame header"
+        );
+        assert_eq!(reason(&|b| b[13] = 1), "unsupported frame flags");
+        assert_eq!(reason(&|b| b[12] = 9), "unknown codec 9");
+        assert_eq!(reason(&|b| b.push(0)), "data after compressed frame");
+        // a decompression bomb claiming more than the maximum, and lengths that overflow
+        let too_long = (MAX_METADATA_LEN + 1).to_be_bytes();
+        assert!(reason(&|b| b[16..24].copy_from_slice(&too_long)).starts_with("frame claims"));
+        assert_eq!(reason(&|b| b[24..32].fill(0xff)), "truncated frame");
+        assert_eq!(
+            reason(&|b| b[24..32].copy_from_slice(&too_long)),
+            "truncated frame"
+        );
+        assert_eq!(reason(&|b| b[40] ^= 1), "frame failed integrity check");
+    }
+} // mod test
@@ -0,0 +1,737 @@
+//! Single-file BigKey containers.
+//!
+//! A key file travels with a manifest and possibly sidecars; a container holds all of it as one
+//! verifiable artifact. Sections are located through a fixed preamble, all integers big-endian:
+//!
+//! | offset | length | field                                            |
+//! |--------|--------|--------------------------------------------------|
+//! | 0      | 12     | magic `BFDISE-BOX\0\0`                           |
+//! | 12     | 2      | container format version (1)                     |
+//! | 14     | 2      | reserved, zero                                   |
+//! | 16     | 16     | manifest section offset and length               |
+//! | 32     | 16     | integrity section offset and length              |
+//! | 48     | 16     | key data section offset and length               |
+//!
+//! Key data starts at `HEADER_LEN` so blocks stay aligned. The manifest section is a `Manifest`
+//! in TOML whose `merkle_root` is the root of a BLAKE3 Merkle tree over the key blocks; the
+//! integrity section holds every level of that tree, leaves first, as 32 byte hashes. Leaves are
+//! `BLAKE3(0x00 || block)`, inner nodes `BLAKE3(0x01 || left || right)`, and a node without a
+//! sibling is carried up to the next level unchanged.
+//!
+//! `ContainerWriter` is a `StorageWriter`, so generators can write containers directly, and
+//! `pack()` copies an existing key into one. `ContainerStorage` probes a container, optionally
+//! checking each probed block against the Merkle root.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::storage::compare::{same_geometry, Comparison};
+use crate::storage::header::HEADER_LEN;
+use crate::storage::native::will_need;
+use crate::storage::util::{
+    block_offset, check_key_evenly_divisible, data_position, StorageContext,
+};
+use crate::storage::{Manifest, StorageReader, StorageWriter};
+use crate::traits::{BigKeyError, BlockIndex, BlockSize, GeneratorId};
+
+/// Container format version written by `ContainerWriter`
+pub const CONTAINER_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 12] = b"BFDISE-BOX\x00\x00";
+const PREAMBLE_LEN: usize = 64;
+const DATA_OFFSET: u64 = HEADER_LEN as u64;
+const HASH_LEN: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Section {
+    offset: u64,
+    length: u64,
+}
+
+/// Writes a BigKey into a new container
+pub struct ContainerWriter {
+    path: String,
+    block_size: BlockSize,
+    key_length: u64,
+    generator: GeneratorId,
+    data: BufWriter<File>,
+    leaves: BufWriter<File>,
+    pending: Vec<u8>,
+    fingerprint: blake3::Hasher,
+    written: u64,
+    manifest: Option<Manifest>,
+}
+
+impl ContainerWriter {
+    /// Manifest of the finalized container
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    // Hash every level above the leaves, returning the root
+    fn build_tree(&self, integrity_offset: u64) -> Result<[u8; 32], BigKeyError> {
+        let counts = level_counts(self.key_length / self.block_size.byte_len as u64);
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).open(&self.path)?);
+
+        let mut level_offset = integrity_offset;
+        writer.seek(SeekFrom::Start(integrity_offset + counts[0] * HASH_LEN))?;
+        reader.seek(SeekFrom::Start(integrity_offset))?;
+        let mut root = read_hash(&mut reader)?;
+
+        for pair in counts.windows(2) {
+            let (children, parents) = (pair[0], pair[1]);
+            // the children were written by the previous iteration
+            writer.flush()?;
+            reader.seek(SeekFrom::Start(level_offset))?;
+
+            for p in 0..parents {
+                let left = read_hash(&mut reader)?;
+                root = match 2 * p + 1 < children {
+                    true => node_hash(&left, &read_hash(&mut reader)?),
+                    false => left,
+                };
+                writer.write_all(&root)?;
+            }
+            level_offset += children * HASH_LEN;
+        }
+
+        writer.flush()?;
+        Ok(root)
+    }
+}
+
+impl StorageWriter for ContainerWriter {
+    type Options = str;
+
+    fn new_writer(
+        block_size: BlockSize,
+        storage_location: &str,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        if expected_size < block_size.byte_len {
+            return Err(BigKeyError::OutputLengthTooShort {
+                out_len: expected_size,
+                min_len: block_size.byte_len,
+            });
+        }
+        check_key_evenly_divisible(block_size, expected_size as u64)?;
+
+        let mut data =
+            BufWriter::new(File::create(storage_location).context("create", storage_location)?);
+        // the preamble is filled in by finalize()
+        data.write_all(&[0u8; HEADER_LEN])
+            .context("write", storage_location)?;
+
+        let mut leaves = OpenOptions::new()
+            .write(true)
+            .open(storage_location)
+            .context("open", storage_location)?;
+        leaves
+            .seek(SeekFrom::Start(DATA_OFFSET + expected_size as u64))
+            .context("seek", storage_location)?;
+
+        Ok(ContainerWriter {
+            path: storage_location.to_string(),
+            block_size,
+            key_length: expected_size as u64,
+            generator: GeneratorId::Unknown,
+            data,
+            leaves: BufWriter::new(leaves),
+            pending: Vec::with_capacity(block_size.byte_len),
+            fingerprint: blake3::Hasher::new(),
+            written: 0,
+            manifest: None,
+        })
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.key_length
+    }
+
+    fn set_generator(&mut self, generator: GeneratorId) {
+        self.generator = generator;
+    }
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        if self.written != self.key_length || !self.pending.is_empty() {
+            return Err(BigKeyError::FailedToWriteBigKey {
+                expected_len: self.key_length as usize,
+                wrote_len: self.written as usize,
+            });
+        }
+        self.data.flush().context("write", &self.path)?;
+        self.leaves.flush().context("write", &self.path)?;
+
+        let block_count = self.key_length / self.block_size.byte_len as u64;
+        let integrity = Section {
+            offset: DATA_OFFSET + self.key_length,
+            length: level_counts(block_count).iter().sum::<u64>() * HASH_LEN,
+        };
+        let root = self
+            .build_tree(integrity.offset)
+            .context("build merkle tree in", &self.path)?;
+
+        let manifest = Manifest {
+            generator: self.generator,
+            block_len: self.block_size.byte_len,
+            key_length: self.key_length,
+            fingerprint: *self.fingerprint.finalize().as_bytes(),
+            merkle_root: Some(root),
+        };
+        let manifest_toml = manifest.to_toml()?;
+        let sections = [
+            Section {
+                offset: integrity.offset + integrity.length,
+                length: manifest_toml.len() as u64,
+            },
+            integrity,
+            Section {
+                offset: DATA_OFFSET,
+                length: self.key_length,
+            },
+        ];
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .context("open", &self.path)?;
+        file.seek(SeekFrom::Start(sections[0].offset))
+            .and_then(|_| file.write_all(manifest_toml.as_bytes()))
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(&preamble(&sections)))
+            .and_then(|_| file.flush())
+            .context("write", &self.path)?;
+
+        self.manifest = Some(manifest);
+        Ok(())
+    }
+
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.manifest.as_ref().map(|manifest| manifest.fingerprint)
+    }
+}
+
+impl Write for ContainerWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.written + buf.len() as u64 > self.key_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the key",
+            ));
+        }
+        self.data.write_all(buf)?;
+        self.fingerprint.update(buf);
+        self.written += buf.len() as u64;
+
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let take = (self.block_size.byte_len - self.pending.len()).min(rest.len());
+            self.pending.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+
+            if self.pending.len() == self.block_size.byte_len {
+                self.leaves.write_all(&leaf_hash(&self.pending))?;
+                self.pending.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.data.flush()?;
+        self.leaves.flush()
+    }
+}
+
+/// Probes the key held in a container
+pub struct ContainerStorage {
+    file: File,
+    path: String,
+    manifest: Manifest,
+    block_size: BlockSize,
+    root: [u8; 32],
+    data: Section,
+    integrity: Section,
+    level_counts: Vec<u64>,
+    verify_probes: bool,
+}
+
+impl ContainerStorage {
+    /// Open the container at `storage_location` for probing
+    pub fn open(storage_location: &str) -> Result<ContainerStorage, BigKeyError> {
+        let mut file = File::open(storage_location).context("open", storage_location)?;
+        let file_length = file.metadata().context("stat", storage_location)?.len();
+
+        let mut preamble = [0u8; PREAMBLE_LEN];
+        file.read_exact(&mut preamble)
+            .context("read", storage_location)?;
+        if &preamble[0..12] != MAGIC {
+            return Err(invalid("not a BigKey container"));
+        }
+        if u16::from_be_bytes(preamble[12..14].try_into().unwrap()) > CONTAINER_VERSION {
+            return Err(invalid("unsupported container version"));
+        }
+        let section = |i: usize| Section {
+            offset: u64::from_be_bytes(preamble[16 + 16 * i..24 + 16 * i].try_into().unwrap()),
+            length: u64::from_be_bytes(preamble[24 + 16 * i..32 + 16 * i].try_into().unwrap()),
+        };
+        let (manifest_section, integrity, data) = (section(0), section(1), section(2));
+        for s in [manifest_section, integrity, data].iter() {
+            if s.offset
+                .checked_add(s.length)
+                .is_none_or(|end| end > file_length)
+            {
+                return Err(invalid("section extends past end of container"));
+            }
+        }
+
+        let mut manifest_toml = vec![0u8; manifest_section.length as usize];
+        file.seek(SeekFrom::Start(manifest_section.offset))
+            .and_then(|_| file.read_exact(&mut manifest_toml))
+            .context("read", storage_location)?;
+        let manifest = Manifest::from_toml(
+            std::str::from_utf8(&manifest_toml).map_err(|_| invalid("manifest is not UTF-8"))?,
+        )?;
+
+        let block_size = manifest.block_size()?;
+        let root = manifest
+            .merkle_root
+            .ok_or_else(|| invalid("manifest records no merkle root"))?;
+        if data.length != manifest.key_length {
+            return Err(invalid("key data length differs from manifest"));
+        }
+        check_key_evenly_divisible(block_size, data.length)?;
+        let level_counts = level_counts(data.length / block_size.byte_len as u64);
+        if integrity.length != level_counts.iter().sum::<u64>() * HASH_LEN {
+            return Err(invalid("integrity section does not match key length"));
+        }
+
+        Ok(ContainerStorage {
+            file,
+            path: storage_location.to_string(),
+            manifest,
+            block_size,
+            root,
+            data,
+            integrity,
+            level_counts,
+            verify_probes: false,
+        })
+    }
+
+    /// Open the container at `storage_location`, checking every probed block against the
+    /// manifest's Merkle root. Blocks that do not match fail the probe with `BlockCorrupted`.
+    pub fn open_verified(storage_location: &str) -> Result<ContainerStorage, BigKeyError> {
+        let mut storage = ContainerStorage::open(storage_location)?;
+        storage.verify_probes = true;
+        Ok(storage)
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Check the whole container: the key data against the manifest fingerprint and every
+    /// level of the Merkle tree against the data and the manifest's root
+    pub fn verify(&mut self) -> Result<(), BigKeyError> {
+        self.verify_all().context("verify", &self.path)
+    }
+
+    fn verify_all(&mut self) -> Result<(), BigKeyError> {
+        let block_len = self.block_size.byte_len;
+        let mut data = BufReader::new(File::open(&self.path)?);
+        let mut tree = BufReader::new(File::open(&self.path)?);
+        data.seek(SeekFrom::Start(self.data.offset))?;
+        tree.seek(SeekFrom::Start(self.integrity.offset))?;
+
+        let mut block = vec![0u8; block_len];
+        let mut fingerprint = blake3::Hasher::new();
+        for _ in 0..self.level_counts[0] {
+            data.read_exact(&mut block)?;
+            fingerprint.update(&block);
+            if read_hash(&mut tree)? != leaf_hash(&block) {
+                return Err(mismatch("leaf hash differs from key data"));
+            }
+        }
+        if *fingerprint.finalize().as_bytes() != self.manifest.fingerprint {
+            return Err(mismatch("fingerprint differs from manifest"));
+        }
+
+        // recompute each level from the one below, read one level behind `tree`
+        let mut children = BufReader::new(File::open(&self.path)?);
+        children.seek(SeekFrom::Start(self.integrity.offset))?;
+        let mut top = None;
+        for pair in self.level_counts.windows(2) {
+            let (child_count, parents) = (pair[0], pair[1]);
+            for p in 0..parents {
+                let left = read_hash(&mut children)?;
+                let expected = match 2 * p + 1 < child_count {
+                    true => node_hash(&left, &read_hash(&mut children)?),
+                    false => left,
+                };
+                let stored = read_hash(&mut tree)?;
+                if stored != expected {
+                    return Err(mismatch("merkle tree node differs from its children"));
+                }
+                top = Some(stored);
+            }
+        }
+        if self.level_counts.len() == 1 {
+            // a single leaf is the root
+            top = Some(read_hash(&mut children)?);
+        }
+
+        if top != Some(self.root) {
+            return Err(mismatch("merkle root differs from manifest"));
+        }
+        Ok(())
+    }
+
+    // Hash `block` at `index` up the tree to the root
+    fn verify_block(&mut self, index: u64, block: &[u8]) -> Result<(), BigKeyError> {
+        let mut hash = leaf_hash(block);
+        let mut position = index;
+        let mut level_offset = self.integrity.offset;
+
+        for &count in &self.level_counts[..self.level_counts.len() - 1] {
+            let sibling = position ^ 1;
+            if sibling < count {
+                self.file
+                    .seek(SeekFrom::Start(level_offset + sibling * HASH_LEN))?;
+                let sibling = read_hash(&mut self.file)?;
+                hash = match position & 1 {
+                    0 => node_hash(&hash, &sibling),
+                    _ => node_hash(&sibling, &hash),
+                };
+            }
+            level_offset += count * HASH_LEN;
+            position /= 2;
+        }
+
+        if hash != self.root {
+            return Err(BigKeyError::BlockCorrupted { index });
+        }
+        Ok(())
+    }
+
+    /// Compare the key with the one in `other` through their Merkle trees, descending from the
+    /// roots to the first leaf that differs. Only the trees are read, so blocks that no longer
+    /// match their own tree go unnoticed: `verify()` containers of unknown integrity first.
+    pub fn compare_trees(
+        &mut self,
+        other: &mut ContainerStorage,
+    ) -> Result<Comparison, BigKeyError> {
+        let blocks = same_geometry(&*self, &*other)?;
+        if self.root == other.root {
+            return Ok(Comparison {
+                blocks,
+                first_difference: None,
+            });
+        }
+
+        // a differing node has a differing child, the left one if both differ
+        let mut position = 0;
+        for level in (0..self.level_counts.len() - 1).rev() {
+            let left = 2 * position;
+            let right = left + 1;
+            position = if self.node(level, left)? != other.node(level, left)? {
+                left
+            } else if right < self.level_counts[level]
+                && self.node(level, right)? != other.node(level, right)?
+            {
+                right
+            } else {
+                return Err(mismatch("merkle tree node differs from its children"));
+            };
+        }
+
+        Ok(Comparison {
+            blocks,
+            first_difference: Some(BlockIndex::new(position)),
+        })
+    }
+
+    // Hash at `position` of tree level `level`, leaves being level 0
+    fn node(&mut self, level: usize, position: u64) -> Result<[u8; 32], BigKeyError> {
+        let preceding: u64 = self.level_counts[..level].iter().sum();
+        let offset = self.integrity.offset + (preceding + position) * HASH_LEN;
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| read_hash(&mut self.file))
+            .context_at("read", &self.path, offset)
+    }
+}
+
+impl StorageReader for ContainerStorage {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        if output.len() != self.block_size.byte_len {
+            return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+                out_buf_len: output.len(),
+                block_len: self.block_size.byte_len,
+            });
+        }
+
+        let offset = block_offset(index, self.block_size, self.data.length)?;
+        let position = data_position(self.data.offset, offset, self.block_size, self.data.length)?;
+
+        self.file
+            .seek(SeekFrom::Start(position))
+            .and_then(|_| self.file.read_exact(output))
+            .context_at("probe", &self.path, position)?;
+
+        if self.verify_probes {
+            self.verify_block(index.get(), output)
+                .context("verify", &self.path)?;
+        }
+        Ok(())
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        for &index in indices {
+            let offset = block_offset(index, self.block_size, self.data.length)?;
+            let position =
+                data_position(self.data.offset, offset, self.block_size, self.data.length)?;
+            will_need(&self.file, position, self.block_size.byte_len as u64);
+        }
+        Ok(())
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.data.length
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+}
+
+/// Copy the key in `reader` into a new container at `container_location`, recording
+/// `generator` in its manifest
+pub fn pack<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    generator: GeneratorId,
+    container_location: &str,
+) -> Result<Manifest, BigKeyError> {
+    let block_size = reader.block_size();
+    let mut writer = ContainerWriter::new_writer(
+        block_size,
+        container_location,
+        reader.big_key_length() as usize,
+    )?;
+    writer.set_generator(generator);
+
+    let mut block = vec![0u8; block_size.byte_len];
+    for index in reader.block_count().indices() {
+        reader.probe(index, &mut block)?;
+        writer
+            .write_all(&block)
+            .context("write", container_location)?;
+    }
+    writer.finalize()?;
+
+    Ok(writer.manifest.take().unwrap())
+}
+
+// Number of hashes in each level of the Merkle tree over `block_count` blocks, leaves first
+fn level_counts(block_count: u64) -> Vec<u64> {
+    let mut counts = vec![block_count];
+    while counts[counts.len() - 1] > 1 {
+        counts.push(counts[counts.len() - 1].div_ceil(2));
+    }
+    counts
+}
+
+/// Merkle root of a container's tree computed over a stream of blocks, holding one hash per
+/// level instead of the whole tree
+#[derive(Default)]
+pub(crate) struct MerkleAccumulator {
+    // roots of complete subtrees with their heights, heights decreasing
+    stack: Vec<([u8; 32], u32)>,
+}
+
+impl MerkleAccumulator {
+    pub(crate) fn push_block(&mut self, block: &[u8]) {
+        let mut node = (leaf_hash(block), 0);
+        while let Some(&(left, height)) = self.stack.last() {
+            if height != node.1 {
+                break;
+            }
+            self.stack.pop();
+            node = (node_hash(&left, &node.0), height + 1);
+        }
+        self.stack.push(node);
+    }
+
+    /// Root of the tree over the blocks pushed, `None` if there were none. Nodes left without
+    /// a sibling are carried up, so the partial subtrees fold together from the right.
+    pub(crate) fn root(&self) -> Option<[u8; 32]> {
+        let mut nodes = self.stack.iter().rev().map(|(hash, _)| *hash);
+        let last = nodes.next()?;
+        Some(nodes.fold(last, |right, left| node_hash(&left, &right)))
+    }
+}
+
+fn leaf_hash(block: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0x00]);
+    hasher.update(block);
+    *hasher.finalize().as_bytes()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+fn read_hash(input: &mut impl Read) -> Result<[u8; 32], io::Error> {
+    let mut hash = [0u8; 32];
+    input.read_exact(&mut hash)?;
+    Ok(hash)
+}
+
+fn preamble(sections: &[Section; 3]) -> [u8; PREAMBLE_LEN] {
+    let mut out = [0u8; PREAMBLE_LEN];
+    out[0..12].copy_from_slice(MAGIC);
+    out[12..14].copy_from_slice(&CONTAINER_VERSION.to_be_bytes());
+    for (i, section) in sections.iter().enumerate() {
+        out[16 + 16 * i..24 + 16 * i].copy_from_slice(&section.offset.to_be_bytes());
+        out[24 + 16 * i..32 + 16 * i].copy_from_slice(&section.length.to_be_bytes());
+    }
+    out
+}
+
+fn invalid(reason: &'static str) -> BigKeyError {
+    BigKeyError::InvalidContainer { reason }
+}
+
+fn mismatch(stage: &'static str) -> BigKeyError {
+    BigKeyError::VerificationFailed { stage }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::container::{level_counts, pack, ContainerStorage, ContainerWriter};
+    use crate::storage::header::HEADER_LEN;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{compare, fingerprint, DiskStorage, StorageReader, StorageWriter};
+    use crate::traits::{BigKeyError, BlockIndex, GeneratorId, BLOCK_1K};
+
+    #[test]
+    fn tree_levels_carry_odd_nodes() {
+        assert_eq!(level_counts(1), vec![1]);
+        assert_eq!(level_counts(5), vec![5, 3, 2, 1]);
+        assert_eq!(level_counts(8), vec![8, 4, 2, 1]);
+    }
+
+    #[test]
+    fn generated_container_verifies_and_probes() {
+        // 13 blocks exercises nodes without siblings on several levels
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = ContainerWriter::new_writer(BLOCK_1K, tmp.to_str(), 13 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 13 * 1024).unwrap();
+        let manifest = writer.manifest().unwrap().clone();
+        assert_eq!(manifest.generator, GeneratorId::Shake256);
+
+        let mut container = ContainerStorage::open_verified(tmp.to_str()).unwrap();
+        assert_eq!(container.manifest(), &manifest);
+        assert_eq!(container.big_key_length(), 13 * 1024);
+        container.verify().unwrap();
+        assert_eq!(fingerprint(&mut container).unwrap(), manifest.fingerprint);
+
+        {
+            let mut file = OpenOptions::new().write(true).open(tmp.as_path()).unwrap();
+            file.seek(SeekFrom::Start(HEADER_LEN as u64 + 12 * 1024 + 7))
+                .unwrap();
+            file.write_all(&[0xff]).unwrap();
+        }
+
+        let mut block = [0u8; 1024];
+        container.probe(BlockIndex::new(11), &mut block).unwrap();
+        match container.probe(BlockIndex::new(12), &mut block) {
+            Err(BigKeyError::BlockCorrupted { index }) => assert_eq!(index, 12),
+            _ => panic!("expected corrupted block to fail verification"),
+        }
+        match container.verify() {
+            Err(BigKeyError::VerificationFailed { .. }) => {}
+            _ => panic!("expected corrupted container to fail verification"),
+        }
+
+        // unverified probes return whatever is stored
+        let mut container = ContainerStorage::open(tmp.to_str()).unwrap();
+        container.probe(BlockIndex::new(12), &mut block).unwrap();
+    }
+
+    #[test]
+    fn packed_key_matches_original() {
+        let key = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, key.to_str(), 16 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 16 * 1024).unwrap();
+        let header = writer.header().unwrap().clone();
+
+        let tmp = tempfile();
+        let mut reader = DiskStorage::open(BLOCK_1K, key.to_str()).unwrap();
+        let manifest = pack(&mut reader, header.generator, tmp.to_str()).unwrap();
+        assert_eq!(Some(manifest.fingerprint), header.fingerprint);
+
+        let mut container = ContainerStorage::open(tmp.to_str()).unwrap();
+        container.verify().unwrap();
+        let (mut a, mut b) = ([0u8; 1024], [0u8; 1024]);
+        for index in 0..16 {
+            reader.probe(BlockIndex::new(index), &mut a).unwrap();
+            container.probe(BlockIndex::new(index), &mut b).unwrap();
+            assert_eq!(a, b);
+        }
+
+        match ContainerStorage::open(key.to_str()) {
+            Err(BigKeyError::InvalidContainer { .. }) => {}
+            _ => panic!("expected a key file not to open as a container"),
+        }
+    }
+
+    #[test]
+    fn tree_comparison_finds_first_differing_block() {
+        let mut data: Vec<u8> = (0..13 * 1024).map(|i| (i % 253) as u8).collect();
+        let pack_data = |data: &[u8]| {
+            let key = tempfile();
+            File::create(key.as_path())
+                .unwrap()
+                .write_all(data)
+                .unwrap();
+            let container = tempfile();
+            let mut reader = DiskStorage::open(BLOCK_1K, key.to_str()).unwrap();
+            pack(&mut reader, GeneratorId::Unknown, container.to_str()).unwrap();
+            container
+        };
+
+        let original = pack_data(&data);
+        let mut a = ContainerStorage::open(original.to_str()).unwrap();
+        let copy = pack_data(&data);
+        let mut b = ContainerStorage::open(copy.to_str()).unwrap();
+        assert!(a.compare_trees(&mut b).unwrap().identical());
+
+        for index in [12u64, 9, 0] {
+            data[index as usize * 1024 + 100] ^= 0x40;
+            let changed = pack_data(&data);
+            let mut b = ContainerStorage::open(changed.to_str()).unwrap();
+            let comparison = a.compare_trees(&mut b).unwrap();
+            assert_eq!(comparison.first_difference, Some(BlockIndex::new(index)));
+            assert_eq!(compare(&mut a, &mut b).unwrap(), comparison);
+        }
+    }
+} // mod test
@@ -0,0 +1,242 @@
+//! A derivation counter that never repeats, even across crashes and restarts.
+//!
+//! New keys are probed with random selectors. Should the random number generator ever repeat
+//! output (e.g. a broken entropy source), two "fresh" keys would probe the same blocks and be
+//! identical. `BigKey` therefore mixes a monotonic counter into every random selector; with the
+//! counter persisted in a sidecar next to the key, selectors stay distinct across process
+//! restarts and between processes sharing the key. A copy of the key and its sidecar, such as
+//! a cloned VM, continues the same counter, so this does not protect clones from each other.
+//!
+//! Values are reserved from the sidecar in batches of `COUNTER_RESERVATION`: the end of the
+//! batch is durably written before any value in it is used, and a restart continues after the
+//! reserved batch. A crash therefore skips the unused rest of a batch but never reuses a value.
+//! Reservations hold an exclusive lock on the sidecar and continue after whatever it holds, so
+//! concurrent processes reserve disjoint batches.
+//!
+//! Sidecar layout (`<key>.counter`): magic followed by the next unreserved value, u64
+//! big-endian.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+
+use crate::storage::util::{sync_parent, StorageContext};
+use crate::traits::BigKeyError;
+
+/// Counter values reserved per sidecar write
+pub const COUNTER_RESERVATION: u64 = 1024;
+
+const MAGIC: &[u8; 12] = b"BFDISE-COUNT";
+const COUNTER_FILE_LEN: usize = 20;
+
+/// Location of the counter sidecar for the BigKey at `storage_location`
+pub fn counter_path(storage_location: &str) -> String {
+    format!("{}.counter", storage_location)
+}
+
+/// Monotonic counter of key derivations, in memory or persisted in a sidecar
+#[derive(Debug)]
+pub struct DerivationCounter {
+    path: Option<String>,
+    next: u64,
+    reserved: u64,
+}
+
+impl DerivationCounter {
+    /// A counter that starts from zero in every process
+    pub fn in_memory() -> Self {
+        DerivationCounter {
+            path: None,
+            next: 0,
+            reserved: u64::MAX,
+        }
+    }
+
+    /// The counter of the BigKey at `storage_location`, continuing after every value reserved
+    /// by earlier processes
+    pub fn open(storage_location: &str) -> Result<Self, BigKeyError> {
+        let path = counter_path(storage_location);
+        let next = match File::open(&path) {
+            Ok(mut file) => read_value(&mut file, &path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).context("open", &path),
+        };
+
+        Ok(DerivationCounter {
+            path: Some(path),
+            next,
+            reserved: next,
+        })
+    }
+
+    /// Take the next counter value
+    pub fn take(&mut self) -> Result<u64, BigKeyError> {
+        if self.next == u64::MAX {
+            return Err(BigKeyError::InvalidCounterSidecar {
+                reason: "counter exhausted",
+            });
+        }
+        if self.next >= self.reserved {
+            if let Some(path) = &self.path {
+                self.next = reserve(path, self.next)?;
+            }
+            self.reserved = self.next.saturating_add(COUNTER_RESERVATION);
+        }
+
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+
+    /// The value `take()` will return next
+    pub fn peek(&self) -> u64 {
+        self.next
+    }
+}
+
+// Reserve a batch from the sidecar at `path`, returning its first value: `at_least` or the
+// sidecar's value if another process advanced it further
+fn reserve(path: &str, at_least: u64) -> Result<u64, BigKeyError> {
+    loop {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .context("open", path)?;
+        file.lock().context("lock", path)?;
+        // a reservation that held the lock before us replaced the sidecar we opened
+        if !is_current(&file, path).context("stat", path)? {
+            continue;
+        }
+
+        let next = at_least.max(read_value(&mut file, path)?);
+        persist(path, next.saturating_add(COUNTER_RESERVATION))?;
+        return Ok(next);
+    }
+}
+
+// Whether `file` is still the file at `path`
+#[cfg(unix)]
+fn is_current(file: &File, path: &str) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (opened, current) = match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(opened), Ok(current)) => (opened, current),
+        (_, Err(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    Ok(opened.dev() == current.dev() && opened.ino() == current.ino())
+}
+
+// Files cannot be renamed over while open elsewhere
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &str) -> io::Result<bool> {
+    Ok(true)
+}
+
+// Value held by the sidecar `file`, 0 for an empty one left by a reservation that crashed
+// before writing it
+fn read_value(file: &mut File, path: &str) -> Result<u64, BigKeyError> {
+    let mut contents = Vec::with_capacity(COUNTER_FILE_LEN);
+    file.read_to_end(&mut contents).context("read", path)?;
+    if contents.is_empty() {
+        return Ok(0);
+    }
+    if contents.len() != COUNTER_FILE_LEN || &contents[..12] != MAGIC {
+        return Err(BigKeyError::InvalidCounterSidecar {
+            reason: "not a counter sidecar",
+        });
+    }
+    Ok(u64::from_be_bytes(contents[12..].try_into().unwrap()))
+}
+
+// Durably replace the sidecar at `path` with one holding `value`
+fn persist(path: &str, value: u64) -> Result<(), BigKeyError> {
+    let tmp = format!("{}.tmp", path);
+    let mut contents = MAGIC.to_vec();
+    contents.extend_from_slice(&value.to_be_bytes());
+
+    let mut file = File::create(&tmp).context("create", &tmp)?;
+    file.write_all(&contents)
+        .and_then(|_| file.sync_all())
+        .context("write", &tmp)?;
+    std::fs::rename(&tmp, path).context("rename", &tmp)?;
+    sync_parent(path).context("sync directory of", path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::thread;
+
+    use crate::storage::counter::{counter_path, DerivationCounter, COUNTER_RESERVATION};
+    use crate::storage::tempfile::tempfile;
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn counter_never_repeats_across_restarts() {
+        let tmp = tempfile();
+        let mut counter = DerivationCounter::open(tmp.to_str()).unwrap();
+        assert_eq!(counter.take().unwrap(), 0);
+        assert_eq!(counter.take().unwrap(), 1);
+        // the process dies without any shutdown
+        drop(counter);
+
+        let mut counter = DerivationCounter::open(tmp.to_str()).unwrap();
+        assert_eq!(counter.take().unwrap(), COUNTER_RESERVATION);
+        for expected in COUNTER_RESERVATION + 1..COUNTER_RESERVATION * 2 + 10 {
+            assert_eq!(counter.take().unwrap(), expected);
+        }
+        drop(counter);
+        let counter = DerivationCounter::open(tmp.to_str()).unwrap();
+        assert_eq!(counter.peek(), COUNTER_RESERVATION * 3);
+
+        std::fs::write(counter_path(tmp.to_str()), b"garbage").unwrap();
+        match DerivationCounter::open(tmp.to_str()) {
+            Err(BigKeyError::InvalidCounterSidecar { .. }) => {}
+            _ => panic!("expected a corrupt sidecar to be rejected"),
+        }
+        let _ = std::fs::remove_file(counter_path(tmp.to_str()));
+    }
+
+    #[test]
+    fn concurrent_counters_take_disjoint_values() {
+        let tmp = tempfile();
+        let location = tmp.to_str().to_string();
+
+        // counters opened together still reserve different batches
+        let mut first = DerivationCounter::open(&location).unwrap();
+        let mut second = DerivationCounter::open(&location).unwrap();
+        assert_eq!(first.take().unwrap(), 0);
+        assert_eq!(second.take().unwrap(), COUNTER_RESERVATION);
+        drop((first, second));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let location = location.clone();
+                thread::spawn(move || {
+                    let mut counter = DerivationCounter::open(&location).unwrap();
+                    (0..3 * COUNTER_RESERVATION)
+                        .map(|_| counter.take().unwrap())
+                        .collect::<Vec<u64>>()
+                })
+            })
+            .collect();
+        let mut taken = HashSet::new();
+        for thread in threads {
+            for value in thread.join().unwrap() {
+                assert!(taken.insert(value), "value {} taken twice", value);
+            }
+        }
+        assert!(taken.iter().all(|value| *value >= 2 * COUNTER_RESERVATION));
+
+        // an empty sidecar, left by a crash before its first reservation, starts from zero
+        std::fs::write(counter_path(&location), b"").unwrap();
+        let mut counter = DerivationCounter::open(&location).unwrap();
+        assert_eq!(counter.take().unwrap(), 0);
+        let _ = std::fs::remove_file(counter_path(&location));
+    }
+} // mod test
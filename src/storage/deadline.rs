@@ -0,0 +1,178 @@
+//! Time-boxed probing.
+//!
+//! Probes of remote or HDD-backed storage can stall. `DeadlineReader` wraps any
+//! `StorageReader` and refuses to start a probe once its deadline has passed or its
+//! `CancellationToken` has been cancelled. Checks are cooperative: a probe already in flight is
+//! not interrupted, so a derivation overruns its deadline by at most one probe.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Shared flag used to abandon outstanding probes from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancel every reader holding a clone of this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A `StorageReader` whose probes fail with `Timeout` after a deadline, or with `Cancelled`
+/// once its `CancellationToken` is cancelled.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use big_fluffy_dise::storage::{DeadlineReader, DiskStorage, StorageReader};
+/// # use big_fluffy_dise::traits::{BlockIndex, BLOCK_4K};
+/// let mut storage = DiskStorage::open(BLOCK_4K, "/srv/big.key")?;
+/// let mut reader = DeadlineReader::new(&mut storage).with_timeout(Duration::from_millis(250));
+/// let mut block = vec![0u8; 4096];
+/// reader.probe(BlockIndex::new(42), &mut block)?;
+/// # Ok::<(), big_fluffy_dise::traits::BigKeyError>(())
+/// ```
+pub struct DeadlineReader<R: StorageReader> {
+    inner: R,
+    deadline: Option<Instant>,
+    token: Option<CancellationToken>,
+}
+
+impl<R: StorageReader> DeadlineReader<R> {
+    /// Wrap `inner` with no deadline and no cancellation
+    pub fn new(inner: R) -> Self {
+        DeadlineReader {
+            inner,
+            deadline: None,
+            token: None,
+        }
+    }
+
+    /// Fail probes started after `deadline`
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Fail probes started more than `timeout` from now
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Fail probes started after `token` is cancelled
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Replace the deadline, e.g. when the reader is reused for another request
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: StorageReader> StorageReader for DeadlineReader<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        if self.token.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Err(BigKeyError::Cancelled);
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(BigKeyError::Timeout);
+            }
+        }
+
+        self.inner.probe(index, output)
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        self.inner.prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::kem::{BigKey, BigKeyKem};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{CancellationToken, DeadlineReader, DiskStorage, StorageReader};
+    use crate::traits::{BigKeyError, BlockIndex, SecurityLevel, BLOCK_1K};
+
+    #[test]
+    fn expired_deadline_and_cancellation_fail_probes() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            ofile.write_all(&[0x17u8; 8 * 1024]).unwrap();
+        }
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut block = vec![0u8; 1024];
+        let token = CancellationToken::new();
+
+        let mut reader = DeadlineReader::new(&mut storage)
+            .with_timeout(Duration::from_secs(60))
+            .with_cancellation(token.clone());
+        reader.probe(BlockIndex::new(1), &mut block).unwrap();
+
+        reader.set_deadline(Some(Instant::now()));
+        match reader.probe(BlockIndex::new(1), &mut block) {
+            Err(BigKeyError::Timeout) => {}
+            _ => panic!("expected probe past deadline to time out"),
+        }
+
+        reader.set_deadline(None);
+        token.cancel();
+        match reader.probe(BlockIndex::new(1), &mut block) {
+            Err(BigKeyError::Cancelled) => {}
+            _ => panic!("expected cancelled probe to fail"),
+        }
+    }
+
+    #[test]
+    fn derivation_fails_past_deadline() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            ofile.write_all(&[0x17u8; 64 * 1024]).unwrap();
+        }
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let reader = DeadlineReader::new(storage).with_deadline(Instant::now());
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, reader, Sha3_256::new());
+
+        match bk.new_key(SecurityLevel::Bits128) {
+            Err(BigKeyError::Timeout) => {}
+            _ => panic!("expected derivation past deadline to time out"),
+        }
+    }
+} // mod test
@@ -1,29 +1,70 @@
 //! StorageMethod defines how BigKeys are read from permanent media.
 
-use std::fs::{File};
+use std::fs::{File, OpenOptions, TryLockError};
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
 
-use crate::storage::traits::StorageReader;
-use crate::storage::util::check_key_evenly_divisible;
+use crate::memory::wipe;
+use crate::storage::checksum::{ChecksumReader, ChecksumWriter};
+use crate::storage::header::{KeyHeader, HEADER_LEN};
+use crate::storage::latency::LatencyStats;
+use crate::storage::lock::lock_range;
+use crate::storage::native::ProbeFile;
+use crate::storage::permutation::{physical_offset, BlockPermutation};
+use crate::storage::provenance::{OperatorKey, Provenance};
+use crate::storage::traits::{StorageReader, StorageReaderFactory};
+use crate::storage::util::{check_key_evenly_divisible, data_position, StorageContext};
 use crate::storage::StorageWriter;
-use crate::traits::types::BlockSize;
-use crate::traits::BigKeyError;
+use crate::traits::types::{BlockSize, GeneratorId, HashAlgorithm};
+use crate::traits::{BigKeyError, BlockIndex};
 
 /// Stores BigKey material in a file on a conventional filesystem. Assumes underlying storage
 /// medium provides efficient random access to the big key contents (think NVMe or SSD, not HDD).
 ///
-/// Probes are made one-at-a-time, reading `BlockSize` bytes each `probe()`
+/// Newly written keys are prefixed with a `KeyHeader`; raw key files without a header can still
+/// be opened for reading.
+///
+/// Probes are made one-at-a-time, reading `BlockSize` bytes each `probe()` with the platform's
+/// fastest uncached random read path (see `storage::native`)
+///
+/// Keys written after `permute_blocks()` are stored in a keyed block order recorded in the
+/// header (see `storage::permutation`); probes, repairs and checksums address logical blocks
+/// either way.
+///
+/// Open key files are advisory locked (`flock` / `LockFileEx`): a writer excludes every other
+/// `DiskStorage`, readers only exclude writers. Opening a key locked by another process fails
+/// with `KeyLocked` rather than waiting.
 pub struct DiskStorage {
     block_size: BlockSize,
     big_key_length: u64,
     big_key_file: File,
+    probe_file: ProbeFile,
+    location: String,
+    data_offset: u64,
+    header: Option<KeyHeader>,
+    generator: GeneratorId,
+    fingerprint: blake3::Hasher,
+    checksum_writer: Option<ChecksumWriter>,
+    checksum_reader: Option<ChecksumReader>,
+    latency: LatencyStats,
+    slow_probe_threshold: Option<Duration>,
+    permutation: Option<BlockPermutation>,
+    hash_algorithm: Option<HashAlgorithm>,
+    merkle_root: Option<[u8; 32]>,
+    provenance: Option<Provenance>,
+    operator_key: Option<OperatorKey>,
+    // Writers of permuted keys: the incomplete block being written, and the blocks written
+    pending: Vec<u8>,
+    blocks_written: u64,
 }
 
 // Differentiate which trait DiskStorage is implementing
 enum IoMode {
-    READ,
-    WRITE,
+    Read,
+    Write,
+    // continue an interrupted write after this many key bytes
+    Resume(u64),
 }
 
 impl DiskStorage {
@@ -33,38 +74,315 @@ impl DiskStorage {
         expected_size: Option<usize>,
         mode: IoMode,
     ) -> Result<DiskStorage, BigKeyError> {
-        let big_key_file: File;
+        let mut big_key_file: File;
         let big_key_length: u64;
+        let header: Option<KeyHeader>;
 
         match mode {
-            IoMode::READ => {
-                big_key_file = File::open(storage_location)?;
-                big_key_length = big_key_file.metadata()?.len();
+            IoMode::Read => {
+                big_key_file = File::open(storage_location).context("open", storage_location)?;
+                lock_file(&big_key_file, storage_location, false)?;
+                let file_length = big_key_file
+                    .metadata()
+                    .context("stat", storage_location)?
+                    .len();
+                header = KeyHeader::read_from(&mut big_key_file)
+                    .context("read header of", storage_location)?;
+
+                big_key_length = match &header {
+                    Some(header) => {
+                        if header.block_len != block_size.byte_len {
+                            return Err(BigKeyError::BlockSizeMismatch {
+                                requested_len: block_size.byte_len,
+                                header_len: header.block_len,
+                            });
+                        }
+                        if file_length - HEADER_LEN as u64 != header.key_length {
+                            return Err(BigKeyError::HeaderLengthMismatch {
+                                header_len: header.key_length,
+                                file_len: file_length - HEADER_LEN as u64,
+                            });
+                        }
+                        header.key_length
+                    }
+                    None => file_length,
+                };
+            }
+            IoMode::Write => {
+                // lock before truncating, so a key in use is never clobbered
+                big_key_file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(storage_location)
+                    .context("create", storage_location)?;
+                lock_file(&big_key_file, storage_location, true)?;
+                big_key_file
+                    .set_len(0)
+                    .context("truncate", storage_location)?;
+                big_key_length = expected_size.unwrap() as u64;
+                header = None;
+
+                // Reserve space for the header, it's filled in by finalize()
+                big_key_file
+                    .write_all(&[0u8; HEADER_LEN])
+                    .context("write header of", storage_location)?;
             }
-            IoMode::WRITE => {
-                big_key_file = File::create(storage_location)?;
+            IoMode::Resume(written) => {
+                big_key_file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(storage_location)
+                    .context("open", storage_location)?;
+                lock_file(&big_key_file, storage_location, true)?;
+                if KeyHeader::read_from(&mut big_key_file)
+                    .context("read header of", storage_location)?
+                    .is_some()
+                {
+                    return Err(BigKeyError::InvalidCheckpoint {
+                        reason: "key is already complete",
+                    });
+                }
+                let file_length = big_key_file
+                    .metadata()
+                    .context("stat", storage_location)?
+                    .len();
+                let resume_at = HEADER_LEN as u64 + written;
+                if file_length < resume_at {
+                    return Err(BigKeyError::InvalidCheckpoint {
+                        reason: "key file is shorter than the checkpoint",
+                    });
+                }
+                // drop anything written after the checkpoint
+                big_key_file
+                    .set_len(resume_at)
+                    .context("truncate", storage_location)?;
                 big_key_length = expected_size.unwrap() as u64;
+                header = None;
             }
         }
 
-        if let Err(e) = check_key_evenly_divisible(block_size, big_key_length) {
-            return Err(e);
+        check_key_evenly_divisible(block_size, big_key_length)?;
+
+        let data_offset = match (&mode, &header) {
+            (IoMode::Read, None) => 0,
+            _ => HEADER_LEN as u64,
+        };
+        let permutation = header
+            .as_ref()
+            .and_then(|header| header.permutation)
+            .map(|key| BlockPermutation::new(key, big_key_length / block_size.byte_len as u64));
+        let probe_file = match mode {
+            IoMode::Read => ProbeFile::open(&big_key_file, storage_location),
+            IoMode::Write | IoMode::Resume(_) => ProbeFile::plain(&big_key_file),
         }
+        .context("open", storage_location)?;
 
         Ok(DiskStorage {
             block_size,
             big_key_length,
             big_key_file,
+            probe_file,
+            location: storage_location.to_string(),
+            data_offset,
+            header,
+            generator: GeneratorId::Unknown,
+            fingerprint: blake3::Hasher::new(),
+            checksum_writer: None,
+            checksum_reader: None,
+            latency: LatencyStats::default(),
+            slow_probe_threshold: None,
+            permutation,
+            hash_algorithm: None,
+            merkle_root: None,
+            provenance: None,
+            operator_key: None,
+            pending: Vec::new(),
+            blocks_written: 0,
         })
     }
-}
 
-impl StorageReader for DiskStorage {
-    fn open(block_size: BlockSize, storage_location: &str) -> Result<DiskStorage, BigKeyError> {
-        DiskStorage::new(block_size, storage_location, None, IoMode::READ)
+    /// Open the key file at `storage_location` for probing
+    pub fn open(block_size: BlockSize, storage_location: &str) -> Result<DiskStorage, BigKeyError> {
+        DiskStorage::new(block_size, storage_location, None, IoMode::Read)
+    }
+
+    /// Open the key file at `storage_location`, verifying every probed block against the
+    /// checksum sidecar written by `new_writer_with_checksums()`. Corrupted blocks fail the probe
+    /// with `BlockCorrupted` rather than flowing into key derivation.
+    pub fn open_with_checksums(
+        block_size: BlockSize,
+        storage_location: &str,
+    ) -> Result<DiskStorage, BigKeyError> {
+        let mut storage = DiskStorage::open(block_size, storage_location)?;
+        let checksums = ChecksumReader::open(storage_location, block_size.byte_len)?;
+
+        if checksums.block_count() != storage.big_key_length / block_size.byte_len as u64 {
+            return Err(BigKeyError::InvalidChecksumSidecar {
+                reason: "block count differs from key",
+            });
+        }
+
+        storage.checksum_reader = Some(checksums);
+        Ok(storage)
+    }
+
+    /// Create a new BigKey writer that also records per-block checksums in a sidecar file
+    /// (see `storage::checksum`).
+    pub fn new_writer_with_checksums(
+        block_size: BlockSize,
+        storage_location: &str,
+        expected_size: usize,
+    ) -> Result<DiskStorage, BigKeyError> {
+        let mut storage = DiskStorage::new_writer(block_size, storage_location, expected_size)?;
+        storage.checksum_writer = Some(ChecksumWriter::create(
+            storage_location,
+            block_size.byte_len,
+            storage.big_key_length / block_size.byte_len as u64,
+        )?);
+        Ok(storage)
+    }
+
+    /// Continue writing the interrupted key at `storage_location` of `expected_size` bytes, of
+    /// which the first `written` were written (see `generation::Checkpoint`). Bytes past
+    /// `written` are discarded; those before it are re-read into the key's fingerprint.
+    pub fn resume_writer(
+        block_size: BlockSize,
+        storage_location: &str,
+        expected_size: usize,
+        written: u64,
+    ) -> Result<DiskStorage, BigKeyError> {
+        if written > expected_size as u64 || !written.is_multiple_of(block_size.byte_len as u64) {
+            return Err(BigKeyError::InvalidCheckpoint {
+                reason: "inconsistent lengths",
+            });
+        }
+        let mut storage = DiskStorage::new(
+            block_size,
+            storage_location,
+            Some(expected_size),
+            IoMode::Resume(written),
+        )?;
+
+        let (mut file, fingerprint) = (&storage.big_key_file, &mut storage.fingerprint);
+        file.seek(SeekFrom::Start(HEADER_LEN as u64))
+            .and_then(|_| io::copy(&mut file.take(written), fingerprint))
+            .and_then(|_| file.seek(SeekFrom::End(0)))
+            .context("read", storage_location)?;
+        Ok(storage)
+    }
+
+    /// Read only the `KeyHeader` of the key file at `storage_location`, if it has one.
+    pub fn read_header(storage_location: &str) -> Result<Option<KeyHeader>, BigKeyError> {
+        let mut file = File::open(storage_location).context("open", storage_location)?;
+        KeyHeader::read_from(&mut file).context("read header of", storage_location)
+    }
+
+    /// The `KeyHeader` of an opened key file, `None` for raw key files.
+    pub fn header(&self) -> Option<&KeyHeader> {
+        self.header.as_ref()
+    }
+
+    /// Store the key being written in a random block order, recorded in its header. Must be
+    /// called before any key data is written; each block is then written in place, so small
+    /// block sizes generate noticeably slower.
+    pub fn permute_blocks(&mut self) -> Result<(), BigKeyError> {
+        let position = self
+            .big_key_file
+            .stream_position()
+            .context("seek", &self.location)?;
+        if self.header.is_some() || position != self.data_offset || self.permutation.is_some() {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "blocks can only be permuted by a writer before any data".to_string(),
+            });
+        }
+        let block_count = self.big_key_length / self.block_size.byte_len as u64;
+        self.permutation = Some(BlockPermutation::random(block_count)?);
+        self.big_key_file
+            .set_len(self.data_offset + self.big_key_length)
+            .context("extend", &self.location)
+    }
+
+    /// Record `algorithm` in the header of the key being written as the hash its keys are
+    /// derived with by default
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.hash_algorithm = Some(algorithm);
+    }
+
+    /// Record `root`, the root of a container style Merkle tree over the blocks (see
+    /// `storage::ContainerWriter`), in the header of the key being written
+    pub fn set_merkle_root(&mut self, root: [u8; 32]) {
+        self.merkle_root = Some(root);
     }
 
-    fn probe(&mut self, index: u64, output: &mut [u8]) -> Result<(), BigKeyError> {
+    /// Record `provenance` in the header of the key being written, signed by `operator` once the
+    /// key is complete if given
+    pub fn set_provenance(&mut self, provenance: Provenance, operator: Option<OperatorKey>) {
+        self.provenance = Some(provenance);
+        self.operator_key = operator;
+    }
+
+    /// Latency of every probe since the key was opened or `reset_latency_stats()`
+    pub fn latency_stats(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    pub fn reset_latency_stats(&mut self) {
+        self.latency = LatencyStats::default();
+    }
+
+    /// Log a warning for every probe slower than `threshold`, or stop warning if `None`
+    pub fn set_slow_probe_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_probe_threshold = threshold;
+    }
+
+    /// Durably rewrite block `index` with `contents`, e.g. a copy from a replica. Probes of the
+    /// block through any `DiskStorage`, in this or another process, wait for the rewrite and
+    /// never see a torn block; on Linux only, elsewhere they are not kept out (see
+    /// `storage::lock`). If the key was opened with checksums, `contents` must match the
+    /// block's checksum or the repair is refused with `BlockCorrupted`.
+    pub fn repair_block(&mut self, index: BlockIndex, contents: &[u8]) -> Result<(), BigKeyError> {
+        if contents.len() != self.block_size.byte_len {
+            return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+                out_buf_len: contents.len(),
+                block_len: self.block_size.byte_len,
+            });
+        }
+        let offset = physical_offset(
+            self.permutation.as_ref(),
+            index,
+            self.block_size,
+            self.big_key_length,
+        )?;
+        if let Some(checksums) = &mut self.checksum_reader {
+            checksums.verify(index, contents)?;
+        }
+
+        let position = data_position(
+            self.data_offset,
+            offset,
+            self.block_size,
+            self.big_key_length,
+        )?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&self.location)
+            .context("open", &self.location)?;
+        let _lock = lock_range(&file, position, contents.len() as u64, true).context_at(
+            "lock",
+            &self.location,
+            position,
+        )?;
+        let mut file = &file;
+        file.seek(SeekFrom::Start(position))
+            .and_then(|_| file.write_all(contents))
+            .and_then(|_| file.sync_data())
+            .context_at("repair", &self.location, position)
+    }
+}
+
+impl StorageReader for DiskStorage {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
         if output.len() != self.block_size.byte_len {
             return Err(BigKeyError::ProbeBufferNotEqBlockSize {
                 out_buf_len: output.len(),
@@ -72,19 +390,65 @@ impl StorageReader for DiskStorage {
             });
         }
 
-        let offset = index * self.block_size.byte_len as u64;
+        let offset = physical_offset(
+            self.permutation.as_ref(),
+            index,
+            self.block_size,
+            self.big_key_length,
+        )?;
 
-        if offset + self.block_size.byte_len as u64 > self.big_key_length {
-            return Err(BigKeyError::ProbeOffsetOutOfBounds {
-                end_of_key: self.big_key_length as usize,
-                offset: offset as usize,
-                probe_len: self.block_size.byte_len,
-            });
+        let started = Instant::now();
+        let position = data_position(
+            self.data_offset,
+            offset,
+            self.block_size,
+            self.big_key_length,
+        )?;
+        {
+            // keep out repairs rewriting this block, see `storage::lock`
+            let _lock = lock_range(&self.big_key_file, position, output.len() as u64, false)
+                .context_at("lock", &self.location, position)?;
+            self.probe_file.read_at(position, output).context_at(
+                "probe",
+                &self.location,
+                position,
+            )?;
+        }
+
+        let elapsed = started.elapsed();
+        self.latency.record(elapsed);
+        if self.slow_probe_threshold.is_some_and(|t| elapsed > t) {
+            log::warn!(
+                "slow probe of block {} took {:?}, storage may not support fast random reads",
+                index,
+                elapsed
+            );
+        }
+
+        if let Some(checksums) = &mut self.checksum_reader {
+            checksums.verify(index, output)?;
         }
 
-        self.big_key_file.seek(SeekFrom::Start(offset))?;
-        self.big_key_file.read_exact(output)?;
+        Ok(())
+    }
 
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        for &index in indices {
+            let offset = physical_offset(
+                self.permutation.as_ref(),
+                index,
+                self.block_size,
+                self.big_key_length,
+            )?;
+            let position = data_position(
+                self.data_offset,
+                offset,
+                self.block_size,
+                self.big_key_length,
+            )?;
+            self.probe_file
+                .will_need(position, self.block_size.byte_len as u64);
+        }
         Ok(())
     }
 
@@ -97,7 +461,40 @@ impl StorageReader for DiskStorage {
     }
 }
 
+// Take an advisory lock on the whole key file for as long as it is open: exclusive for writers,
+// shared for readers. Repairs (`repair_block()`) run under a reader's shared lock and are kept
+// apart from probes by byte range locks instead.
+fn lock_file(file: &File, storage_location: &str, exclusive: bool) -> Result<(), BigKeyError> {
+    let locked = match exclusive {
+        true => file.try_lock(),
+        false => file.try_lock_shared(),
+    };
+    match locked {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(BigKeyError::KeyLocked {
+            path: storage_location.to_string(),
+        }),
+        Err(TryLockError::Error(e)) => Err(e).context("lock", storage_location),
+    }
+}
+
+/// Opens `DiskStorage` readers
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DiskStorageFactory;
+
+impl StorageReaderFactory for DiskStorageFactory {
+    fn open(
+        &self,
+        block_size: BlockSize,
+        storage_location: &str,
+    ) -> Result<Box<dyn StorageReader>, BigKeyError> {
+        Ok(Box::new(DiskStorage::open(block_size, storage_location)?))
+    }
+}
+
 impl StorageWriter for DiskStorage {
+    type Options = str;
+
     fn new_writer(
         block_size: BlockSize,
         storage_location: &str,
@@ -114,7 +511,7 @@ impl StorageWriter for DiskStorage {
             block_size,
             storage_location,
             Some(expected_size),
-            IoMode::WRITE,
+            IoMode::Write,
         )
     }
 
@@ -126,25 +523,110 @@ impl StorageWriter for DiskStorage {
         self.big_key_length
     }
 
+    fn set_generator(&mut self, generator: GeneratorId) {
+        self.generator = generator;
+    }
+
     fn finalize(&mut self) -> Result<(), BigKeyError> {
-        self.flush()?;
+        self.flush().context("flush", &self.location)?;
 
-        let metadata = self.big_key_file.metadata()?;
+        let wrote_len = match self.permutation {
+            Some(_) => {
+                self.blocks_written * self.block_size.byte_len as u64 + self.pending.len() as u64
+            }
+            None => {
+                let metadata = self
+                    .big_key_file
+                    .metadata()
+                    .context("stat", &self.location)?;
+                metadata.len() - self.data_offset
+            }
+        };
 
-        if metadata.len() != self.big_key_length {
+        if wrote_len != self.big_key_length {
             return Err(BigKeyError::FailedToWriteBigKey {
                 expected_len: self.big_key_length as usize,
-                wrote_len: metadata.len() as usize,
+                wrote_len: wrote_len as usize,
             });
-        } else {
-            Ok(())
         }
+
+        if let Some(checksums) = &mut self.checksum_writer {
+            checksums.finalize()?;
+        }
+
+        let mut header = KeyHeader::new(self.generator, self.block_size, self.big_key_length);
+        header.fingerprint = Some(*self.fingerprint.finalize().as_bytes());
+        if let Some(permutation) = &self.permutation {
+            header = header.with_permutation(*permutation.key());
+        }
+        header.hash_algorithm = self.hash_algorithm;
+        header.merkle_root = self.merkle_root;
+        if let Some(mut provenance) = self.provenance.take() {
+            if let Some(operator) = self.operator_key.take() {
+                provenance.sign(&header, &operator)?;
+            }
+            header.provenance = Some(provenance);
+        }
+
+        self.big_key_file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.big_key_file.write_all(&header.to_bytes()))
+            .and_then(|_| self.big_key_file.flush())
+            .context("write header of", &self.location)?;
+        self.header = Some(header);
+
+        // the key is complete, let readers in
+        self.big_key_file
+            .unlock()
+            .context("unlock", &self.location)?;
+        lock_file(&self.big_key_file, &self.location, false)?;
+
+        Ok(())
+    }
+
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.header.as_ref().and_then(|header| header.fingerprint)
+    }
+}
+
+impl DiskStorage {
+    // Collect `buf` into whole blocks, writing each at its permuted position
+    fn write_permuted(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let block_len = self.block_size.byte_len;
+        let len = buf.len().min(block_len - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+
+        if self.pending.len() == block_len {
+            let permutation = self.permutation.as_ref().unwrap();
+            if self.blocks_written == permutation.block_count() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "write past the end of the key",
+                ));
+            }
+            let position = permutation.physical(self.blocks_written) * block_len as u64;
+            let mut file = &self.big_key_file;
+            file.seek(SeekFrom::Start(self.data_offset + position))
+                .and_then(|_| file.write_all(&self.pending))?;
+            wipe(&mut self.pending);
+            self.pending.clear();
+            self.blocks_written += 1;
+        }
+        Ok(len)
     }
 }
 
 impl Write for DiskStorage {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        self.big_key_file.write(buf)
+        let written = match self.permutation {
+            Some(_) => self.write_permuted(buf)?,
+            None => self.big_key_file.write(buf)?,
+        };
+        self.fingerprint.update(&buf[..written]);
+        if let Some(checksums) = &mut self.checksum_writer {
+            checksums.update(&buf[..written])?;
+        }
+        Ok(written)
     }
 
     fn flush(&mut self) -> Result<(), io::Error> {
@@ -154,14 +636,18 @@ impl Write for DiskStorage {
 
 #[cfg(test)]
 mod test {
-    use std::fs::File;
-    use std::io::{Error, ErrorKind, Write};
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use std::time::Duration;
 
-    use crate::storage::disk::DiskStorage;
-    use crate::storage::{StorageReader, StorageWriter};
-    use crate::traits::{BigKeyError, BlockSize, BLOCKS, BLOCK_32};
+    use crate::storage::checksum::sidecar_path;
+    use crate::storage::disk::{DiskStorage, DiskStorageFactory};
+    use crate::storage::header::HEADER_LEN;
     use crate::storage::tempfile::tempfile;
-    use std::io;
+    use crate::storage::{
+        fingerprint, BlockPermutation, StorageReader, StorageReaderFactory, StorageWriter,
+    };
+    use crate::traits::{BigKeyError, BlockIndex, GeneratorId, BLOCKS, BLOCK_32, BLOCK_64};
 
     #[test]
     fn open_succeeds_when_size_matches() {
@@ -182,7 +668,12 @@ mod test {
     fn open_fails_if_file_doesnt_exist() {
         let tmp = tempfile();
         match DiskStorage::open(BLOCK_32, tmp.to_str()) {
-            Err(BigKeyError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::NotFound),
+            Err(BigKeyError::Storage {
+                op, path, source, ..
+            }) => {
+                assert_eq!((op, path.as_str()), ("open", tmp.to_str()));
+                assert_eq!(source.kind(), ErrorKind::NotFound);
+            }
             _ => panic!("open() should have failed as {:?} didn't exist", tmp),
         }
     }
@@ -223,7 +714,7 @@ mod test {
             let storage = DiskStorage::open(*block_size, tmp.to_str()).unwrap();
             assert_eq!(
                 storage.big_key_length() as usize,
-                filler.len() as usize * block_size.byte_len
+                filler.len() * block_size.byte_len
             );
         }
     }
@@ -246,15 +737,15 @@ mod test {
             let mut buf = [0x00].repeat(block_size.byte_len);
 
             // Read 2nd block of 0x22
-            storage.probe(1, &mut buf).unwrap();
+            storage.probe(BlockIndex::new(1), &mut buf).unwrap();
             assert_eq!(buf, data2);
 
             // Read 1st block of 0x11
-            storage.probe(0, &mut buf).unwrap();
+            storage.probe(BlockIndex::new(0), &mut buf).unwrap();
             assert_eq!(buf, data1);
 
             // Read 3rd block of 0x33
-            storage.probe(2, &mut buf).unwrap();
+            storage.probe(BlockIndex::new(2), &mut buf).unwrap();
             assert_eq!(buf, data3);
         }
     }
@@ -272,13 +763,47 @@ mod test {
             let mut storage = DiskStorage::open(*block_size, tmp.to_str()).unwrap();
             let mut unused = [0x00].repeat(block_size.byte_len);
 
-            match storage.probe(1, &mut unused) {
+            match storage.probe(BlockIndex::new(1), &mut unused) {
                 Err(BigKeyError::ProbeOffsetOutOfBounds { .. }) => {}
                 _ => panic!("expected an index out of bounds error"),
             }
         }
     }
 
+    #[test]
+    fn failed_reads_name_path_and_offset() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            ofile.write_all(&[0x42; 64]).unwrap();
+        }
+        let mut storage = DiskStorage::open(BLOCK_32, tmp.to_str()).unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(tmp.as_path())
+            .unwrap()
+            .set_len(32)
+            .unwrap();
+
+        let err = storage
+            .probe(BlockIndex::new(10), &mut [0u8; 4])
+            .unwrap_err();
+        match &err {
+            BigKeyError::Storage {
+                op, path, offset, ..
+            } => {
+                assert_eq!(
+                    (*op, path.as_str(), *offset),
+                    ("probe", tmp.to_str(), Some(40))
+                );
+            }
+            _ => panic!("expected storage error, got {:?}", err),
+        }
+        assert!(err.to_string().contains("at offset 40"));
+        assert_eq!(err.io_error().unwrap().kind(), ErrorKind::UnexpectedEof);
+        assert!(!err.is_retryable());
+    }
+
     #[test]
     fn probe_buffer_length_not_same_as_block_length_fails() {
         for block_size in BLOCKS.iter() {
@@ -292,13 +817,198 @@ mod test {
             let mut storage = DiskStorage::open(*block_size, tmp.to_str()).unwrap();
             let mut buf = [0x00].repeat(block_size.byte_len - 1);
 
-            match storage.probe(1, &mut buf) {
+            match storage.probe(BlockIndex::new(1), &mut buf) {
                 Err(BigKeyError::ProbeBufferNotEqBlockSize { .. }) => {}
                 _ => panic!("expected output != block length"),
             }
         }
     }
 
+    #[test]
+    fn written_key_has_header_and_reopens() {
+        let tmp = tempfile();
+        let data = [0x5a].repeat(BLOCK_32.byte_len * 4);
+        {
+            let mut storage = DiskStorage::new_writer(BLOCK_32, tmp.to_str(), data.len()).unwrap();
+            storage.set_generator(GeneratorId::Shake256);
+            storage.write_all(&data).unwrap();
+            storage.finalize().unwrap();
+        }
+
+        let header = DiskStorage::read_header(tmp.to_str()).unwrap().unwrap();
+        assert_eq!(header.generator, GeneratorId::Shake256);
+        assert_eq!(header.key_length, data.len() as u64);
+        assert_eq!(header.fingerprint, Some(*blake3::hash(&data).as_bytes()));
+
+        let mut storage = DiskStorage::open(BLOCK_32, tmp.to_str()).unwrap();
+        assert_eq!(storage.big_key_length(), data.len() as u64);
+
+        let mut buf = [0u8; 4];
+        storage.probe(BlockIndex::new(3), &mut buf).unwrap();
+        assert_eq!(buf, [0x5a; 4]);
+
+        match DiskStorage::open(BLOCK_64, tmp.to_str()) {
+            Err(BigKeyError::BlockSizeMismatch { .. }) => {}
+            _ => panic!("expected block size mismatch with header"),
+        }
+    }
+
+    #[cfg(feature = "operator-signing")]
+    #[test]
+    fn written_key_records_signed_provenance() {
+        use crate::storage::{OperatorKey, Provenance};
+
+        let tmp = tempfile();
+        let data = [0x3c].repeat(BLOCK_32.byte_len * 4);
+        let operator = OperatorKey::from_bytes([9u8; 32]);
+        let public_key = operator.public_key().unwrap();
+        {
+            let mut storage = DiskStorage::new_writer(BLOCK_32, tmp.to_str(), data.len()).unwrap();
+            storage.set_generator(GeneratorId::Shake256);
+            storage.set_provenance(Provenance::new(Some(b"seed")), Some(operator));
+            storage.write_all(&data).unwrap();
+            storage.finalize().unwrap();
+        }
+
+        let header = DiskStorage::read_header(tmp.to_str()).unwrap().unwrap();
+        let provenance = header.provenance.as_ref().unwrap();
+        assert!(provenance.matches_seed(b"seed"));
+        assert_eq!(provenance.verify(&header).unwrap(), Some(public_key));
+    }
+
+    #[test]
+    fn permuted_keys_scatter_blocks_but_probe_the_same() {
+        let tmp = tempfile();
+        let blocks = 64u8;
+        {
+            let mut storage =
+                DiskStorage::new_writer_with_checksums(BLOCK_32, tmp.to_str(), 4 * blocks as usize)
+                    .unwrap();
+            storage.permute_blocks().unwrap();
+            assert!(storage.permute_blocks().is_err());
+            // writes need not be block aligned
+            for i in 0..blocks {
+                storage.write_all(&[i; 3]).unwrap();
+                storage.write_all(&[i]).unwrap();
+            }
+            storage.finalize().unwrap();
+        }
+
+        let header = DiskStorage::read_header(tmp.to_str()).unwrap().unwrap();
+        let permutation = BlockPermutation::new(header.permutation.unwrap(), blocks as u64);
+        let logical: Vec<u8> = (0..blocks).flat_map(|i| [i; 4]).collect();
+        assert_eq!(header.fingerprint, Some(*blake3::hash(&logical).as_bytes()));
+        let physical = std::fs::read(tmp.as_path()).unwrap();
+        assert_ne!(&physical[HEADER_LEN..], &logical[..]);
+
+        let mut storage = DiskStorage::open_with_checksums(BLOCK_32, tmp.to_str()).unwrap();
+        let mut buf = [0u8; 4];
+        for i in 0..blocks {
+            storage.probe(BlockIndex::new(i as u64), &mut buf).unwrap();
+            assert_eq!(buf, [i; 4]);
+            let at = HEADER_LEN + 4 * permutation.physical(i as u64) as usize;
+            assert_eq!(physical[at..at + 4], [i; 4]);
+        }
+        assert_eq!(
+            fingerprint(&mut storage).unwrap(),
+            header.fingerprint.unwrap()
+        );
+
+        // repairs land at the permuted position
+        storage.repair_block(BlockIndex::new(9), &[9; 4]).unwrap();
+        storage.probe(BlockIndex::new(9), &mut buf).unwrap();
+        assert_eq!(buf, [9; 4]);
+        std::fs::remove_file(sidecar_path(tmp.to_str())).unwrap();
+    }
+
+    #[test]
+    fn writers_exclude_other_users_of_the_key() {
+        let tmp = tempfile();
+        let data = [0x5a].repeat(BLOCK_32.byte_len * 4);
+        let mut writer = DiskStorage::new_writer(BLOCK_32, tmp.to_str(), data.len()).unwrap();
+        writer.write_all(&data).unwrap();
+
+        for result in [
+            DiskStorage::open(BLOCK_32, tmp.to_str()).map(|_| ()),
+            DiskStorage::new_writer(BLOCK_32, tmp.to_str(), data.len()).map(|_| ()),
+        ]
+        .iter()
+        {
+            match result {
+                Err(BigKeyError::KeyLocked { path }) => assert_eq!(path, tmp.to_str()),
+                _ => panic!("expected the key being written to be locked"),
+            }
+        }
+
+        writer.finalize().unwrap();
+        let _reader = DiskStorage::open(BLOCK_32, tmp.to_str()).unwrap();
+        drop(writer);
+        let _second_reader = DiskStorage::open(BLOCK_32, tmp.to_str()).unwrap();
+        match DiskStorage::new_writer(BLOCK_32, tmp.to_str(), data.len()) {
+            Err(BigKeyError::KeyLocked { .. }) => {}
+            _ => panic!("expected readers to keep writers out"),
+        }
+        assert_eq!(
+            std::fs::read(tmp.as_path()).unwrap().len(),
+            HEADER_LEN + data.len()
+        );
+    }
+
+    #[test]
+    fn factory_opens_dyn_reader() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            ofile.write_all(&[0x77u8; 64]).unwrap();
+        }
+
+        let factory: Box<dyn StorageReaderFactory> = Box::new(DiskStorageFactory);
+        let mut reader = factory.open(BLOCK_32, tmp.to_str()).unwrap();
+        let mut buf = [0u8; 4];
+
+        reader.probe(BlockIndex::new(15), &mut buf).unwrap();
+        assert_eq!(buf, [0x77; 4]);
+        assert_eq!(reader.big_key_length(), 64);
+    }
+
+    #[test]
+    fn corrupted_block_detected_by_checksum() {
+        let tmp = tempfile();
+        {
+            let mut storage =
+                DiskStorage::new_writer_with_checksums(BLOCK_32, tmp.to_str(), 32 * 4).unwrap();
+            for i in 0..32u8 {
+                storage.write_all(&[i; 4]).unwrap();
+            }
+            storage.finalize().unwrap();
+        }
+
+        let mut buf = [0u8; 4];
+        {
+            let mut storage = DiskStorage::open_with_checksums(BLOCK_32, tmp.to_str()).unwrap();
+            storage.probe(BlockIndex::new(7), &mut buf).unwrap();
+            assert_eq!(buf, [7; 4]);
+        }
+
+        // flip a byte of block 7
+        {
+            let mut ofile = OpenOptions::new().write(true).open(tmp.as_path()).unwrap();
+            ofile
+                .seek(SeekFrom::Start((HEADER_LEN + 7 * 4) as u64))
+                .unwrap();
+            ofile.write_all(&[0xff]).unwrap();
+        }
+
+        let mut storage = DiskStorage::open_with_checksums(BLOCK_32, tmp.to_str()).unwrap();
+        storage.probe(BlockIndex::new(6), &mut buf).unwrap();
+        match storage.probe(BlockIndex::new(7), &mut buf) {
+            Err(BigKeyError::BlockCorrupted { index: 7 }) => {}
+            _ => panic!("expected block 7 to be reported corrupted"),
+        }
+
+        std::fs::remove_file(sidecar_path(tmp.to_str())).unwrap();
+    }
+
     #[test]
     fn expected_size_must_be_ge_block_size() {
         for block in BLOCKS.iter() {
@@ -308,4 +1018,29 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn probes_are_timed() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            ofile.write_all(&[0x11u8; 256]).unwrap();
+        }
+
+        let mut storage = DiskStorage::open(BLOCK_32, tmp.to_str()).unwrap();
+        storage.set_slow_probe_threshold(Some(Duration::from_secs(0)));
+        let mut buf = [0u8; 4];
+        for index in 0..64 {
+            storage.probe(BlockIndex::new(index), &mut buf).unwrap();
+        }
+        assert!(storage.probe(BlockIndex::new(64), &mut buf).is_err());
+
+        let stats = storage.latency_stats();
+        assert_eq!(stats.count(), 64);
+        assert!(stats.min() <= stats.mean() && stats.mean() <= stats.max());
+        assert_eq!(stats.buckets().iter().sum::<u64>(), 64);
+
+        storage.reset_latency_stats();
+        assert_eq!(storage.latency_stats().count(), 0);
+    }
 } // mod test
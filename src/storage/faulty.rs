@@ -0,0 +1,244 @@
+//! Failure injection for testing BigKey error handling.
+//!
+//! `FaultyStorage` wraps any `StorageReader` and misbehaves on command: chosen block indices
+//! fail with an error, probe slowly or return corrupted contents. Faults are keyed by block
+//! index and counted, never random, so a test sees the same failures on every run:
+//!
+//! ```ignore
+//! let mut storage = FaultyStorage::new(ReadSeekStorage::new(Cursor::new(key), BLOCK_1K)?);
+//! storage.inject_times(7, Fault::Io(io::ErrorKind::TimedOut), 2);
+//! storage.inject(9, Fault::Corrupt);
+//! ```
+//!
+//! Only built with the `test-util` feature.
+
+use std::collections::HashMap;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Misbehaviour of a probe
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail with an IO error of this kind; see `BigKeyError::is_retryable()` for which kinds
+    /// count as transient
+    Io(io::ErrorKind),
+    /// Fail with `BlockCorrupted`, as a checksummed backend detecting the corruption would
+    Corrupted,
+    /// Succeed, but with every bit of the block flipped, as undetected corruption would
+    Corrupt,
+    /// Succeed after sleeping for the duration
+    Delay(Duration),
+}
+
+// A fault and how many more probes it applies to, `None` for all of them
+#[derive(Debug, Copy, Clone)]
+struct Injected {
+    fault: Fault,
+    remaining: Option<u32>,
+}
+
+/// A `StorageReader` injecting faults into the probes of `inner`. Probes without a fault are
+/// passed through unchanged.
+pub struct FaultyStorage<R: StorageReader> {
+    inner: R,
+    faults: HashMap<u64, Vec<Injected>>,
+    everywhere: Vec<Injected>,
+    probes: u64,
+    injected: u64,
+}
+
+impl<R: StorageReader> FaultyStorage<R> {
+    pub fn new(inner: R) -> Self {
+        FaultyStorage {
+            inner,
+            faults: HashMap::new(),
+            everywhere: Vec::new(),
+            probes: 0,
+            injected: 0,
+        }
+    }
+
+    /// Apply `fault` to every probe of block `index`
+    pub fn inject(&mut self, index: u64, fault: Fault) -> &mut Self {
+        self.add(Some(index), fault, None)
+    }
+
+    /// Apply `fault` to the next `times` probes of block `index`, e.g. a transient error that
+    /// a retry gets past
+    pub fn inject_times(&mut self, index: u64, fault: Fault, times: u32) -> &mut Self {
+        self.add(Some(index), fault, Some(times))
+    }
+
+    /// Apply `fault` to every probe of any block
+    pub fn inject_everywhere(&mut self, fault: Fault) -> &mut Self {
+        self.add(None, fault, None)
+    }
+
+    /// Remove every fault
+    pub fn clear(&mut self) {
+        self.faults.clear();
+        self.everywhere.clear();
+    }
+
+    /// Number of probes made so far, faulty or not
+    pub fn probes(&self) -> u64 {
+        self.probes
+    }
+
+    /// Number of faults injected so far; a probe hit by several faults counts each
+    pub fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn add(&mut self, index: Option<u64>, fault: Fault, remaining: Option<u32>) -> &mut Self {
+        let injected = Injected { fault, remaining };
+        match index {
+            Some(index) => self.faults.entry(index).or_default().push(injected),
+            None => self.everywhere.push(injected),
+        }
+        self.remove_spent();
+        self
+    }
+
+    // The faults hitting the next probe of `index`, in the order they were injected
+    fn take(&mut self, index: u64) -> Vec<Fault> {
+        let mut hits = Vec::new();
+        let scheduled = self
+            .everywhere
+            .iter_mut()
+            .chain(self.faults.get_mut(&index).into_iter().flatten());
+        for injected in scheduled {
+            hits.push(injected.fault);
+            if let Some(remaining) = injected.remaining.as_mut() {
+                *remaining -= 1;
+            }
+        }
+        self.remove_spent();
+        hits
+    }
+
+    fn remove_spent(&mut self) {
+        let live = |injected: &Injected| injected.remaining != Some(0);
+        self.everywhere.retain(live);
+        self.faults.retain(|_, faults| {
+            faults.retain(live);
+            !faults.is_empty()
+        });
+    }
+}
+
+impl<R: StorageReader> StorageReader for FaultyStorage<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        self.probes += 1;
+        let faults = self.take(index.get());
+        self.injected += faults.len() as u64;
+
+        let mut corrupt = false;
+        for fault in faults {
+            match fault {
+                Fault::Io(kind) => return Err(io::Error::from(kind).into()),
+                Fault::Corrupted => return Err(BigKeyError::BlockCorrupted { index: index.get() }),
+                Fault::Corrupt => corrupt = true,
+                Fault::Delay(delay) => thread::sleep(delay),
+            }
+        }
+
+        self.inner.probe(index, output)?;
+        if corrupt {
+            output.iter_mut().for_each(|b| *b = !*b);
+        }
+        Ok(())
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        self.inner.prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor};
+    use std::time::{Duration, Instant};
+
+    use crate::storage::{
+        Fault, FaultyStorage, ReadSeekStorage, RetryPolicy, RetryingStorage, StorageReader,
+    };
+    use crate::traits::{BigKeyError, BlockIndex, BLOCK_1K};
+
+    fn storage() -> FaultyStorage<ReadSeekStorage<Cursor<Vec<u8>>>> {
+        let key = (0..16 * 1024).map(|i| (i / 1024) as u8).collect();
+        FaultyStorage::new(ReadSeekStorage::new(Cursor::new(key), BLOCK_1K).unwrap())
+    }
+
+    #[test]
+    fn faults_hit_only_their_blocks() {
+        let mut storage = storage();
+        storage
+            .inject(3, Fault::Io(io::ErrorKind::NotFound))
+            .inject(4, Fault::Corrupted)
+            .inject(5, Fault::Corrupt)
+            .inject(6, Fault::Delay(Duration::from_millis(20)));
+        let mut block = vec![0u8; 1024];
+
+        storage.probe(BlockIndex::new(2), &mut block).unwrap();
+        assert!(block.iter().all(|b| *b == 2));
+        for _ in 0..2 {
+            assert!(matches!(
+                storage.probe(BlockIndex::new(3), &mut block),
+                Err(BigKeyError::IoError(e)) if e.kind() == io::ErrorKind::NotFound
+            ));
+        }
+        assert!(matches!(
+            storage.probe(BlockIndex::new(4), &mut block),
+            Err(BigKeyError::BlockCorrupted { index: 4 })
+        ));
+        storage.probe(BlockIndex::new(5), &mut block).unwrap();
+        assert!(block.iter().all(|b| *b == !5));
+        let started = Instant::now();
+        storage.probe(BlockIndex::new(6), &mut block).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert!(block.iter().all(|b| *b == 6));
+        assert_eq!((storage.probes(), storage.injected()), (6, 5));
+
+        storage.clear();
+        storage.probe(BlockIndex::new(5), &mut block).unwrap();
+        assert!(block.iter().all(|b| *b == 5));
+    }
+
+    #[test]
+    fn counted_faults_wear_off() {
+        let mut faulty = storage();
+        faulty
+            .inject_times(1, Fault::Io(io::ErrorKind::TimedOut), 2)
+            .inject_everywhere(Fault::Delay(Duration::from_millis(1)));
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let mut storage = RetryingStorage::new(faulty, policy);
+        let mut block = vec![0u8; 1024];
+
+        storage.probe(BlockIndex::new(1), &mut block).unwrap();
+        assert!(block.iter().all(|b| *b == 1));
+        assert_eq!(storage.retries(), 2);
+        let faulty = storage.into_inner();
+        assert_eq!((faulty.probes(), faulty.injected()), (3, 5));
+    }
+} // mod test
@@ -0,0 +1,266 @@
+//! Self-describing header stored at the start of a BigKey file.
+
+use std::convert::TryInto;
+use std::io::Read;
+
+use crate::storage::provenance::{Provenance, PROVENANCE_LEN, PROVENANCE_OFFSET};
+use crate::traits::{BigKeyError, BlockSize, GeneratorId, HashAlgorithm};
+
+/// Length of the on-disk header. A multiple of every supported `BlockSize` so that key data
+/// following the header stays block aligned.
+pub const HEADER_LEN: usize = 4096;
+
+/// Current header format version
+pub const HEADER_VERSION: u16 = 1;
+
+/// Header version of keys with a block permutation, which readers predating it must refuse
+pub const PERMUTED_HEADER_VERSION: u16 = 2;
+
+const MAGIC: &[u8; 8] = b"BFDISEK\x00";
+
+const FLAG_FINGERPRINT: u8 = 0x01;
+const FLAG_MERKLE_ROOT: u8 = 0x02;
+const FLAG_PERMUTATION: u8 = 0x04;
+const FLAG_HASH_ALGORITHM: u8 = 0x08;
+const FLAG_PROVENANCE: u8 = 0x10;
+
+/// Metadata describing the BigKey contents that follow the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyHeader {
+    pub version: u16,
+    pub generator: GeneratorId,
+    pub block_len: usize,
+    pub key_length: u64,
+    /// BLAKE3 digest of the key data (header excluded)
+    pub fingerprint: Option<[u8; 32]>,
+    /// Root of a Merkle tree over the key blocks
+    pub merkle_root: Option<[u8; 32]>,
+    /// Key of the block permutation the key data is stored in (see `storage::permutation`)
+    pub permutation: Option<[u8; 32]>,
+    /// Hash new keys are derived with by default, see `kem::SessionParams::algorithm`
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// Where the key came from, see `storage::Provenance`
+    pub provenance: Option<Provenance>,
+}
+
+impl KeyHeader {
+    pub fn new(generator: GeneratorId, block_size: BlockSize, key_length: u64) -> Self {
+        KeyHeader {
+            version: HEADER_VERSION,
+            generator,
+            block_len: block_size.byte_len,
+            key_length,
+            fingerprint: None,
+            merkle_root: None,
+            permutation: None,
+            hash_algorithm: None,
+            provenance: None,
+        }
+    }
+
+    /// Record that the key data is stored permuted under `key`
+    pub fn with_permutation(mut self, key: [u8; 32]) -> Self {
+        self.version = self.version.max(PERMUTED_HEADER_VERSION);
+        self.permutation = Some(key);
+        self
+    }
+
+    /// The `BlockSize` the key was generated with
+    pub fn block_size(&self) -> Result<BlockSize, BigKeyError> {
+        BlockSize::from_byte_len(self.block_len).ok_or(BigKeyError::InvalidHeader {
+            reason: "unsupported block size",
+        })
+    }
+
+    /// Number of blocks in the key
+    pub fn block_count(&self) -> u64 {
+        self.key_length / self.block_len as u64
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        let mut flags = 0u8;
+
+        out[0..8].copy_from_slice(MAGIC);
+        out[8..10].copy_from_slice(&self.version.to_be_bytes());
+        out[10..12].copy_from_slice(&(self.generator as u16).to_be_bytes());
+        out[12..16].copy_from_slice(&(self.block_len as u32).to_be_bytes());
+        out[16..24].copy_from_slice(&self.key_length.to_be_bytes());
+
+        if let Some(fingerprint) = self.fingerprint {
+            flags |= FLAG_FINGERPRINT;
+            out[25..57].copy_from_slice(&fingerprint);
+        }
+        if let Some(root) = self.merkle_root {
+            flags |= FLAG_MERKLE_ROOT;
+            out[57..89].copy_from_slice(&root);
+        }
+        if let Some(key) = self.permutation {
+            flags |= FLAG_PERMUTATION;
+            out[89..121].copy_from_slice(&key);
+        }
+        if let Some(algorithm) = self.hash_algorithm {
+            flags |= FLAG_HASH_ALGORITHM;
+            out[121] = algorithm.id();
+        }
+        if let Some(provenance) = &self.provenance {
+            flags |= FLAG_PROVENANCE;
+            out[PROVENANCE_OFFSET..PROVENANCE_OFFSET + PROVENANCE_LEN]
+                .copy_from_slice(&provenance.to_bytes());
+        }
+        out[24] = flags;
+
+        out
+    }
+
+    /// Parse a header. Returns `Ok(None)` if `bytes` does not start with a BigKey header at all
+    /// (e.g. a raw key file).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, BigKeyError> {
+        if bytes.len() < HEADER_LEN || &bytes[0..8] != MAGIC {
+            return Ok(None);
+        }
+
+        let version = u16::from_be_bytes(bytes[8..10].try_into().unwrap());
+        if version > PERMUTED_HEADER_VERSION {
+            return Err(BigKeyError::InvalidHeader {
+                reason: "unsupported header version",
+            });
+        }
+
+        let generator = GeneratorId::from_u16(u16::from_be_bytes(
+            bytes[10..12].try_into().unwrap(),
+        ))
+        .ok_or(BigKeyError::InvalidHeader {
+            reason: "unknown generator id",
+        })?;
+        let block_len = u32::from_be_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        let key_length = u64::from_be_bytes(bytes[16..24].try_into().unwrap());
+        let flags = bytes[24];
+        if flags & FLAG_PERMUTATION != 0 && version < PERMUTED_HEADER_VERSION {
+            return Err(BigKeyError::InvalidHeader {
+                reason: "block permutation needs header version 2",
+            });
+        }
+
+        let digest_at = |offset: usize, flag: u8| {
+            if flags & flag != 0 {
+                let mut digest = [0u8; 32];
+                digest.copy_from_slice(&bytes[offset..offset + 32]);
+                Some(digest)
+            } else {
+                None
+            }
+        };
+
+        let hash_algorithm = match flags & FLAG_HASH_ALGORITHM {
+            0 => None,
+            _ => Some(
+                HashAlgorithm::from_id(bytes[121]).ok_or(BigKeyError::InvalidHeader {
+                    reason: "unknown hash algorithm id",
+                })?,
+            ),
+        };
+
+        let provenance = match flags & FLAG_PROVENANCE {
+            0 => None,
+            _ => Some(Provenance::from_bytes(&bytes[PROVENANCE_OFFSET..])?),
+        };
+
+        let header = KeyHeader {
+            version,
+            generator,
+            block_len,
+            key_length,
+            fingerprint: digest_at(25, FLAG_FINGERPRINT),
+            merkle_root: digest_at(57, FLAG_MERKLE_ROOT),
+            permutation: digest_at(89, FLAG_PERMUTATION),
+            hash_algorithm,
+            provenance,
+        };
+
+        header.block_size()?;
+
+        Ok(Some(header))
+    }
+
+    /// Read the header (if any) from the start of `input`
+    pub fn read_from(input: &mut impl Read) -> Result<Option<Self>, BigKeyError> {
+        let mut buf = vec![0u8; HEADER_LEN];
+        let mut filled = 0;
+
+        while filled < HEADER_LEN {
+            match input.read(&mut buf[filled..])? {
+                0 => return Ok(None),
+                n => filled += n,
+            }
+        }
+
+        KeyHeader::from_bytes(&buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::header::{KeyHeader, HEADER_LEN};
+    use crate::storage::{OperatorSignature, Provenance};
+    use crate::traits::{BigKeyError, GeneratorId, HashAlgorithm, BLOCK_4K};
+
+    #[test]
+    fn header_round_trips() {
+        let mut header = KeyHeader::new(GeneratorId::Shake256, BLOCK_4K, 4096 * 16);
+        header.fingerprint = Some([0xaa; 32]);
+
+        let bytes = header.to_bytes();
+        assert_eq!(KeyHeader::from_bytes(&bytes).unwrap(), Some(header.clone()));
+
+        header.merkle_root = Some([0x55; 32]);
+        let bytes = header.to_bytes();
+        let parsed = KeyHeader::from_bytes(&bytes).unwrap().unwrap();
+        assert_eq!(parsed.block_count(), 16);
+        assert_eq!(parsed, header);
+
+        let mut header = header.with_permutation([0x33; 32]);
+        let bytes = header.to_bytes();
+        assert_eq!(u16::from_be_bytes([bytes[8], bytes[9]]), 2);
+        assert_eq!(KeyHeader::from_bytes(&bytes).unwrap(), Some(header.clone()));
+
+        header.hash_algorithm = Some(HashAlgorithm::Sha3_512);
+        let mut bytes = header.to_bytes();
+        assert_eq!(KeyHeader::from_bytes(&bytes).unwrap(), Some(header.clone()));
+        bytes[121] = 0xff;
+        assert!(KeyHeader::from_bytes(&bytes).is_err());
+
+        header.provenance = Some(Provenance {
+            seed_commitment: Some([0x77; 32]),
+            host: "keygen-01.example".to_string(),
+            created_at: 1_700_000_000,
+            operator: Some(OperatorSignature {
+                public_key: [0x12; 32],
+                signature: [0x34; 64],
+            }),
+        });
+        let bytes = header.to_bytes();
+        assert_eq!(KeyHeader::from_bytes(&bytes).unwrap(), Some(header.clone()));
+        header.provenance = Some(Provenance::new(None));
+        let bytes = header.to_bytes();
+        assert_eq!(KeyHeader::from_bytes(&bytes).unwrap(), Some(header));
+    }
+
+    #[test]
+    fn raw_key_data_is_not_a_header() {
+        let bytes = [0x42u8; HEADER_LEN];
+        assert_eq!(KeyHeader::from_bytes(&bytes).unwrap(), None);
+        assert_eq!(KeyHeader::from_bytes(&bytes[..16]).unwrap(), None);
+    }
+
+    #[test]
+    fn future_header_version_fails() {
+        let mut header = KeyHeader::new(GeneratorId::Shake256, BLOCK_4K, 4096);
+        header.version = 99;
+
+        match KeyHeader::from_bytes(&header.to_bytes()) {
+            Err(BigKeyError::InvalidHeader { .. }) => {}
+            _ => panic!("expected unsupported header version to be rejected"),
+        }
+    }
+} // mod test
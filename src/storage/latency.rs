@@ -0,0 +1,134 @@
+//! Probe latency accounting.
+//!
+//! Key derivation makes many small random reads, so its latency is dominated by the slowest
+//! probes. A drive that benchmarks well sequentially (e.g. an SMR disk behind a cache) can still
+//! stall individual random reads for tens of milliseconds; `LatencyStats` keeps a histogram of
+//! every probe so such outliers show up in the tail percentiles.
+
+use std::time::Duration;
+
+/// Histogram buckets, each twice as wide as the last: bucket `i > 0` counts latencies in
+/// `[2^(i-1), 2^i)` microseconds, the last bucket everything above
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Histogram and summary of probe latencies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyStats {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        LatencyStats {
+            buckets: [0; LATENCY_BUCKETS],
+            count: 0,
+            total: Duration::from_secs(0),
+            min: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+        }
+    }
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[bucket] += 1;
+
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.count += 1;
+    }
+
+    /// Number of probes recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::from_secs(0),
+            n => Duration::from_nanos((self.total.as_nanos() / n as u128) as u64),
+        }
+    }
+
+    /// Upper bound of the histogram bucket holding the `p`th percentile (`0.0..=1.0`), capped at
+    /// `max()`
+    pub fn percentile(&self, p: f64) -> Duration {
+        let target = (p.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            // the last bucket has no upper bound but the slowest probe
+            if seen >= target && bucket < LATENCY_BUCKETS - 1 {
+                return Duration::from_micros(1 << bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Probe counts per bucket, see `LATENCY_BUCKETS`
+    pub fn buckets(&self) -> &[u64; LATENCY_BUCKETS] {
+        &self.buckets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::storage::latency::{LatencyStats, LATENCY_BUCKETS};
+
+    #[test]
+    fn percentiles_expose_slow_tail() {
+        let mut stats = LatencyStats::default();
+        for _ in 0..99 {
+            stats.record(Duration::from_micros(80));
+        }
+        stats.record(Duration::from_millis(40));
+
+        assert_eq!(stats.count(), 100);
+        assert_eq!(stats.min(), Duration::from_micros(80));
+        assert_eq!(stats.max(), Duration::from_millis(40));
+        assert_eq!(stats.percentile(0.5), Duration::from_micros(128));
+        assert_eq!(stats.percentile(0.99), Duration::from_micros(128));
+        assert_eq!(stats.percentile(1.0), Duration::from_millis(40));
+        assert_eq!(stats.buckets()[7], 99);
+    }
+
+    #[test]
+    fn extremes_land_in_the_outer_buckets() {
+        let empty = LatencyStats::default();
+        assert_eq!(empty.mean(), Duration::from_secs(0));
+        assert_eq!(empty.percentile(0.99), Duration::from_secs(0));
+
+        let mut stats = LatencyStats::default();
+        stats.record(Duration::from_nanos(300));
+        stats.record(Duration::from_secs(3 * 3600));
+        assert_eq!(stats.buckets()[0], 1);
+        assert_eq!(stats.buckets()[LATENCY_BUCKETS - 1], 1);
+        assert_eq!(stats.min(), Duration::from_nanos(300));
+        assert_eq!(stats.max(), Duration::from_secs(3 * 3600));
+
+        // percentiles outside 0..=1, or not a number, are clamped rather than panicking
+        assert_eq!(stats.percentile(-1.0), stats.percentile(0.0));
+        assert_eq!(stats.percentile(2.0), stats.max());
+        assert_eq!(stats.percentile(f64::NAN), stats.percentile(0.0));
+    }
+} // mod test
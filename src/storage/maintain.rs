@@ -0,0 +1,417 @@
+//! Background scrubbing of a BigKey while it is otherwise idle.
+//!
+//! Cheap flash silently loses data. A `Maintainer` walks the key block by block, verifying each
+//! against its checksum sidecar (see `storage::checksum`), but only while no key derivation has
+//! probed the key for a while: foreground readers are wrapped in an `ActivityReader` sharing a
+//! `ProbeActivity` with the maintainer, which backs off whenever they are busy.
+//!
+//! Progress and any corrupted blocks are kept in a small TOML sidecar (`<key>.scrub`), so
+//! scrubbing resumes where it stopped after a restart.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::util::StorageContext;
+use crate::storage::{CancellationToken, DiskStorage, StorageReader};
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Time of the most recent foreground probe, shared between readers and a `Maintainer`
+#[derive(Debug, Clone)]
+pub struct ProbeActivity {
+    epoch: Instant,
+    last_probe_nanos: Arc<AtomicU64>,
+}
+
+impl Default for ProbeActivity {
+    fn default() -> Self {
+        ProbeActivity {
+            epoch: Instant::now(),
+            last_probe_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl ProbeActivity {
+    pub fn new() -> Self {
+        ProbeActivity::default()
+    }
+
+    /// Record a probe happening now
+    pub fn touch(&self) {
+        let nanos = self.epoch.elapsed().as_nanos() as u64;
+        self.last_probe_nanos.store(nanos, Ordering::Relaxed);
+    }
+
+    /// Time since the last recorded probe (or since creation)
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_nanos(self.last_probe_nanos.load(Ordering::Relaxed));
+        self.epoch.elapsed().saturating_sub(last)
+    }
+}
+
+/// A `StorageReader` that records every probe in a `ProbeActivity`
+pub struct ActivityReader<R: StorageReader> {
+    inner: R,
+    activity: ProbeActivity,
+}
+
+impl<R: StorageReader> ActivityReader<R> {
+    pub fn new(inner: R, activity: ProbeActivity) -> Self {
+        ActivityReader { inner, activity }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: StorageReader> StorageReader for ActivityReader<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        self.activity.touch();
+        self.inner.probe(index, output)
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        self.inner.prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+}
+
+/// How eagerly a `Maintainer` scrubs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MaintainerConfig {
+    /// Scrub only once the key has not been probed for this long
+    pub idle_threshold: Duration,
+    /// Blocks verified per step before checking for activity again
+    pub batch_blocks: u64,
+    /// Pause between steps
+    pub pause: Duration,
+}
+
+impl Default for MaintainerConfig {
+    fn default() -> Self {
+        MaintainerConfig {
+            idle_threshold: Duration::from_millis(500),
+            batch_blocks: 64,
+            pause: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Scrubbing progress persisted in the `<key>.scrub` sidecar
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubState {
+    /// Next block to verify
+    pub cursor: u64,
+    /// Completed passes over the whole key
+    pub passes: u64,
+    /// Blocks that failed verification, in the order found
+    pub corrupted_blocks: Vec<u64>,
+}
+
+/// Location of the scrub state sidecar for the key at `storage_location`
+pub fn scrub_state_path(storage_location: &str) -> PathBuf {
+    PathBuf::from(format!("{}.scrub", storage_location))
+}
+
+/// Verifies a BigKey against its checksum sidecar during idle periods
+pub struct Maintainer {
+    storage: DiskStorage,
+    activity: ProbeActivity,
+    config: MaintainerConfig,
+    state: ScrubState,
+    state_path: PathBuf,
+}
+
+impl Maintainer {
+    /// Maintain the key at `storage_location`, which needs a checksum sidecar, backing off while
+    /// `activity` shows foreground probes. Resumes from the scrub state sidecar if present.
+    pub fn open(
+        block_size: BlockSize,
+        storage_location: &str,
+        activity: ProbeActivity,
+        config: MaintainerConfig,
+    ) -> Result<Maintainer, BigKeyError> {
+        let storage = DiskStorage::open_with_checksums(block_size, storage_location)?;
+        let state_path = scrub_state_path(storage_location);
+
+        // a missing or unreadable state only costs re-verifying blocks, so start over
+        let mut state: ScrubState = std::fs::read_to_string(&state_path)
+            .ok()
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default();
+        if state.cursor >= storage.big_key_length() / block_size.byte_len as u64 {
+            state.cursor = 0;
+        }
+
+        Ok(Maintainer {
+            storage,
+            activity,
+            config,
+            state,
+            state_path,
+        })
+    }
+
+    pub fn state(&self) -> &ScrubState {
+        &self.state
+    }
+
+    /// Verify up to `batch_blocks` blocks if the key is idle, returning how many were verified
+    pub fn step(&mut self) -> Result<u64, BigKeyError> {
+        if self.activity.idle_for() < self.config.idle_threshold {
+            return Ok(0);
+        }
+
+        let block_len = self.storage.block_size().byte_len;
+        let block_count = self.storage.big_key_length() / block_len as u64;
+        let mut block = vec![0u8; block_len];
+        let mut verified = 0;
+
+        while verified < self.config.batch_blocks {
+            let index = self.state.cursor;
+            match self.storage.probe(BlockIndex::new(index), &mut block) {
+                Ok(()) => self.state.corrupted_blocks.retain(|b| *b != index),
+                Err(BigKeyError::BlockCorrupted { index }) => {
+                    if !self.state.corrupted_blocks.contains(&index) {
+                        log::warn!("scrub found corrupted block {}", index);
+                        self.state.corrupted_blocks.push(index);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            verified += 1;
+
+            self.state.cursor += 1;
+            if self.state.cursor == block_count {
+                self.state.cursor = 0;
+                self.state.passes += 1;
+                break;
+            }
+        }
+
+        self.save()?;
+        Ok(verified)
+    }
+
+    /// Rewrite the blocks found corrupted with their contents from `source`, such as a replica
+    /// of the key, returning the indices repaired. Concurrent probes of the key wait for each
+    /// rewrite rather than seeing a torn block (see `DiskStorage::repair_block()`). A source
+    /// block that does not match the checksum sidecar is refused with `BlockCorrupted`.
+    pub fn repair_from<R: StorageReader + ?Sized>(
+        &mut self,
+        source: &mut R,
+    ) -> Result<Vec<u64>, BigKeyError> {
+        let mut block = vec![0u8; self.storage.block_size().byte_len];
+        let mut repaired = Vec::new();
+        let mut result = Ok(());
+
+        for index in self.state.corrupted_blocks.clone() {
+            result = source
+                .probe(BlockIndex::new(index), &mut block)
+                .and_then(|_| self.storage.repair_block(BlockIndex::new(index), &block));
+            if result.is_err() {
+                break;
+            }
+            repaired.push(index);
+        }
+
+        self.state
+            .corrupted_blocks
+            .retain(|index| !repaired.contains(index));
+        self.save()?;
+        result.map(|_| repaired)
+    }
+
+    /// Scrub on a background thread until `cancel` is cancelled, returning the final state
+    pub fn spawn(
+        mut self,
+        cancel: CancellationToken,
+    ) -> JoinHandle<Result<ScrubState, BigKeyError>> {
+        std::thread::spawn(move || {
+            while !cancel.is_cancelled() {
+                match self.step()? {
+                    0 => std::thread::sleep(self.config.idle_threshold / 2),
+                    _ => std::thread::sleep(self.config.pause),
+                }
+            }
+            Ok(self.state)
+        })
+    }
+
+    fn save(&self) -> Result<(), BigKeyError> {
+        let contents = toml::to_string(&self.state).map_err(|e| BigKeyError::InvalidConfig {
+            reason: format!("scrub state: {}", e),
+        })?;
+        std::fs::write(&self.state_path, contents)
+            .context("write", &self.state_path.to_string_lossy())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+    use std::time::Duration;
+
+    use crate::storage::checksum::sidecar_path;
+    use crate::storage::header::HEADER_LEN;
+    use crate::storage::maintain::{
+        scrub_state_path, ActivityReader, Maintainer, MaintainerConfig, ProbeActivity,
+    };
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{
+        CancellationToken, DiskStorage, ReadSeekStorage, StorageReader, StorageWriter,
+    };
+    use crate::traits::{BigKeyError, BlockIndex, BLOCK_1K};
+
+    fn key_with_checksums() -> crate::storage::tempfile::TempFile {
+        let tmp = tempfile();
+        let mut writer =
+            DiskStorage::new_writer_with_checksums(BLOCK_1K, tmp.to_str(), 16 * 1024).unwrap();
+        for i in 0..16u8 {
+            writer.write_all(&[i; 1024]).unwrap();
+        }
+        writer.finalize().unwrap();
+        tmp
+    }
+
+    fn cleanup(tmp: &crate::storage::tempfile::TempFile) {
+        let _ = std::fs::remove_file(sidecar_path(tmp.to_str()));
+        let _ = std::fs::remove_file(scrub_state_path(tmp.to_str()));
+    }
+
+    #[test]
+    fn scrub_finds_corruption_and_resumes() {
+        let tmp = key_with_checksums();
+        {
+            let mut file = OpenOptions::new().write(true).open(tmp.as_path()).unwrap();
+            file.seek(SeekFrom::Start(HEADER_LEN as u64 + 5 * 1024))
+                .unwrap();
+            file.write_all(&[0xff; 8]).unwrap();
+        }
+
+        let config = MaintainerConfig {
+            idle_threshold: Duration::from_secs(0),
+            batch_blocks: 10,
+            ..MaintainerConfig::default()
+        };
+        let mut maintainer =
+            Maintainer::open(BLOCK_1K, tmp.to_str(), ProbeActivity::new(), config).unwrap();
+        assert_eq!(maintainer.step().unwrap(), 10);
+        assert_eq!(maintainer.state().corrupted_blocks, vec![5]);
+        drop(maintainer);
+
+        let mut maintainer =
+            Maintainer::open(BLOCK_1K, tmp.to_str(), ProbeActivity::new(), config).unwrap();
+        assert_eq!(maintainer.state().cursor, 10);
+        assert_eq!(maintainer.step().unwrap(), 6);
+        assert_eq!(maintainer.state().passes, 1);
+        assert_eq!(maintainer.state().cursor, 0);
+
+        cleanup(&tmp);
+    }
+
+    #[test]
+    fn repair_never_tears_concurrent_probes() {
+        let tmp = key_with_checksums();
+        {
+            let mut file = OpenOptions::new().write(true).open(tmp.as_path()).unwrap();
+            file.seek(SeekFrom::Start(HEADER_LEN as u64 + 5 * 1024))
+                .unwrap();
+            file.write_all(&[0xff; 512]).unwrap();
+        }
+        let config = MaintainerConfig {
+            idle_threshold: Duration::from_secs(0),
+            ..MaintainerConfig::default()
+        };
+        let mut maintainer =
+            Maintainer::open(BLOCK_1K, tmp.to_str(), ProbeActivity::new(), config).unwrap();
+        maintainer.step().unwrap();
+        assert_eq!(maintainer.state().corrupted_blocks, vec![5]);
+
+        let wrong: Vec<u8> = (0..16u8).flat_map(|i| vec![i ^ 1; 1024]).collect();
+        let mut wrong = ReadSeekStorage::new(Cursor::new(wrong), BLOCK_1K).unwrap();
+        match maintainer.repair_from(&mut wrong) {
+            Err(BigKeyError::BlockCorrupted { index }) => assert_eq!(index, 5),
+            _ => panic!("expected a mismatching source block to be refused"),
+        }
+        assert_eq!(maintainer.state().corrupted_blocks, vec![5]);
+
+        let location = tmp.to_str().to_string();
+        let cancel = CancellationToken::new();
+        let reader_cancel = cancel.clone();
+        let reader = std::thread::spawn(move || {
+            let mut storage = DiskStorage::open(BLOCK_1K, &location).unwrap();
+            let mut block = [0u8; 1024];
+            while !reader_cancel.is_cancelled() {
+                storage.probe(BlockIndex::new(5), &mut block).unwrap();
+                let corrupted =
+                    block[..512].iter().all(|b| *b == 0xff) && block[512..].iter().all(|b| *b == 5);
+                let repaired = block.iter().all(|b| *b == 5);
+                assert!(corrupted || repaired, "probe observed a torn block");
+            }
+        });
+
+        let source: Vec<u8> = (0..16u8).flat_map(|i| vec![i; 1024]).collect();
+        let mut source = ReadSeekStorage::new(Cursor::new(source), BLOCK_1K).unwrap();
+        for _ in 0..50 {
+            assert_eq!(maintainer.repair_from(&mut source).unwrap(), vec![5]);
+            maintainer.state.corrupted_blocks.push(5);
+        }
+        cancel.cancel();
+        reader.join().unwrap();
+
+        let mut storage = DiskStorage::open_with_checksums(BLOCK_1K, tmp.to_str()).unwrap();
+        storage.probe(BlockIndex::new(5), &mut [0u8; 1024]).unwrap();
+        cleanup(&tmp);
+    }
+
+    #[test]
+    fn busy_key_is_not_scrubbed() {
+        let tmp = key_with_checksums();
+        let activity = ProbeActivity::new();
+        let mut reader = ActivityReader::new(
+            DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap(),
+            activity.clone(),
+        );
+        let config = MaintainerConfig {
+            idle_threshold: Duration::from_secs(3600),
+            ..MaintainerConfig::default()
+        };
+        let mut maintainer =
+            Maintainer::open(BLOCK_1K, tmp.to_str(), activity.clone(), config).unwrap();
+
+        reader.probe(BlockIndex::new(3), &mut [0u8; 1024]).unwrap();
+        assert!(activity.idle_for() < Duration::from_secs(3600));
+        assert_eq!(maintainer.step().unwrap(), 0);
+
+        let config = MaintainerConfig {
+            idle_threshold: Duration::from_secs(0),
+            pause: Duration::from_millis(1),
+            ..MaintainerConfig::default()
+        };
+        let maintainer = Maintainer::open(BLOCK_1K, tmp.to_str(), activity, config).unwrap();
+        let cancel = CancellationToken::new();
+        let handle = maintainer.spawn(cancel.clone());
+        std::thread::sleep(Duration::from_millis(50));
+        cancel.cancel();
+        let state = handle.join().unwrap().unwrap();
+        assert!(state.passes >= 1);
+        assert!(state.corrupted_blocks.is_empty());
+
+        cleanup(&tmp);
+    }
+} // mod test
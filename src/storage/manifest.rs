@@ -0,0 +1,281 @@
+//! Portable description of a BigKey, used to check that a copy of the key (e.g. one shipped to
+//! another data center) is identical to the original without moving the key itself.
+//!
+//! Manifests are small TOML files:
+//!
+//! ```toml
+//! version = 1
+//! generator = 1
+//! block_size = 4096
+//! key_length = 1073741824
+//! fingerprint = "5f1d...e2a0"
+//! merkle_root = "0c9b...71d4"   # only present if the key header records one
+//! ```
+//!
+//! `export_with()` can also write them compressed (see `MetadataCompression`); `import()` reads
+//! either.
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::compression::{compress_metadata, decompress_metadata, MetadataCompression};
+use crate::storage::header::KeyHeader;
+use crate::storage::verify::fingerprint;
+use crate::storage::{DiskStorage, StorageReader};
+use crate::traits::{BigKeyError, BlockSize, GeneratorId};
+
+/// Current manifest format version
+const MANIFEST_VERSION: u16 = 1;
+
+/// Everything needed to validate a copy of a BigKey
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub generator: GeneratorId,
+    pub block_len: usize,
+    pub key_length: u64,
+    /// BLAKE3 digest of the key data, as recorded in `KeyHeader::fingerprint`
+    pub fingerprint: [u8; 32],
+    pub merkle_root: Option<[u8; 32]>,
+}
+
+// On-disk representation
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    version: u16,
+    generator: u16,
+    block_size: usize,
+    key_length: u64,
+    fingerprint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    merkle_root: Option<String>,
+}
+
+impl Manifest {
+    /// Manifest of the key described by `header`, which must record a fingerprint
+    pub fn from_header(header: &KeyHeader) -> Result<Manifest, BigKeyError> {
+        let fingerprint = header
+            .fingerprint
+            .ok_or_else(|| invalid("key header records no fingerprint"))?;
+
+        Ok(Manifest {
+            generator: header.generator,
+            block_len: header.block_len,
+            key_length: header.key_length,
+            fingerprint,
+            merkle_root: header.merkle_root,
+        })
+    }
+
+    /// Manifest of the key file at `storage_location`, taken from its header
+    pub fn for_key(storage_location: &str) -> Result<Manifest, BigKeyError> {
+        match DiskStorage::read_header(storage_location)? {
+            Some(header) => Manifest::from_header(&header),
+            None => Err(invalid("raw key file has no header")),
+        }
+    }
+
+    /// The `BlockSize` the key was generated with
+    pub fn block_size(&self) -> Result<BlockSize, BigKeyError> {
+        BlockSize::from_byte_len(self.block_len).ok_or_else(|| invalid("unsupported block size"))
+    }
+
+    /// Write the manifest to `path`
+    pub fn export(&self, path: impl AsRef<Path>) -> Result<(), BigKeyError> {
+        self.export_with(path, MetadataCompression::None)
+    }
+
+    /// Write the manifest to `path` with `compression`
+    pub fn export_with(
+        &self,
+        path: impl AsRef<Path>,
+        compression: MetadataCompression,
+    ) -> Result<(), BigKeyError> {
+        let contents = compress_metadata(self.to_toml()?.as_bytes(), compression)?;
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Read a manifest written by `export()` or `export_with()`
+    pub fn import(path: impl AsRef<Path>) -> Result<Manifest, BigKeyError> {
+        let contents = decompress_metadata(&std::fs::read(path)?)?;
+        Manifest::from_toml(
+            std::str::from_utf8(&contents).map_err(|_| invalid("manifest is not UTF-8"))?,
+        )
+    }
+
+    /// The manifest as TOML, as written by `export()`
+    pub fn to_toml(&self) -> Result<String, BigKeyError> {
+        let file = ManifestFile {
+            version: MANIFEST_VERSION,
+            generator: self.generator as u16,
+            block_size: self.block_len,
+            key_length: self.key_length,
+            fingerprint: to_hex(&self.fingerprint),
+            merkle_root: self.merkle_root.as_ref().map(|root| to_hex(root)),
+        };
+        toml::to_string(&file).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Parse a manifest from TOML
+    pub fn from_toml(contents: &str) -> Result<Manifest, BigKeyError> {
+        let file: ManifestFile = toml::from_str(contents).map_err(|e| invalid(e.to_string()))?;
+
+        if file.version > MANIFEST_VERSION {
+            return Err(invalid(format!(
+                "unsupported manifest version {}",
+                file.version
+            )));
+        }
+
+        let manifest = Manifest {
+            generator: GeneratorId::from_u16(file.generator)
+                .ok_or_else(|| invalid("unknown generator id"))?,
+            block_len: file.block_size,
+            key_length: file.key_length,
+            fingerprint: from_hex(&file.fingerprint)?,
+            merkle_root: file.merkle_root.as_deref().map(from_hex).transpose()?,
+        };
+        manifest.block_size()?;
+
+        Ok(manifest)
+    }
+
+    /// Check the key file at `storage_location` against this manifest, reading the entire key
+    /// to recompute its fingerprint. Raw key files without a header can be verified too.
+    pub fn verify(&self, storage_location: &str) -> Result<(), BigKeyError> {
+        let mut storage = DiskStorage::open(self.block_size()?, storage_location)?;
+
+        if storage.big_key_length() != self.key_length {
+            return Err(mismatch("key length differs from manifest"));
+        }
+
+        if let Some(header) = storage.header() {
+            if header.generator != GeneratorId::Unknown && header.generator != self.generator {
+                return Err(mismatch("generator differs from manifest"));
+            }
+            if let (Some(root), Some(expected)) = (header.merkle_root, self.merkle_root) {
+                if root != expected {
+                    return Err(mismatch("merkle root differs from manifest"));
+                }
+            }
+        }
+
+        if fingerprint(&mut storage)? != self.fingerprint {
+            return Err(mismatch("fingerprint differs from manifest"));
+        }
+
+        Ok(())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<[u8; 32], BigKeyError> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid("digest must be 64 hex digits"));
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid("digest must be 64 hex digits"))?;
+
+    Ok(bytes[..].try_into().unwrap())
+}
+
+fn invalid(reason: impl Into<String>) -> BigKeyError {
+    BigKeyError::InvalidManifest {
+        reason: reason.into(),
+    }
+}
+
+fn mismatch(stage: &'static str) -> BigKeyError {
+    BigKeyError::VerificationFailed { stage }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::header::HEADER_LEN;
+    use crate::storage::tempfile::{tempfile, TempFile};
+    use crate::storage::{DiskStorage, Manifest, StorageWriter};
+    use crate::traits::{BigKeyError, GeneratorId, BLOCK_1K};
+
+    fn key_file() -> TempFile {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 16 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 16 * 1024).unwrap();
+        tmp
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let key = key_file();
+        let path = tempfile();
+
+        let mut manifest = Manifest::for_key(key.to_str()).unwrap();
+        assert_eq!(manifest.generator, GeneratorId::Shake256);
+        assert_eq!(manifest.key_length, 16 * 1024);
+
+        manifest.export(path.as_path()).unwrap();
+        assert_eq!(Manifest::import(path.as_path()).unwrap(), manifest);
+
+        manifest.merkle_root = Some([0x5a; 32]);
+        manifest.export(path.as_path()).unwrap();
+        assert_eq!(Manifest::import(path.as_path()).unwrap(), manifest);
+
+        #[cfg(feature = "compression")]
+        {
+            let compression = crate::storage::MetadataCompression::Zstd { level: 3 };
+            manifest.export_with(path.as_path(), compression).unwrap();
+            assert!(crate::storage::is_compressed(
+                &std::fs::read(path.as_path()).unwrap()
+            ));
+            assert_eq!(Manifest::import(path.as_path()).unwrap(), manifest);
+        }
+    }
+
+    #[test]
+    fn copy_is_verified_against_manifest() {
+        let key = key_file();
+        let manifest = Manifest::for_key(key.to_str()).unwrap();
+        manifest.verify(key.to_str()).unwrap();
+
+        {
+            let mut file = OpenOptions::new().write(true).open(key.as_path()).unwrap();
+            file.seek(SeekFrom::Start(HEADER_LEN as u64 + 5000))
+                .unwrap();
+            file.write_all(&[0xff]).unwrap();
+        }
+
+        match manifest.verify(key.to_str()) {
+            Err(BigKeyError::VerificationFailed { .. }) => {}
+            _ => panic!("expected modified key to fail verification"),
+        }
+    }
+
+    #[test]
+    fn malformed_manifest_fails() {
+        let path = tempfile();
+        std::fs::write(
+            path.as_path(),
+            "version = 1\ngenerator = 1\nblock_size = 1024\nkey_length = 1024\nfingerprint = \"zz\"\n",
+        )
+        .unwrap();
+
+        match Manifest::import(path.as_path()) {
+            Err(BigKeyError::InvalidManifest { .. }) => {}
+            _ => panic!("expected malformed fingerprint to be rejected"),
+        }
+    }
+} // mod test
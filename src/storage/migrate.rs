@@ -0,0 +1,245 @@
+//! Changing the block size of an existing BigKey.
+//!
+//! The key bytes are unchanged by migration, only the unit they are probed in. Keys derived
+//! under the old block size probed whole old-size blocks, so their locators only re-derive when
+//! the migrated key is read through a `RechunkedReader` with the old block size;
+//! `block_position()` gives the location of an old block in the migrated key.
+
+use crate::storage::util::{block_offset, check_key_evenly_divisible};
+use crate::storage::{StorageReader, StorageWriter};
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Rewrite the key in `reader` to `writer`, which must have been created with `new_block_size`
+/// and the same length. The caller sets the writer's generator, if known. Returns the number of
+/// bytes copied.
+pub fn migrate_block_size<R: StorageReader + ?Sized, W: StorageWriter>(
+    reader: &mut R,
+    writer: &mut W,
+    new_block_size: BlockSize,
+) -> Result<u64, BigKeyError> {
+    let key_length = reader.big_key_length();
+
+    if writer.block_size().byte_len != new_block_size.byte_len {
+        return Err(BigKeyError::BlockSizeMismatch {
+            requested_len: new_block_size.byte_len,
+            header_len: writer.block_size().byte_len,
+        });
+    }
+    if writer.expected_big_key_length() != key_length {
+        return Err(BigKeyError::InvalidConfig {
+            reason: "migrated key must be the same length as the original".to_string(),
+        });
+    }
+    check_key_evenly_divisible(new_block_size, key_length)?;
+
+    let mut block = vec![0u8; reader.block_size().byte_len];
+    for index in reader.block_count().indices() {
+        reader.probe(index, &mut block)?;
+        writer.write_all(&block)?;
+    }
+    writer.finalize()?;
+
+    Ok(key_length)
+}
+
+/// Block index and byte offset within that block of block `index` of size `from`, in the same
+/// key chunked into blocks of size `to`. Migrating to a larger block size puts several old
+/// blocks in one new block; to a smaller one, the old block starts at offset 0 of a new block
+/// and spans `from / to` of them.
+pub fn block_position(index: BlockIndex, from: BlockSize, to: BlockSize) -> (BlockIndex, usize) {
+    let byte_offset = index.get() * from.byte_len as u64;
+    (
+        BlockIndex::new(byte_offset / to.byte_len as u64),
+        (byte_offset % to.byte_len as u64) as usize,
+    )
+}
+
+/// Presents the key in a `StorageReader` in blocks of a different size, e.g. to re-derive keys
+/// whose locators predate a block size migration
+pub struct RechunkedReader<R: StorageReader> {
+    inner: R,
+    block_size: BlockSize,
+    buf: Vec<u8>,
+}
+
+impl<R: StorageReader> RechunkedReader<R> {
+    pub fn new(inner: R, block_size: BlockSize) -> Result<Self, BigKeyError> {
+        check_key_evenly_divisible(block_size, inner.big_key_length())?;
+        let buf = vec![0u8; inner.block_size().byte_len];
+        Ok(RechunkedReader {
+            inner,
+            block_size,
+            buf,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: StorageReader> StorageReader for RechunkedReader<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        let block_len = self.block_size.byte_len;
+        if output.len() != block_len {
+            return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+                out_buf_len: output.len(),
+                block_len,
+            });
+        }
+
+        // bounds checked in the outer block size, so the position below cannot overflow
+        block_offset(index, self.block_size, self.big_key_length())?;
+        let inner_len = self.buf.len();
+        let (mut inner_index, mut offset) =
+            block_position(index, self.block_size, self.inner.block_size());
+        let mut copied = 0;
+
+        while copied < block_len {
+            self.inner.probe(inner_index, &mut self.buf)?;
+            let n = (inner_len - offset).min(block_len - copied);
+            output[copied..copied + n].copy_from_slice(&self.buf[offset..offset + n]);
+            copied += n;
+            inner_index = BlockIndex::new(inner_index.get() + 1);
+            offset = 0;
+        }
+
+        Ok(())
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        let (block_len, inner_len) = (self.block_size.byte_len, self.buf.len());
+        let mut inner_indices = Vec::with_capacity(indices.len());
+        for &index in indices {
+            block_offset(index, self.block_size, self.big_key_length())?;
+            let (first, offset) = block_position(index, self.block_size, self.inner.block_size());
+            let spanned = (offset + block_len).div_ceil(inner_len) as u64;
+            inner_indices.extend((0..spanned).map(|i| BlockIndex::new(first.get() + i)));
+        }
+        self.inner.prefetch(&inner_indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::kem::{BigKey, BigKeyKem};
+    use crate::storage::migrate::{block_position, migrate_block_size, RechunkedReader};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{fingerprint, DiskStorage, StorageReader, StorageWriter};
+    use crate::traits::{
+        BigKeyError, BlockIndex, BlockSize, SecurityLevel, BLOCK_1K, BLOCK_4K, BLOCK_64,
+    };
+
+    #[test]
+    fn block_positions() {
+        let position = |index, from, to| {
+            let (block, offset) = block_position(BlockIndex::new(index), from, to);
+            (block.get(), offset)
+        };
+        assert_eq!(position(5, BLOCK_1K, BLOCK_4K), (1, 1024));
+        assert_eq!(position(5, BLOCK_4K, BLOCK_1K), (20, 0));
+        assert_eq!(position(7, BLOCK_64, BLOCK_64), (7, 0));
+    }
+
+    #[test]
+    fn migrated_key_keeps_content_and_old_locators() {
+        let original = tempfile();
+        let migrated = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, original.to_str(), 64 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 64 * 1024).unwrap();
+
+        let mut reader = DiskStorage::open(BLOCK_1K, original.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut reader, Sha3_256::new());
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+
+        let mut writer = DiskStorage::new_writer(BLOCK_4K, migrated.to_str(), 64 * 1024).unwrap();
+        assert_eq!(
+            migrate_block_size(&mut reader, &mut writer, BLOCK_4K).unwrap(),
+            64 * 1024
+        );
+
+        let mut new_reader = DiskStorage::open(BLOCK_4K, migrated.to_str()).unwrap();
+        assert_eq!(
+            fingerprint(&mut new_reader).unwrap(),
+            fingerprint(&mut reader).unwrap()
+        );
+
+        let mut old_view = RechunkedReader::new(new_reader, BLOCK_1K).unwrap();
+        let mut block = [0u8; 1024];
+        let mut expected = [0u8; 1024];
+        old_view.probe(BlockIndex::new(17), &mut block).unwrap();
+        reader.probe(BlockIndex::new(17), &mut expected).unwrap();
+        assert_eq!(block, expected);
+
+        let mut bk =
+            BigKey::new_big_key(SecurityLevel::Bits128, 0.2, &mut old_view, Sha3_256::new());
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+    }
+
+    // Records the blocks prefetched through it
+    struct Prefetches(BlockSize, Vec<u64>);
+
+    impl StorageReader for Prefetches {
+        fn probe(&mut self, _index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+            output.fill(0);
+            Ok(())
+        }
+
+        fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+            self.1.extend(indices.iter().map(|index| index.get()));
+            Ok(())
+        }
+
+        fn big_key_length(&self) -> u64 {
+            64 * 1024
+        }
+
+        fn block_size(&self) -> BlockSize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn prefetches_cover_the_inner_blocks() {
+        let mut larger = RechunkedReader::new(Prefetches(BLOCK_1K, Vec::new()), BLOCK_4K).unwrap();
+        larger.prefetch(&[BlockIndex::new(2)]).unwrap();
+        assert_eq!(larger.inner.1, vec![8, 9, 10, 11]);
+        assert!(larger.prefetch(&[BlockIndex::new(16)]).is_err());
+
+        let mut smaller = RechunkedReader::new(Prefetches(BLOCK_4K, Vec::new()), BLOCK_1K).unwrap();
+        smaller
+            .prefetch(&[BlockIndex::new(5), BlockIndex::new(63)])
+            .unwrap();
+        assert_eq!(smaller.inner.1, vec![1, 15]);
+    }
+
+    #[test]
+    fn writer_block_size_must_match() {
+        let original = tempfile();
+        let migrated = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, original.to_str(), 4096).unwrap();
+        writer.write_all(&[0x42; 4096]).unwrap();
+        writer.finalize().unwrap();
+
+        let mut reader = DiskStorage::open(BLOCK_1K, original.to_str()).unwrap();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, migrated.to_str(), 4096).unwrap();
+        match migrate_block_size(&mut reader, &mut writer, BLOCK_4K) {
+            Err(BigKeyError::BlockSizeMismatch { .. }) => {}
+            _ => panic!("expected writer block size mismatch"),
+        }
+    }
+} // mod test
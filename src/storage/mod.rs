@@ -1,10 +1,95 @@
-pub use disk::DiskStorage;
+pub use analysis::{entropy_report, ConstantRun, EntropyReport, RegionReport};
+pub use bench::{bench_probes, evict_from_cache, recommend_block_size, storage_class, ProbeBench};
+pub use buffered::{BufferedStorageWriter, DEFAULT_WRITE_BUFFER};
+pub use compare::{compare, Comparison};
+pub use compression::{
+    compress_metadata, decompress_metadata, is_compressed, MetadataCompression, MAX_METADATA_LEN,
+};
+pub use container::{pack, ContainerStorage, ContainerWriter, CONTAINER_VERSION};
+pub use counter::{counter_path, DerivationCounter, COUNTER_RESERVATION};
+pub use deadline::{CancellationToken, DeadlineReader};
+pub use disk::{DiskStorage, DiskStorageFactory};
+#[cfg(any(test, feature = "test-util"))]
+pub use faulty::{Fault, FaultyStorage};
+pub use header::KeyHeader;
+pub use latency::{LatencyStats, LATENCY_BUCKETS};
+pub use maintain::{
+    scrub_state_path, ActivityReader, Maintainer, MaintainerConfig, ProbeActivity, ScrubState,
+};
+pub use manifest::Manifest;
+pub use migrate::{block_position, migrate_block_size, RechunkedReader};
+pub use oram::{OramOptions, OramStorage, DEFAULT_RESHUFFLE_MEMORY};
+pub use permutation::{BlockPermutation, PERMUTATION_KEY_LEN};
+pub use pinned::{BlockUsage, PinnedStorage, UsageReader};
+pub use possession::{
+    challenge_len, precompute_challenges, prove_possession, verify_possession, PossessionChallenge,
+    PossessionProof, CHALLENGE_NONCE_LEN, MAX_CHALLENGE_LEN,
+};
+pub use preflight::{detect_compression, preflight, preflight_with, CompressionPolicy};
+pub use provenance::{commit_seed, OperatorKey, OperatorSignature, Provenance, MAX_HOST_LEN};
+pub use readseek::ReadSeekStorage;
+#[cfg(feature = "remote")]
+pub use remote::{ProbePipelineOptions, RangeClient, RemoteStorage};
+pub use retry::{RetryPolicy, RetryingStorage};
+#[cfg(feature = "s3")]
+pub use s3::{
+    CompletedPart, MultipartClient, MultipartUpload, S3MultipartWriter, S3Options,
+    DEFAULT_PART_SIZE, S3_MAX_PARTS, S3_MIN_PART_SIZE,
+};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+pub use stream::{StreamWriter, STDOUT_LOCATION};
+pub use tee::TeeStorageWriter;
 pub use traits::StorageReader;
+pub use traits::StorageReaderFactory;
 pub use traits::StorageWriter;
+pub use usage::{usage_path, KeyUsage, UsageTracker, MAX_USAGE_BITMAP_BITS};
+pub use verify::{fingerprint, spot_check, SpotCheck};
+pub use verifying::VerifyingStorage;
 
+pub(crate) use container::MerkleAccumulator;
+
+mod analysis;
+mod bench;
+mod buffered;
+pub mod checksum;
+mod compare;
+pub(crate) mod compression;
+mod container;
+mod counter;
+mod deadline;
 mod disk;
+#[cfg(any(test, feature = "test-util"))]
+mod faulty;
+pub mod header;
+mod latency;
+mod lock;
+mod maintain;
+mod manifest;
+mod migrate;
+mod native;
+mod oram;
+mod permutation;
+mod pinned;
+mod possession;
+mod preflight;
+mod provenance;
+mod readseek;
+#[cfg(feature = "remote")]
+mod remote;
+pub mod replicate;
+mod retry;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stream;
+mod tee;
 mod traits;
+mod usage;
 mod util;
+mod verify;
+mod verifying;
 
 #[cfg(test)]
 pub(crate) mod tempfile;
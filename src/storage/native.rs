@@ -0,0 +1,309 @@
+//! Platform specific fast paths for probing key files.
+//!
+//! Probes are small reads at random offsets, the worst case for an OS page cache tuned for
+//! sequential access: read-ahead drags in neighbouring blocks that are never used, and every
+//! block read once evicts something more useful. `DiskStorage` reads blocks through a
+//! `ProbeFile`, which picks the best available IO for the platform automatically:
+//!
+//! * Linux: positioned reads (`pread`), with the file advised `POSIX_FADV_RANDOM` so the kernel
+//!   skips read-ahead.
+//! * macOS: positioned reads with `F_NOCACHE` set, keeping key blocks out of the unified buffer
+//!   cache, and read-ahead switched off with `F_RDAHEAD`.
+//! * Windows: a second handle opened with `FILE_FLAG_NO_BUFFERING | FILE_FLAG_RANDOM_ACCESS`,
+//!   read with positioned (`OVERLAPPED` offset) reads. Unbuffered IO must be sector aligned, so
+//!   each probe reads the aligned span around the block into an aligned buffer and copies the
+//!   block out.
+//! * elsewhere: plain positioned reads.
+//!
+//! `ProbeFile::will_need()` asks for blocks to be read into the page cache ahead of their
+//! probes (`POSIX_FADV_WILLNEED`, which starts the reads and returns). Only Linux gets it: the
+//! macOS and Windows paths keep key blocks out of the cache, so there is nothing to warm.
+//!
+//! The hints are best effort: a filesystem refusing them (e.g. a network share on Windows, or
+//! `F_NOCACHE` on some FUSE mounts) falls back to ordinary buffered reads rather than failing.
+
+use std::fs::File;
+use std::io;
+
+/// Alignment assumed for unbuffered IO, covering both 512 byte and 4K sector drives
+#[cfg_attr(not(windows), allow(dead_code))]
+const SECTOR_LEN: u64 = 4096;
+
+/// Read handle of a key file tuned for random block reads
+pub(crate) struct ProbeFile {
+    file: File,
+    #[cfg(windows)]
+    unbuffered: bool,
+    #[cfg(windows)]
+    buffer: Vec<u8>,
+}
+
+impl ProbeFile {
+    /// Probe through the opened key file `file` at `path`, with the platform's fast path
+    pub(crate) fn open(file: &File, path: &str) -> Result<ProbeFile, io::Error> {
+        open_tuned(file, path)
+    }
+
+    /// Probe through `file` as it is, e.g. for writers that never probe
+    pub(crate) fn plain(file: &File) -> Result<ProbeFile, io::Error> {
+        Ok(ProbeFile {
+            file: file.try_clone()?,
+            #[cfg(windows)]
+            unbuffered: false,
+            #[cfg(windows)]
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Fill `output` from the key file starting at byte `position`
+    pub(crate) fn read_at(&mut self, position: u64, output: &mut [u8]) -> Result<(), io::Error> {
+        #[cfg(windows)]
+        {
+            if self.unbuffered {
+                return self.read_unbuffered(position, output);
+            }
+        }
+        read_exact_at(&self.file, position, output)
+    }
+
+    /// Start reading the `len` bytes at `position` into the page cache, without waiting
+    pub(crate) fn will_need(&self, position: u64, len: u64) {
+        will_need(&self.file, position, len)
+    }
+}
+
+/// Start reading the `len` bytes of `file` at `position` into the page cache, without waiting
+#[cfg(target_os = "linux")]
+pub(crate) fn will_need(file: &File, position: u64, len: u64) {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: the descriptor is owned by `file` and stays open for the call
+    match unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            position as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    } {
+        0 => {}
+        errno => log::debug!(
+            "cannot advise prefetch: {}",
+            io::Error::from_raw_os_error(errno)
+        ),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn will_need(_file: &File, _position: u64, _len: u64) {}
+
+#[cfg(target_os = "linux")]
+fn open_tuned(file: &File, _path: &str) -> Result<ProbeFile, io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let probe_file = ProbeFile::plain(file)?;
+    // Safety: the descriptor is owned by `probe_file` and stays open for the call
+    match unsafe { libc::posix_fadvise(probe_file.file.as_raw_fd(), 0, 0, libc::POSIX_FADV_RANDOM) }
+    {
+        0 => {}
+        errno => log::debug!(
+            "cannot advise random access: {}",
+            io::Error::from_raw_os_error(errno)
+        ),
+    }
+    Ok(probe_file)
+}
+
+#[cfg(target_os = "macos")]
+fn open_tuned(file: &File, _path: &str) -> Result<ProbeFile, io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let probe_file = ProbeFile::plain(file)?;
+    let fd = probe_file.file.as_raw_fd();
+    for (command, value, name) in [
+        (libc::F_NOCACHE, 1, "F_NOCACHE"),
+        (libc::F_RDAHEAD, 0, "F_RDAHEAD"),
+    ] {
+        // Safety: the descriptor is owned by `probe_file`, both commands take an int argument
+        if unsafe { libc::fcntl(fd, command, value as libc::c_int) } == -1 {
+            log::debug!("cannot set {}: {}", name, io::Error::last_os_error());
+        }
+    }
+    Ok(probe_file)
+}
+
+#[cfg(windows)]
+fn open_tuned(file: &File, path: &str) -> Result<ProbeFile, io::Error> {
+    use std::fs::OpenOptions;
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+    const FILE_FLAG_RANDOM_ACCESS: u32 = 0x1000_0000;
+
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(FILE_FLAG_NO_BUFFERING | FILE_FLAG_RANDOM_ACCESS)
+        .open(path)
+    {
+        Ok(unbuffered) => Ok(ProbeFile {
+            file: unbuffered,
+            unbuffered: true,
+            buffer: Vec::new(),
+        }),
+        Err(e) => {
+            log::debug!("cannot open {} unbuffered: {}", path, e);
+            ProbeFile::plain(file)
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn open_tuned(file: &File, _path: &str) -> Result<ProbeFile, io::Error> {
+    ProbeFile::plain(file)
+}
+
+#[cfg(windows)]
+impl ProbeFile {
+    fn read_unbuffered(&mut self, position: u64, output: &mut [u8]) -> Result<(), io::Error> {
+        let (start, span) = aligned_span(position, output.len());
+        let wanted = (position - start) as usize + output.len();
+
+        // over-allocate so an aligned window of `span` bytes fits
+        if self.buffer.len() < span + SECTOR_LEN as usize {
+            self.buffer = vec![0u8; span + SECTOR_LEN as usize];
+        }
+        let align = self.buffer.as_ptr().align_offset(SECTOR_LEN as usize);
+        let window = &mut self.buffer[align..align + span];
+
+        let mut filled = 0;
+        let result = loop {
+            if filled >= wanted {
+                break Ok(());
+            }
+            // a short read at the end of the file leaves `filled` unaligned, but only once the
+            // block is complete or the file has ended
+            match read_at(&self.file, &mut window[filled..], start + filled as u64) {
+                Ok(0) => break Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        if result.is_ok() {
+            let skip = (position - start) as usize;
+            output.copy_from_slice(&window[skip..skip + output.len()]);
+        }
+        crate::memory::wipe(&mut window[..filled]);
+        result
+    }
+}
+
+// The sector aligned `(start, len)` span covering `len` bytes at `position`
+#[cfg_attr(not(windows), allow(dead_code))]
+fn aligned_span(position: u64, len: usize) -> (u64, usize) {
+    let start = position - position % SECTOR_LEN;
+    let end = (position + len as u64).div_ceil(SECTOR_LEN) * SECTOR_LEN;
+    (start, (end - start) as usize)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, position: u64, output: &mut [u8]) -> Result<(), io::Error> {
+    std::os::unix::fs::FileExt::read_exact_at(file, output, position)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], position: u64) -> Result<usize, io::Error> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, position)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut position: u64, mut output: &mut [u8]) -> Result<(), io::Error> {
+    while !output.is_empty() {
+        match read_at(file, output, position) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                output = &mut output[n..];
+                position += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(mut file: &File, position: u64, output: &mut [u8]) -> Result<(), io::Error> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    file.seek(SeekFrom::Start(position))?;
+    file.read_exact(output)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Write};
+
+    use crate::storage::native::{aligned_span, ProbeFile};
+    use crate::storage::tempfile::tempfile;
+
+    #[test]
+    fn probe_files_read_any_span() {
+        let path = tempfile();
+        let contents: Vec<u8> = (0..3 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        File::create(path.as_path())
+            .unwrap()
+            .write_all(&contents)
+            .unwrap();
+
+        let file = File::open(path.as_path()).unwrap();
+        let mut probe_file = ProbeFile::open(&file, path.to_str()).unwrap();
+        for (position, len) in [(0, 8), (4090, 16), (4096, 4096), (3 * 4096, 100), (7, 1024)] {
+            let mut output = vec![0u8; len];
+            probe_file.read_at(position as u64, &mut output).unwrap();
+            assert_eq!(&output[..], &contents[position..position + len]);
+        }
+        let mut past_end = vec![0u8; 101];
+        let e = probe_file.read_at(3 * 4096, &mut past_end).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        assert_eq!(aligned_span(0, 8), (0, 4096));
+        assert_eq!(aligned_span(4090, 16), (0, 8192));
+        assert_eq!(aligned_span(4096, 4096), (4096, 4096));
+    }
+
+    #[test]
+    fn probe_files_fail_past_the_end_and_after_truncation() {
+        let path = tempfile();
+        let contents = vec![0x5au8; 2 * 4096];
+        File::create(path.as_path())
+            .unwrap()
+            .write_all(&contents)
+            .unwrap();
+        let file = File::open(path.as_path()).unwrap();
+        let mut tuned = ProbeFile::open(&file, path.to_str()).unwrap();
+        let mut plain = ProbeFile::plain(&file).unwrap();
+
+        assert!(tuned.read_at(1 << 40, &mut []).is_ok());
+        let mut block = vec![0u8; 1024];
+        for probe_file in [&mut tuned, &mut plain] {
+            probe_file.read_at(4096, &mut block).unwrap();
+            assert_eq!(block, contents[..1024]);
+            let e = probe_file.read_at(1 << 40, &mut block).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+            // hints beyond the end are ignored
+            probe_file.will_need(1 << 40, 1 << 20);
+        }
+
+        // a key file shrinking under an open reader fails its probes rather than returning zeros
+        OpenOptions::new()
+            .write(true)
+            .open(path.as_path())
+            .unwrap()
+            .set_len(4096)
+            .unwrap();
+        let e = tuned.read_at(4096, &mut block).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+} // mod test
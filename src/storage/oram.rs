@@ -0,0 +1,508 @@
+//! Hiding which blocks are probed from the host of a remotely stored BigKey.
+//!
+//! Encrypting a key kept on someone else's storage hides its contents, but not the probe
+//! pattern: each derivation reads a few hundred blocks, and the set of blocks read identifies
+//! the derivation to whoever serves them. `OramStorage` is a square-root ORAM layer over any
+//! `Read + Write + Seek` remote volume (a network block device, a mounted object store, a
+//! file on a shared filesystem):
+//!
+//! - The `N` key blocks and `S` dummy blocks, `S` about `sqrt(N)`, are stored encrypted and
+//!   authenticated at the positions of a secret permutation.
+//! - Blocks probed in the current epoch are kept in a local shelter. Probing a sheltered block
+//!   reads an unused dummy instead, so within an epoch every remote read is of a distinct, random
+//!   looking slot, whichever blocks are probed.
+//! - After `S` probes all slots are reshuffled under a fresh permutation and re-encrypted,
+//!   starting a new epoch, so reads of different epochs cannot be linked.
+//!
+//! This is an ORAM-lite: reshuffles are not oblivious sorts. Slots are read back in order, a
+//! chunk of `OramOptions::reshuffle_memory` bytes at a time, and each chunk is written to its
+//! new slots, so the host learns which chunk of the old layout every new slot came from, not
+//! which slot. A reshuffle reads and writes the whole key, amortized over `S` probes: this
+//! suits keys of up to a few gigabytes probed by infrequent derivations, not large keys under
+//! heavy load.
+//!
+//! The remote holds two layouts of `N + S` slots of a block plus a 32 byte tag each, for the
+//! current and the next epoch; a reshuffle interrupted part way leaves the current one intact.
+//! The secret key and the current `epoch()` are all that is needed to `open()` the volume
+//! again, and must be kept locally.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::memory::wipe;
+use crate::storage::util::{block_offset, check_key_evenly_divisible};
+use crate::storage::{BlockPermutation, StorageReader};
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Local memory a reshuffle uses in `OramOptions::default()`
+pub const DEFAULT_RESHUFFLE_MEMORY: usize = 64 * 1024 * 1024;
+
+const TAG_LEN: usize = 32;
+const ENCRYPTION_CONTEXT: &str = "big_fluffy_dise 2024 oram encryption v1";
+const MAC_CONTEXT: &str = "big_fluffy_dise 2024 oram mac v1";
+const PERMUTATION_CONTEXT: &str = "big_fluffy_dise 2024 oram permutation v1";
+
+/// Tuning of `OramStorage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OramOptions {
+    /// Blocks sheltered, and probes, per epoch; the square root of the block count if `None`
+    pub shelter_blocks: Option<u64>,
+    /// Bytes of slots a reshuffle holds at a time; larger chunks leak less about the layout
+    pub reshuffle_memory: usize,
+}
+
+impl Default for OramOptions {
+    fn default() -> Self {
+        OramOptions {
+            shelter_blocks: None,
+            reshuffle_memory: DEFAULT_RESHUFFLE_MEMORY,
+        }
+    }
+}
+
+/// A `StorageReader` whose probes of a remote volume do not reveal which blocks are probed
+pub struct OramStorage<T: Read + Write + Seek> {
+    remote: T,
+    block_size: BlockSize,
+    block_count: u64,
+    shelter_len: u64,
+    reshuffle_slots: u64,
+    keys: OramKeys,
+    epoch: u64,
+    permutation: BlockPermutation,
+    shelter: HashMap<u64, Vec<u8>>,
+    probes: u64,
+    slot: Vec<u8>,
+}
+
+impl<T: Read + Write + Seek> OramStorage<T> {
+    /// Store the blocks of `source` on `remote` under `key`, at epoch 0
+    pub fn setup<R: StorageReader + ?Sized>(
+        source: &mut R,
+        remote: T,
+        key: &[u8; 32],
+        options: &OramOptions,
+    ) -> Result<Self, BigKeyError> {
+        let mut storage = OramStorage::new(
+            remote,
+            source.block_size(),
+            source.big_key_length(),
+            key,
+            0,
+            options,
+        )?;
+
+        let mut block = vec![0u8; storage.block_size.byte_len];
+        let result = (|| -> Result<(), BigKeyError> {
+            for slot in 0..storage.slot_count() {
+                let index = storage.permutation.logical(slot);
+                match index < storage.block_count {
+                    true => source.probe(BlockIndex::new(index), &mut block)?,
+                    false => block.fill(0),
+                }
+                storage.write_slot(0, slot, &block)?;
+            }
+            storage.remote.flush()?;
+            Ok(())
+        })();
+        wipe(&mut block);
+        result.map(|_| storage)
+    }
+
+    /// The key of `big_key_length` bytes stored on `remote` under `key`, at `epoch`
+    pub fn open(
+        remote: T,
+        block_size: BlockSize,
+        big_key_length: u64,
+        key: &[u8; 32],
+        epoch: u64,
+        options: &OramOptions,
+    ) -> Result<Self, BigKeyError> {
+        OramStorage::new(remote, block_size, big_key_length, key, epoch, options)
+    }
+
+    fn new(
+        remote: T,
+        block_size: BlockSize,
+        big_key_length: u64,
+        key: &[u8; 32],
+        epoch: u64,
+        options: &OramOptions,
+    ) -> Result<Self, BigKeyError> {
+        check_key_evenly_divisible(block_size, big_key_length)?;
+        let block_count = big_key_length / block_size.byte_len as u64;
+        let shelter_len = shelter_len(block_count, options);
+        let slot_len = (block_size.byte_len + TAG_LEN) as u64;
+        let keys = OramKeys::new(key);
+
+        Ok(OramStorage {
+            remote,
+            block_size,
+            block_count,
+            shelter_len,
+            reshuffle_slots: (options.reshuffle_memory as u64 / slot_len).max(1),
+            permutation: keys.permutation(epoch, block_count + shelter_len),
+            keys,
+            epoch,
+            shelter: HashMap::new(),
+            probes: 0,
+            slot: vec![0u8; slot_len as usize],
+        })
+    }
+
+    /// Reshuffles so far; record it to `open()` the volume again
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Bytes of remote storage a key of `big_key_length` bytes takes
+    pub fn remote_len(block_size: BlockSize, big_key_length: u64, options: &OramOptions) -> u64 {
+        let block_count = big_key_length / block_size.byte_len as u64;
+        2 * (block_count + shelter_len(block_count, options))
+            * (block_size.byte_len + TAG_LEN) as u64
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.remote
+    }
+
+    fn slot_count(&self) -> u64 {
+        self.block_count + self.shelter_len
+    }
+
+    // Start a new epoch, moving every slot to its position under a fresh permutation
+    fn reshuffle(&mut self) -> Result<(), BigKeyError> {
+        let epoch = self.epoch + 1;
+        let permutation = self.keys.permutation(epoch, self.slot_count());
+        let mut chunk: Vec<(u64, Vec<u8>)> = Vec::new();
+
+        let result = (|| -> Result<(), BigKeyError> {
+            let mut start = 0;
+            while start < self.slot_count() {
+                let end = (start + self.reshuffle_slots).min(self.slot_count());
+                for slot in start..end {
+                    let mut block = vec![0u8; self.block_size.byte_len];
+                    self.read_slot(self.epoch, slot, &mut block)?;
+                    let index = self.permutation.logical(slot);
+                    chunk.push((permutation.physical(index), block));
+                }
+                chunk.sort_unstable_by_key(|(slot, _)| *slot);
+                for (slot, mut block) in chunk.drain(..) {
+                    let written = self.write_slot(epoch, slot, &block);
+                    wipe(&mut block);
+                    written?;
+                }
+                start = end;
+            }
+            self.remote.flush()?;
+            Ok(())
+        })();
+        chunk.iter_mut().for_each(|(_, block)| wipe(block));
+        result?;
+
+        self.epoch = epoch;
+        self.permutation = permutation;
+        self.probes = 0;
+        self.wipe_shelter();
+        log::debug!("reshuffled ORAM storage, epoch {}", epoch);
+        Ok(())
+    }
+
+    // Read, authenticate and decrypt `slot` of the layout of `epoch` into `block`
+    fn read_slot(&mut self, epoch: u64, slot: u64, block: &mut [u8]) -> Result<(), BigKeyError> {
+        let position = self.slot_position(epoch, slot);
+        self.remote.seek(SeekFrom::Start(position))?;
+        self.remote.read_exact(&mut self.slot)?;
+
+        let (ciphertext, tag) = self.slot.split_at(block.len());
+        // blake3::Hash compares in constant time
+        if self.keys.tag(epoch, slot, ciphertext)
+            != blake3::Hash::from(<[u8; TAG_LEN]>::try_from(tag).unwrap())
+        {
+            return Err(BigKeyError::BlockCorrupted {
+                index: self.permutation.logical(slot),
+            });
+        }
+        self.keys.keystream(epoch, slot, block);
+        block.iter_mut().zip(ciphertext).for_each(|(b, c)| *b ^= c);
+        Ok(())
+    }
+
+    // Encrypt `block` into `slot` of the layout of `epoch`
+    fn write_slot(&mut self, epoch: u64, slot: u64, block: &[u8]) -> Result<(), BigKeyError> {
+        let (ciphertext, tag) = self.slot.split_at_mut(block.len());
+        self.keys.keystream(epoch, slot, ciphertext);
+        ciphertext.iter_mut().zip(block).for_each(|(c, b)| *c ^= b);
+        tag.copy_from_slice(self.keys.tag(epoch, slot, ciphertext).as_bytes());
+
+        let position = self.slot_position(epoch, slot);
+        self.remote.seek(SeekFrom::Start(position))?;
+        self.remote.write_all(&self.slot)?;
+        Ok(())
+    }
+
+    // Layouts alternate between the two halves of the remote volume
+    fn slot_position(&self, epoch: u64, slot: u64) -> u64 {
+        ((epoch % 2) * self.slot_count() + slot) * self.slot.len() as u64
+    }
+
+    fn wipe_shelter(&mut self) {
+        self.shelter.values_mut().for_each(|block| wipe(block));
+        self.shelter.clear();
+    }
+}
+
+impl<T: Read + Write + Seek> StorageReader for OramStorage<T> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        if output.len() != self.block_size.byte_len {
+            return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+                out_buf_len: output.len(),
+                block_len: self.block_size.byte_len,
+            });
+        }
+        block_offset(index, self.block_size, self.big_key_length())?;
+
+        // every probe of an epoch reads a slot not read before in it: the block's own slot the
+        // first time, a dummy after that
+        match self.shelter.get(&index.get()) {
+            Some(block) => {
+                output.copy_from_slice(block);
+                let mut dummy = vec![0u8; output.len()];
+                let slot = self.permutation.physical(self.block_count + self.probes);
+                let result = self.read_slot(self.epoch, slot, &mut dummy);
+                wipe(&mut dummy);
+                result?;
+            }
+            None => {
+                let slot = self.permutation.physical(index.get());
+                self.read_slot(self.epoch, slot, output)?;
+                self.shelter.insert(index.get(), output.to_vec());
+            }
+        }
+
+        self.probes += 1;
+        if self.probes == self.shelter_len {
+            self.reshuffle()?;
+        }
+        Ok(())
+    }
+
+    // Reading blocks ahead of their probes would show the host exactly the access pattern
+    // the shuffling hides, so prefetch hints are ignored
+    fn prefetch(&mut self, _indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        Ok(())
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.block_count * self.block_size.byte_len as u64
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+}
+
+impl<T: Read + Write + Seek> Drop for OramStorage<T> {
+    fn drop(&mut self) {
+        self.wipe_shelter();
+    }
+}
+
+fn shelter_len(block_count: u64, options: &OramOptions) -> u64 {
+    options
+        .shelter_blocks
+        .unwrap_or_else(|| (block_count as f64).sqrt().ceil() as u64)
+        .max(1)
+}
+
+fn subkey(context: &str, key: &[u8; 32]) -> [u8; 32] {
+    let mut subkey = [0u8; 32];
+    blake3::derive_key(context, key, &mut subkey);
+    subkey
+}
+
+// Subkeys of the secret key
+struct OramKeys {
+    encryption: [u8; 32],
+    mac: [u8; 32],
+    permutation: [u8; 32],
+}
+
+impl OramKeys {
+    fn new(key: &[u8; 32]) -> Self {
+        OramKeys {
+            encryption: subkey(ENCRYPTION_CONTEXT, key),
+            mac: subkey(MAC_CONTEXT, key),
+            permutation: subkey(PERMUTATION_CONTEXT, key),
+        }
+    }
+
+    fn permutation(&self, epoch: u64, slot_count: u64) -> BlockPermutation {
+        let key = blake3::keyed_hash(&self.permutation, &epoch.to_be_bytes());
+        BlockPermutation::new(*key.as_bytes(), slot_count)
+    }
+
+    // Each slot of each epoch is written once, so its keystream is never reused
+    fn keystream(&self, epoch: u64, slot: u64, output: &mut [u8]) {
+        blake3::Hasher::new_keyed(&self.encryption)
+            .update(&epoch.to_be_bytes())
+            .update(&slot.to_be_bytes())
+            .finalize_xof()
+            .fill(output);
+    }
+
+    fn tag(&self, epoch: u64, slot: u64, ciphertext: &[u8]) -> blake3::Hash {
+        blake3::Hasher::new_keyed(&self.mac)
+            .update(&epoch.to_be_bytes())
+            .update(&slot.to_be_bytes())
+            .update(ciphertext)
+            .finalize()
+    }
+}
+
+impl Drop for OramKeys {
+    fn drop(&mut self) {
+        wipe(&mut self.encryption);
+        wipe(&mut self.mac);
+        wipe(&mut self.permutation);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use crate::storage::{OramOptions, OramStorage, ReadSeekStorage, StorageReader};
+    use crate::traits::{BigKeyError, BlockIndex, BLOCK_1K};
+
+    // A remote volume recording the offsets read
+    #[derive(Default)]
+    struct Volume {
+        inner: Cursor<Vec<u8>>,
+        reads: Vec<u64>,
+    }
+
+    impl Read for Volume {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads.push(self.inner.position());
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for Volume {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Volume {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn probes_read_distinct_slots_and_survive_reshuffles() {
+        let key_bytes: Vec<u8> = (0..100 * 1024).map(|i| (i * 7 / 1024) as u8).collect();
+        let mut source = ReadSeekStorage::new(Cursor::new(key_bytes.clone()), BLOCK_1K).unwrap();
+        let options = OramOptions {
+            shelter_blocks: None,
+            reshuffle_memory: 8 * 1024,
+        };
+        let secret = [9u8; 32];
+        let mut oram =
+            OramStorage::setup(&mut source, Volume::default(), &secret, &options).unwrap();
+        assert_eq!(
+            oram.get_ref().inner.get_ref().len() as u64,
+            OramStorage::<Volume>::remote_len(BLOCK_1K, 100 * 1024, &options) / 2
+        );
+
+        // the same block probed over and over reads a different slot each time
+        let mut block = vec![0u8; 1024];
+        let mut offsets = HashSet::new();
+        for _ in 0..10 {
+            oram.probe(BlockIndex::new(3), &mut block).unwrap();
+            assert_eq!(&block[..], &key_bytes[3 * 1024..4 * 1024]);
+            offsets.insert(*oram.get_ref().reads.last().unwrap());
+        }
+        assert_eq!((offsets.len(), oram.epoch()), (10, 1));
+
+        for index in (0..100).rev().chain(0..100) {
+            oram.probe(BlockIndex::new(index), &mut block).unwrap();
+            assert_eq!(&block[..], &key_bytes[index as usize * 1024..][..1024]);
+        }
+        assert_eq!(oram.epoch(), 21);
+
+        // reopened at its epoch, the volume probes the same key; tampering is detected
+        let volume = Volume {
+            inner: Cursor::new(oram.get_ref().inner.get_ref().clone()),
+            reads: Vec::new(),
+        };
+        let mut reopened =
+            OramStorage::open(volume, BLOCK_1K, 100 * 1024, &secret, 21, &options).unwrap();
+        reopened.probe(BlockIndex::new(42), &mut block).unwrap();
+        assert_eq!(&block[..], &key_bytes[42 * 1024..43 * 1024]);
+
+        let mut tampered = oram.get_ref().inner.get_ref().clone();
+        tampered.iter_mut().for_each(|b| *b ^= 1);
+        let volume = Volume {
+            inner: Cursor::new(tampered),
+            reads: Vec::new(),
+        };
+        let mut tampered =
+            OramStorage::open(volume, BLOCK_1K, 100 * 1024, &secret, 21, &options).unwrap();
+        assert!(matches!(
+            tampered.probe(BlockIndex::new(42), &mut block),
+            Err(BigKeyError::BlockCorrupted { index: 42 })
+        ));
+    }
+
+    #[test]
+    fn wrong_keys_epochs_and_probes_fail() {
+        let key_bytes: Vec<u8> = (0..16 * 1024).map(|i| (i % 253) as u8).collect();
+        let mut source = ReadSeekStorage::new(Cursor::new(key_bytes), BLOCK_1K).unwrap();
+        let options = OramOptions::default();
+        let oram =
+            OramStorage::setup(&mut source, Volume::default(), &[9u8; 32], &options).unwrap();
+        let volume = || Volume {
+            inner: Cursor::new(oram.get_ref().inner.get_ref().clone()),
+            reads: Vec::new(),
+        };
+
+        // a volume opened under another key or at another epoch authenticates nothing
+        let mut block = vec![0u8; 1024];
+        for (key, epoch) in [([8u8; 32], 0), ([9u8; 32], 1), ([9u8; 32], 2)].iter() {
+            let mut wrong =
+                OramStorage::open(volume(), BLOCK_1K, 16 * 1024, key, *epoch, &options).unwrap();
+            assert!(matches!(
+                wrong.probe(BlockIndex::new(5), &mut block),
+                Err(BigKeyError::BlockCorrupted { .. }) | Err(BigKeyError::IoError(_))
+            ));
+        }
+
+        let mut reopened =
+            OramStorage::open(volume(), BLOCK_1K, 16 * 1024, &[9u8; 32], 0, &options).unwrap();
+        assert!(reopened.probe(BlockIndex::new(16), &mut block).is_err());
+        assert!(matches!(
+            reopened.probe(BlockIndex::new(0), &mut [0u8; 32]),
+            Err(BigKeyError::ProbeBufferNotEqBlockSize { .. })
+        ));
+        assert!(OramStorage::open(volume(), BLOCK_1K, 1000, &[9u8; 32], 0, &options).is_err());
+
+        // a truncated volume fails its probes
+        let mut short = volume();
+        short.inner.get_mut().truncate(1024);
+        let mut truncated =
+            OramStorage::open(short, BLOCK_1K, 16 * 1024, &[9u8; 32], 0, &options).unwrap();
+        let failures = (0..16)
+            .filter(|&index| truncated.probe(BlockIndex::new(index), &mut block).is_err())
+            .count();
+        assert_eq!(failures, 16);
+    }
+} // mod test
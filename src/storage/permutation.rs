@@ -0,0 +1,196 @@
+//! Keyed permutation between logical block indices and physical block positions.
+//!
+//! Without it, block `i` of a key is stored at byte `i * block_len`, so a partial leak of the
+//! physical medium (a recovered disk region, a truncated backup, a bad-sector dump) hands an
+//! attacker a contiguous range of block indices. A permuted key file stores logical block `i`
+//! at the physical position `P(i)` of a format-preserving permutation keyed by a random 32 byte
+//! key recorded in the `KeyHeader`. Physically adjacent blocks are then logically unrelated, and
+//! a leaked region is a scattered set of indices rather than a range.
+//!
+//! The permutation is a Feistel network over the smallest even power of two domain covering the
+//! block count, with keyed BLAKE3 as round function, cycle walking back into range. It only
+//! changes where blocks are stored: probes, fingerprints, checksums and derived keys all address
+//! logical blocks and are the same as for an unpermuted key with the same contents.
+//!
+//! The permutation key is not secret from anyone holding the header, it only scatters layout;
+//! leakage of the header together with the physical image undoes the scattering.
+
+use crate::storage::util::block_offset;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Length of a permutation key
+pub const PERMUTATION_KEY_LEN: usize = 32;
+
+const ROUNDS: u8 = 8;
+
+/// A keyed permutation of the block indices `0..block_count`
+#[derive(Clone)]
+pub struct BlockPermutation {
+    key: [u8; PERMUTATION_KEY_LEN],
+    block_count: u64,
+    half_bits: u32,
+    half_mask: u64,
+}
+
+impl BlockPermutation {
+    pub fn new(key: [u8; PERMUTATION_KEY_LEN], block_count: u64) -> Self {
+        // smallest even number of bits covering every index, at least 2
+        let bits = match block_count {
+            0..=1 => 2,
+            n => 64 - (n - 1).leading_zeros(),
+        };
+        let half_bits = bits.div_ceil(2);
+        BlockPermutation {
+            key,
+            block_count,
+            half_bits,
+            half_mask: (1u64 << half_bits) - 1,
+        }
+    }
+
+    /// A permutation of `block_count` blocks under a fresh random key
+    pub fn random(block_count: u64) -> Result<Self, BigKeyError> {
+        let mut key = [0u8; PERMUTATION_KEY_LEN];
+        getrandom::getrandom(&mut key)?;
+        Ok(BlockPermutation::new(key, block_count))
+    }
+
+    pub fn key(&self) -> &[u8; PERMUTATION_KEY_LEN] {
+        &self.key
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    /// Physical position of logical block `index`, which must be below `block_count()`
+    pub fn physical(&self, index: u64) -> u64 {
+        self.walk(index, |value| self.encrypt(value))
+    }
+
+    /// Logical index of the block at physical position `position`
+    pub fn logical(&self, position: u64) -> u64 {
+        self.walk(position, |value| self.decrypt(value))
+    }
+
+    // Apply `step` until the value falls back into `0..block_count`; the domain is less than
+    // four times the block count, so this takes a few steps on average
+    fn walk(&self, value: u64, step: impl Fn(u64) -> u64) -> u64 {
+        debug_assert!(value < self.block_count);
+        let mut value = step(value);
+        while value >= self.block_count {
+            value = step(value);
+        }
+        value
+    }
+
+    fn encrypt(&self, value: u64) -> u64 {
+        let (mut left, mut right) = (value >> self.half_bits, value & self.half_mask);
+        for round in 0..ROUNDS {
+            let next = left ^ self.round(round, right);
+            left = right;
+            right = next;
+        }
+        (left << self.half_bits) | right
+    }
+
+    fn decrypt(&self, value: u64) -> u64 {
+        let (mut left, mut right) = (value >> self.half_bits, value & self.half_mask);
+        for round in (0..ROUNDS).rev() {
+            let previous = right ^ self.round(round, left);
+            right = left;
+            left = previous;
+        }
+        (left << self.half_bits) | right
+    }
+
+    fn round(&self, round: u8, half: u64) -> u64 {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(&[round]);
+        hasher.update(&half.to_be_bytes());
+        let mut out = [0u8; 8];
+        out.copy_from_slice(&hasher.finalize().as_bytes()[..8]);
+        u64::from_be_bytes(out) & self.half_mask
+    }
+}
+
+/// Offset of logical block `index` within the key data, through `permutation` if there is one
+pub(crate) fn physical_offset(
+    permutation: Option<&BlockPermutation>,
+    index: BlockIndex,
+    block_size: BlockSize,
+    key_len: u64,
+) -> Result<u64, BigKeyError> {
+    let offset = block_offset(index, block_size, key_len)?;
+    match permutation {
+        Some(permutation) => block_offset(
+            BlockIndex::new(permutation.physical(index.get())),
+            block_size,
+            key_len,
+        ),
+        None => Ok(offset),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::storage::permutation::physical_offset;
+    use crate::storage::BlockPermutation;
+    use crate::traits::{BlockIndex, BLOCK_64};
+
+    #[test]
+    fn permutations_are_bijective_and_keyed() {
+        for block_count in [1u64, 2, 3, 5, 16, 17, 1000, 4097] {
+            let permutation = BlockPermutation::new([7u8; 32], block_count);
+            let positions: HashSet<u64> = (0..block_count)
+                .map(|index| {
+                    let position = permutation.physical(index);
+                    assert!(position < block_count);
+                    assert_eq!(permutation.logical(position), index);
+                    position
+                })
+                .collect();
+            assert_eq!(positions.len() as u64, block_count);
+        }
+
+        let a = BlockPermutation::new([1u8; 32], 4096);
+        let b = BlockPermutation::new([2u8; 32], 4096);
+        let moved = (0..4096).filter(|i| a.physical(*i) != *i).count();
+        let differ = (0..4096)
+            .filter(|i| a.physical(*i) != b.physical(*i))
+            .count();
+        assert!(moved > 4000 && differ > 4000);
+        // neighbours end up scattered
+        let adjacent = (0..4095)
+            .filter(|i| a.physical(*i).abs_diff(a.physical(i + 1)) == 1)
+            .count();
+        assert!(adjacent < 20);
+    }
+
+    #[test]
+    fn huge_keys_and_out_of_range_blocks() {
+        // the Feistel halves cover the full 64 bit range without overflowing
+        for block_count in [1u64 << 40, (1 << 63) + 1, u64::MAX].iter() {
+            let permutation = BlockPermutation::new([3u8; 32], *block_count);
+            for index in [0, 1, block_count / 2, block_count - 1].iter() {
+                let position = permutation.physical(*index);
+                assert!(position < *block_count);
+                assert_eq!(permutation.logical(position), *index);
+            }
+        }
+
+        let a = BlockPermutation::random(1024).unwrap();
+        let b = BlockPermutation::random(1024).unwrap();
+        assert_ne!(a.key(), b.key());
+        assert_eq!(a.block_count(), 1024);
+
+        let key_len = 1024 * BLOCK_64.byte_len as u64;
+        let offset = |index| physical_offset(Some(&a), BlockIndex::new(index), BLOCK_64, key_len);
+        assert_eq!(offset(5).unwrap(), a.physical(5) * BLOCK_64.byte_len as u64);
+        assert!(offset(1024).is_err());
+        assert!(offset(u64::MAX).is_err());
+        assert!(physical_offset(None, BlockIndex::new(1024), BLOCK_64, key_len).is_err());
+    }
+} // mod test
@@ -0,0 +1,254 @@
+//! Serving frequently probed blocks from RAM.
+//!
+//! Uniform probing touches every block equally, but workloads with skewed locators (e.g. one
+//! locator re-derived for every request) or non-uniform probe distributions hit a small set of
+//! blocks far more often than the rest. `PinnedStorage` keeps a chosen set of blocks in a
+//! `LockedBuffer` and serves only the remaining probes from the underlying storage. The blocks to
+//! pin come from an explicit list or from `BlockUsage` gathered by a `UsageReader`.
+
+use std::collections::HashMap;
+
+use crate::memory::LockedBuffer;
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Probe counts per block index
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUsage {
+    counts: HashMap<u64, u64>,
+}
+
+impl BlockUsage {
+    pub fn new() -> Self {
+        BlockUsage::default()
+    }
+
+    pub fn record(&mut self, index: u64) {
+        *self.counts.entry(index).or_insert(0) += 1;
+    }
+
+    pub fn count(&self, index: u64) -> u64 {
+        self.counts.get(&index).copied().unwrap_or(0)
+    }
+
+    /// Indices of the (at most) `n` most probed blocks, most probed first
+    pub fn hottest(&self, n: usize) -> Vec<u64> {
+        let mut by_count: Vec<(u64, u64)> = self.counts.iter().map(|(i, c)| (*i, *c)).collect();
+        by_count.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        by_count.into_iter().take(n).map(|(i, _)| i).collect()
+    }
+}
+
+/// A `StorageReader` that records which blocks are probed in a `BlockUsage`
+pub struct UsageReader<R: StorageReader> {
+    inner: R,
+    usage: BlockUsage,
+}
+
+impl<R: StorageReader> UsageReader<R> {
+    pub fn new(inner: R) -> Self {
+        UsageReader {
+            inner,
+            usage: BlockUsage::new(),
+        }
+    }
+
+    pub fn usage(&self) -> &BlockUsage {
+        &self.usage
+    }
+
+    pub fn into_parts(self) -> (R, BlockUsage) {
+        (self.inner, self.usage)
+    }
+}
+
+impl<R: StorageReader> StorageReader for UsageReader<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        self.inner.probe(index, output)?;
+        self.usage.record(index.get());
+        Ok(())
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        self.inner.prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+}
+
+/// A `StorageReader` serving a fixed set of blocks from locked memory, the rest from `R`
+pub struct PinnedStorage<R: StorageReader> {
+    inner: R,
+    /// Sorted indices of the pinned blocks; block `pinned[i]` is slot `i` of `blocks`
+    pinned: Vec<u64>,
+    blocks: LockedBuffer,
+    hits: u64,
+    misses: u64,
+}
+
+impl<R: StorageReader> PinnedStorage<R> {
+    /// Pin the blocks at `indices` (in any order, duplicates ignored), reading them from `inner`
+    pub fn new(mut inner: R, indices: &[u64]) -> Result<Self, BigKeyError> {
+        let block_len = inner.block_size().byte_len;
+        let mut pinned = indices.to_vec();
+        pinned.sort_unstable();
+        pinned.dedup();
+
+        let mut blocks = LockedBuffer::new(pinned.len() * block_len);
+        if !pinned.is_empty() && !blocks.is_locked() {
+            log::warn!("pinned blocks could not be locked into memory and may be swapped out");
+        }
+        for (slot, index) in pinned.iter().enumerate() {
+            inner.probe(
+                BlockIndex::new(*index),
+                &mut blocks[slot * block_len..(slot + 1) * block_len],
+            )?;
+        }
+
+        Ok(PinnedStorage {
+            inner,
+            pinned,
+            blocks,
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    /// Pin the `max_blocks` most probed blocks of `usage`
+    pub fn from_usage(
+        inner: R,
+        usage: &BlockUsage,
+        max_blocks: usize,
+    ) -> Result<Self, BigKeyError> {
+        PinnedStorage::new(inner, &usage.hottest(max_blocks))
+    }
+
+    /// Sorted indices of the pinned blocks
+    pub fn pinned_blocks(&self) -> &[u64] {
+        &self.pinned
+    }
+
+    /// Whether the pinned blocks are locked into RAM
+    pub fn is_locked(&self) -> bool {
+        self.blocks.is_locked()
+    }
+
+    /// Probes served from memory and from the underlying storage
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: StorageReader> StorageReader for PinnedStorage<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        let block_len = self.inner.block_size().byte_len;
+
+        match self.pinned.binary_search(&index.get()) {
+            Ok(slot) if output.len() == block_len => {
+                output.copy_from_slice(&self.blocks[slot * block_len..(slot + 1) * block_len]);
+                self.hits += 1;
+                Ok(())
+            }
+            _ => {
+                self.misses += 1;
+                self.inner.probe(index, output)
+            }
+        }
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        let unpinned: Vec<BlockIndex> = indices
+            .iter()
+            .filter(|index| self.pinned.binary_search(&index.get()).is_err())
+            .copied()
+            .collect();
+        self.inner.prefetch(&unpinned)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+
+    use sha3::{Digest, Sha3_256};
+
+    use crate::kem::{BigKey, BigKeyKem, ExcludeEnds};
+    use crate::storage::pinned::{PinnedStorage, UsageReader};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageReader};
+    use crate::traits::{BlockIndex, SecurityLevel, BLOCK_1K};
+
+    #[test]
+    fn pinned_blocks_match_storage() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            for i in 0..32u8 {
+                ofile.write_all(&[i; 1024]).unwrap();
+            }
+        }
+
+        let storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let mut pinned = PinnedStorage::new(storage, &[9, 3, 9, 27]).unwrap();
+        assert_eq!(pinned.pinned_blocks(), &[3, 9, 27]);
+
+        let mut block = [0u8; 1024];
+        for index in [3u64, 4, 9, 27].iter() {
+            pinned.probe(BlockIndex::new(*index), &mut block).unwrap();
+            assert_eq!(block, [*index as u8; 1024]);
+        }
+        assert_eq!(pinned.hits_and_misses(), (3, 1));
+        assert!(pinned.probe(BlockIndex::new(32), &mut block).is_err());
+    }
+
+    #[test]
+    fn hot_blocks_from_usage_serve_derivation() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            for i in 0..64u8 {
+                ofile.write_all(&[i; 1024]).unwrap();
+            }
+        }
+
+        // a distribution restricted to blocks 60..63 concentrates every probe there
+        let storage = UsageReader::new(DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap());
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, storage, Sha3_256::new())
+            .with_probe_distribution(ExcludeEnds { head: 60, tail: 0 });
+        let (locator, key) = bk.new_key(SecurityLevel::Bits128).unwrap();
+
+        let (storage, usage) = bk.into_storage().into_parts();
+        let mut hot = usage.hottest(8);
+        hot.sort_unstable();
+        assert_eq!(hot, vec![60, 61, 62, 63]);
+
+        let pinned = PinnedStorage::from_usage(storage, &usage, 4).unwrap();
+        // only a BigKey configured with it accepts a distribution this narrow
+        let mut bk = BigKey::new_big_key(SecurityLevel::Bits128, 0.2, pinned, Sha3_256::new())
+            .with_probe_distribution(ExcludeEnds { head: 60, tail: 0 });
+        assert_eq!(bk.get_key(&locator).unwrap(), key);
+
+        let (hits, misses) = bk.storage().hits_and_misses();
+        assert!(hits > 0);
+        assert_eq!(misses, 0);
+    }
+} // mod test
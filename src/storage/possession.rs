@@ -0,0 +1,263 @@
+//! Proof that a custodian still holds a BigKey, without moving or revealing any of it.
+//!
+//! The verifier sends a `PossessionChallenge`: random block indices and a fresh nonce. The
+//! prover answers with `prove_possession()`, a BLAKE3 hash over the nonce and the challenged
+//! blocks, and the verifier recomputes it from its own copy with `verify_possession()`. A
+//! prover missing a fraction `f` of the blocks answers a challenge of `n` indices with at best
+//! `(1 - f)^n` probability of not needing a missing block; see `challenge_len()` for choosing
+//! `n`. The nonce keeps answers from being computed ahead and stored instead of the key.
+//!
+//! A verifier that will not keep a copy of the key can `precompute_challenges()` while it
+//! still holds one and keep only those. Each challenge must only be sent once: a prover that
+//! has seen a challenge can replay its answer.
+
+use std::convert::TryInto;
+
+use crate::storage::verify::random_u64;
+use crate::storage::StorageReader;
+use crate::traits::{ct_eq, BigKeyError, BlockCount, BlockIndex};
+
+const POSSESSION_CONTEXT: &str = "big_fluffy_dise 2024 proof of possession v1";
+
+/// Length of the nonce of a `PossessionChallenge`
+pub const CHALLENGE_NONCE_LEN: usize = 32;
+
+/// Most indices a challenge may name
+pub const MAX_CHALLENGE_LEN: usize = 1 << 20;
+
+// Challenged blocks are probed in batches of this many
+const PROOF_BATCH: usize = 64;
+
+/// Blocks a prover must hash, and the nonce to hash them with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PossessionChallenge {
+    pub nonce: [u8; CHALLENGE_NONCE_LEN],
+    pub indices: Vec<BlockIndex>,
+}
+
+/// A prover's answer to a `PossessionChallenge`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PossessionProof(pub [u8; 32]);
+
+impl PossessionChallenge {
+    /// A challenge of `len` uniformly random indices of a key of `block_count` blocks
+    pub fn random(block_count: BlockCount, len: usize) -> Result<Self, BigKeyError> {
+        if block_count.get() == 0 || len == 0 || len > MAX_CHALLENGE_LEN {
+            return Err(BigKeyError::InvalidChallenge {
+                reason: "challenges need 1 to MAX_CHALLENGE_LEN indices of a non-empty key",
+            });
+        }
+        let mut nonce = [0u8; CHALLENGE_NONCE_LEN];
+        getrandom::getrandom(&mut nonce)?;
+        let indices = (0..len)
+            .map(|_| Ok(BlockIndex::new(random_u64()? % block_count.get())))
+            .collect::<Result<Vec<BlockIndex>, BigKeyError>>()?;
+        Ok(PossessionChallenge { nonce, indices })
+    }
+
+    /// Wire encoding: the nonce, the number of indices (u32) and the indices (u64 each), all
+    /// big-endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(CHALLENGE_NONCE_LEN + 4 + 8 * self.indices.len());
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&(self.indices.len() as u32).to_be_bytes());
+        for index in &self.indices {
+            out.extend_from_slice(&index.get().to_be_bytes());
+        }
+        out
+    }
+
+    /// Decode a challenge encoded by `to_bytes()`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BigKeyError> {
+        let invalid = |reason| BigKeyError::InvalidChallenge { reason };
+        if bytes.len() < CHALLENGE_NONCE_LEN + 4 {
+            return Err(invalid("truncated challenge"));
+        }
+        let (nonce, rest) = bytes.split_at(CHALLENGE_NONCE_LEN);
+        let len = u32::from_be_bytes(rest[0..4].try_into().unwrap()) as usize;
+        if len == 0 || len > MAX_CHALLENGE_LEN {
+            return Err(invalid("challenge length out of range"));
+        }
+        if rest.len() - 4 != len * 8 {
+            return Err(invalid("challenge length differs from its indices"));
+        }
+        let indices = rest[4..]
+            .chunks_exact(8)
+            .map(|index| BlockIndex::new(u64::from_be_bytes(index.try_into().unwrap())))
+            .collect();
+        Ok(PossessionChallenge {
+            nonce: nonce.try_into().unwrap(),
+            indices,
+        })
+    }
+}
+
+/// Number of indices a challenge needs for a prover missing a `missing_fraction` of the blocks
+/// to go undetected with probability at most 2^-`security_bits`
+pub fn challenge_len(missing_fraction: f64, security_bits: u32) -> Result<usize, BigKeyError> {
+    if !(missing_fraction > 0.0 && missing_fraction <= 1.0) {
+        return Err(BigKeyError::InvalidConfig {
+            reason: format!(
+                "missing fraction {} outside of (0.0, 1.0]",
+                missing_fraction
+            ),
+        });
+    }
+    let len = (security_bits as f64 * -(2f64.ln()) / (1.0 - missing_fraction).ln()).ceil();
+    Ok((len as usize).clamp(1, MAX_CHALLENGE_LEN))
+}
+
+/// Answer `challenge` from the key in `reader`
+pub fn prove_possession<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    challenge: &PossessionChallenge,
+) -> Result<PossessionProof, BigKeyError> {
+    let block_len = reader.block_size().byte_len;
+    let mut blocks = vec![0u8; PROOF_BATCH.min(challenge.indices.len()) * block_len];
+    let mut hasher = blake3::Hasher::new_derive_key(POSSESSION_CONTEXT);
+    hasher.update(&challenge.nonce);
+    hasher.update(&(challenge.indices.len() as u64).to_be_bytes());
+
+    for batch in challenge.indices.chunks(PROOF_BATCH) {
+        let batch_blocks = &mut blocks[..batch.len() * block_len];
+        reader.probe_many(batch, batch_blocks)?;
+        for (index, block) in batch.iter().zip(batch_blocks.chunks_exact(block_len)) {
+            hasher.update(&index.get().to_be_bytes());
+            hasher.update(block);
+        }
+    }
+    Ok(PossessionProof(*hasher.finalize().as_bytes()))
+}
+
+/// Check `proof` against the answer to `challenge` computed from the key in `reader`, failing
+/// with `VerificationFailed` if they differ
+pub fn verify_possession<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    challenge: &PossessionChallenge,
+    proof: &PossessionProof,
+) -> Result<(), BigKeyError> {
+    let expected = prove_possession(reader, challenge)?;
+    if !ct_eq(&expected.0, &proof.0) {
+        return Err(BigKeyError::VerificationFailed {
+            stage: "possession proof does not match",
+        });
+    }
+    Ok(())
+}
+
+/// `count` random challenges of `len` indices each, with their answers computed from the key
+/// in `reader`, for verifying possession later without a copy of the key
+pub fn precompute_challenges<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    count: usize,
+    len: usize,
+) -> Result<Vec<(PossessionChallenge, PossessionProof)>, BigKeyError> {
+    (0..count)
+        .map(|_| {
+            let challenge = PossessionChallenge::random(reader.block_count(), len)?;
+            let proof = prove_possession(reader, &challenge)?;
+            Ok((challenge, proof))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::storage::{
+        challenge_len, precompute_challenges, prove_possession, verify_possession,
+        PossessionChallenge, PossessionProof, ReadSeekStorage, StorageReader, CHALLENGE_NONCE_LEN,
+        MAX_CHALLENGE_LEN,
+    };
+    use crate::traits::{BigKeyError, BlockCount, BlockIndex, BLOCK_1K};
+
+    #[test]
+    fn custodians_prove_possession_of_the_key() {
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 241) as u8).collect();
+        let mut ours = ReadSeekStorage::new(Cursor::new(contents.clone()), BLOCK_1K).unwrap();
+        let mut custodian = ReadSeekStorage::new(Cursor::new(contents.clone()), BLOCK_1K).unwrap();
+
+        let len = challenge_len(0.5, 40).unwrap();
+        assert_eq!(len, 40);
+        let challenge = PossessionChallenge::random(ours.block_count(), len).unwrap();
+        let sent = PossessionChallenge::from_bytes(&challenge.to_bytes()).unwrap();
+        assert_eq!(sent, challenge);
+        let proof = prove_possession(&mut custodian, &sent).unwrap();
+        verify_possession(&mut ours, &challenge, &proof).unwrap();
+
+        // answers are bound to the nonce
+        let mut replayed = challenge.clone();
+        replayed.nonce[0] ^= 1;
+        assert!(matches!(
+            verify_possession(&mut ours, &replayed, &proof),
+            Err(BigKeyError::VerificationFailed { .. })
+        ));
+
+        // a custodian that lost a block it is challenged on fails
+        let mut damaged = contents;
+        let lost = challenge.indices[0].get() as usize * 1024;
+        damaged[lost..lost + 1024].fill(0);
+        let mut custodian = ReadSeekStorage::new(Cursor::new(damaged), BLOCK_1K).unwrap();
+        let proof = prove_possession(&mut custodian, &challenge).unwrap();
+        assert!(verify_possession(&mut ours, &challenge, &proof).is_err());
+
+        let precomputed = precompute_challenges(&mut ours, 3, 8).unwrap();
+        assert_eq!(precomputed.len(), 3);
+        assert!(precomputed.iter().all(|(c, _)| c.indices.len() == 8));
+        assert!(PossessionChallenge::from_bytes(&challenge.to_bytes()[..40]).is_err());
+    }
+
+    #[test]
+    fn hostile_challenges_and_configs_are_refused() {
+        let reason = |bytes: &[u8]| match PossessionChallenge::from_bytes(bytes) {
+            Err(BigKeyError::InvalidChallenge { reason }) => reason,
+            _ => panic!("expected the challenge to be refused"),
+        };
+        let challenge = PossessionChallenge {
+            nonce: [9u8; CHALLENGE_NONCE_LEN],
+            indices: vec![BlockIndex::new(0), BlockIndex::new(u64::MAX)],
+        };
+        let bytes = challenge.to_bytes();
+        assert_eq!(PossessionChallenge::from_bytes(&bytes).unwrap(), challenge);
+        assert_eq!(
+            reason(&bytes[..CHALLENGE_NONCE_LEN + 3]),
+            "truncated challenge"
+        );
+        assert_eq!(
+            reason(&bytes[..bytes.len() - 1]),
+            "challenge length differs from its indices"
+        );
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(reason(&extra), "challenge length differs from its indices");
+        // empty and oversized counts are refused before any index is read
+        for count in [0u32, MAX_CHALLENGE_LEN as u32 + 1, u32::MAX].iter() {
+            let mut claimed = bytes.clone();
+            claimed[CHALLENGE_NONCE_LEN..CHALLENGE_NONCE_LEN + 4]
+                .copy_from_slice(&count.to_be_bytes());
+            assert_eq!(reason(&claimed), "challenge length out of range");
+        }
+
+        for (block_count, len) in [(0, 8), (8, 0), (8, MAX_CHALLENGE_LEN + 1)].iter() {
+            assert!(matches!(
+                PossessionChallenge::random(BlockCount::new(*block_count), *len),
+                Err(BigKeyError::InvalidChallenge { .. })
+            ));
+        }
+
+        for fraction in [0.0, -0.5, 1.5, f64::NAN].iter() {
+            assert!(matches!(
+                challenge_len(*fraction, 128),
+                Err(BigKeyError::InvalidConfig { .. })
+            ));
+        }
+        assert_eq!(challenge_len(1.0, 128).unwrap(), 1);
+        assert_eq!(challenge_len(1e-12, 256).unwrap(), MAX_CHALLENGE_LEN);
+
+        // indices past the end of the key fail rather than hashing nothing
+        let mut ours = ReadSeekStorage::new(Cursor::new(vec![7u8; 4 * 1024]), BLOCK_1K).unwrap();
+        assert!(prove_possession(&mut ours, &challenge).is_err());
+        assert!(verify_possession(&mut ours, &challenge, &PossessionProof([0u8; 32])).is_err());
+    }
+} // mod test
@@ -0,0 +1,297 @@
+//! Checks to run before generating a BigKey.
+//!
+//! Generating a multi-terabyte key takes hours; `preflight()` catches the failures that would
+//! otherwise only surface at the end (a full disk, a filesystem that caps file size, a
+//! read-only directory) before any key material is written.
+//!
+//! It also looks for filesystems that compress or deduplicate what is written to them. A key on
+//! one is stored in less space the less random it is, so the on-disk size of an all-zero region
+//! or of a generator bug's low-entropy output gives away something about its contents. Two
+//! checks are made: the filesystem type (btrfs, ZFS, bcachefs and F2FS can compress and
+//! deduplicate, depending on how they are mounted) and a scratch file of zeros, which a
+//! compressing filesystem stores in less than its length. `CompressionPolicy` chooses whether a
+//! finding is ignored, logged or fails preflight.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::storage::header::HEADER_LEN;
+use crate::traits::BigKeyError;
+
+// Length of the scratch file of zeros written to detect compression
+const COMPRESSION_PROBE_LEN: usize = 1024 * 1024;
+
+/// What `preflight_with()` does when the key would be stored compressed or deduplicated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionPolicy {
+    /// Skip the checks
+    Ignore,
+    /// Log a warning and carry on
+    #[default]
+    Warn,
+    /// Fail with `CompressingFilesystem`
+    Refuse,
+}
+
+/// Verify that a `size` byte key (plus header) can be written to `path`: the platform can
+/// address it, the directory is writable, and the filesystem has room for it and allows files
+/// that large. An existing file at `path` counts as free space, as generation replaces it.
+///
+/// Warns about filesystems that compress or deduplicate, see `preflight_with()` to refuse them.
+pub fn preflight(path: impl AsRef<Path>, size: u64) -> Result<(), BigKeyError> {
+    preflight_with(path, size, CompressionPolicy::default())
+}
+
+/// `preflight()`, applying `compression` to a filesystem that compresses or deduplicates
+pub fn preflight_with(
+    path: impl AsRef<Path>,
+    size: u64,
+    compression: CompressionPolicy,
+) -> Result<(), BigKeyError> {
+    let path = path.as_ref();
+    let required = size + HEADER_LEN as u64;
+
+    if size > usize::MAX as u64 {
+        return Err(BigKeyError::FileTooLarge {
+            size: required,
+            max_size: usize::MAX as u64,
+        });
+    }
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    check_writable(path, dir)?;
+
+    let limits = filesystem_limits(dir)?;
+    if let Some(max_size) = limits.max_file_size {
+        if required > max_size {
+            return Err(BigKeyError::FileTooLarge {
+                size: required,
+                max_size,
+            });
+        }
+    }
+    if let Some(available) = limits.available {
+        let replaced = path.metadata().map(|m| m.len()).unwrap_or(0);
+        if required > available + replaced {
+            return Err(BigKeyError::InsufficientSpace {
+                required,
+                available: available + replaced,
+            });
+        }
+    }
+
+    if compression != CompressionPolicy::Ignore {
+        if let Some(reason) = detect_compression(path, dir)? {
+            if compression == CompressionPolicy::Refuse {
+                return Err(BigKeyError::CompressingFilesystem {
+                    path: dir.display().to_string(),
+                    reason,
+                });
+            }
+            log::warn!(
+                "{} may store the key compressed or deduplicated: {}",
+                dir.display(),
+                reason
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Why the filesystem holding `dir` may compress or deduplicate a key written to `path` in it,
+/// `None` if neither its type nor a scratch file of zeros suggest it does
+pub fn detect_compression(path: &Path, dir: &Path) -> Result<Option<String>, BigKeyError> {
+    if let Some(name) = filesystem_type(dir)? {
+        return Ok(Some(format!("{} filesystem", name)));
+    }
+
+    let scratch = scratch_path(path, dir);
+    let stored = write_zeros(&scratch);
+    let _ = std::fs::remove_file(&scratch);
+    Ok(stored?
+        .filter(|&stored| stored < COMPRESSION_PROBE_LEN as u64 / 2)
+        .map(|stored| {
+            format!(
+                "{} bytes of zeros were stored in {} bytes",
+                COMPRESSION_PROBE_LEN, stored
+            )
+        }))
+}
+
+// Bytes the filesystem allocated for a synced scratch file of zeros, `None` where unknown
+#[cfg(unix)]
+fn write_zeros(scratch: &Path) -> Result<Option<u64>, BigKeyError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(scratch)?;
+    file.write_all(&[0u8; COMPRESSION_PROBE_LEN])?;
+    file.sync_all()?;
+    Ok(Some(file.metadata()?.blocks() * 512))
+}
+
+#[cfg(not(unix))]
+fn write_zeros(_scratch: &Path) -> Result<Option<u64>, BigKeyError> {
+    Ok(None)
+}
+
+// Name of the filesystem holding `dir` if it is of a type that can compress or deduplicate
+#[cfg(target_os = "linux")]
+fn filesystem_type(dir: &Path) -> Result<Option<&'static str>, BigKeyError> {
+    const COMPRESSING: [(u32, &str); 4] = [
+        (0x9123_683e, "btrfs"),
+        (0x2fc1_2fc1, "ZFS"),
+        (0xca45_1a4e, "bcachefs"),
+        (0xf2f5_2010, "F2FS"),
+    ];
+
+    let c_dir = c_path(dir)?;
+    // Safety: `c_dir` is NUL terminated and `stats` is only read after statfs succeeds
+    let f_type = unsafe {
+        let mut stats: libc::statfs = std::mem::zeroed();
+        match libc::statfs(c_dir.as_ptr(), &mut stats) {
+            0 => stats.f_type as u32,
+            _ => return Err(std::io::Error::last_os_error().into()),
+        }
+    };
+    Ok(COMPRESSING
+        .iter()
+        .find(|(magic, _)| *magic == f_type)
+        .map(|(_, name)| *name))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn filesystem_type(_dir: &Path) -> Result<Option<&'static str>, BigKeyError> {
+    Ok(None)
+}
+
+fn scratch_path(path: &Path, dir: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".preflight");
+    dir.join(name)
+}
+
+// Create and remove a scratch file next to `path`
+fn check_writable(path: &Path, dir: &Path) -> Result<(), BigKeyError> {
+    let scratch = scratch_path(path, dir);
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&scratch)
+        .map_err(|e| BigKeyError::NotWritable {
+            path: dir.display().to_string(),
+            reason: e.to_string(),
+        })?;
+    let _ = std::fs::remove_file(&scratch);
+    Ok(())
+}
+
+// What the filesystem holding a directory allows, `None` where unknown
+struct FilesystemLimits {
+    available: Option<u64>,
+    max_file_size: Option<u64>,
+}
+
+#[cfg(unix)]
+fn c_path(dir: &Path) -> Result<std::ffi::CString, BigKeyError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::CString::new(dir.as_os_str().as_bytes()).map_err(|_| BigKeyError::NotWritable {
+        path: dir.display().to_string(),
+        reason: "path contains a NUL byte".to_string(),
+    })
+}
+
+#[cfg(unix)]
+fn filesystem_limits(dir: &Path) -> Result<FilesystemLimits, BigKeyError> {
+    let c_dir = c_path(dir)?;
+
+    // Safety: `c_dir` is NUL terminated and `stats` is only read after statvfs succeeds
+    let available = unsafe {
+        let mut stats: libc::statvfs = std::mem::zeroed();
+        match libc::statvfs(c_dir.as_ptr(), &mut stats) {
+            0 => Some(stats.f_bavail as u64 * stats.f_frsize as u64),
+            _ => return Err(std::io::Error::last_os_error().into()),
+        }
+    };
+
+    // -1 means no limit (or unknown), otherwise the bits in a signed file offset
+    let bits = unsafe { libc::pathconf(c_dir.as_ptr(), libc::_PC_FILESIZEBITS) };
+    let max_file_size = match bits {
+        1..=63 => Some((1u64 << (bits - 1)) - 1),
+        _ => None,
+    };
+
+    Ok(FilesystemLimits {
+        available,
+        max_file_size,
+    })
+}
+
+#[cfg(not(unix))]
+fn filesystem_limits(_dir: &Path) -> Result<FilesystemLimits, BigKeyError> {
+    Ok(FilesystemLimits {
+        available: None,
+        max_file_size: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::preflight::{detect_compression, preflight, preflight_with};
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::CompressionPolicy;
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn small_key_passes() {
+        let tmp = tempfile();
+        preflight(tmp.as_path(), 1024 * 1024).unwrap();
+        assert!(!tmp.as_path().exists());
+    }
+
+    #[test]
+    fn impossible_keys_fail() {
+        let tmp = tempfile();
+        match preflight(tmp.as_path(), u64::MAX / 2) {
+            Err(BigKeyError::InsufficientSpace { .. }) | Err(BigKeyError::FileTooLarge { .. }) => {}
+            r => panic!("expected an exabyte key to fail preflight, got {:?}", r),
+        }
+
+        let missing_dir = tmp.as_path().join("key");
+        match preflight(&missing_dir, 1024) {
+            Err(BigKeyError::NotWritable { .. }) => {}
+            r => panic!("expected missing directory to fail preflight, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn compressing_filesystems_follow_the_policy() {
+        let tmp = tempfile();
+        let path = tmp.as_path();
+        let dir = path.parent().unwrap();
+        let finding = detect_compression(path, dir).unwrap();
+        assert!(!std::path::Path::new(&format!("{}.preflight", tmp.to_str())).exists());
+
+        preflight_with(path, 1024, CompressionPolicy::Ignore).unwrap();
+        preflight_with(path, 1024, CompressionPolicy::Warn).unwrap();
+        match (
+            preflight_with(path, 1024, CompressionPolicy::Refuse),
+            finding,
+        ) {
+            (Ok(()), None) => {}
+            (Err(BigKeyError::CompressingFilesystem { reason, .. }), Some(found)) => {
+                assert_eq!(reason, found)
+            }
+            (r, found) => panic!("refusal {:?} disagrees with finding {:?}", r, found),
+        }
+    }
+} // mod test
@@ -0,0 +1,346 @@
+//! Where a BigKey came from, recorded in its `KeyHeader` for audits.
+//!
+//! The header already names the generator algorithm; the provenance record adds a commitment to
+//! the seed the key was generated from, the host it was generated on and when, and optionally an
+//! Ed25519 signature by the operator who ran the generation. The signature covers the provenance
+//! together with the generator, geometry and fingerprint of the key, so a verified signature
+//! vouches for the key contents and not just for the record.
+//!
+//! The seed commitment is a keyed BLAKE3 digest of the seed. Auditors holding a candidate seed
+//! (e.g. one recovered from escrow) can check it with `matches_seed()`; the commitment hides the
+//! seed only as long as the seed has enough entropy to resist guessing, which every seed
+//! accepted for key generation must have anyway.
+//!
+//! Provenance region layout, from byte `PROVENANCE_OFFSET` of the header: flags byte, creation
+//! time (u64 seconds since the Unix epoch), 32 byte seed commitment, host length byte and up to
+//! `MAX_HOST_LEN` bytes of host name, 32 byte operator public key and 64 byte signature.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "operator-signing")]
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::memory::wipe;
+use crate::storage::header::KeyHeader;
+use crate::traits::{ct_eq, BigKeyError, SecretBytes};
+
+/// Longest host name recorded, in bytes
+pub const MAX_HOST_LEN: usize = 255;
+
+/// Offset of the provenance region within the header
+pub(crate) const PROVENANCE_OFFSET: usize = 128;
+
+/// Length of the provenance region within the header
+pub(crate) const PROVENANCE_LEN: usize = 1 + 8 + 32 + 1 + MAX_HOST_LEN + 32 + 64;
+
+const SEED_COMMITMENT_CONTEXT: &str = "big_fluffy_dise 2024 seed commitment v1";
+const STATEMENT_DOMAIN: &[u8] = b"big_fluffy_dise provenance v1\x00";
+
+const FLAG_SEED: u8 = 0x01;
+const FLAG_OPERATOR: u8 = 0x02;
+
+const CREATED_AT: usize = 1;
+const SEED: usize = CREATED_AT + 8;
+const HOST: usize = SEED + 32;
+const PUBLIC_KEY: usize = HOST + 1 + MAX_HOST_LEN;
+const SIGNATURE: usize = PUBLIC_KEY + 32;
+
+/// Commitment to a generator seed as recorded in `Provenance::seed_commitment`
+pub fn commit_seed(seed: &[u8]) -> [u8; 32] {
+    let mut commitment = [0u8; 32];
+    blake3::derive_key(SEED_COMMITMENT_CONTEXT, seed, &mut commitment);
+    commitment
+}
+
+/// Provenance of a BigKey
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// `commit_seed()` of the seed the key was generated from, `None` for unseeded generators
+    pub seed_commitment: Option<[u8; 32]>,
+    /// Name of the generating host, at most `MAX_HOST_LEN` bytes
+    pub host: String,
+    /// Seconds since the Unix epoch when generation started
+    pub created_at: u64,
+    /// Operator signature, see `sign()`
+    pub operator: Option<OperatorSignature>,
+}
+
+/// Ed25519 signature by the operator who generated a key
+#[derive(Clone, PartialEq, Eq)]
+pub struct OperatorSignature {
+    pub public_key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl fmt::Debug for OperatorSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OperatorSignature")
+            .field("public_key", &self.public_key.to_hex())
+            .finish()
+    }
+}
+
+/// An operator's Ed25519 signing key. Wiped on drop.
+pub struct OperatorKey([u8; 32]);
+
+impl OperatorKey {
+    /// Generate a new operator key from operating system randomness
+    pub fn generate() -> Result<Self, BigKeyError> {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes)?;
+        Ok(OperatorKey(bytes))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        OperatorKey(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    #[cfg(feature = "operator-signing")]
+    pub fn public_key(&self) -> Result<[u8; 32], BigKeyError> {
+        Ok(SigningKey::from_bytes(&self.0).verifying_key().to_bytes())
+    }
+
+    #[cfg(not(feature = "operator-signing"))]
+    pub fn public_key(&self) -> Result<[u8; 32], BigKeyError> {
+        Err(unsupported())
+    }
+}
+
+impl Drop for OperatorKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl Provenance {
+    /// Provenance of a key being generated now on this host, from `seed` if the generator is
+    /// seeded
+    pub fn new(seed: Option<&[u8]>) -> Self {
+        Provenance {
+            seed_commitment: seed.map(commit_seed),
+            host: local_host(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            operator: None,
+        }
+    }
+
+    /// Whether the key was generated from `seed`, by its commitment
+    pub fn matches_seed(&self, seed: &[u8]) -> bool {
+        self.seed_commitment
+            .is_some_and(|commitment| ct_eq(&commitment, &commit_seed(seed)))
+    }
+
+    /// Sign the provenance together with `header` under `key`. `header` must hold the key's
+    /// fingerprint, so sign once the key is complete.
+    #[cfg(feature = "operator-signing")]
+    pub fn sign(&mut self, header: &KeyHeader, key: &OperatorKey) -> Result<(), BigKeyError> {
+        let signing_key = SigningKey::from_bytes(&key.0);
+        let public_key = signing_key.verifying_key().to_bytes();
+        let statement = self.statement(header, &public_key)?;
+        self.operator = Some(OperatorSignature {
+            public_key,
+            signature: signing_key.sign(&statement).to_bytes(),
+        });
+        Ok(())
+    }
+
+    #[cfg(not(feature = "operator-signing"))]
+    pub fn sign(&mut self, _header: &KeyHeader, _key: &OperatorKey) -> Result<(), BigKeyError> {
+        Err(unsupported())
+    }
+
+    /// Check the operator signature against `header`, returning the operator's public key, or
+    /// `None` if the provenance is unsigned. Fails with `InvalidProvenance` if the signature
+    /// does not match.
+    #[cfg(feature = "operator-signing")]
+    pub fn verify(&self, header: &KeyHeader) -> Result<Option<[u8; 32]>, BigKeyError> {
+        let operator = match &self.operator {
+            Some(operator) => operator,
+            None => return Ok(None),
+        };
+        let statement = self.statement(header, &operator.public_key)?;
+        VerifyingKey::from_bytes(&operator.public_key)
+            .and_then(|key| {
+                key.verify_strict(&statement, &Signature::from_bytes(&operator.signature))
+            })
+            .map_err(|_| BigKeyError::InvalidProvenance {
+                reason: "operator signature does not match",
+            })?;
+        Ok(Some(operator.public_key))
+    }
+
+    #[cfg(not(feature = "operator-signing"))]
+    pub fn verify(&self, _header: &KeyHeader) -> Result<Option<[u8; 32]>, BigKeyError> {
+        match self.operator {
+            Some(_) => Err(unsupported()),
+            None => Ok(None),
+        }
+    }
+
+    // The signed message: the provenance and the header fields describing the key contents
+    #[cfg_attr(not(feature = "operator-signing"), allow(dead_code))]
+    fn statement(&self, header: &KeyHeader, public_key: &[u8; 32]) -> Result<Vec<u8>, BigKeyError> {
+        let fingerprint = header.fingerprint.ok_or(BigKeyError::InvalidProvenance {
+            reason: "header has no fingerprint to sign",
+        })?;
+        let mut statement = STATEMENT_DOMAIN.to_vec();
+        statement.extend_from_slice(&(header.generator as u16).to_be_bytes());
+        statement.extend_from_slice(&(header.block_len as u32).to_be_bytes());
+        statement.extend_from_slice(&header.key_length.to_be_bytes());
+        statement.extend_from_slice(&fingerprint);
+        match &self.seed_commitment {
+            Some(commitment) => {
+                statement.push(1);
+                statement.extend_from_slice(commitment);
+            }
+            None => statement.push(0),
+        }
+        statement.extend_from_slice(&self.created_at.to_be_bytes());
+        let host = truncated(&self.host);
+        statement.push(host.len() as u8);
+        statement.extend_from_slice(host.as_bytes());
+        statement.extend_from_slice(public_key);
+        Ok(statement)
+    }
+
+    pub(crate) fn to_bytes(&self) -> [u8; PROVENANCE_LEN] {
+        let mut out = [0u8; PROVENANCE_LEN];
+        let mut flags = 0u8;
+
+        out[CREATED_AT..SEED].copy_from_slice(&self.created_at.to_be_bytes());
+        if let Some(commitment) = &self.seed_commitment {
+            flags |= FLAG_SEED;
+            out[SEED..HOST].copy_from_slice(commitment);
+        }
+        let host = truncated(&self.host);
+        out[HOST] = host.len() as u8;
+        out[HOST + 1..HOST + 1 + host.len()].copy_from_slice(host.as_bytes());
+        if let Some(operator) = &self.operator {
+            flags |= FLAG_OPERATOR;
+            out[PUBLIC_KEY..SIGNATURE].copy_from_slice(&operator.public_key);
+            out[SIGNATURE..].copy_from_slice(&operator.signature);
+        }
+        out[0] = flags;
+
+        out
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, BigKeyError> {
+        let bytes = &bytes[..PROVENANCE_LEN];
+        let flags = bytes[0];
+        let host_len = bytes[HOST] as usize;
+        let host = std::str::from_utf8(&bytes[HOST + 1..HOST + 1 + host_len]).map_err(|_| {
+            BigKeyError::InvalidProvenance {
+                reason: "host name is not UTF-8",
+            }
+        })?;
+
+        Ok(Provenance {
+            seed_commitment: match flags & FLAG_SEED {
+                0 => None,
+                _ => Some(bytes[SEED..HOST].try_into().unwrap()),
+            },
+            host: host.to_string(),
+            created_at: u64::from_be_bytes(bytes[CREATED_AT..SEED].try_into().unwrap()),
+            operator: match flags & FLAG_OPERATOR {
+                0 => None,
+                _ => Some(OperatorSignature {
+                    public_key: bytes[PUBLIC_KEY..SIGNATURE].try_into().unwrap(),
+                    signature: bytes[SIGNATURE..].try_into().unwrap(),
+                }),
+            },
+        })
+    }
+}
+
+// `host` cut to at most `MAX_HOST_LEN` bytes on a character boundary
+fn truncated(host: &str) -> &str {
+    let mut len = host.len().min(MAX_HOST_LEN);
+    while !host.is_char_boundary(len) {
+        len -= 1;
+    }
+    &host[..len]
+}
+
+// Name of this host, empty if it cannot be determined
+#[cfg(unix)]
+fn local_host() -> String {
+    let mut buf = [0u8; 256];
+    // Safety: `buf` outlives the call and its length is passed along
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc == -1 {
+        return String::new();
+    }
+    let len = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    truncated(&String::from_utf8_lossy(&buf[..len])).to_string()
+}
+
+#[cfg(not(unix))]
+fn local_host() -> String {
+    std::env::var("COMPUTERNAME")
+        .map(|host| truncated(&host).to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(not(feature = "operator-signing"))]
+fn unsupported() -> BigKeyError {
+    BigKeyError::InvalidProvenance {
+        reason: "built without the `operator-signing` feature",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::provenance::{commit_seed, Provenance};
+
+    #[test]
+    fn provenance_commits_to_the_seed() {
+        let provenance = Provenance::new(Some(b"a seed of sixty four bytes, give or take"));
+        assert_eq!(
+            provenance.seed_commitment,
+            Some(commit_seed(b"a seed of sixty four bytes, give or take"))
+        );
+        assert!(provenance.matches_seed(b"a seed of sixty four bytes, give or take"));
+        assert!(!provenance.matches_seed(b"another seed"));
+        assert!(!Provenance::new(None).matches_seed(b""));
+        assert!(provenance.created_at > 0);
+    }
+
+    #[cfg(feature = "operator-signing")]
+    #[test]
+    fn operator_signatures_cover_the_key() {
+        use crate::storage::{KeyHeader, OperatorKey};
+        use crate::traits::{GeneratorId, BLOCK_4K};
+
+        let mut header = KeyHeader::new(GeneratorId::Shake256, BLOCK_4K, 4096 * 4);
+        header.fingerprint = Some([0x11; 32]);
+        let key = OperatorKey::from_bytes([7u8; 32]);
+
+        let mut provenance = Provenance::new(Some(b"seed"));
+        assert_eq!(provenance.verify(&header).unwrap(), None);
+        provenance.sign(&header, &key).unwrap();
+        assert_eq!(
+            provenance.verify(&header).unwrap(),
+            Some(key.public_key().unwrap())
+        );
+
+        let mut other = header.clone();
+        other.fingerprint = Some([0x22; 32]);
+        assert!(provenance.verify(&other).is_err());
+
+        let mut moved = provenance.clone();
+        moved.host.push('x');
+        assert!(moved.verify(&header).is_err());
+
+        header.fingerprint = None;
+        assert!(provenance.verify(&header).is_err());
+    }
+} // mod test
@@ -0,0 +1,197 @@
+//! Probing BigKeys held in any seekable byte stream.
+//!
+//! `ReadSeekStorage` adapts a `Read + Seek` value, e.g. an in-memory `Cursor`, a member of an
+//! archive, or an application's virtual file, into a `StorageReader` without writing a backend
+//! of its own. Streams are interpreted like key files: with a `KeyHeader` if they start with
+//! one, raw key bytes otherwise. `with_range()` instead probes a raw key embedded at a fixed
+//! offset within a larger stream.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::storage::header::{KeyHeader, HEADER_LEN};
+use crate::storage::permutation::{physical_offset, BlockPermutation};
+use crate::storage::util::{check_key_evenly_divisible, data_position};
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// A `StorageReader` over any `Read + Seek`
+pub struct ReadSeekStorage<T: Read + Seek> {
+    inner: T,
+    block_size: BlockSize,
+    data_offset: u64,
+    big_key_length: u64,
+    header: Option<KeyHeader>,
+    permutation: Option<BlockPermutation>,
+}
+
+impl<T: Read + Seek> ReadSeekStorage<T> {
+    /// Probe the whole of `inner` as a key file
+    pub fn new(mut inner: T, block_size: BlockSize) -> Result<Self, BigKeyError> {
+        let stream_length = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(0))?;
+        let header = KeyHeader::read_from(&mut inner)?;
+
+        let (data_offset, big_key_length) = match &header {
+            Some(header) => {
+                if header.block_len != block_size.byte_len {
+                    return Err(BigKeyError::BlockSizeMismatch {
+                        requested_len: block_size.byte_len,
+                        header_len: header.block_len,
+                    });
+                }
+                if stream_length - HEADER_LEN as u64 != header.key_length {
+                    return Err(BigKeyError::HeaderLengthMismatch {
+                        header_len: header.key_length,
+                        file_len: stream_length - HEADER_LEN as u64,
+                    });
+                }
+                (HEADER_LEN as u64, header.key_length)
+            }
+            None => (0, stream_length),
+        };
+        check_key_evenly_divisible(block_size, big_key_length)?;
+        let permutation = header
+            .as_ref()
+            .and_then(|header| header.permutation)
+            .map(|key| BlockPermutation::new(key, big_key_length / block_size.byte_len as u64));
+
+        Ok(ReadSeekStorage {
+            inner,
+            block_size,
+            data_offset,
+            big_key_length,
+            header,
+            permutation,
+        })
+    }
+
+    /// Probe the `length` raw key bytes starting at `offset` within `inner`
+    pub fn with_range(
+        mut inner: T,
+        block_size: BlockSize,
+        offset: u64,
+        length: u64,
+    ) -> Result<Self, BigKeyError> {
+        check_key_evenly_divisible(block_size, length)?;
+        let stream_length = inner.seek(SeekFrom::End(0))?;
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > stream_length)
+        {
+            return Err(BigKeyError::ProbeOffsetOutOfBounds {
+                end_of_key: stream_length as usize,
+                offset: offset as usize,
+                probe_len: length as usize,
+            });
+        }
+
+        Ok(ReadSeekStorage {
+            inner,
+            block_size,
+            data_offset: offset,
+            big_key_length: length,
+            header: None,
+            permutation: None,
+        })
+    }
+
+    /// The `KeyHeader` at the start of the stream, `None` for raw keys
+    pub fn header(&self) -> Option<&KeyHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+// `Read + Seek` cannot read ahead without blocking, so prefetch hints are ignored
+impl<T: Read + Seek> StorageReader for ReadSeekStorage<T> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        if output.len() != self.block_size.byte_len {
+            return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+                out_buf_len: output.len(),
+                block_len: self.block_size.byte_len,
+            });
+        }
+
+        let offset = physical_offset(
+            self.permutation.as_ref(),
+            index,
+            self.block_size,
+            self.big_key_length,
+        )?;
+        let position = data_position(
+            self.data_offset,
+            offset,
+            self.block_size,
+            self.big_key_length,
+        )?;
+
+        self.inner.seek(SeekFrom::Start(position))?;
+        self.inner.read_exact(output)?;
+        Ok(())
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.big_key_length
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Write};
+
+    use crate::storage::readseek::ReadSeekStorage;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{DiskStorage, StorageReader, StorageWriter};
+    use crate::traits::{BigKeyError, BlockIndex, BLOCK_1K, BLOCK_32};
+
+    #[test]
+    fn probes_raw_and_headered_streams() {
+        let raw: Vec<u8> = (0..64u8).collect();
+        let mut storage = ReadSeekStorage::new(Cursor::new(raw), BLOCK_32).unwrap();
+        assert!(storage.header().is_none());
+        assert_eq!(storage.big_key_length(), 64);
+        let mut block = [0u8; 4];
+        storage.probe(BlockIndex::new(3), &mut block).unwrap();
+        assert_eq!(block, [12, 13, 14, 15]);
+        match storage.probe(BlockIndex::new(16), &mut block) {
+            Err(BigKeyError::ProbeOffsetOutOfBounds { .. }) => {}
+            _ => panic!("expected an index out of bounds error"),
+        }
+
+        let tmp = tempfile();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 4096).unwrap();
+        for i in 0..4u8 {
+            writer.write_all(&[i; 1024]).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let bytes = std::fs::read(tmp.as_path()).unwrap();
+        let mut storage = ReadSeekStorage::new(Cursor::new(bytes), BLOCK_1K).unwrap();
+        assert_eq!(storage.header().unwrap().key_length, 4096);
+        let mut block = [0u8; 1024];
+        storage.probe(BlockIndex::new(2), &mut block).unwrap();
+        assert_eq!(block, [2; 1024]);
+    }
+
+    #[test]
+    fn probes_embedded_range() {
+        let mut archive = vec![0xee; 100];
+        archive.extend((0..32u8).collect::<Vec<u8>>());
+        archive.extend_from_slice(&[0xee; 50]);
+
+        let mut storage =
+            ReadSeekStorage::with_range(Cursor::new(&archive[..]), BLOCK_32, 100, 32).unwrap();
+        let mut block = [0u8; 4];
+        storage.probe(BlockIndex::new(7), &mut block).unwrap();
+        assert_eq!(block, [28, 29, 30, 31]);
+
+        assert!(ReadSeekStorage::with_range(Cursor::new(&archive[..]), BLOCK_32, 160, 32).is_err());
+    }
+} // mod test
@@ -0,0 +1,670 @@
+//! Probing BigKeys kept in remote object storage, e.g. a key file uploaded by
+//! `S3MultipartWriter`.
+//!
+//! Every probe of a remote key is a ranged read costing a network round trip, so probing the
+//! 500 blocks of a derivation one after the other over a 20 ms link takes ten seconds.
+//! `RemoteStorage` overrides `probe_many()`, which `BigKey` probes a derivation's blocks
+//! through, to keep a window of reads in flight and place blocks as they complete, in whatever
+//! order they complete in. Reads run on a pool of `ProbePipelineOptions::max_in_flight` worker
+//! threads, started with the first read and kept until the storage is dropped.
+//!
+//! The window adapts like TCP's congestion window. It starts at
+//! `ProbePipelineOptions::initial_window` and grows by one read per completed read, doubling
+//! every round trip, until `slow_start_threshold`; from there it grows by one read per window.
+//! A read failing with a retryable error (see `BigKeyError::is_retryable()`), typically
+//! throttling or a timeout, halves the window and is retried. The window never exceeds
+//! `max_in_flight`, and is kept across derivations.
+//!
+//! `prefetch()` queues reads of blocks without waiting for them. Completed prefetches are kept
+//! (at most `max_prefetched` blocks, the oldest dropped first) until a probe takes them; a
+//! probe of a block still being prefetched reads it again. Failed prefetches are dropped: the
+//! probe reads the block itself and reports any error.
+//!
+//! As with `MultipartClient`, the crate carries no HTTP stack: reads go through a
+//! `RangeClient`, e.g. S3 `GetObject` with a `Range` header, implemented over whichever client
+//! the application already uses.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::memory::wipe;
+use crate::storage::header::{KeyHeader, HEADER_LEN};
+use crate::storage::permutation::{physical_offset, BlockPermutation};
+use crate::storage::traits::check_batch_len;
+use crate::storage::util::{check_key_evenly_divisible, data_position};
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// Ranged reads of a remote object
+pub trait RangeClient: Send + Sync {
+    /// Length of the object in bytes
+    fn object_length(&self) -> Result<u64, BigKeyError>;
+
+    /// Read `output.len()` bytes of the object starting at `offset`
+    fn read_range(&self, offset: u64, output: &mut [u8]) -> Result<(), BigKeyError>;
+}
+
+/// How `RemoteStorage` pipelines its probes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProbePipelineOptions {
+    /// Upper bound of the window, and the number of reader threads
+    pub max_in_flight: usize,
+    /// Window of the first derivation
+    pub initial_window: usize,
+    /// Window up to which it doubles every round trip
+    pub slow_start_threshold: usize,
+    /// Attempts of a read failing with retryable errors before the probe fails
+    pub max_attempts: u32,
+    /// Prefetched blocks kept until probed
+    pub max_prefetched: usize,
+}
+
+impl Default for ProbePipelineOptions {
+    fn default() -> Self {
+        ProbePipelineOptions {
+            max_in_flight: 64,
+            initial_window: 4,
+            slow_start_threshold: 32,
+            max_attempts: 4,
+            max_prefetched: 4096,
+        }
+    }
+}
+
+// Additive increase, multiplicative decrease window of reads in flight
+#[derive(Debug, Clone)]
+struct CongestionWindow {
+    size: usize,
+    threshold: usize,
+    max: usize,
+    // reads completed since the window last grew, past the threshold
+    acked: usize,
+}
+
+impl CongestionWindow {
+    fn new(options: &ProbePipelineOptions) -> Self {
+        let max = options.max_in_flight.max(1);
+        CongestionWindow {
+            size: options.initial_window.clamp(1, max),
+            threshold: options.slow_start_threshold.clamp(1, max),
+            max,
+            acked: 0,
+        }
+    }
+
+    fn completed(&mut self) {
+        if self.size < self.threshold {
+            self.size += 1;
+        } else {
+            self.acked += 1;
+            if self.acked >= self.size {
+                self.size += 1;
+                self.acked = 0;
+            }
+        }
+        self.size = self.size.min(self.max);
+    }
+
+    fn congested(&mut self) {
+        self.threshold = (self.size / 2).max(1);
+        self.size = self.threshold;
+        self.acked = 0;
+    }
+}
+
+// A read for the pool, answered on `done`
+struct ReadJob {
+    position: u64,
+    len: usize,
+    // slot in the output of a `probe_many()`, unused by prefetches
+    slot: usize,
+    attempt: u32,
+    done: Sender<ReadDone>,
+}
+
+struct ReadDone {
+    position: u64,
+    slot: usize,
+    attempt: u32,
+    result: Result<Vec<u8>, BigKeyError>,
+}
+
+// Fixed set of reader threads taking jobs from a shared queue. Dropping it closes the queue;
+// the threads exit once their current read completes.
+struct ReadPool {
+    jobs: Sender<ReadJob>,
+}
+
+impl ReadPool {
+    fn start<C: RangeClient + 'static>(
+        client: &Arc<C>,
+        threads: usize,
+    ) -> Result<ReadPool, BigKeyError> {
+        let (jobs, queue) = mpsc::channel::<ReadJob>();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads.max(1) {
+            let (client, queue) = (client.clone(), queue.clone());
+            thread::Builder::new()
+                .name("bigkey-remote-read".to_string())
+                .spawn(move || read_jobs(&*client, &queue))?;
+        }
+        Ok(ReadPool { jobs })
+    }
+
+    fn submit(&self, job: ReadJob) {
+        // the readers only exit once the pool is dropped
+        let _ = self.jobs.send(job);
+    }
+}
+
+fn read_jobs<C: RangeClient>(client: &C, queue: &Mutex<Receiver<ReadJob>>) {
+    loop {
+        let job = match queue.lock() {
+            Ok(queue) => match queue.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            },
+            Err(_) => return,
+        };
+        let mut block = vec![0u8; job.len];
+        let result = match client.read_range(job.position, &mut block) {
+            Ok(()) => Ok(block),
+            Err(e) => {
+                wipe(&mut block);
+                Err(e)
+            }
+        };
+        let done = ReadDone {
+            position: job.position,
+            slot: job.slot,
+            attempt: job.attempt,
+            result,
+        };
+        if let Err(mpsc::SendError(ReadDone {
+            result: Ok(mut block),
+            ..
+        })) = job.done.send(done)
+        {
+            wipe(&mut block);
+        }
+    }
+}
+
+// Blocks prefetched and not yet probed, by position, oldest first
+#[derive(Default)]
+struct PrefetchedBlocks {
+    blocks: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+}
+
+impl PrefetchedBlocks {
+    fn insert(&mut self, position: u64, block: Vec<u8>, capacity: usize) {
+        if let Some(mut replaced) = self.blocks.insert(position, block) {
+            wipe(&mut replaced);
+        } else {
+            self.order.push_back(position);
+        }
+        while self.blocks.len() > capacity {
+            let oldest = match self.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(mut block) = self.blocks.remove(&oldest) {
+                wipe(&mut block);
+            }
+        }
+    }
+
+    fn remove(&mut self, position: u64) -> Option<Vec<u8>> {
+        let block = self.blocks.remove(&position)?;
+        self.order.retain(|p| *p != position);
+        Some(block)
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+impl Drop for PrefetchedBlocks {
+    fn drop(&mut self) {
+        for block in self.blocks.values_mut() {
+            wipe(block);
+        }
+    }
+}
+
+/// A BigKey read from a remote object through a `RangeClient`
+pub struct RemoteStorage<C: RangeClient + 'static> {
+    client: Arc<C>,
+    location: String,
+    block_size: BlockSize,
+    big_key_length: u64,
+    data_offset: u64,
+    header: Option<KeyHeader>,
+    permutation: Option<BlockPermutation>,
+    options: ProbePipelineOptions,
+    window: CongestionWindow,
+    pool: Option<ReadPool>,
+    prefetched: PrefetchedBlocks,
+    prefetch_done: (Sender<ReadDone>, Receiver<ReadDone>),
+}
+
+impl<C: RangeClient + 'static> RemoteStorage<C> {
+    /// Open the key file read by `client`, with or without a `KeyHeader`. `location` names the
+    /// object in errors, e.g. `s3://bucket/key`.
+    pub fn open(client: C, block_size: BlockSize, location: &str) -> Result<Self, BigKeyError> {
+        let object_length = client
+            .object_length()
+            .map_err(|e| e.in_storage("stat", location, None))?;
+        let header = match object_length >= HEADER_LEN as u64 {
+            true => {
+                let mut bytes = vec![0u8; HEADER_LEN];
+                client
+                    .read_range(0, &mut bytes)
+                    .map_err(|e| e.in_storage("read header of", location, Some(0)))?;
+                KeyHeader::from_bytes(&bytes)?
+            }
+            false => None,
+        };
+
+        let (big_key_length, data_offset) = match &header {
+            Some(header) => {
+                if header.block_len != block_size.byte_len {
+                    return Err(BigKeyError::BlockSizeMismatch {
+                        requested_len: block_size.byte_len,
+                        header_len: header.block_len,
+                    });
+                }
+                if object_length - HEADER_LEN as u64 != header.key_length {
+                    return Err(BigKeyError::HeaderLengthMismatch {
+                        header_len: header.key_length,
+                        file_len: object_length - HEADER_LEN as u64,
+                    });
+                }
+                (header.key_length, HEADER_LEN as u64)
+            }
+            None => (object_length, 0),
+        };
+        check_key_evenly_divisible(block_size, big_key_length)?;
+        let permutation = header
+            .as_ref()
+            .and_then(|header| header.permutation)
+            .map(|key| BlockPermutation::new(key, big_key_length / block_size.byte_len as u64));
+
+        let options = ProbePipelineOptions::default();
+        Ok(RemoteStorage {
+            client: Arc::new(client),
+            location: location.to_string(),
+            block_size,
+            big_key_length,
+            data_offset,
+            header,
+            permutation,
+            window: CongestionWindow::new(&options),
+            options,
+            pool: None,
+            prefetched: PrefetchedBlocks::default(),
+            prefetch_done: mpsc::channel(),
+        })
+    }
+
+    /// Pipeline probes with `options` instead of `ProbePipelineOptions::default()`
+    pub fn with_pipeline(mut self, options: ProbePipelineOptions) -> Self {
+        self.window = CongestionWindow::new(&options);
+        self.options = options;
+        self.pool = None;
+        self
+    }
+
+    /// Header of the key file, if it has one
+    pub fn header(&self) -> Option<&KeyHeader> {
+        self.header.as_ref()
+    }
+
+    /// Current number of probes kept in flight
+    pub fn window(&self) -> usize {
+        self.window.size
+    }
+
+    /// Number of prefetched blocks waiting to be probed
+    pub fn prefetched(&mut self) -> usize {
+        self.collect_prefetched();
+        self.prefetched.len()
+    }
+
+    fn position(&self, index: BlockIndex) -> Result<u64, BigKeyError> {
+        let offset = physical_offset(
+            self.permutation.as_ref(),
+            index,
+            self.block_size,
+            self.big_key_length,
+        )?;
+        data_position(
+            self.data_offset,
+            offset,
+            self.block_size,
+            self.big_key_length,
+        )
+    }
+
+    fn pool(&mut self) -> Result<&ReadPool, BigKeyError> {
+        if self.pool.is_none() {
+            self.pool = Some(ReadPool::start(&self.client, self.options.max_in_flight)?);
+        }
+        Ok(self.pool.as_ref().expect("pool was just started"))
+    }
+
+    // Keep the prefetches completed so far
+    fn collect_prefetched(&mut self) {
+        while let Ok(done) = self.prefetch_done.1.try_recv() {
+            if let Ok(block) = done.result {
+                self.prefetched
+                    .insert(done.position, block, self.options.max_prefetched);
+            }
+        }
+    }
+}
+
+impl<C: RangeClient + 'static> StorageReader for RemoteStorage<C> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        self.probe_many(&[index], output)
+    }
+
+    fn probe_many(&mut self, indices: &[BlockIndex], output: &mut [u8]) -> Result<(), BigKeyError> {
+        let block_len = self.block_size.byte_len;
+        check_batch_len(indices, output, block_len)?;
+        let positions = indices
+            .iter()
+            .map(|&index| self.position(index))
+            .collect::<Result<Vec<u64>, BigKeyError>>()?;
+
+        // (slot in `output`, attempt) of the reads not yet issued
+        self.collect_prefetched();
+        let mut pending = VecDeque::new();
+        for (slot, position) in positions.iter().enumerate() {
+            match self.prefetched.remove(*position) {
+                Some(mut block) => {
+                    output[slot * block_len..(slot + 1) * block_len].copy_from_slice(&block);
+                    wipe(&mut block);
+                }
+                None => pending.push_back((slot, 1)),
+            }
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        self.pool()?;
+        let (pool, location, window) = (
+            self.pool.as_ref().expect("pool was started"),
+            &self.location,
+            &mut self.window,
+        );
+        let max_attempts = self.options.max_attempts.max(1);
+        let (done, completions) = mpsc::channel();
+        let mut in_flight = 0;
+        let mut failure = None;
+        loop {
+            while failure.is_none() && in_flight < window.size {
+                let (slot, attempt) = match pending.pop_front() {
+                    Some(read) => read,
+                    None => break,
+                };
+                pool.submit(ReadJob {
+                    position: positions[slot],
+                    len: block_len,
+                    slot,
+                    attempt,
+                    done: done.clone(),
+                });
+                in_flight += 1;
+            }
+            if in_flight == 0 {
+                break;
+            }
+
+            let ReadDone {
+                slot,
+                attempt,
+                result,
+                ..
+            } = completions.recv().map_err(|_| {
+                BigKeyError::from(io::Error::other("reader threads exited"))
+                    .in_storage("read", location, None)
+            })?;
+            in_flight -= 1;
+            match result {
+                Ok(mut block) => {
+                    output[slot * block_len..(slot + 1) * block_len].copy_from_slice(&block);
+                    wipe(&mut block);
+                    window.completed();
+                }
+                Err(e) if e.is_retryable() && attempt < max_attempts => {
+                    window.congested();
+                    pending.push_front((slot, attempt + 1));
+                }
+                Err(e) => {
+                    failure.get_or_insert(e.in_storage("read", location, Some(positions[slot])));
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        let positions = indices
+            .iter()
+            .map(|&index| self.position(index))
+            .collect::<Result<Vec<u64>, BigKeyError>>()?;
+        let block_len = self.block_size.byte_len;
+        let done = self.prefetch_done.0.clone();
+        let pool = self.pool()?;
+        for position in positions {
+            pool.submit(ReadJob {
+                position,
+                len: block_len,
+                slot: 0,
+                attempt: 1,
+                done: done.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.big_key_length
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use digest::Digest;
+    use sha3::Sha3_256;
+
+    use crate::kem::{BigKey, BigKeyKem};
+    use crate::storage::remote::PrefetchedBlocks;
+    use crate::storage::{
+        ProbePipelineOptions, RangeClient, ReadSeekStorage, RemoteStorage, StorageReader,
+    };
+    use crate::traits::{BigKeyError, BlockIndex, SecurityLevel, BLOCK_1K};
+
+    // An object behind a link of fixed latency, throttling every `throttle_every`th read
+    struct SlowObject {
+        contents: Vec<u8>,
+        latency: Duration,
+        throttle_every: usize,
+        reads: AtomicUsize,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl SlowObject {
+        fn new(contents: Vec<u8>, throttle_every: usize) -> Self {
+            SlowObject {
+                contents,
+                latency: Duration::from_millis(20),
+                throttle_every,
+                reads: AtomicUsize::new(0),
+                in_flight: AtomicUsize::new(0),
+                max_in_flight: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl RangeClient for SlowObject {
+        fn object_length(&self) -> Result<u64, BigKeyError> {
+            Ok(self.contents.len() as u64)
+        }
+
+        fn read_range(&self, offset: u64, output: &mut [u8]) -> Result<(), BigKeyError> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            thread::sleep(self.latency);
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            let read = self.reads.fetch_add(1, Ordering::SeqCst) + 1;
+            if read.is_multiple_of(self.throttle_every) {
+                return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+            }
+            let offset = offset as usize;
+            output.copy_from_slice(&self.contents[offset..offset + output.len()]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pipelined_probes_match_local_probes() {
+        let contents: Vec<u8> = (0..256 * 1024).map(|i| (i % 253) as u8).collect();
+        let local = ReadSeekStorage::new(Cursor::new(contents.clone()), BLOCK_1K).unwrap();
+        let mut local_key =
+            BigKey::new_big_key(SecurityLevel::Bits256, 0.5, local, Sha3_256::new());
+        let (locator, key) = local_key.new_key(SecurityLevel::Bits256).unwrap();
+        let probes = local_key.estimated_probe_count().unwrap();
+        assert!(probes > 100);
+
+        let options = ProbePipelineOptions {
+            max_in_flight: 32,
+            ..ProbePipelineOptions::default()
+        };
+        let remote = RemoteStorage::open(SlowObject::new(contents, 50), BLOCK_1K, "mem://key")
+            .unwrap()
+            .with_pipeline(options);
+        let mut remote_key =
+            BigKey::new_big_key(SecurityLevel::Bits256, 0.5, remote, Sha3_256::new());
+
+        let started = Instant::now();
+        assert_eq!(remote_key.get_key(&locator).unwrap(), key);
+        // sequential probes would take probes * 20 ms
+        assert!(started.elapsed() < Duration::from_millis(20) * probes as u32 / 2);
+
+        let remote = remote_key.into_storage();
+        let max_in_flight = remote.client.max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight > 4 && max_in_flight <= 32);
+        assert!(remote.window() >= 1 && remote.window() <= 32);
+    }
+
+    #[test]
+    fn persistent_failures_fail_the_probe() {
+        // shorter than a header, so opening reads nothing
+        let contents = vec![0x5au8; 3 * 1024];
+        let mut remote =
+            RemoteStorage::open(SlowObject::new(contents, 1), BLOCK_1K, "mem://key").unwrap();
+        let mut blocks = vec![0u8; 2 * 1024];
+        let indices = [BlockIndex::new(0), BlockIndex::new(2)];
+        match remote.probe_many(&indices, &mut blocks) {
+            Err(BigKeyError::Storage { op, offset, .. }) => {
+                assert_eq!(op, "read");
+                assert!(offset.is_some());
+            }
+            other => panic!("expected the probe to fail, got {:?}", other),
+        }
+        assert_eq!(remote.window(), 1);
+        assert!(remote.probe_many(&indices, &mut blocks[..1024]).is_err());
+    }
+
+    #[test]
+    fn out_of_range_probes_fail_before_reading() {
+        let contents = vec![0x5au8; 3 * 1024];
+        let mut remote =
+            RemoteStorage::open(SlowObject::new(contents, 1000), BLOCK_1K, "mem://key").unwrap();
+        let mut blocks = vec![0u8; 2 * 1024];
+        let indices = [BlockIndex::new(1), BlockIndex::new(3)];
+        assert!(remote.probe_many(&indices, &mut blocks).is_err());
+        assert!(remote
+            .probe(BlockIndex::new(u64::MAX), &mut blocks[..1024])
+            .is_err());
+        assert_eq!(remote.client.reads.load(Ordering::SeqCst), 0);
+
+        remote
+            .probe(BlockIndex::new(2), &mut blocks[..1024])
+            .unwrap();
+        assert_eq!(remote.client.reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn prefetched_blocks_serve_probes() {
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 241) as u8).collect();
+        let mut remote = RemoteStorage::open(
+            SlowObject::new(contents.clone(), 1000),
+            BLOCK_1K,
+            "mem://key",
+        )
+        .unwrap();
+        let indices: Vec<BlockIndex> = [3, 7, 11, 13].iter().map(|i| BlockIndex::new(*i)).collect();
+        remote.prefetch(&indices).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while remote.prefetched() < indices.len() {
+            assert!(Instant::now() < deadline, "prefetches did not complete");
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let reads = remote.client.reads.load(Ordering::SeqCst);
+        let mut blocks = vec![0u8; 4 * 1024];
+        remote.probe_many(&indices, &mut blocks).unwrap();
+        assert_eq!(remote.client.reads.load(Ordering::SeqCst), reads);
+        assert_eq!(remote.prefetched(), 0);
+        for (index, block) in indices.iter().zip(blocks.chunks_exact(1024)) {
+            let offset = index.get() as usize * 1024;
+            assert_eq!(block, &contents[offset..offset + 1024]);
+        }
+
+        // taken blocks are read again
+        remote.probe(indices[0], &mut blocks[..1024]).unwrap();
+        assert_eq!(remote.client.reads.load(Ordering::SeqCst), reads + 1);
+        assert!(remote.prefetch(&[BlockIndex::new(64)]).is_err());
+    }
+
+    #[test]
+    fn prefetched_blocks_are_bounded() {
+        let mut prefetched = PrefetchedBlocks::default();
+        for position in 0..4u64 {
+            prefetched.insert(position * 1024, vec![position as u8; 8], 3);
+        }
+        assert_eq!(prefetched.len(), 3);
+        assert_eq!(prefetched.remove(0), None);
+        assert_eq!(prefetched.remove(1024), Some(vec![1u8; 8]));
+
+        // replacing a block keeps its place
+        prefetched.insert(2048, vec![9u8; 8], 3);
+        prefetched.insert(4096, vec![4u8; 8], 2);
+        assert_eq!(prefetched.remove(2048), None);
+        assert_eq!(prefetched.remove(3072), Some(vec![3u8; 8]));
+        assert_eq!(prefetched.len(), 1);
+    }
+} // mod test
@@ -0,0 +1,147 @@
+//! Repair a replica of a BigKey by copying only the blocks that differ from the source.
+//!
+//! Each side summarizes its key as a list of per-block checksums (see `storage::checksum`),
+//! which are small enough to exchange between data centers. Only blocks whose checksums differ
+//! are then shipped and rewritten in place, so a partially corrupted replica of a multi-terabyte
+//! key can be repaired without re-copying all of it.
+
+use crate::storage::checksum::{block_checksum, CHECKSUM_LEN};
+use crate::storage::{DiskStorage, StorageReader};
+use crate::traits::{BigKeyError, BlockIndex};
+
+/// Checksum of a single block, as exchanged between source and replica
+pub type BlockHash = [u8; CHECKSUM_LEN];
+
+/// Outcome of `replicate()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replication {
+    /// Number of blocks compared
+    pub compared: u64,
+    /// Indices of the blocks copied from the source
+    pub copied: Vec<u64>,
+}
+
+/// Checksum of every block of `reader`, in block order
+pub fn block_hashes<R: StorageReader + ?Sized>(
+    reader: &mut R,
+) -> Result<Vec<BlockHash>, BigKeyError> {
+    let block_len = reader.block_size().byte_len;
+    let block_count = reader.big_key_length() / block_len as u64;
+    let mut block = vec![0u8; block_len];
+    let mut hashes = Vec::with_capacity(block_count as usize);
+
+    for index in 0..block_count {
+        reader.probe(BlockIndex::new(index), &mut block)?;
+        hashes.push(block_checksum(index, &block));
+    }
+
+    Ok(hashes)
+}
+
+/// Indices of the blocks whose hashes differ between `source` and `replica`
+pub fn differing_blocks(
+    source: &[BlockHash],
+    replica: &[BlockHash],
+) -> Result<Vec<u64>, BigKeyError> {
+    if source.len() != replica.len() {
+        return Err(BigKeyError::ReplicaLengthMismatch {
+            source_blocks: source.len() as u64,
+            replica_blocks: replica.len() as u64,
+        });
+    }
+
+    Ok(source
+        .iter()
+        .zip(replica.iter())
+        .enumerate()
+        .filter(|(_, (s, r))| s != r)
+        .map(|(index, _)| index as u64)
+        .collect())
+}
+
+/// Bring the key file at `replica_location` in line with `source`, rewriting only the blocks
+/// that differ. The replica must already exist with the same length and block size; its header
+/// (if any) is left untouched. Blocks are rewritten with `DiskStorage::repair_block()`, so the
+/// replica can keep serving probes meanwhile.
+pub fn replicate<R: StorageReader + ?Sized>(
+    source: &mut R,
+    replica_location: &str,
+) -> Result<Replication, BigKeyError> {
+    let block_size = source.block_size();
+    let mut replica = DiskStorage::open(block_size, replica_location)?;
+
+    let copied = differing_blocks(&block_hashes(source)?, &block_hashes(&mut replica)?)?;
+    let mut block = vec![0u8; block_size.byte_len];
+
+    for index in copied.iter() {
+        let index = BlockIndex::new(*index);
+        source.probe(index, &mut block)?;
+        replica.repair_block(index, &block)?;
+    }
+
+    Ok(Replication {
+        compared: source.big_key_length() / block_size.byte_len as u64,
+        copied,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::header::HEADER_LEN;
+    use crate::storage::replicate::{differing_blocks, replicate};
+    use crate::storage::tempfile::{tempfile, TempFile};
+    use crate::storage::{fingerprint, DiskStorage, StorageWriter};
+    use crate::traits::{BigKeyError, BLOCK_1K};
+
+    fn key_file() -> TempFile {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut writer = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 16 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 16 * 1024).unwrap();
+        tmp
+    }
+
+    #[test]
+    fn only_corrupted_blocks_are_copied() {
+        let source = key_file();
+        let replica = key_file();
+
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(replica.as_path())
+                .unwrap();
+            for offset in [3 * 1024 + 17, 11 * 1024].iter() {
+                file.seek(SeekFrom::Start(HEADER_LEN as u64 + offset))
+                    .unwrap();
+                file.write_all(&[0u8; 4]).unwrap();
+            }
+        }
+
+        let mut source_storage = DiskStorage::open(BLOCK_1K, source.to_str()).unwrap();
+        let replication = replicate(&mut source_storage, replica.to_str()).unwrap();
+        assert_eq!(replication.compared, 16);
+        assert_eq!(replication.copied, vec![3, 11]);
+
+        let mut replica_storage = DiskStorage::open(BLOCK_1K, replica.to_str()).unwrap();
+        assert_eq!(
+            fingerprint(&mut replica_storage).unwrap(),
+            fingerprint(&mut source_storage).unwrap()
+        );
+
+        let replication = replicate(&mut source_storage, replica.to_str()).unwrap();
+        assert!(replication.copied.is_empty());
+    }
+
+    #[test]
+    fn replicas_of_different_length_fail() {
+        match differing_blocks(&[[0u8; 8]; 4], &[[0u8; 8]; 3]) {
+            Err(BigKeyError::ReplicaLengthMismatch { .. }) => {}
+            _ => panic!("expected length mismatch to be rejected"),
+        }
+    }
+} // mod test
@@ -0,0 +1,170 @@
+//! Retry transient probe failures of flaky storage (network filesystems, USB disks).
+
+use std::thread;
+use std::time::Duration;
+
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// How often and how patiently `RetryingStorage` retries a failed probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, `0` disables retrying
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between retries, which doubles after each retry
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A `StorageReader` that retries probes failing with a retryable error (see
+/// `BigKeyError::is_retryable()`) with exponential backoff. Permanent errors, and the last
+/// retryable error once retries are exhausted, are returned unchanged.
+pub struct RetryingStorage<R: StorageReader> {
+    inner: R,
+    policy: RetryPolicy,
+    retries: u64,
+}
+
+impl<R: StorageReader> RetryingStorage<R> {
+    pub fn new(inner: R, policy: RetryPolicy) -> Self {
+        RetryingStorage {
+            inner,
+            policy,
+            retries: 0,
+        }
+    }
+
+    /// Total number of retries made so far, useful for spotting degrading storage
+    pub fn retries(&self) -> u64 {
+        self.retries
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: StorageReader> StorageReader for RetryingStorage<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        let mut backoff = self.policy.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            match self.inner.probe(index, output) {
+                Err(e) if e.is_retryable() && attempt < self.policy.max_retries => {
+                    attempt += 1;
+                    self.retries += 1;
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.policy.max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        self.inner.prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use crate::storage::{RetryPolicy, RetryingStorage, StorageReader};
+    use crate::traits::{BigKeyError, BlockIndex, BlockSize, BLOCK_1K};
+
+    // Fails the first `failures` probes with an error of `kind`
+    struct FlakyStorage {
+        failures: u32,
+        kind: io::ErrorKind,
+    }
+
+    impl StorageReader for FlakyStorage {
+        fn probe(&mut self, _index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from(self.kind).into());
+            }
+            output.iter_mut().for_each(|b| *b = 0x42);
+            Ok(())
+        }
+
+        fn big_key_length(&self) -> u64 {
+            1024
+        }
+
+        fn block_size(&self) -> BlockSize {
+            BLOCK_1K
+        }
+    }
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let flaky = FlakyStorage {
+            failures: 2,
+            kind: io::ErrorKind::TimedOut,
+        };
+        let mut storage = RetryingStorage::new(flaky, policy(3));
+        let mut block = vec![0u8; 1024];
+
+        storage.probe(BlockIndex::new(0), &mut block).unwrap();
+        assert_eq!(storage.retries(), 2);
+        assert!(block.iter().all(|b| *b == 0x42));
+    }
+
+    #[test]
+    fn exhausted_and_permanent_errors_fail() {
+        let mut block = vec![0u8; 1024];
+
+        let flaky = FlakyStorage {
+            failures: 5,
+            kind: io::ErrorKind::Interrupted,
+        };
+        let mut storage = RetryingStorage::new(flaky, policy(2));
+        assert!(storage
+            .probe(BlockIndex::new(0), &mut block)
+            .unwrap_err()
+            .is_retryable());
+        assert_eq!(storage.retries(), 2);
+
+        let broken = FlakyStorage {
+            failures: 1,
+            kind: io::ErrorKind::NotFound,
+        };
+        let mut storage = RetryingStorage::new(broken, policy(2));
+        assert!(!storage
+            .probe(BlockIndex::new(0), &mut block)
+            .unwrap_err()
+            .is_retryable());
+        assert_eq!(storage.retries(), 0);
+    }
+} // mod test
@@ -0,0 +1,443 @@
+//! Generating BigKeys straight into S3 compatible object storage.
+//!
+//! `S3MultipartWriter` uploads the key as a multipart upload while it is generated, so no local
+//! staging file is needed. The resulting object is an ordinary key file, `KeyHeader` included.
+//! The header is only known once the key is complete, so the first part (header plus the start
+//! of the key) is held in memory and uploaded last; S3 assembles parts by number, not upload
+//! order. The writer holds at most two parts in memory.
+//!
+//! The crate carries no HTTP or AWS stack: the upload calls go through a `MultipartClient`,
+//! implemented over whichever S3 client the application already uses. An upload that is not
+//! completed (an error, or the writer dropped before `finalize()`) is aborted, so no partial
+//! key is left behind.
+//!
+//! Keys in object storage are read back with `RemoteStorage` over ranged `GetObject` requests.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::memory::wipe;
+use crate::storage::header::{KeyHeader, HEADER_LEN};
+use crate::storage::util::check_key_evenly_divisible;
+use crate::storage::StorageWriter;
+use crate::traits::{BigKeyError, BlockSize, GeneratorId};
+
+/// Smallest part S3 accepts, other than the last part of an upload
+pub const S3_MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Most parts an S3 multipart upload can have
+pub const S3_MAX_PARTS: u64 = 10_000;
+
+/// Part size of `S3Options::new()`
+pub const DEFAULT_PART_SIZE: usize = 16 * 1024 * 1024;
+
+/// An upload in progress
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartUpload {
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+}
+
+/// An uploaded part, as listed when completing the upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletedPart {
+    pub part_number: u32,
+    pub e_tag: String,
+}
+
+/// The S3 multipart upload API (`CreateMultipartUpload`, `UploadPart`,
+/// `CompleteMultipartUpload`, `AbortMultipartUpload`), implemented over an S3 client
+pub trait MultipartClient {
+    /// Start an upload to `key` in `bucket`, returning its upload id
+    fn create_multipart_upload(&self, bucket: &str, key: &str) -> Result<String, BigKeyError>;
+
+    /// Upload part `part_number` (counting from 1), returning its ETag
+    fn upload_part(
+        &self,
+        upload: &MultipartUpload,
+        part_number: u32,
+        body: &[u8],
+    ) -> Result<String, BigKeyError>;
+
+    /// Assemble `parts`, ordered by part number, into the object
+    fn complete_multipart_upload(
+        &self,
+        upload: &MultipartUpload,
+        parts: &[CompletedPart],
+    ) -> Result<(), BigKeyError>;
+
+    fn abort_multipart_upload(&self, upload: &MultipartUpload) -> Result<(), BigKeyError>;
+}
+
+/// Destination of an `S3MultipartWriter`
+#[derive(Clone)]
+pub struct S3Options {
+    pub client: Arc<dyn MultipartClient + Send + Sync>,
+    pub bucket: String,
+    pub key: String,
+    /// Bytes per part, at least `S3_MIN_PART_SIZE`; also bounds the key size to
+    /// `S3_MAX_PARTS` parts
+    pub part_size: usize,
+}
+
+impl S3Options {
+    pub fn new(client: Arc<dyn MultipartClient + Send + Sync>, bucket: &str, key: &str) -> Self {
+        S3Options {
+            client,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            part_size: DEFAULT_PART_SIZE,
+        }
+    }
+
+    fn location(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+impl fmt::Debug for S3Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Options")
+            .field("bucket", &self.bucket)
+            .field("key", &self.key)
+            .field("part_size", &self.part_size)
+            .finish()
+    }
+}
+
+/// A `StorageWriter` uploading the key to S3 as it is written
+pub struct S3MultipartWriter {
+    client: Arc<dyn MultipartClient + Send + Sync>,
+    upload: MultipartUpload,
+    location: String,
+    part_size: usize,
+    block_size: BlockSize,
+    expected_length: u64,
+    generator: GeneratorId,
+    // Part 1: the header placeholder and the start of the key, uploaded by `finalize()`
+    first_part: Vec<u8>,
+    // The part being filled, once the first part is full
+    part: Vec<u8>,
+    parts: Vec<CompletedPart>,
+    written: u64,
+    hasher: blake3::Hasher,
+    fingerprint: Option<[u8; 32]>,
+    completed: bool,
+}
+
+impl StorageWriter for S3MultipartWriter {
+    type Options = S3Options;
+
+    fn new_writer(
+        block_size: BlockSize,
+        options: &S3Options,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        if expected_size < block_size.byte_len {
+            return Err(BigKeyError::OutputLengthTooShort {
+                out_len: expected_size,
+                min_len: block_size.byte_len,
+            });
+        }
+        check_key_evenly_divisible(block_size, expected_size as u64)?;
+        if options.part_size < S3_MIN_PART_SIZE {
+            return Err(BigKeyError::InvalidConfig {
+                reason: format!("S3 parts must be at least {} bytes", S3_MIN_PART_SIZE),
+            });
+        }
+        let object_len = (HEADER_LEN + expected_size) as u64;
+        if object_len.div_ceil(options.part_size as u64) > S3_MAX_PARTS {
+            return Err(BigKeyError::InvalidConfig {
+                reason: format!(
+                    "a {} byte key needs more than {} parts of {} bytes",
+                    expected_size, S3_MAX_PARTS, options.part_size
+                ),
+            });
+        }
+
+        let upload_id = options
+            .client
+            .create_multipart_upload(&options.bucket, &options.key)?;
+        let mut first_part = Vec::with_capacity(options.part_size);
+        first_part.extend_from_slice(&[0u8; HEADER_LEN]);
+
+        Ok(S3MultipartWriter {
+            client: Arc::clone(&options.client),
+            upload: MultipartUpload {
+                bucket: options.bucket.clone(),
+                key: options.key.clone(),
+                upload_id,
+            },
+            location: options.location(),
+            part_size: options.part_size,
+            block_size,
+            expected_length: expected_size as u64,
+            generator: GeneratorId::Unknown,
+            first_part,
+            part: Vec::new(),
+            parts: Vec::new(),
+            written: 0,
+            hasher: blake3::Hasher::new(),
+            fingerprint: None,
+            completed: false,
+        })
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.expected_length
+    }
+
+    fn set_generator(&mut self, generator: GeneratorId) {
+        self.generator = generator;
+    }
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        if self.written != self.expected_length {
+            return Err(BigKeyError::FailedToWriteBigKey {
+                expected_len: self.expected_length as usize,
+                wrote_len: self.written as usize,
+            });
+        }
+        if !self.part.is_empty() {
+            self.upload_part()?;
+        }
+
+        let fingerprint = *self.hasher.finalize().as_bytes();
+        let mut header = KeyHeader::new(self.generator, self.block_size, self.expected_length);
+        header.fingerprint = Some(fingerprint);
+        self.first_part[..HEADER_LEN].copy_from_slice(&header.to_bytes());
+        let e_tag = self.client.upload_part(&self.upload, 1, &self.first_part)?;
+        wipe(&mut self.first_part);
+        self.parts.insert(
+            0,
+            CompletedPart {
+                part_number: 1,
+                e_tag,
+            },
+        );
+
+        self.client
+            .complete_multipart_upload(&self.upload, &self.parts)?;
+        self.completed = true;
+        self.fingerprint = Some(fingerprint);
+        log::debug!(
+            "uploaded key {} in {} parts",
+            self.location,
+            self.parts.len()
+        );
+        Ok(())
+    }
+
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.fingerprint
+    }
+}
+
+impl S3MultipartWriter {
+    /// `s3://bucket/key` of the object being written
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    // Upload the part being filled, numbered after part 1 and those already uploaded
+    fn upload_part(&mut self) -> Result<(), BigKeyError> {
+        let part_number = self.parts.len() as u32 + 2;
+        let e_tag = self
+            .client
+            .upload_part(&self.upload, part_number, &self.part)?;
+        wipe(&mut self.part);
+        self.part.clear();
+        self.parts.push(CompletedPart { part_number, e_tag });
+        Ok(())
+    }
+}
+
+impl Write for S3MultipartWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.written + buf.len() as u64 > self.expected_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the key",
+            ));
+        }
+
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let target = if self.first_part.len() < self.part_size {
+                &mut self.first_part
+            } else {
+                if self.part.capacity() == 0 {
+                    self.part.reserve_exact(self.part_size);
+                }
+                &mut self.part
+            };
+            let len = rest.len().min(self.part_size - target.len());
+            target.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+
+            if self.part.len() == self.part_size {
+                self.upload_part().map_err(io::Error::other)?;
+            }
+        }
+
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    // Parts are uploaded as they fill, there is nothing smaller to flush
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl Drop for S3MultipartWriter {
+    fn drop(&mut self) {
+        wipe(&mut self.first_part);
+        wipe(&mut self.part);
+        if !self.completed {
+            if let Err(e) = self.client.abort_multipart_upload(&self.upload) {
+                log::warn!("failed to abort upload of {}: {}", self.location, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::header::{KeyHeader, HEADER_LEN};
+    use crate::storage::{
+        CompletedPart, MultipartClient, MultipartUpload, S3MultipartWriter, S3Options,
+        StorageWriter, S3_MIN_PART_SIZE,
+    };
+    use crate::traits::{BigKeyError, GeneratorId, BLOCK_1K};
+
+    // An in-memory bucket enforcing the part size rules of S3
+    #[derive(Default)]
+    struct MemoryBucket {
+        uploads: Mutex<HashMap<String, BTreeMap<u32, Vec<u8>>>>,
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        aborted: Mutex<u32>,
+    }
+
+    impl MultipartClient for MemoryBucket {
+        fn create_multipart_upload(&self, _bucket: &str, key: &str) -> Result<String, BigKeyError> {
+            let upload_id = format!("upload-{}", key);
+            self.uploads
+                .lock()
+                .unwrap()
+                .insert(upload_id.clone(), BTreeMap::new());
+            Ok(upload_id)
+        }
+
+        fn upload_part(
+            &self,
+            upload: &MultipartUpload,
+            part_number: u32,
+            body: &[u8],
+        ) -> Result<String, BigKeyError> {
+            let mut uploads = self.uploads.lock().unwrap();
+            let parts = uploads.get_mut(&upload.upload_id).unwrap();
+            parts.insert(part_number, body.to_vec());
+            Ok(format!("etag-{}", part_number))
+        }
+
+        fn complete_multipart_upload(
+            &self,
+            upload: &MultipartUpload,
+            parts: &[CompletedPart],
+        ) -> Result<(), BigKeyError> {
+            let uploaded = self
+                .uploads
+                .lock()
+                .unwrap()
+                .remove(&upload.upload_id)
+                .unwrap();
+            let numbers: Vec<u32> = parts.iter().map(|p| p.part_number).collect();
+            assert_eq!(numbers, uploaded.keys().copied().collect::<Vec<_>>());
+            let mut object = Vec::new();
+            for (i, body) in uploaded.values().enumerate() {
+                assert!(i == uploaded.len() - 1 || body.len() >= S3_MIN_PART_SIZE);
+                object.extend_from_slice(body);
+            }
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(upload.key.clone(), object);
+            Ok(())
+        }
+
+        fn abort_multipart_upload(&self, upload: &MultipartUpload) -> Result<(), BigKeyError> {
+            self.uploads.lock().unwrap().remove(&upload.upload_id);
+            *self.aborted.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keys_upload_as_key_files() {
+        let bucket = Arc::new(MemoryBucket::default());
+        let mut options = S3Options::new(bucket.clone(), "keys", "big.key");
+        options.part_size = S3_MIN_PART_SIZE;
+        let length = 2 * S3_MIN_PART_SIZE + 5 * 1024;
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+
+        let mut writer = S3MultipartWriter::new_writer(BLOCK_1K, &options, length).unwrap();
+        assert_eq!(writer.location(), "s3://keys/big.key");
+        Shake256Generator::generate(&mut writer, Some(seed.clone().into()), length).unwrap();
+        let fingerprint = writer.fingerprint().unwrap();
+        drop(writer);
+        assert_eq!(*bucket.aborted.lock().unwrap(), 0);
+
+        let mut expected = Vec::new();
+        Shake256Generator::new(Some(seed.into()))
+            .unwrap()
+            .fill(&mut expected, length)
+            .unwrap();
+        let object = bucket.objects.lock().unwrap().remove("big.key").unwrap();
+        let header = KeyHeader::from_bytes(&object).unwrap().unwrap();
+        assert_eq!(header.generator, GeneratorId::Shake256);
+        assert_eq!(header.key_length, length as u64);
+        assert_eq!(header.fingerprint, Some(fingerprint));
+        assert_eq!(&object[HEADER_LEN..], &expected[..]);
+        assert_eq!(fingerprint, *blake3::hash(&expected).as_bytes());
+    }
+
+    #[test]
+    fn unfinished_uploads_are_aborted() {
+        let bucket = Arc::new(MemoryBucket::default());
+        let options = S3Options::new(bucket.clone(), "keys", "short.key");
+
+        let mut writer = S3MultipartWriter::new_writer(BLOCK_1K, &options, 4096).unwrap();
+        writer.write_all(&[7u8; 2048]).unwrap();
+        assert!(writer.write_all(&[7u8; 4096]).is_err());
+        assert!(matches!(
+            writer.finalize(),
+            Err(BigKeyError::FailedToWriteBigKey { .. })
+        ));
+        drop(writer);
+        assert_eq!(*bucket.aborted.lock().unwrap(), 1);
+        assert!(bucket.uploads.lock().unwrap().is_empty());
+        assert!(bucket.objects.lock().unwrap().is_empty());
+
+        let mut small_parts = options.clone();
+        small_parts.part_size = 1024 * 1024;
+        assert!(S3MultipartWriter::new_writer(BLOCK_1K, &small_parts, 4096).is_err());
+        let mut too_many_parts = options;
+        too_many_parts.part_size = S3_MIN_PART_SIZE;
+        let huge = S3_MIN_PART_SIZE * 10_000;
+        assert!(S3MultipartWriter::new_writer(BLOCK_1K, &too_many_parts, huge).is_err());
+    }
+} // mod test
@@ -0,0 +1,379 @@
+//! BigKeys stored inside a SQLite database.
+//!
+//! Some deployments already keep their secrets in a (possibly SQLCipher encrypted) SQLite file
+//! and want the BigKey in there too. `SqliteStorage` stores one block per row of the
+//! `bigkey_blocks` table, keyed by block index as the table's `INTEGER PRIMARY KEY`, so a probe
+//! is a single rowid B-tree lookup. The `KeyHeader` is kept as a blob in `bigkey_meta`.
+//!
+//! Readers are tuned for random point reads: a large page cache and memory mapped IO, and the
+//! probe statement is prepared once. For encrypted databases, open and key the connection
+//! yourself and pass it to `SqliteStorage::from_connection()` or `writer_from_connection()`.
+//!
+//! Rows whose block does not fit a database page spill into overflow pages; for 4 KiB blocks
+//! create the database with `PRAGMA page_size = 8192` or larger.
+
+use std::io;
+use std::io::Write;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::memory::wipe;
+use crate::storage::header::KeyHeader;
+use crate::storage::util::{block_offset, check_key_evenly_divisible};
+use crate::storage::{StorageReader, StorageWriter};
+use crate::traits::{BigKeyError, BlockIndex, BlockSize, GeneratorId};
+
+/// Blocks inserted per transaction while writing
+const COMMIT_BLOCKS: u64 = 4096;
+
+const READ_PRAGMAS: &str = "
+    PRAGMA query_only = ON;
+    PRAGMA cache_size = -65536;
+    PRAGMA mmap_size = 1073741824;
+";
+
+const CREATE_TABLES: &str = "
+    DROP TABLE IF EXISTS bigkey_blocks;
+    DROP TABLE IF EXISTS bigkey_meta;
+    CREATE TABLE bigkey_blocks (idx INTEGER PRIMARY KEY, data BLOB NOT NULL);
+    CREATE TABLE bigkey_meta (name TEXT PRIMARY KEY, value BLOB NOT NULL);
+";
+
+const SELECT_BLOCK: &str = "SELECT data FROM bigkey_blocks WHERE idx = ?1";
+const INSERT_BLOCK: &str = "INSERT INTO bigkey_blocks (idx, data) VALUES (?1, ?2)";
+const SELECT_HEADER: &str = "SELECT value FROM bigkey_meta WHERE name = 'header'";
+const INSERT_HEADER: &str = "INSERT INTO bigkey_meta (name, value) VALUES ('header', ?1)";
+
+/// Stores BigKey material as rows of a SQLite database
+pub struct SqliteStorage {
+    conn: Connection,
+    location: String,
+    block_size: BlockSize,
+    big_key_length: u64,
+    header: Option<KeyHeader>,
+    generator: GeneratorId,
+    fingerprint: blake3::Hasher,
+    pending: Vec<u8>,
+    written: u64,
+}
+
+impl SqliteStorage {
+    /// Open the database at `storage_location` for probing
+    pub fn open(
+        block_size: BlockSize,
+        storage_location: &str,
+    ) -> Result<SqliteStorage, BigKeyError> {
+        let conn = Connection::open_with_flags(
+            storage_location,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .context("open", storage_location)?;
+        SqliteStorage::from_connection(conn, block_size, storage_location)
+    }
+
+    /// Probe the key held by an already open (and, for encrypted databases, keyed) connection.
+    /// `location` only names the database in errors.
+    pub fn from_connection(
+        conn: Connection,
+        block_size: BlockSize,
+        location: &str,
+    ) -> Result<SqliteStorage, BigKeyError> {
+        conn.execute_batch(READ_PRAGMAS)
+            .context("configure", location)?;
+
+        let header_bytes: Option<Vec<u8>> = conn
+            .query_row(SELECT_HEADER, [], |row| row.get(0))
+            .optional()
+            .or_else(|e| match e {
+                // no key was ever written to this database
+                rusqlite::Error::SqliteFailure(_, Some(ref msg))
+                    if msg.contains("no such table") =>
+                {
+                    Ok(None)
+                }
+                e => Err(e),
+            })
+            .context("read header of", location)?;
+        let header = match header_bytes {
+            Some(bytes) => KeyHeader::from_bytes(&bytes)?,
+            None => None,
+        }
+        .ok_or(BigKeyError::InvalidHeader {
+            reason: "database holds no finalized BigKey",
+        })?;
+
+        if header.block_len != block_size.byte_len {
+            return Err(BigKeyError::BlockSizeMismatch {
+                requested_len: block_size.byte_len,
+                header_len: header.block_len,
+            });
+        }
+        check_key_evenly_divisible(block_size, header.key_length)?;
+
+        let mut storage = SqliteStorage::new(conn, location, block_size, header.key_length);
+        storage.header = Some(header);
+        Ok(storage)
+    }
+
+    /// Write a new key of `expected_size` bytes into an already open (and, for encrypted
+    /// databases, keyed) connection, replacing any key it holds. `location` only names the
+    /// database in errors.
+    pub fn writer_from_connection(
+        conn: Connection,
+        block_size: BlockSize,
+        expected_size: usize,
+        location: &str,
+    ) -> Result<SqliteStorage, BigKeyError> {
+        if expected_size < block_size.byte_len {
+            return Err(BigKeyError::OutputLengthTooShort {
+                out_len: expected_size,
+                min_len: block_size.byte_len,
+            });
+        }
+        check_key_evenly_divisible(block_size, expected_size as u64)?;
+
+        // the old key stays intact until the first batch of blocks is committed
+        conn.execute_batch("BEGIN")
+            .and_then(|_| conn.execute_batch(CREATE_TABLES))
+            .context("create tables in", location)?;
+
+        Ok(SqliteStorage::new(
+            conn,
+            location,
+            block_size,
+            expected_size as u64,
+        ))
+    }
+
+    /// The `KeyHeader` of the key, once opened or finalized
+    pub fn header(&self) -> Option<&KeyHeader> {
+        self.header.as_ref()
+    }
+
+    fn new(conn: Connection, location: &str, block_size: BlockSize, length: u64) -> Self {
+        SqliteStorage {
+            conn,
+            location: location.to_string(),
+            block_size,
+            big_key_length: length,
+            header: None,
+            generator: GeneratorId::Unknown,
+            fingerprint: blake3::Hasher::new(),
+            pending: Vec::with_capacity(block_size.byte_len),
+            written: 0,
+        }
+    }
+
+    // Insert the completed block in `pending`, committing every `COMMIT_BLOCKS` blocks
+    fn insert_pending(&mut self) -> Result<(), rusqlite::Error> {
+        let index = self.written / self.block_size.byte_len as u64 - 1;
+        self.conn
+            .prepare_cached(INSERT_BLOCK)?
+            .execute(params![index as i64, &self.pending])?;
+        self.pending.clear();
+
+        if (index + 1).is_multiple_of(COMMIT_BLOCKS) {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+        }
+        Ok(())
+    }
+}
+
+// SQLite reads synchronously, so prefetch hints are ignored; its page cache warms as blocks
+// are probed
+impl StorageReader for SqliteStorage {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        if output.len() != self.block_size.byte_len {
+            return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+                out_buf_len: output.len(),
+                block_len: self.block_size.byte_len,
+            });
+        }
+        block_offset(index, self.block_size, self.big_key_length)?;
+
+        let found = self
+            .conn
+            .prepare_cached(SELECT_BLOCK)
+            .and_then(|mut statement| {
+                statement
+                    .query_row([index.get() as i64], |row| {
+                        let block = row.get_ref(0)?.as_blob()?;
+                        match block.len() == output.len() {
+                            true => output.copy_from_slice(block),
+                            false => wipe(output),
+                        }
+                        Ok(block.len() == output.len())
+                    })
+                    .optional()
+            })
+            .context("probe", &self.location)?;
+
+        match found {
+            Some(true) => Ok(()),
+            _ => Err(BigKeyError::BlockCorrupted { index: index.get() }),
+        }
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.big_key_length
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+}
+
+impl StorageWriter for SqliteStorage {
+    type Options = str;
+
+    fn new_writer(
+        block_size: BlockSize,
+        storage_location: &str,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        let conn = Connection::open(storage_location).context("open", storage_location)?;
+        SqliteStorage::writer_from_connection(conn, block_size, expected_size, storage_location)
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.big_key_length
+    }
+
+    fn set_generator(&mut self, generator: GeneratorId) {
+        self.generator = generator;
+    }
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        if self.written != self.big_key_length || !self.pending.is_empty() {
+            return Err(BigKeyError::FailedToWriteBigKey {
+                expected_len: self.big_key_length as usize,
+                wrote_len: self.written as usize,
+            });
+        }
+
+        let mut header = KeyHeader::new(self.generator, self.block_size, self.big_key_length);
+        header.fingerprint = Some(*self.fingerprint.finalize().as_bytes());
+        self.conn
+            .execute(INSERT_HEADER, [&header.to_bytes()[..]])
+            .and_then(|_| self.conn.execute_batch("COMMIT"))
+            .context("write header of", &self.location)?;
+        self.header = Some(header);
+
+        Ok(())
+    }
+
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.header.as_ref().and_then(|header| header.fingerprint)
+    }
+}
+
+impl Write for SqliteStorage {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.written + buf.len() as u64 > self.big_key_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the key",
+            ));
+        }
+
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let take = (self.block_size.byte_len - self.pending.len()).min(rest.len());
+            self.pending.extend_from_slice(&rest[..take]);
+            self.fingerprint.update(&rest[..take]);
+            self.written += take as u64;
+            rest = &rest[take..];
+
+            if self.pending.len() == self.block_size.byte_len {
+                self.insert_pending().map_err(io::Error::other)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+// Attach the operation and database to SQLite errors
+trait SqliteContext<T> {
+    fn context(self, op: &'static str, path: &str) -> Result<T, BigKeyError>;
+}
+
+impl<T> SqliteContext<T> for Result<T, rusqlite::Error> {
+    fn context(self, op: &'static str, path: &str) -> Result<T, BigKeyError> {
+        self.map_err(|source| BigKeyError::Sqlite {
+            op,
+            path: path.to_string(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::conformance::check_storage_reader;
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::sqlite::SqliteStorage;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{StorageReader, StorageWriter};
+    use crate::traits::{BigKeyError, BlockIndex, BLOCK_1K, BLOCK_4K};
+
+    #[test]
+    fn blocks_round_trip_through_rows() {
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut expected = Vec::new();
+        Shake256Generator::new(Some(seed.clone().into()))
+            .unwrap()
+            .fill(&mut expected, 64 * 1024)
+            .unwrap();
+
+        let tmp = tempfile();
+        let mut writer = SqliteStorage::new_writer(BLOCK_1K, tmp.to_str(), 64 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 64 * 1024).unwrap();
+        let fingerprint = writer.fingerprint().unwrap();
+        assert_eq!(fingerprint, *blake3::hash(&expected).as_bytes());
+        drop(writer);
+
+        let mut storage = SqliteStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        assert_eq!(storage.big_key_length(), 64 * 1024);
+        assert_eq!(storage.header().unwrap().fingerprint, Some(fingerprint));
+        let mut block = [0u8; 1024];
+        for index in [0u64, 17, 63].iter() {
+            storage.probe(BlockIndex::new(*index), &mut block).unwrap();
+            let start = *index as usize * 1024;
+            assert_eq!(&block[..], &expected[start..start + 1024]);
+        }
+        for index in [64, u64::MAX / 1024 + 1, u64::MAX].iter() {
+            match storage.probe(BlockIndex::new(*index), &mut block) {
+                Err(BigKeyError::ProbeOffsetOutOfBounds { .. }) => {}
+                _ => panic!("expected probe past the end to fail"),
+            }
+        }
+        check_storage_reader(&mut storage, &expected).unwrap();
+
+        match SqliteStorage::open(BLOCK_4K, tmp.to_str()) {
+            Err(BigKeyError::BlockSizeMismatch { .. }) => {}
+            _ => panic!("expected block size mismatch"),
+        }
+    }
+
+    #[test]
+    fn unfinished_keys_cannot_be_opened() {
+        let tmp = tempfile();
+        let mut writer = SqliteStorage::new_writer(BLOCK_1K, tmp.to_str(), 8 * 1024).unwrap();
+        std::io::Write::write_all(&mut writer, &[1u8; 4096]).unwrap();
+        assert!(writer.finalize().is_err());
+        drop(writer);
+
+        match SqliteStorage::open(BLOCK_1K, tmp.to_str()) {
+            Err(BigKeyError::InvalidHeader { .. }) => {}
+            _ => panic!("expected an unfinished key to be rejected"),
+        }
+    }
+} // mod test
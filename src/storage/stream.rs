@@ -0,0 +1,212 @@
+//! Writing BigKeys to streams that cannot seek, such as stdout or a named pipe.
+//!
+//! `StreamWriter` lets generated key material be piped straight into another tool (`dd`, a
+//! hardware provisioning utility, a network transfer) without a temporary file. A stream can
+//! only be written front to back, so finalization is relaxed compared to `DiskStorage`: no
+//! `KeyHeader` is written and the generator is not recorded, the output is the raw key. The
+//! key length is still enforced, and the fingerprint of the stream is available once finalized.
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+use crate::storage::util::{check_key_evenly_divisible, StorageContext};
+use crate::storage::StorageWriter;
+use crate::traits::{BigKeyError, BlockSize, GeneratorId};
+
+/// Location that `StreamWriter::new_writer()` interprets as stdout
+pub const STDOUT_LOCATION: &str = "-";
+
+/// A `StorageWriter` emitting the raw key to any `Write`
+pub struct StreamWriter {
+    inner: Box<dyn Write>,
+    block_size: BlockSize,
+    expected_length: u64,
+    written: u64,
+    hasher: blake3::Hasher,
+    fingerprint: Option<[u8; 32]>,
+}
+
+impl StreamWriter {
+    /// Write a key of `expected_size` bytes to `inner`
+    pub fn new(
+        inner: impl Write + 'static,
+        block_size: BlockSize,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        if expected_size < block_size.byte_len {
+            return Err(BigKeyError::OutputLengthTooShort {
+                out_len: expected_size,
+                min_len: block_size.byte_len,
+            });
+        }
+        check_key_evenly_divisible(block_size, expected_size as u64)?;
+
+        Ok(StreamWriter {
+            inner: Box::new(inner),
+            block_size,
+            expected_length: expected_size as u64,
+            written: 0,
+            hasher: blake3::Hasher::new(),
+            fingerprint: None,
+        })
+    }
+}
+
+impl StorageWriter for StreamWriter {
+    type Options = str;
+
+    /// Stream to stdout if `storage_location` is `STDOUT_LOCATION`, otherwise to the file or
+    /// named pipe at `storage_location`
+    fn new_writer(
+        block_size: BlockSize,
+        storage_location: &str,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        match storage_location {
+            STDOUT_LOCATION => StreamWriter::new(io::stdout(), block_size, expected_size),
+            path => StreamWriter::new(
+                File::create(path).context("open", path)?,
+                block_size,
+                expected_size,
+            ),
+        }
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.expected_length
+    }
+
+    // Streams carry no header to record the generator in
+    fn set_generator(&mut self, _generator: GeneratorId) {}
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        if self.written != self.expected_length {
+            return Err(BigKeyError::FailedToWriteBigKey {
+                expected_len: self.expected_length as usize,
+                wrote_len: self.written as usize,
+            });
+        }
+        self.inner.flush()?;
+        self.fingerprint = Some(*self.hasher.finalize().as_bytes());
+        Ok(())
+    }
+
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.fingerprint
+    }
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.written + buf.len() as u64 > self.expected_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the key",
+            ));
+        }
+        self.inner.write_all(buf)?;
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::stream::StreamWriter;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::StorageWriter;
+    use crate::traits::{BigKeyError, BLOCK_1K};
+
+    #[test]
+    fn streams_raw_key() {
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let mut expected = Vec::new();
+        Shake256Generator::new(Some(seed.clone().into()))
+            .unwrap()
+            .fill(&mut expected, 8 * 1024)
+            .unwrap();
+
+        let tmp = tempfile();
+        let mut writer = StreamWriter::new_writer(BLOCK_1K, tmp.to_str(), 8 * 1024).unwrap();
+        Shake256Generator::generate(&mut writer, Some(seed.into()), 8 * 1024).unwrap();
+        assert_eq!(
+            writer.fingerprint(),
+            Some(*blake3::hash(&expected).as_bytes())
+        );
+        drop(writer);
+        assert_eq!(std::fs::read(tmp.as_path()).unwrap(), expected);
+
+        let mut writer = StreamWriter::new(std::io::sink(), BLOCK_1K, 2048).unwrap();
+        writer.write_all(&[0u8; 1024]).unwrap();
+        assert!(writer.write_all(&[0u8; 2048]).is_err());
+        match writer.finalize() {
+            Err(BigKeyError::FailedToWriteBigKey { .. }) => {}
+            _ => panic!("expected a short key to fail"),
+        }
+    }
+
+    // A pipe whose reader has gone away
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn bad_lengths_locations_and_closed_pipes_fail() {
+        assert!(matches!(
+            StreamWriter::new(std::io::sink(), BLOCK_1K, 512),
+            Err(BigKeyError::OutputLengthTooShort { .. })
+        ));
+        assert!(matches!(
+            StreamWriter::new(std::io::sink(), BLOCK_1K, 1536),
+            Err(BigKeyError::KeyLengthIndivisible { .. })
+        ));
+        let dir = std::env::temp_dir();
+        assert!(StreamWriter::new_writer(BLOCK_1K, dir.to_str().unwrap(), 1024).is_err());
+
+        // nothing is hashed or counted for a write the pipe refused
+        let mut writer = StreamWriter::new(ClosedPipe, BLOCK_1K, 1024).unwrap();
+        assert_eq!(
+            writer.write(&[0u8; 1024]).unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+        assert!(matches!(
+            writer.finalize(),
+            Err(BigKeyError::FailedToWriteBigKey { wrote_len: 0, .. })
+        ));
+        assert_eq!(writer.fingerprint(), None);
+
+        // an exact write past the end is refused before reaching the stream
+        let mut writer = StreamWriter::new(std::io::sink(), BLOCK_1K, 1024).unwrap();
+        writer.write_all(&[0u8; 1024]).unwrap();
+        assert_eq!(
+            writer.write(&[0u8]).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        writer.finalize().unwrap();
+        assert_eq!(
+            writer.fingerprint(),
+            Some(*blake3::hash(&[0u8; 1024]).as_bytes())
+        );
+    }
+} // mod test
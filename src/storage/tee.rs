@@ -0,0 +1,250 @@
+//! Writing one BigKey to two destinations in a single pass.
+//!
+//! Replicated deployments keep a working copy of the key (e.g. on local NVMe) and an archival
+//! copy (e.g. on removable media). `TeeStorageWriter` hands every block from the generator to
+//! both writers, so the key is generated once rather than once per copy. On `finalize()` the
+//! fingerprints recorded by both destinations are checked against the stream that was
+//! generated, catching a copy that was corrupted on its way to storage. Both destinations must
+//! therefore record fingerprints: a copy that cannot be checked fails `finalize()` as well.
+
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::storage::StorageWriter;
+use crate::traits::{BigKeyError, BlockSize, GeneratorId};
+
+/// A `StorageWriter` that writes the key to a primary and a secondary `StorageWriter`
+pub struct TeeStorageWriter<A: StorageWriter, B: StorageWriter> {
+    primary: A,
+    secondary: B,
+    hasher: blake3::Hasher,
+    fingerprint: Option<[u8; 32]>,
+}
+
+impl<A: StorageWriter, B: StorageWriter> TeeStorageWriter<A, B> {
+    /// Write to both `primary` and `secondary`, which must agree on block size and key length
+    pub fn new(primary: A, secondary: B) -> Result<Self, BigKeyError> {
+        if primary.block_size().byte_len != secondary.block_size().byte_len
+            || primary.expected_big_key_length() != secondary.expected_big_key_length()
+        {
+            return Err(BigKeyError::InvalidConfig {
+                reason: "tee destinations differ in block size or key length".to_string(),
+            });
+        }
+
+        Ok(TeeStorageWriter {
+            primary,
+            secondary,
+            hasher: blake3::Hasher::new(),
+            fingerprint: None,
+        })
+    }
+
+    /// The destination writers
+    pub fn into_inner(self) -> (A, B) {
+        (self.primary, self.secondary)
+    }
+}
+
+impl<A, B> StorageWriter for TeeStorageWriter<A, B>
+where
+    A: StorageWriter<Options = str>,
+    B: StorageWriter<Options = str>,
+{
+    type Options = str;
+
+    /// `storage_location` lists the primary then the secondary location, joined as by
+    /// `std::env::join_paths()`
+    fn new_writer(
+        block_size: BlockSize,
+        storage_location: &str,
+        expected_size: usize,
+    ) -> Result<Self, BigKeyError> {
+        let locations: Vec<PathBuf> = std::env::split_paths(storage_location).collect();
+        let (primary, secondary) = match &locations[..] {
+            [primary, secondary] => (primary.to_string_lossy(), secondary.to_string_lossy()),
+            _ => {
+                return Err(BigKeyError::InvalidConfig {
+                    reason: format!("expected two tee locations in {:?}", storage_location),
+                })
+            }
+        };
+
+        TeeStorageWriter::new(
+            A::new_writer(block_size, &primary, expected_size)?,
+            B::new_writer(block_size, &secondary, expected_size)?,
+        )
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.primary.block_size()
+    }
+
+    fn expected_big_key_length(&self) -> u64 {
+        self.primary.expected_big_key_length()
+    }
+
+    fn set_generator(&mut self, generator: GeneratorId) {
+        self.primary.set_generator(generator);
+        self.secondary.set_generator(generator);
+    }
+
+    fn finalize(&mut self) -> Result<(), BigKeyError> {
+        self.primary.finalize()?;
+        self.secondary.finalize()?;
+
+        let generated = *self.hasher.finalize().as_bytes();
+        check_copy(self.primary.fingerprint(), generated, true)?;
+        check_copy(self.secondary.fingerprint(), generated, false)?;
+
+        self.fingerprint = Some(generated);
+        Ok(())
+    }
+
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.fingerprint
+    }
+}
+
+impl<A: StorageWriter, B: StorageWriter> Write for TeeStorageWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.primary.write_all(buf)?;
+        self.secondary.write_all(buf)?;
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.primary.flush()?;
+        self.secondary.flush()
+    }
+}
+
+// Check the fingerprint recorded by the primary (or secondary) copy is the one generated
+fn check_copy(
+    recorded: Option<[u8; 32]>,
+    generated: [u8; 32],
+    primary: bool,
+) -> Result<(), BigKeyError> {
+    let stage = match (recorded, primary) {
+        (Some(f), _) if f == generated => return Ok(()),
+        (Some(_), true) => "primary copy differs from key generated",
+        (Some(_), false) => "secondary copy differs from key generated",
+        (None, true) => "primary copy recorded no fingerprint",
+        (None, false) => "secondary copy recorded no fingerprint",
+    };
+    Err(BigKeyError::VerificationFailed { stage })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::io::Write;
+
+    use crate::generation::{BigKeyGenerator, Shake256Generator};
+    use crate::storage::tee::TeeStorageWriter;
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::{
+        fingerprint, ContainerStorage, ContainerWriter, DiskStorage, StorageWriter,
+    };
+    use crate::traits::{BigKeyError, BlockSize, GeneratorId, BLOCK_1K, BLOCK_4K};
+
+    #[test]
+    fn both_destinations_receive_the_key() {
+        let (disk, container) = (tempfile(), tempfile());
+        let locations = std::env::join_paths([disk.as_path(), container.as_path()].iter()).unwrap();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+
+        let mut tee = TeeStorageWriter::<DiskStorage, ContainerWriter>::new_writer(
+            BLOCK_1K,
+            locations.to_str().unwrap(),
+            32 * 1024,
+        )
+        .unwrap();
+        Shake256Generator::generate(&mut tee, Some(seed.into()), 32 * 1024).unwrap();
+        let generated = tee.fingerprint().unwrap();
+        drop(tee);
+
+        let mut reader = DiskStorage::open(BLOCK_1K, disk.to_str()).unwrap();
+        assert_eq!(fingerprint(&mut reader).unwrap(), generated);
+        let mut reader = ContainerStorage::open(container.to_str()).unwrap();
+        assert_eq!(fingerprint(&mut reader).unwrap(), generated);
+    }
+
+    #[test]
+    fn mismatched_destinations_are_rejected() {
+        let (a, b) = (tempfile(), tempfile());
+        let primary = DiskStorage::new_writer(BLOCK_1K, a.to_str(), 16 * 1024).unwrap();
+        let secondary = DiskStorage::new_writer(BLOCK_4K, b.to_str(), 16 * 1024).unwrap();
+        match TeeStorageWriter::new(primary, secondary) {
+            Err(BigKeyError::InvalidConfig { .. }) => {}
+            _ => panic!("expected destinations with different block sizes to be rejected"),
+        }
+
+        match TeeStorageWriter::<DiskStorage, DiskStorage>::new_writer(BLOCK_1K, a.to_str(), 1024) {
+            Err(BigKeyError::InvalidConfig { .. }) => {}
+            _ => panic!("expected a single location to be rejected"),
+        }
+    }
+
+    // A destination that keeps the key in memory and records no fingerprint
+    struct Unfingerprinted(Vec<u8>);
+
+    impl Write for Unfingerprinted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl StorageWriter for Unfingerprinted {
+        type Options = str;
+
+        fn new_writer(_: BlockSize, _: &str, _: usize) -> Result<Self, BigKeyError> {
+            Ok(Unfingerprinted(Vec::new()))
+        }
+
+        fn block_size(&self) -> BlockSize {
+            BLOCK_1K
+        }
+
+        fn expected_big_key_length(&self) -> u64 {
+            16 * 1024
+        }
+
+        fn set_generator(&mut self, _generator: GeneratorId) {}
+
+        fn finalize(&mut self) -> Result<(), BigKeyError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn copies_without_fingerprints_fail_verification() {
+        let tmp = tempfile();
+        let seed = b"0123456789abcdef0123456789abcdef".to_vec();
+        let primary = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 16 * 1024).unwrap();
+        let mut tee = TeeStorageWriter::new(primary, Unfingerprinted(Vec::new())).unwrap();
+        match Shake256Generator::generate(&mut tee, Some(seed.clone().into()), 16 * 1024) {
+            Err(BigKeyError::VerificationFailed { stage }) => {
+                assert_eq!(stage, "secondary copy recorded no fingerprint")
+            }
+            _ => panic!("expected an unchecked secondary copy to fail verification"),
+        }
+        assert_eq!(tee.fingerprint(), None);
+        drop(tee);
+
+        let secondary = DiskStorage::new_writer(BLOCK_1K, tmp.to_str(), 16 * 1024).unwrap();
+        let mut tee = TeeStorageWriter::new(Unfingerprinted(Vec::new()), secondary).unwrap();
+        match Shake256Generator::generate(&mut tee, Some(seed.into()), 16 * 1024) {
+            Err(BigKeyError::VerificationFailed { stage }) => {
+                assert_eq!(stage, "primary copy recorded no fingerprint")
+            }
+            _ => panic!("expected an unchecked primary copy to fail verification"),
+        }
+    }
+} // mod test
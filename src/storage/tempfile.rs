@@ -1,7 +1,5 @@
 //! Temporary file helper utility for tests
 
-use std::fs::Metadata;
-use std::io::Error;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -35,9 +33,7 @@ impl Drop for TempFile {
             Err(_) => println!("removing file {:?}", self.pb),
         }
 
-        match std::fs::remove_file(self.pb.as_path()) {
-            _ => {}
-        }
+        let _ = std::fs::remove_file(self.pb.as_path());
     }
 }
 
@@ -1,39 +1,156 @@
 use std::io::Write;
 
-use crate::traits::{BigKeyError, BlockSize};
+use crate::traits::{BigKeyError, BlockCount, BlockIndex, BlockSize, ByteSize, GeneratorId};
 
 /// StorageMethod defines a persistent method of storing and reading BigKey cryptographic material.
 ///
 /// The `probe()` method implements a single large-alphabet probe into the BigKey.
 ///
 /// The `BlockSize` should be chosen to maximize the efficiency of random reads (seeks).
-pub trait StorageReader: Sized {
-    fn open(block_size: BlockSize, storage_location: &str) -> Result<Self, BigKeyError>;
-
+///
+/// `StorageReader` is object safe, so the backend can be chosen at runtime and held as a
+/// `Box<dyn StorageReader>`. Construction lives in `StorageReaderFactory`.
+pub trait StorageReader {
     /// Retrieve the block at `index` writing the value in `output`.
-    fn probe(&mut self, index: u64, output: &mut [u8]) -> Result<(), BigKeyError>;
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError>;
+
+    /// Retrieve the blocks at `indices` into consecutive blocks of `output`, which holds
+    /// exactly as many. Backends paying a round trip per probe (see `RemoteStorage`) override
+    /// this to keep several probes in flight; the default probes one after the other.
+    fn probe_many(&mut self, indices: &[BlockIndex], output: &mut [u8]) -> Result<(), BigKeyError> {
+        let block_len = self.block_size().byte_len;
+        check_batch_len(indices, output, block_len)?;
+        for (&index, block) in indices.iter().zip(output.chunks_exact_mut(block_len)) {
+            self.probe(index, block)?;
+        }
+        Ok(())
+    }
+
+    /// Hint that the blocks at `indices` will be probed soon. Backends that can start loading
+    /// them without waiting override this and return before the reads complete: `DiskStorage`
+    /// and `ContainerStorage` ask the kernel to read them into the page cache, `RemoteStorage`
+    /// reads them on its reader threads. Wrappers pass the hint on. The default does nothing.
+    fn prefetch(&mut self, _indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        Ok(())
+    }
 
     /// Total BigKey length in bytes
     fn big_key_length(&self) -> u64;
 
     /// `BlockSize` of underlying storage media
     fn block_size(&self) -> BlockSize;
+
+    /// Number of blocks of the BigKey
+    fn block_count(&self) -> BlockCount {
+        BlockCount::new(self.big_key_length() / self.block_size().byte_len as u64)
+    }
+}
+
+impl<R: StorageReader + ?Sized> StorageReader for Box<R> {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        (**self).probe(index, output)
+    }
+
+    fn probe_many(&mut self, indices: &[BlockIndex], output: &mut [u8]) -> Result<(), BigKeyError> {
+        (**self).probe_many(indices, output)
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        (**self).prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        (**self).big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        (**self).block_size()
+    }
+}
+
+impl<R: StorageReader + ?Sized> StorageReader for &mut R {
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        (**self).probe(index, output)
+    }
+
+    fn probe_many(&mut self, indices: &[BlockIndex], output: &mut [u8]) -> Result<(), BigKeyError> {
+        (**self).probe_many(indices, output)
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        (**self).prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        (**self).big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        (**self).block_size()
+    }
+}
+
+// `output` of a `probe_many()` must hold exactly one block per index
+pub(crate) fn check_batch_len(
+    indices: &[BlockIndex],
+    output: &[u8],
+    block_len: usize,
+) -> Result<(), BigKeyError> {
+    if indices.len().checked_mul(block_len) != Some(output.len()) {
+        return Err(BigKeyError::ProbeBufferNotEqBlockSize {
+            out_buf_len: output.len(),
+            block_len: indices.len().saturating_mul(block_len),
+        });
+    }
+    Ok(())
+}
+
+/// Opens `StorageReader`s of a particular backend. Select a factory at runtime (e.g. from
+/// configuration) to choose the storage backend.
+pub trait StorageReaderFactory {
+    fn open(
+        &self,
+        block_size: BlockSize,
+        storage_location: &str,
+    ) -> Result<Box<dyn StorageReader>, BigKeyError>;
 }
 
 /// StorageWriter generates a new BigKey
 pub trait StorageWriter: Sized + Write {
+    /// Where and how the backend writes a new key: a path (`str`) for file backends, a richer
+    /// type for destinations such as object storage (see `S3Options`)
+    type Options: ?Sized;
+
     fn new_writer(
         block_size: BlockSize,
-        storage_location: &str,
+        storage_location: &Self::Options,
         expected_size: usize,
     ) -> Result<Self, BigKeyError>;
 
+    /// `new_writer()` for a key of `size`, failing with `InvalidByteSize` if this platform
+    /// cannot address it
+    fn create(
+        block_size: BlockSize,
+        storage_location: &Self::Options,
+        size: ByteSize,
+    ) -> Result<Self, BigKeyError> {
+        Self::new_writer(block_size, storage_location, size.to_usize()?)
+    }
+
     /// `BlockSize` of underlying storage media
     fn block_size(&self) -> BlockSize;
 
     /// Total BigKey length in bytes
     fn expected_big_key_length(&self) -> u64;
 
+    /// Record which generator is producing the BigKey
+    fn set_generator(&mut self, generator: GeneratorId);
+
     /// Perform any finalization and flush the BigKey
     fn finalize(&mut self) -> Result<(), BigKeyError>;
+
+    /// BLAKE3 fingerprint of the key once finalized, `None` if the writer does not record one
+    fn fingerprint(&self) -> Option<[u8; 32]> {
+        None
+    }
 }
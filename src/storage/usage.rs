@@ -0,0 +1,340 @@
+//! Lifetime usage statistics of a BigKey, kept in a sidecar file next to it.
+//!
+//! Every derivation reads its probed blocks, so over many derivations a growing share of the
+//! key has passed through memory and derived keys. A `UsageTracker` counts derivations, blocks
+//! probed, and the distinct blocks touched, letting operators see how much of the key has
+//! effectively been consumed.
+//!
+//! Distinct blocks are tracked in a bitmap of at most `MAX_USAGE_BITMAP_BITS` bits. Keys with
+//! no more blocks than that get one bit per block and an exact count; for larger keys block
+//! indices are hashed into the bitmap and the count is a linear counting estimate.
+//!
+//! The sidecar also records when the key was created, taken from the key file's modification
+//! time when tracking starts, so policies can retire keys by age (see `kem::RetirementPolicy`).
+//!
+//! Derived keys whose data is gone can be retired (see `kem::LocatorStore::gc()`). Retired
+//! derivations no longer count against `RetirementPolicy::max_derivations`; the blocks they
+//! probed stay counted, as they have passed through memory all the same.
+//!
+//! Sidecar layout (`<key>.usage`): 52 byte header (magic, block count, bitmap bits,
+//! derivations, blocks probed, creation time in Unix seconds, all u64 big-endian) followed by
+//! the bitmap and the number of retired derivations (u64 big-endian, absent in sidecars
+//! written before retirement was tracked).
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::storage::util::StorageContext;
+use crate::traits::BigKeyError;
+
+/// Largest usage bitmap, 1 MiB
+pub const MAX_USAGE_BITMAP_BITS: u64 = 1 << 23;
+
+const MAGIC: &[u8; 12] = b"BFDISE-USAGE";
+const USAGE_HEADER_LEN: usize = 52;
+
+/// Location of the usage sidecar for the BigKey at `storage_location`
+pub fn usage_path(storage_location: &str) -> String {
+    format!("{}.usage", storage_location)
+}
+
+/// Usage of a BigKey over its lifetime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyUsage {
+    /// Keys derived
+    pub derivations: u64,
+    /// Derived keys since retired
+    pub retired_derivations: u64,
+    /// Block reads by all derivations, including repeats
+    pub blocks_probed: u64,
+    /// Distinct blocks ever probed
+    pub distinct_blocks: u64,
+    /// Whether `distinct_blocks` is exact rather than an estimate
+    pub exact: bool,
+    pub block_count: u64,
+    pub created: SystemTime,
+}
+
+impl KeyUsage {
+    /// Fraction of the key's blocks that have been probed at least once
+    pub fn touched_fraction(&self) -> f64 {
+        match self.block_count {
+            0 => 0.0,
+            n => self.distinct_blocks as f64 / n as f64,
+        }
+    }
+
+    /// Derived keys not retired
+    pub fn live_derivations(&self) -> u64 {
+        self.derivations.saturating_sub(self.retired_derivations)
+    }
+
+    /// Time since the key was created (zero if its clock is in the future)
+    pub fn age(&self) -> Duration {
+        self.created.elapsed().unwrap_or_default()
+    }
+}
+
+/// Accumulates usage of one BigKey and persists it in the usage sidecar
+pub struct UsageTracker {
+    path: String,
+    block_count: u64,
+    derivations: u64,
+    retired_derivations: u64,
+    blocks_probed: u64,
+    bitmap: Vec<u8>,
+    bitmap_bits: u64,
+    set_bits: u64,
+    created: u64,
+}
+
+impl UsageTracker {
+    /// Track usage of the `block_count` block key at `storage_location`, continuing from its
+    /// usage sidecar if one exists
+    pub fn open(storage_location: &str, block_count: u64) -> Result<Self, BigKeyError> {
+        let path = usage_path(storage_location);
+        match File::open(&path) {
+            Ok(mut file) => UsageTracker::read_from(&mut file, path, Some(block_count)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let bitmap_bits = block_count.clamp(1, MAX_USAGE_BITMAP_BITS);
+                let created = std::fs::metadata(storage_location)
+                    .and_then(|m| m.modified())
+                    .unwrap_or_else(|_| SystemTime::now());
+                Ok(UsageTracker {
+                    path,
+                    block_count,
+                    derivations: 0,
+                    retired_derivations: 0,
+                    blocks_probed: 0,
+                    bitmap: vec![0u8; bitmap_bits.div_ceil(8) as usize],
+                    bitmap_bits,
+                    set_bits: 0,
+                    created: created
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                })
+            }
+            Err(e) => Err(e).context("open", &path),
+        }
+    }
+
+    /// Usage recorded in the sidecar of the key at `storage_location`, if it has one
+    pub fn read(storage_location: &str) -> Result<Option<KeyUsage>, BigKeyError> {
+        let path = usage_path(storage_location);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("open", &path),
+        };
+        let tracker = UsageTracker::read_from(&mut file, path, None)?;
+        Ok(Some(tracker.usage()))
+    }
+
+    // Load a sidecar, checking it belongs to a key of `expected_blocks` blocks if given
+    fn read_from(
+        file: &mut File,
+        path: String,
+        expected_blocks: Option<u64>,
+    ) -> Result<Self, BigKeyError> {
+        let mut header = [0u8; USAGE_HEADER_LEN];
+        file.read_exact(&mut header).context("read", &path)?;
+        let field = |i: usize| u64::from_be_bytes(header[i..i + 8].try_into().unwrap());
+
+        if &header[0..12] != MAGIC {
+            return Err(BigKeyError::InvalidUsageSidecar {
+                reason: "not a usage sidecar",
+            });
+        }
+        let block_count = field(12);
+        if expected_blocks.is_some_and(|expected| expected != block_count) {
+            return Err(BigKeyError::InvalidUsageSidecar {
+                reason: "block count differs from key",
+            });
+        }
+        let bitmap_bits = field(20);
+        if bitmap_bits != block_count.clamp(1, MAX_USAGE_BITMAP_BITS) {
+            return Err(BigKeyError::InvalidUsageSidecar {
+                reason: "unexpected bitmap size",
+            });
+        }
+
+        let bitmap_len = bitmap_bits.div_ceil(8) as usize;
+        let mut bitmap = Vec::with_capacity(bitmap_len + 8);
+        file.read_to_end(&mut bitmap).context("read", &path)?;
+        let retired_derivations = match bitmap.len().checked_sub(bitmap_len) {
+            Some(0) => 0,
+            Some(8) => u64::from_be_bytes(bitmap[bitmap_len..].try_into().unwrap()),
+            _ => {
+                return Err(BigKeyError::InvalidUsageSidecar {
+                    reason: "truncated usage sidecar",
+                })
+            }
+        };
+        bitmap.truncate(bitmap_len);
+        let set_bits = bitmap.iter().map(|b| b.count_ones() as u64).sum();
+
+        Ok(UsageTracker {
+            path,
+            block_count,
+            derivations: field(28),
+            retired_derivations,
+            blocks_probed: field(36),
+            bitmap,
+            bitmap_bits,
+            set_bits,
+            created: field(44),
+        })
+    }
+
+    /// Record a read of block `index`
+    pub fn record_probe(&mut self, index: u64) {
+        self.blocks_probed += 1;
+
+        let bit = match self.block_count > self.bitmap_bits {
+            true => mix(index) % self.bitmap_bits,
+            false => index % self.bitmap_bits,
+        };
+        let mask = 1u8 << (bit % 8);
+        let byte = &mut self.bitmap[(bit / 8) as usize];
+        if *byte & mask == 0 {
+            *byte |= mask;
+            self.set_bits += 1;
+        }
+    }
+
+    /// Record a completed key derivation
+    pub fn record_derivation(&mut self) {
+        self.derivations += 1;
+    }
+
+    /// Record that `derivations` derived keys were retired, never more than were derived
+    pub fn record_retirement(&mut self, derivations: u64) {
+        self.retired_derivations = (self.retired_derivations + derivations).min(self.derivations);
+    }
+
+    pub fn usage(&self) -> KeyUsage {
+        let exact = self.block_count <= self.bitmap_bits;
+        let distinct_blocks = if exact {
+            self.set_bits
+        } else if self.set_bits == self.bitmap_bits {
+            self.block_count
+        } else {
+            // linear counting: n ~ -m ln(unset / m)
+            let m = self.bitmap_bits as f64;
+            let estimate = -m * ((m - self.set_bits as f64) / m).ln();
+            (estimate.round() as u64).min(self.block_count)
+        };
+
+        KeyUsage {
+            derivations: self.derivations,
+            retired_derivations: self.retired_derivations,
+            blocks_probed: self.blocks_probed,
+            distinct_blocks,
+            exact,
+            block_count: self.block_count,
+            created: UNIX_EPOCH + Duration::from_secs(self.created),
+        }
+    }
+
+    /// Write the usage sidecar
+    pub fn save(&self) -> Result<(), BigKeyError> {
+        let mut file = BufWriter::new(File::create(&self.path).context("create", &self.path)?);
+        let mut header = MAGIC.to_vec();
+        for field in [
+            self.block_count,
+            self.bitmap_bits,
+            self.derivations,
+            self.blocks_probed,
+            self.created,
+        ]
+        .iter()
+        {
+            header.extend_from_slice(&field.to_be_bytes());
+        }
+        file.write_all(&header)
+            .and_then(|_| file.write_all(&self.bitmap))
+            .and_then(|_| file.write_all(&self.retired_derivations.to_be_bytes()))
+            .and_then(|_| file.flush())
+            .context("write", &self.path)
+    }
+}
+
+impl Drop for UsageTracker {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            log::warn!("failed to save key usage to {}: {}", self.path, e);
+        }
+    }
+}
+
+// Spread block indices over the bitmap (splitmix64 finalizer)
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::usage::{usage_path, UsageTracker, MAX_USAGE_BITMAP_BITS};
+    use crate::traits::BigKeyError;
+
+    #[test]
+    fn usage_persists_across_opens() {
+        let tmp = tempfile();
+        assert!(UsageTracker::read(tmp.to_str()).unwrap().is_none());
+
+        let mut tracker = UsageTracker::open(tmp.to_str(), 100).unwrap();
+        for index in [3, 7, 3, 99].iter() {
+            tracker.record_probe(*index);
+        }
+        tracker.record_derivation();
+        drop(tracker);
+
+        let mut tracker = UsageTracker::open(tmp.to_str(), 100).unwrap();
+        tracker.record_probe(7);
+        tracker.record_probe(50);
+        tracker.record_derivation();
+        tracker.record_retirement(1);
+        tracker.save().unwrap();
+
+        let usage = UsageTracker::read(tmp.to_str()).unwrap().unwrap();
+        assert_eq!(usage.derivations, 2);
+        assert_eq!(usage.live_derivations(), 1);
+        assert_eq!(usage.blocks_probed, 6);
+        assert_eq!(usage.distinct_blocks, 4);
+        assert!(usage.exact);
+        assert!((usage.touched_fraction() - 0.04).abs() < 1e-9);
+        assert!(usage.age() < std::time::Duration::from_secs(3600));
+        drop(tracker);
+
+        match UsageTracker::open(tmp.to_str(), 200) {
+            Err(BigKeyError::InvalidUsageSidecar { .. }) => {}
+            _ => panic!("expected sidecar of a different key to be rejected"),
+        }
+        let _ = std::fs::remove_file(usage_path(tmp.to_str()));
+    }
+
+    #[test]
+    fn large_keys_estimate_distinct_blocks() {
+        let tmp = tempfile();
+        let mut tracker = UsageTracker::open(tmp.to_str(), 1 << 40).unwrap();
+        for index in 0..1_000_000u64 {
+            tracker.record_probe(index * 1_000_003);
+        }
+
+        let usage = tracker.usage();
+        assert!(!usage.exact);
+        assert_eq!(usage.blocks_probed, 1_000_000);
+        assert!((usage.distinct_blocks as f64 - 1e6).abs() < 1e6 * 0.02);
+        assert_eq!(tracker.bitmap.len() as u64, MAX_USAGE_BITMAP_BITS / 8);
+
+        drop(tracker);
+        let _ = std::fs::remove_file(usage_path(tmp.to_str()));
+    }
+} // mod test
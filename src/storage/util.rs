@@ -1,11 +1,47 @@
-use crate::traits::{BigKeyError, BlockSize};
+use std::io;
+use std::path::Path;
+
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+// Wrap IO errors of storage operations with what was being done where
+pub(crate) trait StorageContext<T> {
+    fn context(self, op: &'static str, path: &str) -> Result<T, BigKeyError>;
+
+    fn context_at(self, op: &'static str, path: &str, offset: u64) -> Result<T, BigKeyError>;
+}
+
+impl<T, E: Into<BigKeyError>> StorageContext<T> for Result<T, E> {
+    fn context(self, op: &'static str, path: &str) -> Result<T, BigKeyError> {
+        self.map_err(|e| e.into().in_storage(op, path, None))
+    }
+
+    fn context_at(self, op: &'static str, path: &str, offset: u64) -> Result<T, BigKeyError> {
+        self.map_err(|e| e.into().in_storage(op, path, Some(offset)))
+    }
+}
+
+// Make a rename into the directory of `path` durable. Only needed (and only possible) on Unix;
+// elsewhere the rename is left to the file system.
+pub(crate) fn sync_parent(path: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match Path::new(path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = Path::new(path);
+    Ok(())
+}
 
 // Ensure that the total big key length is evenly divisible by the block size (no remainder)
 pub(crate) fn check_key_evenly_divisible(
     block_size: BlockSize,
     key_len: u64,
 ) -> Result<(), BigKeyError> {
-    if (key_len % block_size.byte_len as u64) != 0 {
+    if !key_len.is_multiple_of(block_size.byte_len as u64) {
         Err(BigKeyError::KeyLengthIndivisible {
             block_len: block_size.byte_len,
             key_len: key_len as usize,
@@ -14,3 +50,46 @@ pub(crate) fn check_key_evenly_divisible(
         Ok(())
     }
 }
+
+// Byte offset of block `index` within a key of `key_len` bytes, failing for blocks beyond the
+// end of the key (including indices so large the offset overflows)
+pub(crate) fn block_offset(
+    index: BlockIndex,
+    block_size: BlockSize,
+    key_len: u64,
+) -> Result<u64, BigKeyError> {
+    let block_len = block_size.byte_len as u64;
+    match index.byte_offset(block_size) {
+        Some(offset)
+            if offset
+                .checked_add(block_len)
+                .is_some_and(|end| end <= key_len) =>
+        {
+            Ok(offset)
+        }
+        offset => Err(out_of_bounds(offset, block_size, key_len)),
+    }
+}
+
+// Position within a file of the block at byte `offset` of key data starting at `data_offset`,
+// failing like `block_offset()` if it overflows
+pub(crate) fn data_position(
+    data_offset: u64,
+    offset: u64,
+    block_size: BlockSize,
+    key_len: u64,
+) -> Result<u64, BigKeyError> {
+    data_offset
+        .checked_add(offset)
+        .filter(|position| position.checked_add(block_size.byte_len as u64).is_some())
+        .ok_or_else(|| out_of_bounds(None, block_size, key_len))
+}
+
+// An overflowing offset is reported as `u64::MAX`
+fn out_of_bounds(offset: Option<u64>, block_size: BlockSize, key_len: u64) -> BigKeyError {
+    BigKeyError::ProbeOffsetOutOfBounds {
+        end_of_key: key_len as usize,
+        offset: offset.unwrap_or(u64::MAX) as usize,
+        probe_len: block_size.byte_len,
+    }
+}
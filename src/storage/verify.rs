@@ -0,0 +1,110 @@
+//! Quick operational checks of BigKey storage.
+
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex};
+
+/// Result of probing a random sample of blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpotCheck {
+    /// Number of blocks successfully probed
+    pub probed: usize,
+    /// Indices of sampled blocks that were entirely zero, a strong sign of a failed write
+    pub zero_blocks: Vec<u64>,
+}
+
+impl SpotCheck {
+    pub fn passed(&self) -> bool {
+        self.zero_blocks.is_empty()
+    }
+}
+
+/// Probe `samples` uniformly random blocks of `reader`, failing on the first unreadable block.
+pub fn spot_check<R: StorageReader + ?Sized>(
+    reader: &mut R,
+    samples: usize,
+) -> Result<SpotCheck, BigKeyError> {
+    let block_len = reader.block_size().byte_len;
+    let block_count = reader.big_key_length() / block_len as u64;
+    let mut buf = vec![0u8; block_len];
+    let mut zero_blocks = Vec::new();
+
+    if block_count == 0 {
+        return Ok(SpotCheck {
+            probed: 0,
+            zero_blocks,
+        });
+    }
+
+    for _ in 0..samples {
+        let index = random_u64()? % block_count;
+        reader.probe(BlockIndex::new(index), &mut buf)?;
+
+        if buf.iter().all(|b| *b == 0) {
+            zero_blocks.push(index);
+        }
+    }
+
+    Ok(SpotCheck {
+        probed: samples,
+        zero_blocks,
+    })
+}
+
+/// BLAKE3 digest of every block of `reader` in order, matching `KeyHeader::fingerprint`.
+pub fn fingerprint<R: StorageReader + ?Sized>(reader: &mut R) -> Result<[u8; 32], BigKeyError> {
+    let block_len = reader.block_size().byte_len;
+    let mut block = vec![0u8; block_len];
+    let mut hasher = blake3::Hasher::new();
+
+    for index in reader.block_count().indices() {
+        reader.probe(index, &mut block)?;
+        hasher.update(&block);
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+pub(crate) fn random_u64() -> Result<u64, BigKeyError> {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+
+    use crate::storage::tempfile::tempfile;
+    use crate::storage::verify::spot_check;
+    use crate::storage::DiskStorage;
+    use crate::traits::BLOCK_1K;
+
+    #[test]
+    fn zero_blocks_are_flagged() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            ofile.write_all(&[0u8; 1024]).unwrap();
+        }
+
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        let check = spot_check(&mut storage, 3).unwrap();
+
+        assert_eq!(check.probed, 3);
+        assert_eq!(check.zero_blocks, vec![0, 0, 0]);
+        assert!(!check.passed());
+    }
+
+    #[test]
+    fn random_blocks_pass() {
+        let tmp = tempfile();
+        {
+            let mut ofile = File::create(tmp.as_path()).unwrap();
+            ofile.write_all(&[0x17u8; 8 * 1024]).unwrap();
+        }
+
+        let mut storage = DiskStorage::open(BLOCK_1K, tmp.to_str()).unwrap();
+        assert!(spot_check(&mut storage, 16).unwrap().passed());
+    }
+} // mod test
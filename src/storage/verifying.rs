@@ -0,0 +1,156 @@
+//! Checking probed blocks against an external integrity system.
+//!
+//! Deployments that already track block integrity elsewhere, such as a checksum database, a
+//! content-addressed store or a checksumming filesystem exposing its own verification API, can
+//! wrap any `StorageReader` in a `VerifyingStorage`. Its callback sees every probed block and
+//! may veto it, without the backend knowing anything about the integrity system.
+
+use crate::memory::wipe;
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, BlockIndex, BlockSize};
+
+/// A `StorageReader` that passes every probed block to a verification callback.
+///
+/// The callback receives the block index and contents. Returning an error vetoes the block:
+/// the probe fails with that error and the output buffer is zeroed, so a vetoed block never
+/// reaches key derivation. Return `BigKeyError::BlockCorrupted` for blocks that fail
+/// verification, and the integrity system's own error if it cannot be consulted.
+pub struct VerifyingStorage<R, F>
+where
+    R: StorageReader,
+    F: FnMut(BlockIndex, &[u8]) -> Result<(), BigKeyError>,
+{
+    inner: R,
+    verify: F,
+    vetoed: u64,
+}
+
+impl<R, F> VerifyingStorage<R, F>
+where
+    R: StorageReader,
+    F: FnMut(BlockIndex, &[u8]) -> Result<(), BigKeyError>,
+{
+    pub fn new(inner: R, verify: F) -> Self {
+        VerifyingStorage {
+            inner,
+            verify,
+            vetoed: 0,
+        }
+    }
+
+    /// Number of probed blocks the callback has vetoed
+    pub fn vetoed(&self) -> u64 {
+        self.vetoed
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R, F> StorageReader for VerifyingStorage<R, F>
+where
+    R: StorageReader,
+    F: FnMut(BlockIndex, &[u8]) -> Result<(), BigKeyError>,
+{
+    fn probe(&mut self, index: BlockIndex, output: &mut [u8]) -> Result<(), BigKeyError> {
+        self.inner.probe(index, output)?;
+        if let Err(e) = (self.verify)(index, output) {
+            wipe(output);
+            self.vetoed += 1;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn prefetch(&mut self, indices: &[BlockIndex]) -> Result<(), BigKeyError> {
+        self.inner.prefetch(indices)
+    }
+
+    fn big_key_length(&self) -> u64 {
+        self.inner.big_key_length()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use crate::storage::verifying::VerifyingStorage;
+    use crate::storage::{ReadSeekStorage, StorageReader};
+    use crate::traits::{BigKeyError, BlockIndex, BLOCK_32};
+
+    #[test]
+    fn callback_vetoes_blocks() {
+        let mut key: Vec<u8> = (0..64u8).collect();
+        // an external checksum database recorded before block 5 was corrupted
+        let checksums: HashMap<BlockIndex, [u8; 32]> = key
+            .chunks(4)
+            .enumerate()
+            .map(|(i, block)| (BlockIndex::new(i as u64), *blake3::hash(block).as_bytes()))
+            .collect();
+        key[21] ^= 0xff;
+
+        let inner = ReadSeekStorage::new(Cursor::new(key), BLOCK_32).unwrap();
+        let mut storage = VerifyingStorage::new(inner, |index, block: &[u8]| {
+            match checksums.get(&index) == Some(blake3::hash(block).as_bytes()) {
+                true => Ok(()),
+                false => Err(BigKeyError::BlockCorrupted { index: index.get() }),
+            }
+        });
+
+        let mut block = [0u8; 4];
+        storage.probe(BlockIndex::new(4), &mut block).unwrap();
+        assert_eq!(block, [16, 17, 18, 19]);
+        match storage.probe(BlockIndex::new(5), &mut block) {
+            Err(BigKeyError::BlockCorrupted { index }) => assert_eq!(index, 5),
+            _ => panic!("expected block 5 to be vetoed"),
+        }
+        assert_eq!(block, [0u8; 4]);
+        assert_eq!(storage.vetoed(), 1);
+    }
+
+    #[test]
+    fn backend_failures_and_batches() {
+        let key: Vec<u8> = (0..64u8).collect();
+        let inner = ReadSeekStorage::new(Cursor::new(key), BLOCK_32).unwrap();
+        let mut seen = Vec::new();
+        let mut storage = VerifyingStorage::new(inner, |index, _block: &[u8]| {
+            seen.push(index.get());
+            match index.get() {
+                9 => Err(BigKeyError::VerificationFailed {
+                    stage: "checksum database unreachable",
+                }),
+                _ => Ok(()),
+            }
+        });
+
+        // blocks the backend cannot read never reach the callback and are not vetoes
+        let mut block = [0xaau8; 4];
+        assert!(storage.probe(BlockIndex::new(16), &mut block).is_err());
+        assert!(storage
+            .probe(BlockIndex::new(u64::MAX), &mut block)
+            .is_err());
+        assert_eq!(storage.vetoed(), 0);
+
+        // a batch stops at the first veto, passing the callback's own error through
+        let mut blocks = [0u8; 12];
+        let batch = [8, 9, 10].map(BlockIndex::new);
+        assert!(matches!(
+            storage.probe_many(&batch, &mut blocks),
+            Err(BigKeyError::VerificationFailed { .. })
+        ));
+        assert_eq!(blocks[..4], [32, 33, 34, 35]);
+        assert_eq!(blocks[4..], [0u8; 8]);
+        assert_eq!(storage.vetoed(), 1);
+
+        let inner = storage.into_inner();
+        assert_eq!(inner.big_key_length(), 64);
+        assert_eq!(seen, vec![8, 9]);
+    }
+} // mod test
@@ -0,0 +1,341 @@
+//! TLS 1.3 external pre-shared keys (RFC 8446 section 2.2) and session tickets keyed by a BigKey.
+//!
+//! Two hosts holding the same key file can bootstrap mutually authenticated TLS without
+//! certificates: the client derives a fresh PSK with `new_external_psk()` and offers it under
+//! its identity, the armored locator; the server re-derives the PSK from the identity with
+//! `resolve_external_psk()`. The PSK binder proves both sides derived the same key, so only
+//! holders of the BigKey complete the handshake. Use the `psk_dhe_ke` mode so sessions keep
+//! forward secrecy should the BigKey later leak.
+//!
+//! PSKs are bound to the hash of the cipher suites they are used with (RFC 8446 section
+//! 4.2.11), so the same identity yields different secrets under `PskHash::Sha256` and
+//! `PskHash::Sha384`.
+//!
+//! # Scope
+//!
+//! External PSKs are derived only; this module does not drive a handshake. Callers hand
+//! `ExternalPsk` to a stack that takes an identity, secret and hash, such as OpenSSL's
+//! `SSL_CTX_set_psk_use_session_callback` and `SSL_CTX_set_psk_find_session_callback`.
+//!
+//! rustls (through 0.23) negotiates resumption PSKs only, it has no API for external PSKs. What
+//! it does take is a session ticket encrypter: `BigKeyTicketer` implements its
+//! `ProducesTickets` over a `KemSession`, sealing each ticket under a fresh BigKey subkey with
+//! the subkey's locator in the clear (see `kem::wrap_key`). Any server holding the key file
+//! resumes sessions ticketed by any other, without distributing or rotating ticket keys. Every
+//! ticket issued or redeemed costs a derivation, and tickets stay decryptable by the BigKey
+//! after they expire, so resumed sessions are only as forward secret as the BigKey is secret.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use digest::Digest;
+use rustls::server::ProducesTickets;
+
+use crate::kem::{
+    armor_locator, dearmor_locator, unwrap_key, wrap_key, BigKey, KemSession, KEK_LEN,
+};
+use crate::memory::wipe;
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, KeyMaterial, Locator, SecurityLevel};
+
+// Peer id prefix binding derived keys to TLS, followed by the name of the PSK's hash
+const PEER_ID_PREFIX: &[u8] = b"big_fluffy_dise TLS 1.3 external PSK ";
+
+// Expands the subkey sealing a session ticket to a key encryption key
+const TICKET_KEK_CONTEXT: &str = "big_fluffy_dise 2024 TLS session ticket key v1";
+
+/// Hash of the TLS 1.3 cipher suites a PSK is used with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PskHash {
+    /// `TLS_AES_128_GCM_SHA256` and `TLS_CHACHA20_POLY1305_SHA256`
+    Sha256,
+    /// `TLS_AES_256_GCM_SHA384`
+    Sha384,
+}
+
+impl PskHash {
+    pub fn parse(name: &str) -> Option<PskHash> {
+        match name {
+            "sha256" => Some(PskHash::Sha256),
+            "sha384" => Some(PskHash::Sha384),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PskHash::Sha256 => "sha256",
+            PskHash::Sha384 => "sha384",
+        }
+    }
+
+    fn peer_id(self) -> Vec<u8> {
+        let mut peer_id = PEER_ID_PREFIX.to_vec();
+        peer_id.extend_from_slice(self.name().as_bytes());
+        peer_id
+    }
+}
+
+/// A TLS 1.3 external PSK: the identity sent in the ClientHello and the secret behind it. The
+/// secret is wiped when dropped.
+pub struct ExternalPsk {
+    pub identity: Vec<u8>,
+    pub secret: KeyMaterial,
+    pub hash: PskHash,
+}
+
+impl Drop for ExternalPsk {
+    fn drop(&mut self) {
+        wipe(&mut self.secret);
+    }
+}
+
+/// Derive a fresh 256-bit PSK for the client, its identity the armored locator
+pub fn new_external_psk<S: StorageReader, H: Digest>(
+    big_key: &mut BigKey<S, H>,
+    hash: PskHash,
+) -> Result<ExternalPsk, BigKeyError> {
+    let (locator, secret) = big_key.new_key_for_peer(SecurityLevel::Bits256, &hash.peer_id())?;
+    Ok(ExternalPsk {
+        identity: armor_locator(locator.as_bytes()).into_bytes(),
+        secret,
+        hash,
+    })
+}
+
+/// Re-derive the secret of a PSK `identity` offered by a client, `None` if the identity is not
+/// a locator of this BigKey. A locator of another BigKey with the same key id can yield a
+/// secret; the client's binder then fails to verify and the server moves on to its next PSK.
+pub fn resolve_external_psk<S: StorageReader, H: Digest>(
+    big_key: &mut BigKey<S, H>,
+    identity: &[u8],
+    hash: PskHash,
+) -> Result<Option<KeyMaterial>, BigKeyError> {
+    let locator = match std::str::from_utf8(identity).map(dearmor_locator) {
+        Ok(Ok(locator)) => locator,
+        _ => return Ok(None),
+    };
+    match big_key.get_key_for_peer(&locator, &hash.peer_id()) {
+        Ok(secret) => Ok(Some(secret)),
+        Err(BigKeyError::UnknownKeyId { .. })
+        | Err(BigKeyError::LocatorAuthenticationFailed)
+        | Err(BigKeyError::InvalidLocator { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// rustls session ticket encrypter backed by a `KemSession`, see the module documentation.
+///
+/// A ticket is the subkey's locator length as a u16 big-endian, the locator, then the ticket
+/// state and its issue time (seconds since the Unix epoch, a u64 big-endian prefix) wrapped by
+/// `kem::wrap_key()` with the locator as context. Tickets older than the lifetime are refused.
+pub struct BigKeyTicketer {
+    session: Mutex<KemSession>,
+    lifetime: u32,
+}
+
+impl BigKeyTicketer {
+    /// Ticketer deriving ticket keys with `session`, for tickets valid `lifetime` seconds
+    pub fn new(session: KemSession, lifetime: u32) -> Self {
+        BigKeyTicketer {
+            session: Mutex::new(session),
+            lifetime,
+        }
+    }
+
+    // Ticket of `plain` issued at `issued`, `None` if no key can be derived
+    fn seal(&self, plain: &[u8], issued: u64) -> Option<Vec<u8>> {
+        let (locator, subkey) = self.session.lock().ok()?.new_key().ok()?;
+        let len: u16 = locator.len().try_into().ok()?;
+
+        let mut state = Vec::with_capacity(8 + plain.len());
+        state.extend_from_slice(&issued.to_be_bytes());
+        state.extend_from_slice(plain);
+        let mut kek = ticket_kek(subkey);
+        let wrapped = wrap_key(&state, &kek, locator.as_bytes());
+        wipe(&mut kek);
+        wipe(&mut state);
+
+        let mut ticket = Vec::with_capacity(2 + locator.len());
+        ticket.extend_from_slice(&len.to_be_bytes());
+        ticket.extend_from_slice(locator.as_bytes());
+        ticket.extend_from_slice(&wrapped.ok()?);
+        Some(ticket)
+    }
+
+    // State sealed in `ticket`, `None` unless it is an intact ticket of this BigKey issued at
+    // most `lifetime` seconds before `now`
+    fn open(&self, ticket: &[u8], now: u64) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes(ticket.get(..2)?.try_into().unwrap()) as usize;
+        let locator = Locator::from(ticket.get(2..2 + len)?.to_vec());
+
+        let subkey = self.session.lock().ok()?.get_key(&locator).ok()?;
+        let mut kek = ticket_kek(subkey);
+        let state = unwrap_key(&ticket[2 + len..], &kek, locator.as_bytes());
+        wipe(&mut kek);
+        let mut state = state.ok()?;
+
+        let issued = state
+            .get(..8)
+            .map(|t| u64::from_be_bytes(t.try_into().unwrap()));
+        let plain = match issued {
+            Some(issued) if now.saturating_sub(issued) <= self.lifetime as u64 => {
+                Some(state[8..].to_vec())
+            }
+            _ => None,
+        };
+        wipe(&mut state);
+        plain
+    }
+}
+
+impl ProducesTickets for BigKeyTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.seal(plain, unix_now())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.open(cipher, unix_now())
+    }
+}
+
+impl fmt::Debug for BigKeyTicketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BigKeyTicketer")
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
+// Subkeys are as long as the session's security level; the KEK is always KEK_LEN bytes
+fn ticket_kek(mut subkey: KeyMaterial) -> [u8; KEK_LEN] {
+    let mut kek = [0u8; KEK_LEN];
+    blake3::derive_key(TICKET_KEK_CONTEXT, &subkey, &mut kek);
+    wipe(&mut subkey);
+    kek
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use rustls::server::ProducesTickets;
+
+    use crate::helpers::{open_big_key, test_key_file};
+    use crate::kem::{KemSession, SessionParams};
+    use crate::tls::{new_external_psk, resolve_external_psk, unix_now, BigKeyTicketer, PskHash};
+
+    #[test]
+    fn servers_resolve_client_psks() {
+        let tmp = test_key_file();
+        let mut client = open_big_key(tmp.as_path()).unwrap();
+        let mut server = open_big_key(tmp.as_path()).unwrap();
+
+        let psk = new_external_psk(&mut client, PskHash::Sha256).unwrap();
+        assert!(psk.identity.starts_with(b"bklc1"));
+        assert_eq!(psk.secret.len(), 32);
+        let resolved = resolve_external_psk(&mut server, &psk.identity, PskHash::Sha256)
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolved, psk.secret);
+
+        // bound to the hash of the cipher suite
+        let other_hash = resolve_external_psk(&mut server, &psk.identity, PskHash::Sha384)
+            .unwrap()
+            .unwrap();
+        assert_ne!(other_hash, psk.secret);
+
+        for identity in [&b"client-1"[..], &[0xff, 0xfe][..], &b""[..]].iter() {
+            assert!(resolve_external_psk(&mut server, identity, PskHash::Sha256)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn foreign_and_tampered_identities_yield_no_psk() {
+        let (tmp, other) = (test_key_file(), test_key_file());
+        let mut server = open_big_key(tmp.as_path()).unwrap();
+        let mut stranger = open_big_key(other.as_path()).unwrap();
+
+        // another key either rejects the locator or derives another secret, failing the binder
+        let psk = new_external_psk(&mut stranger, PskHash::Sha384).unwrap();
+        let resolved = resolve_external_psk(&mut server, &psk.identity, PskHash::Sha384).unwrap();
+        assert!(resolved.is_none_or(|secret| secret != psk.secret));
+
+        let mut tampered = new_external_psk(&mut server, PskHash::Sha256)
+            .unwrap()
+            .identity
+            .clone();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'q' { b'p' } else { b'q' };
+        assert!(
+            resolve_external_psk(&mut server, &tampered, PskHash::Sha256)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn hash_names_round_trip() {
+        for hash in [PskHash::Sha256, PskHash::Sha384].iter() {
+            assert_eq!(PskHash::parse(hash.name()), Some(*hash));
+        }
+        assert_eq!(PskHash::parse("SHA256"), None);
+        assert_eq!(PskHash::parse("sha512"), None);
+    }
+
+    #[test]
+    fn tickets_resume_on_servers_sharing_the_key() {
+        let (tmp, other) = (test_key_file(), test_key_file());
+        let ticketer = |path: &str| {
+            let session = KemSession::open(path, SessionParams::default()).unwrap();
+            BigKeyTicketer::new(session, 3600)
+        };
+        let issuer = ticketer(tmp.to_str());
+        let resumer: Arc<dyn ProducesTickets> = Arc::new(ticketer(tmp.to_str()));
+        assert!(issuer.enabled());
+        assert_eq!(issuer.lifetime(), 3600);
+
+        let state = b"rustls resumption state";
+        let ticket = issuer.encrypt(state).unwrap();
+        assert!(!ticket.windows(state.len()).any(|w| w == state));
+        assert_ne!(issuer.encrypt(state).unwrap(), ticket);
+        assert_eq!(resumer.decrypt(&ticket).unwrap(), state);
+
+        // tampered, truncated and foreign tickets, and expired ones
+        let mut tampered = ticket.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let foreign = ticketer(other.to_str()).encrypt(state).unwrap();
+        let expired = issuer.seal(state, unix_now() - 3601).unwrap();
+        for bad in [
+            &tampered[..],
+            &ticket[..40],
+            &[][..],
+            &foreign[..],
+            &expired[..],
+        ]
+        .iter()
+        {
+            assert!(resumer.decrypt(bad).is_none());
+        }
+        assert_eq!(issuer.open(&expired, unix_now() - 1).unwrap(), state);
+    }
+} // mod test
@@ -0,0 +1,158 @@
+//! Block indices and counts, kept apart from byte offsets and lengths.
+//!
+//! Storage is addressed in blocks, files and buffers in bytes, and both are `u64`s. The newtypes
+//! here carry the unit in the type: converting between blocks and bytes takes the `BlockSize`
+//! and is checked, so an index taken from an untrusted locator cannot silently wrap into a
+//! valid looking byte offset.
+
+use std::fmt;
+
+use crate::traits::{BigKeyError, BlockSize};
+
+/// Position of a block within a BigKey, counted in blocks from its start
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockIndex(u64);
+
+impl BlockIndex {
+    pub const fn new(index: u64) -> Self {
+        BlockIndex(index)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Byte offset of the block, `None` if it overflows
+    pub fn byte_offset(self, block_size: BlockSize) -> Option<u64> {
+        self.0.checked_mul(block_size.byte_len as u64)
+    }
+}
+
+impl From<u64> for BlockIndex {
+    fn from(index: u64) -> Self {
+        BlockIndex(index)
+    }
+}
+
+impl From<BlockIndex> for u64 {
+    fn from(index: BlockIndex) -> Self {
+        index.0
+    }
+}
+
+impl fmt::Display for BlockIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Number of blocks of a BigKey
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockCount(u64);
+
+impl BlockCount {
+    pub const fn new(count: u64) -> Self {
+        BlockCount(count)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Blocks of a key of `key_len` bytes, failing with `KeyLengthIndivisible` if that is not a
+    /// whole number of blocks
+    pub fn of_key(key_len: u64, block_size: BlockSize) -> Result<Self, BigKeyError> {
+        let block_len = block_size.byte_len as u64;
+        if !key_len.is_multiple_of(block_len) {
+            return Err(BigKeyError::KeyLengthIndivisible {
+                block_len: block_size.byte_len,
+                key_len: key_len as usize,
+            });
+        }
+        Ok(BlockCount(key_len / block_len))
+    }
+
+    /// Length of the blocks in bytes, `None` if it overflows
+    pub fn byte_len(self, block_size: BlockSize) -> Option<u64> {
+        self.0.checked_mul(block_size.byte_len as u64)
+    }
+
+    /// Whether `index` is one of the blocks
+    pub fn contains(self, index: BlockIndex) -> bool {
+        index.0 < self.0
+    }
+
+    /// Every block index, in order
+    pub fn indices(self) -> impl DoubleEndedIterator<Item = BlockIndex> {
+        (0..self.0).map(BlockIndex)
+    }
+}
+
+impl From<u64> for BlockCount {
+    fn from(count: u64) -> Self {
+        BlockCount(count)
+    }
+}
+
+impl From<BlockCount> for u64 {
+    fn from(count: BlockCount) -> Self {
+        count.0
+    }
+}
+
+impl fmt::Display for BlockCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::traits::{BigKeyError, BlockCount, BlockIndex, BLOCK_1K, BLOCK_4K};
+
+    #[test]
+    fn conversions_between_blocks_and_bytes_are_checked() {
+        assert_eq!(BlockIndex::new(3).byte_offset(BLOCK_4K), Some(3 * 4096));
+        assert_eq!(BlockIndex::new(u64::MAX).byte_offset(BLOCK_1K), None);
+        assert_eq!(
+            BlockIndex::new(u64::MAX / 1024).byte_offset(BLOCK_1K),
+            Some(u64::MAX - 1023)
+        );
+
+        let count = BlockCount::of_key(10 * 1024, BLOCK_1K).unwrap();
+        assert_eq!(count, BlockCount::new(10));
+        assert!(count.contains(BlockIndex::new(9)) && !count.contains(BlockIndex::new(10)));
+        assert_eq!(count.indices().last(), Some(BlockIndex::new(9)));
+        assert_eq!(BlockCount::new(u64::MAX).byte_len(BLOCK_4K), None);
+        assert!(matches!(
+            BlockCount::of_key(10 * 1024 + 1, BLOCK_1K),
+            Err(BigKeyError::KeyLengthIndivisible { .. })
+        ));
+    }
+
+    #[test]
+    fn empty_and_extreme_counts() {
+        let empty = BlockCount::of_key(0, BLOCK_4K).unwrap();
+        assert_eq!(empty.get(), 0);
+        assert!(!empty.contains(BlockIndex::new(0)));
+        assert_eq!(empty.indices().next(), None);
+        assert_eq!(empty.byte_len(BLOCK_4K), Some(0));
+
+        let largest = BlockCount::of_key(u64::MAX - 1023, BLOCK_1K).unwrap();
+        assert_eq!(largest.get(), u64::MAX / 1024);
+        assert_eq!(largest.byte_len(BLOCK_1K), Some(u64::MAX - 1023));
+        assert!(largest.contains(BlockIndex::new(u64::MAX / 1024 - 1)));
+        assert!(!largest.contains(BlockIndex::new(u64::MAX)));
+        assert_eq!(
+            largest.indices().next_back(),
+            Some(BlockIndex::new(u64::MAX / 1024 - 1))
+        );
+        assert!(BlockCount::of_key(u64::MAX, BLOCK_1K).is_err());
+
+        // conversions and display carry the raw value, in blocks
+        assert_eq!(u64::from(BlockIndex::from(7)), 7);
+        assert_eq!(u64::from(BlockCount::from(7)), 7);
+        assert_eq!(BlockIndex::new(u64::MAX).to_string(), u64::MAX.to_string());
+        assert_eq!(BlockCount::new(12).to_string(), "12");
+    }
+} // mod test
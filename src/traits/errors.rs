@@ -1,6 +1,8 @@
 use std::io;
 use thiserror::Error;
 
+use crate::traits::HashAlgorithm;
+
 #[derive(Error, Debug)]
 pub enum BigKeyError {
     #[error("block length {block_len} does not evenly divide key length {key_len}")]
@@ -12,6 +14,12 @@ pub enum BigKeyError {
     #[error("requested output length too long; {out_len} > max {max_len}")]
     OutputLengthTooLong { out_len: usize, max_len: usize },
 
+    #[error("invalid generator state: {reason}")]
+    InvalidGeneratorState { reason: &'static str },
+
+    #[error("invalid size {input:?}: {reason}")]
+    InvalidByteSize { input: String, reason: &'static str },
+
     #[error("requested output length too short (less than a block); {out_len} < min {min_len}")]
     OutputLengthTooShort { out_len: usize, min_len: usize },
 
@@ -34,6 +42,296 @@ pub enum BigKeyError {
         block_len: usize,
     },
 
+    #[error("invalid BigKey header: {reason}")]
+    InvalidHeader { reason: &'static str },
+
+    #[error("invalid key provenance: {reason}")]
+    InvalidProvenance { reason: &'static str },
+
+    #[error("header claims key length {header_len} but file holds {file_len} bytes of key data")]
+    HeaderLengthMismatch { header_len: u64, file_len: u64 },
+
+    #[error("key file {path} is locked by another process")]
+    KeyLocked { path: String },
+
+    #[error("block size {requested_len} does not match block size {header_len} in key header")]
+    BlockSizeMismatch {
+        requested_len: usize,
+        header_len: usize,
+    },
+
+    #[error("BigKey verification failed: {stage}")]
+    VerificationFailed { stage: &'static str },
+
+    #[error("leakage tolerance {tolerance} outside of the allowed range [0.0, 1.0)")]
+    LeakageToleranceOutOfRange { tolerance: f32 },
+
+    #[error("invalid configuration: {reason}")]
+    InvalidConfig { reason: String },
+
+    #[error("estimated derivation IO of {estimated} bytes exceeds budget of {budget} bytes")]
+    DerivationBudgetExceeded { estimated: u64, budget: u64 },
+
+    #[error(
+        "{key_len} byte key too small for {bits} bit security at leakage tolerance \
+         {tolerance}, needs at least {min_len} bytes"
+    )]
+    KeyTooSmallForSecurityLevel {
+        bits: u32,
+        tolerance: f32,
+        key_len: u64,
+        min_len: u64,
+    },
+
+    #[error("derivation parameters of the locator do not match the BigKey: {reason}")]
+    DerivationParamsMismatch { reason: &'static str },
+
+    #[error("invalid locator: {reason}")]
+    InvalidLocator { reason: &'static str },
+
+    #[error("invalid armored locator: {reason}{}", at_position(.position))]
+    InvalidArmoredLocator {
+        reason: &'static str,
+        position: Option<usize>,
+    },
+
+    #[error("locator version {version} is newer than supported version {max_supported}")]
+    UnsupportedLocatorVersion { version: u8, max_supported: u8 },
+
+    #[error("locator flags {flags:#04x} set flags newer than supported flags {supported:#04x}")]
+    UnsupportedLocatorFlags { flags: u8, supported: u8 },
+
+    #[error("unsupported hash algorithm id {id}")]
+    UnsupportedHashAlgorithm { id: u8 },
+
+    #[error("locator was derived with {found:?}, not {expected:?}")]
+    HashAlgorithmMismatch {
+        expected: HashAlgorithm,
+        found: HashAlgorithm,
+    },
+
+    #[error("locator failed authentication")]
+    LocatorAuthenticationFailed,
+
+    #[error("locator refers to unknown BigKey id {key_id}")]
+    UnknownKeyId { key_id: u32 },
+
+    #[error("BigKey id {key_id} is already in the keyring")]
+    DuplicateKeyId { key_id: u32 },
+
+    #[error("keyring has no current BigKey")]
+    NoCurrentKey,
+
+    #[error("digest output {digest_len} bytes too short for {key_len} byte key")]
+    DigestTooShort { digest_len: usize, key_len: usize },
+
+    #[error("operating system randomness unavailable")]
+    RandomnessUnavailable(#[from] getrandom::Error),
+
+    #[error("block {index} failed checksum verification")]
+    BlockCorrupted { index: u64 },
+
+    #[error("invalid checksum sidecar: {reason}")]
+    InvalidChecksumSidecar { reason: &'static str },
+
+    #[error("invalid key usage sidecar: {reason}")]
+    InvalidUsageSidecar { reason: &'static str },
+
+    #[error("invalid derivation counter sidecar: {reason}")]
+    InvalidCounterSidecar { reason: &'static str },
+
+    #[error("BigKey retired: {reason}")]
+    KeyRetired { reason: &'static str },
+
+    #[error("invalid BigKey container: {reason}")]
+    InvalidContainer { reason: &'static str },
+
+    #[error("invalid key encoding: {reason}")]
+    InvalidEncoding { reason: &'static str },
+
+    #[error("invalid key manifest: {reason}")]
+    InvalidManifest { reason: String },
+
+    #[error("invalid compressed metadata: {reason}")]
+    InvalidCompressedMetadata { reason: String },
+
+    #[error("invalid probe trace: {reason}")]
+    InvalidProbeTrace { reason: String },
+
+    #[error("replica holds {replica_blocks} blocks but source holds {source_blocks}")]
+    ReplicaLengthMismatch {
+        source_blocks: u64,
+        replica_blocks: u64,
+    },
+
+    #[error("replica has block size {replica_len} but source has block size {source_len}")]
+    ReplicaBlockSizeMismatch {
+        source_len: usize,
+        replica_len: usize,
+    },
+
+    #[error("invalid possession challenge: {reason}")]
+    InvalidChallenge { reason: &'static str },
+
+    #[error("seed escrow failed: {reason}")]
+    EscrowFailed { reason: &'static str },
+
+    #[error("PKCS#11 {op} failed: {reason}")]
+    Pkcs11Failed { op: &'static str, reason: String },
+
+    #[error("key cache failed: {reason}")]
+    KeyCacheFailed { reason: &'static str },
+
+    #[error("key wrapping failed: {reason}")]
+    KeyWrapFailed { reason: &'static str },
+
+    #[error("age plugin failed: {reason}")]
+    AgePluginFailed { reason: &'static str },
+
+    #[error("key agent failed: {reason}")]
+    AgentFailed { reason: String },
+
+    #[error("key host attestation failed: {reason}")]
+    AttestationFailed { reason: String },
+
+    #[error("invalid lease: {reason}")]
+    InvalidLease { reason: &'static str },
+
+    #[error("lease quota exceeded: {used} of {quota} probes used, {requested} more requested")]
+    LeaseQuotaExceeded {
+        quota: u64,
+        used: u64,
+        requested: u64,
+    },
+
+    #[error("locator store failed: {reason}")]
+    LocatorStoreFailed { reason: String },
+
+    #[error("invalid generation checkpoint: {reason}")]
+    InvalidCheckpoint { reason: &'static str },
+
+    #[error("BigKey unusable after a derivation panicked, reload it")]
+    BigKeyPoisoned,
+
+    #[error("probed blocks do not match the locator's probe check value")]
+    ProbeCheckMismatch,
+
+    #[error("locator agreement failed: {reason}")]
+    AgreementFailed { reason: &'static str },
+
+    #[error("not enough free space: {required} bytes required, {available} available")]
+    InsufficientSpace { required: u64, available: u64 },
+
+    #[error("file of {size} bytes exceeds maximum file size of {max_size} bytes")]
+    FileTooLarge { size: u64, max_size: u64 },
+
+    #[error("{path} may store the key compressed or deduplicated: {reason}")]
+    CompressingFilesystem { path: String, reason: String },
+
+    #[error("cannot write to {path}: {reason}")]
+    NotWritable { path: String, reason: String },
+
+    #[error("conformance check {check} failed: {reason}")]
+    ConformanceFailed { check: &'static str, reason: String },
+
+    #[error("probe deadline exceeded")]
+    Timeout,
+
+    #[error("probe cancelled")]
+    Cancelled,
+
+    #[error("no hardware random number generator available")]
+    HardwareRngUnavailable,
+
+    #[error("hardware RNG failed {test} health test")]
+    HealthTestFailed { test: &'static str },
+
+    #[error("entropy source too short; {available} bytes available < {required} bytes required")]
+    EntropySourceTooShort { available: u64, required: u64 },
+
+    #[error("{op} failed on {path}{}: {source}", at_offset(.offset))]
+    Storage {
+        op: &'static str,
+        path: String,
+        offset: Option<u64>,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("volume provisioning failed: {reason}")]
+    VolumeFailed { reason: String },
+
+    #[error("kernel keyring {op} failed: {source}")]
+    KernelKeyring {
+        op: &'static str,
+        #[source]
+        source: io::Error,
+    },
+
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite {op} failed on {path}: {source}")]
+    Sqlite {
+        op: &'static str,
+        path: String,
+        #[source]
+        source: rusqlite::Error,
+    },
+
     #[error("io error")]
     IoError(#[from] io::Error),
 }
+
+impl BigKeyError {
+    /// Whether the operation that failed may succeed if attempted again, e.g. an interrupted or
+    /// timed out read from network storage. Everything else is permanent.
+    pub fn is_retryable(&self) -> bool {
+        match self.io_error() {
+            Some(e) => matches!(
+                e.kind(),
+                io::ErrorKind::Interrupted
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            ),
+            None => false,
+        }
+    }
+
+    /// The underlying IO error, with or without storage context
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            BigKeyError::IoError(e) | BigKeyError::Storage { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Attach the storage operation, path and offset to a bare `IoError`; other errors are
+    /// returned unchanged
+    pub fn in_storage(self, op: &'static str, path: &str, offset: Option<u64>) -> BigKeyError {
+        match self {
+            BigKeyError::IoError(source) => BigKeyError::Storage {
+                op,
+                path: path.to_string(),
+                offset,
+                source,
+            },
+            other => other,
+        }
+    }
+}
+
+fn at_position(position: &Option<usize>) -> String {
+    match position {
+        Some(position) => format!(" at character {}", position),
+        None => String::new(),
+    }
+}
+
+fn at_offset(offset: &Option<u64>) -> String {
+    match offset {
+        Some(offset) => format!(" at offset {}", offset),
+        None => String::new(),
+    }
+}
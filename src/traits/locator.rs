@@ -0,0 +1,130 @@
+//! The opaque handle returned with every derived key.
+//!
+//! A locator is not secret, but its contents are sensitive metadata: the selector expands into
+//! the probed block indices, and a locator together with the BigKey re-derives the key. Its
+//! `Display` and `Debug` output therefore show only a short fingerprint, so a locator that ends
+//! up in a log line or an error message identifies the derivation without reproducing it. The
+//! bytes themselves are only handed out by `as_bytes()` / `to_bytes()`, for storing or sending
+//! the locator deliberately.
+
+use std::fmt;
+
+use crate::traits::SecretBytes;
+
+// Bytes of the BLAKE3 digest shown by `fingerprint()`
+const FINGERPRINT_LEN: usize = 8;
+
+/// An opaque locator into a BigKey
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Locator(Box<[u8]>);
+
+impl Locator {
+    /// The serialized locator, to store or send it
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// A copy of the serialized locator
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Hex of a truncated BLAKE3 digest of the locator, enough to tell locators apart in logs
+    pub fn fingerprint(&self) -> String {
+        blake3::hash(&self.0).as_bytes()[..FINGERPRINT_LEN].to_hex()
+    }
+}
+
+impl From<Box<[u8]>> for Locator {
+    fn from(bytes: Box<[u8]>) -> Self {
+        Locator(bytes)
+    }
+}
+
+impl From<Vec<u8>> for Locator {
+    fn from(bytes: Vec<u8>) -> Self {
+        Locator(bytes.into_boxed_slice())
+    }
+}
+
+impl From<&[u8]> for Locator {
+    fn from(bytes: &[u8]) -> Self {
+        Locator(bytes.into())
+    }
+}
+
+impl AsRef<[u8]> for Locator {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Locator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "locator {}", self.fingerprint())
+    }
+}
+
+impl fmt::Debug for Locator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Locator")
+            .field("fingerprint", &self.fingerprint())
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::traits::Locator;
+
+    #[test]
+    fn formatting_shows_only_a_fingerprint() {
+        let bytes: Vec<u8> = (0..40u8).collect();
+        let locator = Locator::from(bytes.clone());
+        assert_eq!(locator.to_bytes(), bytes);
+        assert_eq!(locator.fingerprint().len(), 16);
+
+        let shown = format!("{} {:?} {:#?}", locator, locator, locator);
+        assert!(shown.contains(&locator.fingerprint()));
+        assert!(shown.contains("len: 40"));
+        // no run of the locator's bytes, in any of the usual renderings
+        for rendering in [format!("{:?}", &bytes[..4]), "00010203".to_string()].iter() {
+            assert!(!shown.contains(rendering.as_str()), "{}", shown);
+        }
+        assert_ne!(
+            locator.fingerprint(),
+            Locator::from(&bytes[1..]).fingerprint()
+        );
+    }
+
+    #[test]
+    fn empty_and_near_identical_locators() {
+        let empty = Locator::default();
+        assert!(empty.is_empty() && empty.as_bytes().is_empty());
+        assert_eq!(empty, Locator::from(Vec::new()));
+        assert_eq!(empty.fingerprint().len(), 16);
+        assert!(format!("{:?}", empty).contains("len: 0"));
+
+        // a single flipped bit changes the fingerprint, equality and ordering still compare
+        // the bytes
+        let bytes = vec![0x5au8; 4096];
+        let mut flipped = bytes.clone();
+        flipped[4095] ^= 1;
+        let (a, b) = (Locator::from(bytes.clone()), Locator::from(flipped));
+        assert_ne!(a.fingerprint(), b.fingerprint());
+        assert_ne!(a, b);
+        assert!(a < b);
+        assert_eq!(a, Locator::from(bytes.into_boxed_slice()));
+        assert_eq!(a.as_ref(), a.as_bytes());
+        assert!(a.to_string().len() < 32);
+    }
+} // mod test
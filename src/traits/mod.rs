@@ -1,6 +1,14 @@
 pub use types::*;
 
+pub mod blocks;
 pub mod errors;
+pub mod locator;
+pub mod secret;
+pub mod size;
 pub mod types;
 
+pub use blocks::{BlockCount, BlockIndex};
 pub use errors::BigKeyError;
+pub use locator::Locator;
+pub use secret::{ct_eq, key_from_base64, key_from_base64url, key_from_hex, SecretBytes};
+pub use size::ByteSize;
@@ -0,0 +1,303 @@
+//! Handling `KeyMaterial` without leaking it through timing.
+//!
+//! Comparing derived keys with `==` stops at the first differing byte, and textbook hex or
+//! base64 codecs index lookup tables with secret values; both leak key bytes to an attacker who
+//! can time them. `SecretBytes` adds constant-time equality and encoders to `KeyMaterial` (and
+//! any byte slice), and `key_from_hex()` / `key_from_base64()` / `key_from_base64url()` decode
+//! without secret-dependent branches or table lookups. Only the length of the input, and whether it is well-formed, are
+//! revealed.
+
+use crate::traits::{BigKeyError, KeyMaterial};
+
+/// Constant-time operations on secret bytes
+pub trait SecretBytes {
+    /// Whether `self` equals `other`, in time depending only on their lengths
+    fn ct_eq(&self, other: &[u8]) -> bool;
+
+    /// Lowercase hex encoding
+    fn to_hex(&self) -> String;
+
+    /// Standard, padded base64 encoding (RFC 4648 section 4)
+    fn to_base64(&self) -> String;
+
+    /// URL and filename safe base64 encoding without padding (RFC 4648 section 5), as used by
+    /// JSON Web Keys
+    fn to_base64url(&self) -> String;
+}
+
+impl SecretBytes for [u8] {
+    fn ct_eq(&self, other: &[u8]) -> bool {
+        ct_eq(self, other)
+    }
+
+    fn to_hex(&self) -> String {
+        let mut out = String::with_capacity(self.len() * 2);
+        for &byte in self {
+            out.push(hex_char(byte as i16 >> 4) as char);
+            out.push(hex_char(byte as i16 & 0x0f) as char);
+        }
+        out
+    }
+
+    fn to_base64(&self) -> String {
+        encode_base64(self, base64_char, true)
+    }
+
+    fn to_base64url(&self) -> String {
+        encode_base64(self, base64url_char, false)
+    }
+}
+
+fn encode_base64(bytes: &[u8], char_of: fn(i16) -> u8, pad: bool) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let sextets = [
+            group[0] >> 2,
+            (group[0] & 0x03) << 4 | group[1] >> 4,
+            (group[1] & 0x0f) << 2 | group[2] >> 6,
+            group[2] & 0x3f,
+        ];
+        for (i, sextet) in sextets.iter().enumerate() {
+            match i <= chunk.len() {
+                true => out.push(char_of(*sextet as i16) as char),
+                false if pad => out.push('='),
+                false => {}
+            }
+        }
+    }
+    out
+}
+
+/// Whether `a` equals `b`, in time depending only on their lengths
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a
+        .iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    // keep the optimiser from short-circuiting on `diff`
+    std::hint::black_box(diff) == 0
+}
+
+/// Decode key material from hex (either case)
+pub fn key_from_hex(hex: &str) -> Result<KeyMaterial, BigKeyError> {
+    let hex = hex.as_bytes();
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid("hex length is odd"));
+    }
+
+    let mut key = Vec::with_capacity(hex.len() / 2);
+    let mut valid = -1i16;
+    for pair in hex.chunks(2) {
+        let (high, high_ok) = hex_value(pair[0] as i16);
+        let (low, low_ok) = hex_value(pair[1] as i16);
+        valid &= high_ok & low_ok;
+        key.push((high << 4 | low) as u8);
+    }
+
+    if valid == 0 {
+        return Err(invalid("not a hex digit"));
+    }
+    Ok(key.into_boxed_slice())
+}
+
+/// Decode key material from standard, padded base64
+pub fn key_from_base64(base64: &str) -> Result<KeyMaterial, BigKeyError> {
+    let base64 = base64.as_bytes();
+    if !base64.len().is_multiple_of(4) {
+        return Err(invalid("base64 length is not a multiple of 4"));
+    }
+    // the amount of padding depends only on the key length
+    let padding = base64
+        .iter()
+        .rev()
+        .take(2)
+        .take_while(|c| **c == b'=')
+        .count();
+    decode_base64(base64, padding, base64_value)
+}
+
+/// Decode key material from unpadded URL and filename safe base64
+pub fn key_from_base64url(base64url: &str) -> Result<KeyMaterial, BigKeyError> {
+    let mut base64 = base64url.as_bytes().to_vec();
+    let padding = match base64.len() % 4 {
+        1 => return Err(invalid("base64url length is impossible")),
+        0 => 0,
+        rem => 4 - rem,
+    };
+    base64.resize(base64.len() + padding, b'=');
+    decode_base64(&base64, padding, base64url_value)
+}
+
+fn decode_base64(
+    base64: &[u8],
+    padding: usize,
+    value_of: fn(i16) -> (i16, i16),
+) -> Result<KeyMaterial, BigKeyError> {
+    let mut key = Vec::with_capacity(base64.len() / 4 * 3);
+    let mut valid = -1i16;
+    for (q, quad) in base64.chunks(4).enumerate() {
+        let last = q == base64.len() / 4 - 1;
+        let data_chars = if last { 4 - padding } else { 4 };
+
+        let mut bits = 0u32;
+        for (i, c) in quad.iter().enumerate() {
+            let value = match i < data_chars {
+                true => {
+                    let (value, ok) = value_of(*c as i16);
+                    valid &= ok;
+                    value
+                }
+                false => 0,
+            };
+            bits = bits << 6 | (value as u32 & 0x3f);
+        }
+
+        let bytes = [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8];
+        key.extend_from_slice(&bytes[..data_chars - 1]);
+    }
+
+    if valid == 0 {
+        return Err(invalid("not a base64 character"));
+    }
+    Ok(key.into_boxed_slice())
+}
+
+// Lowercase hex digit of nibble `n` without branches: '0' + n, plus 39 when n > 9
+fn hex_char(n: i16) -> u8 {
+    (87 + n + (((n - 10) >> 8) & !38)) as u8
+}
+
+// Value of hex digit `c`, and a mask that is all ones if `c` is a hex digit, else zero
+fn hex_value(c: i16) -> (i16, i16) {
+    let digit = c ^ 48;
+    let digit_ok = (digit - 10) >> 8;
+    let alpha = (c & !32) - 55;
+    let alpha_ok = ((alpha - 10) ^ (alpha - 16)) >> 8;
+    ((digit_ok & digit) | (alpha_ok & alpha), digit_ok | alpha_ok)
+}
+
+// Base64 character of sextet `x` without branches or table lookups
+fn base64_char(x: i16) -> u8 {
+    let mut c = x + 65;
+    c += ((25 - x) >> 8) & 6;
+    c -= ((51 - x) >> 8) & 75;
+    c -= ((61 - x) >> 8) & 15;
+    c += ((62 - x) >> 8) & 3;
+    c as u8
+}
+
+// Base64url character of sextet `x`: as `base64_char()`, moving '+' (62) to '-' and '/' (63)
+// to '_'
+fn base64url_char(x: i16) -> u8 {
+    let mut c = base64_char(x) as i16;
+    c += ((61 - x) >> 8) & 2;
+    c += ((62 - x) >> 8) & 46;
+    c as u8
+}
+
+// Value of base64 character `c`, and a mask that is all ones if it is valid, else zero
+fn base64_value(c: i16) -> (i16, i16) {
+    sextet_value(c, b'+', b'/')
+}
+
+fn base64url_value(c: i16) -> (i16, i16) {
+    sextet_value(c, b'-', b'_')
+}
+
+// Value of `c` in the base64 alphabet ending in `c62`, `c63`, and its validity mask
+fn sextet_value(c: i16, c62: u8, c63: u8) -> (i16, i16) {
+    let upper = in_range(c, b'A', b'Z');
+    let lower = in_range(c, b'a', b'z');
+    let digit = in_range(c, b'0', b'9');
+    let s62 = in_range(c, c62, c62);
+    let s63 = in_range(c, c63, c63);
+
+    let value =
+        (upper & (c - 65)) | (lower & (c - 71)) | (digit & (c + 4)) | (s62 & 62) | (s63 & 63);
+    (value, upper | lower | digit | s62 | s63)
+}
+
+// All ones if lo <= c <= hi, else zero
+fn in_range(c: i16, lo: u8, hi: u8) -> i16 {
+    ((lo as i16 - 1 - c) & (c - hi as i16 - 1)) >> 8
+}
+
+fn invalid(reason: &'static str) -> BigKeyError {
+    BigKeyError::InvalidEncoding { reason }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::traits::secret::{
+        ct_eq, key_from_base64, key_from_base64url, key_from_hex, SecretBytes,
+    };
+    use crate::traits::{BigKeyError, KeyMaterial};
+
+    #[test]
+    fn encodings_round_trip() {
+        let all: KeyMaterial = (0..=255u8).collect::<Vec<u8>>().into_boxed_slice();
+        let hex = all.to_hex();
+        assert_eq!(&hex[..8], "00010203");
+        assert_eq!(&hex[hex.len() - 4..], "feff");
+        assert_eq!(key_from_hex(&hex).unwrap(), all);
+        assert_eq!(key_from_hex(&hex.to_uppercase()).unwrap(), all);
+
+        for len in 0..8 {
+            let key = &all[250 - len..250];
+            assert_eq!(key_from_base64(&key.to_base64()).unwrap()[..], key[..]);
+        }
+        // RFC 4648 section 10
+        assert_eq!(b"foob".to_base64(), "Zm9vYg==");
+        assert_eq!(b"fooba".to_base64(), "Zm9vYmE=");
+        assert_eq!(b"\xfb\xff".to_base64(), "+/8=");
+        assert_eq!(&key_from_base64("Zm9vYmFy").unwrap()[..], b"foobar");
+
+        for len in 0..8 {
+            let key = &all[250 - len..250];
+            assert_eq!(
+                key_from_base64url(&key.to_base64url()).unwrap()[..],
+                key[..]
+            );
+        }
+        assert_eq!(b"fooba".to_base64url(), "Zm9vYmE");
+        assert_eq!(b"\xfb\xff\xbf".to_base64url(), "-_-_");
+        assert_eq!(&key_from_base64url("-_-_").unwrap()[..], b"\xfb\xff\xbf");
+    }
+
+    #[test]
+    fn malformed_encodings_fail() {
+        for bad in ["0", "0g", "zz", "0:"].iter() {
+            match key_from_hex(bad) {
+                Err(BigKeyError::InvalidEncoding { .. }) => {}
+                _ => panic!("expected {:?} to be rejected", bad),
+            }
+        }
+        for bad in ["Zm9vY", "Zm9v+A", "Zm9v/A", "Zm9v=A"].iter() {
+            match key_from_base64url(bad) {
+                Err(BigKeyError::InvalidEncoding { .. }) => {}
+                _ => panic!("expected {:?} to be rejected", bad),
+            }
+        }
+        for bad in ["Zm9", "Zm9v!A==", "Zm=v", "===="].iter() {
+            match key_from_base64(bad) {
+                Err(BigKeyError::InvalidEncoding { .. }) => {}
+                _ => panic!("expected {:?} to be rejected", bad),
+            }
+        }
+    }
+
+    #[test]
+    fn equality_is_exact() {
+        let key: KeyMaterial = vec![7u8; 32].into_boxed_slice();
+        let mut other = key.to_vec();
+        assert!(key.ct_eq(&other));
+        other[31] ^= 1;
+        assert!(!key.ct_eq(&other));
+        assert!(!ct_eq(&key, &other[..31]));
+    }
+} // mod test
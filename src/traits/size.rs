@@ -0,0 +1,223 @@
+//! Byte sizes with explicit decimal (MB) or binary (MiB) units.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::traits::BigKeyError;
+
+// Accepted units, matched case-insensitively
+const UNITS: [(&str, u64); 11] = [
+    ("b", 1),
+    ("kb", 1_000),
+    ("kib", 1 << 10),
+    ("mb", 1_000_000),
+    ("mib", 1 << 20),
+    ("gb", 1_000_000_000),
+    ("gib", 1 << 30),
+    ("tb", 1_000_000_000_000),
+    ("tib", 1 << 40),
+    ("pb", 1_000_000_000_000_000),
+    ("pib", 1 << 50),
+];
+
+// Units `Display` picks from, largest first
+const DISPLAY_UNITS: [(&str, u64); 10] = [
+    ("PiB", 1 << 50),
+    ("PB", 1_000_000_000_000_000),
+    ("TiB", 1 << 40),
+    ("TB", 1_000_000_000_000),
+    ("GiB", 1 << 30),
+    ("GB", 1_000_000_000),
+    ("MiB", 1 << 20),
+    ("MB", 1_000_000),
+    ("KiB", 1 << 10),
+    ("kB", 1_000),
+];
+
+/// A size in bytes, parsed from and displayed as e.g. `512MiB` or `2TB`.
+///
+/// Parsing takes a whole number followed by an optional unit: `B`, decimal `kB`, `MB`, `GB`,
+/// `TB`, `PB` or binary `KiB`, `MiB`, `GiB`, `TiB`, `PiB`, in any case. A bare number is bytes.
+/// Single letter units such as `M` are rejected as ambiguous. Display uses the largest unit
+/// that represents the size exactly, so displayed sizes parse back to the same value.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub const fn from_bytes(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+
+    pub const fn from_mib(mib: u64) -> Self {
+        ByteSize(mib << 20)
+    }
+
+    pub const fn from_gib(gib: u64) -> Self {
+        ByteSize(gib << 30)
+    }
+
+    pub const fn bytes(self) -> u64 {
+        self.0
+    }
+
+    /// The size as a `usize`, failing on platforms that cannot address it
+    pub fn to_usize(self) -> Result<usize, BigKeyError> {
+        usize::try_from(self.0).map_err(|_| BigKeyError::InvalidByteSize {
+            input: self.to_string(),
+            reason: "too large for this platform",
+        })
+    }
+}
+
+impl From<u64> for ByteSize {
+    fn from(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(size: ByteSize) -> Self {
+        size.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = BigKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| BigKeyError::InvalidByteSize {
+            input: s.to_string(),
+            reason,
+        };
+
+        let trimmed = s.trim();
+        let digits_end = trimmed
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(trimmed.len());
+        let (number, unit) = (&trimmed[..digits_end], trimmed[digits_end..].trim_start());
+        if number.is_empty() {
+            return Err(invalid("expected a whole number of bytes or units"));
+        }
+        if unit.starts_with('.') {
+            return Err(invalid("fractional sizes are not supported"));
+        }
+
+        let multiplier = match unit {
+            "" => 1,
+            unit => UNITS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+                .map(|(_, multiplier)| *multiplier)
+                .ok_or_else(|| match unit.len() {
+                    1 => invalid("ambiguous unit, use e.g. MiB (2^20) or MB (10^6)"),
+                    _ => invalid("unknown unit"),
+                })?,
+        };
+
+        u64::from_str(number)
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(ByteSize)
+            .ok_or_else(|| invalid("larger than 2^64 - 1 bytes"))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = DISPLAY_UNITS
+            .iter()
+            .find(|(_, multiplier)| self.0 != 0 && self.0.is_multiple_of(*multiplier));
+        match unit {
+            Some((name, multiplier)) => write!(f, "{}{}", self.0 / multiplier, name),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use crate::traits::{BigKeyError, ByteSize};
+
+    #[test]
+    fn sizes_parse_and_display() {
+        for (input, bytes, shown) in [
+            ("512MiB", 512 << 20, "512MiB"),
+            ("2TB", 2_000_000_000_000, "2TB"),
+            ("2 tb", 2_000_000_000_000, "2TB"),
+            ("1024KiB", 1 << 20, "1MiB"),
+            ("4096", 4096, "4KiB"),
+            ("1500B", 1500, "1500B"),
+            ("0", 0, "0B"),
+        ]
+        .iter()
+        {
+            let size = ByteSize::from_str(input).unwrap();
+            assert_eq!(size.bytes(), *bytes, "{}", input);
+            assert_eq!(size.to_string(), *shown);
+            assert_eq!(ByteSize::from_str(shown).unwrap(), size);
+        }
+
+        for (input, reason) in [
+            ("512M", "ambiguous"),
+            ("1.5GiB", "fractional"),
+            ("MiB", "whole number"),
+            ("16EiB", "unknown unit"),
+            ("99999999PiB", "larger"),
+        ]
+        .iter()
+        {
+            match ByteSize::from_str(input) {
+                Err(BigKeyError::InvalidByteSize { reason: r, .. }) => {
+                    assert!(r.contains(reason), "{}: {}", input, r)
+                }
+                other => panic!("{} should not parse: {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn hostile_and_extreme_sizes() {
+        let max = ByteSize::from_str("18446744073709551615").unwrap();
+        assert_eq!(max.bytes(), u64::MAX);
+        assert_eq!(max.to_string(), "18446744073709551615B");
+        assert_eq!(
+            ByteSize::from_str(" 16 PiB ").unwrap(),
+            ByteSize::from(1 << 54)
+        );
+        assert_eq!(ByteSize::from_str("3gIb").unwrap(), ByteSize::from_gib(3));
+        assert_eq!(ByteSize::from_mib(1).to_string(), "1MiB");
+        assert_eq!(u64::from(ByteSize::from_bytes(1000)), 1000);
+        // the decimal and binary units of a size divisible by both pick the binary one
+        assert_eq!(ByteSize::from_bytes(1 << 40).to_string(), "1TiB");
+
+        for (input, reason) in [
+            ("", "whole number"),
+            ("   ", "whole number"),
+            ("-1", "whole number"),
+            ("+5", "whole number"),
+            ("5 M", "ambiguous"),
+            ("5MiBs", "unknown unit"),
+            ("1_000", "unknown unit"),
+            ("5 MiB 2", "unknown unit"),
+            ("12é", "unknown unit"),
+            ("18446744073709551616", "larger"),
+            ("18446744073709551615KB", "larger"),
+        ]
+        .iter()
+        {
+            match ByteSize::from_str(input) {
+                Err(BigKeyError::InvalidByteSize {
+                    input: i,
+                    reason: r,
+                }) => {
+                    assert_eq!(i, *input);
+                    assert!(r.contains(reason), "{:?}: {}", input, r)
+                }
+                other => panic!("{:?} should not parse: {:?}", input, other),
+            }
+        }
+    }
+} // mod test
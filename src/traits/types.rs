@@ -1,5 +1,5 @@
 /// Cryptographic security level
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SecurityLevel {
     /// 128-bit security level
     Bits128 = 128,
@@ -8,6 +8,16 @@ pub enum SecurityLevel {
     Bits256 = 256,
 }
 
+impl SecurityLevel {
+    pub fn from_bits(bits: u32) -> Option<SecurityLevel> {
+        match bits {
+            128 => Some(SecurityLevel::Bits128),
+            256 => Some(SecurityLevel::Bits256),
+            _ => None,
+        }
+    }
+}
+
 /// Native unit of capacity for a given StorageMethod.
 #[derive(Debug, Copy, Clone)]
 pub struct BlockSize {
@@ -36,12 +46,72 @@ pub const BLOCK_4K: BlockSize = BlockSize {
     byte_len: 4096,
 };
 
-pub const BLOCKS: [BlockSize; 5] = [
-    BLOCK_8, BLOCK_32, BLOCK_64, BLOCK_1K, BLOCK_4K
-];
+pub const BLOCKS: [BlockSize; 5] = [BLOCK_8, BLOCK_32, BLOCK_64, BLOCK_1K, BLOCK_4K];
+
+impl BlockSize {
+    /// The supported `BlockSize` that is `byte_len` bytes long, if any
+    pub fn from_byte_len(byte_len: usize) -> Option<BlockSize> {
+        BLOCKS.iter().find(|b| b.byte_len == byte_len).copied()
+    }
+}
+
+/// Identifies the `BigKeyGenerator` that produced a BigKey
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GeneratorId {
+    /// Generator was not recorded (e.g. a raw key file)
+    Unknown = 0,
+
+    /// SHAKE256 from SHA3
+    Shake256 = 1,
+
+    /// BLAKE3 in XOF mode
+    Blake3 = 2,
+
+    /// Four interleaved SHAKE256 streams, see `generation::Shake256x4Generator`
+    Shake256x4 = 3,
 
-/// An opaque locator into a BigKey
-pub type Locator = Box<[u8]>;
+    /// Hardware random number generator, not reproducible from a seed
+    HwRng = 4,
+
+    /// External entropy file or stream, see `generation::FileSeedGenerator`
+    EntropyFile = 5,
+}
+
+impl GeneratorId {
+    pub fn from_u16(value: u16) -> Option<GeneratorId> {
+        match value {
+            0 => Some(GeneratorId::Unknown),
+            1 => Some(GeneratorId::Shake256),
+            2 => Some(GeneratorId::Blake3),
+            3 => Some(GeneratorId::Shake256x4),
+            4 => Some(GeneratorId::HwRng),
+            5 => Some(GeneratorId::EntropyFile),
+            _ => None,
+        }
+    }
+}
+
+/// Hash function used for probe selection and key derivation, identified in locators and key
+/// headers by `id()`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha3_256 = 1,
+    Sha3_512 = 2,
+}
+
+impl HashAlgorithm {
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<HashAlgorithm> {
+        match id {
+            1 => Some(HashAlgorithm::Sha3_256),
+            2 => Some(HashAlgorithm::Sha3_512),
+            _ => None,
+        }
+    }
+}
 
 /// Sensitive/secret cryptographic information; treat with caution!
 pub type KeyMaterial = Box<[u8]>;
@@ -0,0 +1,448 @@
+//! Disk and directory encryption keys derived from a BigKey.
+//!
+//! A volume key is a 256-bit key derived from the BigKey, expanded to the `VOLUME_KEY_LEN`
+//! bytes AES-256-XTS takes. Its locator is kept with the volume, so whoever holds the BigKey
+//! can re-derive the key and unlock the volume, and nobody else can:
+//!
+//! * LUKS2 devices get a keyslot opened by the volume key and a token of type
+//!   `big_fluffy_dise` holding the armored locator. `cryptsetup` is run to add the keyslot,
+//!   import the token and open the device, with the key passed through a pipe inherited by
+//!   `cryptsetup` (`/dev/fd/N`), never through its arguments or a shell.
+//! * fscrypt directories get a v1 encryption policy whose master key is loaded into the session
+//!   keyring as a logon key (see `kernel_keyring`), and the armored locator in the
+//!   `user.big_fluffy_dise.locator` extended attribute of the directory.
+//!
+//! fscrypt v1 policies leave keys in the keyring until they are unlinked or the session ends,
+//! and files stay readable to any process that can see them while the key is loaded.
+
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use digest::Digest;
+use serde_json::Value;
+
+use crate::kem::{armor_locator, dearmor_locator, BigKey, BigKeyKem};
+use crate::kernel_keyring::{add_key, KernelKeyring, KeySerial, KeyType};
+use crate::memory::{wipe, LockedBuffer};
+use crate::storage::StorageReader;
+use crate::traits::{BigKeyError, Locator, SecretBytes, SecurityLevel};
+
+/// Length of volume keys: two AES-256 keys for XTS
+pub const VOLUME_KEY_LEN: usize = 64;
+
+/// Type of the LUKS2 tokens holding locators
+pub const LUKS_TOKEN_TYPE: &str = "big_fluffy_dise";
+
+/// Extended attribute holding the locator of an fscrypt directory
+pub const FSCRYPT_LOCATOR_XATTR: &str = "user.big_fluffy_dise.locator";
+
+const VOLUME_KEY_CONTEXT: &str = "big_fluffy_dise 2024 volume key v1";
+const DESCRIPTOR_CONTEXT: &str = "big_fluffy_dise 2024 fscrypt key descriptor v1";
+
+const CRYPTSETUP: &str = "cryptsetup";
+
+// fscrypt v1 policy, see linux/fscrypt.h
+const FSCRYPT_DESCRIPTOR_LEN: usize = 8;
+const FSCRYPT_POLICY_V1: u8 = 0;
+const FSCRYPT_MODE_AES_256_XTS: u8 = 1;
+const FSCRYPT_MODE_AES_256_CTS: u8 = 4;
+const FSCRYPT_POLICY_FLAGS_PAD_32: u8 = 0x03;
+// _IOR('f', 19, struct fscrypt_policy_v1)
+const FS_IOC_SET_ENCRYPTION_POLICY: libc::c_ulong = 0x800c_6613;
+// struct fscrypt_key: u32 mode, u8 raw[64], u32 size
+const FSCRYPT_KEY_PAYLOAD_LEN: usize = 4 + VOLUME_KEY_LEN + 4;
+
+/// Kinds of volume `provision-volume` sets up
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VolumeKind {
+    Luks,
+    Fscrypt,
+}
+
+impl VolumeKind {
+    pub fn parse(name: &str) -> Option<VolumeKind> {
+        match name {
+            "luks" => Some(VolumeKind::Luks),
+            "fscrypt" => Some(VolumeKind::Fscrypt),
+            _ => None,
+        }
+    }
+}
+
+/// Volume key expanded from the derived key `derived`
+pub fn volume_key(derived: &[u8]) -> LockedBuffer {
+    let mut hasher = blake3::Hasher::new_derive_key(VOLUME_KEY_CONTEXT);
+    hasher.update(derived);
+    let mut key = LockedBuffer::new(VOLUME_KEY_LEN);
+    hasher.finalize_xof().fill(&mut key);
+    key
+}
+
+/// Add a keyslot opened by a new volume key to the LUKS2 device `device`, and record its
+/// locator in a token. `cryptsetup` asks for an existing passphrase of the device on the
+/// terminal.
+pub fn provision_luks<S, H>(
+    big_key: &mut BigKey<S, H>,
+    device: &str,
+) -> Result<Locator, BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    let (locator, key) = new_volume_key(big_key)?;
+    run_with_key("luksAddKey", &key, |key_file| {
+        let mut command = Command::new(CRYPTSETUP);
+        command.args(["luksAddKey", device, key_file]);
+        command
+    })?;
+
+    let mut import = Command::new(CRYPTSETUP);
+    import
+        .args(["token", "import", "--json-file", "-", device])
+        .stdin(Stdio::piped());
+    let mut child = import.spawn().map_err(|e| spawn_failed(&e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(luks_token(&locator).as_bytes())?;
+    }
+    check_status("token import", child.wait()?)?;
+    Ok(locator)
+}
+
+/// Re-derive the volume key of the LUKS2 device `device` from the locator in its token and
+/// open the device as `/dev/mapper/<name>`
+pub fn unlock_luks<S, H>(
+    big_key: &mut BigKey<S, H>,
+    device: &str,
+    name: &str,
+) -> Result<(), BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    let output = Command::new(CRYPTSETUP)
+        .args(["luksDump", "--dump-json-metadata", device])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| spawn_failed(&e))?;
+    check_status("luksDump", output.status)?;
+    let locator = locator_from_luks_metadata(&String::from_utf8_lossy(&output.stdout))?;
+
+    let key = rederive_volume_key(big_key, &locator)?;
+    run_with_key("open", &key, |key_file| {
+        let mut command = Command::new(CRYPTSETUP);
+        command.args(["open", "--key-file", key_file, device, name]);
+        command
+    })
+}
+
+/// Set a v1 encryption policy with a new volume key on the empty directory `dir`, loading the
+/// key into the session keyring and recording its locator on the directory
+pub fn provision_fscrypt<S, H>(
+    big_key: &mut BigKey<S, H>,
+    dir: &Path,
+) -> Result<(Locator, KeySerial), BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    if std::fs::read_dir(dir)?.next().is_some() {
+        return Err(failed(format!(
+            "{} is not an empty directory",
+            dir.display()
+        )));
+    }
+    let (locator, key) = new_volume_key(big_key)?;
+    let serial = load_fscrypt_key(&key)?;
+    set_fscrypt_policy(dir, &fscrypt_descriptor(&key))?;
+    set_xattr(
+        dir,
+        FSCRYPT_LOCATOR_XATTR,
+        armor_locator(locator.as_bytes()).as_bytes(),
+    )?;
+    Ok((locator, serial))
+}
+
+/// Re-derive the volume key of the fscrypt directory `dir` from its locator and load it into
+/// the session keyring, unlocking the directory
+pub fn unlock_fscrypt<S, H>(
+    big_key: &mut BigKey<S, H>,
+    dir: &Path,
+) -> Result<KeySerial, BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    let armored = get_xattr(dir, FSCRYPT_LOCATOR_XATTR)?;
+    let locator = dearmor_locator(&String::from_utf8_lossy(&armored))?;
+    let key = rederive_volume_key(big_key, &locator)?;
+    load_fscrypt_key(&key)
+}
+
+/// LUKS2 token JSON recording `locator`
+pub fn luks_token(locator: &Locator) -> String {
+    serde_json::json!({
+        "type": LUKS_TOKEN_TYPE,
+        "keyslots": [],
+        "locator": armor_locator(locator.as_bytes()),
+    })
+    .to_string()
+}
+
+/// Locator in the first `LUKS_TOKEN_TYPE` token of the LUKS2 metadata `metadata`, as dumped by
+/// `cryptsetup luksDump --dump-json-metadata`
+pub fn locator_from_luks_metadata(metadata: &str) -> Result<Locator, BigKeyError> {
+    let metadata: Value =
+        serde_json::from_str(metadata).map_err(|_| failed("malformed LUKS2 metadata"))?;
+    let mut tokens: Vec<(u64, &Value)> = metadata["tokens"]
+        .as_object()
+        .map(|tokens| {
+            tokens
+                .iter()
+                .filter_map(|(id, token)| Some((id.parse().ok()?, token)))
+                .collect()
+        })
+        .unwrap_or_default();
+    tokens.sort_by_key(|(id, _)| *id);
+
+    let armored = tokens
+        .into_iter()
+        .find(|(_, token)| token["type"] == LUKS_TOKEN_TYPE)
+        .and_then(|(_, token)| token["locator"].as_str())
+        .ok_or_else(|| failed(format!("no {} token on the device", LUKS_TOKEN_TYPE)))?;
+    dearmor_locator(armored)
+}
+
+// A fresh 256-bit key of `big_key` and the volume key expanded from it
+fn new_volume_key<S, H>(big_key: &mut BigKey<S, H>) -> Result<(Locator, LockedBuffer), BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    let (locator, mut derived) = big_key.new_key(SecurityLevel::Bits256)?;
+    let key = volume_key(&derived);
+    wipe(&mut derived);
+    Ok((locator, key))
+}
+
+fn rederive_volume_key<S, H>(
+    big_key: &mut BigKey<S, H>,
+    locator: &Locator,
+) -> Result<LockedBuffer, BigKeyError>
+where
+    S: StorageReader,
+    H: Digest,
+{
+    let mut derived = big_key.get_key(locator)?;
+    let key = volume_key(&derived);
+    wipe(&mut derived);
+    Ok(key)
+}
+
+// Master key descriptor of the fscrypt volume key `key`, naming it in policies and the keyring
+fn fscrypt_descriptor(key: &[u8]) -> [u8; FSCRYPT_DESCRIPTOR_LEN] {
+    let mut hasher = blake3::Hasher::new_derive_key(DESCRIPTOR_CONTEXT);
+    hasher.update(key);
+    blake3::Hasher::finalize(&hasher).as_bytes()[..FSCRYPT_DESCRIPTOR_LEN]
+        .try_into()
+        .unwrap()
+}
+
+// struct fscrypt_key holding `key`, in native byte order
+fn fscrypt_key_payload(key: &[u8]) -> LockedBuffer {
+    let mut payload = LockedBuffer::new(FSCRYPT_KEY_PAYLOAD_LEN);
+    payload[4..4 + VOLUME_KEY_LEN].copy_from_slice(key);
+    payload[4 + VOLUME_KEY_LEN..].copy_from_slice(&(VOLUME_KEY_LEN as u32).to_ne_bytes());
+    payload
+}
+
+fn load_fscrypt_key(key: &[u8]) -> Result<KeySerial, BigKeyError> {
+    let description = format!("fscrypt:{}", fscrypt_descriptor(key).to_hex());
+    add_key(
+        KeyType::Logon,
+        &description,
+        &fscrypt_key_payload(key),
+        KernelKeyring::Session,
+    )
+}
+
+fn set_fscrypt_policy(
+    dir: &Path,
+    descriptor: &[u8; FSCRYPT_DESCRIPTOR_LEN],
+) -> Result<(), BigKeyError> {
+    let mut policy = [0u8; 4 + FSCRYPT_DESCRIPTOR_LEN];
+    policy[0] = FSCRYPT_POLICY_V1;
+    policy[1] = FSCRYPT_MODE_AES_256_XTS;
+    policy[2] = FSCRYPT_MODE_AES_256_CTS;
+    policy[3] = FSCRYPT_POLICY_FLAGS_PAD_32;
+    policy[4..].copy_from_slice(descriptor);
+
+    let file = File::open(dir)?;
+    // Safety: the descriptor is owned by `file` and `policy` is a valid fscrypt_policy_v1
+    if unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            FS_IOC_SET_ENCRYPTION_POLICY as _,
+            policy.as_ptr(),
+        )
+    } == -1
+    {
+        return Err(io_failed(
+            "set encryption policy",
+            dir,
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> Result<(), BigKeyError> {
+    let (c_path, c_name) = (
+        c_path(path)?,
+        CString::new(name).expect("no NUL in xattr names"),
+    );
+    // Safety: both strings are NUL terminated and `value` is valid for its length
+    let rc = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if rc == -1 {
+        return Err(io_failed("setxattr", path, io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn get_xattr(path: &Path, name: &str) -> Result<Vec<u8>, BigKeyError> {
+    let (c_path, c_name) = (
+        c_path(path)?,
+        CString::new(name).expect("no NUL in xattr names"),
+    );
+    let mut value = vec![0u8; 1024];
+    // Safety: both strings are NUL terminated and `value` is valid for writes of its length
+    let len = unsafe {
+        libc::getxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if len == -1 {
+        return Err(io_failed("getxattr", path, io::Error::last_os_error()));
+    }
+    value.truncate(len as usize);
+    Ok(value)
+}
+
+fn c_path(path: &Path) -> Result<CString, BigKeyError> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| failed("path contains NUL"))
+}
+
+// Run the `cryptsetup` `step` that `build` makes given the path of a pipe holding `key`, which
+// the child inherits (pipe(2) descriptors are not close-on-exec)
+fn run_with_key(
+    step: &str,
+    key: &[u8],
+    build: impl FnOnce(&str) -> Command,
+) -> Result<(), BigKeyError> {
+    let mut fds = [0; 2];
+    // Safety: `fds` has room for the two descriptors pipe(2) returns
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Safety: pipe(2) returned two open descriptors nothing else owns
+    let (read_end, mut write_end) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // the key fits in the pipe buffer, so the write completes before the child reads
+    write_end.write_all(key)?;
+    drop(write_end);
+
+    let key_file = format!("/dev/fd/{}", read_end.as_raw_fd());
+    let status = build(&key_file).status().map_err(|e| spawn_failed(&e));
+    drop(read_end);
+    check_status(step, status?)
+}
+
+fn check_status(step: &str, status: std::process::ExitStatus) -> Result<(), BigKeyError> {
+    if !status.success() {
+        return Err(failed(format!(
+            "{} {} exited with {}",
+            CRYPTSETUP, step, status
+        )));
+    }
+    Ok(())
+}
+
+fn spawn_failed(e: &io::Error) -> BigKeyError {
+    failed(format!("cannot run {}: {}", CRYPTSETUP, e))
+}
+
+fn io_failed(op: &'static str, path: &Path, source: io::Error) -> BigKeyError {
+    BigKeyError::Storage {
+        op,
+        path: path.display().to_string(),
+        offset: None,
+        source,
+    }
+}
+
+fn failed(reason: impl Into<String>) -> BigKeyError {
+    BigKeyError::VolumeFailed {
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kem::armor_locator;
+    use crate::traits::{BigKeyError, Locator};
+    use crate::volume::{
+        fscrypt_descriptor, fscrypt_key_payload, locator_from_luks_metadata, luks_token,
+        volume_key, VOLUME_KEY_LEN,
+    };
+
+    #[test]
+    fn luks_tokens_round_trip_through_metadata() {
+        let locator = Locator::from(vec![3u8; 70]);
+        let metadata = format!(
+            r#"{{"keyslots":{{}},"tokens":{{"1":{},"0":{{"type":"systemd-tpm2"}}}}}}"#,
+            luks_token(&locator)
+        );
+        assert_eq!(locator_from_luks_metadata(&metadata).unwrap(), locator);
+        assert!(luks_token(&locator).contains(&armor_locator(locator.as_bytes())));
+
+        match locator_from_luks_metadata(r#"{"tokens":{}}"#) {
+            Err(BigKeyError::VolumeFailed { .. }) => {}
+            _ => panic!("expected metadata without a token to fail"),
+        }
+    }
+
+    #[test]
+    fn fscrypt_payload_layout() {
+        let key = volume_key(&[7u8; 32]);
+        assert_eq!(key.len(), VOLUME_KEY_LEN);
+        assert_eq!(&key[..], &volume_key(&[7u8; 32])[..]);
+        assert_ne!(&key[..], &volume_key(&[8u8; 32])[..]);
+
+        let payload = fscrypt_key_payload(&key);
+        assert_eq!(&payload[..4], &[0u8; 4]);
+        assert_eq!(&payload[4..68], &key[..]);
+        assert_eq!(payload[68..], (VOLUME_KEY_LEN as u32).to_ne_bytes());
+        assert_ne!(
+            fscrypt_descriptor(&key),
+            fscrypt_descriptor(&volume_key(&[8u8; 32]))
+        );
+    }
+} // mod test
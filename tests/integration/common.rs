@@ -0,0 +1,68 @@
+//! Workload sizing and scratch files shared by the integration tests
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const GIB: usize = 1024 * 1024 * 1024;
+const SMOKE_KEY_LENGTH: usize = 4 * 1024 * 1024;
+const SMOKE_KEY_COUNT: usize = 200;
+const FULL_KEY_COUNT: usize = 2000;
+
+/// Size of the synthetic key and number of keys to derive from it
+pub struct Workload {
+    pub key_length: usize,
+    pub key_count: usize,
+}
+
+impl Workload {
+    pub fn from_env() -> Self {
+        let gib = env_usize("BFD_INTEGRATION_GIB");
+        let key_length = gib.map_or(SMOKE_KEY_LENGTH, |gib| gib * GIB);
+        let default_count = if gib.is_some() {
+            FULL_KEY_COUNT
+        } else {
+            SMOKE_KEY_COUNT
+        };
+
+        Workload {
+            key_length,
+            key_count: env_usize("BFD_INTEGRATION_KEYS").unwrap_or(default_count),
+        }
+    }
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    std::env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a whole number, got {:?}", name, value))
+    })
+}
+
+/// Key file in the temporary directory, removed along with its sidecars on drop
+pub struct ScratchKey {
+    path: PathBuf,
+}
+
+impl ScratchKey {
+    pub fn new(name: &str) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let mut path = std::env::temp_dir();
+        path.push(format!("big_fluffy_dise_it_{}_{}", name, nanos));
+        ScratchKey { path }
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for ScratchKey {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_file(format!("{}.sums", self.to_str()));
+    }
+}
@@ -0,0 +1,9 @@
+//! End-to-end tests over realistic workloads.
+//!
+//! By default every test runs against a small key so the suite stays fast. Set
+//! `BFD_INTEGRATION_GIB` to the key size in GiB (e.g. `BFD_INTEGRATION_GIB=4`) for the full
+//! multi-GiB run, preferably with `cargo test --release --test integration`.
+//! `BFD_INTEGRATION_KEYS` overrides the number of keys derived.
+
+mod common;
+mod round_trip;
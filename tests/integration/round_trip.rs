@@ -0,0 +1,118 @@
+//! Generate a key, derive many keys from it, reopen it and recover them all
+
+use sha3::{Digest, Sha3_256};
+
+use big_fluffy_dise::generation::{generate_verified, Shake256Generator};
+use big_fluffy_dise::kem::{BigKey, BigKeyKem};
+use big_fluffy_dise::storage::{fingerprint, DiskStorage, Manifest};
+use big_fluffy_dise::traits::{Locator, SecurityLevel, BLOCK_4K};
+
+use crate::common::{ScratchKey, Workload};
+
+const SEED: &[u8; 32] = b"big_fluffy_dise integration seed";
+const LEAKAGE_TOLERANCE: f32 = 0.2;
+const HOSTILE_KEY_LENGTH: usize = 1024 * 1024;
+
+#[test]
+fn derived_keys_survive_reopening() {
+    let workload = Workload::from_env();
+    let key = ScratchKey::new("round_trip");
+
+    let generated = generate_verified::<Shake256Generator>(
+        BLOCK_4K,
+        key.to_str(),
+        Some(SEED.to_vec().into_boxed_slice()),
+        workload.key_length,
+    )
+    .unwrap();
+    let manifest = Manifest::for_key(key.to_str()).unwrap();
+    assert_eq!(manifest.fingerprint, generated);
+
+    let derived = {
+        let mut storage = DiskStorage::open(BLOCK_4K, key.to_str()).unwrap();
+        let mut bk = BigKey::new_big_key(
+            SecurityLevel::Bits128,
+            LEAKAGE_TOLERANCE,
+            &mut storage,
+            Sha3_256::new(),
+        );
+        (0..workload.key_count)
+            .map(|_| bk.new_key(SecurityLevel::Bits128).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let mut storage = DiskStorage::open(BLOCK_4K, key.to_str()).unwrap();
+    assert_eq!(fingerprint(&mut storage).unwrap(), generated);
+    manifest.verify(key.to_str()).unwrap();
+
+    let mut bk = BigKey::new_big_key(
+        SecurityLevel::Bits128,
+        LEAKAGE_TOLERANCE,
+        &mut storage,
+        Sha3_256::new(),
+    );
+    for (locator, expected) in derived.iter() {
+        assert_eq!(&bk.get_key(locator).unwrap(), expected);
+    }
+
+    let mut locators: Vec<_> = derived.iter().map(|(locator, _)| locator).collect();
+    locators.sort();
+    locators.dedup();
+    assert_eq!(locators.len(), workload.key_count);
+}
+
+#[test]
+fn hostile_locators_and_damaged_keys_fail() {
+    let key = ScratchKey::new("round_trip_hostile");
+    generate_verified::<Shake256Generator>(
+        BLOCK_4K,
+        key.to_str(),
+        Some(SEED.to_vec().into_boxed_slice()),
+        HOSTILE_KEY_LENGTH,
+    )
+    .unwrap();
+    let manifest = Manifest::for_key(key.to_str()).unwrap();
+
+    let mut storage = DiskStorage::open(BLOCK_4K, key.to_str()).unwrap();
+    let mut bk = BigKey::new_big_key(
+        SecurityLevel::Bits128,
+        LEAKAGE_TOLERANCE,
+        &mut storage,
+        Sha3_256::new(),
+    );
+    let (locator, expected) = bk.new_key(SecurityLevel::Bits128).unwrap();
+    let bytes = locator.to_bytes();
+
+    for hostile in [
+        Vec::new(),
+        bytes[..1].to_vec(),
+        bytes[..bytes.len() - 1].to_vec(),
+        [bytes.as_slice(), &[0u8; 64]].concat(),
+        vec![0xff; bytes.len()],
+    ]
+    .iter()
+    {
+        let hostile = Locator::from(hostile.as_slice());
+        assert!(bk.get_key(&hostile).is_err(), "{:?}", hostile);
+    }
+    // a locator altered anywhere never yields the original key
+    for position in 0..bytes.len() {
+        let mut altered = bytes.clone();
+        altered[position] ^= 0x01;
+        if let Ok(key) = bk.get_key(&Locator::from(altered)) {
+            assert_ne!(key, expected, "byte {}", position);
+        }
+    }
+    assert_eq!(bk.get_key(&locator).unwrap(), expected);
+    drop(bk);
+    drop(storage);
+
+    // a key damaged on disk no longer matches its manifest
+    let mut contents = std::fs::read(key.to_str()).unwrap();
+    let last = contents.len() - 1;
+    contents[last] ^= 0x01;
+    std::fs::write(key.to_str(), &contents).unwrap();
+    assert!(manifest.verify(key.to_str()).is_err());
+    let mut storage = DiskStorage::open(BLOCK_4K, key.to_str()).unwrap();
+    assert_ne!(fingerprint(&mut storage).unwrap(), manifest.fingerprint);
+}